@@ -0,0 +1,87 @@
+//! Criterion benchmarks of the codec hot paths: fixed headers, remaining
+//! length varints and PUBLISH encoding and decoding at several payload
+//! sizes. Run with `cargo bench --bench codec`
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sake::mqtt::{Deserialize, FixedHeader, FrameReader, Request, Response, Serialize, VarInt};
+
+const PAYLOAD_SIZES: [usize; 4] = [16, 1024, 64 * 1024, 1024 * 1024];
+
+fn publish(size: usize) -> Request {
+    Request::Publish {
+        packet_id: 1,
+        qos: 1,
+        dup: false,
+        retain: false,
+        topic: "sensors/room-1/temperature".to_string(),
+        payload: vec![0xAB; size],
+    }
+}
+
+fn fixed_header(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fixed_header");
+    // One remaining length byte, and the most a 1 MiB PUBLISH needs
+    let large = publish(1024 * 1024).to_bytes().unwrap();
+    for (name, bytes) in [("pingresp", &[0xD0, 0][..]), ("publish_1MiB", &large[..5])] {
+        group.bench_function(name, |b| {
+            b.iter(|| FixedHeader::from_bytes(&mut black_box(bytes)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn varint(c: &mut Criterion) {
+    let mut group = c.benchmark_group("varint");
+    for value in [0, 127, 16_383, 2_097_151, VarInt::MAX] {
+        let varint = VarInt::new(value as usize).unwrap();
+        let mut buf = Vec::with_capacity(4);
+        group.bench_with_input(BenchmarkId::new("write", value), &varint, |b, varint| {
+            b.iter(|| {
+                buf.clear();
+                black_box(varint).write(&mut buf).unwrap()
+            })
+        });
+        let bytes = buf.clone();
+        group.bench_with_input(BenchmarkId::new("decode", value), &bytes, |b, bytes| {
+            b.iter(|| VarInt::decode(black_box(bytes)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn publish_codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("publish");
+    for size in PAYLOAD_SIZES {
+        let request = publish(size);
+        let bytes = request.to_bytes().unwrap();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        // Reusing one buffer, as `MqttWriter` does
+        let mut buf = Vec::new();
+        group.bench_with_input(BenchmarkId::new("encode", size), &request, |b, request| {
+            b.iter(|| {
+                buf.clear();
+                request.serialize(&mut buf).unwrap();
+                black_box(&buf);
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &bytes, |b, bytes| {
+            b.iter(|| Response::from_slice(black_box(bytes)).unwrap())
+        });
+        // Buffering the packet from a reader first, as `MqttReader` does
+        group.bench_with_input(BenchmarkId::new("read", size), &bytes, |b, bytes| {
+            b.iter(|| {
+                let mut frames = FrameReader::new(black_box(&bytes[..]));
+                let frame = loop {
+                    if let Some(frame) = frames.next_frame().unwrap() {
+                        break frame;
+                    }
+                    frames.fill().unwrap();
+                };
+                Response::from_slice(frame).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fixed_header, varint, publish_codec);
+criterion_main!(benches);
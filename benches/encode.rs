@@ -0,0 +1,59 @@
+//! Compares encoding a PUBLISH the way `MqttWriter` used to, copying the
+//! topic and payload into a `Request` and serializing it into a fresh
+//! buffer, against borrowing them with `PublishRef` and reusing one buffer,
+//! which is what it does now. Run with `cargo bench --bench encode`
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sake::mqtt::{PublishRef, Request, Serialize};
+
+const PAYLOAD_SIZES: [usize; 3] = [16, 1024, 64 * 1024];
+
+const TOPIC: &str = "sensors/room-1/temperature";
+
+fn publish_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("publish_encode");
+    for size in PAYLOAD_SIZES {
+        let payload = vec![0xAB; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(
+            BenchmarkId::new("owned_fresh", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    let request = Request::Publish {
+                        packet_id: 1,
+                        qos: 1,
+                        dup: false,
+                        retain: false,
+                        topic: black_box(TOPIC).to_string(),
+                        payload: black_box(payload).to_vec(),
+                    };
+                    request.to_bytes().unwrap()
+                })
+            },
+        );
+        let mut buf = Vec::new();
+        group.bench_with_input(
+            BenchmarkId::new("borrowed_reused", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    buf.clear();
+                    let publish = PublishRef {
+                        packet_id: 1,
+                        qos: 1,
+                        dup: false,
+                        retain: false,
+                        topic: black_box(TOPIC),
+                        payload: black_box(payload),
+                    };
+                    publish.serialize(&mut buf).unwrap();
+                    buf.len()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, publish_encode);
+criterion_main!(benches);
@@ -0,0 +1,87 @@
+//! End-to-end throughput through the embedded broker over loopback: a
+//! client subscribed to a topic publishes batches of messages to it and
+//! reads them back, acknowledging them at QoS 1. Run with
+//! `cargo bench --bench throughput`
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sake::broker::{Broker, BrokerOptions};
+use sake::mqtt::{AckType, Protocol, Qos, Request, Response};
+use std::net::SocketAddr;
+use std::thread;
+
+const TOPIC: &str = "bench/throughput";
+
+/// Messages published before reading them back, so that reads and writes
+/// overlap in the broker
+const BATCH: u16 = 100;
+
+const PAYLOAD_SIZES: [usize; 3] = [16, 1024, 16 * 1024];
+
+fn start_broker() -> SocketAddr {
+    let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default()).unwrap();
+    let addr = broker.local_addr().unwrap();
+    thread::spawn(move || broker.run());
+    addr
+}
+
+fn connect(addr: SocketAddr, qos: Qos) -> Protocol {
+    let mut client = Protocol::builder()
+        .addrs(&[addr])
+        .client_id("sake-bench-throughput")
+        .connect()
+        .unwrap();
+    client.subscribe(TOPIC, qos).unwrap();
+    match client.next_message().unwrap() {
+        Response::Suback { .. } => client,
+        response => panic!("Unexpected response {}", response),
+    }
+}
+
+/// Publish a batch and wait for every message to come back, and for every
+/// PUBACK at QoS 1
+fn round_trip(client: &mut Protocol, qos: u8, payload: &[u8]) {
+    for packet_id in 1..=BATCH {
+        let publish = Request::Publish {
+            packet_id: if qos > 0 { packet_id } else { 0 },
+            qos,
+            dup: false,
+            retain: false,
+            topic: TOPIC.to_string(),
+            payload: payload.to_vec(),
+        };
+        client.send_message(&publish).unwrap();
+    }
+    let (mut received, mut acked) = (0, 0);
+    let expected_acks = if qos > 0 { BATCH } else { 0 };
+    while received < BATCH || acked < expected_acks {
+        match client.next_message().unwrap() {
+            Response::Publish { packet_id, qos, .. } => {
+                if qos > 0 {
+                    client.ack(AckType::Puback(packet_id)).unwrap();
+                }
+                received += 1;
+            }
+            Response::Puback { .. } => acked += 1,
+            response => panic!("Unexpected response {}", response),
+        }
+    }
+}
+
+fn throughput(c: &mut Criterion) {
+    let addr = start_broker();
+    let mut group = c.benchmark_group("loopback");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    for (name, qos) in [("qos0", Qos::AtMostOnce), ("qos1", Qos::AtLeastOnce)] {
+        let mut client = connect(addr, qos);
+        for size in PAYLOAD_SIZES {
+            let payload = vec![0xAB; size];
+            group.bench_with_input(BenchmarkId::new(name, size), &payload, |b, payload| {
+                b.iter(|| round_trip(&mut client, u8::from(&qos), payload))
+            });
+        }
+        client.disconnect().unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
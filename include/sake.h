@@ -0,0 +1,58 @@
+/* C API of the sake MQTT client, see src/ffi.rs */
+#ifndef SAKE_H
+#define SAKE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Opaque client handle */
+typedef struct SakeClient SakeClient;
+
+/*
+ * Called for every message received, on a thread owned by the client, so
+ * user_data must be safe to use from there. topic and payload are only valid
+ * for the duration of the call.
+ */
+typedef void (*SakeMessageCallback)(const char *topic,
+                                    const uint8_t *payload,
+                                    size_t payload_len,
+                                    void *user_data);
+
+/* Connect performing the MQTT handshake, returns NULL on failure */
+SakeClient *sake_connect(const char *host, uint16_t port, const char *client_id);
+
+/* Publish waiting for the QoS flow to complete, returns 0 on success, -1 on failure */
+int sake_publish(SakeClient *client,
+                 const char *topic,
+                 const uint8_t *payload,
+                 size_t payload_len,
+                 uint8_t qos);
+
+/*
+ * Subscribe to topic, callback replaces any previously registered one and
+ * receives the messages of every subscription. Returns 0 on success, -1 on failure
+ */
+int sake_subscribe(SakeClient *client,
+                   const char *topic,
+                   uint8_t qos,
+                   SakeMessageCallback callback,
+                   void *user_data);
+
+/* Disconnect and free the client, returns 0 on success, -1 on failure */
+int sake_disconnect(SakeClient *client);
+
+/*
+ * Why the last failing call on this thread failed, NULL if none did. Valid
+ * until the next failing call on the same thread.
+ */
+const char *sake_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SAKE_H */
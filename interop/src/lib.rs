@@ -0,0 +1,504 @@
+use sake::broker::{Message, ServerPacket};
+use sake::mqtt::{Qos, Request, SubscriptionTopic, Will};
+use std::env;
+
+/// Seed of the packets generated, `INTEROP_SEED` to replay a failure
+pub fn seed() -> u64 {
+    env::var("INTEROP_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(0x5EED)
+}
+
+/// Packets generated per test, `INTEROP_ROUNDS` to search longer
+pub fn rounds() -> usize {
+    env::var("INTEROP_ROUNDS")
+        .ok()
+        .and_then(|rounds| rounds.parse().ok())
+        .unwrap_or(500)
+}
+
+/// xorshift64*, enough to vary packets reproducibly without depending on
+/// a random number crate
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number in `0..n`
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    /// Packet ids are never 0 on the wire
+    pub fn packet_id(&mut self) -> u16 {
+        1 + self.below(u16::MAX as usize) as u16
+    }
+
+    pub fn qos(&mut self) -> u8 {
+        self.below(3) as u8
+    }
+
+    /// Short strings mixing ASCII and multi-byte UTF-8
+    pub fn string(&mut self, max: usize) -> String {
+        const CHARS: [char; 8] = ['a', 'z', '0', '-', '_', 'é', '€', '𝄞'];
+        (0..self.below(max + 1))
+            .map(|_| CHARS[self.below(CHARS.len())])
+            .collect()
+    }
+
+    /// Topic names of 1 to 4 levels, possibly empty ones
+    pub fn topic(&mut self) -> String {
+        let levels: Vec<String> = (0..1 + self.below(4)).map(|_| self.string(6)).collect();
+        match levels.join("/") {
+            topic if topic.is_empty() => "t".to_string(),
+            topic => topic,
+        }
+    }
+
+    /// Topic filters, a topic with some levels turned into wildcards
+    pub fn filter(&mut self) -> String {
+        let mut levels: Vec<String> = self.topic().split('/').map(str::to_string).collect();
+        for level in levels.iter_mut() {
+            if self.chance(20) {
+                *level = "+".to_string();
+            }
+        }
+        if self.chance(20) {
+            levels.push("#".to_string());
+        }
+        levels.join("/")
+    }
+
+    /// Payload sizes around the remaining length boundaries, where a byte
+    /// more or less changes the fixed header
+    pub fn payload(&mut self) -> Vec<u8> {
+        const SIZES: [usize; 8] = [0, 1, 120, 127, 128, 16_370, 16_383, 16_384];
+        let len = match self.chance(50) {
+            true => SIZES[self.below(SIZES.len())],
+            false => self.below(300),
+        };
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+
+    fn optional(&mut self, max: usize) -> Option<String> {
+        self.chance(50).then(|| self.string(max))
+    }
+}
+
+/// A packet a client sends, any of those `Request` has
+pub fn request(rng: &mut Rng) -> Request {
+    match rng.below(9) {
+        0 => Request::Connect {
+            client_id: rng.string(23),
+            clean_session: rng.chance(50),
+            username: rng.optional(12),
+            password: rng.optional(12),
+            keepalive: rng.next_u64() as u16,
+            will: rng.chance(50).then(|| {
+                Will::new(
+                    &rng.topic(),
+                    &rng.payload(),
+                    Qos::from(rng.qos()),
+                    rng.chance(50),
+                )
+            }),
+        },
+        1 => {
+            let qos = rng.qos();
+            Request::Publish {
+                packet_id: if qos > 0 { rng.packet_id() } else { 0 },
+                qos,
+                dup: qos > 0 && rng.chance(30),
+                retain: rng.chance(30),
+                topic: rng.topic(),
+                payload: rng.payload(),
+            }
+        }
+        2 => Request::Puback {
+            packet_id: rng.packet_id(),
+        },
+        3 => Request::Pubrec {
+            packet_id: rng.packet_id(),
+        },
+        4 => Request::Pubrel {
+            packet_id: rng.packet_id(),
+        },
+        5 => Request::Pubcomp {
+            packet_id: rng.packet_id(),
+        },
+        6 => Request::Subscribe {
+            packet_id: rng.packet_id(),
+            subscription_topics: (0..1 + rng.below(4))
+                .map(|_| SubscriptionTopic {
+                    topic: rng.filter(),
+                    qos: Qos::from(rng.qos()),
+                })
+                .collect(),
+        },
+        7 => Request::Pingreq,
+        _ => Request::Disconnect { reason: None },
+    }
+}
+
+/// A packet a broker sends, any of those `ServerPacket` has
+pub fn server_packet(rng: &mut Rng) -> ServerPacket {
+    match rng.below(9) {
+        0 => ServerPacket::Connack {
+            session_present: rng.chance(50),
+            return_code: rng.below(6) as u8,
+        },
+        1 => {
+            let qos = rng.qos();
+            ServerPacket::Publish {
+                packet_id: if qos > 0 { rng.packet_id() } else { 0 },
+                dup: qos > 0 && rng.chance(30),
+                message: Message {
+                    topic: rng.topic(),
+                    payload: rng.payload(),
+                    qos,
+                    retain: rng.chance(30),
+                },
+            }
+        }
+        2 => ServerPacket::Puback(rng.packet_id()),
+        3 => ServerPacket::Pubrec(rng.packet_id()),
+        4 => ServerPacket::Pubrel(rng.packet_id()),
+        5 => ServerPacket::Pubcomp(rng.packet_id()),
+        6 => ServerPacket::Suback {
+            packet_id: rng.packet_id(),
+            return_codes: (0..1 + rng.below(4))
+                .map(|_| [0, 1, 2, 0x80][rng.below(4)])
+                .collect(),
+        },
+        7 => ServerPacket::Unsuback(rng.packet_id()),
+        _ => ServerPacket::Pingresp,
+    }
+}
+
+#[cfg(test)]
+mod mqttrs_tests {
+    use super::*;
+    use mqttrs::{
+        decode_slice, encode_slice, ConnectReturnCode, Packet, QoS, QosPid, SubscribeReturnCodes,
+    };
+    use sake::broker::ClientPacket;
+    use sake::mqtt::{Deserialize, Response, Serialize};
+
+    fn encode(packet: &Packet) -> Vec<u8> {
+        let mut buf = vec![0; 1 << 17];
+        let len = encode_slice(packet, &mut buf).expect("mqttrs encodes");
+        buf.truncate(len);
+        buf
+    }
+
+    fn decode(bytes: &[u8]) -> Packet<'_> {
+        decode_slice(bytes)
+            .expect("mqttrs decodes")
+            .expect("a whole packet")
+    }
+
+    fn qos(qos: QoS) -> u8 {
+        match qos {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+            QoS::ExactlyOnce => 2,
+        }
+    }
+
+    fn qos_pid(qospid: QosPid) -> (u8, u16) {
+        match qospid {
+            QosPid::AtMostOnce => (0, 0),
+            QosPid::AtLeastOnce(pid) => (1, pid.get()),
+            QosPid::ExactlyOnce(pid) => (2, pid.get()),
+        }
+    }
+
+    fn return_code(code: ConnectReturnCode) -> u8 {
+        match code {
+            ConnectReturnCode::Accepted => 0,
+            ConnectReturnCode::RefusedProtocolVersion => 1,
+            ConnectReturnCode::RefusedIdentifierRejected => 2,
+            ConnectReturnCode::ServerUnavailable => 3,
+            ConnectReturnCode::BadUsernamePassword => 4,
+            ConnectReturnCode::NotAuthorized => 5,
+        }
+    }
+
+    /// Panic unless mqttrs decoded the same packet sake encoded
+    fn check_request(request: &Request, packet: &Packet) {
+        match (request, packet) {
+            (
+                Request::Connect {
+                    client_id,
+                    clean_session,
+                    username,
+                    password,
+                    keepalive,
+                    will,
+                },
+                Packet::Connect(connect),
+            ) => {
+                assert_eq!(connect.client_id, client_id);
+                assert_eq!(connect.clean_session, *clean_session);
+                assert_eq!(connect.keep_alive, *keepalive);
+                assert_eq!(connect.username, username.as_deref());
+                assert_eq!(connect.password, password.as_ref().map(|p| p.as_bytes()));
+                match (will, &connect.last_will) {
+                    (None, None) => {}
+                    (Some(will), Some(last_will)) => {
+                        assert_eq!(last_will.topic, will.topic);
+                        assert_eq!(last_will.message, will.message);
+                        assert_eq!(qos(last_will.qos), u8::from(&will.qos));
+                        assert_eq!(last_will.retain, will.retain);
+                    }
+                    (will, last_will) => panic!("Will {:?} decoded as {:?}", will, last_will),
+                }
+            }
+            (
+                Request::Publish {
+                    packet_id,
+                    qos,
+                    dup,
+                    retain,
+                    topic,
+                    payload,
+                },
+                Packet::Publish(publish),
+            ) => {
+                assert_eq!(qos_pid(publish.qospid), (*qos, *packet_id));
+                assert_eq!((publish.dup, publish.retain), (*dup, *retain));
+                assert_eq!(publish.topic_name, topic);
+                assert_eq!(publish.payload, payload.as_slice());
+            }
+            (Request::Puback { packet_id }, Packet::Puback(pid))
+            | (Request::Pubrec { packet_id }, Packet::Pubrec(pid))
+            | (Request::Pubrel { packet_id }, Packet::Pubrel(pid))
+            | (Request::Pubcomp { packet_id }, Packet::Pubcomp(pid)) => {
+                assert_eq!(pid.get(), *packet_id)
+            }
+            (
+                Request::Subscribe {
+                    packet_id,
+                    subscription_topics,
+                },
+                Packet::Subscribe(subscribe),
+            ) => {
+                assert_eq!(subscribe.pid.get(), *packet_id);
+                let topics: Vec<(&str, u8)> = subscribe
+                    .topics
+                    .iter()
+                    .map(|t| (t.topic_path.as_str(), qos(t.qos)))
+                    .collect();
+                let expected: Vec<(&str, u8)> = subscription_topics
+                    .iter()
+                    .map(|s| (s.topic.as_str(), u8::from(&s.qos)))
+                    .collect();
+                assert_eq!(topics, expected);
+            }
+            (Request::Pingreq, Packet::Pingreq)
+            | (Request::Disconnect { .. }, Packet::Disconnect) => {}
+            (request, packet) => panic!("{:?} decoded as {:?}", request, packet),
+        }
+    }
+
+    /// Panic unless a packet decoded by mqttrs and one decoded by sake are
+    /// the broker packet sake encoded
+    fn check_server_packet(sent: &ServerPacket, packet: &Packet, response: &Response) {
+        match (sent, packet, response) {
+            (
+                ServerPacket::Connack {
+                    session_present,
+                    return_code: code,
+                },
+                Packet::Connack(connack),
+                Response::Connack {
+                    session_present: present,
+                    return_code: returned,
+                },
+            ) => {
+                assert_eq!(
+                    (connack.session_present, *present),
+                    (*session_present, *session_present)
+                );
+                assert_eq!((return_code(connack.code), *returned), (*code, *code));
+            }
+            (
+                ServerPacket::Publish {
+                    packet_id,
+                    dup,
+                    message,
+                },
+                Packet::Publish(publish),
+                Response::Publish {
+                    packet_id: id,
+                    qos,
+                    retain,
+                    topic,
+                    payload,
+                    ..
+                },
+            ) => {
+                assert_eq!(qos_pid(publish.qospid), (message.qos, *packet_id));
+                assert_eq!((publish.dup, publish.retain), (*dup, message.retain));
+                assert_eq!(publish.topic_name, message.topic);
+                assert_eq!(publish.payload, message.payload.as_slice());
+                assert_eq!(
+                    (*qos, *id, *retain),
+                    (message.qos, *packet_id, message.retain)
+                );
+                assert_eq!((topic, payload), (&message.topic, &message.payload));
+            }
+            (ServerPacket::Puback(sent), Packet::Puback(pid), Response::Puback { packet_id })
+            | (ServerPacket::Pubrec(sent), Packet::Pubrec(pid), Response::Pubrec { packet_id })
+            | (ServerPacket::Pubrel(sent), Packet::Pubrel(pid), Response::Pubrel { packet_id })
+            | (
+                ServerPacket::Pubcomp(sent),
+                Packet::Pubcomp(pid),
+                Response::Pubcomp { packet_id },
+            ) => {
+                assert_eq!((pid.get(), *packet_id), (*sent, *sent))
+            }
+            (
+                ServerPacket::Suback {
+                    packet_id,
+                    return_codes,
+                },
+                Packet::Suback(suback),
+                Response::Suback {
+                    packet_id: id,
+                    return_codes: codes,
+                },
+            ) => {
+                let decoded: Vec<u8> = suback
+                    .return_codes
+                    .iter()
+                    .map(|code| match code {
+                        SubscribeReturnCodes::Success(granted) => qos(*granted),
+                        SubscribeReturnCodes::Failure => 0x80,
+                    })
+                    .collect();
+                assert_eq!((suback.pid.get(), *id), (*packet_id, *packet_id));
+                assert_eq!((&decoded, codes), (return_codes, return_codes));
+            }
+            (
+                ServerPacket::Unsuback(sent),
+                Packet::Unsuback(pid),
+                Response::Unknown { packet_type, body },
+            ) => {
+                assert_eq!(pid.get(), *sent);
+                assert_eq!(
+                    (*packet_type, body.as_slice()),
+                    (0x0B, &sent.to_be_bytes()[..])
+                );
+            }
+            (ServerPacket::Pingresp, Packet::Pingresp, Response::Pingresp) => {}
+            (sent, packet, response) => {
+                panic!("{:?} decoded as {:?} and {}", sent, packet, response)
+            }
+        }
+    }
+
+    #[test]
+    fn test_requests() {
+        let seed = seed();
+        let mut rng = Rng::new(seed);
+        for round in 0..rounds() {
+            let request = request(&mut rng);
+            let bytes = request.to_bytes().unwrap();
+            let packet = decode(&bytes);
+            check_request(&request, &packet);
+            // Both encode the same packet the same way
+            assert_eq!(encode(&packet), bytes, "seed {} round {}", seed, round);
+        }
+    }
+
+    #[test]
+    fn test_broker_decodes_mqttrs() {
+        let seed = seed();
+        let mut rng = Rng::new(seed);
+        for round in 0..rounds() {
+            let request = request(&mut rng);
+            let bytes = encode(&decode(&request.to_bytes().unwrap()));
+            let decoded = ClientPacket::from_slice(&bytes).unwrap();
+            let same = match (&request, &decoded) {
+                (
+                    Request::Connect {
+                        client_id,
+                        username,
+                        password,
+                        will,
+                        ..
+                    },
+                    ClientPacket::Connect {
+                        client_id: id,
+                        username: user,
+                        password: pass,
+                        will: last_will,
+                        ..
+                    },
+                ) => {
+                    client_id == id
+                        && username == user
+                        && password.as_ref().map(|p| p.as_bytes()) == pass.as_deref()
+                        && will.as_ref().map(|w| (&w.topic, &w.message))
+                            == last_will.as_ref().map(|w| (&w.topic, &w.payload))
+                }
+                (
+                    Request::Publish { topic, payload, .. },
+                    ClientPacket::Publish { message, .. },
+                ) => (topic, payload) == (&message.topic, &message.payload),
+                (Request::Puback { packet_id }, ClientPacket::Puback(id))
+                | (Request::Pubrec { packet_id }, ClientPacket::Pubrec(id))
+                | (Request::Pubrel { packet_id }, ClientPacket::Pubrel(id))
+                | (Request::Pubcomp { packet_id }, ClientPacket::Pubcomp(id)) => packet_id == id,
+                (
+                    Request::Subscribe {
+                        subscription_topics,
+                        ..
+                    },
+                    ClientPacket::Subscribe { filters, .. },
+                ) => subscription_topics
+                    .iter()
+                    .map(|s| (s.topic.clone(), u8::from(&s.qos)))
+                    .eq(filters.iter().cloned()),
+                (Request::Pingreq, ClientPacket::Pingreq)
+                | (Request::Disconnect { .. }, ClientPacket::Disconnect) => true,
+                _ => false,
+            };
+            assert!(
+                same,
+                "seed {} round {}: {:?} decoded as {:?}",
+                seed, round, request, decoded
+            );
+        }
+    }
+
+    #[test]
+    fn test_server_packets() {
+        let seed = seed();
+        let mut rng = Rng::new(seed);
+        for round in 0..rounds() {
+            let sent = server_packet(&mut rng);
+            let bytes = sent.to_bytes().unwrap();
+            let packet = decode(&bytes);
+            let response = Response::from_slice(&bytes).unwrap();
+            check_server_packet(&sent, &packet, &response);
+            assert_eq!(encode(&packet), bytes, "seed {} round {}", seed, round);
+        }
+    }
+}
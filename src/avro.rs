@@ -0,0 +1,582 @@
+use crate::json::{self, Value};
+use std::collections::HashMap;
+use std::io;
+
+/// First byte of payloads framed as Confluent serializers do, followed by
+/// the schema id in 4 bytes big endian and the Avro data
+pub const MAGIC_BYTE: u8 = 0;
+
+/// Nesting accepted in schemas and data, bounding recursion on hostile input
+const MAX_DEPTH: usize = 64;
+
+fn invalid_schema(what: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid Avro schema: {}", what),
+    )
+}
+
+fn invalid_data(what: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid Avro data: {}", what),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Schema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record(Vec<Field>),
+    Enum(Vec<String>),
+    Array(Box<Schema>),
+    Map(Box<Schema>),
+    Union(Vec<Schema>),
+    Fixed(usize),
+    /// A named type, by full name, possibly the record being defined
+    Ref(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    name: String,
+    schema: Schema,
+    default: Option<Value>,
+}
+
+/// An Avro schema parsed from its JSON form, decoding and encoding the
+/// binary encoding of its data from and to JSON. Unions are decoded as
+/// the value of their branch and encoded in the first branch the value
+/// fits. Bytes and fixed are strings of code points up to 255, as in the
+/// Avro JSON encoding, and logical types are left to their underlying type
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvroSchema {
+    root: Schema,
+    named: HashMap<String, Schema>,
+}
+
+fn primitive(name: &str) -> Option<Schema> {
+    Some(match name {
+        "null" => Schema::Null,
+        "boolean" => Schema::Boolean,
+        "int" => Schema::Int,
+        "long" => Schema::Long,
+        "float" => Schema::Float,
+        "double" => Schema::Double,
+        "bytes" => Schema::Bytes,
+        "string" => Schema::String,
+        _ => return None,
+    })
+}
+
+fn full_name(name: &str, namespace: &str) -> String {
+    match name.contains('.') || namespace.is_empty() {
+        true => name.to_string(),
+        false => format!("{}.{}", namespace, name),
+    }
+}
+
+impl std::str::FromStr for AvroSchema {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut named = HashMap::new();
+        let root = parse_schema(&json::parse(s)?, "", &mut named, 0)?;
+        Ok(Self { root, named })
+    }
+}
+
+fn parse_schema(
+    value: &Value,
+    namespace: &str,
+    named: &mut HashMap<String, Schema>,
+    depth: usize,
+) -> io::Result<Schema> {
+    if depth > MAX_DEPTH {
+        return Err(invalid_schema("nested too deeply"));
+    }
+    let type_name = match value {
+        Value::String(name) => name,
+        Value::Array(branches) => {
+            let branches = branches
+                .iter()
+                .map(|branch| parse_schema(branch, namespace, named, depth + 1))
+                .collect::<io::Result<_>>()?;
+            return Ok(Schema::Union(branches));
+        }
+        Value::Object(_) => match value.get("type") {
+            Some(Value::String(name)) => name,
+            Some(nested) => return parse_schema(nested, namespace, named, depth + 1),
+            None => return Err(invalid_schema("missing type")),
+        },
+        value => return Err(invalid_schema(format!("unexpected {}", value))),
+    };
+    if let Some(schema) = primitive(type_name) {
+        return Ok(schema);
+    }
+    let string = |key: &str| value.get(key).and_then(Value::as_str);
+    match (value, type_name.as_str()) {
+        (Value::Object(_), "record" | "error" | "enum" | "fixed") => {
+            let name = string("name").ok_or_else(|| invalid_schema("named type without name"))?;
+            let namespace = string("namespace").unwrap_or(namespace);
+            let name = full_name(name, namespace);
+            // The type's own namespace applies to its fields
+            let namespace = name.rsplit_once('.').map_or("", |(namespace, _)| namespace);
+            // Known before the fields are parsed, which may refer to it
+            named.insert(name.clone(), Schema::Null);
+            let schema = match type_name.as_str() {
+                "enum" => match value.get("symbols") {
+                    Some(Value::Array(symbols)) => Schema::Enum(
+                        symbols
+                            .iter()
+                            .map(|symbol| symbol.as_str().map(str::to_string))
+                            .collect::<Option<_>>()
+                            .ok_or_else(|| invalid_schema("enum symbols must be strings"))?,
+                    ),
+                    _ => return Err(invalid_schema("enum without symbols")),
+                },
+                "fixed" => match value.get("size").and_then(Value::as_f64) {
+                    Some(size) if size >= 0.0 && size.fract() == 0.0 => {
+                        Schema::Fixed(size as usize)
+                    }
+                    _ => return Err(invalid_schema("fixed without size")),
+                },
+                _ => match value.get("fields") {
+                    Some(Value::Array(fields)) => Schema::Record(
+                        fields
+                            .iter()
+                            .map(|field| {
+                                let name = field
+                                    .get("name")
+                                    .and_then(Value::as_str)
+                                    .ok_or_else(|| invalid_schema("field without name"))?;
+                                let schema = field
+                                    .get("type")
+                                    .ok_or_else(|| invalid_schema("field without type"))?;
+                                Ok(Field {
+                                    name: name.to_string(),
+                                    schema: parse_schema(schema, namespace, named, depth + 1)?,
+                                    default: field.get("default").cloned(),
+                                })
+                            })
+                            .collect::<io::Result<_>>()?,
+                    ),
+                    _ => return Err(invalid_schema("record without fields")),
+                },
+            };
+            named.insert(name.clone(), schema);
+            Ok(Schema::Ref(name))
+        }
+        (Value::Object(_), "array") => {
+            let items = value
+                .get("items")
+                .ok_or_else(|| invalid_schema("array without items"))?;
+            Ok(Schema::Array(Box::new(parse_schema(
+                items,
+                namespace,
+                named,
+                depth + 1,
+            )?)))
+        }
+        (Value::Object(_), "map") => {
+            let values = value
+                .get("values")
+                .ok_or_else(|| invalid_schema("map without values"))?;
+            Ok(Schema::Map(Box::new(parse_schema(
+                values,
+                namespace,
+                named,
+                depth + 1,
+            )?)))
+        }
+        (_, name) => [full_name(name, namespace), name.to_string()]
+            .into_iter()
+            .find(|name| named.contains_key(name))
+            .map(Schema::Ref)
+            .ok_or_else(|| invalid_schema(format!("unknown type {}", name))),
+    }
+}
+
+impl AvroSchema {
+    fn resolve<'a>(&'a self, schema: &'a Schema) -> &'a Schema {
+        match schema {
+            Schema::Ref(name) => &self.named[name],
+            schema => schema,
+        }
+    }
+
+    /// Decode `data`, which must hold a single datum
+    pub fn decode(&self, data: &[u8]) -> io::Result<Value> {
+        let mut reader = Reader { data, pos: 0 };
+        let value = self.read(&self.root, &mut reader, 0)?;
+        match reader.pos == data.len() {
+            true => Ok(value),
+            false => Err(invalid_data("trailing bytes")),
+        }
+    }
+
+    fn read(&self, schema: &Schema, reader: &mut Reader, depth: usize) -> io::Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid_data("nested too deeply"));
+        }
+        Ok(match self.resolve(schema) {
+            Schema::Null => Value::Null,
+            Schema::Boolean => match reader.take(1)?[0] {
+                0 => Value::Bool(false),
+                1 => Value::Bool(true),
+                byte => return Err(invalid_data(format!("boolean {}", byte))),
+            },
+            Schema::Int | Schema::Long => Value::Number(reader.long()? as f64),
+            Schema::Float => {
+                Value::Number(f32::from_le_bytes(reader.take(4)?.try_into().unwrap()) as f64)
+            }
+            Schema::Double => {
+                Value::Number(f64::from_le_bytes(reader.take(8)?.try_into().unwrap()))
+            }
+            Schema::Bytes => {
+                let len = reader.len()?;
+                Value::String(reader.take(len)?.iter().map(|&b| b as char).collect())
+            }
+            Schema::Fixed(size) => {
+                Value::String(reader.take(*size)?.iter().map(|&b| b as char).collect())
+            }
+            Schema::String => {
+                let len = reader.len()?;
+                let bytes = reader.take(len)?;
+                Value::String(
+                    String::from_utf8(bytes.to_vec())
+                        .map_err(|_| invalid_data("string not UTF-8"))?,
+                )
+            }
+            Schema::Record(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|field| {
+                        Ok((
+                            field.name.clone(),
+                            self.read(&field.schema, reader, depth + 1)?,
+                        ))
+                    })
+                    .collect::<io::Result<_>>()?,
+            ),
+            Schema::Enum(symbols) => {
+                let index = reader.long()?;
+                let symbol = usize::try_from(index)
+                    .ok()
+                    .and_then(|index| symbols.get(index))
+                    .ok_or_else(|| invalid_data(format!("enum index {}", index)))?;
+                Value::String(symbol.clone())
+            }
+            Schema::Array(items) => {
+                let mut values = vec![];
+                while let Some(count) = reader.block()? {
+                    for _ in 0..count {
+                        values.push(self.read(items, reader, depth + 1)?);
+                    }
+                }
+                Value::Array(values)
+            }
+            Schema::Map(values) => {
+                let mut fields = vec![];
+                while let Some(count) = reader.block()? {
+                    for _ in 0..count {
+                        let Value::String(key) = self.read(&Schema::String, reader, depth)? else {
+                            unreachable!("strings decode as strings");
+                        };
+                        fields.push((key, self.read(values, reader, depth + 1)?));
+                    }
+                }
+                Value::Object(fields)
+            }
+            Schema::Union(branches) => {
+                let index = reader.long()?;
+                let branch = usize::try_from(index)
+                    .ok()
+                    .and_then(|index| branches.get(index))
+                    .ok_or_else(|| invalid_data(format!("union index {}", index)))?;
+                self.read(branch, reader, depth + 1)?
+            }
+            Schema::Ref(_) => unreachable!("references are resolved"),
+        })
+    }
+
+    /// Encode `value`, failing with `InvalidData` if it doesn't fit the schema
+    pub fn encode(&self, value: &Value) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        self.write(&self.root, value, &mut out, 0)?;
+        Ok(out)
+    }
+
+    fn write(
+        &self,
+        schema: &Schema,
+        value: &Value,
+        out: &mut Vec<u8>,
+        depth: usize,
+    ) -> io::Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(invalid_data("nested too deeply"));
+        }
+        let mismatch = || invalid_data(format!("{} doesn't fit {:?}", value, schema));
+        match (self.resolve(schema), value) {
+            (Schema::Null, Value::Null) => {}
+            (Schema::Boolean, Value::Bool(b)) => out.push(*b as u8),
+            (Schema::Int, Value::Number(n)) => {
+                match integer(*n).filter(|n| i32::try_from(*n).is_ok()) {
+                    Some(n) => write_long(n, out),
+                    None => return Err(mismatch()),
+                }
+            }
+            (Schema::Long, Value::Number(n)) => write_long(integer(*n).ok_or_else(mismatch)?, out),
+            (Schema::Float, Value::Number(n)) => out.extend_from_slice(&(*n as f32).to_le_bytes()),
+            (Schema::Double, Value::Number(n)) => out.extend_from_slice(&n.to_le_bytes()),
+            (Schema::Bytes, Value::String(s)) => {
+                let bytes = latin1(s).ok_or_else(mismatch)?;
+                write_long(bytes.len() as i64, out);
+                out.extend_from_slice(&bytes);
+            }
+            (Schema::Fixed(size), Value::String(s)) => match latin1(s) {
+                Some(bytes) if bytes.len() == *size => out.extend_from_slice(&bytes),
+                _ => return Err(mismatch()),
+            },
+            (Schema::String, Value::String(s)) => {
+                write_long(s.len() as i64, out);
+                out.extend_from_slice(s.as_bytes());
+            }
+            (Schema::Record(fields), Value::Object(_)) => {
+                for field in fields {
+                    let value = value
+                        .get(&field.name)
+                        .or(field.default.as_ref())
+                        .ok_or_else(|| invalid_data(format!("missing field {}", field.name)))?;
+                    self.write(&field.schema, value, out, depth + 1)?;
+                }
+            }
+            (Schema::Enum(symbols), Value::String(s)) => {
+                let index = symbols
+                    .iter()
+                    .position(|symbol| symbol == s)
+                    .ok_or_else(mismatch)?;
+                write_long(index as i64, out);
+            }
+            (Schema::Array(items), Value::Array(values)) => {
+                if !values.is_empty() {
+                    write_long(values.len() as i64, out);
+                    for value in values {
+                        self.write(items, value, out, depth + 1)?;
+                    }
+                }
+                out.push(0);
+            }
+            (Schema::Map(schema), Value::Object(fields)) => {
+                if !fields.is_empty() {
+                    write_long(fields.len() as i64, out);
+                    for (key, value) in fields {
+                        write_long(key.len() as i64, out);
+                        out.extend_from_slice(key.as_bytes());
+                        self.write(schema, value, out, depth + 1)?;
+                    }
+                }
+                out.push(0);
+            }
+            (Schema::Union(branches), value) => {
+                let index = branches
+                    .iter()
+                    .position(|branch| self.fits(branch, value))
+                    .ok_or_else(mismatch)?;
+                write_long(index as i64, out);
+                self.write(&branches[index], value, out, depth + 1)?;
+            }
+            _ => return Err(mismatch()),
+        }
+        Ok(())
+    }
+
+    /// Whether `value` has the JSON type of `schema`, to pick a union branch
+    fn fits(&self, schema: &Schema, value: &Value) -> bool {
+        match (self.resolve(schema), value) {
+            (Schema::Null, Value::Null) | (Schema::Boolean, Value::Bool(_)) => true,
+            (Schema::Int, Value::Number(n)) => {
+                integer(*n).is_some_and(|n| i32::try_from(n).is_ok())
+            }
+            (Schema::Long, Value::Number(n)) => integer(*n).is_some(),
+            (Schema::Float | Schema::Double, Value::Number(_)) => true,
+            (Schema::String, Value::String(_)) => true,
+            (Schema::Bytes, Value::String(s)) => latin1(s).is_some(),
+            (Schema::Fixed(size), Value::String(s)) => latin1(s).is_some_and(|b| b.len() == *size),
+            (Schema::Enum(symbols), Value::String(s)) => symbols.contains(s),
+            (Schema::Record(_) | Schema::Map(_), Value::Object(_)) => true,
+            (Schema::Array(_), Value::Array(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+fn integer(n: f64) -> Option<i64> {
+    (n.fract() == 0.0 && n.abs() < 9_223_372_036_854_775_808.0).then_some(n as i64)
+}
+
+/// Bytes of a string made of code points up to 255
+fn latin1(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+/// Zigzag variable length encoding of ints and longs
+fn write_long(n: i64, out: &mut Vec<u8>) {
+    let mut n = ((n << 1) ^ (n >> 63)) as u64;
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| invalid_data("unexpected end"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn long(&mut self) -> io::Result<i64> {
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            n |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((n >> 1) as i64 ^ -((n & 1) as i64));
+            }
+        }
+        Err(invalid_data("long over 10 bytes"))
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        let len = self.long()?;
+        usize::try_from(len).map_err(|_| invalid_data(format!("length {}", len)))
+    }
+
+    /// Item count of the next block of an array or map, `None` at the end.
+    /// Negative counts are followed by the size of the block, skipped here
+    fn block(&mut self) -> io::Result<Option<u64>> {
+        match self.long()? {
+            0 => Ok(None),
+            count if count < 0 => {
+                self.long()?;
+                Ok(Some(count.unsigned_abs()))
+            }
+            count => Ok(Some(count as u64)),
+        }
+    }
+}
+
+/// Schema id and Avro data of a payload framed with `MAGIC_BYTE`, `None`
+/// for other payloads
+pub fn unframe(payload: &[u8]) -> Option<(u32, &[u8])> {
+    match payload {
+        [MAGIC_BYTE, a, b, c, d, data @ ..] => Some((u32::from_be_bytes([*a, *b, *c, *d]), data)),
+        _ => None,
+    }
+}
+
+/// Frame Avro data as Confluent serializers do, see `MAGIC_BYTE`
+pub fn frame(schema_id: u32, data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(data.len() + 5);
+    payload.push(MAGIC_BYTE);
+    payload.extend_from_slice(&schema_id.to_be_bytes());
+    payload.extend_from_slice(data);
+    payload
+}
+
+#[cfg(test)]
+mod avro_tests {
+    use super::*;
+
+    const READING: &str = r#"{
+        "type": "record", "name": "Reading", "namespace": "plant",
+        "fields": [
+            {"name": "device", "type": "string"},
+            {"name": "temp", "type": "double"},
+            {"name": "count", "type": "long"},
+            {"name": "state", "type": {"type": "enum", "name": "State", "symbols": ["OFF", "ON"]}},
+            {"name": "tags", "type": {"type": "map", "values": "int"}},
+            {"name": "note", "type": ["null", "string"], "default": null},
+            {"name": "next", "type": ["null", "Reading"]}
+        ]
+    }"#;
+
+    #[test]
+    fn test_decode() -> io::Result<()> {
+        let schema: AvroSchema = READING.parse()?;
+        let mut data = vec![6, b'p', b'-', b'7'];
+        data.extend_from_slice(&21.5f64.to_le_bytes());
+        // count -3, state ON, a map block of 1 entry, note null
+        data.extend_from_slice(&[5, 2, 2, 2, b'a', 0x80, 0x01, 0, 0]);
+        // A nested reading without tags nor next
+        data.extend_from_slice(&[2, 2, b'q']);
+        data.extend_from_slice(&0f64.to_le_bytes());
+        data.extend_from_slice(&[0, 0, 0, 2, 2, b'x', 0]);
+        let value = schema.decode(&data)?;
+        assert_eq!(
+            value.to_string(),
+            r#"{"device":"p-7","temp":21.5,"count":-3,"state":"ON","tags":{"a":64},"note":null,"next":{"device":"q","temp":0,"count":0,"state":"OFF","tags":{},"note":"x","next":null}}"#
+        );
+        // Encoding the decoded value gives the same bytes back
+        assert_eq!(schema.encode(&value)?, data);
+        assert!(schema.decode(&data[..data.len() - 1]).is_err());
+        assert!(schema.decode(&[data.clone(), vec![0]].concat()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode() -> io::Result<()> {
+        let schema: AvroSchema = READING.parse()?;
+        let value = json::parse(
+            r#"{"device": "p", "temp": 1, "count": 300, "state": "OFF", "tags": {}, "next": null}"#,
+        )?;
+        // The note takes its default
+        let data = schema.encode(&value)?;
+        assert_eq!(data[..2], [2, b'p']);
+        assert_eq!(data[10..], [0xD8, 0x04, 0, 0, 0, 0]);
+        let missing = json::parse(r#"{"device": "p"}"#)?;
+        assert!(schema.encode(&missing).is_err());
+        let wrong = json::parse(
+            r#"{"device": "p", "temp": 1, "count": 1.5, "state": "OFF", "tags": {}, "next": null}"#,
+        )?;
+        assert_eq!(
+            schema.encode(&wrong).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let bytes: AvroSchema = r#"{"type": "fixed", "name": "Id", "size": 2}"#.parse()?;
+        assert_eq!(
+            bytes.encode(&Value::String("\u{ff}a".into()))?,
+            [0xFF, b'a']
+        );
+        assert!("\"Unknown\"".parse::<AvroSchema>().is_err());
+        assert!(r#"{"type": "record", "name": "R"}"#.parse::<AvroSchema>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_frame() {
+        let payload = frame(258, &[1, 2]);
+        assert_eq!(payload, [0, 0, 0, 1, 2, 1, 2]);
+        assert_eq!(unframe(&payload), Some((258, &[1, 2][..])));
+        assert_eq!(unframe(b"{\"a\": 1}"), None);
+        assert_eq!(unframe(&[0, 0, 0]), None);
+    }
+}
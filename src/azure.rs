@@ -0,0 +1,161 @@
+use crate::encoding::{base64_decode, base64_encode};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// API version advertised in the username, as required by IoT Hub
+pub const API_VERSION: &str = "2021-04-12";
+const HOST_SUFFIX: &str = "azure-devices.net";
+
+/// Connection preset for an Azure IoT Hub device using a symmetric key
+#[derive(Debug, Clone)]
+pub struct AzurePreset {
+    pub hub: String,
+    pub device_id: String,
+    /// Base64 encoded device primary or secondary key
+    pub key: String,
+}
+
+/// A SAS token and the time it stops being accepted by the hub
+#[derive(Debug, Clone, PartialEq)]
+pub struct SasToken {
+    pub token: String,
+    pub expires_at: SystemTime,
+}
+
+impl SasToken {
+    /// Whether the token expires within `margin`, a new one should be
+    /// generated and the client reconnected before that happens
+    pub fn needs_refresh(&self, margin: Duration) -> bool {
+        SystemTime::now() + margin >= self.expires_at
+    }
+}
+
+impl AzurePreset {
+    pub fn new(hub: &str, device_id: &str, key: &str) -> Self {
+        // Accept both the short hub name and the full host name
+        let hub = hub.trim_end_matches(&format!(".{}", HOST_SUFFIX));
+        Self {
+            hub: hub.to_string(),
+            device_id: device_id.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    pub fn hostname(&self) -> String {
+        format!("{}.{}", self.hub, HOST_SUFFIX)
+    }
+
+    /// IoT Hub requires the client id to be the device id
+    pub fn client_id(&self) -> &str {
+        &self.device_id
+    }
+
+    pub fn username(&self) -> String {
+        format!(
+            "{}/{}/?api-version={}",
+            self.hostname(),
+            self.device_id,
+            API_VERSION
+        )
+    }
+
+    /// Generates a SAS token valid for `ttl`, to be used as password
+    pub fn password(&self, ttl: Duration) -> io::Result<SasToken> {
+        let expires_at = SystemTime::now() + ttl;
+        let expiry = expires_at
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .as_secs();
+        let resource = format!("{}/devices/{}", self.hostname(), self.device_id);
+        Ok(SasToken {
+            token: sas_token(&resource, &self.key, expiry)?,
+            expires_at,
+        })
+    }
+}
+
+/// Builds a shared access signature for `resource_uri`, signing the URL
+/// encoded resource and the expiry with the base64 decoded key
+pub fn sas_token(resource_uri: &str, key: &str, expiry: u64) -> io::Result<String> {
+    let resource = url_encode(resource_uri);
+    let key = base64_decode(key)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid base64 key"))?;
+    let to_sign = format!("{}\n{}", resource, expiry);
+    let signature = base64_encode(&hmac_sha256(&key, to_sign.as_bytes()));
+    Ok(format!(
+        "SharedAccessSignature sr={}&sig={}&se={}",
+        resource,
+        url_encode(&signature),
+        expiry
+    ))
+}
+
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod azure_tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_preset() {
+        let preset = AzurePreset::new("myhub.azure-devices.net", "dev1", "c2FrZQ==");
+        assert_eq!(preset.client_id(), "dev1");
+        assert_eq!(
+            preset.username(),
+            "myhub.azure-devices.net/dev1/?api-version=2021-04-12"
+        );
+    }
+
+    #[test]
+    fn test_sas_token() -> io::Result<()> {
+        let token = sas_token(
+            "myhub.azure-devices.net/devices/dev1",
+            "c2FrZQ==",
+            1700000000,
+        )?;
+        assert!(token
+            .starts_with("SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Fdev1&sig="));
+        assert!(token.ends_with("&se=1700000000"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_needs_refresh() {
+        let token = SasToken {
+            token: String::new(),
+            expires_at: SystemTime::now() + Duration::from_secs(60),
+        };
+        assert!(!token.needs_refresh(Duration::from_secs(10)));
+        assert!(token.needs_refresh(Duration::from_secs(120)));
+    }
+}
@@ -0,0 +1,371 @@
+use crate::bench::Latencies;
+use crate::csv;
+use crate::json::{self, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the exported reports, bumped whenever a field changes
+/// meaning or goes away. Adding one doesn't
+pub const SCHEMA: u32 = 1;
+
+/// A bench report along with what it ran against, in a stable schema. The
+/// JSON form nests the config and latencies, the CSV form is a header and a
+/// single row with dotted column names, to append runs to
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub bench: &'static str,
+    pub started: Option<SystemTime>,
+    /// First broker address a connection was opened to
+    pub broker: Option<SocketAddr>,
+    pub config: Vec<(&'static str, Value)>,
+    pub results: Vec<(&'static str, Value)>,
+    /// Failures by error kind or return code, a single `kind=count;...`
+    /// column in CSV as they vary from run to run
+    pub errors: BTreeMap<String, usize>,
+}
+
+impl Export {
+    fn header(&self) -> Vec<(&'static str, Value)> {
+        let started = self.started.map_or(Value::Null, |started| {
+            let secs = started.duration_since(UNIX_EPOCH).unwrap_or_default();
+            Value::Number(secs.as_secs() as f64)
+        });
+        vec![
+            ("schema", Value::Number(SCHEMA as f64)),
+            ("bench", Value::String(self.bench.to_string())),
+            (
+                "version",
+                Value::String(env!("CARGO_PKG_VERSION").to_string()),
+            ),
+            ("started_at", started),
+            (
+                "broker",
+                self.broker
+                    .map_or(Value::Null, |addr| Value::String(addr.to_string())),
+            ),
+        ]
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut doc = own(&self.header());
+        doc.push(("config".to_string(), Value::Object(own(&self.config))));
+        doc.extend(own(&self.results));
+        let errors = self
+            .errors
+            .iter()
+            .map(|(kind, count)| (kind.clone(), Value::Number(*count as f64)))
+            .collect();
+        doc.push(("errors".to_string(), Value::Object(errors)));
+        Value::Object(doc).to_string()
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut columns = vec![];
+        flatten("", &Value::Object(own(&self.header())), &mut columns);
+        flatten("config", &Value::Object(own(&self.config)), &mut columns);
+        flatten("", &Value::Object(own(&self.results)), &mut columns);
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .map(|(kind, count)| format!("{}={}", kind, count))
+            .collect();
+        columns.push(("errors".to_string(), Value::String(errors.join(";"))));
+        let header: Vec<&str> = columns.iter().map(|(name, _)| name.as_str()).collect();
+        let row: Vec<String> = columns
+            .iter()
+            .map(|(_, value)| match value {
+                Value::Null => String::new(),
+                Value::String(s) => csv::field(s),
+                value => value.to_string(),
+            })
+            .collect();
+        format!("{}\n{}\n", header.join(","), row.join(","))
+    }
+}
+
+fn own(fields: &[(&str, Value)]) -> Vec<(String, Value)> {
+    fields
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect()
+}
+
+/// The leaves of `value` with their dotted path under `prefix`
+fn flatten(prefix: &str, value: &Value, out: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                let path = match prefix {
+                    "" => key.clone(),
+                    prefix => format!("{}.{}", prefix, key),
+                };
+                flatten(&path, value, out);
+            }
+        }
+        value => out.push((prefix.to_string(), value.clone())),
+    }
+}
+
+/// A latency summary, in microseconds and `null` without samples
+pub fn latencies(latencies: &Latencies) -> Value {
+    let names = ["min_us", "p50_us", "p90_us", "p99_us", "p99_9_us", "max_us"];
+    let summary = latencies.summary();
+    let mut fields = vec![("count".to_string(), Value::Number(latencies.len() as f64))];
+    for (i, name) in names.iter().enumerate() {
+        let value = summary.map_or(Value::Null, |s| Value::Number(s[i].as_micros() as f64));
+        fields.push((name.to_string(), value));
+    }
+    Value::Object(fields)
+}
+
+/// Change of a numeric field between two runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    pub field: String,
+    pub baseline: Option<f64>,
+    pub current: Option<f64>,
+}
+
+impl Delta {
+    /// Relative change in percent, `None` unless both runs have a non zero
+    /// baseline to compare with
+    pub fn change(&self) -> Option<f64> {
+        match (self.baseline, self.current) {
+            (Some(baseline), Some(current)) if baseline != 0.0 => {
+                Some((current - baseline) / baseline * 100.0)
+            }
+            _ => None,
+        }
+    }
+
+    /// How much worse the current run is in percent, for the fields where
+    /// lower or higher is better: latencies, durations, failures and
+    /// throughput. Failures appearing from none are infinitely worse
+    pub fn regression(&self) -> Option<f64> {
+        let lower_is_better = self.field.ends_with("_us") || self.field.starts_with("errors.");
+        let higher_is_better = self.field == "throughput";
+        let worse = match (lower_is_better, higher_is_better) {
+            (true, _) => match (self.baseline.unwrap_or(0.0), self.current.unwrap_or(0.0)) {
+                (baseline, current) if baseline == 0.0 && current > 0.0 => f64::INFINITY,
+                _ => self.change()?,
+            },
+            (_, true) => -self.change()?,
+            _ => return None,
+        };
+        (worse > 0.0).then_some(worse)
+    }
+}
+
+/// Field by field comparison of two runs of the same bench
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub bench: String,
+    pub deltas: Vec<Delta>,
+}
+
+impl Comparison {
+    /// The fields worse by more than `threshold` percent
+    pub fn regressions(&self, threshold: f64) -> Vec<&Delta> {
+        self.deltas
+            .iter()
+            .filter(|d| d.regression().is_some_and(|worse| worse > threshold))
+            .collect()
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.deltas.iter().map(|d| d.field.len()).max().unwrap_or(0);
+        let value = |v: Option<f64>| v.map_or("-".to_string(), |v| Value::Number(v).to_string());
+        write!(
+            f,
+            "{:<width$}  {:>14}  {:>14}  {:>9}",
+            self.bench,
+            "baseline",
+            "current",
+            "change",
+            width = width
+        )?;
+        for delta in &self.deltas {
+            let change = delta
+                .change()
+                .map_or(String::new(), |c| format!("{:+.2}%", c));
+            write!(
+                f,
+                "\n{:<width$}  {:>14}  {:>14}  {:>9}",
+                delta.field,
+                value(delta.baseline),
+                value(delta.current),
+                change,
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The bench name and the numeric fields of an exported JSON report, but
+/// for those saying when it ran
+fn numbers(doc: &str) -> io::Result<(String, Vec<(String, f64)>)> {
+    let doc = json::parse(doc)?;
+    let schema = doc.get("schema").and_then(|s| s.as_f64());
+    if schema != Some(SCHEMA as f64) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Unsupported report schema {:?}, expected {}",
+                schema, SCHEMA
+            ),
+        ));
+    }
+    let bench = doc
+        .get("bench")
+        .and_then(|b| b.as_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Report without a bench name"))?
+        .to_string();
+    let mut leaves = vec![];
+    flatten("", &doc, &mut leaves);
+    let numbers = leaves
+        .into_iter()
+        .filter(|(field, _)| field != "schema" && field != "started_at")
+        .filter_map(|(field, value)| Some((field, value.as_f64()?)))
+        .collect();
+    Ok((bench, numbers))
+}
+
+/// Compare two JSON reports, the fields of the baseline first then those
+/// only the current run has
+pub fn compare(baseline: &str, current: &str) -> io::Result<Comparison> {
+    let (bench, baseline) = numbers(baseline)?;
+    let (current_bench, mut current) = numbers(current)?;
+    if bench != current_bench {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Can't compare a {} report with a {} one",
+                bench, current_bench
+            ),
+        ));
+    }
+    let mut deltas = vec![];
+    for (field, value) in baseline {
+        let i = current.iter().position(|(f, _)| *f == field);
+        deltas.push(Delta {
+            current: i.map(|i| current.remove(i).1),
+            baseline: Some(value),
+            field,
+        });
+    }
+    deltas.extend(current.into_iter().map(|(field, value)| Delta {
+        field,
+        baseline: None,
+        current: Some(value),
+    }));
+    Ok(Comparison { bench, deltas })
+}
+
+#[cfg(test)]
+mod export_tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn export(p99: u64, errors: &[(&str, usize)]) -> Export {
+        let mut receive = Latencies::default();
+        receive.record(Duration::from_micros(10));
+        receive.record(Duration::from_micros(p99));
+        Export {
+            bench: "latency",
+            started: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            broker: Some("127.0.0.1:1883".parse().unwrap()),
+            config: vec![("topic", Value::String("a,b".to_string()))],
+            results: vec![
+                ("throughput", Value::Number(1000.5)),
+                ("receive", latencies(&receive)),
+            ],
+            errors: errors.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json = export(200, &[("TimedOut", 2)]).to_json();
+        assert!(json.starts_with("{\"schema\":1,\"bench\":\"latency\",\"version\":\"",));
+        assert!(json.ends_with(
+            "\"started_at\":1700000000,\"broker\":\"127.0.0.1:1883\",\
+             \"config\":{\"topic\":\"a,b\"},\"throughput\":1000.5,\
+             \"receive\":{\"count\":2,\"min_us\":10,\"p50_us\":10,\"p90_us\":200,\
+             \"p99_us\":200,\"p99_9_us\":200,\"max_us\":200},\"errors\":{\"TimedOut\":2}}"
+        ));
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let csv = export(200, &[("TimedOut", 2), ("Other", 1)]).to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "schema,bench,version,started_at,broker,config.topic,throughput,\
+             receive.count,receive.min_us,receive.p50_us,receive.p90_us,receive.p99_us,\
+             receive.p99_9_us,receive.max_us,errors"
+        );
+        assert!(lines[1].starts_with("1,latency,"));
+        assert!(lines[1].ends_with(
+            ",1700000000,127.0.0.1:1883,\"a,b\",1000.5,2,10,10,200,200,200,200,Other=1;TimedOut=2"
+        ));
+    }
+
+    #[test]
+    fn test_compare() -> io::Result<()> {
+        let baseline = export(200, &[]).to_json();
+        let current = export(300, &[("TimedOut", 1)]).to_json();
+        let comparison = compare(&baseline, &current)?;
+        let delta = |field: &str| comparison.deltas.iter().find(|d| d.field == field);
+        let p99 = delta("receive.p99_us").unwrap();
+        assert_eq!((p99.baseline, p99.current), (Some(200.0), Some(300.0)));
+        assert_eq!(p99.change(), Some(50.0));
+        assert_eq!(delta("receive.min_us").unwrap().regression(), None);
+        assert_eq!(delta("started_at"), None);
+        let errors = delta("errors.TimedOut").unwrap();
+        assert_eq!(errors.baseline, None);
+        assert_eq!(errors.regression(), Some(f64::INFINITY));
+        let regressed: Vec<&str> = comparison
+            .regressions(40.0)
+            .iter()
+            .map(|d| d.field.as_str())
+            .collect();
+        assert_eq!(
+            regressed,
+            [
+                "receive.p90_us",
+                "receive.p99_us",
+                "receive.p99_9_us",
+                "receive.max_us",
+                "errors.TimedOut"
+            ]
+        );
+        assert!(comparison.regressions(100.0).len() == 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_rejects_mismatches() {
+        let latency = export(200, &[]).to_json();
+        let storm = Export {
+            bench: "storm",
+            ..export(200, &[])
+        }
+        .to_json();
+        assert_eq!(
+            compare(&latency, &storm).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let old = latency.replacen("\"schema\":1", "\"schema\":0", 1);
+        assert_eq!(
+            compare(&old, &latency).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,1302 @@
+mod export;
+mod scenario;
+
+pub use export::{compare, Comparison, Delta, Export, SCHEMA};
+pub use scenario::{
+    run as run_scenario, Phase, PhaseReport, PublisherGroup, Scenario, ScenarioReport,
+    SubscriberGroup,
+};
+
+use crate::json::Value;
+use crate::mqtt::{
+    random_client_id, AckType, ConnectionRefused, Protocol, Qos, QuicUrl, Request, Response,
+    SUBACK_FAILURE,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// xorshift64* generator, enough for client ids and payloads, not for
+/// anything security related
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Zero is the one state xorshift never leaves
+        Self(seed.max(1))
+    }
+
+    /// Seeded from the clock and the process id, different on every run
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self::new(nanos ^ ((std::process::id() as u64) << 32))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+}
+
+/// Content of generated payloads, random bytes don't compress while zeros
+/// and incrementing bytes do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadKind {
+    Random,
+    Zeros,
+    /// Bytes counting up from the number of the message, wrapping at 255
+    Incrementing,
+}
+
+impl std::str::FromStr for PayloadKind {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(PayloadKind::Random),
+            "zeros" => Ok(PayloadKind::Zeros),
+            "incrementing" => Ok(PayloadKind::Incrementing),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown payload kind {}, expected random, zeros or incrementing",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PayloadKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PayloadKind::Random => "random",
+            PayloadKind::Zeros => "zeros",
+            PayloadKind::Incrementing => "incrementing",
+        })
+    }
+}
+
+/// Generates the payloads of a kind, the same sequence for the same seed so
+/// runs can be compared
+#[derive(Debug, Clone)]
+pub struct Payloads {
+    kind: PayloadKind,
+    rng: Rng,
+    count: u64,
+}
+
+impl Payloads {
+    pub fn new(kind: PayloadKind, seed: u64) -> Self {
+        Self {
+            kind,
+            rng: Rng::new(seed),
+            count: 0,
+        }
+    }
+
+    /// Overwrite `payload` with the next one
+    pub fn fill(&mut self, payload: &mut [u8]) {
+        match self.kind {
+            PayloadKind::Random => {
+                for chunk in payload.chunks_mut(8) {
+                    let bytes = self.rng.next_u64().to_le_bytes();
+                    chunk.copy_from_slice(&bytes[..chunk.len()]);
+                }
+            }
+            PayloadKind::Zeros => payload.fill(0),
+            PayloadKind::Incrementing => {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte = self.count.wrapping_add(i as u64) as u8;
+                }
+            }
+        }
+        self.count += 1;
+    }
+
+    pub fn next_payload(&mut self, size: usize) -> Vec<u8> {
+        let mut payload = vec![0; size];
+        self.fill(&mut payload);
+        payload
+    }
+}
+
+/// Linear buckets per power of two of a histogram, a percentile is off by
+/// at most 1/64th of its value
+const SUB_BUCKETS: u64 = 128;
+const HALF_BUCKETS: u64 = SUB_BUCKETS / 2;
+
+/// Bucket of a value, the values below `SUB_BUCKETS` have one each
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    // Keep the top bits, value >> shift is in HALF_BUCKETS..SUB_BUCKETS
+    let shift = 64 - value.leading_zeros() - SUB_BUCKETS.trailing_zeros();
+    (shift as u64 * HALF_BUCKETS + (value >> shift)) as usize
+}
+
+/// Highest value falling in a bucket
+fn bucket_value(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / HALF_BUCKETS - 1;
+    let top = (index - shift * HALF_BUCKETS + 1) as u128;
+    ((top << shift) - 1).min(u64::MAX as u128) as u64
+}
+
+/// Durations measured during a run, in an HDR style histogram of
+/// nanoseconds: log-linear buckets keep the memory constant however long
+/// the run, with a bounded relative error
+#[derive(Debug, Clone, Default)]
+pub struct Latencies {
+    counts: Vec<u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Latencies {
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        let index = bucket_index(nanos);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.min = if self.count == 0 {
+            nanos
+        } else {
+            self.min.min(nanos)
+        };
+        self.max = self.max.max(nanos);
+        self.count += 1;
+    }
+
+    /// Add the samples of another histogram, as recorded by another thread
+    pub fn merge(&mut self, other: &Latencies) {
+        if other.count == 0 {
+            return;
+        }
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.min = if self.count == 0 {
+            other.min
+        } else {
+            self.min.min(other.min)
+        };
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+    }
+
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The sample below which `p` percent of them fall, nearest rank. Exact
+    /// for the min and max, the highest value of its bucket otherwise
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        if rank == 1 {
+            return Some(Duration::from_nanos(self.min));
+        }
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let value = bucket_value(index).clamp(self.min, self.max);
+                return Some(Duration::from_nanos(value));
+            }
+        }
+        Some(Duration::from_nanos(self.max))
+    }
+
+    /// min, p50, p90, p99, p99.9 and max
+    pub fn summary(&self) -> Option<[Duration; 6]> {
+        Some([
+            self.percentile(0.0)?,
+            self.percentile(50.0)?,
+            self.percentile(90.0)?,
+            self.percentile(99.0)?,
+            self.percentile(99.9)?,
+            self.percentile(100.0)?,
+        ])
+    }
+}
+
+impl fmt::Display for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        match self.summary() {
+            Some([min, p50, p90, p99, p999, max]) => write!(
+                f,
+                "min {:.3} ms, p50 {:.3} ms, p90 {:.3} ms, p99 {:.3} ms, p99.9 {:.3} ms, max {:.3} ms",
+                ms(min),
+                ms(p50),
+                ms(p90),
+                ms(p99),
+                ms(p999),
+                ms(max)
+            ),
+            None => write!(f, "no samples"),
+        }
+    }
+}
+
+/// What a connection storm opens and how it tears it down, see `storm`
+#[derive(Debug, Clone)]
+pub struct StormOptions {
+    pub addrs: Vec<SocketAddr>,
+    pub connections: usize,
+    /// Connections being opened at the same time
+    pub concurrency: usize,
+    /// Followed by the connection number, `sake-storm-` and a random suffix
+    /// by default so that concurrent runs don't collide
+    pub client_id_prefix: String,
+    /// Random suffixes instead of sequential ones, so that runs never take
+    /// over each other's sessions
+    pub random_ids: bool,
+    /// Connections subscribing to `topic`, the first ones opened
+    pub subscribers: usize,
+    /// Connections publishing to `topic` every second while held, the ones
+    /// after the subscribers
+    pub publishers: usize,
+    pub topic: String,
+    /// How long every connection is held open once all are opened
+    pub hold: Duration,
+    /// Batches the connections are closed in
+    pub waves: usize,
+    pub wave_interval: Duration,
+    /// Bounds the TCP connection and the wait for each CONNACK and SUBACK
+    pub timeout: Duration,
+    /// Pause of the subscribers after each message read, to simulate slow
+    /// consumers and have the broker queue or drop
+    pub consume_delay: Duration,
+    /// Kernel receive buffer of every connection, the system default if
+    /// `None`
+    pub recv_buffer: Option<usize>,
+}
+
+impl Default for StormOptions {
+    fn default() -> Self {
+        Self {
+            addrs: vec![],
+            connections: 100,
+            concurrency: 16,
+            client_id_prefix: random_client_id("sake-storm"),
+            random_ids: false,
+            subscribers: 0,
+            publishers: 0,
+            topic: "sake/storm".to_string(),
+            hold: Duration::from_secs(10),
+            waves: 1,
+            wave_interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+            consume_delay: Duration::ZERO,
+            recv_buffer: None,
+        }
+    }
+}
+
+/// Outcome of a connection storm
+#[derive(Debug, Clone, Default)]
+pub struct StormReport {
+    pub options: StormOptions,
+    pub started: Option<SystemTime>,
+    /// Address of the first connection opened
+    pub broker: Option<SocketAddr>,
+    pub attempted: usize,
+    pub connected: usize,
+    /// CONNACK failures by return code
+    pub refused: BTreeMap<String, usize>,
+    /// Other failures to connect or subscribe, by error kind
+    pub errors: BTreeMap<String, usize>,
+    /// From the TCP connect to the CONNACK
+    pub connect: Latencies,
+    /// Time taken to open every connection
+    pub ramp_up: Duration,
+    pub published: usize,
+    /// Messages read by the subscribers while held
+    pub received: usize,
+    pub disconnected: usize,
+}
+
+impl StormReport {
+    fn rate(&self) -> f64 {
+        self.connected as f64 / self.ramp_up.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The report with its options, CONNACK return codes count as errors
+    pub fn export(&self) -> Export {
+        let o = &self.options;
+        let number = |n: usize| Value::Number(n as f64);
+        let ms = |d: Duration| Value::Number(d.as_millis() as f64);
+        let mut errors = self.errors.clone();
+        errors.extend(self.refused.clone());
+        Export {
+            bench: "storm",
+            started: self.started,
+            broker: self.broker,
+            config: vec![
+                ("connections", number(o.connections)),
+                ("concurrency", number(o.concurrency)),
+                ("random_ids", Value::Bool(o.random_ids)),
+                ("subscribers", number(o.subscribers)),
+                ("publishers", number(o.publishers)),
+                ("topic", Value::String(o.topic.clone())),
+                ("hold_ms", ms(o.hold)),
+                ("waves", number(o.waves)),
+                ("wave_interval_ms", ms(o.wave_interval)),
+                ("consume_delay_ms", ms(o.consume_delay)),
+                ("recv_buffer", o.recv_buffer.map_or(Value::Null, number)),
+            ],
+            results: vec![
+                ("attempted", number(self.attempted)),
+                ("connected", number(self.connected)),
+                ("ramp_up_us", Value::Number(self.ramp_up.as_micros() as f64)),
+                ("throughput", Value::Number(round(self.rate()))),
+                ("connect", export::latencies(&self.connect)),
+                ("published", number(self.published)),
+                ("received", number(self.received)),
+                ("disconnected", number(self.disconnected)),
+            ],
+            errors,
+        }
+    }
+}
+
+/// Rounded to 3 decimals, the rest is noise in a report
+fn round(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+impl fmt::Display for StormReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Connections {} attempted, {} connected in {:.2} s ({:.1}/s)",
+            self.attempted,
+            self.connected,
+            self.ramp_up.as_secs_f64(),
+            self.rate()
+        )?;
+        writeln!(f, "Connect     {}", self.connect)?;
+        for (return_code, count) in &self.refused {
+            writeln!(f, "Refused     {}: {}", return_code, count)?;
+        }
+        for (kind, count) in &self.errors {
+            writeln!(f, "Failed      {}: {}", kind, count)?;
+        }
+        writeln!(f, "Published   {}", self.published)?;
+        writeln!(f, "Received    {}", self.received)?;
+        write!(f, "Closed      {}", self.disconnected)
+    }
+}
+
+/// Open a connection and wait for its CONNACK, without the logging of
+/// `ProtocolBuilder::connect` which would drown the report
+fn open(
+    addrs: &[SocketAddr],
+    client_id: &str,
+    timeout: Duration,
+    recv_buffer: Option<usize>,
+) -> io::Result<Protocol> {
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+    for addr in addrs {
+        let stream = match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => stream,
+            Err(e) => {
+                last_err = e;
+                continue;
+            }
+        };
+        // Measured latencies shouldn't include Nagle's delay
+        stream.set_nodelay(true)?;
+        let client = Protocol::with_stream(stream)?;
+        if let Some(size) = recv_buffer {
+            client.set_recv_buffer_size(size)?;
+        }
+        return handshake(client, client_id, timeout);
+    }
+    Err(last_err)
+}
+
+/// Open a connection to the broker at `url` over QUIC, as `open` does over
+/// TCP
+#[cfg(feature = "quic")]
+fn open_quic(
+    url: &QuicUrl,
+    ca: Option<&Path>,
+    client_id: &str,
+    timeout: Duration,
+) -> io::Result<Protocol> {
+    let transport = crate::mqtt::QuicTransport::open(url, ca, timeout)?;
+    handshake(Protocol::with_transport(transport)?, client_id, timeout)
+}
+
+#[cfg(not(feature = "quic"))]
+fn open_quic(
+    _url: &QuicUrl,
+    _ca: Option<&Path>,
+    _client_id: &str,
+    _timeout: Duration,
+) -> io::Result<Protocol> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "QUIC needs sake built with the quic feature",
+    ))
+}
+
+fn handshake(mut client: Protocol, client_id: &str, timeout: Duration) -> io::Result<Protocol> {
+    client.set_read_timeout(Some(timeout))?;
+    // Held connections send no PINGREQ, keepalive 0 keeps the broker from
+    // timing them out
+    let connect = Protocol::builder().client_id(client_id).keepalive(0);
+    client.send_message(&connect.connect_request())?;
+    match client.read_message::<Response>()? {
+        Response::Connack { return_code: 0, .. } => Ok(client),
+        Response::Connack { return_code, .. } => Err(ConnectionRefused {
+            return_code: return_code.into(),
+            server_reference: None,
+        }
+        .into()),
+        resp => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Expected CONNACK, received {}", resp),
+        )),
+    }
+}
+
+fn subscribe(client: &mut Protocol, topic: &str, qos: Qos) -> io::Result<()> {
+    client.subscribe(topic, qos)?;
+    match client.read_message::<Response>()? {
+        Response::Suback { return_codes, .. } if !return_codes.contains(&SUBACK_FAILURE) => Ok(()),
+        resp => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Subscription refused: {}", resp),
+        )),
+    }
+}
+
+/// Read the messages of a subscriber until the end of the hold, pausing
+/// `consume_delay` after each
+fn consume(client: &mut Protocol, options: &StormOptions, held: Instant, received: &AtomicUsize) {
+    while let Some(left) = options.hold.checked_sub(held.elapsed()) {
+        // Wakes up in time for the end of the hold even without messages
+        let wait = left.clamp(Duration::from_millis(1), Duration::from_millis(100));
+        if client.set_read_timeout(Some(wait)).is_err() {
+            return;
+        }
+        match client.read_message::<Response>() {
+            Ok(Response::Publish { .. }) => {
+                received.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(options.consume_delay);
+            }
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Open `connections` as fast as `concurrency` allows, hold them while the
+/// publishers publish, then close them in waves. Failures are counted in
+/// the report rather than ending the run
+pub fn storm(options: &StormOptions) -> StormReport {
+    let next = AtomicUsize::new(0);
+    let opened = Mutex::new(vec![]);
+    let report = Mutex::new(StormReport {
+        options: options.clone(),
+        started: Some(SystemTime::now()),
+        attempted: options.connections,
+        ..StormReport::default()
+    });
+    let mut rng = Rng::from_time();
+    let client_ids: Vec<String> = (0..options.connections)
+        .map(|i| match options.random_ids {
+            true => format!("{}-{:016x}", options.client_id_prefix, rng.next_u64()),
+            false => format!("{}-{}", options.client_id_prefix, i),
+        })
+        .collect();
+
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..options.concurrency.clamp(1, options.connections.max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= options.connections {
+                    break;
+                }
+                let connect_start = Instant::now();
+                let result = open(
+                    &options.addrs,
+                    &client_ids[i],
+                    options.timeout,
+                    options.recv_buffer,
+                );
+                let latency = connect_start.elapsed();
+                let result = result.and_then(|mut client| {
+                    report.lock().unwrap().connect.record(latency);
+                    if i < options.subscribers {
+                        subscribe(&mut client, &options.topic, Qos::AtMostOnce)?;
+                    }
+                    Ok(client)
+                });
+                let mut report = report.lock().unwrap();
+                match result {
+                    Ok(client) => {
+                        if report.broker.is_none() {
+                            report.broker = client.peer_addr().ok();
+                        }
+                        report.connected += 1;
+                        opened.lock().unwrap().push((i, client));
+                    }
+                    Err(e) => match e
+                        .get_ref()
+                        .and_then(|inner| inner.downcast_ref::<ConnectionRefused>())
+                    {
+                        Some(refused) => {
+                            *report
+                                .refused
+                                .entry(refused.return_code.to_string())
+                                .or_default() += 1
+                        }
+                        None => *report.errors.entry(format!("{:?}", e.kind())).or_default() += 1,
+                    },
+                }
+            });
+        }
+    });
+    let mut report = report.into_inner().unwrap();
+    report.ramp_up = start.elapsed();
+
+    let mut opened = opened.into_inner().unwrap();
+    opened.sort_by_key(|(i, _)| *i);
+    let held = Instant::now();
+    let received = AtomicUsize::new(0);
+    let split = opened.partition_point(|(i, _)| *i < options.subscribers);
+    let (subscribers, others) = opened.split_at_mut(split);
+    thread::scope(|scope| {
+        for (_, client) in subscribers.iter_mut() {
+            scope.spawn(|| consume(client, options, held, &received));
+        }
+        while held.elapsed() < options.hold {
+            for (i, client) in others.iter_mut() {
+                if *i >= options.subscribers + options.publishers {
+                    continue;
+                }
+                let publish = Request::Publish {
+                    packet_id: 0,
+                    qos: 0,
+                    dup: false,
+                    retain: false,
+                    topic: options.topic.clone(),
+                    payload: format!("{}", report.published).into_bytes(),
+                };
+                if client.send_message(&publish).is_ok() {
+                    report.published += 1;
+                }
+            }
+            thread::sleep(
+                options
+                    .hold
+                    .saturating_sub(held.elapsed())
+                    .min(Duration::from_secs(1)),
+            );
+        }
+    });
+    report.received = received.into_inner();
+
+    let waves = options.waves.max(1);
+    let per_wave = opened.len().div_ceil(waves).max(1);
+    let mut opened = opened.into_iter().peekable();
+    while opened.peek().is_some() {
+        for (_, mut client) in opened.by_ref().take(per_wave) {
+            if client.disconnect().is_ok() {
+                report.disconnected += 1;
+            }
+        }
+        if opened.peek().is_some() {
+            thread::sleep(options.wave_interval);
+        }
+    }
+    report
+}
+
+/// Size of the send timestamp heading every latency payload
+const TIMESTAMP_LEN: usize = 8;
+
+/// Bursty traffic: `messages` spread over `window`, then `idle` without any
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Burst {
+    pub messages: usize,
+    pub window: Duration,
+    pub idle: Duration,
+}
+
+impl Burst {
+    /// When the `n`th message is due, from the start of the first burst
+    pub fn due(&self, n: usize) -> Duration {
+        let messages = self.messages.max(1);
+        let (cycle, position) = (n / messages, n % messages);
+        (self.window + self.idle).mul_f64(cycle as f64)
+            + self.window.mul_f64(position as f64 / messages as f64)
+    }
+}
+
+/// What a latency run publishes, see `latency`
+#[derive(Debug, Clone)]
+pub struct LatencyOptions {
+    pub addrs: Vec<SocketAddr>,
+    /// Reach the broker over QUIC instead of TCP at `addrs`, to compare
+    /// both transports
+    pub quic: Option<QuicUrl>,
+    /// PEM certificates the QUIC broker must chain to, the web PKI roots if
+    /// `None`
+    pub quic_ca: Option<PathBuf>,
+    /// Followed by the role and number of each connection, `sake-latency-`
+    /// and a random suffix by default
+    pub client_id_prefix: String,
+    pub publishers: usize,
+    pub subscribers: usize,
+    /// Messages sent by each publisher
+    pub messages: usize,
+    /// Payload size, at least the send timestamp
+    pub size: usize,
+    /// What follows the send timestamp
+    pub payload: PayloadKind,
+    /// Of the random payloads, each publisher adds its number to it
+    pub seed: u64,
+    pub qos: u8,
+    pub topic: String,
+    /// Messages per second of each publisher, as fast as the acks allow if
+    /// `None`
+    pub rate: Option<u32>,
+    /// Publish in bursts instead, `rate` is then ignored
+    pub burst: Option<Burst>,
+    /// Bounds the connections, each ack and the wait for the last messages
+    /// once the publishers are done
+    pub timeout: Duration,
+}
+
+impl Default for LatencyOptions {
+    fn default() -> Self {
+        Self {
+            addrs: vec![],
+            quic: None,
+            quic_ca: None,
+            client_id_prefix: random_client_id("sake-latency"),
+            publishers: 1,
+            subscribers: 1,
+            messages: 1000,
+            size: 64,
+            payload: PayloadKind::Zeros,
+            seed: Rng::from_time().next_u64(),
+            qos: 1,
+            topic: "sake/latency".to_string(),
+            rate: None,
+            burst: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Outcome of a latency run, rendered as text, JSON or CSV to compare broker
+/// versions
+#[derive(Debug, Clone, Default)]
+pub struct LatencyReport {
+    pub options: LatencyOptions,
+    pub started: Option<SystemTime>,
+    /// Address of the first connection opened
+    pub broker: Option<SocketAddr>,
+    pub published: usize,
+    /// Deliveries expected by the subscribers together
+    pub expected: usize,
+    pub received: usize,
+    /// Failures of the publishers and subscribers, by error kind
+    pub errors: BTreeMap<String, usize>,
+    /// From the first PUBLISH to the last delivery
+    pub duration: Duration,
+    /// From PUBLISH to PUBACK, or to PUBCOMP at QoS 2, empty at QoS 0
+    pub ack: Latencies,
+    /// From PUBLISH to its delivery to a subscriber
+    pub receive: Latencies,
+}
+
+impl LatencyReport {
+    fn error(&mut self, e: &io::Error) {
+        *self.errors.entry(format!("{:?}", e.kind())).or_default() += 1;
+    }
+
+    fn throughput(&self) -> f64 {
+        self.received as f64 / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The report with its options, durations in microseconds
+    pub fn export(&self) -> Export {
+        let o = &self.options;
+        let number = |n: usize| Value::Number(n as f64);
+        Export {
+            bench: "latency",
+            started: self.started,
+            broker: self.broker,
+            config: vec![
+                (
+                    "transport",
+                    Value::String(o.quic.as_ref().map_or("tcp", |_| "quic").to_string()),
+                ),
+                ("publishers", number(o.publishers)),
+                ("subscribers", number(o.subscribers)),
+                ("messages", number(o.messages)),
+                ("size", number(o.size)),
+                ("payload", Value::String(o.payload.to_string())),
+                // Too large for a JSON number to hold exactly
+                ("seed", Value::String(o.seed.to_string())),
+                ("qos", Value::Number(o.qos as f64)),
+                ("topic", Value::String(o.topic.clone())),
+                (
+                    "rate",
+                    o.rate.map_or(Value::Null, |r| Value::Number(r as f64)),
+                ),
+                (
+                    "burst",
+                    o.burst.map_or(Value::Null, |burst| {
+                        Value::Object(vec![
+                            ("messages".to_string(), number(burst.messages)),
+                            (
+                                "window_us".to_string(),
+                                Value::Number(burst.window.as_micros() as f64),
+                            ),
+                            (
+                                "idle_us".to_string(),
+                                Value::Number(burst.idle.as_micros() as f64),
+                            ),
+                        ])
+                    }),
+                ),
+            ],
+            results: vec![
+                (
+                    "duration_us",
+                    Value::Number(self.duration.as_micros() as f64),
+                ),
+                ("throughput", Value::Number(round(self.throughput()))),
+                ("published", number(self.published)),
+                ("expected", number(self.expected)),
+                ("received", number(self.received)),
+                ("ack", export::latencies(&self.ack)),
+                ("receive", export::latencies(&self.receive)),
+            ],
+            errors: self.errors.clone(),
+        }
+    }
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(url) = &self.options.quic {
+            writeln!(f, "Transport   QUIC to {}", url)?;
+        }
+        writeln!(
+            f,
+            "Messages    {} published, {}/{} received in {:.2} s ({:.1}/s)",
+            self.published,
+            self.received,
+            self.expected,
+            self.duration.as_secs_f64(),
+            self.throughput()
+        )?;
+        if self.options.qos > 0 {
+            writeln!(f, "Ack         {}", self.ack)?;
+        }
+        for (kind, count) in &self.errors {
+            writeln!(f, "Failed      {}: {}", kind, count)?;
+        }
+        write!(f, "Receive     {}", self.receive)
+    }
+}
+
+/// Wait for the acknowledgement of a QoS 1 or 2 message, releasing it at
+/// QoS 2
+fn acknowledged(client: &mut Protocol, qos: u8, packet_id: u16) -> io::Result<()> {
+    loop {
+        match (qos, client.read_message::<Response>()?) {
+            (1, Response::Puback { packet_id: id }) if id == packet_id => return Ok(()),
+            (2, Response::Pubrec { packet_id: id }) if id == packet_id => {
+                client.ack(AckType::Pubrel(packet_id))?
+            }
+            (2, Response::Pubcomp { packet_id: id }) if id == packet_id => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// Publish the messages of the `index`th publisher, each stamped with its
+/// send time since `epoch`, one at a time
+fn publish_timed(
+    client: &mut Protocol,
+    index: usize,
+    options: &LatencyOptions,
+    epoch: Instant,
+    report: &Mutex<LatencyReport>,
+) {
+    let mut ack = Latencies::default();
+    let mut published = 0;
+    let mut error = None;
+    let mut payloads = Payloads::new(options.payload, options.seed.wrapping_add(index as u64));
+    let mut payload = vec![0; options.size];
+    let started = Instant::now();
+    for n in 0..options.messages {
+        let due = match (options.burst, options.rate.filter(|r| *r > 0)) {
+            (Some(burst), _) => Some(burst.due(n)),
+            (None, Some(rate)) => Some(Duration::from_secs_f64(n as f64 / rate as f64)),
+            (None, None) => None,
+        };
+        if let Some(due) = due {
+            thread::sleep((started + due).saturating_duration_since(Instant::now()));
+        }
+        let packet_id = match options.qos {
+            0 => 0,
+            _ => client.next_packet_id(),
+        };
+        payloads.fill(&mut payload);
+        let sent = epoch.elapsed();
+        payload[..TIMESTAMP_LEN].copy_from_slice(&(sent.as_nanos() as u64).to_be_bytes());
+        let result = client
+            .send_message(&Request::Publish {
+                packet_id,
+                qos: options.qos,
+                dup: false,
+                retain: false,
+                topic: options.topic.clone(),
+                payload: payload.clone(),
+            })
+            .and_then(|_| match options.qos {
+                0 => Ok(()),
+                qos => acknowledged(client, qos, packet_id),
+            });
+        if let Err(e) = result {
+            error = Some(e);
+            break;
+        }
+        if options.qos > 0 {
+            ack.record(epoch.elapsed().saturating_sub(sent));
+        }
+        published += 1;
+    }
+    let mut report = report.lock().unwrap();
+    report.published += published;
+    report.ack.merge(&ack);
+    if let Some(e) = error {
+        report.error(&e);
+    }
+}
+
+/// Read the messages of a subscriber until it got all of them or
+/// `options.timeout` passed since the publishers are `done`
+fn receive_timed(
+    client: &mut Protocol,
+    options: &LatencyOptions,
+    epoch: Instant,
+    done: &AtomicBool,
+    report: &Mutex<LatencyReport>,
+) {
+    let expected = options.publishers * options.messages;
+    let mut latencies = Latencies::default();
+    let mut received = 0;
+    let mut last = Duration::ZERO;
+    let mut deadline = None;
+    let mut error = None;
+    while received < expected {
+        if done.load(Ordering::Acquire) {
+            let deadline = *deadline.get_or_insert_with(|| Instant::now() + options.timeout);
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        if let Err(e) = client.set_read_timeout(Some(Duration::from_millis(100))) {
+            error = Some(e);
+            break;
+        }
+        let ack = match client.read_message::<Response>() {
+            Ok(Response::Publish {
+                packet_id,
+                qos,
+                payload,
+                ..
+            }) => {
+                last = epoch.elapsed();
+                if let Some(stamp) = payload.get(..TIMESTAMP_LEN) {
+                    let sent = Duration::from_nanos(u64::from_be_bytes(stamp.try_into().unwrap()));
+                    latencies.record(last.saturating_sub(sent));
+                }
+                received += 1;
+                match qos {
+                    1 => Some(AckType::Puback(packet_id)),
+                    2 => Some(AckType::Pubrec(packet_id)),
+                    _ => None,
+                }
+            }
+            Ok(Response::Pubrel { packet_id }) => Some(AckType::Pubcomp(packet_id)),
+            Ok(_) => None,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                None
+            }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+        if let Some(Err(e)) = ack.map(|ack| client.ack(ack)) {
+            error = Some(e);
+            break;
+        }
+    }
+    let mut report = report.lock().unwrap();
+    report.received += received;
+    report.receive.merge(&latencies);
+    report.duration = report.duration.max(last);
+    if let Some(e) = error {
+        report.error(&e);
+    }
+}
+
+/// Publish timestamped messages to subscribers of the same topic and
+/// measure how long they take to be acknowledged and delivered. Every
+/// client connects before the first PUBLISH, failing to connect or
+/// subscribe ends the run
+pub fn latency(options: &LatencyOptions) -> io::Result<LatencyReport> {
+    if options.size < TIMESTAMP_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Payload size must be at least {} bytes, the send timestamp",
+                TIMESTAMP_LEN
+            ),
+        ));
+    }
+    let connect = |role: &str, i: usize| {
+        let client_id = format!("{}-{}-{}", options.client_id_prefix, role, i);
+        match &options.quic {
+            Some(url) => open_quic(url, options.quic_ca.as_deref(), &client_id, options.timeout),
+            None => open(&options.addrs, &client_id, options.timeout, None),
+        }
+    };
+    let mut subscribers = (0..options.subscribers)
+        .map(|i| {
+            let mut client = connect("sub", i)?;
+            subscribe(&mut client, &options.topic, Qos::from(options.qos))?;
+            Ok(client)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut publishers = (0..options.publishers)
+        .map(|i| connect("pub", i))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let report = Mutex::new(LatencyReport {
+        options: options.clone(),
+        started: Some(SystemTime::now()),
+        broker: subscribers
+            .iter()
+            .chain(&publishers)
+            .find_map(|client| client.peer_addr().ok()),
+        expected: options.publishers * options.messages * options.subscribers,
+        ..LatencyReport::default()
+    });
+    let done = AtomicBool::new(false);
+    let epoch = Instant::now();
+    thread::scope(|scope| {
+        for client in subscribers.iter_mut() {
+            scope.spawn(|| receive_timed(client, options, epoch, &done, &report));
+        }
+        thread::scope(|publishing| {
+            for (i, client) in publishers.iter_mut().enumerate() {
+                let report = &report;
+                publishing.spawn(move || publish_timed(client, i, options, epoch, report));
+            }
+        });
+        let mut report = report.lock().unwrap();
+        report.duration = report.duration.max(epoch.elapsed());
+        done.store(true, Ordering::Release);
+    });
+    for client in subscribers.iter_mut().chain(publishers.iter_mut()) {
+        let _ = client.disconnect();
+    }
+    Ok(report.into_inner().unwrap())
+}
+
+#[cfg(test)]
+mod bench_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+
+    #[test]
+    fn test_percentiles() {
+        let mut latencies = Latencies::default();
+        assert_eq!(latencies.percentile(50.0), None);
+        for ms in (1..=100).rev() {
+            latencies.record(Duration::from_millis(ms));
+        }
+        let close = |p: f64, ms: u64| {
+            let expected = Duration::from_millis(ms).as_secs_f64();
+            let error = latencies.percentile(p).unwrap().as_secs_f64() / expected - 1.0;
+            (0.0..1.0 / 64.0).contains(&error)
+        };
+        assert_eq!(latencies.percentile(0.0), Some(Duration::from_millis(1)));
+        assert!(close(50.0, 50));
+        assert!(close(99.0, 99));
+        assert_eq!(
+            latencies.percentile(100.0),
+            Some(Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn test_buckets() {
+        for value in [0, 1, 127, 128, 129, 1000, 123_456_789, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(bucket_value(index) >= value);
+            assert!(index == 0 || bucket_value(index - 1) < value);
+        }
+        assert_eq!(bucket_index(128), 128);
+        assert_eq!(bucket_value(128), 129);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = Latencies::default();
+        let mut b = Latencies::default();
+        a.record(Duration::from_micros(10));
+        b.record(Duration::from_micros(5));
+        b.record(Duration::from_micros(2000));
+        a.merge(&b);
+        a.merge(&Latencies::default());
+        assert_eq!(a.len(), 3);
+        assert_eq!(a.percentile(0.0), Some(Duration::from_micros(5)));
+        assert_eq!(a.percentile(100.0), Some(Duration::from_micros(2000)));
+    }
+
+    #[test]
+    fn test_rng() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        assert_eq!(a.next_u64(), b.next_u64());
+        assert_ne!(a.next_u64(), Rng::new(43).next_u64());
+        assert_ne!(Rng::new(0).next_u64(), 0);
+    }
+
+    #[test]
+    fn test_payloads() {
+        let random = |seed| Payloads::new(PayloadKind::Random, seed).next_payload(20);
+        assert_eq!(random(42), random(42));
+        assert_ne!(random(42), random(43));
+        let mut zeros = Payloads::new(PayloadKind::Zeros, 42);
+        assert_eq!(zeros.next_payload(3), [0, 0, 0]);
+        let mut incrementing = Payloads::new(PayloadKind::Incrementing, 42);
+        assert_eq!(incrementing.next_payload(3), [0, 1, 2]);
+        assert_eq!(incrementing.next_payload(3), [1, 2, 3]);
+        assert_eq!(incrementing.next_payload(300)[254..256], [0, 1]);
+        assert_eq!(
+            "incrementing".parse::<PayloadKind>().unwrap(),
+            PayloadKind::Incrementing
+        );
+        assert!("ones".parse::<PayloadKind>().is_err());
+    }
+
+    fn start(options: BrokerOptions) -> io::Result<SocketAddr> {
+        let broker = Broker::bind("127.0.0.1:0", options)?;
+        let addr = broker.local_addr()?;
+        thread::spawn(move || broker.run());
+        Ok(addr)
+    }
+
+    #[test]
+    fn test_storm() -> io::Result<()> {
+        let addr = start(BrokerOptions::default())?;
+        let report = storm(&StormOptions {
+            addrs: vec![addr],
+            connections: 20,
+            concurrency: 4,
+            random_ids: true,
+            subscribers: 5,
+            publishers: 2,
+            hold: Duration::from_millis(500),
+            waves: 3,
+            wave_interval: Duration::from_millis(10),
+            consume_delay: Duration::from_millis(10),
+            recv_buffer: Some(4096),
+            ..StormOptions::default()
+        });
+        assert_eq!((report.connected, report.disconnected), (20, 20));
+        assert_eq!(report.published, 2);
+        assert_eq!(report.received, 10);
+        assert!(report.refused.is_empty() && report.errors.is_empty());
+        assert_eq!(report.connect.len(), 20);
+        assert!(report.connect.percentile(50.0).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_storm_counts_refusals() -> io::Result<()> {
+        let addr = start(BrokerOptions {
+            passwords: Some("".parse()?),
+            ..BrokerOptions::default()
+        })?;
+        let report = storm(&StormOptions {
+            addrs: vec![addr],
+            connections: 3,
+            hold: Duration::ZERO,
+            ..StormOptions::default()
+        });
+        assert_eq!(report.connected, 0);
+        assert_eq!(report.refused.get("Not Authorized"), Some(&3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency() -> io::Result<()> {
+        let addr = start(BrokerOptions::default())?;
+        for qos in 0..=2 {
+            let report = latency(&LatencyOptions {
+                addrs: vec![addr],
+                client_id_prefix: format!("sake-latency-{}", qos),
+                publishers: 2,
+                subscribers: 2,
+                messages: 50,
+                qos,
+                topic: format!("sake/latency/{}", qos),
+                ..LatencyOptions::default()
+            })?;
+            assert_eq!((report.published, report.expected), (100, 200));
+            assert_eq!(report.received, 200);
+            assert_eq!(report.receive.len(), 200);
+            assert_eq!(report.ack.len(), if qos == 0 { 0 } else { 100 });
+            assert!(report.errors.is_empty());
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "quic")]
+    #[test]
+    fn test_latency_over_quic() -> io::Result<()> {
+        let (relay, pem) = crate::mqtt::quic_relay(start(BrokerOptions::default())?)?;
+        let ca = std::env::temp_dir().join(format!("sake-bench-quic-{}.pem", std::process::id()));
+        std::fs::write(&ca, pem)?;
+        let report = latency(&LatencyOptions {
+            quic: Some(QuicUrl {
+                host: "localhost".to_string(),
+                port: relay.port(),
+            }),
+            quic_ca: Some(ca.clone()),
+            messages: 20,
+            ..LatencyOptions::default()
+        });
+        std::fs::remove_file(&ca)?;
+        let report = report?;
+        assert_eq!(report.broker, Some(relay));
+        assert_eq!(report.received, 20);
+        assert!(report.errors.is_empty());
+        assert!(report
+            .to_string()
+            .starts_with("Transport   QUIC to quic://localhost:"));
+        let config = &report.export().config;
+        assert!(config.contains(&("transport", Value::String("quic".to_string()))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_burst() -> io::Result<()> {
+        let burst = Burst {
+            messages: 4,
+            window: Duration::from_millis(8),
+            idle: Duration::from_millis(100),
+        };
+        let due: Vec<u128> = (0..6).map(|n| burst.due(n).as_millis()).collect();
+        assert_eq!(due, [0, 2, 4, 6, 108, 110]);
+
+        let addr = start(BrokerOptions::default())?;
+        let started = Instant::now();
+        let report = latency(&LatencyOptions {
+            addrs: vec![addr],
+            messages: 9,
+            rate: Some(1),
+            burst: Some(burst),
+            ..LatencyOptions::default()
+        })?;
+        assert_eq!(report.received, 9);
+        // The ninth message opens the third burst, the rate is ignored
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(216), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency_rejects_short_payloads() {
+        let err = latency(&LatencyOptions {
+            size: 4,
+            ..LatencyOptions::default()
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_latency_export() -> io::Result<()> {
+        let addr = start(BrokerOptions::default())?;
+        let options = LatencyOptions {
+            addrs: vec![addr],
+            messages: 10,
+            ..LatencyOptions::default()
+        };
+        let report = latency(&options)?;
+        let doc = crate::json::parse(&report.export().to_json())?;
+        assert_eq!(doc.get("bench").and_then(|b| b.as_str()), Some("latency"));
+        assert_eq!(
+            doc.get("broker").and_then(|b| b.as_str()),
+            Some(addr.to_string().as_str())
+        );
+        let config = doc.get("config").unwrap();
+        assert_eq!(config.get("messages").and_then(|m| m.as_f64()), Some(10.0));
+        assert_eq!(config.get("rate"), Some(&Value::Null));
+        let count = |name: &str| doc.get(name)?.get("count")?.as_f64();
+        assert_eq!((count("ack"), count("receive")), (Some(10.0), Some(10.0)));
+        let comparison = compare(&report.export().to_json(), &report.export().to_json())?;
+        assert!(comparison.regressions(0.0).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,929 @@
+use crate::bench::{
+    acknowledged, export, open, round, subscribe, Export, Latencies, PayloadKind, Payloads, Rng,
+    TIMESTAMP_LEN,
+};
+use crate::json::Value;
+use crate::mqtt::{random_client_id, AckType, Protocol, Qos, Request, Response};
+use crate::toml;
+use crate::topic;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Longest a publisher sleeps before looking at the load again
+const IDLE_STEP: Duration = Duration::from_millis(10);
+
+/// A stretch of a scenario, scaling the rate of every publisher
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phase {
+    pub name: String,
+    pub duration: Duration,
+    /// Multiplier of the publisher rates at the start and at the end of the
+    /// phase, ramped linearly in between
+    pub load: (f64, f64),
+    /// Highest p99 delivery latency allowed to the messages sent during the
+    /// phase
+    pub max_p99: Option<Duration>,
+}
+
+/// Clients publishing to the same topic
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublisherGroup {
+    pub name: String,
+    pub clients: usize,
+    pub topic: String,
+    pub qos: u8,
+    /// Payload size, at least the send timestamp
+    pub size: usize,
+    /// What follows the send timestamp
+    pub payload: PayloadKind,
+    /// Messages per second of each client at a load of 1
+    pub rate: f64,
+}
+
+/// Clients subscribing to the same filter
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriberGroup {
+    pub name: String,
+    pub clients: usize,
+    pub filter: String,
+    pub qos: u8,
+}
+
+/// A load test read from a TOML file, see `Scenario::from_toml`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub name: String,
+    pub addrs: Vec<SocketAddr>,
+    /// Followed by the group and number of each client
+    pub client_id_prefix: String,
+    pub phases: Vec<Phase>,
+    pub publishers: Vec<PublisherGroup>,
+    pub subscribers: Vec<SubscriberGroup>,
+    /// Wait for the last deliveries once the phases are over
+    pub drain: Duration,
+    /// Of the random payloads, each publisher adds its number to it
+    pub seed: u64,
+    /// Bounds the connections and each ack
+    pub timeout: Duration,
+    /// Highest p99 delivery latency allowed over the whole run
+    pub max_p99: Option<Duration>,
+    /// Highest share of the expected deliveries allowed to go missing
+    pub max_error_rate: Option<f64>,
+}
+
+fn invalid(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid scenario: {}", what),
+    )
+}
+
+/// The fields of a table, refusing the keys not in `known` as they are
+/// likely typos
+fn table<'a>(value: &'a Value, what: &str, known: &[&str]) -> io::Result<&'a [(String, Value)]> {
+    let Value::Object(fields) = value else {
+        return Err(invalid(format!("{} must be a table", what)));
+    };
+    match fields
+        .iter()
+        .find(|(key, _)| !known.contains(&key.as_str()))
+    {
+        Some((key, _)) => Err(invalid(format!("unknown key {} in {}", key, what))),
+        None => Ok(fields),
+    }
+}
+
+/// The tables of an array of tables, none if it's missing
+fn tables<'a>(doc: &'a Value, key: &str) -> io::Result<&'a [Value]> {
+    match doc.get(key) {
+        None => Ok(&[]),
+        Some(Value::Array(tables)) => Ok(tables),
+        Some(_) => Err(invalid(format!("{} must be an array of tables", key))),
+    }
+}
+
+fn string(value: Option<&Value>, what: &str) -> io::Result<Option<String>> {
+    match value {
+        None => Ok(None),
+        Some(Value::String(s)) if !s.is_empty() => Ok(Some(s.clone())),
+        Some(_) => Err(invalid(format!("{} must be a non empty string", what))),
+    }
+}
+
+fn number(value: Option<&Value>, what: &str) -> io::Result<Option<f64>> {
+    match value {
+        None => Ok(None),
+        Some(Value::Number(n)) if *n >= 0.0 => Ok(Some(*n)),
+        Some(_) => Err(invalid(format!("{} must be a positive number", what))),
+    }
+}
+
+fn count(value: Option<&Value>, what: &str) -> io::Result<Option<usize>> {
+    match number(value, what)? {
+        Some(n) if n.fract() != 0.0 => Err(invalid(format!("{} must be an integer", what))),
+        n => Ok(n.map(|n| n as usize)),
+    }
+}
+
+fn qos(value: Option<&Value>, what: &str) -> io::Result<u8> {
+    match count(value, what)? {
+        Some(qos @ 0..=2) => Ok(qos as u8),
+        None => Ok(0),
+        Some(_) => Err(invalid(format!("{} must be 0, 1 or 2", what))),
+    }
+}
+
+fn payload(value: Option<&Value>) -> io::Result<PayloadKind> {
+    match value {
+        None => Ok(PayloadKind::Zeros),
+        Some(Value::String(kind)) => kind.parse().map_err(|e: io::Error| invalid(e.to_string())),
+        Some(_) => Err(invalid("payload must be a string".to_string())),
+    }
+}
+
+/// Seconds as a number, or a string with a unit as `500ms`, `10s` or `2m`
+fn duration(value: Option<&Value>, what: &str) -> io::Result<Option<Duration>> {
+    let Some(Value::String(s)) = value else {
+        return Ok(number(value, what)?.map(Duration::from_secs_f64));
+    };
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let scale = match unit {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(invalid(format!("{} has an unknown unit {}", what, unit))),
+    };
+    match amount.parse::<f64>() {
+        Ok(amount) => Ok(Some(Duration::from_secs_f64(amount * scale))),
+        Err(_) => Err(invalid(format!("{} must be a duration as 10s", what))),
+    }
+}
+
+impl Scenario {
+    /// Read a scenario as
+    ///
+    /// ```toml
+    /// name = "load"
+    /// drain = "5s"
+    /// seed = 42
+    ///
+    /// [[phase]]
+    /// name = "ramp-up"
+    /// duration = "10s"
+    /// load = [0.1, 1.0]
+    ///
+    /// [[phase]]
+    /// name = "spike"
+    /// duration = "5s"
+    /// load = 5
+    /// max_p99 = "200ms"
+    ///
+    /// [[publishers]]
+    /// name = "sensors"
+    /// clients = 10
+    /// topic = "load/sensors"
+    /// qos = 1
+    /// size = 64
+    /// payload = "random"
+    /// rate = 20
+    ///
+    /// [[subscribers]]
+    /// name = "dashboards"
+    /// clients = 2
+    /// filter = "load/#"
+    ///
+    /// [assert]
+    /// max_p99 = "50ms"
+    /// max_error_rate = 0.01
+    /// ```
+    pub fn from_toml(s: &str) -> io::Result<Self> {
+        let doc = toml::parse(s)?;
+        let root = table(
+            &doc,
+            "the scenario",
+            &[
+                "name",
+                "drain",
+                "timeout",
+                "seed",
+                "phase",
+                "publishers",
+                "subscribers",
+                "assert",
+            ],
+        )?;
+        let get = |key: &str| root.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+        let phases = tables(&doc, "phase")?
+            .iter()
+            .enumerate()
+            .map(|(i, phase)| {
+                let what = format!("phase {}", i + 1);
+                table(phase, &what, &["name", "duration", "load", "max_p99"])?;
+                let load = match phase.get("load") {
+                    Some(Value::Array(ramp)) => match ramp[..] {
+                        [ref from, ref to] => (
+                            number(Some(from), "load")?.unwrap(),
+                            number(Some(to), "load")?.unwrap(),
+                        ),
+                        _ => return Err(invalid(format!("load of {} must be [from, to]", what))),
+                    },
+                    load => {
+                        let load = number(load, "load")?.unwrap_or(1.0);
+                        (load, load)
+                    }
+                };
+                Ok(Phase {
+                    name: string(phase.get("name"), "name")?.unwrap_or_else(|| what.clone()),
+                    duration: duration(phase.get("duration"), "duration")?
+                        .filter(|d| !d.is_zero())
+                        .ok_or_else(|| invalid(format!("{} needs a duration", what)))?,
+                    load,
+                    max_p99: duration(phase.get("max_p99"), "max_p99")?,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let publishers = tables(&doc, "publishers")?
+            .iter()
+            .enumerate()
+            .map(|(i, group)| {
+                let what = format!("publishers {}", i + 1);
+                table(
+                    group,
+                    &what,
+                    &["name", "clients", "topic", "qos", "size", "payload", "rate"],
+                )?;
+                Ok(PublisherGroup {
+                    name: string(group.get("name"), "name")?.unwrap_or_else(|| format!("pub{}", i)),
+                    clients: count(group.get("clients"), "clients")?.unwrap_or(1),
+                    topic: string(group.get("topic"), "topic")?
+                        .ok_or_else(|| invalid(format!("{} needs a topic", what)))?,
+                    qos: qos(group.get("qos"), "qos")?,
+                    size: count(group.get("size"), "size")?.unwrap_or(64),
+                    payload: payload(group.get("payload"))?,
+                    rate: number(group.get("rate"), "rate")?.unwrap_or(1.0),
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let subscribers = tables(&doc, "subscribers")?
+            .iter()
+            .enumerate()
+            .map(|(i, group)| {
+                let what = format!("subscribers {}", i + 1);
+                table(group, &what, &["name", "clients", "filter", "qos"])?;
+                Ok(SubscriberGroup {
+                    name: string(group.get("name"), "name")?.unwrap_or_else(|| format!("sub{}", i)),
+                    clients: count(group.get("clients"), "clients")?.unwrap_or(1),
+                    filter: string(group.get("filter"), "filter")?
+                        .ok_or_else(|| invalid(format!("{} needs a filter", what)))?,
+                    qos: qos(group.get("qos"), "qos")?,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let assert = match get("assert") {
+            Some(assert) => table(assert, "assert", &["max_p99", "max_error_rate"])?,
+            None => &[],
+        };
+        let assert = |key: &str| assert.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+
+        if phases.is_empty() || publishers.is_empty() {
+            return Err(invalid(
+                "at least a phase and publishers are needed".to_string(),
+            ));
+        }
+        if let Some(group) = publishers.iter().find(|g| g.size < TIMESTAMP_LEN) {
+            return Err(invalid(format!(
+                "size of {} must be at least {} bytes, the send timestamp",
+                group.name, TIMESTAMP_LEN
+            )));
+        }
+        for (i, phase) in phases.iter().enumerate() {
+            if phases[..i].iter().any(|p| p.name == phase.name) {
+                return Err(invalid(format!("phase {} defined twice", phase.name)));
+            }
+        }
+        Ok(Self {
+            name: string(get("name"), "name")?.unwrap_or_else(|| "scenario".to_string()),
+            addrs: vec![],
+            client_id_prefix: random_client_id("sake-scenario"),
+            phases,
+            publishers,
+            subscribers,
+            drain: duration(get("drain"), "drain")?.unwrap_or(Duration::from_secs(5)),
+            seed: count(get("seed"), "seed")?
+                .map_or_else(|| Rng::from_time().next_u64(), |n| n as u64),
+            timeout: duration(get("timeout"), "timeout")?.unwrap_or(Duration::from_secs(10)),
+            max_p99: duration(assert("max_p99"), "max_p99")?,
+            max_error_rate: number(assert("max_error_rate"), "max_error_rate")?,
+        })
+    }
+
+    /// Index of the phase running `elapsed` into the run, and its load
+    /// then. `None` once the run is over
+    fn phase_at(&self, elapsed: Duration) -> Option<(usize, f64)> {
+        let mut start = Duration::ZERO;
+        for (i, phase) in self.phases.iter().enumerate() {
+            if elapsed < start + phase.duration {
+                let progress = (elapsed - start).as_secs_f64() / phase.duration.as_secs_f64();
+                let (from, to) = phase.load;
+                return Some((i, from + (to - from) * progress));
+            }
+            start += phase.duration;
+        }
+        None
+    }
+
+    /// Subscriber clients receiving what `group` publishes
+    fn audience(&self, group: &PublisherGroup) -> usize {
+        self.subscribers
+            .iter()
+            .filter(|s| topic::matches(&s.filter, &group.topic))
+            .map(|s| s.clients)
+            .sum()
+    }
+}
+
+/// What happened to the messages sent during a phase
+#[derive(Debug, Clone, Default)]
+pub struct PhaseReport {
+    pub name: String,
+    /// Publishes attempted, including the failed ones
+    pub published: usize,
+    /// Deliveries the subscribers should have got
+    pub expected: usize,
+    pub received: usize,
+    /// From PUBLISH to PUBACK, or to PUBCOMP at QoS 2
+    pub ack: Latencies,
+    /// From PUBLISH to its delivery to a subscriber
+    pub receive: Latencies,
+}
+
+impl PhaseReport {
+    /// Share of the expected deliveries that went missing
+    pub fn error_rate(&self) -> f64 {
+        match self.expected {
+            0 => 0.0,
+            expected => 1.0 - self.received.min(expected) as f64 / expected as f64,
+        }
+    }
+
+    fn export(&self) -> Value {
+        let number = |n: usize| Value::Number(n as f64);
+        Value::Object(vec![
+            ("published".to_string(), number(self.published)),
+            ("expected".to_string(), number(self.expected)),
+            ("received".to_string(), number(self.received)),
+            (
+                "error_rate".to_string(),
+                Value::Number(round(self.error_rate())),
+            ),
+            ("ack".to_string(), export::latencies(&self.ack)),
+            ("receive".to_string(), export::latencies(&self.receive)),
+        ])
+    }
+}
+
+/// Outcome of a scenario, per phase and over the whole run, with the
+/// assertions that failed
+#[derive(Debug, Clone, Default)]
+pub struct ScenarioReport {
+    pub name: String,
+    pub started: Option<SystemTime>,
+    pub broker: Option<SocketAddr>,
+    pub phases: Vec<PhaseReport>,
+    /// Failures of the clients, by error kind
+    pub errors: BTreeMap<String, usize>,
+    pub duration: Duration,
+    /// One line per failed assertion, the run passed if empty
+    pub failures: Vec<String>,
+}
+
+impl ScenarioReport {
+    /// The phases together
+    pub fn total(&self) -> PhaseReport {
+        let mut total = PhaseReport {
+            name: "total".to_string(),
+            ..PhaseReport::default()
+        };
+        for phase in &self.phases {
+            total.published += phase.published;
+            total.expected += phase.expected;
+            total.received += phase.received;
+            total.ack.merge(&phase.ack);
+            total.receive.merge(&phase.receive);
+        }
+        total
+    }
+
+    fn check(&mut self, scenario: &Scenario) {
+        let mut failures = vec![];
+        let mut p99 = |report: &PhaseReport, max: Option<Duration>| {
+            let (Some(max), Some(p99)) = (max, report.receive.percentile(99.0)) else {
+                return;
+            };
+            if p99 > max {
+                failures.push(format!(
+                    "p99 of {} is {:.1?}, above {:.1?}",
+                    report.name, p99, max
+                ));
+            }
+        };
+        for (phase, report) in scenario.phases.iter().zip(&self.phases) {
+            p99(report, phase.max_p99);
+        }
+        let total = self.total();
+        p99(&total, scenario.max_p99);
+        if let Some(max) = scenario.max_error_rate {
+            if total.error_rate() > max {
+                failures.push(format!(
+                    "error rate is {:.2}%, above {:.2}%",
+                    total.error_rate() * 100.0,
+                    max * 100.0
+                ));
+            }
+        }
+        self.failures = failures;
+    }
+
+    fn error(&mut self, e: &io::Error) {
+        *self.errors.entry(format!("{:?}", e.kind())).or_default() += 1;
+    }
+
+    /// The report with the scenario, durations in microseconds
+    pub fn export(&self, scenario: &Scenario) -> Export {
+        let number = |n: usize| Value::Number(n as f64);
+        let total = self.total();
+        let mut results = vec![
+            (
+                "duration_us",
+                Value::Number(self.duration.as_micros() as f64),
+            ),
+            ("published", number(total.published)),
+            ("expected", number(total.expected)),
+            ("received", number(total.received)),
+            ("error_rate", Value::Number(round(total.error_rate()))),
+            ("ack", export::latencies(&total.ack)),
+            ("receive", export::latencies(&total.receive)),
+        ];
+        results.push((
+            "phases",
+            Value::Object(
+                self.phases
+                    .iter()
+                    .map(|phase| (phase.name.clone(), phase.export()))
+                    .collect(),
+            ),
+        ));
+        results.push((
+            "failures",
+            Value::Array(self.failures.iter().cloned().map(Value::String).collect()),
+        ));
+        Export {
+            bench: "scenario",
+            started: self.started,
+            broker: self.broker,
+            config: vec![
+                ("name", Value::String(scenario.name.clone())),
+                ("seed", Value::String(scenario.seed.to_string())),
+                ("phases", number(scenario.phases.len())),
+                (
+                    "publishers",
+                    number(scenario.publishers.iter().map(|g| g.clients).sum()),
+                ),
+                (
+                    "subscribers",
+                    number(scenario.subscribers.iter().map(|g| g.clients).sum()),
+                ),
+            ],
+            results,
+            errors: self.errors.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ScenarioReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Scenario    {} in {:.2} s",
+            self.name,
+            self.duration.as_secs_f64()
+        )?;
+        for phase in self.phases.iter().chain([&self.total()]) {
+            writeln!(
+                f,
+                "{:<11} {} published, {}/{} received, {:.2}% missing",
+                phase.name,
+                phase.published,
+                phase.received,
+                phase.expected,
+                phase.error_rate() * 100.0
+            )?;
+            if !phase.ack.is_empty() {
+                writeln!(f, "  Ack       {}", phase.ack)?;
+            }
+            writeln!(f, "  Receive   {}", phase.receive)?;
+        }
+        for (kind, count) in &self.errors {
+            writeln!(f, "Failed      {}: {}", kind, count)?;
+        }
+        match self.failures.is_empty() {
+            true => write!(f, "Assertions  passed"),
+            false => write!(f, "Assertions  failed: {}", self.failures.join("; ")),
+        }
+    }
+}
+
+/// Counts of a client, merged into the report once it's done
+#[derive(Default)]
+struct Tally {
+    published: Vec<usize>,
+    expected: Vec<usize>,
+    received: Vec<usize>,
+    ack: Vec<Latencies>,
+    receive: Vec<Latencies>,
+}
+
+impl Tally {
+    fn new(phases: usize) -> Self {
+        Self {
+            published: vec![0; phases],
+            expected: vec![0; phases],
+            received: vec![0; phases],
+            ack: vec![Latencies::default(); phases],
+            receive: vec![Latencies::default(); phases],
+        }
+    }
+
+    fn merge(self, report: &mut ScenarioReport) {
+        for (i, phase) in report.phases.iter_mut().enumerate() {
+            phase.published += self.published[i];
+            phase.expected += self.expected[i];
+            phase.received += self.received[i];
+            phase.ack.merge(&self.ack[i]);
+            phase.receive.merge(&self.receive[i]);
+        }
+    }
+}
+
+/// Publish at the rate of the group scaled by the load of each phase,
+/// until the last phase ends or a publish fails
+fn publish_phases(
+    client: &mut Protocol,
+    scenario: &Scenario,
+    group: &PublisherGroup,
+    epoch: Instant,
+    report: &Mutex<ScenarioReport>,
+    published: &Mutex<Vec<usize>>,
+    (group_index, client_index): (usize, usize),
+) {
+    let audience = scenario.audience(group);
+    let mut tally = Tally::new(scenario.phases.len());
+    let seed = scenario.seed.wrapping_add(client_index as u64);
+    let mut payloads = Payloads::new(group.payload, seed);
+    let mut payload = vec![0; group.size];
+    let mut error = None;
+    while let Some((phase, load)) = scenario.phase_at(epoch.elapsed()) {
+        if group.rate * load <= 0.0 {
+            thread::sleep(IDLE_STEP);
+            continue;
+        }
+        let packet_id = match group.qos {
+            0 => 0,
+            _ => client.next_packet_id(),
+        };
+        payloads.fill(&mut payload);
+        let sent = epoch.elapsed();
+        payload[..TIMESTAMP_LEN].copy_from_slice(&(sent.as_nanos() as u64).to_be_bytes());
+        tally.published[phase] += 1;
+        tally.expected[phase] += audience;
+        published.lock().unwrap()[group_index] += 1;
+        let result = client
+            .send_message(&Request::Publish {
+                packet_id,
+                qos: group.qos,
+                dup: false,
+                retain: false,
+                topic: group.topic.clone(),
+                payload: payload.clone(),
+            })
+            .and_then(|_| match group.qos {
+                0 => Ok(()),
+                qos => acknowledged(client, qos, packet_id),
+            });
+        if let Err(e) = result {
+            error = Some(e);
+            break;
+        }
+        if group.qos > 0 {
+            tally.ack[phase].record(epoch.elapsed().saturating_sub(sent));
+        }
+        // The load changes while waiting, the next publish is due once the
+        // current rate says so
+        while let Some((_, load)) = scenario.phase_at(epoch.elapsed()) {
+            let interval =
+                Duration::try_from_secs_f64(1.0 / (group.rate * load)).unwrap_or(Duration::MAX);
+            let Some(wait) = sent.saturating_add(interval).checked_sub(epoch.elapsed()) else {
+                break;
+            };
+            thread::sleep(wait.min(IDLE_STEP));
+        }
+    }
+    let mut report = report.lock().unwrap();
+    tally.merge(&mut report);
+    if let Some(e) = error {
+        report.error(&e);
+    }
+}
+
+/// Read the messages of a subscriber, attributing each to the phase it was
+/// sent in, until it got what the publishers sent it or `drain` passed
+/// since they're `done`
+fn receive_phases(
+    client: &mut Protocol,
+    scenario: &Scenario,
+    group: &SubscriberGroup,
+    epoch: Instant,
+    done: &AtomicBool,
+    report: &Mutex<ScenarioReport>,
+    published: &Mutex<Vec<usize>>,
+) {
+    let mut tally = Tally::new(scenario.phases.len());
+    let mut received = 0;
+    let mut deadline = None;
+    let mut error = None;
+    loop {
+        if done.load(Ordering::Acquire) {
+            let expected: usize = scenario
+                .publishers
+                .iter()
+                .zip(published.lock().unwrap().iter())
+                .filter(|(publishers, _)| topic::matches(&group.filter, &publishers.topic))
+                .map(|(_, published)| published)
+                .sum();
+            let deadline = *deadline.get_or_insert_with(|| Instant::now() + scenario.drain);
+            if received >= expected || Instant::now() >= deadline {
+                break;
+            }
+        }
+        if let Err(e) = client.set_read_timeout(Some(Duration::from_millis(100))) {
+            error = Some(e);
+            break;
+        }
+        let ack = match client.read_message::<Response>() {
+            Ok(Response::Publish {
+                packet_id,
+                qos,
+                payload,
+                ..
+            }) => {
+                let now = epoch.elapsed();
+                if let Some(stamp) = payload.get(..TIMESTAMP_LEN) {
+                    let sent = Duration::from_nanos(u64::from_be_bytes(stamp.try_into().unwrap()));
+                    if let Some((phase, _)) = scenario.phase_at(sent) {
+                        tally.received[phase] += 1;
+                        tally.receive[phase].record(now.saturating_sub(sent));
+                        received += 1;
+                    }
+                }
+                match qos {
+                    1 => Some(AckType::Puback(packet_id)),
+                    2 => Some(AckType::Pubrec(packet_id)),
+                    _ => None,
+                }
+            }
+            Ok(Response::Pubrel { packet_id }) => Some(AckType::Pubcomp(packet_id)),
+            Ok(_) => None,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                None
+            }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+        if let Some(Err(e)) = ack.map(|ack| client.ack(ack)) {
+            error = Some(e);
+            break;
+        }
+    }
+    let mut report = report.lock().unwrap();
+    tally.merge(&mut report);
+    if let Some(e) = error {
+        report.error(&e);
+    }
+}
+
+/// Run the phases of a scenario against the broker and check its
+/// assertions, which fail the run through `ScenarioReport::failures`.
+/// Every client connects and subscribes before the first phase, failing to
+/// ends the run
+pub fn run(scenario: &Scenario) -> io::Result<ScenarioReport> {
+    let client_id =
+        |group: &str, i: usize| format!("{}-{}-{}", scenario.client_id_prefix, group, i);
+    let mut subscribers = vec![];
+    for group in &scenario.subscribers {
+        for i in 0..group.clients {
+            let mut client = open(
+                &scenario.addrs,
+                &client_id(&group.name, i),
+                scenario.timeout,
+                None,
+            )?;
+            subscribe(&mut client, &group.filter, Qos::from(group.qos))?;
+            subscribers.push((group, client));
+        }
+    }
+    let mut publishers = vec![];
+    for (index, group) in scenario.publishers.iter().enumerate() {
+        for i in 0..group.clients {
+            let client = open(
+                &scenario.addrs,
+                &client_id(&group.name, i),
+                scenario.timeout,
+                None,
+            )?;
+            publishers.push((index, group, client));
+        }
+    }
+
+    let report = Mutex::new(ScenarioReport {
+        name: scenario.name.clone(),
+        started: Some(SystemTime::now()),
+        broker: subscribers
+            .iter()
+            .map(|(_, client)| client)
+            .chain(publishers.iter().map(|(_, _, client)| client))
+            .find_map(|client| client.peer_addr().ok()),
+        phases: scenario
+            .phases
+            .iter()
+            .map(|phase| PhaseReport {
+                name: phase.name.clone(),
+                ..PhaseReport::default()
+            })
+            .collect(),
+        ..ScenarioReport::default()
+    });
+    let published = Mutex::new(vec![0; scenario.publishers.len()]);
+    let done = AtomicBool::new(false);
+    let epoch = Instant::now();
+    thread::scope(|scope| {
+        for (group, client) in subscribers.iter_mut() {
+            let (report, published, done) = (&report, &published, &done);
+            scope.spawn(move || {
+                receive_phases(client, scenario, group, epoch, done, report, published)
+            });
+        }
+        thread::scope(|publishing| {
+            for (i, (index, group, client)) in publishers.iter_mut().enumerate() {
+                let (report, published) = (&report, &published);
+                publishing.spawn(move || {
+                    publish_phases(
+                        client,
+                        scenario,
+                        group,
+                        epoch,
+                        report,
+                        published,
+                        (*index, i),
+                    )
+                });
+            }
+        });
+        done.store(true, Ordering::Release);
+    });
+    for client in subscribers
+        .iter_mut()
+        .map(|(_, client)| client)
+        .chain(publishers.iter_mut().map(|(_, _, client)| client))
+    {
+        let _ = client.disconnect();
+    }
+    let mut report = report.into_inner().unwrap();
+    report.duration = epoch.elapsed();
+    report.check(scenario);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod scenario_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+
+    const SCENARIO: &str = r#"
+name = "test"
+drain = "2s"
+
+[[phase]]
+name = "ramp-up"
+duration = "300ms"
+load = [0, 1]
+
+[[phase]]
+name = "spike"
+duration = 0.2
+load = 2
+
+[[publishers]]
+name = "sensors"
+clients = 2
+topic = "scenario/sensors"
+qos = 1
+rate = 50
+
+[[publishers]]
+name = "unheard"
+topic = "elsewhere"
+size = 8
+payload = "random"
+rate = 10
+
+[[subscribers]]
+name = "dashboards"
+clients = 2
+filter = "scenario/#"
+
+[assert]
+max_p99 = "10s"
+max_error_rate = 0
+"#;
+
+    #[test]
+    fn test_from_toml() -> io::Result<()> {
+        let scenario = Scenario::from_toml(SCENARIO)?;
+        assert_eq!(scenario.phases.len(), 2);
+        assert_eq!(scenario.phases[0].load, (0.0, 1.0));
+        assert_eq!(scenario.phases[1].duration, Duration::from_millis(200));
+        assert_eq!(scenario.publishers[1].qos, 0);
+        assert_eq!(scenario.publishers[1].payload, PayloadKind::Random);
+        assert_eq!(scenario.audience(&scenario.publishers[0]), 2);
+        assert_eq!(scenario.audience(&scenario.publishers[1]), 0);
+        assert_eq!(scenario.max_error_rate, Some(0.0));
+        assert_eq!(
+            scenario.phase_at(Duration::from_millis(150)),
+            Some((0, 0.5))
+        );
+        assert_eq!(
+            scenario.phase_at(Duration::from_millis(400)),
+            Some((1, 2.0))
+        );
+        assert_eq!(scenario.phase_at(Duration::from_millis(500)), None);
+
+        for invalid in [
+            "[[publishers]]\ntopic = \"t\"",
+            "[[phase]]\nduration = 1\n[[publishers]]\ntopic = \"t\"\nsize = 4",
+            "[[phase]]\nduration = 1\n[[publishers]]\ntopic = \"t\"\nqos = 3",
+            "[[phase]]\nduration = \"1y\"\n[[publishers]]\ntopic = \"t\"",
+            "[[phase]]\nduration = 1\nlaod = 2\n[[publishers]]\ntopic = \"t\"",
+        ] {
+            let err = Scenario::from_toml(invalid).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_run() -> io::Result<()> {
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let addr = broker.local_addr()?;
+        thread::spawn(move || broker.run());
+        let mut scenario = Scenario::from_toml(SCENARIO)?;
+        scenario.addrs = vec![addr];
+        let report = run(&scenario)?;
+        assert_eq!(report.phases.len(), 2);
+        let total = report.total();
+        assert!(total.published > 0);
+        assert_eq!(total.received, total.expected);
+        assert!(report.phases[1].received > 0);
+        assert!(report.errors.is_empty());
+        assert!(report.failures.is_empty(), "{:?}", report.failures);
+
+        // Nothing can be delivered within a nanosecond
+        scenario.max_p99 = Some(Duration::from_nanos(1));
+        scenario.phases[1].max_p99 = Some(Duration::from_nanos(1));
+        let report = run(&scenario)?;
+        assert_eq!(report.failures.len(), 2, "{:?}", report.failures);
+        assert!(report
+            .to_string()
+            .contains("Assertions  failed: p99 of spike"));
+        let doc = crate::json::parse(&report.export(&scenario).to_json())?;
+        let spike = doc.get("phases").and_then(|p| p.get("spike")).unwrap();
+        assert!(spike.get("received").and_then(Value::as_f64) > Some(0.0));
+        Ok(())
+    }
+}
@@ -0,0 +1,1050 @@
+use crate::mqtt::{
+    validate_topic_name, ClientHandle, DeliveryToken, Protocol, Qos, Response, ThreadedClient,
+};
+use crate::queue::{Queue, QueueOptions, QueuedMessage};
+use crate::sink::JsonlSink;
+use crate::template::Template;
+use crate::topic::{self, RewriteRule};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often a forwarding loop looks at the stop flag
+const STOP_CHECK: Duration = Duration::from_millis(100);
+
+/// How long a draining bridge waits for its deliveries by default
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a forwarded message is remembered to recognize its echo
+pub const DEFAULT_LOOP_WINDOW: Duration = Duration::from_secs(10);
+
+/// Fingerprints remembered at most per direction, the oldest are forgotten
+/// first when a burst exceeds it
+const MAX_FINGERPRINTS: usize = 100_000;
+
+/// Messages recently forwarded to a broker, so that the copies it sends back
+/// through an overlapping subscription are not forwarded again. MQTT 3.1.1
+/// has no user properties to tag them with, messages are recognized by a
+/// hash of their topic and payload instead.
+///
+/// Each fingerprint matches a single echo, a message published again by
+/// someone else after its echo arrived is forwarded as usual
+#[derive(Debug)]
+pub struct LoopGuard {
+    window: Duration,
+    recent: VecDeque<(Instant, u64)>,
+}
+
+fn fingerprint(topic: &str, payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    topic.hash(&mut hasher);
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl LoopGuard {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(at, _)) = self.recent.front() {
+            if now.duration_since(at) < self.window && self.recent.len() < MAX_FINGERPRINTS {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    /// Remember a message about to be forwarded
+    pub fn record(&mut self, topic: &str, payload: &[u8]) {
+        let now = Instant::now();
+        self.expire(now);
+        self.recent.push_back((now, fingerprint(topic, payload)));
+    }
+
+    /// Whether the message is the echo of one forwarded within the window,
+    /// its fingerprint is consumed
+    pub fn is_echo(&mut self, topic: &str, payload: &[u8]) -> bool {
+        self.expire(Instant::now());
+        let fingerprint = fingerprint(topic, payload);
+        match self.recent.iter().position(|&(_, f)| f == fingerprint) {
+            Some(i) => {
+                self.recent.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// What a bridge forwards, see `Bridge`
+#[derive(Debug, Clone)]
+pub struct BridgeOptions {
+    /// Filters subscribed on the local broker, forwarded to the remote one
+    pub outgoing: Vec<String>,
+    /// Filters subscribed on the remote broker, forwarded to the local one
+    pub incoming: Vec<String>,
+    /// Applied to the topics of outgoing messages, reversed on incoming ones
+    pub rewrites: Vec<RewriteRule>,
+    /// Topic of outgoing messages instead of the rewritten one, see
+    /// `topic_from_template`
+    pub topic_template: Option<Template>,
+    /// QoS of the subscriptions, messages keep the QoS and retain flag they
+    /// are received with
+    pub qos: Qos,
+    pub loop_window: Duration,
+    /// Raised to drain the bridge: no more messages are taken from either
+    /// broker, the deliveries in flight are awaited up to `drain_timeout`
+    /// and what couldn't be delivered goes to `spill`
+    pub stop: Option<Arc<AtomicBool>>,
+    pub drain_timeout: Duration,
+    /// Messages received but not delivered once stopped, appended as JSON
+    /// lines with their destination topic. Unused with a queue
+    pub spill: Option<PathBuf>,
+    /// Directory of the durable queues of messages received but not
+    /// delivered yet, one per direction, replayed when the bridge starts
+    /// again
+    pub queue: Option<PathBuf>,
+    pub queue_options: QueueOptions,
+}
+
+impl Default for BridgeOptions {
+    fn default() -> Self {
+        Self {
+            outgoing: vec![],
+            incoming: vec![],
+            rewrites: vec![],
+            topic_template: None,
+            qos: Qos::AtLeastOnce,
+            loop_window: DEFAULT_LOOP_WINDOW,
+            stop: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            spill: None,
+            queue: None,
+            queue_options: QueueOptions::default(),
+        }
+    }
+}
+
+/// Messages handled by a bridge until it stopped
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BridgeStats {
+    pub outgoing: u64,
+    pub incoming: u64,
+    /// Echoes of forwarded messages that were dropped
+    pub looped: u64,
+    /// Messages the topic template didn't apply to, not forwarded
+    pub skipped: u64,
+    /// Messages received but not delivered when the bridge stopped, kept in
+    /// the queue or spilled if there's either
+    pub undelivered: u64,
+    /// Messages left in the queues by a previous run, forwarded first and
+    /// counted in `outgoing` and `incoming` as well
+    pub replayed: u64,
+}
+
+/// Expand `template` for a message, `{{topic}}` being its topic and JSON
+/// placeholders reading its payload. `None` if the payload doesn't have
+/// the values or they don't make a valid topic name
+pub fn topic_from_template(template: &Template, topic: &str, payload: &[u8]) -> Option<String> {
+    let lookup = |name: &str| (name == "topic").then(|| topic.to_string());
+    template
+        .expand_payload(payload, lookup)
+        .ok()
+        .filter(|topic| validate_topic_name(topic).is_ok())
+}
+
+/// Messages handled by one direction of a bridge
+#[derive(Debug, Default)]
+struct Counts {
+    forwarded: u64,
+    looped: u64,
+    skipped: u64,
+    undelivered: u64,
+    replayed: u64,
+}
+
+/// A message on its way to the destination, `seq` numbering its copy in
+/// the queue
+struct Outgoing {
+    topic: String,
+    payload: Vec<u8>,
+    qos: Qos,
+    retain: bool,
+    seq: Option<u64>,
+}
+
+/// A message forwarded, until the destination acknowledges it
+struct Pending {
+    token: DeliveryToken,
+    message: Outgoing,
+}
+
+/// Directions of a bridge done with their deliveries. A draining direction
+/// closes the connections once the other one doesn't need them anymore
+#[derive(Debug)]
+struct Settled {
+    count: Mutex<usize>,
+    all: Condvar,
+    directions: usize,
+}
+
+impl Settled {
+    fn new(directions: usize) -> Arc<Self> {
+        Arc::new(Self {
+            count: Mutex::new(0),
+            all: Condvar::new(),
+            directions,
+        })
+    }
+
+    fn settle(&self) {
+        *self.count.lock().unwrap() += 1;
+        self.all.notify_all();
+    }
+
+    /// Wait for every direction to settle, up to `deadline`
+    fn wait(&self, deadline: Instant) {
+        let count = self.count.lock().unwrap();
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let _ = self
+            .all
+            .wait_timeout_while(count, timeout, |count| *count < self.directions);
+    }
+}
+
+/// How the directions of a bridge stop gracefully, see `BridgeOptions::stop`
+#[derive(Clone)]
+struct Drain {
+    stop: Arc<AtomicBool>,
+    timeout: Duration,
+    spill: Option<Arc<Mutex<JsonlSink>>>,
+    settled: Arc<Settled>,
+}
+
+impl Drain {
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::Acquire)
+    }
+}
+
+/// Forwards messages between two brokers, in both directions when
+/// subscribing on both sides
+pub struct Bridge {
+    local: Protocol,
+    remote: Protocol,
+    options: BridgeOptions,
+}
+
+/// One direction of a bridge
+struct Forward {
+    filters: Vec<String>,
+    qos: Qos,
+    rewrites: Vec<RewriteRule>,
+    rewrite: fn(&[RewriteRule], &str) -> String,
+    /// Replaces the rewrites when set
+    topic_template: Option<Template>,
+    /// Recorded on forwarding to the destination
+    sent: Arc<Mutex<LoopGuard>>,
+    /// Checked on receiving from the source
+    echoes: Arc<Mutex<LoopGuard>>,
+    /// Stop forwarding at this point in time
+    deadline: Option<Instant>,
+    /// Stop once this many messages were forwarded
+    limit: Option<u64>,
+    drain: Option<Drain>,
+    queue: Option<Mutex<Queue>>,
+    /// Left in the queue by a previous run
+    replay: Vec<QueuedMessage>,
+}
+
+impl Forward {
+    /// Forward until the source disconnects or a stop condition is met, then
+    /// disconnect the destination
+    fn run(mut self, from: ThreadedClient, to: ClientHandle) -> io::Result<Counts> {
+        let mut counts = Counts::default();
+        let mut pending = vec![];
+        let replay = std::mem::take(&mut self.replay);
+        let mut result = self.forward(&from, &to, replay, &mut counts, &mut pending);
+        match &self.drain {
+            Some(drain) if drain.stopping() => {
+                result = result.and(self.drain(drain, &from, pending, &mut counts));
+            }
+            // Not stopped, no need to wait for this direction
+            Some(drain) => {
+                drain.settled.settle();
+                result = result.and(self.check(&mut pending, &mut counts));
+            }
+            None => result = result.and(self.check(&mut pending, &mut counts)),
+        }
+        // The other direction ends as well once its source is gone
+        let _ = to.disconnect();
+        // Closing the connection fails if the broker already closed it
+        let joined = match from.join() {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotConnected
+                        | io::ErrorKind::BrokenPipe
+                        | io::ErrorKind::ConnectionReset
+                ) =>
+            {
+                Ok(())
+            }
+            joined => joined,
+        };
+        result.and(joined)?;
+        Ok(counts)
+    }
+
+    /// Topic a message goes to at the destination, `None` for echoes and
+    /// messages the topic template doesn't apply to
+    fn destination(&self, topic: &str, payload: &[u8], counts: &mut Counts) -> Option<String> {
+        if self.echoes.lock().unwrap().is_echo(topic, payload) {
+            counts.looped += 1;
+            return None;
+        }
+        match &self.topic_template {
+            Some(template) => {
+                let topic = topic_from_template(template, topic, payload);
+                if topic.is_none() {
+                    counts.skipped += 1;
+                }
+                topic
+            }
+            None => Some((self.rewrite)(&self.rewrites, topic)),
+        }
+    }
+
+    /// The message to forward for a packet from the source, `None` if it's
+    /// not a PUBLISH or not to be forwarded. A PUBLISH with the reserved QoS
+    /// 3 is malformed, it's dropped rather than guessed at
+    fn outgoing(&self, response: Response, counts: &mut Counts) -> Option<Outgoing> {
+        let Response::Publish {
+            qos,
+            retain,
+            topic,
+            payload,
+            ..
+        } = response
+        else {
+            return None;
+        };
+        let Some(qos) = Qos::checked(qos) else {
+            eprintln!(
+                "Dropping a PUBLISH on {} with the invalid QoS {}",
+                topic, qos
+            );
+            return None;
+        };
+        let topic = self.destination(&topic, &payload, counts)?;
+        Some(Outgoing {
+            topic,
+            payload,
+            qos,
+            retain,
+            seq: None,
+        })
+    }
+
+    fn forward(
+        &self,
+        from: &ThreadedClient,
+        to: &ClientHandle,
+        replay: Vec<QueuedMessage>,
+        counts: &mut Counts,
+        pending: &mut Vec<Pending>,
+    ) -> io::Result<()> {
+        for filter in &self.filters {
+            from.handle.subscribe(filter, self.qos)?;
+        }
+        for message in replay {
+            counts.replayed += 1;
+            let message = Outgoing {
+                topic: message.topic,
+                payload: message.payload,
+                qos: message.qos,
+                retain: message.retain,
+                seq: Some(message.seq),
+            };
+            if !self.send(to, message, counts, pending)? {
+                return Ok(());
+            }
+        }
+        loop {
+            if self.drain.as_ref().is_some_and(Drain::stopping) {
+                break;
+            }
+            let wait = match self.deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => STOP_CHECK,
+            };
+            let response = match from.incoming.recv_timeout(wait.min(STOP_CHECK)) {
+                Ok(response) => response,
+                Err(RecvTimeoutError::Timeout) if wait > STOP_CHECK || self.deadline.is_none() => {
+                    continue
+                }
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+            };
+            let Some(message) = self.outgoing(response, counts) else {
+                continue;
+            };
+            if !self.send(to, message, counts, pending)? {
+                break;
+            }
+            if self.limit.is_some_and(|limit| counts.forwarded >= limit) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish a message to the destination, queued first if there's a
+    /// queue. `false` once the destination is gone
+    fn send(
+        &self,
+        to: &ClientHandle,
+        mut message: Outgoing,
+        counts: &mut Counts,
+        pending: &mut Vec<Pending>,
+    ) -> io::Result<bool> {
+        if let (Some(queue), None) = (&self.queue, message.seq) {
+            let mut queue = queue.lock().unwrap();
+            message.seq = Some(queue.push(
+                &message.topic,
+                &message.payload,
+                message.qos,
+                message.retain,
+            )?);
+        }
+        self.sent
+            .lock()
+            .unwrap()
+            .record(&message.topic, &message.payload);
+        let delivery = match message.retain {
+            true => to.publish_retained(&message.topic, &message.payload, message.qos),
+            false => to.publish(&message.topic, &message.payload, message.qos),
+        };
+        match delivery {
+            Ok(token) => {
+                counts.forwarded += 1;
+                // Only followed up when there's something to do about it
+                if self.drain.is_some() || self.queue.is_some() {
+                    self.check(pending, counts)?;
+                    pending.push(Pending { token, message });
+                }
+                Ok(true)
+            }
+            // The destination may have gone away, its own direction reports
+            // why
+            Err(_) => {
+                self.keep(message, counts)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Forget the deliveries completed
+    fn check(&self, pending: &mut Vec<Pending>, counts: &mut Counts) -> io::Result<()> {
+        for delivery in std::mem::take(pending) {
+            match delivery.token.try_result() {
+                None => pending.push(delivery),
+                Some(result) => self.settle(delivery.message, result.is_ok(), counts)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Acknowledge a delivered message in the queue, keep the others
+    fn settle(&self, message: Outgoing, delivered: bool, counts: &mut Counts) -> io::Result<()> {
+        if !delivered {
+            counts.forwarded -= 1;
+            return self.keep(message, counts);
+        }
+        match (&self.queue, message.seq) {
+            (Some(queue), Some(seq)) => queue.lock().unwrap().ack(seq),
+            _ => Ok(()),
+        }
+    }
+
+    /// Keep a message that couldn't be delivered: in the queue for the next
+    /// run if there's one, in the spill file otherwise
+    fn keep(&self, message: Outgoing, counts: &mut Counts) -> io::Result<()> {
+        counts.undelivered += 1;
+        if let Some(queue) = &self.queue {
+            if message.seq.is_none() {
+                let mut queue = queue.lock().unwrap();
+                queue.push(
+                    &message.topic,
+                    &message.payload,
+                    message.qos,
+                    message.retain,
+                )?;
+            }
+            return Ok(());
+        }
+        match self.drain.as_ref().and_then(|drain| drain.spill.as_ref()) {
+            Some(sink) => {
+                let mut sink = sink.lock().unwrap();
+                sink.write(&message.topic, &message.payload, SystemTime::now())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Wait for the deliveries in flight up to the drain timeout, then keep
+    /// those that failed and the messages the source sent since the stop
+    fn drain(
+        &self,
+        drain: &Drain,
+        from: &ThreadedClient,
+        pending: Vec<Pending>,
+        counts: &mut Counts,
+    ) -> io::Result<()> {
+        let deadline = Instant::now() + drain.timeout;
+        for delivery in pending {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let delivered = delivery.token.wait_timeout(left).is_ok();
+            self.settle(delivery.message, delivered, counts)?;
+        }
+        drain.settled.settle();
+        drain.settled.wait(deadline);
+        // The other direction delivers nothing to the source anymore, once
+        // closed everything it received is in `incoming`
+        let _ = from.handle.disconnect();
+        while let Ok(response) = from.incoming.recv_timeout(drain.timeout) {
+            if let Some(message) = self.outgoing(response, counts) {
+                self.keep(message, counts)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bridge {
+    /// Both connections must already be established, usually through
+    /// `Protocol::builder()`
+    pub fn new(local: Protocol, remote: Protocol, options: BridgeOptions) -> Self {
+        Self {
+            local,
+            remote,
+            options,
+        }
+    }
+
+    /// Forward messages until either connection closes or the stop flag of
+    /// the options is raised
+    pub fn run(self) -> io::Result<BridgeStats> {
+        let local = ThreadedClient::spawn(self.local)?;
+        let remote = ThreadedClient::spawn(self.remote)?;
+        let to_local = Arc::new(Mutex::new(LoopGuard::new(self.options.loop_window)));
+        let to_remote = Arc::new(Mutex::new(LoopGuard::new(self.options.loop_window)));
+        let spill = match (&self.options.spill, &self.options.queue) {
+            (Some(path), None) => Some(Arc::new(Mutex::new(JsonlSink::open(path)?))),
+            _ => None,
+        };
+        let queue = |direction: &str| -> io::Result<(Option<Mutex<Queue>>, Vec<_>)> {
+            match &self.options.queue {
+                Some(dir) => {
+                    let options = self.options.queue_options.clone();
+                    let (queue, replay) = Queue::open(dir.join(direction), options)?;
+                    Ok((Some(Mutex::new(queue)), replay))
+                }
+                None => Ok((None, vec![])),
+            }
+        };
+        let (outgoing_queue, outgoing_replay) = queue("outgoing")?;
+        let (incoming_queue, incoming_replay) = queue("incoming")?;
+        let drain = self.options.stop.map(|stop| Drain {
+            stop,
+            timeout: self.options.drain_timeout,
+            spill: spill.clone(),
+            settled: Settled::new(2),
+        });
+        let outgoing = Forward {
+            filters: self.options.outgoing,
+            qos: self.options.qos,
+            rewrites: self.options.rewrites.clone(),
+            rewrite: topic::rewrite,
+            topic_template: self.options.topic_template,
+            sent: Arc::clone(&to_remote),
+            echoes: Arc::clone(&to_local),
+            deadline: None,
+            limit: None,
+            drain: drain.clone(),
+            queue: outgoing_queue,
+            replay: outgoing_replay,
+        };
+        let incoming = Forward {
+            filters: self.options.incoming,
+            qos: self.options.qos,
+            rewrites: self.options.rewrites,
+            rewrite: topic::rewrite_back,
+            topic_template: None,
+            sent: to_local,
+            echoes: to_remote,
+            deadline: None,
+            limit: None,
+            drain,
+            queue: incoming_queue,
+            replay: incoming_replay,
+        };
+        let remote_handle = remote.handle.clone();
+        let local_handle = local.handle.clone();
+        let outgoing = thread::Builder::new()
+            .name("sake-bridge".into())
+            .spawn(move || outgoing.run(local, remote_handle))?;
+        let incoming = incoming.run(remote, local_handle);
+        let outgoing = outgoing
+            .join()
+            .map_err(|_| io::Error::other("Bridge thread panicked"))?;
+        let (outgoing, incoming) = (outgoing?, incoming?);
+        if let Some(spill) = spill {
+            spill.lock().unwrap().sync()?;
+        }
+        Ok(BridgeStats {
+            outgoing: outgoing.forwarded,
+            incoming: incoming.forwarded,
+            looped: outgoing.looped + incoming.looped,
+            skipped: outgoing.skipped,
+            undelivered: outgoing.undelivered + incoming.undelivered,
+            replayed: outgoing.replayed + incoming.replayed,
+        })
+    }
+}
+
+/// What `copy` relays and when it stops
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    /// Filters subscribed on the source broker
+    pub filters: Vec<String>,
+    /// Applied to the topics of the messages copied
+    pub rewrites: Vec<RewriteRule>,
+    /// Topic of the messages copied instead of the rewritten one, see
+    /// `topic_from_template`
+    pub topic_template: Option<Template>,
+    /// QoS of the subscriptions, messages keep the QoS and retain flag they
+    /// are received with
+    pub qos: Qos,
+    /// Stop after this long
+    pub duration: Option<Duration>,
+    /// Stop after copying this many messages
+    pub count: Option<u64>,
+    pub loop_window: Duration,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            filters: vec![],
+            rewrites: vec![],
+            topic_template: None,
+            qos: Qos::AtLeastOnce,
+            duration: None,
+            count: None,
+            loop_window: DEFAULT_LOOP_WINDOW,
+        }
+    }
+}
+
+/// One way bridge stopping on its own, after `duration` or `count` messages,
+/// or when either connection closes. Without a destination messages are
+/// copied within the source broker, where copies matching the filters again
+/// are recognized and not copied twice. Only `outgoing`, `looped` and
+/// `skipped` of the stats are counted
+pub fn copy(from: Protocol, to: Option<Protocol>, options: CopyOptions) -> io::Result<BridgeStats> {
+    let from = ThreadedClient::spawn(from)?;
+    let to = to.map(ThreadedClient::spawn).transpose()?;
+    let guard = Arc::new(Mutex::new(LoopGuard::new(options.loop_window)));
+    let forward = Forward {
+        filters: options.filters,
+        qos: options.qos,
+        rewrites: options.rewrites,
+        rewrite: topic::rewrite,
+        topic_template: options.topic_template,
+        sent: Arc::clone(&guard),
+        echoes: guard,
+        deadline: options.duration.map(|d| Instant::now() + d),
+        limit: options.count,
+        drain: None,
+        queue: None,
+        replay: vec![],
+    };
+    let handle = to.as_ref().map_or(&from.handle, |to| &to.handle).clone();
+    let counts = forward.run(from, handle)?;
+    if let Some(to) = to {
+        to.join()?;
+    }
+    Ok(BridgeStats {
+        outgoing: counts.forwarded,
+        incoming: 0,
+        looped: counts.looped,
+        skipped: counts.skipped,
+        undelivered: 0,
+        replayed: 0,
+    })
+}
+
+#[cfg(test)]
+mod bridge_tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+
+    #[test]
+    fn test_loop_guard() {
+        let mut guard = LoopGuard::new(DEFAULT_LOOP_WINDOW);
+        guard.record("a", b"1");
+        guard.record("a", b"1");
+        assert!(!guard.is_echo("a", b"2"));
+        assert!(!guard.is_echo("b", b"1"));
+        assert!(guard.is_echo("a", b"1"));
+        assert!(guard.is_echo("a", b"1"));
+        assert!(!guard.is_echo("a", b"1"));
+
+        let mut expired = LoopGuard::new(Duration::ZERO);
+        expired.record("a", b"1");
+        assert!(!expired.is_echo("a", b"1"));
+    }
+
+    #[test]
+    fn test_topic_from_template() -> io::Result<()> {
+        let template: Template = "{{topic}}/{{json:$.device_id}}".parse()?;
+        let payload = br#"{"device_id": "pump-7"}"#;
+        assert_eq!(
+            topic_from_template(&template, "plant", payload).as_deref(),
+            Some("plant/pump-7")
+        );
+        assert_eq!(topic_from_template(&template, "plant", b"{}"), None);
+        // Wildcards can't end up in a topic name
+        let payload = br#"{"device_id": "+"}"#;
+        assert_eq!(topic_from_template(&template, "plant", payload), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_echo_not_forwarded_back() -> io::Result<()> {
+        let subscribe = [0x82, 6, 0, 1, 0, 1, b'#', 0];
+        let publish = [0x30, 4, 0, 1, b'a', b'1'];
+        let local = TcpListener::bind("127.0.0.1:0")?;
+        let remote = TcpListener::bind("127.0.0.1:0")?;
+        let (local_addr, remote_addr) = (local.local_addr()?, remote.local_addr()?);
+        let local = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let (mut stream, _) = local.accept()?;
+            let mut received = vec![0; subscribe.len()];
+            stream.read_exact(&mut received)?;
+            stream.write_all(&publish)?;
+            stream.read_to_end(&mut received)?;
+            Ok(received)
+        });
+        // Delivers the forwarded message back, as a broker would to a
+        // subscription matching it
+        let remote = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let (mut stream, _) = remote.accept()?;
+            let mut received = vec![0; subscribe.len() + publish.len()];
+            stream.read_exact(&mut received)?;
+            stream.write_all(&publish)?;
+            Ok(received)
+        });
+
+        let options = BridgeOptions {
+            outgoing: vec!["#".into()],
+            incoming: vec!["#".into()],
+            qos: Qos::AtMostOnce,
+            ..BridgeOptions::default()
+        };
+        let bridge = Bridge::new(
+            Protocol::connect(local_addr)?,
+            Protocol::connect(remote_addr)?,
+            options,
+        );
+        let stats = bridge.run()?;
+        assert_eq!(
+            stats,
+            BridgeStats {
+                outgoing: 1,
+                incoming: 0,
+                looped: 1,
+                skipped: 0,
+                undelivered: 0,
+                replayed: 0,
+            }
+        );
+        // Each direction writes from its own thread, in no particular order
+        let received = remote.join().unwrap()?;
+        assert!(
+            received == [&subscribe[..], &publish].concat()
+                || received == [&publish[..], &subscribe].concat()
+        );
+        // Only the subscription and the DISCONNECT, the echo never came back
+        assert_eq!(
+            local.join().unwrap()?,
+            [&subscribe[..], &[0xE0, 0]].concat()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_qos_dropped() -> io::Result<()> {
+        let subscribe = [0x82, 6, 0, 1, 0, 1, b'#', 0];
+        // QoS 3 is reserved, then a valid QoS 0 PUBLISH
+        let malformed = [0x36, 6, 0, 1, b'a', 0, 1, b'x'];
+        let publish = [0x30, 4, 0, 1, b'b', b'y'];
+        let source = TcpListener::bind("127.0.0.1:0")?;
+        let destination = TcpListener::bind("127.0.0.1:0")?;
+        let (source_addr, destination_addr) = (source.local_addr()?, destination.local_addr()?);
+        let source = thread::spawn(move || -> io::Result<()> {
+            let (mut stream, _) = source.accept()?;
+            stream.read_exact(&mut vec![0; subscribe.len()])?;
+            stream.write_all(&[&malformed[..], &publish].concat())
+        });
+        let destination = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let (mut stream, _) = destination.accept()?;
+            let mut received = vec![];
+            stream.read_to_end(&mut received)?;
+            Ok(received)
+        });
+
+        let options = CopyOptions {
+            filters: vec!["#".into()],
+            qos: Qos::AtMostOnce,
+            ..CopyOptions::default()
+        };
+        let stats = copy(
+            Protocol::connect(source_addr)?,
+            Some(Protocol::connect(destination_addr)?),
+            options,
+        )?;
+        source.join().unwrap()?;
+        assert_eq!(stats.outgoing, 1);
+        assert_eq!(
+            destination.join().unwrap()?,
+            [&publish[..], &[0xE0, 0]].concat()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_within_broker() -> io::Result<()> {
+        use crate::broker::{Broker, BrokerOptions};
+        use crate::mqtt::PublishOptions;
+
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let addr = broker.local_addr()?;
+        thread::spawn(move || broker.run());
+        let connect = |client_id: &str| {
+            Protocol::builder()
+                .addrs(&[addr])
+                .client_id(client_id)
+                .connect()
+        };
+        let retained = PublishOptions {
+            retain: true,
+            ..PublishOptions::default()
+        };
+        let mut publisher = connect("publisher")?;
+        publisher.publish_with("src/r", b"kept", &retained)?;
+
+        // Copies to a topic matching the filter again, its echo is dropped
+        let options = CopyOptions {
+            filters: vec!["#".into()],
+            rewrites: vec!["src/# -> dst/#".parse()?],
+            duration: Some(Duration::from_secs(1)),
+            ..CopyOptions::default()
+        };
+        let copier = connect("copier")?;
+        let copy = thread::spawn(move || copy(copier, None, options));
+        thread::sleep(Duration::from_millis(200));
+        publisher.publish_with("src/a", b"live", &PublishOptions::default())?;
+        let stats = copy.join().unwrap()?;
+        assert_eq!(
+            stats,
+            BridgeStats {
+                outgoing: 2,
+                incoming: 0,
+                looped: 2,
+                skipped: 0,
+                undelivered: 0,
+                replayed: 0,
+            }
+        );
+
+        let mut checker = connect("checker")?;
+        checker.set_read_timeout(Some(Duration::from_secs(5)))?;
+        checker.subscribe("dst/#", Qos::AtMostOnce)?;
+        loop {
+            match checker.read_message::<Response>()? {
+                Response::Publish {
+                    retain,
+                    topic,
+                    payload,
+                    ..
+                } => {
+                    assert!(retain);
+                    assert_eq!((topic.as_str(), &payload[..]), ("dst/r", &b"kept"[..]));
+                    break;
+                }
+                Response::Suback { .. } => {}
+                resp => panic!("Unexpected {}", resp),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_drain_spills_undelivered() -> io::Result<()> {
+        use crate::broker::{Broker, BrokerOptions};
+
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let local_addr = broker.local_addr()?;
+        thread::spawn(move || broker.run());
+        // Never acknowledges, the deliveries stay in flight
+        let remote = TcpListener::bind("127.0.0.1:0")?;
+        let remote_addr = remote.local_addr()?;
+        let remote = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let (mut stream, _) = remote.accept()?;
+            let mut received = vec![];
+            stream.read_to_end(&mut received)?;
+            Ok(received)
+        });
+
+        let path = std::env::temp_dir().join(format!("sake-spill-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let stop = Arc::new(AtomicBool::new(false));
+        let options = BridgeOptions {
+            outgoing: vec!["drain/#".into()],
+            stop: Some(Arc::clone(&stop)),
+            drain_timeout: Duration::from_millis(200),
+            spill: Some(path.clone()),
+            ..BridgeOptions::default()
+        };
+        let local = Protocol::builder()
+            .addrs(&[local_addr])
+            .client_id("bridge")
+            .connect()?;
+        let bridge = Bridge::new(local, Protocol::connect(remote_addr)?, options);
+        let bridge = thread::spawn(move || bridge.run());
+        thread::sleep(Duration::from_millis(200));
+        let mut publisher = Protocol::builder()
+            .addrs(&[local_addr])
+            .client_id("publisher")
+            .connect()?;
+        publisher.publish("drain/a", b"1")?;
+        publisher.publish("drain/b", b"2")?;
+        thread::sleep(Duration::from_millis(200));
+        stop.store(true, Ordering::Release);
+        let stats = bridge.join().unwrap()?;
+        assert_eq!((stats.outgoing, stats.undelivered), (0, 2));
+        // Both were sent, then the DISCONNECT
+        assert!(remote.join().unwrap()?.ends_with(&[0xE0, 0]));
+
+        let spilled = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        let topics = spilled
+            .lines()
+            .map(|line| {
+                let value = crate::json::parse(line)?;
+                Ok(value
+                    .get("topic")
+                    .and_then(|t| t.as_str())
+                    .map(String::from))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(topics, [Some("drain/a".into()), Some("drain/b".into())]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_replayed() -> io::Result<()> {
+        use crate::broker::{Broker, BrokerOptions};
+
+        let start = || -> io::Result<SocketAddr> {
+            let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+            let addr = broker.local_addr()?;
+            thread::spawn(move || broker.run());
+            Ok(addr)
+        };
+        let connect = |addr: SocketAddr, client_id: &str| {
+            Protocol::builder()
+                .addrs(&[addr])
+                .client_id(client_id)
+                .connect()
+        };
+        let local_addr = start()?;
+        let dir = std::env::temp_dir().join(format!("sake-bridge-queue-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let stop = Arc::new(AtomicBool::new(false));
+        let options = BridgeOptions {
+            outgoing: vec!["queue/#".into()],
+            stop: Some(Arc::clone(&stop)),
+            drain_timeout: Duration::from_millis(200),
+            queue: Some(dir.clone()),
+            ..BridgeOptions::default()
+        };
+
+        // The first remote never acknowledges, both messages stay queued
+        let remote = TcpListener::bind("127.0.0.1:0")?;
+        let remote_addr = remote.local_addr()?;
+        let remote = thread::spawn(move || -> io::Result<()> {
+            let (mut stream, _) = remote.accept()?;
+            stream.read_to_end(&mut vec![])?;
+            Ok(())
+        });
+        let bridge = Bridge::new(
+            connect(local_addr, "bridge")?,
+            Protocol::connect(remote_addr)?,
+            options.clone(),
+        );
+        let bridge = thread::spawn(move || bridge.run());
+        thread::sleep(Duration::from_millis(200));
+        let mut publisher = connect(local_addr, "publisher")?;
+        publisher.publish("queue/a", b"1")?;
+        publisher.publish("queue/b", b"2")?;
+        thread::sleep(Duration::from_millis(200));
+        stop.store(true, Ordering::Release);
+        let stats = bridge.join().unwrap()?;
+        remote.join().unwrap()?;
+        assert_eq!((stats.outgoing, stats.undelivered), (0, 2));
+
+        // The next run delivers them first
+        let remote_addr = start()?;
+        let mut subscriber = connect(remote_addr, "subscriber")?;
+        subscriber.set_read_timeout(Some(Duration::from_secs(5)))?;
+        subscriber.subscribe("queue/#", Qos::AtMostOnce)?;
+        stop.store(false, Ordering::Release);
+        let bridge = Bridge::new(
+            connect(local_addr, "bridge")?,
+            connect(remote_addr, "bridge")?,
+            options,
+        );
+        let bridge = thread::spawn(move || bridge.run());
+        let mut received = vec![];
+        while received.len() < 2 {
+            if let Response::Publish { topic, .. } = subscriber.read_message::<Response>()? {
+                received.push(topic);
+            }
+        }
+        assert_eq!(received, ["queue/a", "queue/b"]);
+        thread::sleep(Duration::from_millis(200));
+        stop.store(true, Ordering::Release);
+        let stats = bridge.join().unwrap()?;
+        assert_eq!(
+            (stats.outgoing, stats.replayed, stats.undelivered),
+            (2, 2, 0)
+        );
+        let (_, pending) = crate::queue::inspect(dir.join("outgoing"))?;
+        assert!(pending.is_empty());
+        std::fs::remove_dir_all(&dir)
+    }
+}
@@ -0,0 +1,196 @@
+use crate::broker::bcrypt;
+use crate::topic;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn invalid_line(file: &str, n: usize, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid {} line {}: {}", file, n + 1, reason),
+    )
+}
+
+/// Lines of a configuration file without blank ones and `#` comments, with
+/// their index for errors
+fn lines(s: &str) -> impl Iterator<Item = (usize, &str)> {
+    s.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// `user:hash` lines with bcrypt hashes, as written by `htpasswd -B`
+#[derive(Debug, Clone, Default)]
+pub struct PasswordFile {
+    hashes: HashMap<String, String>,
+}
+
+impl std::str::FromStr for PasswordFile {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut hashes = HashMap::new();
+        for (n, line) in lines(s) {
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| invalid_line("password file", n, "expected user:hash"))?;
+            // Checked now rather than refusing every login of that user
+            if !bcrypt::is_valid(hash) {
+                return Err(invalid_line("password file", n, "not a bcrypt hash"));
+            }
+            hashes.insert(user.to_string(), hash.to_string());
+        }
+        Ok(Self { hashes })
+    }
+}
+
+impl PasswordFile {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Whether `user` is listed and `password` matches its hash
+    pub fn check(&self, user: &str, password: &[u8]) -> bool {
+        let Ok(password) = std::str::from_utf8(password) else {
+            return false;
+        };
+        self.hashes
+            .get(user)
+            .is_some_and(|hash| bcrypt::verify(password, hash).unwrap_or(false))
+    }
+}
+
+/// What an ACL rule allows on the topics matching its pattern
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn allows(self, wanted: Access) -> bool {
+        self == Access::ReadWrite || self == wanted
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct AclRule {
+    /// `None` for `*`, applying to every client, anonymous ones included
+    user: Option<String>,
+    pattern: String,
+    access: Access,
+}
+
+/// `user pattern read|write|readwrite` lines, where `pattern` is a topic
+/// filter and `*` as user applies to every client. Anything no rule allows
+/// is denied
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+/// Whether every topic matching `filter` also matches `pattern`
+fn covers(pattern: &str, filter: &str) -> bool {
+    let mut levels = filter.split('/');
+    for part in pattern.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(level)) if level != "#" => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+impl std::str::FromStr for Acl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = vec![];
+        for (n, line) in lines(s) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [user, pattern, access] = fields[..] else {
+                return Err(invalid_line("ACL", n, "expected user pattern access"));
+            };
+            let access = match access {
+                "read" => Access::Read,
+                "write" => Access::Write,
+                "readwrite" => Access::ReadWrite,
+                _ => return Err(invalid_line("ACL", n, "expected read, write or readwrite")),
+            };
+            rules.push(AclRule {
+                user: (user != "*").then(|| user.to_string()),
+                pattern: pattern.to_string(),
+                access,
+            });
+        }
+        Ok(Self { rules })
+    }
+}
+
+impl Acl {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    fn rules_of<'a>(&'a self, user: Option<&'a str>) -> impl Iterator<Item = &'a AclRule> {
+        self.rules
+            .iter()
+            .filter(move |rule| rule.user.is_none() || rule.user.as_deref() == user)
+    }
+
+    /// Whether `user` may publish on `topic`
+    pub fn can_write(&self, user: Option<&str>, topic: &str) -> bool {
+        self.rules_of(user)
+            .any(|rule| rule.access.allows(Access::Write) && topic::matches(&rule.pattern, topic))
+    }
+
+    /// Whether `user` may subscribe to `filter`, a single rule has to cover
+    /// every topic it matches
+    pub fn can_read(&self, user: Option<&str>, filter: &str) -> bool {
+        self.rules_of(user)
+            .any(|rule| rule.access.allows(Access::Read) && covers(&rule.pattern, filter))
+    }
+}
+
+#[cfg(test)]
+mod auth_tests {
+    use super::*;
+
+    #[test]
+    fn test_password_file() -> io::Result<()> {
+        let file: PasswordFile = "# users\n\
+            alice:$2b$04$/uaF/uaF/uaF/uaF/uaF/uyUa5B4sNev9rTvA6TvEBWYO4koffwMy\n"
+            .parse()?;
+        assert!(file.check("alice", b"secret"));
+        assert!(!file.check("alice", b"wrong"));
+        assert!(!file.check("bob", b"secret"));
+        assert!("alice:secret".parse::<PasswordFile>().is_err());
+        assert!("alice".parse::<PasswordFile>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_acl() -> io::Result<()> {
+        let acl: Acl = "alice sensors/# readwrite\n\
+            bob sensors/+/temp read\n\
+            * public/# read\n"
+            .parse()?;
+        assert!(acl.can_write(Some("alice"), "sensors/a/temp"));
+        assert!(!acl.can_write(Some("bob"), "sensors/a/temp"));
+        assert!(acl.can_read(Some("bob"), "sensors/a/temp"));
+        assert!(acl.can_read(Some("bob"), "sensors/+/temp"));
+        assert!(!acl.can_read(Some("bob"), "sensors/#"));
+        assert!(!acl.can_read(Some("bob"), "sensors/a/+"));
+        assert!(acl.can_read(None, "public/news"));
+        assert!(!acl.can_write(None, "public/news"));
+        assert!(!acl.can_read(None, "sensors/a/temp"));
+        assert!("alice sensors/#".parse::<Acl>().is_err());
+        assert!("alice sensors/# delete".parse::<Acl>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::io;
+
+const BCRYPT_ALPHABET: &[u8; 64] =
+    b"./ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+/// Salt and hash, 16 and 23 bytes in the bcrypt base64
+const ENCODED_LEN: usize = 22 + 31;
+const MIN_COST: u32 = 4;
+const MAX_COST: u32 = 31;
+
+/// Whether `hash` is a `$2a$`, `$2b$` or `$2y$` bcrypt hash, as written by
+/// `htpasswd -B`, with a two digit cost
+pub fn is_valid(hash: &str) -> bool {
+    let mut parts = hash.splitn(4, '$');
+    let (Some(""), Some(version), Some(cost), Some(encoded)) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    matches!(version, "2a" | "2b" | "2y")
+        && cost.len() == 2
+        && cost
+            .parse()
+            .is_ok_and(|cost| (MIN_COST..=MAX_COST).contains(&cost))
+        && encoded.len() == ENCODED_LEN
+        && encoded.bytes().all(|c| BCRYPT_ALPHABET.contains(&c))
+}
+
+/// Whether `password` matches `hash`, compared in constant time. Fails if
+/// `hash` is not valid, see `is_valid`
+pub fn verify(password: &str, hash: &str) -> io::Result<bool> {
+    if !is_valid(hash) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid bcrypt hash",
+        ));
+    }
+    bcrypt::verify(password, hash).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod bcrypt_tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() -> io::Result<()> {
+        // Known answers of the OpenBSD bcrypt implementation
+        let cases = [
+            (
+                "U*U",
+                "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            ),
+            (
+                "U*U*",
+                "$2a$05$CCCCCCCCCCCCCCCCCCCCC.VGOzA784oUp/Z0DY336zx7pLYAy0lwK",
+            ),
+            (
+                "U*U*U",
+                "$2a$05$XXXXXXXXXXXXXXXXXXXXXOAcXxm9kjPGEMsLznoKqmqw7tc8WCx4a",
+            ),
+            (
+                "",
+                "$2a$05$CCCCCCCCCCCCCCCCCCCCC.7uG0VCzI2bS7j6ymqJi9CdcdxiRTWNy",
+            ),
+            (
+                "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789chars after 72 are ignored",
+                "$2a$05$abcdefghijklmnopqrstuu5s2v8.iXieOjg/.AySBTTZIIVFJeBui",
+            ),
+            (
+                "a",
+                "$2a$06$m0CrhHm10qJ3lXRY.5zDGO3rS2KdeeWLuGmsfGlMfOxih58VYVfxe",
+            ),
+            (
+                "abc",
+                "$2a$06$If6bvum7DFjUnE9p2uDeDu0YHzrHM6tf.iqN8.yx.jNN1ILEf7h0i",
+            ),
+            (
+                "abcdefghijklmnopqrstuvwxyz",
+                "$2a$06$.rCVZVOThsIa97pEDOxvGuRRgzG64bvtJ0938xuqzv18d3ZpQhstC",
+            ),
+            (
+                "secret",
+                "$2b$04$/uaF/uaF/uaF/uaF/uaF/uyUa5B4sNev9rTvA6TvEBWYO4koffwMy",
+            ),
+            (
+                "",
+                "$2b$04$/uaF/uaF/uaF/uaF/uaF/ug0IxynTgi9KGDC9Ai.2CD1wsFqroU9q",
+            ),
+        ];
+        for (password, hash) in cases {
+            assert!(verify(password, hash)?, "{}", password);
+            assert!(!verify("wrong", hash)?, "{}", password);
+        }
+        // Written by htpasswd, the same hash as $2a$ or $2b$
+        let hash = "$2y$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+        assert!(verify("U*U", hash)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_hashes() {
+        for hash in [
+            "",
+            "plain",
+            "$1$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            "$2a$5$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            "$2a$03$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvy",
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC!E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+        ] {
+            assert!(!is_valid(hash), "{}", hash);
+            assert!(verify("U*U", hash).is_err(), "{}", hash);
+        }
+    }
+}
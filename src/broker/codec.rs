@@ -0,0 +1,400 @@
+use crate::broker::Message;
+use crate::mqtt::{protocol, Deserialize, Serialize, VarInt};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+/// Protocol level of MQTT 3.1 and 3.1.1 in CONNECT
+const MQTT_V3: u8 = 0x03;
+const MQTT_V4: u8 = 0x04;
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Malformed {} packet", what),
+    )
+}
+
+/// Packets sent by clients, the broker side counterpart of `Request`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientPacket {
+    Connect {
+        /// 3 for MQTT 3.1, 4 for 3.1.1, anything else is refused
+        level: u8,
+        client_id: String,
+        clean_session: bool,
+        keepalive: u16,
+        username: Option<String>,
+        password: Option<Vec<u8>>,
+        will: Option<Message>,
+    },
+    Publish {
+        packet_id: u16,
+        dup: bool,
+        message: Message,
+    },
+    Puback(u16),
+    Pubrec(u16),
+    Pubrel(u16),
+    Pubcomp(u16),
+    Subscribe {
+        packet_id: u16,
+        /// Topic filters with their requested QoS
+        filters: Vec<(String, u8)>,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Vec<String>,
+    },
+    Pingreq,
+    Disconnect,
+}
+
+fn read_bytes(buf: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = buf.read_u16::<NetworkEndian>()?;
+    let mut bytes = vec![0; len as usize];
+    buf.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_connect(body: &mut &[u8]) -> io::Result<ClientPacket> {
+    let name = protocol::read_string(body)?;
+    let level = body.read_u8()?;
+    if !matches!(
+        (name.as_str(), level),
+        ("MQTT", MQTT_V4) | ("MQIsdp", MQTT_V3)
+    ) {
+        // Still decoded, the broker answers with an unacceptable version
+        return Ok(ClientPacket::Connect {
+            level,
+            client_id: String::new(),
+            clean_session: true,
+            keepalive: 0,
+            username: None,
+            password: None,
+            will: None,
+        });
+    }
+    let flags = body.read_u8()?;
+    if flags & 0x01 != 0 {
+        return Err(malformed("CONNECT"));
+    }
+    let keepalive = body.read_u16::<NetworkEndian>()?;
+    let client_id = protocol::read_string(body)?;
+    let will = if flags & 0x04 != 0 {
+        let topic = protocol::read_string(body)?;
+        let payload = read_bytes(body)?;
+        Some(Message {
+            topic,
+            payload,
+            qos: (flags >> 3) & 0x03,
+            retain: flags & 0x20 != 0,
+        })
+    } else {
+        None
+    };
+    let username = if flags & 0x80 != 0 {
+        Some(protocol::read_string(body)?)
+    } else {
+        None
+    };
+    let password = if flags & 0x40 != 0 {
+        Some(read_bytes(body)?)
+    } else {
+        None
+    };
+    Ok(ClientPacket::Connect {
+        level,
+        client_id,
+        clean_session: flags & 0x02 != 0,
+        keepalive,
+        username,
+        password,
+        will,
+    })
+}
+
+impl Deserialize for ClientPacket {
+    type Output = ClientPacket;
+
+    fn deserialize(buf: &mut impl Read) -> io::Result<Self::Output> {
+        ClientPacket::read(buf, VarInt::MAX)
+    }
+}
+
+impl ClientPacket {
+    /// Reads a packet, refusing one with a remaining length over `max_len`
+    /// before buffering any of it
+    pub fn read(buf: &mut impl Read, max_len: u32) -> io::Result<ClientPacket> {
+        let byte = buf.read_u8()?;
+        let len = protocol::read_remaining_length(buf)?;
+        if len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Packet of {} bytes over the limit of {}", len, max_len),
+            ));
+        }
+        // Grows with the bytes actually received rather than the length
+        // announced
+        let mut bytes = vec![];
+        buf.take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() < len as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let body = &mut &bytes[..];
+        let packet = match byte >> 4 {
+            1 => read_connect(body)?,
+            3 => {
+                let qos = (byte >> 1) & 0x03;
+                let topic = protocol::read_string(body)?;
+                let packet_id = if qos > 0 {
+                    body.read_u16::<NetworkEndian>()?
+                } else {
+                    0
+                };
+                let payload = std::mem::take(body).to_vec();
+                ClientPacket::Publish {
+                    packet_id,
+                    dup: byte & 0x08 != 0,
+                    message: Message {
+                        topic,
+                        payload,
+                        qos,
+                        retain: byte & 0x01 != 0,
+                    },
+                }
+            }
+            4 => ClientPacket::Puback(body.read_u16::<NetworkEndian>()?),
+            5 => ClientPacket::Pubrec(body.read_u16::<NetworkEndian>()?),
+            6 => ClientPacket::Pubrel(body.read_u16::<NetworkEndian>()?),
+            7 => ClientPacket::Pubcomp(body.read_u16::<NetworkEndian>()?),
+            8 => {
+                let packet_id = body.read_u16::<NetworkEndian>()?;
+                let mut filters = vec![];
+                while !body.is_empty() {
+                    let filter = protocol::read_string(body)?;
+                    filters.push((filter, body.read_u8()?));
+                }
+                if filters.is_empty() {
+                    return Err(malformed("SUBSCRIBE"));
+                }
+                ClientPacket::Subscribe { packet_id, filters }
+            }
+            10 => {
+                let packet_id = body.read_u16::<NetworkEndian>()?;
+                let mut filters = vec![];
+                while !body.is_empty() {
+                    filters.push(protocol::read_string(body)?);
+                }
+                if filters.is_empty() {
+                    return Err(malformed("UNSUBSCRIBE"));
+                }
+                ClientPacket::Unsubscribe { packet_id, filters }
+            }
+            12 => ClientPacket::Pingreq,
+            14 => ClientPacket::Disconnect,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected packet from client 0x{:02X}", byte),
+                ))
+            }
+        };
+        Ok(packet)
+    }
+}
+
+/// Packets sent by the broker, the counterpart of `Response`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerPacket {
+    Connack {
+        session_present: bool,
+        return_code: u8,
+    },
+    /// A packet id of 0 on a QoS > 0 message is assigned when it is written
+    Publish {
+        packet_id: u16,
+        dup: bool,
+        message: Message,
+    },
+    Puback(u16),
+    Pubrec(u16),
+    Pubrel(u16),
+    Pubcomp(u16),
+    Suback {
+        packet_id: u16,
+        return_codes: Vec<u8>,
+    },
+    Unsuback(u16),
+    Pingresp,
+}
+
+impl Serialize for ServerPacket {
+    fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+        let (byte, len) = match self {
+            ServerPacket::Connack { .. } => (0x20, 2),
+            ServerPacket::Publish { dup, message, .. } => (
+                0x30 | (*dup as u8) << 3 | message.qos << 1 | message.retain as u8,
+                2 + message.topic.len()
+                    + message.payload.len()
+                    + if message.qos > 0 { 2 } else { 0 },
+            ),
+            ServerPacket::Puback(_) => (0x40, 2),
+            ServerPacket::Pubrec(_) => (0x50, 2),
+            ServerPacket::Pubrel(_) => (0x62, 2),
+            ServerPacket::Pubcomp(_) => (0x70, 2),
+            ServerPacket::Suback { return_codes, .. } => (0x90, 2 + return_codes.len()),
+            ServerPacket::Unsuback(_) => (0xB0, 2),
+            ServerPacket::Pingresp => (0xD0, 0),
+        };
+        buf.write_u8(byte)?;
+        let header = 1 + protocol::write_remaining_length(buf, len)?;
+        match self {
+            ServerPacket::Connack {
+                session_present,
+                return_code,
+            } => {
+                buf.write_u8(*session_present as u8)?;
+                buf.write_u8(*return_code)?;
+            }
+            ServerPacket::Publish {
+                packet_id, message, ..
+            } => {
+                protocol::write_string(buf, &message.topic)?;
+                if message.qos > 0 {
+                    buf.write_u16::<NetworkEndian>(*packet_id)?;
+                }
+                protocol::write_bytes(buf, &message.payload)?;
+            }
+            ServerPacket::Puback(packet_id)
+            | ServerPacket::Pubrec(packet_id)
+            | ServerPacket::Pubrel(packet_id)
+            | ServerPacket::Pubcomp(packet_id)
+            | ServerPacket::Unsuback(packet_id) => {
+                buf.write_u16::<NetworkEndian>(*packet_id)?;
+            }
+            ServerPacket::Suback {
+                packet_id,
+                return_codes,
+            } => {
+                buf.write_u16::<NetworkEndian>(*packet_id)?;
+                buf.write_all(return_codes)?;
+            }
+            ServerPacket::Pingresp => {}
+        }
+        Ok(header + len)
+    }
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use super::*;
+    use crate::mqtt::{Qos, Request, Response, SubscriptionTopic, Will};
+
+    #[test]
+    fn test_decode_client_packets() -> io::Result<()> {
+        let connect = Request::Connect {
+            client_id: "c".into(),
+            clean_session: true,
+            username: Some("u".into()),
+            password: Some("p".into()),
+            keepalive: 30,
+            will: Some(Will::new("w", b"bye", Qos::AtLeastOnce, true)),
+        };
+        assert_eq!(
+            ClientPacket::from_slice(&connect.to_bytes()?)?,
+            ClientPacket::Connect {
+                level: MQTT_V4,
+                client_id: "c".into(),
+                clean_session: true,
+                keepalive: 30,
+                username: Some("u".into()),
+                password: Some(b"p".to_vec()),
+                will: Some(Message {
+                    topic: "w".into(),
+                    payload: b"bye".to_vec(),
+                    qos: 1,
+                    retain: true,
+                }),
+            }
+        );
+        let subscribe = Request::Subscribe {
+            packet_id: 7,
+            subscription_topics: vec![SubscriptionTopic {
+                topic: "a/#".into(),
+                qos: Qos::ExactlyOnce,
+            }],
+        };
+        assert_eq!(
+            ClientPacket::from_slice(&subscribe.to_bytes()?)?,
+            ClientPacket::Subscribe {
+                packet_id: 7,
+                filters: vec![("a/#".into(), 2)],
+            }
+        );
+        // Retained QoS 1 PUBLISH
+        assert_eq!(
+            ClientPacket::from_slice(&[0x33, 6, 0, 1, b'a', 0, 9, b'x'])?,
+            ClientPacket::Publish {
+                packet_id: 9,
+                dup: false,
+                message: Message {
+                    topic: "a".into(),
+                    payload: b"x".to_vec(),
+                    qos: 1,
+                    retain: true,
+                },
+            }
+        );
+        assert_eq!(
+            ClientPacket::from_slice(&[0xA2, 5, 0, 3, 0, 1, b'a'])?,
+            ClientPacket::Unsubscribe {
+                packet_id: 3,
+                filters: vec!["a".into()],
+            }
+        );
+        assert_eq!(ClientPacket::from_slice(&[0xC0, 0])?, ClientPacket::Pingreq);
+        Ok(())
+    }
+
+    #[test]
+    fn test_packet_size_limit() -> io::Result<()> {
+        let publish = [0x30, 4, 0, 1, b'a', b'x'];
+        assert!(ClientPacket::read(&mut &publish[..], 4).is_ok());
+        let err = ClientPacket::read(&mut &publish[..], 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Announcing the largest remaining length buffers nothing upfront
+        let err = ClientPacket::read(&mut &[0x30, 0xFF, 0xFF, 0xFF, 0x7F, 0][..], VarInt::MAX)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_server_packets() -> io::Result<()> {
+        let publish = ServerPacket::Publish {
+            packet_id: 3,
+            dup: false,
+            message: Message {
+                topic: "a".into(),
+                payload: b"x".to_vec(),
+                qos: 1,
+                retain: true,
+            },
+        };
+        let bytes = publish.to_bytes()?;
+        assert_eq!(bytes, &[0x33, 6, 0, 1, b'a', 0, 3, b'x']);
+        match Response::from_slice(&bytes)? {
+            Response::Publish {
+                packet_id, topic, ..
+            } => assert_eq!((packet_id, topic.as_str()), (3, "a")),
+            resp => panic!("Unexpected {}", resp),
+        }
+        let suback = ServerPacket::Suback {
+            packet_id: 1,
+            return_codes: vec![0, 0x80],
+        };
+        assert_eq!(suback.to_bytes()?, &[0x90, 4, 0, 1, 0, 0x80]);
+        assert_eq!(ServerPacket::Pingresp.to_bytes()?, &[0xD0, 0]);
+        Ok(())
+    }
+}
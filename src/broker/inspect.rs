@@ -0,0 +1,78 @@
+use crate::broker::Message;
+use crate::json::Value;
+
+/// Prefix of the inspection topics, which clients can't publish to and
+/// wildcards don't match
+pub const INSPECT_PREFIX: &str = "$SAKE/";
+
+/// The `$SAKE/...` messages describing the broker state: `sessions`, an
+/// array of the sessions sorted by client id, and `retained`, the count and
+/// topics of the retained messages
+pub fn messages(mut sessions: Vec<Value>, mut retained: Vec<String>) -> Vec<Message> {
+    let client_id = |session: &Value| {
+        session
+            .get("client_id")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    };
+    sessions.sort_by_key(client_id);
+    retained.sort();
+    let retained = Value::Object(vec![
+        ("count".to_string(), Value::Number(retained.len() as f64)),
+        (
+            "topics".to_string(),
+            Value::Array(retained.into_iter().map(Value::String).collect()),
+        ),
+    ]);
+    [("sessions", Value::Array(sessions)), ("retained", retained)]
+        .into_iter()
+        .map(|(name, value)| Message {
+            topic: format!("{}{}", INSPECT_PREFIX, name),
+            payload: value.to_string().into_bytes(),
+            qos: 0,
+            retain: true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod inspect_tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let session = |id: &str| {
+            Value::Object(vec![(
+                "client_id".to_string(),
+                Value::String(id.to_string()),
+            )])
+        };
+        let messages = messages(
+            vec![session("b"), session("a")],
+            vec!["t/2".to_string(), "t/1".to_string()],
+        );
+        let payloads: Vec<(&str, String)> = messages
+            .iter()
+            .map(|m| {
+                (
+                    m.topic.as_str(),
+                    String::from_utf8_lossy(&m.payload).into_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            payloads,
+            [
+                (
+                    "$SAKE/sessions",
+                    r#"[{"client_id":"a"},{"client_id":"b"}]"#.to_string()
+                ),
+                (
+                    "$SAKE/retained",
+                    r#"{"count":2,"topics":["t/1","t/2"]}"#.to_string()
+                ),
+            ]
+        );
+        assert!(messages.iter().all(|m| m.retain && m.qos == 0));
+    }
+}
@@ -0,0 +1,1232 @@
+mod auth;
+mod bcrypt;
+mod codec;
+mod inspect;
+mod persist;
+mod retained;
+mod shard;
+mod sys;
+mod websocket;
+pub use auth::{Access, Acl, PasswordFile};
+pub use codec::{ClientPacket, ServerPacket};
+pub use inspect::INSPECT_PREFIX;
+pub use persist::{Log, Record};
+pub use retained::{Eviction, RetainedStore};
+use shard::{Connection, Shard};
+pub use sys::DEFAULT_SYS_INTERVAL;
+use sys::{CountingReader, SysStats};
+use websocket::{WsReader, WsWriter};
+
+use crate::mqtt::{Serialize, Transport};
+use crate::topic;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// CONNACK return codes sent by the broker
+const CONNACK_ACCEPTED: u8 = 0x00;
+const CONNACK_UNACCEPTABLE_VERSION: u8 = 0x01;
+const CONNACK_IDENTIFIER_REJECTED: u8 = 0x02;
+const CONNACK_BAD_USERNAME_PASSWORD: u8 = 0x04;
+const CONNACK_NOT_AUTHORIZED: u8 = 0x05;
+/// SUBACK return code of a refused filter
+const SUBACK_FAILURE: u8 = 0x80;
+
+/// Remaining length of a client packet accepted by default
+pub const DEFAULT_MAX_PACKET_SIZE: u32 = 1024 * 1024;
+
+/// An application message as routed by the broker
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// Limits and access control of an embedded broker
+#[derive(Debug, Clone)]
+pub struct BrokerOptions {
+    /// Topics retained at most, unbounded if `None`
+    pub max_retained: Option<usize>,
+    pub eviction: Eviction,
+    /// Credentials required to connect, anonymous clients are refused when
+    /// set. Anyone connects otherwise
+    pub passwords: Option<PasswordFile>,
+    /// Topics each user may publish and subscribe to, all of them if `None`
+    pub acl: Option<Acl>,
+    /// Log file keeping the retained messages and the persistent sessions,
+    /// their subscriptions and pending messages, across restarts
+    pub persistence: Option<PathBuf>,
+    /// Period of the `$SYS/broker/...` statistics, none published if `None`
+    pub sys_interval: Option<Duration>,
+    /// Shards the sessions are spread across, each with a thread delivering
+    /// the messages routed to it
+    pub workers: usize,
+    /// Describe the sessions and retained messages to the clients
+    /// subscribing to `$SAKE/sessions` and `$SAKE/retained`, see
+    /// `INSPECT_PREFIX`. Each subscription gets the state at the time
+    pub inspect: bool,
+    /// Remaining length of the largest packet accepted from a client, the
+    /// connection is closed on anything larger
+    pub max_packet_size: u32,
+}
+
+impl Default for BrokerOptions {
+    fn default() -> Self {
+        Self {
+            max_retained: None,
+            eviction: Eviction::Oldest,
+            passwords: None,
+            acl: None,
+            persistence: None,
+            sys_interval: Some(DEFAULT_SYS_INTERVAL),
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            inspect: false,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl BrokerOptions {
+    /// The user name the ACL applies to, only trusted once a password file
+    /// has checked it, otherwise the client gets the `*` rules alone.
+    fn verified<'a>(&self, username: Option<&'a str>) -> Option<&'a str> {
+        username.filter(|_| self.passwords.is_some())
+    }
+
+    fn can_write(&self, username: Option<&str>, topic: &str) -> bool {
+        self.acl
+            .as_ref()
+            .is_none_or(|acl| acl.can_write(self.verified(username), topic))
+    }
+
+    fn can_read(&self, username: Option<&str>, filter: &str) -> bool {
+        self.acl
+            .as_ref()
+            .is_none_or(|acl| acl.can_read(self.verified(username), filter))
+    }
+}
+
+/// Broker state shared by the connections. Sessions live in shards locked
+/// independently, a publish is handed to the worker of every shard rather
+/// than delivered by the thread of its publisher
+struct State {
+    shards: Vec<Arc<Mutex<Shard>>>,
+    routes: Vec<Sender<Message>>,
+    retained: Mutex<RetainedStore>,
+    /// Latest `$SYS` messages, replayed to new subscriptions like retained
+    /// ones but kept apart from them, out of their limit and of the log
+    sys: Mutex<RetainedStore>,
+    log: Arc<Mutex<Option<Log>>>,
+    stats: Arc<SysStats>,
+    /// Source of connection ids and of generated client ids
+    connections: AtomicU64,
+}
+
+impl State {
+    /// Start `workers` shards with their delivery threads, which end along
+    /// with the state
+    fn new(options: &BrokerOptions) -> Self {
+        let log = Arc::new(Mutex::new(None));
+        let mut shards = vec![];
+        let mut routes = vec![];
+        for _ in 0..options.workers.max(1) {
+            let shard = Arc::new(Mutex::new(Shard::new(Arc::clone(&log))));
+            let (route, receiver) = mpsc::channel();
+            let worker = Arc::clone(&shard);
+            thread::spawn(move || shard::work(&worker, receiver));
+            shards.push(shard);
+            routes.push(route);
+        }
+        Self {
+            shards,
+            routes,
+            retained: Mutex::new(RetainedStore::new(options.max_retained, options.eviction)),
+            sys: Mutex::new(RetainedStore::new(None, Eviction::Oldest)),
+            log,
+            stats: Arc::new(SysStats::new()),
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    /// The shard holding the session of `client_id`
+    fn shard(&self, client_id: &str) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        client_id.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Retain `message` if flagged so and hand it to every shard, it is
+    /// delivered once this returns but not necessarily yet
+    fn route(&self, message: &Message) {
+        if message.retain {
+            if self.retained.lock().unwrap().retain(message) {
+                if let Some(log) = &mut *self.log.lock().unwrap() {
+                    if let Err(e) = log.append(&Record::Retain(message.clone())) {
+                        eprintln!("Broker log: {}", e);
+                    }
+                }
+            } else {
+                eprintln!("Retained store full, {} not retained", message.topic);
+            }
+        }
+        for route in &self.routes {
+            // Only fails once the worker is gone, along with the broker
+            route.send(message.clone()).ok();
+        }
+    }
+
+    /// Publish the current statistics on `$SYS/broker/...`
+    fn publish_sys(&self) {
+        let (mut connected, mut total, mut subscriptions) = (0, 0, 0);
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            connected += shard.connected();
+            total += shard.sessions.len();
+            subscriptions += shard.subscriptions.len();
+        }
+        let retained = self.retained.lock().unwrap().len();
+        for message in self
+            .stats
+            .messages(connected, total, subscriptions, retained)
+        {
+            self.sys.lock().unwrap().retain(&message);
+            self.route(&Message {
+                retain: false,
+                ..message
+            });
+        }
+    }
+
+    /// The `$SAKE/...` messages describing the current state. Locks every
+    /// shard in turn, so none may be held by the caller
+    fn inspect(&self) -> Vec<Message> {
+        let mut sessions = vec![];
+        for shard in &self.shards {
+            shard.lock().unwrap().inspect(&mut sessions);
+        }
+        let retained = self
+            .retained
+            .lock()
+            .unwrap()
+            .messages()
+            .into_iter()
+            .map(|message| message.topic.clone())
+            .collect();
+        inspect::messages(sessions, retained)
+    }
+
+    /// Rebuild the state from the records of a log, sessions are offline
+    fn replay(&self, records: Vec<Record>) {
+        for record in records {
+            match record {
+                Record::Retain(message) => {
+                    self.retained.lock().unwrap().retain(&message);
+                }
+                Record::Session(ref client_id)
+                | Record::SessionEnd(ref client_id)
+                | Record::Subscribe { ref client_id, .. }
+                | Record::Unsubscribe { ref client_id, .. }
+                | Record::Store { ref client_id, .. }
+                | Record::Done { ref client_id, .. } => {
+                    let shard = self.shard(client_id);
+                    shard.lock().unwrap().replay(record);
+                }
+            }
+        }
+    }
+
+    /// Records the current state boils down to, to compact the log with
+    fn snapshot(&self) -> Vec<Record> {
+        let mut records: Vec<Record> = self
+            .retained
+            .lock()
+            .unwrap()
+            .messages()
+            .into_iter()
+            .map(|message| Record::Retain(message.clone()))
+            .collect();
+        for shard in &self.shards {
+            shard.lock().unwrap().snapshot(&mut records);
+        }
+        records
+    }
+}
+
+/// Whether `filter` is a valid subscription, wildcards taking a whole level
+/// and `#` only the last one
+fn is_valid_filter(filter: &str) -> bool {
+    let levels: Vec<&str> = filter.split('/').collect();
+    !filter.is_empty()
+        && levels.iter().enumerate().all(|(i, level)| match *level {
+            "#" => i == levels.len() - 1,
+            "+" => true,
+            level => !level.contains(['+', '#']),
+        })
+}
+
+/// A small MQTT 3.1.1 broker, handling each connection on its own thread
+/// and spreading the sessions across `BrokerOptions::workers` shards.
+/// Clients connect over TCP and, once `bind_websocket` is called, over
+/// WebSocket as well. `serve_transport` takes clients connected otherwise
+pub struct Broker {
+    listener: Option<TcpListener>,
+    ws_listener: Option<TcpListener>,
+    state: Arc<State>,
+    options: Arc<BrokerOptions>,
+}
+
+impl Broker {
+    /// Bind the TCP listener, restoring the state kept in the persistence
+    /// log if there is one. The log is compacted to that state
+    pub fn bind(addr: impl ToSocketAddrs, options: BrokerOptions) -> io::Result<Self> {
+        let mut broker = Self::new(options)?;
+        broker.listener = Some(TcpListener::bind(addr)?);
+        Ok(broker)
+    }
+
+    /// A broker listening nowhere, whose clients all come through
+    /// `serve_transport`. The state is restored as with `bind`
+    pub fn new(options: BrokerOptions) -> io::Result<Self> {
+        let state = State::new(&options);
+        if let Some(path) = &options.persistence {
+            let (mut log, records) = Log::open(path)?;
+            state.replay(records);
+            log.compact(&state.snapshot())?;
+            *state.log.lock().unwrap() = Some(log);
+        }
+        Ok(Self {
+            listener: None,
+            ws_listener: None,
+            state: Arc::new(state),
+            options: Arc::new(options),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.listener {
+            Some(listener) => listener.local_addr(),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Broker not bound to an address",
+            )),
+        }
+    }
+
+    /// Serve a client already connected over `transport` on its own thread,
+    /// as if it had been accepted by the TCP listener
+    pub fn serve_transport(&self, transport: impl Transport + 'static) -> io::Result<()> {
+        let reader = BufReader::new(transport.try_clone()?);
+        let writer = transport.try_clone()?;
+        let state = Arc::clone(&self.state);
+        let options = Arc::clone(&self.options);
+        thread::spawn(move || {
+            let socket = Box::new(transport);
+            if let Err(e) = serve_client(reader, Box::new(writer), socket, &state, &options) {
+                eprintln!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Also accept MQTT over WebSocket clients on `addr`, returns the
+    /// address bound
+    pub fn bind_websocket(&mut self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        self.ws_listener = Some(listener);
+        Ok(local_addr)
+    }
+
+    /// Accept clients forever, errors tied to a single client are logged
+    /// and only close its connection. A broker bound nowhere only starts
+    /// publishing its `$SYS` topics and returns
+    pub fn run(&self) -> io::Result<()> {
+        if let Some(interval) = self.options.sys_interval {
+            let state = Arc::clone(&self.state);
+            thread::spawn(move || loop {
+                state.publish_sys();
+                thread::sleep(interval);
+            });
+        }
+        let ws_accept = match &self.ws_listener {
+            Some(listener) => {
+                let listener = listener.try_clone()?;
+                let state = Arc::clone(&self.state);
+                let options = Arc::clone(&self.options);
+                Some(thread::spawn(move || {
+                    accept(&listener, true, &state, &options)
+                }))
+            }
+            None => None,
+        };
+        if let Some(listener) = &self.listener {
+            accept(listener, false, &self.state, &self.options)?;
+        }
+        match ws_accept {
+            Some(handle) => handle.join().unwrap(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn accept(
+    listener: &TcpListener,
+    websocket: bool,
+    state: &Arc<State>,
+    options: &Arc<BrokerOptions>,
+) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let state = Arc::clone(state);
+        let options = Arc::clone(options);
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(e) = serve(stream, websocket, &state, &options) {
+                match peer {
+                    Ok(peer) => eprintln!("{}: {}", peer, e),
+                    Err(_) => eprintln!("{}", e),
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Handle a client connection from its CONNECT, or its WebSocket handshake,
+/// to its end
+fn serve(
+    stream: TcpStream,
+    websocket: bool,
+    state: &State,
+    options: &BrokerOptions,
+) -> io::Result<()> {
+    // Every packet is a single write, Nagle would only hold deliveries back
+    // until the client acks the previous one
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    if websocket {
+        websocket::accept(&mut reader, &mut stream.try_clone()?)?;
+        let writer = WsWriter::new(stream.try_clone()?);
+        // A frame holds at most a packet and its fixed header
+        let max_frame_len = options.max_packet_size as u64 + 5;
+        let reader = WsReader::new(reader, writer.clone(), max_frame_len);
+        serve_client(reader, Box::new(writer), Box::new(stream), state, options)
+    } else {
+        let writer = stream.try_clone()?;
+        serve_client(reader, Box::new(writer), Box::new(stream), state, options)
+    }
+}
+
+fn serve_client(
+    reader: impl Read,
+    mut writer: Box<dyn Write + Send>,
+    socket: Box<dyn Transport>,
+    state: &State,
+    options: &BrokerOptions,
+) -> io::Result<()> {
+    let mut reader = CountingReader::new(reader);
+    let (level, client_id, clean_session, keepalive, username, password, mut will) =
+        match ClientPacket::read(&mut reader, options.max_packet_size)? {
+            ClientPacket::Connect {
+                level,
+                client_id,
+                clean_session,
+                keepalive,
+                username,
+                password,
+                will,
+            } => (
+                level,
+                client_id,
+                clean_session,
+                keepalive,
+                username,
+                password,
+                will,
+            ),
+            packet => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} before CONNECT", packet),
+                ))
+            }
+        };
+    let mut refuse = |return_code| {
+        let connack = ServerPacket::Connack {
+            session_present: false,
+            return_code,
+        };
+        writer.write_all(&connack.to_bytes()?)
+    };
+    if !matches!(level, 3 | 4) {
+        return refuse(CONNACK_UNACCEPTABLE_VERSION);
+    }
+    if client_id.is_empty() && !clean_session {
+        return refuse(CONNACK_IDENTIFIER_REJECTED);
+    }
+    if let Some(passwords) = &options.passwords {
+        match (&username, &password) {
+            (None, _) => return refuse(CONNACK_NOT_AUTHORIZED),
+            (Some(user), password) => {
+                if !passwords.check(user, password.as_deref().unwrap_or_default()) {
+                    return refuse(CONNACK_BAD_USERNAME_PASSWORD);
+                }
+            }
+        }
+    }
+    if will
+        .as_ref()
+        .is_some_and(|will| !options.can_write(username.as_deref(), &will.topic))
+    {
+        return refuse(CONNACK_NOT_AUTHORIZED);
+    }
+    if keepalive > 0 {
+        // The client is gone after one and a half keepalive without packets
+        let timeout = Duration::from_millis(keepalive as u64 * 1500);
+        socket.set_read_timeout(Some(timeout))?;
+    }
+
+    state.stats.received(reader.take_count(), false);
+    let id = state.connections.fetch_add(1, Ordering::Relaxed) + 1;
+    let client_id = if client_id.is_empty() {
+        format!("sake-{}", id)
+    } else {
+        client_id
+    };
+    let shard = state.shard(&client_id);
+    {
+        let mut shard = shard.lock().unwrap();
+        let previous = shard.sessions.get(&client_id);
+        if let Some(old) = previous.as_ref().and_then(|s| s.connection.as_ref()) {
+            // Taken over, the previous connection ends as if it dropped
+            old.socket.shutdown(Shutdown::Both).ok();
+        }
+        let session_present = !clean_session && previous.is_some_and(|s| !s.clean_session);
+        if !session_present {
+            shard.remove_session(&client_id);
+        }
+        let session = shard.insert_session(&client_id, clean_session);
+        session.connection = Some(Connection::new(
+            id,
+            writer,
+            socket,
+            Arc::clone(&state.stats),
+        )?);
+        session.send(&ServerPacket::Connack {
+            session_present,
+            return_code: CONNACK_ACCEPTED,
+        })?;
+        session.resume()?;
+    }
+
+    let result = handle(&mut reader, state, options, &client_id, username.as_deref());
+    if result.is_ok() {
+        will = None;
+    }
+    {
+        let mut shard = shard.lock().unwrap();
+        if let Some(session) = shard.sessions.get_mut(&client_id) {
+            if session.connection.as_ref().is_some_and(|c| c.id == id) {
+                session.connection = None;
+                if session.clean_session {
+                    shard.remove_session(&client_id);
+                }
+            }
+        }
+    }
+    if let Some(will) = will {
+        state.route(&will);
+    }
+    result
+}
+
+/// Serve the packets of a connected client until its DISCONNECT, any error
+/// ends the connection as dropped
+fn handle(
+    reader: &mut CountingReader<impl Read>,
+    state: &State,
+    options: &BrokerOptions,
+    client_id: &str,
+    username: Option<&str>,
+) -> io::Result<()> {
+    // QoS 2 messages received and not released yet, delivered only once
+    let mut incoming = HashSet::new();
+    let shard = state.shard(client_id);
+    loop {
+        let packet = ClientPacket::read(reader, options.max_packet_size)?;
+        let publish = matches!(packet, ClientPacket::Publish { .. });
+        state.stats.received(reader.take_count(), publish);
+        let reply = match packet {
+            ClientPacket::Publish {
+                packet_id, message, ..
+            } => {
+                if message.topic.is_empty() || message.topic.contains(['+', '#']) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid topic name {}", message.topic),
+                    ));
+                }
+                // MQTT 3.1.1 can't refuse a PUBLISH, a denied one is still
+                // acknowledged and then dropped. Only the broker publishes
+                // on $SYS and $SAKE
+                let allowed = !message.topic.starts_with("$SYS/")
+                    && !message.topic.starts_with(INSPECT_PREFIX)
+                    && options.can_write(username, &message.topic);
+                match message.qos {
+                    0 => {
+                        if allowed {
+                            state.route(&message);
+                        }
+                        None
+                    }
+                    1 => {
+                        if allowed {
+                            state.route(&message);
+                        }
+                        Some(ServerPacket::Puback(packet_id))
+                    }
+                    _ => {
+                        if incoming.insert(packet_id) && allowed {
+                            state.route(&message);
+                        }
+                        Some(ServerPacket::Pubrec(packet_id))
+                    }
+                }
+            }
+            ClientPacket::Pubrel(packet_id) => {
+                incoming.remove(&packet_id);
+                Some(ServerPacket::Pubcomp(packet_id))
+            }
+            ClientPacket::Puback(packet_id) => {
+                shard.lock().unwrap().complete(client_id, packet_id, 1);
+                None
+            }
+            ClientPacket::Pubrec(packet_id) => {
+                // The session may be gone, taken over or ended as clean
+                let mut shard = shard.lock().unwrap();
+                if let Some(pending) = shard.sessions.get_mut(client_id).and_then(|session| {
+                    session
+                        .pending
+                        .iter_mut()
+                        .find(|p| p.packet_id == Some(packet_id))
+                }) {
+                    pending.released = true;
+                }
+                Some(ServerPacket::Pubrel(packet_id))
+            }
+            ClientPacket::Pubcomp(packet_id) => {
+                shard.lock().unwrap().complete(client_id, packet_id, 2);
+                None
+            }
+            ClientPacket::Subscribe { packet_id, filters } => {
+                // Taken before the shard of the client is locked, as it
+                // locks them all
+                let inspected = match options.inspect
+                    && filters.iter().any(|(f, _)| f.starts_with(INSPECT_PREFIX))
+                {
+                    true => state.inspect(),
+                    false => vec![],
+                };
+                let mut shard = shard.lock().unwrap();
+                let mut return_codes = vec![];
+                for (filter, qos) in &filters {
+                    if !is_valid_filter(filter) || *qos > 2 || !options.can_read(username, filter) {
+                        return_codes.push(SUBACK_FAILURE);
+                        continue;
+                    }
+                    shard.subscribe(client_id, filter, *qos);
+                    return_codes.push(*qos);
+                }
+                if let Some(session) = shard.sessions.get_mut(client_id) {
+                    session.send(&ServerPacket::Suback {
+                        packet_id,
+                        return_codes: return_codes.clone(),
+                    })?;
+                }
+                for ((filter, qos), code) in filters.iter().zip(return_codes) {
+                    if code == SUBACK_FAILURE {
+                        continue;
+                    }
+                    let mut retained: Vec<Message> = state
+                        .retained
+                        .lock()
+                        .unwrap()
+                        .matching(filter)
+                        .into_iter()
+                        .cloned()
+                        .collect();
+                    retained.extend(
+                        state
+                            .sys
+                            .lock()
+                            .unwrap()
+                            .matching(filter)
+                            .into_iter()
+                            .cloned(),
+                    );
+                    retained.extend(
+                        inspected
+                            .iter()
+                            .filter(|message| topic::matches(filter, &message.topic))
+                            .cloned(),
+                    );
+                    for message in &retained {
+                        shard.deliver(client_id, message, *qos, true)?;
+                    }
+                }
+                None
+            }
+            ClientPacket::Unsubscribe { packet_id, filters } => {
+                let mut shard = shard.lock().unwrap();
+                for filter in &filters {
+                    shard.unsubscribe(client_id, filter);
+                }
+                Some(ServerPacket::Unsuback(packet_id))
+            }
+            ClientPacket::Pingreq => Some(ServerPacket::Pingresp),
+            ClientPacket::Disconnect => return Ok(()),
+            ClientPacket::Connect { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Second CONNECT on the same connection",
+                ))
+            }
+        };
+        if let Some(reply) = reply {
+            if let Some(session) = shard.lock().unwrap().sessions.get_mut(client_id) {
+                session.send(&reply)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod broker_tests {
+    use super::*;
+    use crate::mqtt::{
+        AckType, ConnectReturnCode, ConnectionRefused, Protocol, ProtocolBuilder, Qos, Request,
+        Response, SubscriptionTopic, Will,
+    };
+    use crate::testing::{duplex, Duplex};
+
+    fn start(options: BrokerOptions) -> io::Result<Broker> {
+        Broker::new(options)
+    }
+
+    /// One end of an in-memory connection, the broker serving the other
+    fn dial(broker: &Broker) -> io::Result<Duplex> {
+        let (client, server) = duplex();
+        broker.serve_transport(server)?;
+        Ok(client)
+    }
+
+    fn client(broker: &Broker, client_id: &str) -> io::Result<Protocol> {
+        let mut protocol = Protocol::builder()
+            .client_id(client_id)
+            .connect_transport(dial(broker)?)?;
+        protocol.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(protocol)
+    }
+
+    fn publish_retained(protocol: &mut Protocol, topic: &str, payload: &[u8]) -> io::Result<()> {
+        protocol.send_message(&Request::Publish {
+            packet_id: 0,
+            qos: 0,
+            dup: false,
+            retain: true,
+            topic: topic.into(),
+            payload: payload.to_vec(),
+        })
+    }
+
+    fn subscribe(protocol: &mut Protocol, filter: &str) -> io::Result<()> {
+        protocol.subscribe(filter, Qos::AtMostOnce)?;
+        match protocol.read_message::<Response>()? {
+            Response::Suback { .. } => Ok(()),
+            resp => panic!("Unexpected {}", resp),
+        }
+    }
+
+    fn next_publish(protocol: &mut Protocol) -> io::Result<(String, Vec<u8>)> {
+        match protocol.read_message::<Response>()? {
+            Response::Publish { topic, payload, .. } => Ok((topic, payload)),
+            resp => panic!("Unexpected {}", resp),
+        }
+    }
+
+    #[test]
+    fn test_route_to_subscribers() -> io::Result<()> {
+        let broker = start(BrokerOptions::default())?;
+        let mut subscriber = client(&broker, "sub")?;
+        subscribe(&mut subscriber, "a/+")?;
+        let mut publisher = client(&broker, "pub")?;
+        publisher.publish("b/1", b"skipped")?;
+        publisher.publish("a/1", b"x")?;
+        assert!(matches!(
+            publisher.read_message::<Response>()?,
+            Response::Puback { .. }
+        ));
+        assert_eq!(
+            next_publish(&mut subscriber)?,
+            ("a/1".into(), b"x".to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlapping_subscriptions_deliver_once() -> io::Result<()> {
+        let broker = start(BrokerOptions::default())?;
+        let mut subscriber = client(&broker, "sub")?;
+        subscribe(&mut subscriber, "a/#")?;
+        subscribe(&mut subscriber, "a/+")?;
+        let mut publisher = client(&broker, "pub")?;
+        publisher.publish("a/1", b"x")?;
+        publisher.publish("a/2", b"y")?;
+        assert_eq!(
+            next_publish(&mut subscriber)?,
+            ("a/1".into(), b"x".to_vec())
+        );
+        assert_eq!(
+            next_publish(&mut subscriber)?,
+            ("a/2".into(), b"y".to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_across_shards() -> io::Result<()> {
+        let broker = start(BrokerOptions {
+            workers: 4,
+            ..BrokerOptions::default()
+        })?;
+        let mut subscribers = (0..16)
+            .map(|i| client(&broker, &format!("sub-{}", i)))
+            .collect::<io::Result<Vec<_>>>()?;
+        for subscriber in &mut subscribers {
+            subscribe(subscriber, "a/#")?;
+        }
+        let mut publisher = client(&broker, "pub")?;
+        for i in 0..10u8 {
+            publisher.publish("a/1", &[i])?;
+        }
+        // Every subscriber gets them all, in the order they were published
+        for subscriber in &mut subscribers {
+            for i in 0..10u8 {
+                assert_eq!(next_publish(subscriber)?, ("a/1".into(), vec![i]));
+            }
+        }
+        Ok(())
+    }
+
+    /// Transport whose writes wait while `gate` is held, as those to a
+    /// client which stopped reading once its socket buffer is full
+    #[derive(Clone)]
+    struct Stalling {
+        inner: Duplex,
+        gate: Arc<Mutex<()>>,
+    }
+
+    impl Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for Stalling {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            drop(self.gate.lock().unwrap());
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Stalling {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(self.clone()))
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.inner.set_read_timeout(timeout)
+        }
+
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            self.inner.shutdown(how)
+        }
+    }
+
+    #[test]
+    fn test_slow_subscriber() -> io::Result<()> {
+        let broker = start(BrokerOptions {
+            workers: 1,
+            ..BrokerOptions::default()
+        })?;
+        let (link, server) = duplex();
+        let gate = Arc::new(Mutex::new(()));
+        broker.serve_transport(Stalling {
+            inner: server,
+            gate: Arc::clone(&gate),
+        })?;
+        let mut slow = Protocol::builder()
+            .client_id("slow")
+            .connect_transport(link)?;
+        slow.set_read_timeout(Some(Duration::from_secs(5)))?;
+        subscribe(&mut slow, "a")?;
+
+        // Publishers sharing its shard are still served while nothing can
+        // be written to the slow subscriber
+        let stalled = gate.lock().unwrap();
+        let mut publisher = client(&broker, "pub")?;
+        for _ in 0..shard::MAX_OUTGOING + 10 {
+            publisher.publish("a", b"x")?;
+            assert!(matches!(
+                publisher.read_message::<Response>()?,
+                Response::Puback { .. }
+            ));
+        }
+        drop(stalled);
+        // Disconnected once too far behind
+        let received = std::iter::from_fn(|| next_publish(&mut slow).ok()).count();
+        assert!(received < shard::MAX_OUTGOING + 10, "{}", received);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retained_replayed_and_cleared() -> io::Result<()> {
+        let broker = start(BrokerOptions::default())?;
+        let mut publisher = client(&broker, "pub")?;
+        publish_retained(&mut publisher, "a/1", b"x")?;
+        publish_retained(&mut publisher, "a/2", b"y")?;
+        publish_retained(&mut publisher, "a/2", b"")?;
+        // The PUBACK of a later QoS 1 publish means the ones above are handled
+        publisher.publish("sync", b"")?;
+        publisher.read_message::<Response>()?;
+        let mut first = client(&broker, "first")?;
+        subscribe(&mut first, "a/#")?;
+        assert_eq!(next_publish(&mut first)?, ("a/1".into(), b"x".to_vec()));
+
+        publish_retained(&mut publisher, "a/1", b"z")?;
+        assert_eq!(next_publish(&mut first)?, ("a/1".into(), b"z".to_vec()));
+        let mut second = client(&broker, "second")?;
+        subscribe(&mut second, "a/+")?;
+        assert_eq!(next_publish(&mut second)?, ("a/1".into(), b"z".to_vec()));
+        second.set_read_timeout(Some(Duration::from_millis(100)))?;
+        assert!(second.read_message::<Response>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_will_published_on_drop() -> io::Result<()> {
+        let broker = start(BrokerOptions::default())?;
+        let mut subscriber = client(&broker, "sub")?;
+        subscribe(&mut subscriber, "will")?;
+        let dropped = Protocol::builder()
+            .client_id("dropped")
+            .will(Will::new("will", b"bye", Qos::AtMostOnce, false))
+            .disconnect_on_drop(None)
+            .connect_transport(dial(&broker)?)?;
+        drop(dropped);
+        assert_eq!(
+            next_publish(&mut subscriber)?,
+            ("will".into(), b"bye".to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_credentials_and_acl() -> io::Result<()> {
+        let broker = start(BrokerOptions {
+            passwords: Some(
+                "alice:$2b$04$/uaF/uaF/uaF/uaF/uaF/uyUa5B4sNev9rTvA6TvEBWYO4koffwMy".parse()?,
+            ),
+            acl: Some("alice a/# readwrite\nalice b readwrite\n* b read".parse()?),
+            ..BrokerOptions::default()
+        })?;
+        let refused =
+            |builder: ProtocolBuilder| match builder.connect_transport(dial(&broker).unwrap()) {
+                Ok(_) => panic!("Connection accepted"),
+                Err(e) => e
+                    .get_ref()
+                    .and_then(|inner| inner.downcast_ref::<ConnectionRefused>())
+                    .map(|refused| refused.return_code),
+            };
+        assert_eq!(
+            refused(Protocol::builder().credentials("alice", Some("wrong"))),
+            Some(ConnectReturnCode::BadUserNamePassword)
+        );
+        assert_eq!(
+            refused(Protocol::builder()),
+            Some(ConnectReturnCode::NotAuthorized)
+        );
+
+        let mut alice = Protocol::builder()
+            .client_id("alice")
+            .credentials("alice", Some("secret"))
+            .connect_transport(dial(&broker)?)?;
+        alice.set_read_timeout(Some(Duration::from_secs(5)))?;
+        alice.send_message(&Request::Subscribe {
+            packet_id: 1,
+            subscription_topics: ["a/+", "c", "b"]
+                .iter()
+                .map(|topic| SubscriptionTopic {
+                    topic: topic.to_string(),
+                    qos: Qos::AtMostOnce,
+                })
+                .collect(),
+        })?;
+        match alice.read_message::<Response>()? {
+            Response::Suback { return_codes, .. } => {
+                assert_eq!(return_codes, [0, SUBACK_FAILURE, 0])
+            }
+            resp => panic!("Unexpected {}", resp),
+        }
+        // Acknowledged but dropped, the next message is the allowed one
+        alice.publish("c", b"denied")?;
+        alice.publish("b", b"x")?;
+        assert!(matches!(
+            alice.read_message::<Response>()?,
+            Response::Puback { .. }
+        ));
+        // Delivered by a worker, either side of the PUBACK of its own publish
+        let mut routed = vec![];
+        for _ in 0..2 {
+            match alice.read_message::<Response>()? {
+                Response::Publish { topic, payload, .. } => routed.push((topic, payload)),
+                Response::Puback { .. } => {}
+                resp => panic!("Unexpected {}", resp),
+            }
+        }
+        assert_eq!(routed, [("b".into(), b"x".to_vec())]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_acl_without_passwords() -> io::Result<()> {
+        let broker = start(BrokerOptions {
+            acl: Some("alice a readwrite\n* b read".parse()?),
+            ..BrokerOptions::default()
+        })?;
+        // Nothing checked the name, so only the `*` rules apply
+        let mut mallory = Protocol::builder()
+            .client_id("mallory")
+            .credentials("alice", None)
+            .connect_transport(dial(&broker)?)?;
+        mallory.set_read_timeout(Some(Duration::from_secs(5)))?;
+        mallory.send_message(&Request::Subscribe {
+            packet_id: 1,
+            subscription_topics: ["a", "b"]
+                .iter()
+                .map(|topic| SubscriptionTopic {
+                    topic: topic.to_string(),
+                    qos: Qos::AtMostOnce,
+                })
+                .collect(),
+        })?;
+        match mallory.read_message::<Response>()? {
+            Response::Suback { return_codes, .. } => {
+                assert_eq!(return_codes, [SUBACK_FAILURE, 0])
+            }
+            resp => panic!("Unexpected {}", resp),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_survives_restart() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("sake-persist-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let options = BrokerOptions {
+            persistence: Some(path.clone()),
+            workers: 1,
+            ..BrokerOptions::default()
+        };
+        let persistent = || {
+            Protocol::builder()
+                .client_id("persistent")
+                .clean_session(false)
+        };
+        let broker = start(options.clone())?;
+        let mut subscriber = persistent().connect_transport(dial(&broker)?)?;
+        assert!(!subscriber.session_present());
+        subscriber.set_read_timeout(Some(Duration::from_secs(5)))?;
+        subscriber.subscribe("a/#", Qos::AtLeastOnce)?;
+        subscriber.read_message::<Response>()?;
+        subscriber.disconnect()?;
+        let mut sync = client(&broker, "sync")?;
+        subscribe(&mut sync, "sync")?;
+        let mut publisher = client(&broker, "pub")?;
+        publish_retained(&mut publisher, "r", b"kept")?;
+        // Queued for the offline session
+        publisher.publish("a/1", b"queued")?;
+        // Delivered once the single worker is done with the message above
+        publisher.publish("sync", b"")?;
+        next_publish(&mut sync)?;
+
+        let broker = start(options)?;
+        let mut subscriber = persistent().connect_transport(dial(&broker)?)?;
+        assert!(subscriber.session_present());
+        subscriber.set_read_timeout(Some(Duration::from_secs(5)))?;
+        match subscriber.read_message::<Response>()? {
+            Response::Publish {
+                packet_id,
+                qos,
+                topic,
+                payload,
+                ..
+            } => {
+                assert_eq!(
+                    (qos, topic.as_str(), payload.as_slice()),
+                    (1, "a/1", &b"queued"[..])
+                );
+                subscriber.ack(AckType::Puback(packet_id))?;
+            }
+            resp => panic!("Unexpected {}", resp),
+        }
+        let mut other = client(&broker, "other")?;
+        subscribe(&mut other, "r")?;
+        assert_eq!(next_publish(&mut other)?, ("r".into(), b"kept".to_vec()));
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_sys_topics() -> io::Result<()> {
+        let broker = start(BrokerOptions {
+            sys_interval: Some(Duration::from_millis(50)),
+            ..BrokerOptions::default()
+        })?;
+        // Bound nowhere, it returns once the $SYS publisher is started
+        broker.run()?;
+        let mut everything = client(&broker, "everything")?;
+        subscribe(&mut everything, "#")?;
+        let mut monitor = client(&broker, "monitor")?;
+        subscribe(&mut monitor, "$SYS/broker/clients/connected")?;
+        // The replayed value may predate both clients
+        while next_publish(&mut monitor)?.1 != b"2" {}
+        // Wildcards don't reach $SYS, nor can clients publish there
+        monitor.publish("$SYS/broker/clients/connected", b"0")?;
+        everything.set_read_timeout(Some(Duration::from_millis(200)))?;
+        assert!(everything.read_message::<Response>().is_err());
+        assert!(matches!(
+            monitor.read_message::<Response>()?,
+            Response::Puback { .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_inspect() -> io::Result<()> {
+        let broker = start(BrokerOptions {
+            inspect: true,
+            ..BrokerOptions::default()
+        })?;
+        let mut busy = client(&broker, "busy")?;
+        busy.subscribe("a/#", Qos::AtLeastOnce)?;
+        busy.read_message::<Response>()?;
+        publish_retained(&mut busy, "a/1", b"x")?;
+        busy.read_message::<Response>()?;
+        // Delivered at QoS 1 and left unacknowledged, along with the PUBACK
+        busy.publish("a/2", b"y")?;
+        busy.read_message::<Response>()?;
+        busy.read_message::<Response>()?;
+        let mut inspector = client(&broker, "inspector")?;
+        subscribe(&mut inspector, "$SAKE/+")?;
+        let (topic, sessions) = next_publish(&mut inspector)?;
+        assert_eq!(topic, "$SAKE/sessions");
+        assert_eq!(
+            String::from_utf8_lossy(&sessions),
+            r#"[{"client_id":"busy","connected":true,"clean_session":true,"subscriptions":[{"filter":"a/#","qos":1}],"in_flight":1,"queued":0},{"client_id":"inspector","connected":true,"clean_session":true,"subscriptions":[],"in_flight":0,"queued":0}]"#
+        );
+        assert_eq!(
+            next_publish(&mut inspector)?,
+            (
+                "$SAKE/retained".to_string(),
+                br#"{"count":1,"topics":["a/1"]}"#.to_vec()
+            )
+        );
+        // Nor can clients publish there
+        inspector.publish("$SAKE/retained", b"{}")?;
+        inspector.read_message::<Response>()?;
+        inspector.set_read_timeout(Some(Duration::from_millis(200)))?;
+        assert!(inspector.read_message::<Response>().is_err());
+
+        let broker = start(BrokerOptions::default())?;
+        let mut inspector = client(&broker, "inspector")?;
+        subscribe(&mut inspector, "$SAKE/sessions")?;
+        inspector.set_read_timeout(Some(Duration::from_millis(200)))?;
+        assert!(inspector.read_message::<Response>().is_err());
+        Ok(())
+    }
+
+    /// A masked binary frame, as clients send them
+    fn ws_frame(payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x82, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        frame
+    }
+
+    #[test]
+    fn test_websocket_client() -> io::Result<()> {
+        let mut broker = Broker::new(BrokerOptions::default())?;
+        let ws_addr = broker.bind_websocket("127.0.0.1:0")?;
+        let broker = Arc::new(broker);
+        let server = Arc::clone(&broker);
+        thread::spawn(move || server.run());
+        let mut subscriber = client(&broker, "sub")?;
+        subscribe(&mut subscriber, "a")?;
+
+        let mut stream = TcpStream::connect(ws_addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(
+            b"GET /mqtt HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Protocol: mqtt\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n",
+        )?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\n") {
+            io::BufRead::read_line(&mut reader, &mut response)?;
+        }
+        assert!(response.starts_with("HTTP/1.1 101 "));
+
+        let connect = Protocol::builder().client_id("ws").connect_request();
+        // Split across two frames, packets may span them
+        let connect = connect.to_bytes()?;
+        stream.write_all(&ws_frame(&connect[..3]))?;
+        stream.write_all(&ws_frame(&connect[3..]))?;
+        let mut connack = [0; 6];
+        reader.read_exact(&mut connack)?;
+        assert_eq!(connack, [0x82, 4, 0x20, 2, 0, 0]);
+
+        stream.write_all(&ws_frame(&[0x30, 4, 0, 1, b'a', b'x']))?;
+        assert_eq!(next_publish(&mut subscriber)?, ("a".into(), b"x".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_filters() {
+        assert!(is_valid_filter("a/+/b"));
+        assert!(is_valid_filter("#"));
+        assert!(is_valid_filter("a/#"));
+        assert!(!is_valid_filter(""));
+        assert!(!is_valid_filter("a/#/b"));
+        assert!(!is_valid_filter("a+/b"));
+    }
+}
@@ -0,0 +1,273 @@
+use crate::broker::Message;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const TAG_RETAIN: u8 = 1;
+const TAG_SESSION: u8 = 2;
+const TAG_SESSION_END: u8 = 3;
+const TAG_SUBSCRIBE: u8 = 4;
+const TAG_UNSUBSCRIBE: u8 = 5;
+const TAG_STORE: u8 = 6;
+const TAG_DONE: u8 = 7;
+
+/// A change to the broker state worth surviving a restart. Only sessions
+/// without the clean session flag are recorded
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// A retained message, an empty payload clears its topic
+    Retain(Message),
+    Session(String),
+    SessionEnd(String),
+    Subscribe {
+        client_id: String,
+        filter: String,
+        qos: u8,
+    },
+    Unsubscribe {
+        client_id: String,
+        filter: String,
+    },
+    /// A QoS 1 or 2 message to deliver to a client, `seq` identifies it
+    /// within its session
+    Store {
+        client_id: String,
+        seq: u64,
+        message: Message,
+    },
+    /// The message `seq` of a client was acknowledged or dropped
+    Done {
+        client_id: String,
+        seq: u64,
+    },
+}
+
+fn write_string(writer: &mut impl Write, s: &str) -> io::Result<()> {
+    writer.write_u16::<BigEndian>(s.len() as u16)?;
+    writer.write_all(s.as_bytes())
+}
+
+fn read_string(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut bytes = vec![0; reader.read_u16::<BigEndian>()? as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(writer: &mut impl Write, message: &Message) -> io::Result<()> {
+    write_string(writer, &message.topic)?;
+    writer.write_u32::<BigEndian>(message.payload.len() as u32)?;
+    writer.write_all(&message.payload)?;
+    writer.write_u8(message.qos)?;
+    writer.write_u8(message.retain as u8)
+}
+
+fn read_message(reader: &mut impl BufRead) -> io::Result<Message> {
+    let topic = read_string(reader)?;
+    let mut payload = vec![0; reader.read_u32::<BigEndian>()? as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Message {
+        topic,
+        payload,
+        qos: reader.read_u8()?,
+        retain: reader.read_u8()? != 0,
+    })
+}
+
+impl Record {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Record::Retain(message) => {
+                writer.write_u8(TAG_RETAIN)?;
+                write_message(writer, message)
+            }
+            Record::Session(client_id) => {
+                writer.write_u8(TAG_SESSION)?;
+                write_string(writer, client_id)
+            }
+            Record::SessionEnd(client_id) => {
+                writer.write_u8(TAG_SESSION_END)?;
+                write_string(writer, client_id)
+            }
+            Record::Subscribe {
+                client_id,
+                filter,
+                qos,
+            } => {
+                writer.write_u8(TAG_SUBSCRIBE)?;
+                write_string(writer, client_id)?;
+                write_string(writer, filter)?;
+                writer.write_u8(*qos)
+            }
+            Record::Unsubscribe { client_id, filter } => {
+                writer.write_u8(TAG_UNSUBSCRIBE)?;
+                write_string(writer, client_id)?;
+                write_string(writer, filter)
+            }
+            Record::Store {
+                client_id,
+                seq,
+                message,
+            } => {
+                writer.write_u8(TAG_STORE)?;
+                write_string(writer, client_id)?;
+                writer.write_u64::<BigEndian>(*seq)?;
+                write_message(writer, message)
+            }
+            Record::Done { client_id, seq } => {
+                writer.write_u8(TAG_DONE)?;
+                write_string(writer, client_id)?;
+                writer.write_u64::<BigEndian>(*seq)
+            }
+        }
+    }
+
+    /// Read the next record of a log, `None` at the end of it
+    fn read(reader: &mut impl BufRead) -> io::Result<Option<Self>> {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let record = match reader.read_u8()? {
+            TAG_RETAIN => Record::Retain(read_message(reader)?),
+            TAG_SESSION => Record::Session(read_string(reader)?),
+            TAG_SESSION_END => Record::SessionEnd(read_string(reader)?),
+            TAG_SUBSCRIBE => Record::Subscribe {
+                client_id: read_string(reader)?,
+                filter: read_string(reader)?,
+                qos: reader.read_u8()?,
+            },
+            TAG_UNSUBSCRIBE => Record::Unsubscribe {
+                client_id: read_string(reader)?,
+                filter: read_string(reader)?,
+            },
+            TAG_STORE => Record::Store {
+                client_id: read_string(reader)?,
+                seq: reader.read_u64::<BigEndian>()?,
+                message: read_message(reader)?,
+            },
+            TAG_DONE => Record::Done {
+                client_id: read_string(reader)?,
+                seq: reader.read_u64::<BigEndian>()?,
+            },
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown broker log record 0x{:02X}", tag),
+                ))
+            }
+        };
+        Ok(Some(record))
+    }
+}
+
+/// Append-only log of `Record`s, replayed on startup
+#[derive(Debug)]
+pub struct Log {
+    path: PathBuf,
+    file: File,
+}
+
+impl Log {
+    /// Open the log at `path`, created if missing, along with the records
+    /// it holds. A record cut short by a crash ends the log
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, Vec<Record>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut records = vec![];
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            loop {
+                match Record::read(&mut reader) {
+                    Ok(Some(record)) => records.push(record),
+                    Ok(None) => break,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        eprintln!("{}: ignoring a truncated record", path.display());
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((Self { path, file }, records))
+    }
+
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut bytes = vec![];
+        record.write(&mut bytes)?;
+        self.file.write_all(&bytes)
+    }
+
+    /// Replace the log with `records`, the live state it boils down to
+    pub fn compact(&mut self, records: &[Record]) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for record in records {
+            record.write(&mut writer)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod persist_tests {
+    use super::*;
+
+    #[test]
+    fn test_log_round_trip() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("sake-broker-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let message = Message {
+            topic: "a".into(),
+            payload: b"x".to_vec(),
+            qos: 1,
+            retain: true,
+        };
+        let records = vec![
+            Record::Retain(message.clone()),
+            Record::Session("c".into()),
+            Record::Subscribe {
+                client_id: "c".into(),
+                filter: "a/#".into(),
+                qos: 2,
+            },
+            Record::Store {
+                client_id: "c".into(),
+                seq: 3,
+                message,
+            },
+            Record::Done {
+                client_id: "c".into(),
+                seq: 3,
+            },
+            Record::Unsubscribe {
+                client_id: "c".into(),
+                filter: "a/#".into(),
+            },
+            Record::SessionEnd("c".into()),
+        ];
+        let (mut log, replayed) = Log::open(&path)?;
+        assert!(replayed.is_empty());
+        for record in &records {
+            log.append(record)?;
+        }
+        drop(log);
+        // Cut the last record short
+        let len = fs::metadata(&path)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(len - 1)?;
+        let (mut log, replayed) = Log::open(&path)?;
+        assert_eq!(replayed, &records[..records.len() - 1]);
+
+        log.compact(&records[..2])?;
+        log.append(&records[2])?;
+        drop(log);
+        let (_, replayed) = Log::open(&path)?;
+        assert_eq!(replayed, &records[..3]);
+        fs::remove_file(&path)
+    }
+}
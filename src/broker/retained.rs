@@ -0,0 +1,160 @@
+use crate::broker::Message;
+use crate::topic;
+use std::collections::HashMap;
+use std::io;
+
+/// What to do with a new retained topic once the store is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eviction {
+    /// Forget the topic retained the longest ago to make room
+    Oldest,
+    /// Keep the store as is, the message is still delivered
+    Reject,
+}
+
+impl std::str::FromStr for Eviction {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oldest" => Ok(Eviction::Oldest),
+            "reject" => Ok(Eviction::Reject),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid eviction policy {}, expected oldest or reject", s),
+            )),
+        }
+    }
+}
+
+/// Last retained message of each topic, replayed to new subscriptions
+#[derive(Debug)]
+pub struct RetainedStore {
+    /// Messages with the order they were retained in, for the eviction
+    messages: HashMap<String, (u64, Message)>,
+    next: u64,
+    max_messages: Option<usize>,
+    eviction: Eviction,
+}
+
+impl RetainedStore {
+    pub fn new(max_messages: Option<usize>, eviction: Eviction) -> Self {
+        Self {
+            messages: HashMap::new(),
+            next: 0,
+            max_messages,
+            eviction,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Retain `message` for its topic, replacing the previous one, an empty
+    /// payload clears the topic instead. Returns false if the store is full
+    /// and rejects new topics
+    pub fn retain(&mut self, message: &Message) -> bool {
+        if message.payload.is_empty() {
+            self.messages.remove(&message.topic);
+            return true;
+        }
+        let full = self
+            .max_messages
+            .is_some_and(|max| self.messages.len() >= max);
+        if full && !self.messages.contains_key(&message.topic) {
+            match self.eviction {
+                Eviction::Reject => return false,
+                Eviction::Oldest => {
+                    let oldest = self
+                        .messages
+                        .iter()
+                        .min_by_key(|(_, (seq, _))| *seq)
+                        .map(|(topic, _)| topic.clone());
+                    if let Some(oldest) = oldest {
+                        self.messages.remove(&oldest);
+                    }
+                }
+            }
+        }
+        self.next += 1;
+        self.messages
+            .insert(message.topic.clone(), (self.next, message.clone()));
+        true
+    }
+
+    /// Every retained message, in the order they were retained
+    pub fn messages(&self) -> Vec<&Message> {
+        let mut messages: Vec<&(u64, Message)> = self.messages.values().collect();
+        messages.sort_by_key(|(seq, _)| *seq);
+        messages.into_iter().map(|(_, message)| message).collect()
+    }
+
+    /// Retained messages matching a subscription filter, sorted by topic
+    pub fn matching(&self, filter: &str) -> Vec<&Message> {
+        let mut matching: Vec<&Message> = self
+            .messages
+            .values()
+            .map(|(_, message)| message)
+            .filter(|message| topic::matches(filter, &message.topic))
+            .collect();
+        matching.sort_by(|a, b| a.topic.cmp(&b.topic));
+        matching
+    }
+}
+
+#[cfg(test)]
+mod retained_tests {
+    use super::*;
+
+    fn message(topic: &str, payload: &[u8]) -> Message {
+        Message {
+            topic: topic.into(),
+            payload: payload.to_vec(),
+            qos: 0,
+            retain: true,
+        }
+    }
+
+    fn topics(store: &RetainedStore, filter: &str) -> Vec<String> {
+        store
+            .matching(filter)
+            .iter()
+            .map(|m| m.topic.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_retain_and_clear() {
+        let mut store = RetainedStore::new(None, Eviction::Oldest);
+        assert!(store.retain(&message("a/1", b"x")));
+        assert!(store.retain(&message("a/2", b"y")));
+        assert!(store.retain(&message("a/1", b"z")));
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.matching("a/1")[0].payload, b"z");
+        assert_eq!(topics(&store, "a/+"), ["a/1", "a/2"]);
+        assert!(store.retain(&message("a/1", b"")));
+        assert_eq!(topics(&store, "#"), ["a/2"]);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut store = RetainedStore::new(Some(2), Eviction::Oldest);
+        store.retain(&message("a", b"1"));
+        store.retain(&message("b", b"1"));
+        // Updating a topic makes it the most recent one
+        store.retain(&message("a", b"2"));
+        assert!(store.retain(&message("c", b"1")));
+        assert_eq!(topics(&store, "#"), ["a", "c"]);
+
+        let mut store = RetainedStore::new(Some(1), Eviction::Reject);
+        assert!(store.retain(&message("a", b"1")));
+        assert!(!store.retain(&message("b", b"1")));
+        assert!(store.retain(&message("a", b"2")));
+        assert_eq!(topics(&store, "#"), ["a"]);
+    }
+}
@@ -0,0 +1,500 @@
+use crate::broker::sys::SysStats;
+use crate::broker::{Log, Message, Record, ServerPacket};
+use crate::json::Value;
+use crate::mqtt::{PacketType, Serialize, Transport};
+use crate::topic::TopicTree;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::net::Shutdown;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// QoS 1 and 2 messages kept per session, the oldest are dropped first
+const MAX_PENDING: usize = 10_000;
+/// Routed messages a worker delivers under a single lock of its shard
+const ROUTE_BATCH: usize = 256;
+/// Packets queued for a client not written yet, a client falling further
+/// behind is disconnected
+pub const MAX_OUTGOING: usize = 1000;
+
+/// Write half of a client connection, over TCP, WebSocket or a transport
+/// handed to `Broker::serve_transport`. Packets are queued and written by a
+/// thread of its own, never under the lock of the shard
+pub struct Connection {
+    /// Tells it apart from a connection that took the session over
+    pub id: u64,
+    outgoing: SyncSender<Vec<u8>>,
+    /// Shut down when the session is taken over
+    pub socket: Box<dyn Transport>,
+}
+
+impl Connection {
+    /// Start the thread writing the packets queued to `writer`, it ends
+    /// once the connection is dropped and the queue is drained
+    pub fn new(
+        id: u64,
+        mut writer: Box<dyn Write + Send>,
+        socket: Box<dyn Transport>,
+        stats: Arc<SysStats>,
+    ) -> io::Result<Self> {
+        let (outgoing, packets) = mpsc::sync_channel::<Vec<u8>>(MAX_OUTGOING);
+        let writer_socket = socket.try_clone()?;
+        thread::Builder::new()
+            .name(format!("sake-broker-writer-{}", id))
+            .spawn(move || {
+                for bytes in packets {
+                    // A single write, a WebSocket frame each
+                    if writer.write_all(&bytes).is_err() {
+                        // The reading side notices and ends the connection
+                        writer_socket.shutdown(Shutdown::Both).ok();
+                        return;
+                    }
+                    let publish =
+                        bytes.first().map(|byte| byte >> 4) == Some(PacketType::Publish as u8);
+                    stats.sent(bytes.len() as u64, publish);
+                }
+            })?;
+        Ok(Self {
+            id,
+            outgoing,
+            socket,
+        })
+    }
+
+    /// Queue `bytes` for the client, disconnecting it if it lags too far
+    /// behind
+    fn queue(&self, bytes: Vec<u8>) -> io::Result<()> {
+        match self.outgoing.try_send(bytes) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.socket.shutdown(Shutdown::Both).ok();
+                Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("Client too slow, {} packets left unwritten", MAX_OUTGOING),
+                ))
+            }
+        }
+    }
+}
+
+/// A QoS 1 or 2 message on its way to a client
+pub struct Pending {
+    /// Identifies it within the session in the log
+    seq: u64,
+    /// Assigned once sent, the message waits for the client to come back
+    /// until then
+    pub packet_id: Option<u16>,
+    message: Message,
+    /// QoS 2 only, PUBREC received and PUBREL sent
+    pub released: bool,
+}
+
+/// What the broker knows of a client, kept across connections unless it
+/// asked for a clean session
+pub struct Session {
+    /// `None` while offline
+    pub connection: Option<Connection>,
+    pub clean_session: bool,
+    /// Filters with their granted QoS, also indexed in `Shard::subscriptions`
+    subscriptions: Vec<(String, u8)>,
+    next_packet_id: u16,
+    pub pending: VecDeque<Pending>,
+    next_seq: u64,
+}
+
+impl Session {
+    fn new(clean_session: bool) -> Self {
+        Self {
+            connection: None,
+            clean_session,
+            subscriptions: vec![],
+            next_packet_id: 0,
+            pending: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+
+    pub fn send(&mut self, packet: &ServerPacket) -> io::Result<()> {
+        match &self.connection {
+            Some(connection) => connection.queue(packet.to_bytes()?),
+            None => Ok(()),
+        }
+    }
+
+    /// Next packet id not used by a message in flight
+    fn allocate_packet_id(&mut self) -> u16 {
+        loop {
+            self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+            let id = self.next_packet_id;
+            if !self.pending.iter().any(|p| p.packet_id == Some(id)) {
+                return id;
+            }
+        }
+    }
+
+    /// Send the pending messages not sent yet, if the client is connected
+    fn flush(&mut self) -> io::Result<()> {
+        if self.connection.is_none() {
+            return Ok(());
+        }
+        for i in 0..self.pending.len() {
+            if self.pending[i].packet_id.is_some() {
+                continue;
+            }
+            let packet_id = self.allocate_packet_id();
+            self.pending[i].packet_id = Some(packet_id);
+            self.send(&ServerPacket::Publish {
+                packet_id,
+                dup: false,
+                message: self.pending[i].message.clone(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Resend what was in flight when the previous connection ended, then
+    /// what queued up meanwhile
+    pub fn resume(&mut self) -> io::Result<()> {
+        for i in 0..self.pending.len() {
+            let packet = match &self.pending[i] {
+                Pending {
+                    packet_id: Some(packet_id),
+                    released: true,
+                    ..
+                } => ServerPacket::Pubrel(*packet_id),
+                Pending {
+                    packet_id: Some(packet_id),
+                    message,
+                    ..
+                } => ServerPacket::Publish {
+                    packet_id: *packet_id,
+                    dup: true,
+                    message: message.clone(),
+                },
+                Pending {
+                    packet_id: None, ..
+                } => continue,
+            };
+            self.send(&packet)?;
+        }
+        self.flush()
+    }
+
+    /// Forget the message in flight with `packet_id` if it has `qos`,
+    /// returns its seq
+    fn complete(&mut self, packet_id: u16, qos: u8) -> Option<u64> {
+        let i = self
+            .pending
+            .iter()
+            .position(|p| p.packet_id == Some(packet_id) && p.message.qos == qos)?;
+        self.pending.remove(i).map(|p| p.seq)
+    }
+}
+
+/// The sessions whose client id hashes to it, along with their
+/// subscriptions. A client is only ever handled by its own shard
+pub struct Shard {
+    pub sessions: HashMap<String, Session>,
+    /// Client id and granted QoS of every subscription
+    pub subscriptions: TopicTree<(String, u8)>,
+    /// Shared by every shard, `None` until the state is restored
+    log: Arc<Mutex<Option<Log>>>,
+}
+
+impl Shard {
+    pub fn new(log: Arc<Mutex<Option<Log>>>) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            subscriptions: TopicTree::new(),
+            log,
+        }
+    }
+
+    /// Append to the log, if any. A failure is reported but doesn't stop
+    /// the broker, only what comes after it may not survive a restart
+    fn record(&self, record: Record) {
+        if let Some(log) = &mut *self.log.lock().unwrap() {
+            if let Err(e) = log.append(&record) {
+                eprintln!("Broker log: {}", e);
+            }
+        }
+    }
+
+    fn is_persistent(&self, client_id: &str) -> bool {
+        self.sessions
+            .get(client_id)
+            .is_some_and(|s| !s.clean_session)
+            && self.log.lock().unwrap().is_some()
+    }
+
+    /// A JSON object per session, for `$SAKE/sessions`: its client id,
+    /// whether it's connected and clean, its subscriptions, the messages
+    /// sent and not acknowledged yet and those still to be sent
+    pub fn inspect(&self, out: &mut Vec<Value>) {
+        for (client_id, session) in &self.sessions {
+            let in_flight = session
+                .pending
+                .iter()
+                .filter(|p| p.packet_id.is_some())
+                .count();
+            let subscriptions = session
+                .subscriptions
+                .iter()
+                .map(|(filter, qos)| {
+                    Value::Object(vec![
+                        ("filter".to_string(), Value::String(filter.clone())),
+                        ("qos".to_string(), Value::Number(*qos as f64)),
+                    ])
+                })
+                .collect();
+            out.push(Value::Object(vec![
+                ("client_id".to_string(), Value::String(client_id.clone())),
+                (
+                    "connected".to_string(),
+                    Value::Bool(session.connection.is_some()),
+                ),
+                (
+                    "clean_session".to_string(),
+                    Value::Bool(session.clean_session),
+                ),
+                ("subscriptions".to_string(), Value::Array(subscriptions)),
+                ("in_flight".to_string(), Value::Number(in_flight as f64)),
+                (
+                    "queued".to_string(),
+                    Value::Number((session.pending.len() - in_flight) as f64),
+                ),
+            ]));
+        }
+    }
+
+    /// Clients connected right now
+    pub fn connected(&self) -> usize {
+        self.sessions
+            .values()
+            .filter(|s| s.connection.is_some())
+            .count()
+    }
+
+    /// Deliver `message` to the matching subscriptions, with the retain
+    /// flag cleared, it is only set on messages replayed to a new
+    /// subscription
+    fn route(&mut self, message: &Message) {
+        // Overlapping subscriptions of a client get a single copy, with the
+        // highest QoS granted
+        let mut granted: HashMap<String, u8> = HashMap::new();
+        for (client_id, qos) in self.subscriptions.matches(&message.topic) {
+            let max = granted.entry(client_id.clone()).or_default();
+            *max = (*max).max(*qos);
+        }
+        for (client_id, qos) in granted {
+            if let Err(e) = self.deliver(&client_id, message, qos, false) {
+                eprintln!("{}: {}", client_id, e);
+            }
+        }
+    }
+
+    /// Send `message` to a client at most at `qos`. QoS 1 and 2 messages
+    /// are kept until acknowledged, and queued while the client is offline
+    pub fn deliver(
+        &mut self,
+        client_id: &str,
+        message: &Message,
+        qos: u8,
+        retain: bool,
+    ) -> io::Result<()> {
+        let message = Message {
+            qos: message.qos.min(qos),
+            retain,
+            ..message.clone()
+        };
+        let persistent = self.is_persistent(client_id);
+        let Some(session) = self.sessions.get_mut(client_id) else {
+            return Ok(());
+        };
+        if message.qos == 0 {
+            return session.send(&ServerPacket::Publish {
+                packet_id: 0,
+                dup: false,
+                message,
+            });
+        }
+        let dropped = if session.pending.len() >= MAX_PENDING {
+            session.pending.pop_front().map(|p| p.seq)
+        } else {
+            None
+        };
+        let seq = session.next_seq;
+        session.next_seq += 1;
+        session.pending.push_back(Pending {
+            seq,
+            packet_id: None,
+            message: message.clone(),
+            released: false,
+        });
+        let sent = session.flush();
+        if persistent {
+            if let Some(seq) = dropped {
+                self.record(Record::Done {
+                    client_id: client_id.to_string(),
+                    seq,
+                });
+            }
+            self.record(Record::Store {
+                client_id: client_id.to_string(),
+                seq,
+                message,
+            });
+        }
+        sent
+    }
+
+    /// Forget an acknowledged message
+    pub fn complete(&mut self, client_id: &str, packet_id: u16, qos: u8) {
+        let persistent = self.is_persistent(client_id);
+        let seq = self
+            .sessions
+            .get_mut(client_id)
+            .and_then(|session| session.complete(packet_id, qos));
+        if let (Some(seq), true) = (seq, persistent) {
+            self.record(Record::Done {
+                client_id: client_id.to_string(),
+                seq,
+            });
+        }
+    }
+
+    /// Add or replace the subscription of a client to `filter`
+    pub fn subscribe(&mut self, client_id: &str, filter: &str, qos: u8) {
+        self.unsubscribe(client_id, filter);
+        if let Some(session) = self.sessions.get_mut(client_id) {
+            session.subscriptions.push((filter.to_string(), qos));
+            self.subscriptions
+                .insert(filter, (client_id.to_string(), qos));
+            if self.is_persistent(client_id) {
+                self.record(Record::Subscribe {
+                    client_id: client_id.to_string(),
+                    filter: filter.to_string(),
+                    qos,
+                });
+            }
+        }
+    }
+
+    pub fn unsubscribe(&mut self, client_id: &str, filter: &str) {
+        let Some(session) = self.sessions.get_mut(client_id) else {
+            return;
+        };
+        let before = session.subscriptions.len();
+        session.subscriptions.retain(|(f, _)| f != filter);
+        if session.subscriptions.len() < before {
+            self.subscriptions.retain(filter, |(c, _)| c != client_id);
+            if self.is_persistent(client_id) {
+                self.record(Record::Unsubscribe {
+                    client_id: client_id.to_string(),
+                    filter: filter.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Get or start a session, recorded unless it is a clean one
+    pub fn insert_session(&mut self, client_id: &str, clean_session: bool) -> &mut Session {
+        if !clean_session && !self.sessions.contains_key(client_id) {
+            self.record(Record::Session(client_id.to_string()));
+        }
+        self.sessions
+            .entry(client_id.to_string())
+            .or_insert_with(|| Session::new(clean_session))
+    }
+
+    /// Forget a session along with its subscriptions
+    pub fn remove_session(&mut self, client_id: &str) -> Option<Session> {
+        let persistent = self.is_persistent(client_id);
+        let session = self.sessions.remove(client_id)?;
+        for (filter, _) in &session.subscriptions {
+            self.subscriptions.retain(filter, |(c, _)| c != client_id);
+        }
+        if persistent {
+            self.record(Record::SessionEnd(client_id.to_string()));
+        }
+        Some(session)
+    }
+
+    /// Apply a record of a log to the sessions, they are restored offline
+    pub fn replay(&mut self, record: Record) {
+        match record {
+            Record::Retain(_) => {}
+            Record::Session(client_id) => {
+                self.sessions
+                    .entry(client_id)
+                    .or_insert_with(|| Session::new(false));
+            }
+            Record::SessionEnd(client_id) => {
+                self.remove_session(&client_id);
+            }
+            Record::Subscribe {
+                client_id,
+                filter,
+                qos,
+            } => self.subscribe(&client_id, &filter, qos),
+            Record::Unsubscribe { client_id, filter } => self.unsubscribe(&client_id, &filter),
+            Record::Store {
+                client_id,
+                seq,
+                message,
+            } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.next_seq = session.next_seq.max(seq + 1);
+                    session.pending.push_back(Pending {
+                        seq,
+                        packet_id: None,
+                        message,
+                        released: false,
+                    });
+                }
+            }
+            Record::Done { client_id, seq } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.pending.retain(|p| p.seq != seq);
+                }
+            }
+        }
+    }
+
+    /// Records the persistent sessions boil down to, to compact the log with
+    pub fn snapshot(&self, records: &mut Vec<Record>) {
+        for (client_id, session) in &self.sessions {
+            if session.clean_session {
+                continue;
+            }
+            records.push(Record::Session(client_id.clone()));
+            for (filter, qos) in &session.subscriptions {
+                records.push(Record::Subscribe {
+                    client_id: client_id.clone(),
+                    filter: filter.clone(),
+                    qos: *qos,
+                });
+            }
+            for pending in &session.pending {
+                records.push(Record::Store {
+                    client_id: client_id.clone(),
+                    seq: pending.seq,
+                    message: pending.message.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// Deliver the messages handed to a shard until the broker is dropped.
+/// Whatever queued up meanwhile is delivered under the same lock
+pub fn work(shard: &Mutex<Shard>, routes: Receiver<Message>) {
+    while let Ok(message) = routes.recv() {
+        let mut shard = shard.lock().unwrap();
+        shard.route(&message);
+        for message in routes.try_iter().take(ROUTE_BATCH) {
+            shard.route(&message);
+        }
+    }
+}
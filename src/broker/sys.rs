@@ -0,0 +1,154 @@
+use crate::broker::Message;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Default period of the `$SYS` updates, as Mosquitto's `sys_interval`
+pub const DEFAULT_SYS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Traffic counters behind the `$SYS/broker/...` topics, shared with the
+/// connections writing to clients
+#[derive(Debug)]
+pub struct SysStats {
+    started: Instant,
+    /// Packets of any type
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    publish_received: AtomicU64,
+    publish_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl SysStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            publish_received: AtomicU64::new(0),
+            publish_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        }
+    }
+
+    pub fn received(&self, bytes: u64, publish: bool) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+        if publish {
+            self.publish_received.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn sent(&self, bytes: u64, publish: bool) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        if publish {
+            self.publish_sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The retained `$SYS` messages to publish, given the gauges only the
+    /// broker state knows
+    pub fn messages(
+        &self,
+        connected: usize,
+        total: usize,
+        subscriptions: usize,
+        retained: usize,
+    ) -> Vec<Message> {
+        let counter = |c: &AtomicU64| c.load(Ordering::Relaxed).to_string();
+        let values = [
+            ("version", format!("sake {}", env!("CARGO_PKG_VERSION"))),
+            (
+                "uptime",
+                format!("{} seconds", self.started.elapsed().as_secs()),
+            ),
+            ("clients/connected", connected.to_string()),
+            ("clients/total", total.to_string()),
+            ("subscriptions/count", subscriptions.to_string()),
+            ("retained messages/count", retained.to_string()),
+            ("messages/received", counter(&self.messages_received)),
+            ("messages/sent", counter(&self.messages_sent)),
+            ("publish/messages/received", counter(&self.publish_received)),
+            ("publish/messages/sent", counter(&self.publish_sent)),
+            ("bytes/received", counter(&self.bytes_received)),
+            ("bytes/sent", counter(&self.bytes_sent)),
+        ];
+        values
+            .into_iter()
+            .map(|(name, value)| Message {
+                topic: format!("$SYS/broker/{}", name),
+                payload: value.into_bytes(),
+                qos: 0,
+                retain: true,
+            })
+            .collect()
+    }
+}
+
+/// Counts the bytes read through it, for `bytes/received`
+pub struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    /// Bytes read since the last call
+    pub fn take_count(&mut self) -> u64 {
+        std::mem::take(&mut self.count)
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod sys_tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let stats = SysStats::new();
+        stats.sent(40, true);
+        stats.sent(2, false);
+        stats.received(5, false);
+        let messages = stats.messages(1, 2, 3, 4);
+        let value = |topic: &str| {
+            messages
+                .iter()
+                .find(|m| m.topic == topic)
+                .map(|m| String::from_utf8(m.payload.clone()).unwrap())
+        };
+        assert_eq!(value("$SYS/broker/clients/connected").as_deref(), Some("1"));
+        assert_eq!(value("$SYS/broker/bytes/sent").as_deref(), Some("42"));
+        assert_eq!(value("$SYS/broker/messages/sent").as_deref(), Some("2"));
+        assert_eq!(
+            value("$SYS/broker/publish/messages/sent").as_deref(),
+            Some("1")
+        );
+        assert_eq!(value("$SYS/broker/bytes/received").as_deref(), Some("5"));
+        assert_eq!(value("$SYS/broker/uptime").as_deref(), Some("0 seconds"));
+        assert!(messages.iter().all(|m| m.retain));
+    }
+
+    #[test]
+    fn test_counting_reader() -> io::Result<()> {
+        let mut reader = CountingReader::new(&b"abcdef"[..]);
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(reader.take_count(), 4);
+        assert_eq!(reader.take_count(), 0);
+        Ok(())
+    }
+}
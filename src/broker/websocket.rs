@@ -0,0 +1,311 @@
+use crate::encoding::base64_encode;
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use sha1::{Digest, Sha1};
+use std::io::{self, BufRead, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+/// Appended to the client key to compute Sec-WebSocket-Accept, RFC 6455
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Subprotocol of MQTT over WebSocket
+const SUBPROTOCOL: &str = "mqtt";
+/// Bound on the request line and headers of the opening handshake
+const MAX_HANDSHAKE_LEN: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+fn accept_key(key: &str) -> String {
+    base64_encode(&Sha1::digest(
+        format!("{}{}", key, WEBSOCKET_GUID).as_bytes(),
+    ))
+}
+
+fn bad_handshake(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid WebSocket handshake: {}", reason),
+    )
+}
+
+/// Read the HTTP upgrade request of a client and accept it with the `mqtt`
+/// subprotocol. Anything buffered past the headers stays in `reader`
+pub fn accept(reader: &mut impl BufRead, writer: &mut impl Write) -> io::Result<()> {
+    let mut request = String::new();
+    let mut key = None;
+    let mut upgrade = false;
+    loop {
+        // A byte past the bound tells a line cut short from one ending there
+        let left = MAX_HANDSHAKE_LEN - request.len() + 1;
+        let mut line = String::new();
+        if reader.take(left as u64).read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.push_str(&line);
+        if request.len() > MAX_HANDSHAKE_LEN {
+            return Err(bad_handshake("headers too long"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+                "sec-websocket-key" => key = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+    if !request.starts_with("GET ") {
+        return Err(bad_handshake("expected a GET request"));
+    }
+    let key = match key {
+        Some(key) if upgrade => key,
+        _ => {
+            writer.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+            return Err(bad_handshake("not a WebSocket upgrade"));
+        }
+    };
+    // The subprotocol is answered even if the client didn't list it, some
+    // clients omit it and MQTT is the only one spoken here
+    write!(
+        writer,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\r\n",
+        accept_key(&key),
+        SUBPROTOCOL
+    )?;
+    writer.flush()
+}
+
+fn write_frame(writer: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.write_u8(0x80 | opcode)?;
+    match payload.len() {
+        len @ 0..=125 => frame.write_u8(len as u8)?,
+        len @ 126..=0xFFFF => {
+            frame.write_u8(126)?;
+            frame.write_u16::<NetworkEndian>(len as u16)?;
+        }
+        len => {
+            frame.write_u8(127)?;
+            frame.write_u64::<NetworkEndian>(len as u64)?;
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)
+}
+
+/// Write half of a WebSocket connection, every write is sent as a single
+/// binary frame. Shared with the reader, which answers pings and closes
+#[derive(Clone)]
+pub struct WsWriter {
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl WsWriter {
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            stream: Arc::new(Mutex::new(stream)),
+        }
+    }
+}
+
+impl Write for WsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_frame(&mut *self.stream.lock().unwrap(), OPCODE_BINARY, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.lock().unwrap().flush()
+    }
+}
+
+/// Read half of a WebSocket connection, yields the payloads of the data
+/// frames as a single stream so that MQTT packets may span frames. A close
+/// frame ends the stream
+pub struct WsReader<R> {
+    inner: R,
+    writer: WsWriter,
+    /// Longest payload of a data frame accepted
+    max_frame_len: u64,
+    /// Payload bytes left in the current frame
+    remaining: u64,
+    mask: [u8; 4],
+    offset: usize,
+    closed: bool,
+}
+
+impl<R: Read> WsReader<R> {
+    pub fn new(inner: R, writer: WsWriter, max_frame_len: u64) -> Self {
+        Self {
+            inner,
+            writer,
+            max_frame_len,
+            remaining: 0,
+            mask: [0; 4],
+            offset: 0,
+            closed: false,
+        }
+    }
+
+    /// Read frame headers until a data frame with a payload, handling the
+    /// control frames in between. Returns false once the peer closed
+    fn next_frame(&mut self) -> io::Result<bool> {
+        while self.remaining == 0 {
+            let first = self.inner.read_u8()?;
+            let second = self.inner.read_u8()?;
+            if second & 0x80 == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unmasked WebSocket frame from client",
+                ));
+            }
+            let len = match second & 0x7F {
+                126 => self.inner.read_u16::<NetworkEndian>()? as u64,
+                127 => self.inner.read_u64::<NetworkEndian>()?,
+                len => len as u64,
+            };
+            if len > self.max_frame_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "WebSocket frame of {} bytes over the limit of {}",
+                        len, self.max_frame_len
+                    ),
+                ));
+            }
+            self.inner.read_exact(&mut self.mask)?;
+            self.offset = 0;
+            match first & 0x0F {
+                OPCODE_BINARY | OPCODE_CONTINUATION => self.remaining = len,
+                opcode @ (OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG) if len <= 125 => {
+                    let mut payload = vec![0; len as usize];
+                    self.inner.read_exact(&mut payload)?;
+                    self.unmask(&mut payload);
+                    let mut stream = self.writer.stream.lock().unwrap();
+                    match opcode {
+                        OPCODE_PING => write_frame(&mut *stream, OPCODE_PONG, &payload)?,
+                        OPCODE_CLOSE => {
+                            // Echo the status code, the connection ends after
+                            write_frame(
+                                &mut *stream,
+                                OPCODE_CLOSE,
+                                &payload[..len.min(2) as usize],
+                            )?;
+                            return Ok(false);
+                        }
+                        _ => {}
+                    }
+                }
+                opcode => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unexpected WebSocket frame, opcode 0x{:X}", opcode),
+                    ))
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn unmask(&mut self, bytes: &mut [u8]) {
+        for byte in bytes {
+            *byte ^= self.mask[self.offset % 4];
+            self.offset += 1;
+        }
+    }
+}
+
+impl<R: Read> Read for WsReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.closed {
+            return Ok(0);
+        }
+        if !self.next_frame()? {
+            self.closed = true;
+            return Ok(0);
+        }
+        let len = buf
+            .len()
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.unmask(&mut buf[..n]);
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod websocket_tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_handshake() -> io::Result<()> {
+        let request = b"GET /mqtt HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Protocol: mqtt\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n\x82";
+        let mut reader = io::BufReader::new(&request[..]);
+        let mut response = vec![];
+        accept(&mut reader, &mut response)?;
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        // The first frame stays buffered
+        assert_eq!(reader.read_u8()?, 0x82);
+
+        let mut reader = io::BufReader::new(&b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..]);
+        let mut response = vec![];
+        assert!(accept(&mut reader, &mut response).is_err());
+        assert!(response.starts_with(b"HTTP/1.1 400 "));
+
+        // Refused without buffering the whole line
+        let mut request = b"GET /".to_vec();
+        request.resize(MAX_HANDSHAKE_LEN * 4, b'a');
+        let mut reader = io::BufReader::new(&request[..]);
+        let err = accept(&mut reader, &mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_frame_limit() -> io::Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let writer = WsWriter::new(stream);
+        // Masked binary frames of 4 and 5 bytes, with a zero mask
+        let frames = [0x82, 0x84, 0, 0, 0, 0, 1, 2, 3, 4, 0x82, 0x85, 0, 0, 0, 0];
+        let mut reader = WsReader::new(&frames[..], writer, 4);
+        let mut payload = [0; 4];
+        reader.read_exact(&mut payload)?;
+        assert_eq!(payload, [1, 2, 3, 4]);
+        let err = reader.read(&mut payload).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
@@ -0,0 +1,629 @@
+use crate::mqtt::Property;
+use std::io;
+
+/// User property naming the codec of compressed payloads on MQTT 5
+/// sessions, 3.1.1 subscribers recognize them by their magic number
+pub const CONTENT_ENCODING: &str = "content-encoding";
+/// Payloads smaller than this are published as they are
+pub const DEFAULT_COMPRESS_THRESHOLD: usize = 256;
+/// Bound on decompressed payloads, the largest an MQTT packet can carry
+const MAX_INFLATED: usize = 268_435_455;
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B, 8];
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Candidates examined per position when looking for a match
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which a dynamic block lists the code lengths of its code
+/// length alphabet
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Payload compression selected with `--compress`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl std::str::FromStr for Codec {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Codec::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Codec::Zstd),
+            #[cfg(not(feature = "zstd"))]
+            "zstd" => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "zstd needs sake built with the zstd feature, use gzip",
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown codec {}, expected gzip or zstd", s),
+            )),
+        }
+    }
+}
+
+impl Codec {
+    const ALL: &'static [Codec] = &[
+        Codec::Gzip,
+        #[cfg(feature = "zstd")]
+        Codec::Zstd,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// First bytes of every payload compressed with the codec
+    fn magic(&self) -> &'static [u8] {
+        match self {
+            Codec::Gzip => GZIP_MAGIC,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => ZSTD_MAGIC,
+        }
+    }
+
+    /// Compress `payload` if it reaches `threshold` bytes, `None` if it is
+    /// to be published as it is. The topic is left alone, MQTT 5 sessions
+    /// tag the message with `Codec::property`
+    pub fn encode(&self, payload: &[u8], threshold: usize) -> Option<Vec<u8>> {
+        if payload.len() < threshold {
+            return None;
+        }
+        match self {
+            Codec::Gzip => Some(gzip(payload)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::bulk::compress(payload, 0).ok(),
+        }
+    }
+
+    /// The content-encoding user property of the payloads `encode`
+    /// compressed
+    pub fn property(&self) -> Property {
+        Property::UserProperty(CONTENT_ENCODING.to_string(), self.name().to_string())
+    }
+
+    fn decompress(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Gzip => gunzip(payload),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::bulk::decompress(payload, MAX_INFLATED),
+        }
+    }
+}
+
+/// Undo `Codec::encode`. A payload tagged by a content-encoding property
+/// must decompress with the codec it names, untagged ones are
+/// decompressed if they start with the magic number of a codec and
+/// decompress with it. Anything else is returned as it is
+pub fn decode(payload: &[u8], properties: &[Property]) -> io::Result<Vec<u8>> {
+    let tagged = properties.iter().find_map(|property| match property {
+        Property::UserProperty(key, value) if key.eq_ignore_ascii_case(CONTENT_ENCODING) => {
+            Some(value)
+        }
+        _ => None,
+    });
+    if let Some(name) = tagged {
+        return match Codec::ALL.iter().find(|codec| codec.name() == name) {
+            Some(codec) => codec.decompress(payload),
+            None => Ok(payload.to_vec()),
+        };
+    }
+    let decompressed = Codec::ALL
+        .iter()
+        .filter(|codec| payload.starts_with(codec.magic()))
+        .find_map(|codec| codec.decompress(payload).ok());
+    Ok(decompressed.unwrap_or_else(|| payload.to_vec()))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid gzip: {}", message),
+    )
+}
+
+/// The CRC-32 of gzip and zlib, IEEE polynomial
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Compress into a gzip member holding a single fixed Huffman block
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no mtime, no extra flags, unknown OS
+    let mut out = vec![0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 0xFF];
+    let mut bits = BitWriter {
+        out,
+        acc: 0,
+        len: 0,
+    };
+    // BFINAL, then BTYPE 01
+    bits.write(1, 1);
+    bits.write(1, 2);
+    deflate(data, &mut bits);
+    write_literal(&mut bits, 256);
+    out = bits.finish();
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.acc |= (value as u64) << self.len;
+        self.len += bits;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit
+    fn write_code(&mut self, code: u32, bits: u32) {
+        self.write(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+fn write_literal(bits: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => bits.write_code(0x30 + symbol, 8),
+        144..=255 => bits.write_code(0x190 + symbol - 144, 9),
+        256..=279 => bits.write_code(symbol - 256, 7),
+        _ => bits.write_code(0xC0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(bits: &mut BitWriter, len: usize, dist: usize) {
+    let i = LENGTH_BASE
+        .iter()
+        .rposition(|&b| b as usize <= len)
+        .unwrap();
+    write_literal(bits, 257 + i as u32);
+    bits.write(
+        (len - LENGTH_BASE[i] as usize) as u32,
+        LENGTH_EXTRA[i] as u32,
+    );
+    let i = DIST_BASE.iter().rposition(|&b| b as usize <= dist).unwrap();
+    bits.write_code(i as u32, 5);
+    bits.write((dist - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i] as u32);
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let key = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Hash chains of the positions seen so far, stored off by one so that 0
+/// marks the end of a chain
+struct Chains {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl Chains {
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos..]);
+            self.prev[pos] = self.head[h];
+            self.head[h] = pos + 1;
+        }
+    }
+}
+
+/// Greedy LZ77 over hash chains, emitting fixed Huffman symbols
+fn deflate(data: &[u8], bits: &mut BitWriter) {
+    let mut chains = Chains {
+        head: vec![0; 1 << HASH_BITS],
+        prev: vec![0; data.len()],
+    };
+    let mut pos = 0;
+    while pos < data.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let mut candidate = chains.head[hash(&data[pos..])];
+            let max_len = MAX_MATCH.min(data.len() - pos);
+            for _ in 0..MAX_CHAIN {
+                if candidate == 0 || pos - (candidate - 1) > WINDOW {
+                    break;
+                }
+                let start = candidate - 1;
+                let len = data[start..]
+                    .iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    (best_len, best_dist) = (len, pos - start);
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = chains.prev[start];
+            }
+        }
+        if best_len >= MIN_MATCH {
+            write_match(bits, best_len, best_dist);
+            for p in pos..pos + best_len {
+                chains.insert(data, p);
+            }
+            pos += best_len;
+        } else {
+            write_literal(bits, data[pos] as u32);
+            chains.insert(data, pos);
+            pos += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| invalid("truncated stream"))?;
+            value |= ((byte >> self.bit) as u32 & 1) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// Canonical Huffman code, decoded by walking the code lengths one bit at
+/// a time
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len > 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("bad Huffman code"))
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(bits: &mut BitReader) -> io::Result<(Huffman, Huffman)> {
+    let nlen = bits.bits(5)? as usize + 257;
+    let ndist = bits.bits(5)? as usize + 1;
+    let ncode = bits.bits(4)? as usize + 4;
+    let mut lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..ncode] {
+        lengths[i] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&lengths);
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let (value, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 if i > 0 => (lengths[i - 1], 3 + bits.bits(2)? as usize),
+            17 => (0, 3 + bits.bits(3)? as usize),
+            18 => (0, 11 + bits.bits(7)? as usize),
+            _ => return Err(invalid("bad code lengths")),
+        };
+        let end = i + repeat;
+        lengths
+            .get_mut(i..end)
+            .ok_or_else(|| invalid("too many code lengths"))?
+            .fill(value);
+        i = end;
+    }
+    Ok((
+        Huffman::new(&lengths[..nlen]),
+        Huffman::new(&lengths[nlen..]),
+    ))
+}
+
+fn inflate(bits: &mut BitReader) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = bits
+                    .data
+                    .get(bits.pos..bits.pos + 4)
+                    .ok_or_else(|| invalid("truncated stored block"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                if len != !u16::from_le_bytes([header[2], header[3]]) as usize {
+                    return Err(invalid("stored block length mismatch"));
+                }
+                bits.pos += 4;
+                let block = bits
+                    .data
+                    .get(bits.pos..bits.pos + len)
+                    .ok_or_else(|| invalid("truncated stored block"))?;
+                out.extend_from_slice(block);
+                bits.pos += len;
+            }
+            btype @ (1 | 2) => {
+                let (lit, dist) = if btype == 1 {
+                    fixed_codes()
+                } else {
+                    dynamic_codes(bits)?
+                };
+                loop {
+                    let symbol = lit.decode(bits)? as usize;
+                    match symbol {
+                        0..=255 => out.push(symbol as u8),
+                        256 => break,
+                        _ => {
+                            let i = symbol - 257;
+                            let len = *LENGTH_BASE.get(i).ok_or_else(|| invalid("bad length"))?
+                                as usize
+                                + bits.bits(LENGTH_EXTRA[i] as u32)? as usize;
+                            let i = dist.decode(bits)? as usize;
+                            let distance =
+                                *DIST_BASE.get(i).ok_or_else(|| invalid("bad distance"))? as usize
+                                    + bits.bits(DIST_EXTRA[i] as u32)? as usize;
+                            if distance > out.len() {
+                                return Err(invalid("distance too far back"));
+                            }
+                            let start = out.len() - distance;
+                            for j in 0..len {
+                                out.push(out[start + j]);
+                            }
+                        }
+                    }
+                    if out.len() > MAX_INFLATED {
+                        return Err(invalid("decompressed payload too large"));
+                    }
+                }
+            }
+            _ => return Err(invalid("reserved block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Decompress a gzip member, checking its CRC and size
+pub fn gunzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < 18 || !data.starts_with(GZIP_MAGIC) {
+        return Err(invalid("bad header"));
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    let skip_zero_terminated = |pos: usize| -> io::Result<usize> {
+        let len = data[pos..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| invalid("truncated header"))?;
+        Ok(pos + len + 1)
+    };
+    if flags & 0x04 != 0 {
+        let xlen = data
+            .get(pos..pos + 2)
+            .ok_or_else(|| invalid("truncated header"))?;
+        pos += 2 + u16::from_le_bytes([xlen[0], xlen[1]]) as usize;
+    }
+    if flags & 0x08 != 0 {
+        pos = skip_zero_terminated(pos)?;
+    }
+    if flags & 0x10 != 0 {
+        pos = skip_zero_terminated(pos)?;
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    let mut bits = BitReader {
+        data: data.get(pos..).ok_or_else(|| invalid("truncated header"))?,
+        pos: 0,
+        bit: 0,
+    };
+    let out = inflate(&mut bits)?;
+    bits.align();
+    let trailer = bits
+        .data
+        .get(bits.pos..bits.pos + 8)
+        .ok_or_else(|| invalid("truncated trailer"))?;
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    if crc != crc32(&out) || size != out.len() as u32 {
+        return Err(invalid("checksum mismatch"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod compress_tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() -> io::Result<()> {
+        let mut data = b"sensors/room-1 temperature=21.5 ".repeat(40);
+        data.extend((0..=255).collect::<Vec<u8>>());
+        for input in [&b""[..], b"a", &data] {
+            assert_eq!(gunzip(&gzip(input))?, input);
+        }
+        assert!(gzip(&data).len() < data.len() / 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gunzip_zlib_output() -> io::Result<()> {
+        // Fixed Huffman block, written by Python's gzip module
+        let fixed = unhex("1f8b0800000000000203cb48cdc9c957c84027b9000088590b18000000");
+        assert_eq!(gunzip(&fixed)?, b"hello hello hello hello\n");
+        // Dynamic Huffman block
+        let dynamic = unhex(
+            "1f8b0800000000000203b5ca470180300c00402b51809a1ae89e34dd4b3d18e0c9fb8e\
+             1809b95bee81159c11142e70fd4e1570c802ede540cf0681fa02f25ba68c0ba9b4b1ce\
+             873b62caa5b63ee6dae75b1e459dcc8bbb000000",
+        );
+        let mut expected = b"The quick brown fox jumps over the lazy dog. ".repeat(3);
+        expected.extend((b'a'..=b'z').chain(b'a'..=b'z'));
+        assert_eq!(gunzip(&dynamic)?, expected);
+        let mut corrupt = fixed.clone();
+        corrupt[12] ^= 1;
+        assert!(gunzip(&corrupt).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag() -> io::Result<()> {
+        let payload = b"x".repeat(300);
+        assert_eq!(Codec::Gzip.encode(b"x", 256), None);
+        let compressed = Codec::Gzip.encode(&payload, 256).unwrap();
+        let property = Codec::Gzip.property();
+        assert_eq!(
+            property,
+            Property::UserProperty("content-encoding".to_string(), "gzip".to_string())
+        );
+        // MQTT 3.1.1 subscribers go by the magic number
+        assert_eq!(decode(&compressed, &[])?, payload);
+        assert_eq!(
+            decode(&compressed, std::slice::from_ref(&property))?,
+            payload
+        );
+        // A tagged payload must decompress, an untagged one is left alone
+        let mut corrupt = compressed.clone();
+        corrupt[12] ^= 1;
+        assert!(decode(&corrupt, &[property]).is_err());
+        assert_eq!(decode(&corrupt, &[])?, corrupt);
+        assert_eq!(
+            decode(b"\x1f\x8b\x08 not gzip", &[])?,
+            b"\x1f\x8b\x08 not gzip"
+        );
+        let identity =
+            Property::UserProperty("content-encoding".to_string(), "identity".to_string());
+        assert_eq!(decode(&compressed, &[identity])?, compressed);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd() -> io::Result<()> {
+        let payload = b"sensors/room-1 temperature=21.5 ".repeat(40);
+        let codec: Codec = "zstd".parse()?;
+        let compressed = codec.encode(&payload, 256).unwrap();
+        assert!(compressed.starts_with(ZSTD_MAGIC));
+        assert!(compressed.len() < payload.len() / 4);
+        assert_eq!(decode(&compressed, &[])?, payload);
+        assert_eq!(decode(&compressed, &[codec.property()])?, payload);
+        assert!(decode(&compressed, &[Codec::Gzip.property()]).is_err());
+        Ok(())
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn test_zstd_rejected() {
+        let err = "zstd".parse::<Codec>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
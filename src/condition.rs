@@ -0,0 +1,208 @@
+use crate::json::{self, Value};
+use crate::regex::Regex;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+
+/// Comparison of a JSON condition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Longest operators first, so that `<=` isn't read as `<`
+const OPS: [(&str, Op); 6] = [
+    ("==", Op::Eq),
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+/// A test on message payloads, parsed from:
+///
+/// - `regex:PATTERN` or just `PATTERN`: the UTF-8 payload matches the
+///   regular expression anywhere, see `Regex`
+/// - `json:$.path`: the payload is JSON with a value other than null or
+///   false at the path, keys are separated by dots and array elements are
+///   selected by their index as in `json:$.readings.0.temp`
+/// - `json:$.path OP VALUE`: the value at the path compares to a JSON
+///   value, OP being one of `== != < <= > >=`, ordering only applies to
+///   numbers and strings and a VALUE that isn't JSON is taken as a string,
+///   as in `json:$.status == done`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Regex(Regex),
+    Json {
+        path: Vec<String>,
+        test: Option<(Op, Value)>,
+    },
+}
+
+impl std::str::FromStr for Condition {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid condition {}: {}", s, what),
+            )
+        };
+        let Some(spec) = s.strip_prefix("json:") else {
+            let pattern = s.strip_prefix("regex:").unwrap_or(s);
+            return pattern.parse().map(Condition::Regex);
+        };
+        let spec = spec.trim();
+        let end = spec
+            .find(|c: char| c.is_whitespace() || "=!<>".contains(c))
+            .unwrap_or(spec.len());
+        let (path, rest) = spec.split_at(end);
+        let path = json::parse_path(path).map_err(invalid)?;
+        let rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(Condition::Json { path, test: None });
+        }
+        let (op, value) = OPS
+            .iter()
+            .find_map(|(token, op)| rest.strip_prefix(token).map(|value| (*op, value.trim())))
+            .ok_or_else(|| invalid("expected one of == != < <= > >="))?;
+        if value.is_empty() {
+            return Err(invalid("missing value to compare with"));
+        }
+        let value = json::parse(value).unwrap_or_else(|_| Value::String(value.to_string()));
+        Ok(Condition::Json {
+            path,
+            test: Some((op, value)),
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let token = OPS.iter().find(|(_, op)| op == self).unwrap().0;
+        f.write_str(token)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Regex(regex) => write!(f, "regex:{}", regex),
+            Condition::Json { path, test } => {
+                write!(f, "json:$")?;
+                for key in path {
+                    write!(f, ".{}", key)?;
+                }
+                match test {
+                    Some((op, value)) => write!(f, " {} {}", op, value),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Condition {
+    /// Whether a payload satisfies the condition, never for payloads that
+    /// are not UTF-8, or not JSON for JSON conditions
+    pub fn matches(&self, payload: &[u8]) -> bool {
+        let Ok(text) = std::str::from_utf8(payload) else {
+            return false;
+        };
+        match self {
+            Condition::Regex(regex) => regex.is_match(text),
+            Condition::Json { path, test } => {
+                let Ok(doc) = json::parse(text) else {
+                    return false;
+                };
+                let Some(found) = doc.at(path) else {
+                    return false;
+                };
+                let Some((op, expected)) = test else {
+                    return !matches!(found, Value::Null | Value::Bool(false));
+                };
+                let ordering = match (found, expected) {
+                    (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+                    (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+                    _ => None,
+                };
+                match op {
+                    Op::Eq => found == expected,
+                    Op::Ne => found != expected,
+                    Op::Lt => ordering == Some(Ordering::Less),
+                    Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                    Op::Gt => ordering == Some(Ordering::Greater),
+                    Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod condition_tests {
+    use super::*;
+
+    fn matches(condition: &str, payload: &str) -> bool {
+        condition
+            .parse::<Condition>()
+            .unwrap()
+            .matches(payload.as_bytes())
+    }
+
+    #[test]
+    fn test_parse() -> io::Result<()> {
+        let condition: Condition = "json:$.a.0 >= 3".parse()?;
+        assert_eq!(
+            condition,
+            Condition::Json {
+                path: vec!["a".into(), "0".into()],
+                test: Some((Op::Ge, Value::Number(3.0)))
+            }
+        );
+        assert_eq!(condition.to_string(), "json:$.a.0 >= 3");
+        assert_eq!(
+            "json:$.status==done".parse::<Condition>()?.to_string(),
+            "json:$.status == \"done\""
+        );
+        assert_eq!("^ok".parse::<Condition>()?.to_string(), "regex:^ok");
+        for invalid in [
+            "json:a",
+            "json:$.",
+            "json:$.a ~ 1",
+            "json:$.a ==",
+            "regex:(",
+        ] {
+            assert!(invalid.parse::<Condition>().is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("regex:sta(rt|ge)d", "staged"));
+        assert!(matches("done$", "all done"));
+        assert!(!matches("done$", "done?"));
+
+        let reading = r#"{"status": "done", "temp": 31.5, "tags": ["a", "b"], "off": false}"#;
+        assert!(matches("json:$.status", reading));
+        assert!(!matches("json:$.off", reading));
+        assert!(!matches("json:$.missing", reading));
+        assert!(matches("json:$.status == done", reading));
+        assert!(matches("json:$.status != \"failed\"", reading));
+        assert!(matches("json:$.temp > 30", reading));
+        assert!(!matches("json:$.temp <= 30", reading));
+        assert!(matches("json:$.tags.1 == b", reading));
+        assert!(!matches("json:$.tags.2", reading));
+        assert!(!matches("json:$.status > 3", reading));
+        assert!(!matches("json:$.status", "not json"));
+        assert!(matches("json:$ == 42", "42"));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::json::{self, Value};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+
+/// How messages of both topics are paired up, parsed from `--pair-by`:
+///
+/// - `arrival`: the first message of one topic with the first of the other,
+///   and so on
+/// - `json:$.id`: messages with the same value at the path, nested keys are
+///   separated by dots as in `json:$.meta.id`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pairing {
+    Arrival,
+    Key(Vec<String>),
+}
+
+impl std::str::FromStr for Pairing {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid pairing {}, expected arrival or json:$.path", s),
+            )
+        };
+        if s == "arrival" {
+            return Ok(Pairing::Arrival);
+        }
+        let path = s
+            .strip_prefix("json:$.")
+            .filter(|p| !p.is_empty())
+            .ok_or_else(invalid)?;
+        let keys: Vec<String> = path.split('.').map(|k| k.to_string()).collect();
+        if keys.iter().any(|k| k.is_empty()) {
+            return Err(invalid());
+        }
+        Ok(Pairing::Key(keys))
+    }
+}
+
+/// A difference between two JSON documents, at a path like `$.readings.0`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Only on the right
+    Added { path: String, value: Value },
+    /// Only on the left
+    Removed { path: String, value: Value },
+    Changed {
+        path: String,
+        left: Value,
+        right: Value,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { path, value } => write!(f, "+ {}: {}", path, value),
+            Change::Removed { path, value } => write!(f, "- {}: {}", path, value),
+            Change::Changed { path, left, right } => {
+                write!(f, "~ {}: {} -> {}", path, left, right)
+            }
+        }
+    }
+}
+
+/// Differences from `left` to `right`, objects are compared key by key
+/// whatever their order and arrays element by element
+pub fn diff(left: &Value, right: &Value) -> Vec<Change> {
+    let mut changes = vec![];
+    walk("$", left, right, &mut changes);
+    changes
+}
+
+fn walk(path: &str, left: &Value, right: &Value, changes: &mut Vec<Change>) {
+    match (left, right) {
+        (Value::Object(fields), Value::Object(other)) => {
+            for (key, value) in fields {
+                let path = format!("{}.{}", path, key);
+                match right.get(key) {
+                    Some(other) => walk(&path, value, other, changes),
+                    None => changes.push(Change::Removed {
+                        path,
+                        value: value.clone(),
+                    }),
+                }
+            }
+            for (key, value) in other.iter().filter(|(key, _)| left.get(key).is_none()) {
+                changes.push(Change::Added {
+                    path: format!("{}.{}", path, key),
+                    value: value.clone(),
+                });
+            }
+        }
+        (Value::Array(values), Value::Array(other)) => {
+            for i in 0..values.len().max(other.len()) {
+                let path = format!("{}.{}", path, i);
+                match (values.get(i), other.get(i)) {
+                    (Some(value), Some(other)) => walk(&path, value, other, changes),
+                    (Some(value), None) => changes.push(Change::Removed {
+                        path,
+                        value: value.clone(),
+                    }),
+                    (None, Some(value)) => changes.push(Change::Added {
+                        path,
+                        value: value.clone(),
+                    }),
+                    (None, None) => unreachable!(),
+                }
+            }
+        }
+        (left, right) if left != right => changes.push(Change::Changed {
+            path: path.to_string(),
+            left: left.clone(),
+            right: right.clone(),
+        }),
+        _ => {}
+    }
+}
+
+fn parse(payload: &[u8]) -> Option<Value> {
+    json::parse(std::str::from_utf8(payload).ok()?).ok()
+}
+
+/// Differences between two payloads, compared as strings unless both are
+/// JSON
+pub fn diff_payloads(left: &[u8], right: &[u8]) -> Vec<Change> {
+    match (parse(left), parse(right)) {
+        (Some(left), Some(right)) => diff(&left, &right),
+        _ if left == right => vec![],
+        _ => vec![Change::Changed {
+            path: "$".to_string(),
+            left: Value::String(String::from_utf8_lossy(left).into_owned()),
+            right: Value::String(String::from_utf8_lossy(right).into_owned()),
+        }],
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Two messages paired up, with the value of the key they share when
+/// paired by key
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pair {
+    pub key: Option<String>,
+    pub left: Vec<u8>,
+    pub right: Vec<u8>,
+}
+
+/// Holds the messages of each side until one of the other side pairs up
+/// with them
+#[derive(Debug)]
+pub struct Pairer {
+    pairing: Pairing,
+    /// Pending messages of the left and right side by key, a single `None`
+    /// key when pairing by arrival
+    pending: HashMap<Option<String>, [VecDeque<Vec<u8>>; 2]>,
+    unkeyed: u64,
+}
+
+impl Pairer {
+    pub fn new(pairing: Pairing) -> Self {
+        Self {
+            pairing,
+            pending: HashMap::new(),
+            unkeyed: 0,
+        }
+    }
+
+    /// Pair a message with the oldest pending one of the other side, or
+    /// keep it pending. Messages without the key are counted and dropped
+    pub fn push(&mut self, side: Side, payload: Vec<u8>) -> Option<Pair> {
+        let key = match &self.pairing {
+            Pairing::Arrival => None,
+            Pairing::Key(path) => match parse(&payload).as_ref().and_then(|doc| doc.at(path)) {
+                Some(Value::String(s)) => Some(s.clone()),
+                Some(value) => Some(value.to_string()),
+                None => {
+                    self.unkeyed += 1;
+                    return None;
+                }
+            },
+        };
+        let queues = self.pending.entry(key.clone()).or_default();
+        let (mine, other) = match side {
+            Side::Left => (0, 1),
+            Side::Right => (1, 0),
+        };
+        let Some(paired) = queues[other].pop_front() else {
+            queues[mine].push_back(payload);
+            return None;
+        };
+        if queues.iter().all(|queue| queue.is_empty()) {
+            self.pending.remove(&key);
+        }
+        let (left, right) = match side {
+            Side::Left => (payload, paired),
+            Side::Right => (paired, payload),
+        };
+        Some(Pair { key, left, right })
+    }
+
+    /// Messages of the left and right side still waiting for a pair
+    pub fn unpaired(&self) -> (usize, usize) {
+        self.pending.values().fold((0, 0), |(left, right), queues| {
+            (left + queues[0].len(), right + queues[1].len())
+        })
+    }
+
+    /// Messages dropped for not having the key to pair by
+    pub fn unkeyed(&self) -> u64 {
+        self.unkeyed
+    }
+}
+
+#[cfg(test)]
+mod diff_tests {
+    use super::*;
+
+    #[test]
+    fn test_pairing() {
+        assert_eq!("arrival".parse::<Pairing>().unwrap(), Pairing::Arrival);
+        assert_eq!(
+            "json:$.meta.id".parse::<Pairing>().unwrap(),
+            Pairing::Key(vec!["meta".to_string(), "id".to_string()])
+        );
+        for invalid in ["", "json:$.", "json:$.a..b", "id"] {
+            assert!(invalid.parse::<Pairing>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let changes = diff_payloads(
+            br#"{"id": 1, "temp": 21.5, "tags": ["a", "b"], "meta": {"v": 1}}"#,
+            br#"{"meta": {"v": 2}, "id": 1, "temp": 21.5, "tags": ["a"], "unit": "C"}"#,
+        );
+        let lines: Vec<String> = changes.iter().map(|change| change.to_string()).collect();
+        assert_eq!(
+            lines,
+            ["- $.tags.1: \"b\"", "~ $.meta.v: 1 -> 2", "+ $.unit: \"C\""]
+        );
+        assert!(diff_payloads(b"{\"a\": [1, 2]}", b"{ \"a\":[1,2.0] }").is_empty());
+        assert!(diff_payloads(b"on", b"on").is_empty());
+        assert_eq!(
+            diff_payloads(b"on", b"{}")[0].to_string(),
+            "~ $: \"on\" -> \"{}\""
+        );
+    }
+
+    #[test]
+    fn test_pairer() {
+        let mut pairer = Pairer::new(Pairing::Arrival);
+        assert_eq!(pairer.push(Side::Left, b"1".to_vec()), None);
+        assert_eq!(pairer.push(Side::Left, b"2".to_vec()), None);
+        let pair = pairer.push(Side::Right, b"a".to_vec()).unwrap();
+        assert_eq!((pair.left, pair.right), (b"1".to_vec(), b"a".to_vec()));
+        assert_eq!(pairer.unpaired(), (1, 0));
+
+        let mut pairer = Pairer::new("json:$.id".parse().unwrap());
+        assert_eq!(
+            pairer.push(Side::Left, br#"{"id": "x", "v": 1}"#.to_vec()),
+            None
+        );
+        assert_eq!(pairer.push(Side::Right, br#"{"id": 7}"#.to_vec()), None);
+        assert_eq!(pairer.push(Side::Right, b"no key".to_vec()), None);
+        let pair = pairer
+            .push(Side::Right, br#"{"id": "x", "v": 2}"#.to_vec())
+            .unwrap();
+        assert_eq!(pair.key.as_deref(), Some("x"));
+        assert_eq!(pair.left, br#"{"id": "x", "v": 1}"#);
+        let pair = pairer.push(Side::Left, br#"{"id": 7}"#.to_vec()).unwrap();
+        assert_eq!(pair.key.as_deref(), Some("7"));
+        assert_eq!((pairer.unpaired(), pairer.unkeyed()), ((0, 0), 1));
+    }
+}
@@ -0,0 +1,555 @@
+use crate::bench::Rng;
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// RD bit of a standard query, unicast DNS only
+const RECURSION_DESIRED: u16 = 0x0100;
+/// QU bit, asks mDNS responders to answer directly to the querying socket
+const UNICAST_RESPONSE: u16 = 0x8000;
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const MAX_UDP_RESPONSE: usize = 4096;
+const MAX_POINTER_JUMPS: usize = 16;
+
+/// A single SRV record target, e.g. the result of querying
+/// `_mqtt._tcp.example.com`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+fn malformed(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("DNS: {}", reason))
+}
+
+/// Reads the first `nameserver` entry from the system resolver configuration
+pub fn system_nameserver() -> io::Result<SocketAddr> {
+    let conf = fs::read_to_string(RESOLV_CONF)?;
+    conf.lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|ns| ns.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no nameserver found in {}", RESOLV_CONF),
+            )
+        })
+}
+
+/// Resolves the SRV records of `name` using the system nameserver, targets are
+/// returned in connection order, see `order_targets`
+pub fn resolve_srv(name: &str, timeout: Duration) -> io::Result<Vec<SrvTarget>> {
+    resolve_srv_with(name, system_nameserver()?, timeout)
+}
+
+/// Resolves the SRV records of `name` querying `nameserver` over UDP
+pub fn resolve_srv_with(
+    name: &str,
+    nameserver: SocketAddr,
+    timeout: Duration,
+) -> io::Result<Vec<SrvTarget>> {
+    let id = Rng::from_time().next_u64() as u16;
+    let query = build_query(id, RECURSION_DESIRED, name, TYPE_SRV, CLASS_IN)?;
+    let bind_addr: SocketAddr = if nameserver.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(nameserver)?;
+    socket.send(&query)?;
+    let mut response = vec![0u8; MAX_UDP_RESPONSE];
+    let len = socket.recv(&mut response)?;
+    response.truncate(len);
+    let mut targets = parse_response(id, &response)?;
+    order_targets(&mut targets);
+    Ok(targets)
+}
+
+/// Orders targets by ascending priority and, within the same priority, at
+/// random as RFC 2782 says: each target comes next with a probability
+/// proportional to its weight, those of weight 0 are rarely tried first
+pub fn order_targets(targets: &mut [SrvTarget]) {
+    order_targets_with(targets, &mut Rng::from_time());
+}
+
+/// Like `order_targets`, drawing the weighted selections from `rng`
+pub fn order_targets_with(targets: &mut [SrvTarget], rng: &mut Rng) {
+    targets.sort_by_key(|target| target.priority);
+    for group in targets.chunk_by_mut(|a, b| a.priority == b.priority) {
+        // Weight 0 first, only picked when the draw is 0
+        group.sort_by_key(|target| target.weight != 0);
+        for next in 0..group.len() {
+            let left = &mut group[next..];
+            let total: u64 = left.iter().map(|target| target.weight as u64).sum();
+            let draw = rng.next_u64() % (total + 1);
+            let mut sum = 0;
+            let picked = left
+                .iter()
+                .position(|target| {
+                    sum += target.weight as u64;
+                    sum >= draw
+                })
+                .unwrap_or(0);
+            // The others keep their order, weight 0 still first
+            left[..=picked].rotate_right(1);
+        }
+    }
+}
+
+/// A standard query with the header `flags`, mDNS ones have none and an id
+/// of 0
+fn build_query(id: u16, flags: u16, name: &str, qtype: u16, qclass: u16) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    buf.write_u16::<NetworkEndian>(id)?;
+    buf.write_u16::<NetworkEndian>(flags)?;
+    // One question, no answer, authority or additional records
+    buf.write_u16::<NetworkEndian>(1)?;
+    buf.write_u16::<NetworkEndian>(0)?;
+    buf.write_u16::<NetworkEndian>(0)?;
+    buf.write_u16::<NetworkEndian>(0)?;
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid DNS name {}", name),
+            ));
+        }
+        buf.write_u8(label.len() as u8)?;
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.write_u8(0)?;
+    buf.write_u16::<NetworkEndian>(qtype)?;
+    buf.write_u16::<NetworkEndian>(qclass)?;
+    Ok(buf)
+}
+
+/// Reads a possibly compressed domain name starting at `offset`, returns the
+/// name and the offset right after it in the original message
+fn read_name(msg: &[u8], mut offset: usize) -> io::Result<(String, usize)> {
+    let mut labels: Vec<String> = vec![];
+    let mut next = None;
+    let mut jumps = 0;
+    loop {
+        let len = *msg.get(offset).ok_or_else(|| malformed("truncated name"))? as usize;
+        if len & 0xC0 == 0xC0 {
+            let lsb = *msg
+                .get(offset + 1)
+                .ok_or_else(|| malformed("truncated pointer"))?;
+            next.get_or_insert(offset + 2);
+            jumps += 1;
+            if jumps > MAX_POINTER_JUMPS {
+                return Err(malformed("too many compression pointers"));
+            }
+            offset = ((len & 0x3F) << 8) | lsb as usize;
+        } else if len == 0 {
+            let name = labels.join(".");
+            return Ok((name, next.unwrap_or(offset + 1)));
+        } else {
+            let label = msg
+                .get(offset + 1..offset + 1 + len)
+                .ok_or_else(|| malformed("truncated label"))?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + len;
+        }
+    }
+}
+
+/// Resource record data, only the types needed for broker discovery are decoded
+#[derive(Debug, Clone, PartialEq)]
+enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv(SrvTarget),
+    Txt(Vec<String>),
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+/// Parses a DNS message header, returning the ID, the response code and all
+/// the records found in the answer, authority and additional sections
+fn parse_records(msg: &[u8]) -> io::Result<(u16, u16, Vec<Record>)> {
+    let mut header = msg.get(..12).ok_or_else(|| malformed("truncated header"))?;
+    let id = header.read_u16::<NetworkEndian>()?;
+    let rcode = header.read_u16::<NetworkEndian>()? & 0x000F;
+    let questions = header.read_u16::<NetworkEndian>()?;
+    let records = header.read_u16::<NetworkEndian>()? as usize
+        + header.read_u16::<NetworkEndian>()? as usize
+        + header.read_u16::<NetworkEndian>()? as usize;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        let (_, next) = read_name(msg, offset)?;
+        offset = next + 4;
+    }
+
+    let mut parsed = vec![];
+    for _ in 0..records {
+        let (name, next) = read_name(msg, offset)?;
+        let mut rr = msg
+            .get(next..next + 10)
+            .ok_or_else(|| malformed("truncated record"))?;
+        let rtype = rr.read_u16::<NetworkEndian>()?;
+        let _class = rr.read_u16::<NetworkEndian>()?;
+        let _ttl = rr.read_u32::<NetworkEndian>()?;
+        let rdlength = rr.read_u16::<NetworkEndian>()? as usize;
+        let rdata_offset = next + 10;
+        let mut rdata = msg
+            .get(rdata_offset..rdata_offset + rdlength)
+            .ok_or_else(|| malformed("truncated record data"))?;
+        let data = match rtype {
+            TYPE_A if rdlength == 4 => {
+                RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+            }
+            TYPE_AAAA if rdlength == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                RecordData::Aaaa(Ipv6Addr::from(octets))
+            }
+            TYPE_PTR => RecordData::Ptr(read_name(msg, rdata_offset)?.0),
+            TYPE_SRV => {
+                let priority = rdata.read_u16::<NetworkEndian>()?;
+                let weight = rdata.read_u16::<NetworkEndian>()?;
+                let port = rdata.read_u16::<NetworkEndian>()?;
+                let (target, _) = read_name(msg, rdata_offset + 6)?;
+                RecordData::Srv(SrvTarget {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                })
+            }
+            TYPE_TXT => {
+                let mut entries = vec![];
+                while let Some((&len, rest)) = rdata.split_first() {
+                    let entry = rest
+                        .get(..len as usize)
+                        .ok_or_else(|| malformed("truncated TXT entry"))?;
+                    if !entry.is_empty() {
+                        entries.push(String::from_utf8_lossy(entry).into_owned());
+                    }
+                    rdata = &rest[len as usize..];
+                }
+                RecordData::Txt(entries)
+            }
+            _ => RecordData::Other,
+        };
+        parsed.push(Record { name, data });
+        offset = rdata_offset + rdlength;
+    }
+    Ok((id, rcode, parsed))
+}
+
+fn parse_response(id: u16, msg: &[u8]) -> io::Result<Vec<SrvTarget>> {
+    let (response_id, rcode, records) = parse_records(msg)?;
+    if response_id != id {
+        return Err(malformed("response ID mismatch"));
+    }
+    match rcode {
+        0 => {}
+        3 => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "DNS: no such domain",
+            ))
+        }
+        rcode => return Err(malformed(&format!("server error, rcode {}", rcode))),
+    }
+    // A target of "." says the service is decidedly not available
+    Ok(records
+        .into_iter()
+        .filter_map(|r| match r.data {
+            RecordData::Srv(target) if !target.target.is_empty() => Some(target),
+            _ => None,
+        })
+        .collect())
+}
+
+/// A broker advertised on the local network through DNS-SD
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredBroker {
+    /// Service instance name, e.g. `Mosquitto._mqtt._tcp.local`
+    pub name: String,
+    /// Host the service runs on, e.g. `raspberrypi.local`
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+    pub txt: Vec<String>,
+}
+
+impl DiscoveredBroker {
+    /// Socket addresses to connect to this broker
+    pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+        self.addrs
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.port))
+            .collect()
+    }
+}
+
+/// Browses the local network via mDNS for instances of `service` (e.g.
+/// `_mqtt._tcp.local`), collecting answers until `timeout` elapses
+pub fn browse_mdns(service: &str, timeout: Duration) -> io::Result<Vec<DiscoveredBroker>> {
+    let query = build_query(0, 0, service, TYPE_PTR, CLASS_IN | UNICAST_RESPONSE)?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.send_to(&query, SocketAddr::new(IpAddr::V4(MDNS_GROUP), MDNS_PORT))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut records = vec![];
+    let mut buf = vec![0u8; MAX_UDP_RESPONSE];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv_from(&mut buf) {
+            // Ignore garbage from misbehaving responders, keep listening
+            Ok((len, _)) => {
+                if let Ok((_, _, parsed)) = parse_records(&buf[..len]) {
+                    records.extend(parsed);
+                }
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(collect_brokers(service, &records))
+}
+
+/// Joins PTR, SRV, TXT and address records into a list of brokers
+fn collect_brokers(service: &str, records: &[Record]) -> Vec<DiscoveredBroker> {
+    let mut brokers: Vec<DiscoveredBroker> = vec![];
+    let instances = records.iter().filter_map(|r| match &r.data {
+        RecordData::Ptr(instance) if r.name.eq_ignore_ascii_case(service) => Some(instance),
+        _ => None,
+    });
+    for instance in instances {
+        if brokers.iter().any(|b| &b.name == instance) {
+            continue;
+        }
+        let srv = records.iter().find_map(|r| match &r.data {
+            RecordData::Srv(srv) if &r.name == instance => Some(srv),
+            _ => None,
+        });
+        let Some(srv) = srv else { continue };
+        let txt = records
+            .iter()
+            .find_map(|r| match &r.data {
+                RecordData::Txt(txt) if &r.name == instance => Some(txt.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let mut addrs = vec![];
+        for r in records
+            .iter()
+            .filter(|r| r.name.eq_ignore_ascii_case(&srv.target))
+        {
+            let ip = match r.data {
+                RecordData::A(ip) => IpAddr::V4(ip),
+                RecordData::Aaaa(ip) => IpAddr::V6(ip),
+                _ => continue,
+            };
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+        brokers.push(DiscoveredBroker {
+            name: instance.clone(),
+            host: srv.target.clone(),
+            port: srv.port,
+            addrs,
+            txt,
+        });
+    }
+    brokers
+}
+
+#[cfg(test)]
+mod discovery_tests {
+    use super::*;
+
+    fn srv_answer(priority: u16, weight: u16, port: u16, target: &[u8]) -> Vec<u8> {
+        let mut rr = vec![0xC0, 12];
+        rr.write_u16::<NetworkEndian>(TYPE_SRV).unwrap();
+        rr.write_u16::<NetworkEndian>(CLASS_IN).unwrap();
+        rr.write_u32::<NetworkEndian>(300).unwrap();
+        rr.write_u16::<NetworkEndian>(6 + target.len() as u16)
+            .unwrap();
+        rr.write_u16::<NetworkEndian>(priority).unwrap();
+        rr.write_u16::<NetworkEndian>(weight).unwrap();
+        rr.write_u16::<NetworkEndian>(port).unwrap();
+        rr.extend_from_slice(target);
+        rr
+    }
+
+    #[test]
+    fn test_build_query() -> io::Result<()> {
+        let query = build_query(
+            0x1234,
+            RECURSION_DESIRED,
+            "_mqtt._tcp.a.io",
+            TYPE_SRV,
+            CLASS_IN,
+        )?;
+        assert_eq!(
+            query,
+            &[
+                0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 5, b'_', b'm', b'q', b't', b't', 4, b'_',
+                b't', b'c', b'p', 1, b'a', 2, b'i', b'o', 0, 0, 33, 0, 1
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_response() -> io::Result<()> {
+        let mut msg = build_query(7, RECURSION_DESIRED, "_mqtt._tcp.a.io", TYPE_SRV, CLASS_IN)?;
+        // Flip it into a response carrying two answers
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[7] = 2;
+        msg.extend(srv_answer(10, 5, 1883, b"\x02b1\xC0\x17"));
+        msg.extend(srv_answer(5, 0, 8883, b"\x02b2\x01a\x02io\x00"));
+        let mut targets = parse_response(7, &msg)?;
+        order_targets(&mut targets);
+        assert_eq!(
+            targets,
+            vec![
+                SrvTarget {
+                    priority: 5,
+                    weight: 0,
+                    port: 8883,
+                    target: "b2.a.io".into()
+                },
+                SrvTarget {
+                    priority: 10,
+                    weight: 5,
+                    port: 1883,
+                    target: "b1.a.io".into()
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_response_unavailable() -> io::Result<()> {
+        let mut msg = build_query(7, RECURSION_DESIRED, "_mqtt._tcp.a.io", TYPE_SRV, CLASS_IN)?;
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[7] = 1;
+        msg.extend(srv_answer(0, 0, 0, b"\x00"));
+        assert_eq!(parse_response(7, &msg)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_response_id_mismatch() -> io::Result<()> {
+        let msg = build_query(7, RECURSION_DESIRED, "_mqtt._tcp.a.io", TYPE_SRV, CLASS_IN)?;
+        assert!(parse_response(8, &msg).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_order_targets_weight() {
+        let target = |priority, weight| SrvTarget {
+            priority,
+            weight,
+            port: 1883,
+            target: format!("{}-{}", priority, weight),
+        };
+        let mut rng = Rng::new(7);
+        let mut first = [0; 3];
+        for _ in 0..1000 {
+            let mut targets = vec![target(1, 10), target(0, 1), target(1, 60), target(1, 0)];
+            order_targets_with(&mut targets, &mut rng);
+            assert_eq!(targets[0], target(0, 1));
+            assert!(targets[1..].iter().all(|target| target.priority == 1));
+            let weight = targets[1].weight;
+            first[[60, 10, 0].iter().position(|w| *w == weight).unwrap()] += 1;
+        }
+        // Picked first with probability 60/71, 10/71 and 1/71
+        assert!((800..890).contains(&first[0]), "{:?}", first);
+        assert!((100..180).contains(&first[1]), "{:?}", first);
+        assert!((1..40).contains(&first[2]), "{:?}", first);
+
+        // The same seed gives the same order
+        let ordered = |seed| {
+            let mut targets: Vec<_> = (1..=5).map(|weight| target(0, weight)).collect();
+            order_targets_with(&mut targets, &mut Rng::new(seed));
+            targets
+        };
+        assert_eq!(ordered(42), ordered(42));
+    }
+
+    fn record(name: &[u8], rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut rr = name.to_vec();
+        rr.write_u16::<NetworkEndian>(rtype).unwrap();
+        rr.write_u16::<NetworkEndian>(CLASS_IN).unwrap();
+        rr.write_u32::<NetworkEndian>(120).unwrap();
+        rr.write_u16::<NetworkEndian>(rdata.len() as u16).unwrap();
+        rr.extend_from_slice(rdata);
+        rr
+    }
+
+    #[test]
+    fn test_collect_brokers() -> io::Result<()> {
+        let service = "_mqtt._tcp.local";
+        let mut msg = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 3];
+        // Offset 12: "_mqtt._tcp.local", offset 30: "b._mqtt._tcp.local"
+        msg.extend(record(
+            b"\x05_mqtt\x04_tcp\x05local\x00",
+            TYPE_PTR,
+            b"\x01b\xC0\x0C",
+        ));
+        msg.extend(record(
+            b"\xC0\x28",
+            TYPE_SRV,
+            b"\x00\x00\x00\x00\x07\x5B\x04host\xC0\x17",
+        ));
+        msg.extend(record(b"\xC0\x28", TYPE_TXT, b"\x07version\x00\x03a=b"));
+        msg.extend(record(b"\x04host\xC0\x17", TYPE_A, &[192, 168, 1, 10]));
+        let (_, _, records) = parse_records(&msg)?;
+        let brokers = collect_brokers(service, &records);
+        assert_eq!(
+            brokers,
+            vec![DiscoveredBroker {
+                name: "b._mqtt._tcp.local".into(),
+                host: "host.local".into(),
+                port: 1883,
+                addrs: vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10))],
+                txt: vec!["version".into(), "a=b".into()],
+            }]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+use crate::json::{self, Value};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io;
+
+/// Largest magnitude encoded as an integer, beyond it numbers are floats
+const MAX_INTEGER: f64 = 9_223_372_036_854_775_808.0;
+
+/// Binary format JSON messages are converted to with `--encode`, for
+/// consumers expecting compact payloads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Cbor,
+    MsgPack,
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cbor" => Ok(Encoding::Cbor),
+            "msgpack" => Ok(Encoding::MsgPack),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown encoding {}, expected cbor or msgpack", s),
+            )),
+        }
+    }
+}
+
+impl Encoding {
+    /// Integral numbers are encoded as the smallest integer holding them,
+    /// other ones as 64 bits floats
+    pub fn encode(&self, value: &Value) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Encoding::Cbor => cbor(value, &mut out),
+            Encoding::MsgPack => msgpack(value, &mut out),
+        }
+        out
+    }
+
+    /// Encode a payload holding a JSON document
+    pub fn encode_json(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let text = std::str::from_utf8(payload)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Payload is not UTF-8"))?;
+        Ok(self.encode(&json::parse(text)?))
+    }
+
+    /// Decode a whole payload to JSON. Byte strings become hex strings and
+    /// map keys other than strings their JSON text, CBOR tags are dropped
+    /// and MessagePack extension types rejected
+    pub fn decode(&self, payload: &[u8]) -> io::Result<Value> {
+        let mut buf = payload;
+        let value = match self {
+            Encoding::Cbor => cbor_value(&mut buf)?,
+            Encoding::MsgPack => msgpack_value(&mut buf)?,
+        };
+        match buf.is_empty() {
+            true => Ok(value),
+            false => Err(invalid("trailing bytes")),
+        }
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid encoded payload: {}", what),
+    )
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < n {
+        return Err(invalid("unexpected end"));
+    }
+    let (taken, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(taken)
+}
+
+/// Big endian unsigned integer of `n` bytes, at most 8
+fn uint(buf: &mut &[u8], n: usize) -> io::Result<u64> {
+    Ok(take(buf, n)?
+        .iter()
+        .fold(0, |value, byte| (value << 8) | *byte as u64))
+}
+
+fn hex(bytes: &[u8]) -> Value {
+    Value::String(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn text(bytes: &[u8]) -> io::Result<Value> {
+    String::from_utf8(bytes.to_vec())
+        .map(Value::String)
+        .map_err(|_| invalid("string is not UTF-8"))
+}
+
+fn key(value: Value) -> String {
+    match value {
+        Value::String(key) => key,
+        value => value.to_string(),
+    }
+}
+
+/// IEEE 754 half precision float, RFC 8949 appendix D
+fn half(bits: u16) -> f64 {
+    let exponent = (bits >> 10) & 0x1F;
+    let mantissa = (bits & 0x3FF) as f64;
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent as i32 - 25),
+    };
+    match bits & 0x8000 {
+        0 => magnitude,
+        _ => -magnitude,
+    }
+}
+
+/// CBOR break, ending the items of an indefinite length
+const CBOR_BREAK: u8 = 0xFF;
+
+/// Argument of a CBOR head, `None` for an indefinite length
+fn cbor_argument(info: u8, buf: &mut &[u8]) -> io::Result<Option<u64>> {
+    match info {
+        0..=23 => Ok(Some(info as u64)),
+        24..=27 => uint(buf, 1 << (info - 24)).map(Some),
+        31 => Ok(None),
+        _ => Err(invalid("reserved CBOR argument")),
+    }
+}
+
+/// Whether the next item is a break, consuming it
+fn cbor_break(buf: &mut &[u8]) -> io::Result<bool> {
+    match buf.first() {
+        Some(&CBOR_BREAK) => {
+            *buf = &buf[1..];
+            Ok(true)
+        }
+        Some(_) => Ok(false),
+        None => Err(invalid("unexpected end")),
+    }
+}
+
+/// Bytes of a definite or indefinite length string of `major` type
+fn cbor_bytes(major: u8, length: Option<u64>, buf: &mut &[u8]) -> io::Result<Vec<u8>> {
+    match length {
+        Some(n) => Ok(take(buf, n as usize)?.to_vec()),
+        None => {
+            let mut out = vec![];
+            while !cbor_break(buf)? {
+                let head = take(buf, 1)?[0];
+                if head >> 5 != major {
+                    return Err(invalid("chunk of another type"));
+                }
+                let n = cbor_argument(head & 0x1F, buf)?.ok_or_else(|| invalid("nested chunks"))?;
+                out.extend_from_slice(take(buf, n as usize)?);
+            }
+            Ok(out)
+        }
+    }
+}
+
+fn cbor_value(buf: &mut &[u8]) -> io::Result<Value> {
+    let head = take(buf, 1)?[0];
+    let (major, info) = (head >> 5, head & 0x1F);
+    if major == 7 {
+        return match info {
+            20 => Ok(Value::Bool(false)),
+            21 => Ok(Value::Bool(true)),
+            22 | 23 => Ok(Value::Null),
+            25 => Ok(Value::Number(half(uint(buf, 2)? as u16))),
+            26 => Ok(Value::Number(f32::from_bits(uint(buf, 4)? as u32) as f64)),
+            27 => Ok(Value::Number(f64::from_bits(uint(buf, 8)?))),
+            _ => Err(invalid("unexpected CBOR simple value")),
+        };
+    }
+    let argument = cbor_argument(info, buf)?;
+    let count = |buf: &mut &[u8], i: u64| match argument {
+        Some(n) => Ok(i < n),
+        None => cbor_break(buf).map(|end| !end),
+    };
+    match (major, argument) {
+        (0, Some(n)) => Ok(Value::Number(n as f64)),
+        (1, Some(n)) => Ok(Value::Number(-1.0 - n as f64)),
+        (2, _) => Ok(hex(&cbor_bytes(major, argument, buf)?)),
+        (3, _) => text(&cbor_bytes(major, argument, buf)?),
+        (4, _) => {
+            let mut values = vec![];
+            while count(buf, values.len() as u64)? {
+                values.push(cbor_value(buf)?);
+            }
+            Ok(Value::Array(values))
+        }
+        (5, _) => {
+            let mut fields = vec![];
+            while count(buf, fields.len() as u64)? {
+                fields.push((key(cbor_value(buf)?), cbor_value(buf)?));
+            }
+            Ok(Value::Object(fields))
+        }
+        (6, Some(_)) => cbor_value(buf),
+        _ => Err(invalid("indefinite length of a CBOR number or tag")),
+    }
+}
+
+fn msgpack_value(buf: &mut &[u8]) -> io::Result<Value> {
+    let marker = take(buf, 1)?[0];
+    let array = |buf: &mut &[u8], n: u64| {
+        (0..n)
+            .map(|_| msgpack_value(buf))
+            .collect::<io::Result<Vec<_>>>()
+            .map(Value::Array)
+    };
+    let map = |buf: &mut &[u8], n: u64| {
+        (0..n)
+            .map(|_| Ok((key(msgpack_value(buf)?), msgpack_value(buf)?)))
+            .collect::<io::Result<Vec<_>>>()
+            .map(Value::Object)
+    };
+    let string = |buf: &mut &[u8], n: u64| text(take(buf, n as usize)?);
+    let signed = |buf: &mut &[u8], n: usize| {
+        let bits = 64 - 8 * n as u32;
+        uint(buf, n).map(|value| Value::Number((((value << bits) as i64) >> bits) as f64))
+    };
+    match marker {
+        0x00..=0x7F => Ok(Value::Number(marker as f64)),
+        0x80..=0x8F => map(buf, (marker & 0x0F) as u64),
+        0x90..=0x9F => array(buf, (marker & 0x0F) as u64),
+        0xA0..=0xBF => string(buf, (marker & 0x1F) as u64),
+        0xC0 => Ok(Value::Null),
+        0xC2 => Ok(Value::Bool(false)),
+        0xC3 => Ok(Value::Bool(true)),
+        0xC4..=0xC6 => {
+            let n = uint(buf, 1 << (marker - 0xC4))?;
+            Ok(hex(take(buf, n as usize)?))
+        }
+        0xCA => Ok(Value::Number(f32::from_bits(uint(buf, 4)? as u32) as f64)),
+        0xCB => Ok(Value::Number(f64::from_bits(uint(buf, 8)?))),
+        0xCC..=0xCF => Ok(Value::Number(uint(buf, 1 << (marker - 0xCC))? as f64)),
+        0xD0..=0xD3 => signed(buf, 1 << (marker - 0xD0)),
+        0xD9..=0xDB => {
+            let n = uint(buf, 1 << (marker - 0xD9))?;
+            string(buf, n)
+        }
+        0xDC | 0xDD => {
+            let n = uint(buf, 2 << (marker - 0xDC))?;
+            array(buf, n)
+        }
+        0xDE | 0xDF => {
+            let n = uint(buf, 2 << (marker - 0xDE))?;
+            map(buf, n)
+        }
+        0xE0..=0xFF => Ok(Value::Number(marker as i8 as f64)),
+        0xC7..=0xC9 | 0xD4..=0xD8 => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "MessagePack extension types are not supported",
+        )),
+        0xC1 => Err(invalid("reserved MessagePack marker")),
+    }
+}
+
+fn integer(n: f64) -> Option<i64> {
+    (n.fract() == 0.0 && n.abs() < MAX_INTEGER).then_some(n as i64)
+}
+
+/// Major type and argument of a CBOR data item, RFC 8949 section 3
+fn cbor_head(major: u8, n: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xFF => out.extend_from_slice(&[major | 24, n as u8]),
+        0x100..=0xFFFF => {
+            out.push(major | 25);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xFFFF_FFFF => {
+            out.push(major | 26);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+fn cbor(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(0xF6),
+        Value::Bool(false) => out.push(0xF4),
+        Value::Bool(true) => out.push(0xF5),
+        Value::Number(n) => match integer(*n) {
+            Some(i) if i >= 0 => cbor_head(0, i as u64, out),
+            Some(i) => cbor_head(1, (-1 - i) as u64, out),
+            None => {
+                out.push(0xFB);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+        },
+        Value::String(s) => {
+            cbor_head(3, s.len() as u64, out);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(values) => {
+            cbor_head(4, values.len() as u64, out);
+            for value in values {
+                cbor(value, out);
+            }
+        }
+        Value::Object(fields) => {
+            cbor_head(5, fields.len() as u64, out);
+            for (key, value) in fields {
+                cbor(&Value::String(key.clone()), out);
+                cbor(value, out);
+            }
+        }
+    }
+}
+
+/// Header of a MessagePack string, array or map of `len` items: the fixed
+/// form below `fixed_max` then the 8 (strings only), 16 and 32 bits ones
+fn msgpack_len(len: usize, fixed: u8, fixed_max: usize, sized: [Option<u8>; 3], out: &mut Vec<u8>) {
+    match (len, sized) {
+        (len, _) if len <= fixed_max => out.push(fixed | len as u8),
+        (0..=0xFF, [Some(marker), _, _]) => out.extend_from_slice(&[marker, len as u8]),
+        (0..=0xFFFF, [_, Some(marker), _]) => {
+            out.push(marker);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        (_, [_, _, Some(marker)]) => {
+            out.push(marker);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        _ => unreachable!("every length has a 32 bits form"),
+    }
+}
+
+fn msgpack(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(0xC0),
+        Value::Bool(false) => out.push(0xC2),
+        Value::Bool(true) => out.push(0xC3),
+        Value::Number(n) => match integer(*n) {
+            Some(i @ -32..=0x7F) => out.push(i as i8 as u8),
+            Some(i @ 0x80..=0xFF) => out.extend_from_slice(&[0xCC, i as u8]),
+            Some(i @ 0x100..=0xFFFF) => {
+                out.push(0xCD);
+                out.extend_from_slice(&(i as u16).to_be_bytes());
+            }
+            Some(i @ 0x1_0000..=0xFFFF_FFFF) => {
+                out.push(0xCE);
+                out.extend_from_slice(&(i as u32).to_be_bytes());
+            }
+            Some(i @ 0x1_0000_0000..) => {
+                out.push(0xCF);
+                out.extend_from_slice(&(i as u64).to_be_bytes());
+            }
+            Some(i @ -0x80..) => out.extend_from_slice(&[0xD0, i as i8 as u8]),
+            Some(i @ -0x8000..) => {
+                out.push(0xD1);
+                out.extend_from_slice(&(i as i16).to_be_bytes());
+            }
+            Some(i @ -0x8000_0000..) => {
+                out.push(0xD2);
+                out.extend_from_slice(&(i as i32).to_be_bytes());
+            }
+            Some(i) => {
+                out.push(0xD3);
+                out.extend_from_slice(&i.to_be_bytes());
+            }
+            None => {
+                out.push(0xCB);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+        },
+        Value::String(s) => {
+            msgpack_len(s.len(), 0xA0, 31, [Some(0xD9), Some(0xDA), Some(0xDB)], out);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(values) => {
+            msgpack_len(values.len(), 0x90, 15, [None, Some(0xDC), Some(0xDD)], out);
+            for value in values {
+                msgpack(value, out);
+            }
+        }
+        Value::Object(fields) => {
+            msgpack_len(fields.len(), 0x80, 15, [None, Some(0xDE), Some(0xDF)], out);
+            for (key, value) in fields {
+                msgpack(&Value::String(key.clone()), out);
+                msgpack(value, out);
+            }
+        }
+    }
+}
+
+/// Standard base64 with padding, as embedded in JSON and HTTP headers
+pub(crate) fn base64_encode(bytes: &[u8]) -> String {
+    STANDARD.encode(bytes)
+}
+
+pub(crate) fn base64_decode(s: &str) -> io::Result<Vec<u8>> {
+    STANDARD
+        .decode(s)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid base64"))
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use super::*;
+
+    const DOC: &[u8] = br#"{"a": 1, "b": [true, null, -2, 1.5, "x"]}"#;
+
+    #[test]
+    fn test_cbor() -> io::Result<()> {
+        let cbor = Encoding::Cbor;
+        assert_eq!(
+            cbor.encode_json(DOC)?,
+            [
+                0xA2, 0x61, b'a', 0x01, 0x61, b'b', 0x85, 0xF5, 0xF6, 0x21, 0xFB, 0x3F, 0xF8, 0, 0,
+                0, 0, 0, 0, 0x61, b'x'
+            ]
+        );
+        // Examples of RFC 8949 appendix A
+        let number = |n| cbor.encode(&Value::Number(n));
+        assert_eq!(number(1000.0), [0x19, 0x03, 0xE8]);
+        assert_eq!(number(-1000.0), [0x39, 0x03, 0xE7]);
+        assert_eq!(
+            number(1e12),
+            [0x1B, 0x00, 0x00, 0x00, 0xE8, 0xD4, 0xA5, 0x10, 0x00]
+        );
+        assert_eq!(cbor.encode(&Value::String("a".repeat(24)))[..2], [0x78, 24]);
+        assert!(cbor.encode_json(b"{").is_err());
+
+        assert_eq!(
+            cbor.decode(&cbor.encode_json(DOC)?)?,
+            json::parse(std::str::from_utf8(DOC).unwrap())?
+        );
+        // Half floats, byte strings, tags, indefinite lengths and integer keys
+        assert_eq!(
+            cbor.decode(&[0x9F, 0xF9, 0x3E, 0x00, 0x42, 0x01, 0xFF, 0xC1, 0x01, 0xFF])?
+                .to_string(),
+            r#"[1.5,"01ff",1]"#
+        );
+        assert_eq!(
+            cbor.decode(&[0xBF, 0x01, 0x7F, 0x61, b'a', 0x61, b'b', 0xFF, 0xFF])?
+                .to_string(),
+            r#"{"1":"ab"}"#
+        );
+        assert!(cbor.decode(&[0x82, 0x01]).is_err());
+        assert!(cbor.decode(&[0x01, 0x01]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_msgpack() -> io::Result<()> {
+        let msgpack = Encoding::MsgPack;
+        assert_eq!(
+            msgpack.encode_json(DOC)?,
+            [
+                0x82, 0xA1, b'a', 0x01, 0xA1, b'b', 0x95, 0xC3, 0xC0, 0xFE, 0xCB, 0x3F, 0xF8, 0, 0,
+                0, 0, 0, 0, 0xA1, b'x'
+            ]
+        );
+        let number = |n| msgpack.encode(&Value::Number(n));
+        assert_eq!(number(200.0), [0xCC, 200]);
+        assert_eq!(number(1000.0), [0xCD, 0x03, 0xE8]);
+        assert_eq!(number(-33.0), [0xD0, 0xDF]);
+        assert_eq!(number(-1000.0), [0xD1, 0xFC, 0x18]);
+        assert_eq!(number(-1e10)[0], 0xD3);
+        assert_eq!(
+            msgpack.encode(&Value::String("a".repeat(32)))[..2],
+            [0xD9, 32]
+        );
+        let array = Value::Array(vec![Value::Null; 16]);
+        assert_eq!(msgpack.encode(&array)[..3], [0xDC, 0, 16]);
+        assert!("bson".parse::<Encoding>().is_err());
+
+        assert_eq!(
+            msgpack.decode(&msgpack.encode_json(DOC)?)?,
+            json::parse(std::str::from_utf8(DOC).unwrap())?
+        );
+        for n in [200.0, 1000.0, -33.0, -1000.0, -1e10, 1e12] {
+            assert_eq!(msgpack.decode(&number(n))?, Value::Number(n));
+        }
+        assert_eq!(msgpack.decode(&msgpack.encode(&array))?, array);
+        assert_eq!(
+            msgpack
+                .decode(&[0x81, 0xC3, 0xC4, 2, 0xAB, 0xCD])?
+                .to_string(),
+            r#"{"true":"abcd"}"#
+        );
+        let ext = msgpack.decode(&[0xD4, 1, 0]).unwrap_err();
+        assert_eq!(ext.kind(), io::ErrorKind::Unsupported);
+        assert!(msgpack.decode(&[0xA3, b'a']).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_base64() -> io::Result<()> {
+        assert_eq!(base64_encode(b"sake"), "c2FrZQ==");
+        assert_eq!(base64_encode(b"mqtt!"), "bXF0dCE=");
+        assert_eq!(base64_decode("c2FrZQ==")?, b"sake");
+        assert!(base64_decode("not base64!").is_err());
+        Ok(())
+    }
+}
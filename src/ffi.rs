@@ -0,0 +1,303 @@
+//! Minimal C API over `ThreadedClient`, see `include/sake.h`. Every function
+//! returning `c_int` returns 0 on success and -1 on failure, with the reason
+//! in `sake_last_error`
+use crate::mqtt::{Protocol, Qos, Response, ThreadedClient};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Called for every message received, on a thread owned by the client.
+/// `topic` and `payload` are only valid for the duration of the call
+pub type SakeMessageCallback = extern "C" fn(
+    topic: *const c_char,
+    payload: *const u8,
+    payload_len: usize,
+    user_data: *mut c_void,
+);
+
+#[derive(Clone, Copy)]
+struct Callback {
+    func: SakeMessageCallback,
+    user_data: *mut c_void,
+}
+
+// The C caller is responsible for `user_data` being usable from the
+// dispatcher thread, as documented in the header
+unsafe impl Send for Callback {}
+
+/// Opaque client handle handed out to C
+pub struct SakeClient {
+    client: ThreadedClient,
+    callback: Arc<Mutex<Option<Callback>>>,
+    dispatcher: Option<JoinHandle<()>>,
+}
+
+thread_local! {
+    /// Why the last failing call of this thread failed
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: impl ToString) {
+    let message = CString::new(message.to_string().replace('\0', " ")).ok();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// Record why a call failed and return its error code
+fn fail(message: impl ToString) -> c_int {
+    set_last_error(message);
+    -1
+}
+
+/// `Qos::from` panics on anything past 2, which must not unwind into C
+fn to_qos(qos: u8) -> Option<Qos> {
+    (qos <= 2).then(|| Qos::from(qos))
+}
+
+/// Why the last failing call on this thread failed, NULL if none did. Valid
+/// until the next failing call on the same thread
+#[no_mangle]
+pub extern "C" fn sake_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// Connect to `host`:`port` performing the MQTT handshake, returns NULL on
+/// failure
+///
+/// # Safety
+///
+/// `host` and `client_id` must be valid NUL terminated strings
+#[no_mangle]
+pub unsafe extern "C" fn sake_connect(
+    host: *const c_char,
+    port: u16,
+    client_id: *const c_char,
+) -> *mut SakeClient {
+    let (Some(host), Some(client_id)) = (to_str(host), to_str(client_id)) else {
+        set_last_error("host and client_id must be valid UTF-8 strings");
+        return std::ptr::null_mut();
+    };
+    let client = match Protocol::builder()
+        .host(host)
+        .port(port)
+        .client_id(client_id)
+        .connect()
+        .and_then(ThreadedClient::spawn)
+    {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("sake_connect: {}", e);
+            set_last_error(e);
+            return std::ptr::null_mut();
+        }
+    };
+    let callback: Arc<Mutex<Option<Callback>>> = Arc::default();
+    let mut client = SakeClient {
+        client,
+        callback: Arc::clone(&callback),
+        dispatcher: None,
+    };
+    // The incoming receiver can't be shared, swap it out for the dispatcher
+    let (_, placeholder) = std::sync::mpsc::channel();
+    let incoming = std::mem::replace(&mut client.client.incoming, placeholder);
+    client.dispatcher = thread::Builder::new()
+        .name("sake-ffi".into())
+        .spawn(move || {
+            for response in incoming {
+                let Response::Publish { topic, payload, .. } = response else {
+                    continue;
+                };
+                let Some(cb) = *callback.lock().unwrap() else {
+                    continue;
+                };
+                if let Ok(topic) = CString::new(topic) {
+                    (cb.func)(
+                        topic.as_ptr(),
+                        payload.as_ptr(),
+                        payload.len(),
+                        cb.user_data,
+                    );
+                }
+            }
+        })
+        .ok();
+    Box::into_raw(Box::new(client))
+}
+
+/// Publish `payload_len` bytes from `payload` on `topic`, waiting for the
+/// QoS flow to complete
+///
+/// # Safety
+///
+/// `client` must come from `sake_connect`, `topic` must be a valid NUL
+/// terminated string and `payload` must point to `payload_len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn sake_publish(
+    client: *mut SakeClient,
+    topic: *const c_char,
+    payload: *const u8,
+    payload_len: usize,
+    qos: u8,
+) -> c_int {
+    let (Some(client), Some(topic)) = (client.as_ref(), to_str(topic)) else {
+        return fail("client and topic must not be NULL, topic must be UTF-8");
+    };
+    if payload.is_null() && payload_len > 0 {
+        return fail("payload is NULL");
+    }
+    let Some(qos) = to_qos(qos) else {
+        return fail(format!("Invalid QoS {}", qos));
+    };
+    let payload = match payload_len {
+        0 => &[],
+        len => std::slice::from_raw_parts(payload, len),
+    };
+    let delivered = client
+        .client
+        .handle
+        .publish(topic, payload, qos)
+        .and_then(|token| token.wait());
+    match delivered {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("sake_publish: {}", e);
+            fail(e)
+        }
+    }
+}
+
+/// Subscribe to `topic`, `callback` replaces any previously registered one
+/// and receives the messages of every subscription
+///
+/// # Safety
+///
+/// `client` must come from `sake_connect` and `topic` must be a valid NUL
+/// terminated string
+#[no_mangle]
+pub unsafe extern "C" fn sake_subscribe(
+    client: *mut SakeClient,
+    topic: *const c_char,
+    qos: u8,
+    callback: SakeMessageCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let (Some(client), Some(topic)) = (client.as_ref(), to_str(topic)) else {
+        return fail("client and topic must not be NULL, topic must be UTF-8");
+    };
+    let Some(qos) = to_qos(qos) else {
+        return fail(format!("Invalid QoS {}", qos));
+    };
+    *client.callback.lock().unwrap() = Some(Callback {
+        func: callback,
+        user_data,
+    });
+    match client.client.handle.subscribe(topic, qos) {
+        Ok(()) => 0,
+        Err(e) => fail(e),
+    }
+}
+
+/// Disconnect and free the client, which can't be used afterwards
+///
+/// # Safety
+///
+/// `client` must come from `sake_connect` and not have been disconnected
+/// already
+#[no_mangle]
+pub unsafe extern "C" fn sake_disconnect(client: *mut SakeClient) -> c_int {
+    if client.is_null() {
+        return fail("client is NULL");
+    }
+    let client = Box::from_raw(client);
+    let result = client.client.join();
+    if let Some(dispatcher) = client.dispatcher {
+        let _ = dispatcher.join();
+    }
+    match result {
+        Ok(()) => 0,
+        Err(e) => fail(e),
+    }
+}
+
+#[cfg(test)]
+mod ffi_tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    extern "C" fn collect(
+        topic: *const c_char,
+        payload: *const u8,
+        payload_len: usize,
+        user_data: *mut c_void,
+    ) {
+        let received = unsafe { &*(user_data as *const Mutex<Vec<(String, Vec<u8>)>>) };
+        let topic = unsafe { CStr::from_ptr(topic) }
+            .to_string_lossy()
+            .into_owned();
+        let payload = unsafe { std::slice::from_raw_parts(payload, payload_len) }.to_vec();
+        received.lock().unwrap().push((topic, payload));
+    }
+
+    #[test]
+    fn test_subscribe_callback() -> std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let broker = thread::spawn(move || -> std::io::Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header)?;
+            stream.read_exact(&mut vec![0; header[1] as usize])?;
+            stream.write_all(&[0x20, 2, 0, 0])?;
+            // SUBSCRIBE, answered with a PUBLISH on the subscribed topic
+            stream.read_exact(&mut header)?;
+            stream.read_exact(&mut vec![0; header[1] as usize])?;
+            stream.write_all(&[0x30, 5, 0, 1, b'a', b'h', b'i'])?;
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest)?;
+            Ok(())
+        });
+
+        let received: Mutex<Vec<(String, Vec<u8>)>> = Mutex::new(vec![]);
+        unsafe {
+            let client = sake_connect(c"127.0.0.1".as_ptr(), port, c"ffi".as_ptr());
+            assert!(!client.is_null());
+            let user_data = &received as *const _ as *mut c_void;
+            // Rejected before reaching the broker
+            assert_eq!(
+                sake_subscribe(client, c"a".as_ptr(), 3, collect, user_data),
+                -1
+            );
+            assert_eq!(CStr::from_ptr(sake_last_error()), c"Invalid QoS 3");
+            assert_eq!(
+                sake_publish(client, c"a".as_ptr(), std::ptr::null(), 0, 3),
+                -1
+            );
+            assert_eq!(
+                sake_subscribe(client, c"a".as_ptr(), 0, collect, user_data),
+                0
+            );
+            while received.lock().unwrap().is_empty() {
+                thread::yield_now();
+            }
+            assert_eq!(sake_disconnect(client), 0);
+        }
+        broker.join().unwrap()?;
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![("a".to_string(), b"hi".to_vec())]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Drops messages whose topic and payload were already let through less
+/// than `window` ago. Suppressed copies don't extend the window, so a device
+/// republishing the same state still shows up once per window. Only a hash
+/// of each message is kept, entries are forgotten once their window is over
+#[derive(Debug)]
+pub struct DuplicateFilter {
+    window: Duration,
+    seen: HashMap<u64, Instant>,
+    last_prune: Option<Instant>,
+    suppressed: u64,
+}
+
+impl DuplicateFilter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+            last_prune: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Whether the message should go through, remembering it if so
+    pub fn admit(&mut self, topic: &str, payload: &[u8], now: Instant) -> bool {
+        self.prune(now);
+        let mut hasher = DefaultHasher::new();
+        (topic, payload).hash(&mut hasher);
+        let key = hasher.finish();
+        match self.seen.get(&key) {
+            Some(&at) if now.saturating_duration_since(at) < self.window => {
+                self.suppressed += 1;
+                false
+            }
+            _ => {
+                self.seen.insert(key, now);
+                true
+            }
+        }
+    }
+
+    /// Messages dropped so far
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    /// At most once per window, forget the messages whose window is over
+    fn prune(&mut self, now: Instant) {
+        if self
+            .last_prune
+            .is_some_and(|last| now.saturating_duration_since(last) < self.window)
+        {
+            return;
+        }
+        let window = self.window;
+        self.seen
+            .retain(|_, at| now.saturating_duration_since(*at) < window);
+        self.last_prune = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_filter() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut filter = DuplicateFilter::new(Duration::from_secs(5));
+        assert!(filter.admit("a", b"on", at(0)));
+        assert!(!filter.admit("a", b"on", at(1)));
+        assert!(filter.admit("a", b"off", at(1)));
+        assert!(filter.admit("b", b"on", at(1)));
+        // Suppressed copies don't extend the window
+        assert!(!filter.admit("a", b"on", at(4)));
+        assert!(filter.admit("a", b"on", at(5)));
+        assert_eq!(filter.suppressed(), 2);
+        filter.admit("c", b"", at(20));
+        assert_eq!(filter.seen.len(), 1);
+    }
+}
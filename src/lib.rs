@@ -1 +1,36 @@
+pub mod avro;
+pub mod azure;
+pub mod bench;
+pub mod bridge;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod broker;
+pub mod compress;
+pub mod condition;
+pub mod csv;
+pub mod diff;
+pub mod discovery;
+pub mod encoding;
+pub mod ffi;
+pub mod filter;
+pub mod json;
 pub mod mqtt;
+pub mod mqttsn;
+pub mod notify;
+pub mod pcap;
+pub mod pretty;
+pub mod proxy;
+pub mod queue;
+pub mod rates;
+pub mod record;
+pub mod regex;
+pub mod registry;
+pub mod schedule;
+pub mod shutdown;
+pub mod sink;
+pub mod snapshot;
+pub mod template;
+pub mod testing;
+pub mod toml;
+pub mod topic;
+pub mod verify;
+pub mod watch;
@@ -1,10 +1,78 @@
-use clap::ArgAction;
 use clap::{arg, Command};
-use sake::mqtt::{Protocol, Request, Response};
-use std::io::Write;
+use clap::{ArgAction, ArgMatches};
+use sake::avro::{self, AvroSchema};
+use sake::azure::{AzurePreset, SasToken};
+use sake::bench::{
+    self, Burst, Export, LatencyOptions, PayloadKind, Payloads, Rng, Scenario, StormOptions,
+};
+use sake::bridge::{self, Bridge, BridgeOptions, BridgeStats, CopyOptions, DEFAULT_DRAIN_TIMEOUT};
+use sake::broker::{
+    Acl, Broker, BrokerOptions, Eviction, PasswordFile, DEFAULT_MAX_PACKET_SIZE,
+    DEFAULT_SYS_INTERVAL,
+};
+use sake::compress::{self, Codec, DEFAULT_COMPRESS_THRESHOLD};
+use sake::condition::Condition;
+use sake::diff::{diff_payloads, Pairer, Pairing, Side};
+use sake::discovery;
+use sake::encoding::Encoding;
+use sake::filter::DuplicateFilter;
+use sake::json;
+use sake::mqtt::{
+    random_client_id, validate_topic_filter, validate_topic_name, AckType, ConnectReturnCode,
+    ConnectionRefused, Dedup, JumpHost, PreSharedKey, Property, Protocol, ProtocolBuilder,
+    PublishOptions, PublishV5, Qos, QuicUrl, RateLimit, Request, Response, StatsSnapshot,
+    TlsConfig, Will, WireDump, DEFAULT_TLS_PORT, MAX_PORTABLE_CLIENT_ID_LEN, MQTT_V4, MQTT_V5,
+    SUBACK_FAILURE,
+};
+use sake::mqttsn::{Gateway, SnClient};
+use sake::notify::{Backend, Notifier};
+use sake::pcap;
+use sake::pretty::Printer;
+use sake::proxy::{Impairment, Proxy, ProxyOptions};
+use sake::queue::{self, QueueOptions};
+use sake::rates::{RateTable, TopicRates};
+use sake::record::{self, RecordOptions, Recorder, ReplayOptions, Rotate};
+use sake::registry::SchemaRegistry;
+use sake::schedule::{self, Cron, Schedule};
+use sake::sink::{self, JsonlSink, TopicDir};
+use sake::snapshot::{self, Snapshot};
+use sake::template::Template;
+use sake::topic::{self, RewriteRule};
+use sake::verify::{SeqEvent, SeqSpec, SeqVerifier};
+use sake::watch::DirWatcher;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const DEFAULT_HOSTNAME: &str = "127.0.0.1";
-const DEFAULT_CLIENT_ID: &str = "sake-cli";
+const DEFAULT_PORT: u16 = 1883;
+/// Followed by a random suffix when no `--client_id` is given
+const CLIENT_ID_PREFIX: &str = "sake-cli";
+const DEFAULT_KEEPALIVE: u16 = 60;
+/// Redirects followed in a row with `--follow-redirects`
+const MAX_REDIRECTS: u8 = 3;
+/// Lines of `publish --stdin-lines` read ahead of their publish
+const STDIN_BACKLOG: usize = 64;
+/// Scheduled publishes printed by `publish --dry-run`
+const DRY_RUN_PUBLISHES: usize = 5;
+const DEFAULT_HEALTHCHECK_TIMEOUT: u64 = 5;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_BAUD: u32 = 115_200;
+const DEFAULT_DISCOVER_TIMEOUT: u64 = 3;
+const DEFAULT_MDNS_SERVICE: &str = "_mqtt._tcp.local";
+const DEFAULT_SN_PORT: u16 = 1884;
+const DEFAULT_SAS_TTL: u64 = 3600;
+/// Seconds before its SAS token expires that a subscriber reconnects
+const SAS_REFRESH_MARGIN: u64 = 300;
+const DEFAULT_SN_LISTEN: &str = "0.0.0.0:1884";
+const DEFAULT_BROKER_LISTEN: &str = "0.0.0.0:1883";
+const MAX_SOURCE_DEPTH: usize = 16;
 
 fn cli() -> Command {
     Command::new("sake")
@@ -12,7 +80,333 @@ fn cli() -> Command {
         .subcommand_required(true)
         .arg_required_else_help(true)
         .allow_external_subcommands(true)
-        .subcommand(Command::new("shell").about("Start an interactive MQTT shell"))
+        .arg(
+            arg!(--format <FORMAT> "Report errors as text or as a JSON object")
+                .value_parser(["text", "json"])
+                .action(ArgAction::Set)
+                .required(false)
+                .global(true),
+        )
+        .subcommand(
+            Command::new("shell")
+                .about("Start an interactive MQTT shell")
+                .arg(
+                    arg!(--script <FILE> "Run the shell commands in FILE instead of prompting")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"stop-on-error" "Abort a script at the first failing command")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("decode")
+                .about("Print an annotated dump of a raw packet given in hex, or of the MQTT packets of a capture")
+                .arg(
+                    arg!([HEX] "Packet bytes, spaces allowed, - reads them from stdin")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .required_unless_present("pcap")
+                        .conflicts_with("pcap"),
+                )
+                .arg(
+                    arg!(--pcap <PATH> "Decode every MQTT packet of a pcap or pcapng capture instead")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT> "TCP port of the MQTT traffic in the capture")
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("HEX"),
+                )
+                .arg(
+                    arg!(--export <PATH> "Write a CSV summary of the capture, a row per packet, instead of dumping them")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("HEX"),
+                )
+                .arg(
+                    arg!(--profile "Print the traffic of each client of the capture, packet counts by type, bytes, publish sizes and keepalive, instead of dumping them")
+                        .action(ArgAction::SetTrue)
+                        .requires("pcap")
+                        .conflicts_with("export"),
+                ),
+        )
+        .subcommand(
+            Command::new("discover")
+                .about("Browse the local network for brokers advertised via mDNS")
+                .arg(
+                    arg!(--service <SERVICE>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--timeout <SECONDS>)
+                        .value_parser(clap::value_parser!(u64))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--shell <INDEX> "Open the shell against the broker at INDEX")
+                        .value_parser(clap::value_parser!(usize))
+                        .action(ArgAction::Set)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("sn")
+                .about("MQTT-SN client and gateway")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("publish")
+                        .about("Publish a message through an MQTT-SN gateway")
+                        .arg(
+                            arg!(--host <HOST>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--port <PORT>)
+                                .value_parser(clap::value_parser!(u16))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--message <MESSAGE>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(true),
+                        )
+                        .arg(
+                            arg!(--topic <TOPIC>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(true),
+                        )
+                        .arg(
+                            arg!(--qos <QOS>)
+                                .value_parser(clap::value_parser!(u8).range(0..=1))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("clean-session"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("gateway")
+                        .about("Run a transparent MQTT-SN to MQTT gateway")
+                        .arg(
+                            arg!(--listen <ADDR> "UDP address to accept MQTT-SN clients on")
+                                .value_parser(clap::value_parser!(SocketAddr))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--host <HOST>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--port <PORT>)
+                                .value_parser(clap::value_parser!(u16))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .conflicts_with("host"),
+                        )
+                        .arg(
+                            arg!(--rewrite <RULE> "Rewrite forwarded topics, as 'local/# -> remote/#', repeatable")
+                                .value_parser(clap::value_parser!(RewriteRule))
+                                .action(ArgAction::Append)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--verbose "Log every packet received from the clients")
+                                .action(ArgAction::SetTrue),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("healthcheck")
+                .about("Check broker health, exits 0 (ok), 1 (degraded) or 2 (fail)")
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--timeout <SECONDS>)
+                        .value_parser(clap::value_parser!(u64))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--probe "Run a loopback publish/subscribe probe after connecting")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Connect with MQTT 5 and print the capabilities the broker advertises")
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--username <USERNAME>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--password <PASSWORD>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("username"),
+                ),
+        )
+        .subcommand(
+            Command::new("will-test")
+                .about("Check that the broker publishes the will of a client dropped without DISCONNECT")
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--topic <TOPIC> "Will topic, sake/will-test/CLIENT_ID by default")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--qos <QOS> "QoS of the will and of the subscription to it")
+                        .value_parser(clap::value_parser!(u8).range(0..=2))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Id of the dropped client, the watcher appends -watch")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--timeout <SECONDS> "Time allowed for the will to arrive")
+                        .value_parser(clap::value_parser!(u64))
+                        .action(ArgAction::Set)
+                        .required(false),
+                ),
+        )
         .subcommand(
             Command::new("publish")
                 .about("Publish a message to a topic")
@@ -22,36 +416,4444 @@ fn cli() -> Command {
                         .action(ArgAction::Set)
                         .required(false),
                 )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--"max-rate" <MSGS> "Publish at most MSGS messages per second, fractions allowed")
+                        .value_parser(parse_rate)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"max-bandwidth" <BYTES> "Publish at most BYTES bytes per second, packet headers included")
+                        .value_parser(parse_rate)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--events "Print connection events, connects, acks, ping timeouts and disconnects, on stderr")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--serial <DEVICE> "Reach the broker through a gateway tunnelling MQTT in SLIP frames over a serial port")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with_all(["host", "port", "discover-srv", "follow-redirects"]),
+                )
+                .arg(
+                    arg!(--baud <RATE> "Baud rate of the --serial port, defaults to 115200")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("serial"),
+                )
+                .arg(
+                    arg!(--ssh <"USER@HOST"> "Reach the broker at --host and --port from an SSH jump host, as user@host[:port]")
+                        .value_parser(clap::value_parser!(JumpHost))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with_all(["serial", "discover-srv", "follow-redirects"]),
+                )
+                .arg(
+                    arg!(--"ssh-key" <PATH> "Private key for --ssh, instead of the SSH agent and the default keys")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("ssh"),
+                )
+                .arg(
+                    arg!(--quic <URL> "Reach the broker over QUIC at quic://host[:port] instead of TCP")
+                        .value_parser(clap::value_parser!(QuicUrl))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with_all(["host", "port", "serial", "ssh", "discover-srv", "follow-redirects"]),
+                )
+                .arg(
+                    arg!(--"quic-ca" <PATH> "PEM certificates the --quic broker must chain to, instead of the web PKI roots")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("quic"),
+                )
+                .arg(
+                    arg!(--tls "Connect over TLS, to port 8883 unless --port is given")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["serial", "ssh", "quic", "discover-srv"]),
+                )
+                .arg(
+                    arg!(--"tls-ca" <PATH> "PEM certificates the --tls broker must chain to, instead of the web PKI roots")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("tls"),
+                )
+                .arg(
+                    arg!(--"tls-sni" <NAME> "Server name sent and verified instead of --host, for brokers behind a shared address")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("tls"),
+                )
+                .arg(
+                    arg!(--"tls-alpn" <PROTOCOL> "Offer PROTOCOL through ALPN, as mqtt, repeatable or comma separated")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Append)
+                        .value_delimiter(',')
+                        .required(false)
+                        .requires("tls"),
+                )
+                .arg(
+                    arg!(--insecure "Accept any certificate from the --tls broker, for tests only")
+                        .action(ArgAction::SetTrue)
+                        .requires("tls")
+                        .conflicts_with("tls-ca"),
+                )
+                .arg(
+                    arg!(--"psk-identity" <IDENTITY> "Authenticate the --tls broker and this client with a pre-shared key instead of certificates")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires_all(["tls", "psk-key"])
+                        .conflicts_with_all(["tls-ca", "insecure"]),
+                )
+                .arg(
+                    arg!(--"psk-key" <HEX> "Pre-shared key of --psk-identity, as hex digits")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("psk-identity"),
+                )
                 .arg(
                     arg!(--message <MESSAGE>)
                         .value_parser(clap::builder::NonEmptyStringValueParser::new())
                         .action(ArgAction::Set)
-                        .required(true),
+                        .required(false)
+                        .required_unless_present_any(["watch-dir", "stdin-lines", "payload-size"]),
+                )
+                .arg(
+                    arg!(--"payload-size" <BYTES> "Publish BYTES generated bytes instead of --message")
+                        .value_parser(clap::value_parser!(usize))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with_all([
+                            "message",
+                            "watch-dir",
+                            "stdin-lines",
+                            "await-response",
+                            "cron",
+                            "at",
+                            "topic-template",
+                            "encode",
+                        ]),
+                )
+                .arg(
+                    arg!(--"payload-kind" <KIND> "Content of the generated payload: random, the default, zeros or incrementing bytes")
+                        .value_parser(clap::value_parser!(PayloadKind))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("payload-size"),
+                )
+                .arg(
+                    arg!(--seed <SEED> "Seed of the random payload, to publish the same one across runs")
+                        .value_parser(clap::value_parser!(u64))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("payload-size"),
                 )
                 .arg(
                     arg!(--topic <TOPIC>)
+                        .value_parser(parse_topic_name)
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .required_unless_present_any(["watch-dir", "topic-template"]),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix, empty for an MQTT 5 broker to assign one")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"mqtt-version" <VERSION> "Protocol version to connect with")
+                        .value_parser(["3.1.1", "5"])
+                        .default_value("3.1.1")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    arg!(--"follow-redirects" "Connect to the server an MQTT 5 broker redirects to")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--username <USERNAME>)
                         .value_parser(clap::builder::NonEmptyStringValueParser::new())
                         .action(ArgAction::Set)
-                        .required(true),
+                        .required(false),
                 )
                 .arg(
-                    arg!(--client_id <CLIENT_ID>)
+                    arg!(--password <PASSWORD>)
                         .value_parser(clap::builder::NonEmptyStringValueParser::new())
                         .action(ArgAction::Set)
+                        .required(false)
+                        .requires("username"),
+                )
+                .arg(
+                    arg!(--"ack-timeout" <MS> "Retransmit if the PUBACK doesn't arrive within MS milliseconds")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .action(ArgAction::Set)
                         .required(false),
-                ),
-        )
+                )
+                .arg(
+                    arg!(--retries <N> "Retransmissions before failing with a timeout")
+                        .value_parser(clap::value_parser!(u32))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("ack-timeout"),
+                )
+                .arg(
+                    arg!(--encode <FORMAT> "Convert JSON messages to cbor or msgpack before publishing them")
+                        .value_parser(clap::value_parser!(Encoding))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--compress <CODEC> "Compress payloads with gzip or zstd, subscribe recognizes and decompresses them")
+                        .value_parser(clap::value_parser!(Codec))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"compress-threshold" <BYTES> "Publish smaller payloads uncompressed")
+                        .value_parser(clap::value_parser!(usize))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("compress"),
+                )
+                .arg(
+                    arg!(--"schema-registry" <URL> "Confluent compatible schema registry of --subject, as http://host:8081")
+                        .value_parser(clap::value_parser!(SchemaRegistry))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("subject"),
+                )
+                .arg(
+                    arg!(--subject <SUBJECT> "Publish JSON messages in Avro with the latest schema of SUBJECT, framed with its id")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("schema-registry")
+                        .conflicts_with("encode"),
+                )
+                .arg(
+                    arg!(--cron <EXPR> "Keep publishing on a UTC cron schedule, as '*/5 * * * *'")
+                        .value_parser(clap::value_parser!(Cron))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--at <TIMESTAMP> "Publish once at a UTC time, as 2026-10-16T08:30:00Z, or Unix time")
+                        .value_parser(|s: &str| schedule::parse_timestamp(s).map_err(|e| e.to_string()))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("cron"),
+                )
+                .arg(
+                    arg!(--count <COUNT> "Exit after COUNT scheduled publishes")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("cron"),
+                )
+                .arg(
+                    arg!(--"dry-run" "Print the next scheduled publishes instead of connecting")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"watch-dir" <DIR> "Keep publishing the files created or modified in DIR")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("topic-template")
+                        .conflicts_with_all(["topic", "message", "cron", "at", "dry-run"]),
+                )
+                .arg(
+                    arg!(--"topic-template" <TEMPLATE> "Topic computed from JSON payloads, as 'devices/{{json:$.device_id}}', also {{name}}, {{stem}} and {{ext}} of --watch-dir files")
+                        .value_parser(clap::value_parser!(Template))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with_all(["topic", "cron", "at", "dry-run", "await-response"]),
+                )
+                .arg(
+                    arg!(--"poll-interval" <DURATION> "How often --watch-dir is listed, files are published once unchanged for as long")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .default_value("1s")
+                        .requires("watch-dir"),
+                )
+                .arg(
+                    arg!(--"stdin-lines" "Publish each line read from stdin until its end")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["message", "cron", "at", "dry-run", "watch-dir"]),
+                )
+                .arg(
+                    arg!(--delimiter <DELIM> "Split stdin on DELIM, a single byte or \\n, \\r, \\t, \\0")
+                        .value_parser(parse_delimiter)
+                        .action(ArgAction::Set)
+                        .default_value("\\n")
+                        .requires("stdin-lines"),
+                )
+                .arg(
+                    arg!(--rate <LINES> "Publish at most LINES lines of stdin per second")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("stdin-lines"),
+                )
+                .arg(
+                    arg!(--"response-topic" <TOPIC> "Ask for replies on TOPIC, connects with MQTT 5")
+                        .value_parser(parse_topic_name)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"content-type" <TYPE> "Tag messages with the MIME type TYPE, connects with MQTT 5")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"correlation-data" <DATA> "Tag the message with DATA for the replier to echo, random with --await-response")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("response-topic"),
+                )
+                .arg(
+                    arg!(--"await-response" "Wait for the reply carrying the correlation data and print it")
+                        .action(ArgAction::SetTrue)
+                        .requires("response-topic")
+                        .conflicts_with_all(["cron", "at", "watch-dir", "stdin-lines", "ack-timeout"]),
+                )
+                .arg(
+                    arg!(--"response-timeout" <DURATION> "Give up on the reply after DURATION")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .default_value("5s")
+                        .requires("await-response"),
+                )
+                .arg(
+                    arg!(--"azure-hub" <HUB> "Azure IoT Hub preset, derives client id and credentials")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires_all(["azure-device", "azure-key"])
+                        .conflicts_with_all(["username", "client_id"]),
+                )
+                .arg(
+                    arg!(--"azure-device" <DEVICE_ID>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("azure-hub"),
+                )
+                .arg(
+                    arg!(--"azure-key" <KEY> "Base64 encoded device symmetric key")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("azure-hub"),
+                ),
+        )
+        .subcommand(
+            Command::new("subscribe")
+                .about("Subscribe to a topic and print the messages received")
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--events "Print connection events, connects, acks, ping timeouts and disconnects, on stderr")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--serial <DEVICE> "Reach the broker through a gateway tunnelling MQTT in SLIP frames over a serial port")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with_all(["host", "port", "discover-srv", "follow-redirects", "recv-buffer"]),
+                )
+                .arg(
+                    arg!(--baud <RATE> "Baud rate of the --serial port, defaults to 115200")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("serial"),
+                )
+                .arg(
+                    arg!(--ssh <"USER@HOST"> "Reach the broker at --host and --port from an SSH jump host, as user@host[:port]")
+                        .value_parser(clap::value_parser!(JumpHost))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with_all(["serial", "discover-srv", "follow-redirects", "recv-buffer"]),
+                )
+                .arg(
+                    arg!(--"ssh-key" <PATH> "Private key for --ssh, instead of the SSH agent and the default keys")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("ssh"),
+                )
+                .arg(
+                    arg!(--quic <URL> "Reach the broker over QUIC at quic://host[:port] instead of TCP")
+                        .value_parser(clap::value_parser!(QuicUrl))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with_all(["host", "port", "serial", "ssh", "discover-srv", "follow-redirects", "recv-buffer"]),
+                )
+                .arg(
+                    arg!(--"quic-ca" <PATH> "PEM certificates the --quic broker must chain to, instead of the web PKI roots")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("quic"),
+                )
+                .arg(
+                    arg!(--tls "Connect over TLS, to port 8883 unless --port is given")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["serial", "ssh", "quic", "discover-srv"]),
+                )
+                .arg(
+                    arg!(--"tls-ca" <PATH> "PEM certificates the --tls broker must chain to, instead of the web PKI roots")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("tls"),
+                )
+                .arg(
+                    arg!(--"tls-sni" <NAME> "Server name sent and verified instead of --host, for brokers behind a shared address")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("tls"),
+                )
+                .arg(
+                    arg!(--"tls-alpn" <PROTOCOL> "Offer PROTOCOL through ALPN, as mqtt, repeatable or comma separated")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Append)
+                        .value_delimiter(',')
+                        .required(false)
+                        .requires("tls"),
+                )
+                .arg(
+                    arg!(--insecure "Accept any certificate from the --tls broker, for tests only")
+                        .action(ArgAction::SetTrue)
+                        .requires("tls")
+                        .conflicts_with("tls-ca"),
+                )
+                .arg(
+                    arg!(--"psk-identity" <IDENTITY> "Authenticate the --tls broker and this client with a pre-shared key instead of certificates")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires_all(["tls", "psk-key"])
+                        .conflicts_with_all(["tls-ca", "insecure"]),
+                )
+                .arg(
+                    arg!(--"psk-key" <HEX> "Pre-shared key of --psk-identity, as hex digits")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("psk-identity"),
+                )
+                .arg(
+                    arg!(--topic <TOPIC>)
+                        .value_parser(parse_topic_filter)
+                        .action(ArgAction::Set)
+                        .required(true),
+                )
+                .arg(
+                    arg!(--qos <QOS>)
+                        .value_parser(clap::value_parser!(u8).range(0..=2))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix, empty for an MQTT 5 broker to assign one")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"mqtt-version" <VERSION> "Protocol version to connect with")
+                        .value_parser(["3.1.1", "5"])
+                        .default_value("3.1.1")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    arg!(--"follow-redirects" "Connect to the server an MQTT 5 broker redirects to")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--count <COUNT> "Exit after receiving COUNT messages")
+                        .value_parser(clap::value_parser!(u64))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--duration <DURATION> "Exit after DURATION, like 500ms, 60s, 5m or 1h")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--until <CONDITION> "Exit once a payload matches a regex or json:$.path [OP VALUE]")
+                        .value_parser(clap::value_parser!(Condition))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"dedup-window" <DURATION> "Skip messages whose topic and payload were already shown within DURATION")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"idle-timeout" <DURATION> "Warn when no message arrives for DURATION, though the broker still answers pings")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--notify "Raise a desktop notification with the topic and payload of messages")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"notify-if" <CONDITION> "Only notify about payloads matching a regex or json:$.path [OP VALUE], implies --notify")
+                        .value_parser(clap::value_parser!(Condition))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"notify-interval" <DURATION> "Raise at most one notification per DURATION, counting the others")
+                        .value_parser(parse_duration)
+                        .default_value("10s")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--strict "Fail on packets breaking the MQTT spec")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"verify-seq" <SPEC> "Check per topic sequence numbers, text or json:$.path")
+                        .value_parser(clap::value_parser!(SeqSpec))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"consume-delay" <MS> "Pause after each message, to read slower than the broker sends")
+                        .value_parser(clap::value_parser!(u64))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"recv-buffer" <BYTES> "Kernel receive buffer of the connection")
+                        .value_parser(clap::value_parser!(usize))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"output-dir" <DIR> "Also write every message to a file under DIR, by topic")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"output-mode" <MODE> "A .bin file per message or a .jsonl file per topic")
+                        .value_parser(["bin", "jsonl"])
+                        .default_value("bin")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--jsonl <PATH> "Append every message to PATH as a JSON object per line")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"jsonl-sync" <LINES> "Lines appended between two fsync, at most a second apart")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("100")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"jsonl-max-size" <BYTES> "Rotate the --jsonl file once it would grow past BYTES")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"jsonl-keep" <COUNT> "Rotated --jsonl files kept, PATH.1 being the newest")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("5")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"schema-registry" <URL> "Decode Avro payloads framed with a schema id to JSON, as http://host:8081")
+                        .value_parser(clap::value_parser!(SchemaRegistry))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"azure-hub" <HUB> "Azure IoT Hub preset, derives client id and credentials, renewed before they expire")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires_all(["azure-device", "azure-key"])
+                        .conflicts_with("client_id"),
+                )
+                .arg(
+                    arg!(--"azure-device" <DEVICE_ID>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("azure-hub"),
+                )
+                .arg(
+                    arg!(--"azure-key" <KEY> "Base64 encoded device symmetric key")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("azure-hub"),
+                ),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("Pair up the messages of two topics and print how their payloads differ")
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--left <TOPIC> "Topic of the reference messages, like those of the old publisher")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(true),
+                )
+                .arg(
+                    arg!(--right <TOPIC> "Topic of the messages compared to them")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(true),
+                )
+                .arg(
+                    arg!(--"pair-by" <PAIRING> "arrival, or json:$.path to pair messages with the same value")
+                        .value_parser(clap::value_parser!(Pairing))
+                        .default_value("arrival")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--qos <QOS>)
+                        .value_parser(clap::value_parser!(u8).range(0..=1))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--count <PAIRS> "Exit after comparing PAIRS pairs")
+                        .value_parser(clap::value_parser!(u64))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--duration <DURATION> "Exit after DURATION, like 500ms, 60s, 5m or 1h")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Print the message rate and size of the busiest topics every interval")
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--filter <FILTER> "Topics to measure")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .default_value("#")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--group <FILTER> "Count the topics matching FILTER as one, as 'sensors/+/temp', repeatable")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--interval <DURATION> "Print the rates over every DURATION")
+                        .value_parser(parse_duration)
+                        .default_value("5s")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--top <COUNT> "Topics shown, the busiest first")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--duration <DURATION> "Exit after DURATION, like 500ms, 60s, 5m or 1h")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("bridge")
+                .about("Forward messages between a local and a remote broker")
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--"remote-host" <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(true),
+                )
+                .arg(
+                    arg!(--"remote-port" <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--out <FILTER> "Forward local messages matching FILTER to the remote broker, repeatable")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Append)
+                        .required_unless_present("in"),
+                )
+                .arg(
+                    arg!(--in <FILTER> "Forward remote messages matching FILTER to the local broker, repeatable")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--rewrite <RULE> "Rewrite outgoing topics, reversed on incoming ones, repeatable")
+                        .value_parser(clap::value_parser!(RewriteRule))
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"topic-template" <TEMPLATE> "Topic of outgoing messages from their JSON payload, as 'devices/{{json:$.device_id}}', {{topic}} being the original")
+                        .value_parser(clap::value_parser!(Template))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("rewrite"),
+                )
+                .arg(
+                    arg!(--qos <QOS>)
+                        .value_parser(clap::value_parser!(u8).range(0..=2))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"max-rate" <MSGS> "Publish at most MSGS messages per second, fractions allowed")
+                        .value_parser(parse_rate)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"max-bandwidth" <BYTES> "Publish at most BYTES bytes per second, packet headers included")
+                        .value_parser(parse_rate)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--"drain-timeout" <DURATION> "On SIGTERM or SIGINT, wait this long for the deliveries in flight, 10s by default")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--spill <PATH> "Append the messages left undelivered when stopping to PATH, as JSON lines")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("queue"),
+                )
+                .arg(
+                    arg!(--queue <DIR> "Keep the messages not delivered yet in DIR, delivered first on the next run")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"queue-segment-size" <BYTES> "Start a new --queue segment file past BYTES, 16MiB by default")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("queue"),
+                )
+                .arg(
+                    arg!(--"queue-max-age" <DURATION> "Drop the --queue segments whose newest message is older than DURATION")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("queue"),
+                )
+                .arg(
+                    arg!(--"queue-max-size" <BYTES> "Drop the oldest --queue segments while a direction takes more than BYTES")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .requires("queue"),
+                ),
+        )
+        .subcommand(
+            Command::new("queue")
+                .about("Look into the durable queue of a bridge")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("inspect")
+                        .about("Describe the segments of a queue directory and the messages not delivered yet")
+                        .arg(
+                            arg!(<DIR> "A direction of a bridge --queue, as DIR/outgoing")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            arg!(--pending "Print the messages not delivered yet as JSON lines")
+                                .action(ArgAction::SetTrue),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("copy")
+                .about("Copy the messages matching a filter to other topics or another broker, then stop")
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--from <FILTER> "Copy the messages matching FILTER, repeatable")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Append)
+                        .required(true),
+                )
+                .arg(
+                    arg!(--"to-prefix" <PREFIX> "Prepend PREFIX to the topics of the copies")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--rewrite <RULE> "Rewrite the topics of the copies, repeatable")
+                        .value_parser(clap::value_parser!(RewriteRule))
+                        .action(ArgAction::Append)
+                        .required(false)
+                        .conflicts_with("to-prefix"),
+                )
+                .arg(
+                    arg!(--"topic-template" <TEMPLATE> "Topic of the copies from their JSON payload, as 'devices/{{json:$.device_id}}', {{topic}} being the original")
+                        .value_parser(clap::value_parser!(Template))
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with_all(["to-prefix", "rewrite"]),
+                )
+                .arg(
+                    arg!(--"dest-host" <HOST> "Broker to copy to, HOST or HOST:PORT, the source one by default")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"dest-port" <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--duration <DURATION> "Stop after DURATION, like 500ms, 60s, 5m or 1h")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required_unless_present("count"),
+                )
+                .arg(
+                    arg!(--count <COUNT> "Stop after copying COUNT messages")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--qos <QOS>)
+                        .value_parser(clap::value_parser!(u8).range(0..=2))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Load test a broker")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("storm")
+                        .about("Open many connections at once, hold them, then close them in waves")
+                        .arg(
+                            arg!(--host <HOST>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--port <PORT>)
+                                .value_parser(clap::value_parser!(u16))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .conflicts_with("host"),
+                        )
+                        .arg(
+                            arg!(--connections <COUNT> "Connections to open")
+                                .value_parser(clap::value_parser!(usize))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--concurrency <COUNT> "Connections being opened at the same time")
+                                .value_parser(clap::value_parser!(usize))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"client-id-prefix" <PREFIX> "Prefix of the client ids, a random one by default")
+                                .alias("client_id")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"random-ids" "Random client id suffixes instead of sequential ones")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            arg!(--subscribers <COUNT> "Connections subscribing to the topic")
+                                .value_parser(clap::value_parser!(usize))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--publishers <COUNT> "Connections publishing to the topic every second")
+                                .value_parser(clap::value_parser!(usize))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--topic <TOPIC>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--hold <SECONDS> "Time every connection is held open")
+                                .value_parser(clap::value_parser!(u64))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--waves <COUNT> "Batches the connections are closed in")
+                                .value_parser(clap::value_parser!(u64).range(1..))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"wave-interval" <MS> "Pause between two waves")
+                                .value_parser(clap::value_parser!(u64))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"consume-delay" <MS> "Pause of the subscribers after each message")
+                                .value_parser(clap::value_parser!(u64))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"recv-buffer" <BYTES> "Kernel receive buffer of every connection")
+                                .value_parser(clap::value_parser!(usize))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--report <FORMAT> "Report format")
+                                .value_parser(["text", "json", "csv"])
+                                .default_value("text")
+                                .action(ArgAction::Set),
+                        )
+                        .arg(
+                            arg!(--output <PATH> "Also write the report with the run options to PATH, as CSV if it ends in .csv, JSON otherwise")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("latency")
+                        .about("Measure publish to ack and publish to delivery latencies")
+                        .arg(
+                            arg!(--host <HOST>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--port <PORT>)
+                                .value_parser(clap::value_parser!(u16))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .conflicts_with("host"),
+                        )
+                        .arg(
+                            arg!(--quic <URL> "Publish and subscribe over QUIC to the broker at quic://host[:port], to compare with TCP")
+                                .value_parser(clap::value_parser!(QuicUrl))
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .conflicts_with_all(["host", "port", "discover-srv"]),
+                        )
+                        .arg(
+                            arg!(--"quic-ca" <PATH> "PEM certificates the --quic broker must chain to, instead of the web PKI roots")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .requires("quic"),
+                        )
+                        .arg(
+                            arg!(--publishers <COUNT> "Connections publishing to the topic")
+                                .value_parser(clap::value_parser!(usize))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--subscribers <COUNT> "Connections subscribing to the topic")
+                                .value_parser(clap::value_parser!(usize))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--messages <COUNT> "Messages sent by each publisher")
+                                .value_parser(clap::value_parser!(usize))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--size <BYTES> "Payload size, at least 8")
+                                .alias("payload-size")
+                                .value_parser(clap::value_parser!(u64).range(8..))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"payload-kind" <KIND> "What follows the send timestamp: zeros, the default, random or incrementing bytes")
+                                .value_parser(clap::value_parser!(PayloadKind))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--seed <SEED> "Seed of the random payloads, to publish the same ones across runs")
+                                .value_parser(clap::value_parser!(u64))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--rate <MSGS> "Messages per second of each publisher")
+                                .value_parser(clap::value_parser!(u32).range(1..))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--burst <"COUNT@WINDOW"> "Publish in bursts of COUNT messages spread over WINDOW, as 100@10ms")
+                                .value_parser(parse_burst)
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .conflicts_with("rate"),
+                        )
+                        .arg(
+                            arg!(--idle <DURATION> "Silence after each burst, as 1s")
+                                .value_parser(parse_duration)
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .requires("burst"),
+                        )
+                        .arg(
+                            arg!(--qos <QOS>)
+                                .value_parser(clap::value_parser!(u8).range(0..=2))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--topic <TOPIC>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"client-id-prefix" <PREFIX> "Prefix of the client ids, a random one by default")
+                                .alias("client_id")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--report <FORMAT> "Report format")
+                                .value_parser(["text", "json", "csv"])
+                                .default_value("text")
+                                .action(ArgAction::Set),
+                        )
+                        .arg(
+                            arg!(--output <PATH> "Also write the report with the run options to PATH, as CSV if it ends in .csv, JSON otherwise")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("scenario")
+                        .about("Run the phases and client groups of a TOML scenario, failing if its assertions don't hold")
+                        .arg(
+                            arg!(<FILE> "Scenario with [[phase]], [[publishers]], [[subscribers]] and [assert] tables")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            arg!(--host <HOST>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--port <PORT>)
+                                .value_parser(clap::value_parser!(u16))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .conflicts_with("host"),
+                        )
+                        .arg(
+                            arg!(--"client-id-prefix" <PREFIX> "Prefix of the client ids, a random one by default")
+                                .alias("client_id")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--report <FORMAT> "Report format")
+                                .value_parser(["text", "json", "csv"])
+                                .default_value("text")
+                                .action(ArgAction::Set),
+                        )
+                        .arg(
+                            arg!(--output <PATH> "Also write the report with the scenario to PATH, as CSV if it ends in .csv, JSON otherwise")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("compare")
+                        .about("Print the changes between two JSON reports written with --output")
+                        .arg(
+                            arg!(<BASELINE> "Report of the reference run")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            arg!(<CURRENT> "Report of the run to compare with it")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            arg!(--threshold <PERCENT> "Fail if a latency, the throughput or the errors got worse by more than PERCENT")
+                                .value_parser(clap::value_parser!(f64))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("retained")
+                .about("Save the retained messages of a broker or republish them")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("export")
+                        .about("Collect the retained messages matching a filter into a JSON snapshot")
+                        .arg(
+                            arg!(--host <HOST>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--port <PORT>)
+                                .value_parser(clap::value_parser!(u16))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .conflicts_with("host"),
+                        )
+                        .arg(
+                            arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("clean-session"),
+                        )
+                        .arg(
+                            arg!(--filter <FILTER> "Topic filter of the messages to export")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .default_value("#")
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--out <PATH> "Write the snapshot to PATH instead of stdout")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--wait <MS> "Stop once no retained message arrived for MS milliseconds")
+                                .value_parser(clap::value_parser!(u64).range(1..))
+                                .default_value("1000")
+                                .action(ArgAction::Set)
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Republish the messages of a snapshot with the retain flag set")
+                        .arg(
+                            arg!(<SNAPSHOT> "Snapshot written by retained export")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            arg!(--host <HOST>)
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--port <PORT>)
+                                .value_parser(clap::value_parser!(u16))
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false)
+                                .conflicts_with("host"),
+                        )
+                        .arg(
+                            arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                                .action(ArgAction::Set)
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("clean-session"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("record")
+                .about("Record the messages matching filters to a file sake replay can publish again")
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--topic <FILTER> "Topic filter to record, can be repeated")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .default_value("#")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--out <PATH> "Recording to write, numbered as PATH.0001.skr and on with --rotate")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(true),
+                )
+                .arg(
+                    arg!(--compress "Compress the recording with zstd, needs the zstd feature")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--rotate <LIMIT> "Start a new file past a size or a duration, like 100MB or 1h")
+                        .value_parser(clap::value_parser!(Rotate))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--duration <DURATION> "Stop after DURATION, like 500ms, 60s, 5m or 1h")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Publish the messages of recordings again, spaced as they were received")
+                .arg(
+                    arg!(<RECORDING> ... "Files written by sake record, replayed in the order given")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--host <HOST>)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--client_id <CLIENT_ID> "Defaults to sake-cli- followed by a random suffix")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"clean-session" "Start a new session, discarding any the broker kept (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"no-clean-session" "Resume the session the broker kept for the client id")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("clean-session"),
+                )
+                .arg(
+                    arg!(--from <TIMESTAMP> "Start at the first message received at or after a UTC time, or Unix time")
+                        .value_parser(|s: &str| schedule::parse_timestamp(s).map_err(|e| e.to_string()))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--offset <DURATION> "Start DURATION after the first message, like 30s, 5m or 1h")
+                        .value_parser(parse_duration)
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--filter <FILTER> "Only replay the messages matching FILTER, repeatable")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--remap <RULE> "Rewrite replayed topics, as 'old/# -> new/#', repeatable")
+                        .value_parser(clap::value_parser!(RewriteRule))
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--speed <FACTOR> "Replay FACTOR times as fast as recorded")
+                        .value_parser(|s: &str| match s.parse::<f64>() {
+                            Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+                            _ => Err(format!("Invalid speed {}, expected a positive factor", s)),
+                        })
+                        .default_value("1")
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"no-wait" "Publish one message after the other, without the recorded pauses")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("speed"),
+                ),
+        )
+        .subcommand(
+            Command::new("broker")
+                .about("Run a small embedded MQTT 3.1.1 broker")
+                .arg(
+                    arg!(--listen <ADDR> "TCP address to accept clients on")
+                        .value_parser(clap::value_parser!(SocketAddr))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"ws-listen" <ADDR> "Also accept MQTT over WebSocket clients, as 0.0.0.0:9001")
+                        .value_parser(clap::value_parser!(SocketAddr))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"max-retained" <COUNT> "Topics with a retained message kept at most")
+                        .value_parser(clap::value_parser!(usize))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"max-packet-size" <BYTES> "Close connections sending a larger packet, 1MiB by default")
+                        .value_parser(clap::value_parser!(u32))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"retain-eviction" <POLICY> "Forget the oldest retained topic or reject new ones when full")
+                        .value_parser(["oldest", "reject"])
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"passwd-file" <PATH> "Require credentials from user:bcrypt-hash lines")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"acl-file" <PATH> "Restrict topics with 'user pattern read|write|readwrite' lines")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .requires("passwd-file")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--persist <PATH> "Keep retained messages and persistent sessions in a log file across restarts")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"sys-interval" <SECONDS> "Period of the $SYS/broker statistics, 0 disables them")
+                        .value_parser(clap::value_parser!(u64))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--workers <COUNT> "Threads delivering messages, sessions are sharded across them")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--inspect "Describe the sessions and retained messages as JSON to subscribers of $SAKE/sessions and $SAKE/retained")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("proxy")
+                .about("Relay clients to a broker over a degraded link, each option taking one value or TO_BROKER/TO_CLIENT")
+                .arg(
+                    arg!(--listen <ADDR> "TCP address to accept clients on")
+                        .value_parser(clap::value_parser!(SocketAddr))
+                        .action(ArgAction::Set)
+                        .required(true),
+                )
+                .arg(
+                    arg!(--host <HOST> "Broker to relay to")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--port <PORT>)
+                        .value_parser(clap::value_parser!(u16))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"discover-srv" <NAME> "Resolve brokers from the SRV records of NAME")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .conflicts_with("host"),
+                )
+                .arg(
+                    arg!(--latency <DURATION> "One-way delay of every packet, as 50ms or 20ms/80ms")
+                        .value_parser(|s: &str| both_ways(s, parse_duration))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--jitter <DURATION> "Random extra delay up to DURATION, packets keep their order")
+                        .value_parser(|s: &str| both_ways(s, parse_duration))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--bandwidth <BYTES> "Bytes per second, as 10000 or 5000/100000")
+                        .value_parser(|s: &str| both_ways(s, parse_rate))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--reorder <SHARE> "Share of the PUBLISH packets overtaken by the next packet, between 0 and 1")
+                        .value_parser(|s: &str| both_ways(s, parse_share))
+                        .action(ArgAction::Set)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--seed <SEED> "Seed of the jitter and the reordering, to repeat a run")
+                        .value_parser(clap::value_parser!(u64))
+                        .action(ArgAction::Set)
+                        .required(false),
+                ),
+        )
+}
+
+/// Exit codes of a failed command, its discriminant is the process exit code.
+/// 0 means success, the health check keeps its own 0/1/2 contract
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Exit {
+    Failure = 1,
+    Usage = 2,
+    ConnectionRefused = 3,
+    AuthFailure = 4,
+    Timeout = 5,
+    ProtocolError = 6,
+}
+
+impl Exit {
+    fn from_error(err: &io::Error) -> Self {
+        let refused = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ConnectionRefused>());
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => match refused.map(|r| r.return_code) {
+                Some(ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized) => {
+                    Exit::AuthFailure
+                }
+                _ => Exit::ConnectionRefused,
+            },
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::AddrNotAvailable => Exit::ConnectionRefused,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Exit::Timeout,
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Exit::ProtocolError,
+            io::ErrorKind::InvalidInput => Exit::Usage,
+            _ => Exit::Failure,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Exit::Failure => "failure",
+            Exit::Usage => "usage",
+            Exit::ConnectionRefused => "connection_refused",
+            Exit::AuthFailure => "auth_failure",
+            Exit::Timeout => "timeout",
+            Exit::ProtocolError => "protocol_error",
+        }
+    }
+}
+
+/// Print `err` in the requested format and exit with the matching code
+fn fail(err: &io::Error, matches: &ArgMatches) -> ! {
+    let exit = Exit::from_error(err);
+    match matches.get_one::<String>("format").map(|f| f.as_str()) {
+        Some("json") => println!(
+            "{{\"error\":{{\"kind\":{},\"code\":{},\"message\":{}}}}}",
+            json::string(exit.as_str()),
+            exit as i32,
+            json::string(&err.to_string())
+        ),
+        _ => eprintln!("Error: {}", err),
+    }
+    std::process::exit(exit as i32)
+}
+
+/// Outcome of a health check, its discriminant is the process exit code
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Health {
+    Ok = 0,
+    Degraded = 1,
+    Fail = 2,
+}
+
+impl Health {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Health::Ok => "ok",
+            Health::Degraded => "degraded",
+            Health::Fail => "fail",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HealthReport {
+    status: Health,
+    host: String,
+    port: u16,
+    connect_ms: Option<u128>,
+    probe: &'static str,
+    error: Option<String>,
+}
+
+impl HealthReport {
+    fn new(host: &str, port: u16) -> Self {
+        Self {
+            status: Health::Fail,
+            host: host.to_string(),
+            port,
+            connect_ms: None,
+            probe: "skipped",
+            error: None,
+        }
+    }
+
+    fn fail(mut self, status: Health, error: impl ToString) -> Self {
+        self.status = status;
+        self.error = Some(error.to_string());
+        self
+    }
+
+    /// Single line JSON representation, meant to be consumed by probes and scripts
+    fn to_json(&self) -> String {
+        let connect_ms = self
+            .connect_ms
+            .map_or("null".to_string(), |ms| ms.to_string());
+        let error = self
+            .error
+            .as_deref()
+            .map_or("null".to_string(), json::string);
+        format!(
+            "{{\"status\":\"{}\",\"host\":{},\"port\":{},\"connect_ms\":{},\"probe\":\"{}\",\"error\":{}}}",
+            self.status.as_str(),
+            json::string(&self.host),
+            self.port,
+            connect_ms,
+            self.probe,
+            error
+        )
+    }
+}
+
+/// Client id used without `--client_id`, generated once so that every
+/// connection of the process, like the REPL ones, shares it
+fn default_client_id() -> &'static str {
+    static CLIENT_ID: OnceLock<String> = OnceLock::new();
+    CLIENT_ID.get_or_init(|| random_client_id(CLIENT_ID_PREFIX))
+}
+
+/// `--mqtt-version`, MQTT 5 regardless with `--response-topic` or
+/// `--content-type` as they set MQTT 5 properties
+fn protocol_level(matches: &ArgMatches) -> u8 {
+    let properties = ["response-topic", "content-type"]
+        .iter()
+        .any(|id| matches.try_get_one::<String>(id).ok().flatten().is_some());
+    match matches
+        .get_one::<String>("mqtt-version")
+        .map(|v| v.as_str())
+    {
+        Some("5") => MQTT_V5,
+        _ if properties => MQTT_V5,
+        _ => MQTT_V4,
+    }
+}
+
+/// `MAX_REDIRECTS` with `--follow-redirects`, none otherwise
+fn max_redirects(matches: &ArgMatches) -> u8 {
+    match matches.get_flag("follow-redirects") {
+        true => MAX_REDIRECTS,
+        false => 0,
+    }
+}
+
+/// Tell the client id an MQTT 5 broker assigned, the one to pass with
+/// `--client_id` to resume the session later
+fn report_assigned_client_id(client: &Protocol, requested: &str) {
+    if client.client_id() != requested {
+        eprintln!("Assigned client id {}", client.client_id());
+    }
+}
+
+/// `--client_id` if given, warning when brokers aren't all required to
+/// accept it, `default_client_id` otherwise
+fn client_id(matches: &ArgMatches) -> &str {
+    let Some(client_id) = matches.get_one::<String>("client_id") else {
+        return default_client_id();
+    };
+    if client_id.len() > MAX_PORTABLE_CLIENT_ID_LEN {
+        eprintln!(
+            "Client id {} is longer than the {} characters every broker must accept, it may be refused",
+            client_id, MAX_PORTABLE_CLIENT_ID_LEN
+        );
+    }
+    client_id
+}
+
+/// Clean sessions unless `--no-clean-session` asks the broker to keep it
+fn clean_session(matches: &ArgMatches) -> bool {
+    !matches.get_flag("no-clean-session")
+}
+
+/// Builds the ordered list of broker addresses to try, either from the SRV
+/// records of `--discover-srv`, from `--host` and `--port` or, if none of them
+/// is given, from `fallback`
+fn broker_addrs(
+    matches: &ArgMatches,
+    fallback: Option<&[SocketAddr]>,
+    timeout: Duration,
+) -> io::Result<Vec<SocketAddr>> {
+    let explicit = matches.contains_id("discover-srv") || matches.contains_id("host");
+    if let (false, Some(fallback)) = (explicit, fallback) {
+        return Ok(fallback.to_vec());
+    }
+    let addrs: Vec<SocketAddr> = match matches.get_one::<String>("discover-srv") {
+        Some(name) => discovery::resolve_srv(name, timeout)?
+            .iter()
+            .filter_map(|t| (t.target.as_str(), t.port).to_socket_addrs().ok())
+            .flatten()
+            .collect(),
+        None => {
+            let host = matches
+                .get_one::<String>("host")
+                .map_or(DEFAULT_HOSTNAME, |h| h.as_str());
+            let port = *matches.get_one::<u16>("port").unwrap_or(&DEFAULT_PORT);
+            (host, port).to_socket_addrs()?.collect()
+        }
+    };
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no broker address found",
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Resolve the brokers of the address arguments into `builder`, unless
+/// `--serial`, `--ssh` or `--quic` leads to one
+fn broker_route(
+    builder: ProtocolBuilder,
+    matches: &ArgMatches,
+    fallback: Option<&[SocketAddr]>,
+    timeout: Duration,
+) -> io::Result<ProtocolBuilder> {
+    match ["serial", "ssh", "quic"]
+        .iter()
+        .any(|route| matches.contains_id(route))
+        || tls_enabled(matches)
+    {
+        true => Ok(builder),
+        false => Ok(builder.addrs(&broker_addrs(matches, fallback, timeout)?)),
+    }
+}
+
+/// Connect with `builder` through the gateway on the `--serial` port, the
+/// `--ssh` jump host or to the `--quic` broker if given, see `broker_route`
+fn connect(builder: ProtocolBuilder, matches: &ArgMatches) -> io::Result<Protocol> {
+    if let Some(device) = matches.get_one::<String>("serial") {
+        let baud = *matches.get_one::<u32>("baud").unwrap_or(&DEFAULT_BAUD);
+        let link = open_serial(device, baud)?;
+        eprintln!("Connecting through {} at {} baud", device, baud);
+        return builder.connect_transport(link);
+    }
+    if let Some(jump) = matches.get_one::<JumpHost>("ssh") {
+        // Resolved by the jump host, the broker is usually private to it
+        let host = matches
+            .get_one::<String>("host")
+            .map_or(DEFAULT_HOSTNAME, |h| h.as_str());
+        let port = *matches.get_one::<u16>("port").unwrap_or(&DEFAULT_PORT);
+        let key = matches.get_one::<PathBuf>("ssh-key").map(PathBuf::as_path);
+        return connect_ssh(builder, jump, key, host, port);
+    }
+    if let Some(url) = matches.get_one::<QuicUrl>("quic") {
+        let ca = matches.get_one::<PathBuf>("quic-ca").map(PathBuf::as_path);
+        return connect_quic(builder, url, ca);
+    }
+    if tls_enabled(matches) {
+        let host = matches
+            .get_one::<String>("host")
+            .map_or(DEFAULT_HOSTNAME, |h| h.as_str());
+        let port = *matches.get_one::<u16>("port").unwrap_or(&DEFAULT_TLS_PORT);
+        return connect_tls(builder, host, port, &tls_config(matches)?);
+    }
+    builder.connect()
+}
+
+/// Whether `--tls` was given, to commands that have it
+fn tls_enabled(matches: &ArgMatches) -> bool {
+    matches!(matches.try_get_one::<bool>("tls"), Ok(Some(true)))
+}
+
+fn tls_config(matches: &ArgMatches) -> io::Result<TlsConfig> {
+    let insecure = matches.get_flag("insecure");
+    if insecure {
+        eprintln!(
+            "WARNING: --insecure accepts any certificate, anyone on the path can impersonate the broker and read the traffic"
+        );
+    }
+    Ok(TlsConfig {
+        ca: matches.get_one::<PathBuf>("tls-ca").cloned(),
+        server_name: matches.get_one::<String>("tls-sni").cloned(),
+        alpn: matches
+            .get_many::<String>("tls-alpn")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        insecure,
+        psk: match matches.get_one::<String>("psk-identity") {
+            Some(identity) => {
+                let key = matches.get_one::<String>("psk-key").unwrap();
+                Some(PreSharedKey::from_hex(identity, key)?)
+            }
+            None => None,
+        },
+    })
+}
+
+/// Have `builder` print the events of its connections with `--events`
+fn print_events(builder: ProtocolBuilder, matches: &ArgMatches) -> ProtocolBuilder {
+    match matches.get_flag("events") {
+        true => builder.on_event(|event| {
+            eprintln!(
+                "{} [event] {}",
+                schedule::format_timestamp(SystemTime::now()),
+                event
+            )
+        }),
+        false => builder,
+    }
+}
+
+#[cfg(feature = "serial")]
+fn open_serial(device: &str, baud: u32) -> io::Result<sake::mqtt::Slip> {
+    sake::mqtt::open_serial(device, baud)
+}
+
+#[cfg(not(feature = "serial"))]
+fn open_serial(_device: &str, _baud: u32) -> io::Result<sake::mqtt::Slip> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--serial needs sake built with the serial feature",
+    ))
+}
+
+#[cfg(feature = "ssh")]
+fn connect_ssh(
+    builder: ProtocolBuilder,
+    jump: &JumpHost,
+    key: Option<&Path>,
+    host: &str,
+    port: u16,
+) -> io::Result<Protocol> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let tunnel = sake::mqtt::SshTransport::open(jump, key, host, port, timeout)?;
+    eprintln!("Connecting to {}:{} through {}", host, port, jump);
+    builder.connect_transport(tunnel)
+}
+
+#[cfg(not(feature = "ssh"))]
+fn connect_ssh(
+    _builder: ProtocolBuilder,
+    _jump: &JumpHost,
+    _key: Option<&Path>,
+    _host: &str,
+    _port: u16,
+) -> io::Result<Protocol> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--ssh needs sake built with the ssh feature",
+    ))
+}
+
+#[cfg(feature = "quic")]
+fn connect_quic(
+    builder: ProtocolBuilder,
+    url: &QuicUrl,
+    ca: Option<&Path>,
+) -> io::Result<Protocol> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let transport = sake::mqtt::QuicTransport::open(url, ca, timeout)?;
+    eprintln!("Connecting to {}", url);
+    builder.connect_transport(transport)
+}
+
+#[cfg(not(feature = "quic"))]
+fn connect_quic(
+    _builder: ProtocolBuilder,
+    _url: &QuicUrl,
+    _ca: Option<&Path>,
+) -> io::Result<Protocol> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--quic needs sake built with the quic feature",
+    ))
+}
+
+#[cfg(feature = "tls")]
+fn connect_tls(
+    builder: ProtocolBuilder,
+    host: &str,
+    port: u16,
+    config: &TlsConfig,
+) -> io::Result<Protocol> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let transport = sake::mqtt::TlsTransport::open(host, port, config, timeout)?;
+    eprintln!("Connecting to {}:{} over TLS", host, port);
+    builder.connect_transport(transport)
+}
+
+#[cfg(not(feature = "tls"))]
+fn connect_tls(
+    _builder: ProtocolBuilder,
+    _host: &str,
+    _port: u16,
+    _config: &TlsConfig,
+) -> io::Result<Protocol> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--tls needs sake built with the tls feature",
+    ))
+}
+
+/// Subscribe to a private topic, publish to it and wait for the message to
+/// come back, every read is bounded by the read timeout set on the client
+fn loopback_probe(client: &mut Protocol, client_id: &str) -> io::Result<()> {
+    let topic = format!("sake/healthcheck/{}", client_id);
+    client.subscribe(&topic, Qos::AtMostOnce)?;
+    match client.read_message::<Response>()? {
+        Response::Suback { return_codes, .. } if !return_codes.contains(&SUBACK_FAILURE) => {}
+        resp => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("subscription refused: {}", resp),
+            ))
+        }
+    }
+    client.send_message(&Request::Publish {
+        packet_id: 0,
+        qos: 0,
+        dup: false,
+        retain: false,
+        topic: topic.clone(),
+        payload: b"ping".to_vec(),
+    })?;
+    loop {
+        if let Response::Publish { topic: t, .. } = client.read_message::<Response>()? {
+            if t == topic {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn healthcheck(
+    matches: &ArgMatches,
+    client_id: &str,
+    probe: bool,
+    timeout: Duration,
+) -> HealthReport {
+    let host = matches
+        .get_one::<String>("discover-srv")
+        .or_else(|| matches.get_one::<String>("host"))
+        .map_or(DEFAULT_HOSTNAME, |h| h.as_str());
+    let port = *matches.get_one::<u16>("port").unwrap_or(&DEFAULT_PORT);
+    let mut report = HealthReport::new(host, port);
+    let addrs = match broker_addrs(matches, None, timeout) {
+        Ok(addrs) => addrs,
+        Err(e) => return report.fail(Health::Fail, e),
+    };
+    let start = Instant::now();
+    let mut client = match Protocol::builder()
+        .addrs(&addrs)
+        .client_id(client_id)
+        .timeout(timeout)
+        .clean_session(clean_session(matches))
+        .connect()
+        .and_then(|mut client| client.set_read_timeout(Some(timeout)).map(|_| client))
+    {
+        Ok(client) => client,
+        Err(e) => return report.fail(Health::Fail, e),
+    };
+    report.status = Health::Ok;
+    report.connect_ms = Some(start.elapsed().as_millis());
+    if let Ok(peer) = client.peer_addr() {
+        report.host = peer.ip().to_string();
+        report.port = peer.port();
+    }
+    if probe {
+        match loopback_probe(&mut client, client_id) {
+            Ok(()) => report.probe = "ok",
+            Err(e) => {
+                report.probe = "failed";
+                report = report.fail(Health::Degraded, e);
+            }
+        }
+    }
+    // Best effort, the broker state has already been assessed
+    let _ = client.disconnect();
+    report
+}
+
+/// Probe the broker with an MQTT 5 CONNECT and print the capabilities of
+/// its CONNACK, see `ProtocolBuilder::probe`
+fn info(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let addrs = broker_addrs(matches, None, timeout)?;
+    let mut builder = Protocol::builder()
+        .addrs(&addrs)
+        .timeout(timeout)
+        .keepalive(DEFAULT_KEEPALIVE)
+        .client_id(client_id(matches))
+        .clean_session(clean_session(matches));
+    if let Some(username) = matches.get_one::<String>("username") {
+        builder = builder.credentials(
+            username,
+            matches.get_one::<String>("password").map(|p| p.as_str()),
+        );
+    }
+    let capabilities = builder.probe()?;
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+    println!("Broker                    {}", addrs[0]);
+    if capabilities.protocol_level < MQTT_V5 {
+        println!("Protocol                  MQTT 3.1.1, no capabilities advertised");
+        return Ok(());
+    }
+    println!("Protocol                  MQTT 5.0");
+    println!(
+        "Maximum QoS               {}",
+        capabilities.maximum_qos as u8
+    );
+    println!(
+        "Retain available          {}",
+        yes_no(capabilities.retain_available)
+    );
+    match capabilities.maximum_packet_size {
+        Some(size) => println!("Maximum packet size       {} bytes", size),
+        None => println!("Maximum packet size       unlimited"),
+    }
+    println!(
+        "Topic alias maximum       {}",
+        capabilities.topic_alias_maximum
+    );
+    println!("Receive maximum           {}", capabilities.receive_maximum);
+    match capabilities.server_keep_alive {
+        Some(secs) => println!("Server keep alive         {}s", secs),
+        None => println!(
+            "Server keep alive         {}s as requested",
+            DEFAULT_KEEPALIVE
+        ),
+    }
+    println!(
+        "Assigned client id        {}",
+        capabilities.assigned_client_id.as_deref().unwrap_or("-")
+    );
+    if let Some(secs) = capabilities.session_expiry_interval {
+        println!("Session expiry            {}s", secs);
+    }
+    println!(
+        "Wildcard subscriptions    {}",
+        yes_no(capabilities.wildcard_subscriptions)
+    );
+    println!(
+        "Subscription identifiers  {}",
+        yes_no(capabilities.subscription_identifiers)
+    );
+    println!(
+        "Shared subscriptions      {}",
+        yes_no(capabilities.shared_subscriptions)
+    );
+    for (key, value) in &capabilities.user_properties {
+        println!("User property             {}={}", key, value);
+    }
+    Ok(())
+}
+
+/// Connect a client with a will and a second one subscribed to it, then
+/// drop the first without DISCONNECT and wait for its will
+fn will_test(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(
+        *matches
+            .get_one::<u64>("timeout")
+            .unwrap_or(&DEFAULT_HEALTHCHECK_TIMEOUT),
+    );
+    let addrs = broker_addrs(matches, None, timeout)?;
+    let client_id = client_id(matches);
+    let topic = matches
+        .get_one::<String>("topic")
+        .cloned()
+        .unwrap_or_else(|| format!("sake/will-test/{}", client_id));
+    let qos = Qos::from(*matches.get_one::<u8>("qos").unwrap_or(&0));
+    // Tells this run's will apart from a retained or stale one
+    let nonce = format!(
+        "{}-{}",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    );
+
+    let mut watcher = Protocol::builder()
+        .addrs(&addrs)
+        .timeout(timeout)
+        .client_id(&format!("{}-watch", client_id))
+        .clean_session(clean_session(matches))
+        .connect()?;
+    watcher.subscribe(&topic, qos)?;
+    watcher.set_read_timeout(Some(timeout))?;
+    match watcher.read_message::<Response>()? {
+        Response::Suback { return_codes, .. } if !return_codes.contains(&SUBACK_FAILURE) => {}
+        resp => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Subscription refused: {}", resp),
+            ))
+        }
+    }
+    let dropped = Protocol::builder()
+        .addrs(&addrs)
+        .timeout(timeout)
+        .client_id(client_id)
+        .clean_session(clean_session(matches))
+        .will(Will::new(&topic, nonce.as_bytes(), qos, false))
+        .disconnect_on_drop(None)
+        .connect()?;
+    drop(dropped);
+    let start = Instant::now();
+
+    while let Some(left) = timeout.checked_sub(start.elapsed()) {
+        watcher.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        let response = match watcher.read_message::<Response>() {
+            Ok(response) => response,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+        match response {
+            Response::Publish {
+                packet_id,
+                qos,
+                topic: t,
+                payload,
+                ..
+            } => {
+                match qos {
+                    1 => watcher.ack(AckType::Puback(packet_id))?,
+                    2 => watcher.ack(AckType::Pubrec(packet_id))?,
+                    _ => {}
+                }
+                if t == topic && payload == nonce.as_bytes() {
+                    println!(
+                        "Will received on {} after {} ms",
+                        topic,
+                        start.elapsed().as_millis()
+                    );
+                    // Best effort, the will has already arrived
+                    let _ = watcher.disconnect();
+                    return Ok(());
+                }
+            }
+            Response::Pubrel { packet_id } => watcher.ack(AckType::Pubcomp(packet_id))?,
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("No will on {} within {} s", topic, timeout.as_secs()),
+    ))
+}
+
+fn azure_preset(matches: &ArgMatches) -> Option<AzurePreset> {
+    let hub = matches.get_one::<String>("azure-hub")?;
+    let device = matches.get_one::<String>("azure-device")?;
+    let key = matches.get_one::<String>("azure-key")?;
+    Some(AzurePreset::new(hub, device, key))
+}
+
+/// Connect as the device of `preset` with a new SAS token
+fn azure_credentials(
+    builder: ProtocolBuilder,
+    preset: &AzurePreset,
+) -> io::Result<(ProtocolBuilder, SasToken)> {
+    let sas = preset.password(Duration::from_secs(DEFAULT_SAS_TTL))?;
+    let builder = builder
+        .client_id(preset.client_id())
+        .credentials(&preset.username(), Some(&sas.token));
+    Ok((builder, sas))
+}
+
+/// Outcome of a command that talked to a broker, used to keep the shell
+/// session up to date
+struct Exchange {
+    /// `None` over a serial link or an SSH tunnel
+    broker: Option<SocketAddr>,
+    client_id: String,
+    protocol_level: u8,
+    /// As negotiated, an MQTT 5 broker may impose its own
+    keepalive: Duration,
+    stats: StatsSnapshot,
+}
+
+/// Connects, publishes a single message and disconnects, or keeps
+/// publishing on the `--cron` or `--at` schedule, see `publish_scheduled`,
+/// the files of `--watch-dir`, see `publish_watched`, or the lines of stdin,
+/// see `publish_lines`, or waits for the reply to the message with
+/// `--await-response`, see `publish_request`.
+/// Inside the shell `fallback` is the broker the shell was opened against,
+/// used when no broker is given on the command line. `None` for dry runs
+fn publish(matches: &ArgMatches, fallback: Option<&[SocketAddr]>) -> io::Result<Option<Exchange>> {
+    let schedule = match (
+        matches.get_one::<Cron>("cron"),
+        matches.get_one::<SystemTime>("at"),
+    ) {
+        (Some(cron), _) => Some(Schedule::Cron(cron.clone())),
+        (None, Some(at)) => Some(Schedule::At(*at)),
+        (None, None) => None,
+    };
+    if let Some(schedule) = schedule {
+        return publish_scheduled(matches, fallback, &schedule);
+    }
+    if let Some(dir) = matches.get_one::<PathBuf>("watch-dir") {
+        return publish_watched(matches, fallback, dir);
+    }
+    if matches.get_flag("stdin-lines") {
+        return publish_lines(matches, fallback);
+    }
+    if matches.get_flag("await-response") {
+        return publish_request(matches, fallback);
+    }
+    if matches.get_flag("dry-run") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--dry-run needs --cron or --at",
+        ));
+    }
+    let message = match matches.get_one::<usize>("payload-size") {
+        Some(size) => {
+            let kind = matches.get_one::<PayloadKind>("payload-kind");
+            let seed = matches.get_one::<u64>("seed").copied();
+            Payloads::new(
+                kind.copied().unwrap_or(PayloadKind::Random),
+                seed.unwrap_or_else(|| Rng::from_time().next_u64()),
+            )
+            .next_payload(*size)
+        }
+        None => matches
+            .get_one::<String>("message")
+            .unwrap()
+            .clone()
+            .into_bytes(),
+    };
+    let topic = match payload_template(matches)? {
+        Some(template) => checked_topic(template.expand_payload(&message, |_| None)?)?,
+        None => matches.get_one::<String>("topic").unwrap().clone(),
+    };
+    let (mut client, client_id) = publish_connect(matches, fallback)?;
+    let broker = client.peer_addr().ok();
+    let packet_id = publish_one(matches, &mut client, &topic, &message)?;
+    match client.effective_qos(Qos::AtLeastOnce) {
+        Qos::AtMostOnce => println!("Published with QoS 0, unacknowledged"),
+        _ => println!("{}", Response::Puback { packet_id }),
+    }
+    client.disconnect()?;
+    Ok(Some(Exchange {
+        broker,
+        client_id,
+        protocol_level: client.protocol_level(),
+        keepalive: client.keepalive(),
+        stats: client.stats(),
+    }))
+}
+
+/// Connect as configured by the publish arguments, returning the client id
+/// actually used, the one the broker assigned if any
+fn publish_connect(
+    matches: &ArgMatches,
+    fallback: Option<&[SocketAddr]>,
+) -> io::Result<(Protocol, String)> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let client_id = client_id(matches);
+    let builder = broker_route(
+        Protocol::builder()
+            .timeout(timeout)
+            .keepalive(DEFAULT_KEEPALIVE)
+            .clean_session(clean_session(matches))
+            .protocol_level(protocol_level(matches))
+            .follow_redirects(max_redirects(matches)),
+        matches,
+        fallback,
+        timeout,
+    )?;
+    let builder = print_events(builder, matches).rate_limit(rate_limit(matches));
+    let (builder, client_id) = match azure_preset(matches) {
+        Some(preset) => {
+            let (builder, _) = azure_credentials(builder, &preset)?;
+            (builder, preset.client_id().to_string())
+        }
+        None => match matches.get_one::<String>("username") {
+            Some(username) => (
+                builder.client_id(client_id).credentials(
+                    username,
+                    matches.get_one::<String>("password").map(|p| p.as_str()),
+                ),
+                client_id.to_string(),
+            ),
+            None => (builder.client_id(client_id), client_id.to_string()),
+        },
+    };
+    let client = connect(builder, matches)?;
+    report_assigned_client_id(&client, &client_id);
+    let client_id = client.client_id().to_string();
+    Ok((client, client_id))
+}
+
+/// Publish a message with the retransmission, encoding, compression and
+/// request arguments, returning the packet id the PUBACK carried
+fn publish_one(
+    matches: &ArgMatches,
+    client: &mut Protocol,
+    topic: &str,
+    payload: &[u8],
+) -> io::Result<u16> {
+    let options = PublishOptions {
+        ack_timeout: matches
+            .get_one::<u64>("ack-timeout")
+            .map(|ms| Duration::from_millis(*ms)),
+        retries: *matches.get_one::<u32>("retries").unwrap_or(&0),
+        ..PublishOptions::default()
+    };
+    let payload = encode_message(matches, payload)?;
+    let (payload, encoding) = compress_message(matches, &payload);
+    let correlation_data = matches.get_one::<String>("correlation-data");
+    let mut properties = request_properties(matches, correlation_data.map(|d| d.as_bytes()));
+    properties.extend(content_properties(matches, &payload));
+    // 3.1.1 subscribers recognize compressed payloads by their magic number
+    if client.capabilities().is_some() {
+        properties.extend(encoding);
+    }
+    client.publish_with_properties(topic, &payload, &options, &properties)
+}
+
+/// Payload as `--encode` or `--subject` has it published
+fn encode_message(matches: &ArgMatches, payload: &[u8]) -> io::Result<Vec<u8>> {
+    if let Some(subject) = matches.get_one::<String>("subject") {
+        let (id, schema) = subject_schema(matches, subject)?;
+        let text = std::str::from_utf8(payload)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Message is not UTF-8"))?;
+        return Ok(avro::frame(*id, &schema.encode(&json::parse(text)?)?));
+    }
+    match matches.get_one::<Encoding>("encode") {
+        Some(encoding) => encoding.encode_json(payload),
+        None => Ok(payload.to_vec()),
+    }
+}
+
+/// Id and latest schema of `--subject`, looked up once per run
+fn subject_schema(matches: &ArgMatches, subject: &str) -> io::Result<&'static (u32, AvroSchema)> {
+    static SCHEMA: OnceLock<(u32, AvroSchema)> = OnceLock::new();
+    if let Some(schema) = SCHEMA.get() {
+        return Ok(schema);
+    }
+    let mut registry = matches
+        .get_one::<SchemaRegistry>("schema-registry")
+        .unwrap()
+        .clone();
+    let (id, schema) = registry.latest(subject)?;
+    Ok(SCHEMA.get_or_init(|| (id, schema.clone())))
+}
+
+/// Payload as `--compress` has it published, with the user property
+/// naming its codec if it was compressed
+fn compress_message(matches: &ArgMatches, payload: &[u8]) -> (Vec<u8>, Option<Property>) {
+    let Some(codec) = matches.get_one::<Codec>("compress") else {
+        return (payload.to_vec(), None);
+    };
+    let threshold = *matches
+        .get_one::<usize>("compress-threshold")
+        .unwrap_or(&DEFAULT_COMPRESS_THRESHOLD);
+    match codec.encode(payload, threshold) {
+        Some(compressed) => (compressed, Some(codec.property())),
+        None => (payload.to_vec(), None),
+    }
+}
+
+/// The Response Topic and Correlation Data properties of `--response-topic`
+fn request_properties(matches: &ArgMatches, correlation_data: Option<&[u8]>) -> Vec<Property> {
+    let Some(response_topic) = matches.get_one::<String>("response-topic") else {
+        return vec![];
+    };
+    let mut properties = vec![Property::ResponseTopic(response_topic.clone())];
+    if let Some(data) = correlation_data {
+        properties.push(Property::CorrelationData(data.to_vec()));
+    }
+    properties
+}
+
+/// The Payload Format Indicator and Content Type properties of
+/// `--content-type`, the payload flagged as UTF-8 when it is
+fn content_properties(matches: &ArgMatches, payload: &[u8]) -> Vec<Property> {
+    let Some(content_type) = matches.get_one::<String>("content-type") else {
+        return vec![];
+    };
+    vec![
+        Property::PayloadFormatIndicator(std::str::from_utf8(payload).is_ok().into()),
+        Property::ContentType(content_type.clone()),
+    ]
+}
+
+/// The next packet received before `deadline`, `None` once it passed
+fn next_message_before(client: &mut Protocol, deadline: Instant) -> io::Result<Option<Response>> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Ok(None);
+    }
+    client.set_read_timeout(Some(left))?;
+    match client.next_message() {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        response => response.map(Some),
+    }
+}
+
+/// Publishes a request and prints the reply: subscribes to
+/// `--response-topic` first, then publishes the message with the response
+/// topic and the correlation data, random unless `--correlation-data` is
+/// given, and waits for a message on the response topic carrying the same
+/// correlation data, skipping the others, until `--response-timeout`
+fn publish_request(
+    matches: &ArgMatches,
+    fallback: Option<&[SocketAddr]>,
+) -> io::Result<Option<Exchange>> {
+    let topic = matches.get_one::<String>("topic").unwrap();
+    let message = matches.get_one::<String>("message").unwrap();
+    let response_topic = matches.get_one::<String>("response-topic").unwrap();
+    let correlation_data = matches
+        .get_one::<String>("correlation-data")
+        .cloned()
+        .unwrap_or_else(|| random_client_id("request"));
+    let timeout = *matches.get_one::<Duration>("response-timeout").unwrap();
+    let (mut client, client_id) = publish_connect(matches, fallback)?;
+    let broker = client.peer_addr().ok();
+    let deadline = Instant::now() + timeout;
+    let timed_out = || {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("No response on {} within {:?}", response_topic, timeout),
+        )
+    };
+
+    // The request can only be published once the subscription is in place
+    // or a quick reply would be missed
+    let sub_id = client.subscribe(response_topic, Qos::AtLeastOnce)?;
+    loop {
+        match next_message_before(&mut client, deadline)? {
+            Some(Response::Suback {
+                packet_id,
+                return_codes,
+            }) if packet_id == sub_id => {
+                if return_codes.iter().any(|code| *code >= SUBACK_FAILURE) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("Subscription to {} refused", response_topic),
+                    ));
+                }
+                break;
+            }
+            Some(_) => {}
+            None => return Err(timed_out()),
+        }
+    }
+
+    // Sent by hand rather than with publish_with, which would discard a
+    // reply arriving before the PUBACK
+    let payload = encode_message(matches, message.as_bytes())?;
+    let (payload, encoding) = compress_message(matches, &payload);
+    let qos = u8::from(&client.effective_qos(Qos::AtLeastOnce));
+    let packet_id = if qos > 0 { client.next_packet_id() } else { 0 };
+    let mut properties = request_properties(matches, Some(correlation_data.as_bytes()));
+    properties.extend(content_properties(matches, &payload));
+    properties.extend(encoding);
+    client.send_message(&PublishV5 {
+        packet_id,
+        qos,
+        dup: false,
+        retain: false,
+        topic: topic.to_string(),
+        payload,
+        properties,
+    })?;
+    let mut skipped = 0;
+    loop {
+        match next_message_before(&mut client, deadline)? {
+            Some(Response::Puback { packet_id: id }) if id == packet_id => {
+                println!("{}", Response::Puback { packet_id })
+            }
+            Some(Response::Publish {
+                packet_id,
+                qos,
+                topic,
+                payload,
+                properties,
+                ..
+            }) => {
+                if qos > 0 {
+                    client.ack(AckType::Puback(packet_id))?;
+                }
+                let correlated = properties.iter().any(|property| {
+                    matches!(property, Property::CorrelationData(data)
+                        if data == correlation_data.as_bytes())
+                });
+                if correlated {
+                    println!("{} {}", topic, String::from_utf8_lossy(&payload));
+                    break;
+                }
+                skipped += 1;
+            }
+            Some(_) => {}
+            None => return Err(timed_out()),
+        }
+    }
+    if skipped > 0 {
+        eprintln!("Skipped {} replies to other requests", skipped);
+    }
+    client.disconnect()?;
+    Ok(Some(Exchange {
+        broker,
+        client_id,
+        protocol_level: client.protocol_level(),
+        keepalive: client.keepalive(),
+        stats: client.stats(),
+    }))
+}
+
+/// Wait for `timeout` on a connection only publishing, sending the pings
+/// the keepalive calls for meanwhile
+fn wait_answering_pings(client: &mut Protocol, timeout: Duration) -> io::Result<()> {
+    // A zero timeout is refused by the socket
+    client.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+    match client.next_message() {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        response => response.map(|_| ()),
+    }
+}
+
+/// Reads stdin split on `--delimiter` and publishes each line, empty ones
+/// aside, to `--topic` until the end of input, at most `--rate` per second.
+/// With `--topic-template` the topic comes from each line as a JSON
+/// document, lines without its fields are skipped
+fn publish_lines(
+    matches: &ArgMatches,
+    fallback: Option<&[SocketAddr]>,
+) -> io::Result<Option<Exchange>> {
+    let template = payload_template(matches)?;
+    let topic = matches.get_one::<String>("topic");
+    let delimiter = *matches.get_one::<u8>("delimiter").unwrap();
+    let period = matches
+        .get_one::<u32>("rate")
+        .map(|rate| Duration::from_secs_f64(1.0 / *rate as f64));
+    let (mut client, client_id) = publish_connect(matches, fallback)?;
+    let broker = client.peer_addr().ok();
+    // Stdin is read from a thread so that pings go on while it's quiet, as
+    // with tail -f, bounded to stop reading while lines wait for --rate
+    let (tx, rx) = mpsc::sync_channel(STDIN_BACKLOG);
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        loop {
+            let mut line = vec![];
+            let line = match stdin.read_until(delimiter, &mut line) {
+                Ok(0) => return,
+                Ok(_) => {
+                    if line.last() == Some(&delimiter) {
+                        line.pop();
+                        if delimiter == b'\n' && line.last() == Some(&b'\r') {
+                            line.pop();
+                        }
+                    }
+                    Ok(line)
+                }
+                Err(e) => Err(e),
+            };
+            if tx.send(line).is_err() {
+                return;
+            }
+        }
+    });
+    let mut next = Instant::now();
+    let mut published = 0;
+    loop {
+        // Wake up while stdin is quiet often enough to ping in time
+        let line = match rx.recv_timeout((client.keepalive() / 2).max(Duration::from_secs(1))) {
+            Ok(line) => line?,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                wait_answering_pings(&mut client, Duration::ZERO)?;
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if line.is_empty() {
+            continue;
+        }
+        let line_topic = match &template {
+            Some(template) => {
+                match template
+                    .expand_payload(&line, |_| None)
+                    .and_then(checked_topic)
+                {
+                    Ok(topic) => topic,
+                    Err(e) => {
+                        eprintln!("Skipping line: {}", e);
+                        continue;
+                    }
+                }
+            }
+            None => topic.unwrap().clone(),
+        };
+        if let Some(period) = period {
+            let now = Instant::now();
+            if next > now {
+                wait_answering_pings(&mut client, next - now)?;
+            }
+            next = next.max(now) + period;
+        }
+        publish_one(matches, &mut client, &line_topic, &line)?;
+        published += 1;
+    }
+    eprintln!("Published {} lines", published);
+    client.disconnect()?;
+    Ok(Some(Exchange {
+        broker,
+        client_id,
+        protocol_level: client.protocol_level(),
+        keepalive: client.keepalive(),
+        stats: client.stats(),
+    }))
+}
+
+/// `--topic-template` when publishing messages given or read from stdin,
+/// where it can only read their JSON fields
+fn payload_template(matches: &ArgMatches) -> io::Result<Option<&Template>> {
+    let template = matches.get_one::<Template>("topic-template");
+    if let Some(template) = template {
+        template.check(|_| false)?;
+    }
+    Ok(template)
+}
+
+/// Refuse a topic expanded from a template that isn't a valid topic name,
+/// like one with a wildcard
+fn checked_topic(topic: String) -> io::Result<String> {
+    match validate_topic_name(&topic) {
+        Ok(()) => Ok(topic),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+/// Topic names are checked before connecting, rather than failing once
+/// the broker drops the connection
+fn parse_topic_name(s: &str) -> Result<String, String> {
+    match validate_topic_name(s) {
+        Ok(()) => Ok(s.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_topic_filter(s: &str) -> Result<String, String> {
+    match validate_topic_filter(s) {
+        Ok(()) => Ok(s.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("invalid rate {}, expected a positive number", s)),
+    }
+}
+
+/// Throughput of `--max-rate` and `--max-bandwidth`
+fn rate_limit(matches: &ArgMatches) -> RateLimit {
+    RateLimit {
+        messages_per_sec: matches.get_one::<f64>("max-rate").copied(),
+        bytes_per_sec: matches.get_one::<f64>("max-bandwidth").copied(),
+        ..RateLimit::default()
+    }
+}
+
+/// Parse a delimiter, a single byte character or one of the escapes \n,
+/// \r, \t and \0
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "\\n" => Ok(b'\n'),
+        "\\r" => Ok(b'\r'),
+        "\\t" => Ok(b'\t'),
+        "\\0" => Ok(0),
+        _ if s.len() == 1 => Ok(s.as_bytes()[0]),
+        _ => Err(format!(
+            "invalid delimiter {}, expected a single byte or \\n, \\r, \\t, \\0",
+            s
+        )),
+    }
+}
+
+/// Placeholders of `--topic-template` for `--watch-dir`: the file name, the
+/// name without and the extension alone
+const WATCH_PLACEHOLDERS: [&str; 3] = ["name", "stem", "ext"];
+
+/// Publishes the files created or modified in `--watch-dir` until
+/// interrupted, each to the topic `--topic-template` expands to for its name
+fn publish_watched(
+    matches: &ArgMatches,
+    fallback: Option<&[SocketAddr]>,
+    dir: &Path,
+) -> io::Result<Option<Exchange>> {
+    let template = matches.get_one::<Template>("topic-template").unwrap();
+    template.check(|name| WATCH_PLACEHOLDERS.contains(&name))?;
+    let interval = *matches.get_one::<Duration>("poll-interval").unwrap();
+    let mut watcher = DirWatcher::new(dir)?;
+    let (mut client, _) = publish_connect(matches, fallback)?;
+    eprintln!("Watching {}", dir.display());
+    loop {
+        wait_answering_pings(&mut client, interval)?;
+        for path in watcher.poll()? {
+            // Files may go away between the listing and now
+            let payload = match std::fs::read(&path) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    eprintln!("Cannot read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let part = |part: Option<&std::ffi::OsStr>| {
+                part.map_or(String::new(), |s| s.to_string_lossy().into_owned())
+            };
+            let topic = template.expand_payload(&payload, |name| match name {
+                "name" => Some(part(path.file_name())),
+                "stem" => Some(part(path.file_stem())),
+                "ext" => Some(part(path.extension())),
+                _ => None,
+            });
+            let topic = match topic.and_then(checked_topic) {
+                Ok(topic) => topic,
+                Err(e) => {
+                    eprintln!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let packet_id = publish_one(matches, &mut client, &topic, &payload)?;
+            println!(
+                "{} {} {}",
+                path.display(),
+                topic,
+                Response::Puback { packet_id }
+            );
+        }
+    }
+}
+
+/// Placeholders of scheduled publishes: the number of the publish from 1,
+/// and the time it was scheduled at in Unix seconds, milliseconds and UTC
+const SCHEDULE_PLACEHOLDERS: [&str; 4] = ["n", "ts", "ts_ms", "iso"];
+
+/// Publishes repeatedly from a single connection, `--at` once and `--cron`
+/// until `--count` publishes were made. Topic and message are templates
+/// expanded for every publish, see `SCHEDULE_PLACEHOLDERS`. `--dry-run`
+/// prints the next publishes without connecting
+fn publish_scheduled(
+    matches: &ArgMatches,
+    fallback: Option<&[SocketAddr]>,
+    schedule: &Schedule,
+) -> io::Result<Option<Exchange>> {
+    let topic: Template = matches.get_one::<String>("topic").unwrap().parse()?;
+    let message: Template = matches.get_one::<String>("message").unwrap().parse()?;
+    for template in [&topic, &message] {
+        template.check(|name| SCHEDULE_PLACEHOLDERS.contains(&name))?;
+    }
+    let count = matches.get_one::<u64>("count").copied();
+    let expand = |n: u64, at: SystemTime| -> io::Result<(String, String)> {
+        let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let lookup = |name: &str| match name {
+            "n" => Some(n.to_string()),
+            "ts" => Some(since_epoch.as_secs().to_string()),
+            "ts_ms" => Some(since_epoch.as_millis().to_string()),
+            "iso" => Some(schedule::format_timestamp(at)),
+            _ => None,
+        };
+        Ok((topic.expand(lookup)?, message.expand(lookup)?))
+    };
+    let never = || io::Error::new(io::ErrorKind::InvalidInput, "The schedule never fires");
+
+    if matches.get_flag("dry-run") {
+        let shown = count.map_or(DRY_RUN_PUBLISHES, |c| {
+            c.min(DRY_RUN_PUBLISHES as u64) as usize
+        });
+        let upcoming = schedule.upcoming(SystemTime::now(), shown);
+        if upcoming.is_empty() {
+            return Err(never());
+        }
+        for (n, at) in (1..).zip(upcoming) {
+            let (topic, message) = expand(n, at)?;
+            println!("{} {} {}", schedule::format_timestamp(at), topic, message);
+        }
+        return Ok(None);
+    }
+
+    let (mut client, client_id) = publish_connect(matches, fallback)?;
+    let broker = client.peer_addr().ok();
+    let mut last = SystemTime::now();
+    for n in 1.. {
+        if count.is_some_and(|count| n > count) {
+            break;
+        }
+        let Some(at) = schedule.next_after(last) else {
+            if n == 1 {
+                return Err(never());
+            }
+            break;
+        };
+        // The wall clock is checked again after each wake up as it may jump
+        while let Ok(left) = at.duration_since(SystemTime::now()) {
+            if left.is_zero() {
+                break;
+            }
+            wait_answering_pings(&mut client, left)?;
+        }
+        let (topic, message) = expand(n, at)?;
+        let packet_id = publish_one(matches, &mut client, &topic, message.as_bytes())?;
+        println!(
+            "{} {} {}",
+            schedule::format_timestamp(at),
+            topic,
+            Response::Puback { packet_id }
+        );
+        last = at;
+    }
+    client.disconnect()?;
+    Ok(Some(Exchange {
+        broker,
+        client_id,
+        protocol_level: client.protocol_level(),
+        keepalive: client.keepalive(),
+        stats: client.stats(),
+    }))
+}
+
+/// Subscribes and prints every message received until `--count` is reached,
+/// `--duration` has elapsed or a message matches `--until`, the latter
+/// failing if the duration elapses first. `--output-dir` also stores them on
+/// disk by topic and `--jsonl` appends them to a single rotating file.
+/// With `--verify-seq` anomalies are reported on stderr as they happen, a
+/// summary is printed at the end and the exit status is an error if any
+/// was found. `--notify` raises desktop notifications, at most one per
+/// `--notify-interval`, and `--idle-timeout` warns about silences
+/// Subscribe to `topic`, failing unless the broker grants it
+fn subscribe_checked(client: &mut Protocol, topic: &str, qos: Qos) -> io::Result<()> {
+    client.subscribe(topic, qos)?;
+    // MQTT 5 reason codes of refused subscriptions are all past 0x80
+    match client.next_message()? {
+        Response::Suback { return_codes, .. }
+            if return_codes.iter().all(|c| *c < SUBACK_FAILURE) =>
+        {
+            Ok(())
+        }
+        resp => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Subscription refused: {}", resp),
+        )),
+    }
+}
+
+fn subscribe(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let topic = matches.get_one::<String>("topic").unwrap();
+    let qos = Qos::from(*matches.get_one::<u8>("qos").unwrap_or(&0));
+    let client_id = client_id(matches);
+    let count = matches.get_one::<u64>("count").copied();
+    let duration = matches.get_one::<Duration>("duration").copied();
+    let until = matches.get_one::<Condition>("until");
+    let mut dedup = matches
+        .get_one::<Duration>("dedup-window")
+        .map(|window| DuplicateFilter::new(*window));
+    let mut verifier = matches
+        .get_one::<SeqSpec>("verify-seq")
+        .map(|spec| SeqVerifier::new(spec.clone()));
+    let notify_if = matches.get_one::<Condition>("notify-if");
+    let mut notifier = (matches.get_flag("notify") || notify_if.is_some()).then(|| {
+        let interval = *matches.get_one::<Duration>("notify-interval").unwrap();
+        Notifier::new(Backend::default(), interval)
+    });
+
+    let consume_delay = matches
+        .get_one::<u64>("consume-delay")
+        .map(|ms| Duration::from_millis(*ms));
+
+    let mut output = match matches.get_one::<PathBuf>("output-dir") {
+        Some(dir) => {
+            let mode = matches.get_one::<String>("output-mode").unwrap().parse()?;
+            Some(TopicDir::new(dir, mode)?)
+        }
+        None => None,
+    };
+    let mut registry = matches
+        .get_one::<SchemaRegistry>("schema-registry")
+        .cloned();
+    let mut jsonl = match matches.get_one::<PathBuf>("jsonl") {
+        Some(path) => {
+            let mut sink = JsonlSink::open(path)?;
+            sink.sync_every = *matches.get_one::<u64>("jsonl-sync").unwrap() as usize;
+            sink.max_size = matches.get_one::<u64>("jsonl-max-size").copied();
+            sink.keep = *matches.get_one::<u64>("jsonl-keep").unwrap() as usize;
+            Some(sink)
+        }
+        None => None,
+    };
+
+    let mut builder = broker_route(
+        Protocol::builder()
+            .timeout(timeout)
+            .client_id(client_id)
+            .clean_session(clean_session(matches))
+            .protocol_level(protocol_level(matches))
+            .follow_redirects(max_redirects(matches))
+            .strict(matches.get_flag("strict")),
+        matches,
+        None,
+        timeout,
+    )?;
+    builder = print_events(builder, matches);
+    let mut azure = match azure_preset(matches) {
+        Some(preset) => {
+            let (authenticated, sas) = azure_credentials(builder, &preset)?;
+            builder = authenticated;
+            Some((preset, sas))
+        }
+        None => None,
+    };
+    if let Some(idle_timeout) = matches.get_one::<Duration>("idle-timeout") {
+        let topic = topic.clone();
+        builder = builder.on_idle(*idle_timeout, move |silence| {
+            eprintln!("No message on {} for {:.1?}", topic, silence)
+        });
+    }
+    let mut client = connect(builder.clone(), matches)?;
+    report_assigned_client_id(&client, client_id);
+    if client.session_present() {
+        eprintln!("Resumed the session of {}", client.client_id());
+    }
+    if let Some(size) = matches.get_one::<usize>("recv-buffer") {
+        client.set_recv_buffer_size(*size)?;
+        eprintln!("Receive buffer {} bytes", client.recv_buffer_size()?);
+    }
+    subscribe_checked(&mut client, topic, qos)?;
+    let mut redirects = 0;
+
+    let deadline = duration.map(|d| Instant::now() + d);
+    let mut qos2 = Dedup::default();
+    let mut received = 0;
+    let mut matched = false;
+    let refresh_margin = Duration::from_secs(SAS_REFRESH_MARGIN);
+    while count.is_none_or(|c| received < c) {
+        // The hub drops connections whose token expired, reconnect first
+        if let Some((preset, sas)) = azure
+            .as_mut()
+            .filter(|(_, sas)| sas.needs_refresh(refresh_margin))
+        {
+            let (authenticated, token) = azure_credentials(builder, preset)?;
+            builder = authenticated;
+            *sas = token;
+            eprintln!("Renewing the SAS token, reconnecting");
+            client.disconnect()?;
+            client = builder.reconnect(&client)?;
+            subscribe_checked(&mut client, topic, qos)?;
+        }
+        let deadline_left = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => Some(left),
+                _ => break,
+            },
+            None => None,
+        };
+        let refresh_left = azure.as_ref().map(|(_, sas)| {
+            sas.expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .saturating_sub(refresh_margin)
+                .max(Duration::from_millis(1))
+        });
+        let waiting = deadline_left.is_some() || refresh_left.is_some();
+        if let Some(left) = deadline_left.into_iter().chain(refresh_left).min() {
+            client.set_read_timeout(Some(left))?;
+        }
+        let response = match client.next_message() {
+            Err(e) if waiting && e.kind() == io::ErrorKind::WouldBlock => continue,
+            // Brokers steering connections send DISCONNECT with a Server
+            // Reference, the session doesn't follow so subscribe again
+            Err(e) if redirects < max_redirects(matches) => match builder.redirected(&e) {
+                Some(redirected) => {
+                    eprintln!("{}, reconnecting", e);
+                    redirects += 1;
+                    builder = redirected;
+                    client = builder.reconnect(&client)?;
+                    subscribe_checked(&mut client, topic, qos)?;
+                    continue;
+                }
+                None => return Err(e),
+            },
+            response => response?,
+        };
+        let (topic, payload, properties) = match response {
+            Response::Publish {
+                packet_id,
+                qos,
+                topic,
+                payload,
+                properties,
+                ..
+            } => {
+                match qos {
+                    1 => client.ack(AckType::Puback(packet_id))?,
+                    2 => {
+                        client.ack(AckType::Pubrec(packet_id))?;
+                        if !qos2.arrived(packet_id) {
+                            continue;
+                        }
+                    }
+                    _ => {}
+                }
+                (topic, payload, properties)
+            }
+            Response::Pubrel { packet_id } => {
+                client.ack(AckType::Pubcomp(packet_id))?;
+                qos2.released(&packet_id);
+                continue;
+            }
+            _ => continue,
+        };
+        let payload = compress::decode(&payload, &properties)?;
+        // Unframed payloads are shown as they are, Avro ones as JSON
+        // whatever their content type
+        let (payload, decoded) = match registry.as_mut().map(|registry| registry.decode(&payload)) {
+            Some(Ok(Some(value))) => (value.to_string().into_bytes(), true),
+            Some(Err(e)) => {
+                eprintln!("Cannot decode the Avro payload on {}: {}", topic, e);
+                (payload, false)
+            }
+            _ => (payload, false),
+        };
+        let content_type = match decoded {
+            true => None,
+            false => content_type(&topic, &payload, &properties),
+        };
+        if let Some(dedup) = dedup.as_mut() {
+            if !dedup.admit(&topic, &payload, Instant::now()) {
+                continue;
+            }
+        }
+        received += 1;
+        println!("{} {}", topic, show_payload(&topic, &payload, content_type));
+        if let Some(output) = output.as_mut() {
+            output.write(&topic, &payload, SystemTime::now())?;
+        }
+        if let Some(jsonl) = jsonl.as_mut() {
+            jsonl.write(&topic, &payload, SystemTime::now())?;
+        }
+        if let Some(verifier) = verifier.as_mut() {
+            match verifier.observe(&topic, &payload) {
+                SeqEvent::InOrder => {}
+                event => eprintln!("{}: {}", topic, event),
+            }
+        }
+        if let Some(n) = notifier.as_mut() {
+            if notify_if.is_none_or(|condition| condition.matches(&payload)) {
+                if let Err(e) = n.notify(&topic, &payload, Instant::now()) {
+                    eprintln!("{}, notifications disabled", e);
+                    notifier = None;
+                }
+            }
+        }
+        if until.is_some_and(|until| until.matches(&payload)) {
+            matched = true;
+            break;
+        }
+        if let Some(delay) = consume_delay {
+            thread::sleep(delay);
+        }
+    }
+    client.disconnect()?;
+    if let Some(jsonl) = jsonl.as_mut() {
+        jsonl.sync()?;
+    }
+    if let Some(dedup) = dedup.filter(|dedup| dedup.suppressed() > 0) {
+        eprintln!("Skipped {} duplicate messages", dedup.suppressed());
+    }
+    if let (Some(until), false) = (until, matched) {
+        if count.is_none_or(|c| received < c) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No message matched {} in time", until),
+            ));
+        }
+    }
+
+    let Some(verifier) = verifier else {
+        return Ok(());
+    };
+    let report = verifier.report();
+    println!("{}", report);
+    if report.gaps + report.out_of_order + report.duplicates + report.missing_seq > 0 {
+        return Err(io::Error::other("Sequence verification failed"));
+    }
+    Ok(())
 }
 
-fn repl() -> Result<(), String> {
+/// Content Type property of a message, warning when the Payload Format
+/// Indicator flags a payload as UTF-8 that isn't
+fn content_type<'a>(topic: &str, payload: &[u8], properties: &'a [Property]) -> Option<&'a str> {
+    let mut content_type = None;
+    for property in properties {
+        match property {
+            Property::PayloadFormatIndicator(1) if std::str::from_utf8(payload).is_err() => {
+                eprintln!("Payload on {} is flagged as UTF-8 but isn't", topic)
+            }
+            Property::ContentType(value) => content_type = Some(value.as_str()),
+            _ => {}
+        }
+    }
+    content_type
+}
+
+/// Payload printed as its content type says, as it is for unknown types
+/// or when it doesn't decode
+fn show_payload(topic: &str, payload: &[u8], content_type: Option<&str>) -> String {
+    let Some((content_type, printer)) =
+        content_type.and_then(|t| Printer::for_content_type(t).map(|p| (t, p)))
+    else {
+        return String::from_utf8_lossy(payload).into_owned();
+    };
+    printer.print(payload).unwrap_or_else(|e| {
+        eprintln!(
+            "Cannot print the payload on {} as {}: {}",
+            topic, content_type, e
+        );
+        String::from_utf8_lossy(payload).into_owned()
+    })
+}
+
+/// Subscribes to `--left` and `--right`, pairs up their messages and prints
+/// whether each pair is identical or its differences, one per line, until
+/// `--count` pairs were compared or `--duration` has elapsed. Messages on a
+/// topic matching both filters count as left ones. The exit status is an
+/// error if any pair differs
+fn diff(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let addrs = broker_addrs(matches, None, timeout)?;
+    let left = matches.get_one::<String>("left").unwrap();
+    let right = matches.get_one::<String>("right").unwrap();
+    let qos = Qos::from(*matches.get_one::<u8>("qos").unwrap_or(&0));
+    let count = matches.get_one::<u64>("count").copied();
+    let duration = matches.get_one::<Duration>("duration").copied();
+    let mut pairer = Pairer::new(matches.get_one::<Pairing>("pair-by").unwrap().clone());
+
+    let mut client = Protocol::builder()
+        .addrs(&addrs)
+        .timeout(timeout)
+        .client_id(client_id(matches))
+        .clean_session(clean_session(matches))
+        .connect()?;
+    client.subscribe(left, qos)?;
+    client.subscribe(right, qos)?;
+
+    let deadline = duration.map(|d| Instant::now() + d);
+    let (mut pairs, mut different) = (0, 0);
+    while count.is_none_or(|c| pairs < c) {
+        if let Some(deadline) = deadline {
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => client.set_read_timeout(Some(left))?,
+                _ => break,
+            }
+        }
+        let response = match client.next_message() {
+            Err(e) if deadline.is_some() && e.kind() == io::ErrorKind::WouldBlock => continue,
+            response => response?,
+        };
+        let (topic, payload) = match response {
+            Response::Publish {
+                packet_id,
+                qos,
+                topic,
+                payload,
+                ..
+            } => {
+                if qos == 1 {
+                    client.ack(AckType::Puback(packet_id))?;
+                }
+                (topic, payload)
+            }
+            Response::Suback { return_codes, .. } if return_codes.contains(&SUBACK_FAILURE) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Subscription refused",
+                ))
+            }
+            _ => continue,
+        };
+        let payload = compress::decode(&payload, &[])?;
+        let side = if topic::matches(left, &topic) {
+            Side::Left
+        } else if topic::matches(right, &topic) {
+            Side::Right
+        } else {
+            continue;
+        };
+        let Some(pair) = pairer.push(side, payload) else {
+            continue;
+        };
+        pairs += 1;
+        let label = pair
+            .key
+            .map_or(format!("#{}", pairs), |key| format!("key {}", key));
+        let changes = diff_payloads(&pair.left, &pair.right);
+        if changes.is_empty() {
+            println!("= {}", label);
+            continue;
+        }
+        different += 1;
+        println!("! {}, {} differences", label, changes.len());
+        for change in changes {
+            println!("  {}", change);
+        }
+    }
+    client.disconnect()?;
+
+    let (unpaired_left, unpaired_right) = pairer.unpaired();
+    println!(
+        "pairs {}, identical {}, different {}, unpaired left {}, unpaired right {}, without key {}",
+        pairs,
+        pairs - different,
+        different,
+        unpaired_left,
+        unpaired_right,
+        pairer.unkeyed()
+    );
+    if different > 0 {
+        return Err(io::Error::other("Payloads differ"));
+    }
+    Ok(())
+}
+
+/// Subscribes to `--filter` and every `--interval` prints the message and
+/// byte rates of the `--top` busiest topics over the interval, until
+/// `--duration` has elapsed. The totals of the connection follow at exit
+fn stats(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let addrs = broker_addrs(matches, None, timeout)?;
+    let filter = matches.get_one::<String>("filter").unwrap();
+    let groups: Vec<String> = matches
+        .get_many::<String>("group")
+        .map_or(vec![], |groups| groups.cloned().collect());
+    let interval = *matches.get_one::<Duration>("interval").unwrap();
+    let top = *matches.get_one::<usize>("top").unwrap();
+    let duration = matches.get_one::<Duration>("duration").copied();
+
+    let mut client = Protocol::builder()
+        .addrs(&addrs)
+        .timeout(timeout)
+        .client_id(client_id(matches))
+        .clean_session(clean_session(matches))
+        .connect()?;
+    client.subscribe(filter, Qos::AtMostOnce)?;
+
+    let start = Instant::now();
+    let deadline = duration.map(|d| start + d);
+    let mut rates = TopicRates::new(&groups, start);
+    let mut next = start + interval;
     loop {
-        let line = readline()?;
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            break;
+        }
+        if now >= next {
+            println!("{}\n", RateTable(&rates.rates(now, top)));
+            next += interval;
+            continue;
+        }
+        let wake = deadline.map_or(next, |deadline| deadline.min(next));
+        client.set_read_timeout(Some(wake - now))?;
+        match client.next_message() {
+            Ok(Response::Publish { topic, payload, .. }) => rates.record(&topic, payload.len()),
+            Ok(Response::Suback { return_codes, .. }) if return_codes.contains(&SUBACK_FAILURE) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Subscription to {} refused", filter),
+                ))
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+    client.disconnect()?;
+    println!("{} topics\n{}", rates.len(), client.stats());
+    Ok(())
+}
+
+/// Connect to both brokers and forward messages until either disconnects
+fn bridge(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let local = broker_addrs(matches, None, timeout)?;
+    let remote_host = matches.get_one::<String>("remote-host").unwrap();
+    let remote_port = *matches
+        .get_one::<u16>("remote-port")
+        .unwrap_or(&DEFAULT_PORT);
+    let remote: Vec<SocketAddr> = (remote_host.as_str(), remote_port)
+        .to_socket_addrs()?
+        .collect();
+    let client_id = client_id(matches);
+    let filters = |id| {
+        matches
+            .get_many::<String>(id)
+            .unwrap_or_default()
+            .cloned()
+            .collect()
+    };
+    let options = BridgeOptions {
+        outgoing: filters("out"),
+        incoming: filters("in"),
+        rewrites: matches
+            .get_many::<RewriteRule>("rewrite")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        topic_template: relay_template(matches)?,
+        qos: Qos::from(*matches.get_one::<u8>("qos").unwrap_or(&1)),
+        stop: Some(sake::shutdown::on_terminate()?),
+        drain_timeout: matches
+            .get_one::<Duration>("drain-timeout")
+            .copied()
+            .unwrap_or(DEFAULT_DRAIN_TIMEOUT),
+        spill: matches.get_one::<PathBuf>("spill").cloned(),
+        queue: matches.get_one::<PathBuf>("queue").cloned(),
+        queue_options: QueueOptions {
+            segment_size: matches
+                .get_one::<u64>("queue-segment-size")
+                .copied()
+                .unwrap_or(QueueOptions::default().segment_size),
+            max_age: matches.get_one::<Duration>("queue-max-age").copied(),
+            max_size: matches.get_one::<u64>("queue-max-size").copied(),
+        },
+        ..BridgeOptions::default()
+    };
+
+    let connect = |addrs: &[SocketAddr]| {
+        Protocol::builder()
+            .addrs(addrs)
+            .timeout(timeout)
+            .client_id(client_id)
+            .clean_session(clean_session(matches))
+            .rate_limit(rate_limit(matches))
+            .connect()
+    };
+    let bridge = Bridge::new(connect(&local)?, connect(&remote)?, options);
+    let stats = bridge.run()?;
+    eprintln!(
+        "Forwarded {} outgoing and {} incoming messages, dropped {} echoes",
+        stats.outgoing, stats.incoming, stats.looped
+    );
+    print_skipped(&stats);
+    if stats.replayed > 0 {
+        eprintln!("Replayed {} queued messages", stats.replayed);
+    }
+    if stats.undelivered > 0 {
+        eprintln!("Left {} messages undelivered", stats.undelivered);
+    }
+    Ok(())
+}
+
+/// Describe a queue directory, segment by segment
+fn queue(matches: &ArgMatches) -> io::Result<()> {
+    let (_, matches) = matches.subcommand().expect("subcommand required");
+    let (segments, pending) = queue::inspect(matches.get_one::<PathBuf>("DIR").unwrap())?;
+    let time = |t: Option<SystemTime>| t.map_or("-".into(), schedule::format_timestamp);
+    for segment in &segments {
+        let name = segment
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let mut line = format!(
+            "{}  {} bytes  {} messages  {} acked  {} .. {}",
+            name,
+            segment.bytes,
+            segment.messages,
+            segment.acked,
+            time(segment.oldest),
+            time(segment.newest)
+        );
+        if segment.corrupt > 0 {
+            line.push_str(&format!("  {} corrupt", segment.corrupt));
+        }
+        if segment.truncated {
+            line.push_str("  truncated");
+        }
+        println!("{}", line);
+    }
+    println!(
+        "{} segments, {} messages pending",
+        segments.len(),
+        pending.len()
+    );
+    if matches.get_flag("pending") {
+        for message in &pending {
+            println!(
+                "{}",
+                sink::json_line(&message.topic, &message.payload, message.timestamp)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// `--topic-template` of bridge and copy, where `{{topic}}` is the topic
+/// of the message relayed
+fn relay_template(matches: &ArgMatches) -> io::Result<Option<Template>> {
+    let template = matches.get_one::<Template>("topic-template").cloned();
+    if let Some(template) = &template {
+        template.check(|name| name == "topic")?;
+    }
+    Ok(template)
+}
+
+fn print_skipped(stats: &BridgeStats) {
+    if stats.skipped > 0 {
+        eprintln!(
+            "Skipped {} messages without the fields of --topic-template",
+            stats.skipped
+        );
+    }
+}
+
+/// Parse a duration made of a number and a unit among ms, s, m and h,
+/// seconds if there's no unit
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration {}, expected like 60s", s))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 3600)),
+        _ => Err(format!(
+            "unknown unit {} in {}, expected ms, s, m or h",
+            unit, s
+        )),
+    }
+}
+
+/// Parse a burst as a message count and the duration they're spread over,
+/// as `100@10ms`
+fn parse_burst(s: &str) -> Result<(usize, Duration), String> {
+    let (count, window) = s
+        .split_once('@')
+        .ok_or_else(|| format!("invalid burst {}, expected like 100@10ms", s))?;
+    match count.parse() {
+        Ok(count) if count > 0 => Ok((count, parse_duration(window)?)),
+        _ => Err(format!("invalid message count {} in {}", count, s)),
+    }
+}
+
+/// Copy messages within a broker or to another one until `--duration` has
+/// elapsed or `--count` messages were copied
+fn copy(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let source = broker_addrs(matches, None, timeout)?;
+    let dest = match matches.get_one::<String>("dest-host") {
+        Some(host) => {
+            let port = *matches.get_one::<u16>("dest-port").unwrap_or(&DEFAULT_PORT);
+            let addrs: Vec<SocketAddr> = host
+                .to_socket_addrs()
+                .or_else(|_| (host.as_str(), port).to_socket_addrs())?
+                .collect();
+            Some(addrs)
+        }
+        None => None,
+    };
+    let mut rewrites: Vec<RewriteRule> = matches
+        .get_many::<RewriteRule>("rewrite")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    if let Some(prefix) = matches.get_one::<String>("to-prefix") {
+        rewrites.push(format!("# -> {}/#", prefix.trim_end_matches('/')).parse()?);
+    }
+    let topic_template = relay_template(matches)?;
+    if dest.is_none() && rewrites.is_empty() && topic_template.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Copying within the same broker needs --to-prefix, --rewrite or --topic-template",
+        ));
+    }
+    let options = CopyOptions {
+        filters: matches
+            .get_many::<String>("from")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        rewrites,
+        topic_template,
+        qos: Qos::from(*matches.get_one::<u8>("qos").unwrap_or(&1)),
+        duration: matches.get_one::<Duration>("duration").copied(),
+        count: matches.get_one::<u64>("count").copied(),
+        ..CopyOptions::default()
+    };
+
+    let client_id = client_id(matches);
+    let connect = |addrs: &[SocketAddr]| {
+        Protocol::builder()
+            .addrs(addrs)
+            .timeout(timeout)
+            .client_id(client_id)
+            .clean_session(clean_session(matches))
+            .connect()
+    };
+    let dest = dest.as_deref().map(connect).transpose()?;
+    let stats = bridge::copy(connect(&source)?, dest, options)?;
+    eprintln!(
+        "Copied {} messages, dropped {} echoes",
+        stats.outgoing, stats.looped
+    );
+    print_skipped(&stats);
+    Ok(())
+}
+
+fn bench(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    match matches.subcommand() {
+        Some(("storm", matches)) => {
+            let defaults = StormOptions::default();
+            let options = StormOptions {
+                addrs: broker_addrs(matches, None, timeout)?,
+                connections: *matches
+                    .get_one::<usize>("connections")
+                    .unwrap_or(&defaults.connections),
+                concurrency: *matches
+                    .get_one::<usize>("concurrency")
+                    .unwrap_or(&defaults.concurrency),
+                client_id_prefix: matches
+                    .get_one::<String>("client-id-prefix")
+                    .cloned()
+                    .unwrap_or(defaults.client_id_prefix),
+                random_ids: matches.get_flag("random-ids"),
+                subscribers: *matches.get_one::<usize>("subscribers").unwrap_or(&0),
+                publishers: *matches.get_one::<usize>("publishers").unwrap_or(&0),
+                topic: matches
+                    .get_one::<String>("topic")
+                    .cloned()
+                    .unwrap_or(defaults.topic),
+                hold: matches
+                    .get_one::<u64>("hold")
+                    .map_or(defaults.hold, |secs| Duration::from_secs(*secs)),
+                waves: matches
+                    .get_one::<u64>("waves")
+                    .map_or(defaults.waves, |waves| *waves as usize),
+                wave_interval: matches
+                    .get_one::<u64>("wave-interval")
+                    .map_or(defaults.wave_interval, |ms| Duration::from_millis(*ms)),
+                consume_delay: matches
+                    .get_one::<u64>("consume-delay")
+                    .map_or(Duration::ZERO, |ms| Duration::from_millis(*ms)),
+                recv_buffer: matches.get_one::<usize>("recv-buffer").copied(),
+                timeout,
+            };
+            let report = bench::storm(&options);
+            print_report(matches, &report, &report.export())
+        }
+        Some(("latency", matches)) => {
+            let defaults = LatencyOptions::default();
+            let quic = matches.get_one::<QuicUrl>("quic").cloned();
+            let options = LatencyOptions {
+                addrs: match quic {
+                    Some(_) => vec![],
+                    None => broker_addrs(matches, None, timeout)?,
+                },
+                quic,
+                quic_ca: matches.get_one::<PathBuf>("quic-ca").cloned(),
+                client_id_prefix: matches
+                    .get_one::<String>("client-id-prefix")
+                    .cloned()
+                    .unwrap_or(defaults.client_id_prefix),
+                publishers: *matches
+                    .get_one::<usize>("publishers")
+                    .unwrap_or(&defaults.publishers),
+                subscribers: *matches
+                    .get_one::<usize>("subscribers")
+                    .unwrap_or(&defaults.subscribers),
+                messages: *matches
+                    .get_one::<usize>("messages")
+                    .unwrap_or(&defaults.messages),
+                size: matches
+                    .get_one::<u64>("size")
+                    .map_or(defaults.size, |size| *size as usize),
+                payload: matches
+                    .get_one::<PayloadKind>("payload-kind")
+                    .copied()
+                    .unwrap_or(defaults.payload),
+                seed: *matches.get_one::<u64>("seed").unwrap_or(&defaults.seed),
+                qos: *matches.get_one::<u8>("qos").unwrap_or(&defaults.qos),
+                topic: matches
+                    .get_one::<String>("topic")
+                    .cloned()
+                    .unwrap_or(defaults.topic),
+                rate: matches.get_one::<u32>("rate").copied(),
+                burst: matches
+                    .get_one::<(usize, Duration)>("burst")
+                    .map(|&(messages, window)| Burst {
+                        messages,
+                        window,
+                        idle: matches
+                            .get_one::<Duration>("idle")
+                            .copied()
+                            .unwrap_or_default(),
+                    }),
+                timeout,
+            };
+            let report = bench::latency(&options)?;
+            print_report(matches, &report, &report.export())
+        }
+        Some(("scenario", matches)) => {
+            let path = matches.get_one::<PathBuf>("FILE").unwrap();
+            let mut scenario = Scenario::from_toml(&std::fs::read_to_string(path)?)?;
+            scenario.addrs = broker_addrs(matches, None, timeout)?;
+            if let Some(prefix) = matches.get_one::<String>("client-id-prefix") {
+                scenario.client_id_prefix = prefix.clone();
+            }
+            let report = bench::run_scenario(&scenario)?;
+            print_report(matches, &report, &report.export(&scenario))?;
+            match report.failures.is_empty() {
+                true => Ok(()),
+                false => Err(io::Error::other(format!(
+                    "Scenario assertions failed: {}",
+                    report.failures.join("; ")
+                ))),
+            }
+        }
+        Some(("compare", matches)) => {
+            let read =
+                |name: &str| std::fs::read_to_string(matches.get_one::<PathBuf>(name).unwrap());
+            let comparison = bench::compare(&read("BASELINE")?, &read("CURRENT")?)?;
+            println!("{}", comparison);
+            let Some(threshold) = matches.get_one::<f64>("threshold") else {
+                return Ok(());
+            };
+            let regressions: Vec<&str> = comparison
+                .regressions(*threshold)
+                .iter()
+                .map(|d| d.field.as_str())
+                .collect();
+            match regressions.is_empty() {
+                true => Ok(()),
+                false => Err(io::Error::other(format!(
+                    "Worse by more than {}%: {}",
+                    threshold,
+                    regressions.join(", ")
+                ))),
+            }
+        }
+        _ => unreachable!("subcommand required"),
+    }
+}
+
+/// Export the retained messages of a broker to a snapshot, or import one
+/// into the same or another broker
+fn retained(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let (name, matches) = matches.subcommand().expect("subcommand required");
+    let addrs = broker_addrs(matches, None, timeout)?;
+    let client_id = client_id(matches);
+    let mut client = Protocol::builder()
+        .addrs(&addrs)
+        .timeout(timeout)
+        .client_id(client_id)
+        .clean_session(clean_session(matches))
+        .connect()?;
+    match name {
+        "export" => {
+            let filter = matches.get_one::<String>("filter").unwrap();
+            let wait = Duration::from_millis(*matches.get_one::<u64>("wait").unwrap());
+            let snapshot = snapshot::export(&mut client, filter, wait)?;
+            client.disconnect()?;
+            match matches.get_one::<PathBuf>("out") {
+                Some(path) => {
+                    std::fs::write(path, snapshot.to_json())?;
+                    println!(
+                        "{} retained messages written to {}",
+                        snapshot.messages.len(),
+                        path.display()
+                    );
+                }
+                None => print!("{}", snapshot.to_json()),
+            }
+        }
+        "import" => {
+            let path = matches.get_one::<PathBuf>("SNAPSHOT").unwrap();
+            let snapshot = Snapshot::from_json(&std::fs::read_to_string(path)?)?;
+            snapshot::import(&mut client, &snapshot, timeout)?;
+            client.disconnect()?;
+            println!("{} retained messages published", snapshot.messages.len());
+        }
+        _ => unreachable!("subcommand required"),
+    }
+    Ok(())
+}
+
+/// Record the messages matching `--topic` until interrupted or `--duration`
+/// has elapsed. The files written so far are closed with their index even
+/// if the connection fails
+fn record(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let path = matches.get_one::<PathBuf>("out").unwrap();
+    let filters: Vec<String> = matches
+        .get_many::<String>("topic")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    for filter in &filters {
+        validate_topic_filter(filter)?;
+    }
+    let options = RecordOptions {
+        compress: matches.get_flag("compress"),
+        rotate: matches.get_one::<Rotate>("rotate").copied(),
+    };
+    let mut recorder = Recorder::create(path, options)?;
+    let addrs = broker_addrs(matches, None, timeout)?;
+    let mut client = Protocol::builder()
+        .addrs(&addrs)
+        .timeout(timeout)
+        .client_id(client_id(matches))
+        .clean_session(clean_session(matches))
+        .connect()?;
+    let stop = sake::shutdown::on_terminate()?;
+    if let Some(duration) = matches.get_one::<Duration>("duration").copied() {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            thread::sleep(duration);
+            stop.store(true, Ordering::Release);
+        });
+    }
+    let recorded = record::record(&mut client, &filters, &mut recorder, &stop);
+    let paths = recorder.finish()?;
+    let recorded = recorded?;
+    client.disconnect()?;
+    let files: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    println!("{} messages recorded to {}", recorded, files.join(", "));
+    Ok(())
+}
+
+/// Publish the messages of recordings again, from `--from` or `--offset` on,
+/// those matching `--filter` only and on the topics `--remap` gives them
+fn replay(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let paths: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("RECORDING")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let filters: Vec<String> = matches
+        .get_many::<String>("filter")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    for filter in &filters {
+        validate_topic_filter(filter)?;
+    }
+    let options = ReplayOptions {
+        from: matches.get_one::<SystemTime>("from").copied(),
+        offset: matches.get_one::<Duration>("offset").copied(),
+        filters,
+        rewrites: matches
+            .get_many::<RewriteRule>("remap")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        speed: (!matches.get_flag("no-wait")).then(|| *matches.get_one::<f64>("speed").unwrap()),
+        ack_timeout: timeout,
+    };
+    let addrs = broker_addrs(matches, None, timeout)?;
+    let mut client = Protocol::builder()
+        .addrs(&addrs)
+        .timeout(timeout)
+        .client_id(client_id(matches))
+        .clean_session(clean_session(matches))
+        .connect()?;
+    let replayed = record::replay(&mut client, &paths, &options)?;
+    client.disconnect()?;
+    println!("{} messages replayed", replayed);
+    Ok(())
+}
+
+/// Print a bench report in the `--report` format, and write it to
+/// `--output` if given
+fn print_report(matches: &ArgMatches, text: &dyn fmt::Display, export: &Export) -> io::Result<()> {
+    if let Some(path) = matches.get_one::<PathBuf>("output") {
+        let contents = match path.extension().is_some_and(|ext| ext == "csv") {
+            true => export.to_csv(),
+            false => format!("{}\n", export.to_json()),
+        };
+        std::fs::write(path, contents)?;
+    }
+    match matches.get_one::<String>("report").map(|f| f.as_str()) {
+        Some("json") => println!("{}", export.to_json()),
+        Some("csv") => print!("{}", export.to_csv()),
+        _ => println!("{}", text),
+    }
+    Ok(())
+}
+
+fn broker(matches: &ArgMatches) -> io::Result<()> {
+    let listen = matches
+        .get_one::<SocketAddr>("listen")
+        .copied()
+        .unwrap_or_else(|| DEFAULT_BROKER_LISTEN.parse().unwrap());
+    let options = BrokerOptions {
+        max_retained: matches.get_one::<usize>("max-retained").copied(),
+        eviction: matches
+            .get_one::<String>("retain-eviction")
+            .map_or(Ok(Eviction::Oldest), |policy| policy.parse())?,
+        passwords: matches
+            .get_one::<String>("passwd-file")
+            .map(PasswordFile::load)
+            .transpose()?,
+        acl: matches
+            .get_one::<String>("acl-file")
+            .map(Acl::load)
+            .transpose()?,
+        persistence: matches.get_one::<PathBuf>("persist").cloned(),
+        sys_interval: match matches.get_one::<u64>("sys-interval") {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(*secs)),
+            None => Some(DEFAULT_SYS_INTERVAL),
+        },
+        workers: matches
+            .get_one::<u64>("workers")
+            .map_or_else(|| BrokerOptions::default().workers, |n| *n as usize),
+        inspect: matches.get_flag("inspect"),
+        max_packet_size: matches
+            .get_one::<u32>("max-packet-size")
+            .copied()
+            .unwrap_or(DEFAULT_MAX_PACKET_SIZE),
+    };
+    let mut broker = Broker::bind(listen, options)?;
+    eprintln!("Broker listening on {}", broker.local_addr()?);
+    if let Some(ws_listen) = matches.get_one::<SocketAddr>("ws-listen") {
+        let ws_addr = broker.bind_websocket(ws_listen)?;
+        eprintln!("Broker listening on {} for WebSocket clients", ws_addr);
+    }
+    broker.run()
+}
+
+/// A value for both directions of the proxy, or one per direction as
+/// `TO_BROKER/TO_CLIENT`
+fn both_ways<T: Copy>(
+    s: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<(T, T), String> {
+    match s.split_once('/') {
+        Some((to_broker, to_client)) => Ok((parse(to_broker)?, parse(to_client)?)),
+        None => parse(s).map(|value| (value, value)),
+    }
+}
+
+fn parse_share(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(share) if (0.0..=1.0).contains(&share) => Ok(share),
+        _ => Err(format!("invalid share {}, expected between 0 and 1", s)),
+    }
+}
+
+/// The value of a `both_ways` argument for packets to the client, or to
+/// the broker
+fn one_way<T: Copy + Send + Sync + 'static>(
+    matches: &ArgMatches,
+    name: &str,
+    to_client: bool,
+) -> Option<T> {
+    matches
+        .get_one::<(T, T)>(name)
+        .map(|&(to_broker, to_client_value)| match to_client {
+            true => to_client_value,
+            false => to_broker,
+        })
+}
+
+/// Relay clients to the broker at --host and --port through the impairments
+/// of each direction
+fn proxy(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    let impairment = |to_client| Impairment {
+        latency: one_way(matches, "latency", to_client).unwrap_or_default(),
+        jitter: one_way(matches, "jitter", to_client).unwrap_or_default(),
+        bandwidth: one_way(matches, "bandwidth", to_client),
+        reorder: one_way(matches, "reorder", to_client).unwrap_or(0.0),
+    };
+    let defaults = ProxyOptions::default();
+    let options = ProxyOptions {
+        upstream: broker_addrs(matches, None, timeout)?,
+        to_broker: impairment(false),
+        to_client: impairment(true),
+        seed: *matches.get_one::<u64>("seed").unwrap_or(&defaults.seed),
+        timeout,
+    };
+    let proxy = Proxy::bind(matches.get_one::<SocketAddr>("listen").unwrap(), options)?;
+    eprintln!("Proxy listening on {}", proxy.local_addr()?);
+    proxy.run()
+}
+
+/// Decodes a packet given as hex digits and prints its annotated wire format
+fn decode(matches: &ArgMatches) -> io::Result<()> {
+    if let Some(path) = matches.get_one::<PathBuf>("pcap") {
+        let port = *matches.get_one::<u16>("port").unwrap_or(&DEFAULT_PORT);
+        let frames = pcap::mqtt_frames(&std::fs::read(path)?, port)?;
+        if let Some(export) = matches.get_one::<PathBuf>("export") {
+            std::fs::write(export, pcap::summary_csv(&frames, port))?;
+            println!("{} packets written to {}", frames.len(), export.display());
+            return Ok(());
+        }
+        if matches.get_flag("profile") {
+            for profile in pcap::profile(&frames, port) {
+                println!("{}", profile);
+            }
+            return Ok(());
+        }
+        let start = frames.first().map_or(Duration::ZERO, |f| f.timestamp);
+        for (i, frame) in frames.iter().enumerate() {
+            println!(
+                "#{} +{:.6} s {} -> {}",
+                i + 1,
+                frame.timestamp.saturating_sub(start).as_secs_f64(),
+                frame.src,
+                frame.dst
+            );
+            print!("{}", WireDump::new(&frame.bytes));
+        }
+        return Ok(());
+    }
+    let mut hex = matches.get_one::<String>("HEX").unwrap().clone();
+    if hex == "-" {
+        hex.clear();
+        io::Read::read_to_string(&mut io::stdin(), &mut hex)?;
+    }
+    let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let bytes = digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .filter(|_| pair.len() == 2)
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid hex packet"))?;
+    print!("{}", WireDump::new(&bytes));
+    Ok(())
+}
+
+/// Lists the brokers advertised via mDNS, optionally opening the shell against
+/// the one selected with `--shell`
+fn discover(matches: &ArgMatches) -> io::Result<()> {
+    let service = matches
+        .get_one::<String>("service")
+        .map_or(DEFAULT_MDNS_SERVICE, |s| s.as_str());
+    let timeout = matches
+        .get_one::<u64>("timeout")
+        .unwrap_or(&DEFAULT_DISCOVER_TIMEOUT);
+    let brokers = discovery::browse_mdns(service, Duration::from_secs(*timeout))?;
+    if brokers.is_empty() {
+        eprintln!("No broker found for {}", service);
+    }
+    for (i, broker) in brokers.iter().enumerate() {
+        let addrs: Vec<String> = broker.addrs.iter().map(|a| a.to_string()).collect();
+        println!(
+            "[{}] {} {}:{} [{}] {}",
+            i,
+            broker.name,
+            broker.host,
+            broker.port,
+            addrs.join(", "),
+            broker.txt.join(" ")
+        );
+    }
+    if let Some(index) = matches.get_one::<usize>("shell") {
+        let broker = brokers.get(*index).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no broker at index {}", index),
+            )
+        })?;
+        let mut addrs = broker.socket_addrs();
+        if addrs.is_empty() {
+            addrs = (broker.host.as_str(), broker.port)
+                .to_socket_addrs()?
+                .collect();
+        }
+        repl(Some(&addrs)).map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+fn sn(matches: &ArgMatches) -> io::Result<()> {
+    let timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT);
+    match matches.subcommand() {
+        Some(("publish", matches)) => {
+            let host = matches
+                .get_one::<String>("host")
+                .map_or(DEFAULT_HOSTNAME, |h| h.as_str());
+            let port = *matches.get_one::<u16>("port").unwrap_or(&DEFAULT_SN_PORT);
+            let gateway = (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no gateway address found")
+            })?;
+            let topic = matches.get_one::<String>("topic").unwrap();
+            let message = matches.get_one::<String>("message").unwrap();
+            let qos = *matches.get_one::<u8>("qos").unwrap_or(&1);
+            let client_id = client_id(matches);
+            let mut client = SnClient::connect(gateway, timeout)?;
+            client.handshake(client_id, clean_session(matches), 60)?;
+            let topic_id = client.register(topic)?;
+            println!("REGACK {} {}", topic_id, topic);
+            client.publish(topic_id, message.as_bytes(), qos)?;
+            println!("PUBLISH {} {}", topic_id, qos);
+            client.disconnect()
+        }
+        Some(("gateway", matches)) => {
+            let listen = matches
+                .get_one::<SocketAddr>("listen")
+                .copied()
+                .unwrap_or_else(|| DEFAULT_SN_LISTEN.parse().unwrap());
+            let broker = broker_addrs(matches, None, timeout)?;
+            let mut gateway = Gateway::bind(listen, broker, timeout)?;
+            gateway.set_rewrites(
+                matches
+                    .get_many::<RewriteRule>("rewrite")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+            );
+            gateway.set_verbose(matches.get_flag("verbose"));
+            eprintln!("MQTT-SN gateway listening on {}", gateway.local_addr()?);
+            gateway.run()
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// State of an interactive shell. Every command opens its own connection,
+/// so the session tracks the last broker used and totals across connections
+#[derive(Default)]
+struct Session {
+    broker: Option<SocketAddr>,
+    client_id: Option<String>,
+    protocol_level: Option<u8>,
+    keepalive: Option<Duration>,
+    last_activity: Option<Instant>,
+    connections: u64,
+    stats: StatsSnapshot,
+    /// Variables defined with `set`, expanded as `$NAME` or `${NAME}`
+    vars: HashMap<String, String>,
+    stop_on_error: bool,
+    /// Nesting of `source` commands, bounded to catch files sourcing themselves
+    depth: usize,
+}
+
+impl Session {
+    fn record(&mut self, exchange: Exchange) {
+        self.broker = exchange.broker;
+        self.client_id = Some(exchange.client_id);
+        self.protocol_level = Some(exchange.protocol_level);
+        self.keepalive = Some(exchange.keepalive);
+        self.last_activity = Some(Instant::now());
+        self.connections += 1;
+        self.stats += exchange.stats;
+    }
+
+    fn status(&self) -> String {
+        let broker = self
+            .broker
+            .map_or("none yet".to_string(), |b| b.to_string());
+        let activity = self
+            .last_activity
+            .map_or("no activity yet".to_string(), |t| {
+                format!("last activity {}s ago", t.elapsed().as_secs())
+            });
+        let protocol = match self.protocol_level {
+            Some(MQTT_V5) => "MQTT 5",
+            Some(_) => "MQTT 3.1.1",
+            None => "none yet",
+        };
+        // Connections only last as long as a command, there's no live
+        // keepalive, subscription or message in flight to report
+        let keepalive = self
+            .keepalive
+            .map_or("none yet".to_string(), |k| format!("{}s", k.as_secs()));
+        format!(
+            "broker:    {}\n\
+             client id: {}\n\
+             protocol:  {}\n\
+             keepalive: {}, connections close after each command ({})",
+            broker,
+            self.client_id.as_deref().unwrap_or(default_client_id()),
+            protocol,
+            keepalive,
+            activity
+        )
+    }
+
+    fn stats(&self) -> String {
+        format!("connections:      {}\n{}", self.connections, self.stats)
+    }
+
+    /// Replace `$NAME` and `${NAME}` with shell variables, falling back to
+    /// the environment. `$$` is a literal `$`
+    fn expand(&self, line: &str) -> Result<String, String> {
+        let mut out = String::with_capacity(line.len());
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                out.push(c);
+                continue;
+            }
+            let braced = chars.next_if_eq(&'{').is_some();
+            if !braced && chars.next_if_eq(&'$').is_some() {
+                out.push('$');
+                continue;
+            }
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+            if braced && chars.next() != Some('}') {
+                return Err(format!("error: Unterminated ${{{}", name));
+            }
+            if name.is_empty() {
+                out.push('$');
+                continue;
+            }
+            match self
+                .vars
+                .get(&name)
+                .cloned()
+                .or_else(|| std::env::var(&name).ok())
+            {
+                Some(value) => out.push_str(&value),
+                None => return Err(format!("error: Undefined variable {}", name)),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Runs the shell commands in `path` line by line, blank lines and lines
+/// starting with `#` are skipped. Returns whether a `quit` was executed and
+/// the number of commands that failed
+fn run_script(
+    path: &str,
+    target: Option<&[SocketAddr]>,
+    session: &mut Session,
+) -> Result<(bool, usize), String> {
+    if session.depth >= MAX_SOURCE_DEPTH {
+        return Err(format!("error: {} nested too deeply", path));
+    }
+    let script = std::fs::read_to_string(path).map_err(|e| format!("error: {}: {}", path, e))?;
+    session.depth += 1;
+    let mut failures = 0;
+    let mut quit = false;
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        println!("$ {}", line);
+        match respond(line, target, session) {
+            Ok(true) => {
+                quit = true;
+                break;
+            }
+            Ok(false) => {}
+            Err(err) => {
+                failures += 1;
+                eprintln!("{}:{}: {}", path, number + 1, err.trim_end());
+                if session.stop_on_error {
+                    session.depth -= 1;
+                    return Err(format!("error: {} aborted", path));
+                }
+            }
+        }
+    }
+    session.depth -= 1;
+    Ok((quit, failures))
+}
+
+fn repl(target: Option<&[SocketAddr]>) -> Result<(), String> {
+    if let Some(addrs) = target {
+        let addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+        println!("Using broker {}", addrs.join(", "));
+    }
+    let mut session = Session::default();
+    while let Some(line) = readline()? {
         let line = line.trim();
         if line.is_empty() {
             continue;
         }
 
-        match respond(line) {
+        match respond(line, target, &mut session) {
             Ok(quit) => {
                 if quit {
                     break;
@@ -66,8 +4868,17 @@ fn repl() -> Result<(), String> {
     Ok(())
 }
 
-fn respond(line: &str) -> Result<bool, String> {
-    let args = shlex::split(line).ok_or("error: Invalid quoting")?;
+fn respond(
+    line: &str,
+    target: Option<&[SocketAddr]>,
+    session: &mut Session,
+) -> Result<bool, String> {
+    let line = session.expand(line)?;
+    let mut args = shlex::split(&line).ok_or("error: Invalid quoting")?;
+    // Commands can be typed with or without the program name in front
+    if args.first().map(|a| a.as_str()) != Some("sake") {
+        args.insert(0, "sake".to_string());
+    }
     let matches = cli()
         .try_get_matches_from(args)
         .map_err(|e| e.to_string())?;
@@ -81,67 +4892,126 @@ fn respond(line: &str) -> Result<bool, String> {
             std::io::stdout().flush().map_err(|e| e.to_string())?;
             return Ok(true);
         }
-        Some((name, _matches)) => unimplemented!("{}", name),
+        Some(("set", matches)) => {
+            let args: Vec<String> = matches
+                .get_many::<std::ffi::OsString>("")
+                .into_iter()
+                .flatten()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect();
+            match args.as_slice() {
+                [name, value @ ..] if !name.is_empty() => {
+                    session.vars.insert(name.clone(), value.join(" "));
+                }
+                _ => return Err("usage: set NAME VALUE".to_string()),
+            }
+        }
+        Some(("source", matches)) => {
+            let path = matches
+                .get_many::<std::ffi::OsString>("")
+                .into_iter()
+                .flatten()
+                .next()
+                .ok_or("usage: source FILE")?
+                .to_string_lossy()
+                .into_owned();
+            let (quit, failures) = run_script(&path, target, session)?;
+            if failures > 0 {
+                return Err(format!("error: {} commands failed in {}", failures, path));
+            }
+            return Ok(quit);
+        }
+        Some(("status", _matches)) => println!("{}", session.status()),
+        Some(("stats", _matches)) => println!("{}", session.stats()),
+        Some(("publish", matches)) => {
+            if let Some(exchange) = publish(matches, target).map_err(|e| e.to_string())? {
+                session.record(exchange);
+            }
+        }
+        Some((name, _matches)) => {
+            return Err(format!("error: {} is unsupported in the shell", name))
+        }
         None => unreachable!("subcommand required"),
     }
 
     Ok(false)
 }
 
-fn readline() -> Result<String, String> {
+/// Prompt for a line, `None` once stdin is closed
+fn readline() -> Result<Option<String>, String> {
     write!(std::io::stdout(), "$ ").map_err(|e| e.to_string())?;
     std::io::stdout().flush().map_err(|e| e.to_string())?;
     let mut buffer = String::new();
-    std::io::stdin()
+    let read = std::io::stdin()
         .read_line(&mut buffer)
         .map_err(|e| e.to_string())?;
-    Ok(buffer)
+    Ok((read > 0).then_some(buffer))
 }
 
-fn main() -> std::io::Result<()> {
+fn main() {
     let matches = cli().get_matches();
+    if let Err(err) = run(&matches) {
+        fail(&err, &matches);
+    }
+}
 
+fn run(matches: &ArgMatches) -> io::Result<()> {
     match matches.subcommand() {
-        Some(("shell", _)) => repl().unwrap(),
-        Some(("publish", sub_matches)) => {
-            let default_hostname = DEFAULT_HOSTNAME.to_string();
-            let default_cid = DEFAULT_CLIENT_ID.to_string();
-            let host = sub_matches
-                .get_one::<String>("host")
-                .unwrap_or(&default_hostname);
-            let topic = sub_matches.get_one::<String>("topic").unwrap();
-            let message = sub_matches.get_one::<String>("message").unwrap();
-            let client_id = sub_matches
-                .get_one::<String>("client_id")
-                .unwrap_or(&default_cid);
-            let request = Request::Connect {
-                client_id: client_id.into(),
-                clean_session: false,
-            };
-            Protocol::connect(format!("{}:1883", host).parse().unwrap())
-                .and_then(|mut client| {
-                    client.send_message(&request)?;
-                    Ok(client)
-                })
-                .and_then(|mut client| Ok((client.read_message::<Response>(), client)))
-                .and_then(|(resp, mut client)| {
-                    println!("{}", resp?);
-                    let pub_req = Request::Publish {
-                        packet_id: 1,
-                        qos: 1,
-                        topic: topic.to_string(),
-                        payload: message.as_bytes().to_vec(),
-                    };
-                    client.send_message(&pub_req)?;
-                    Ok(client)
-                })
-                .and_then(|mut client| Ok((client.read_message::<Response>(), client)))
-                .and_then(|(resp, mut client)| {
-                    println!("{}", resp?);
-                    client.disconnect()
-                })?;
+        Some(("shell", sub_matches)) => match sub_matches.get_one::<String>("script") {
+            Some(path) => {
+                let mut session = Session {
+                    stop_on_error: sub_matches.get_flag("stop-on-error"),
+                    ..Session::default()
+                };
+                match run_script(path, None, &mut session) {
+                    Ok((_, 0)) => {}
+                    Ok((_, failures)) => {
+                        return Err(io::Error::other(format!("{} commands failed", failures)))
+                    }
+                    Err(err) => return Err(io::Error::other(err)),
+                }
+            }
+            None => repl(None).map_err(io::Error::other)?,
+        },
+        Some(("healthcheck", sub_matches)) => {
+            let client_id = client_id(sub_matches);
+            let timeout = sub_matches
+                .get_one::<u64>("timeout")
+                .unwrap_or(&DEFAULT_HEALTHCHECK_TIMEOUT);
+            let report = healthcheck(
+                sub_matches,
+                client_id,
+                sub_matches.get_flag("probe"),
+                Duration::from_secs(*timeout),
+            );
+            println!("{}", report.to_json());
+            std::process::exit(report.status as i32);
         }
-        _ => unreachable!(),
+        Some(("info", sub_matches)) => info(sub_matches)?,
+        Some(("will-test", sub_matches)) => will_test(sub_matches)?,
+        Some(("publish", sub_matches)) => publish(sub_matches, None).map(|_| ())?,
+        Some(("subscribe", sub_matches)) => subscribe(sub_matches)?,
+        Some(("diff", sub_matches)) => diff(sub_matches)?,
+        Some(("stats", sub_matches)) => stats(sub_matches)?,
+        Some(("bridge", sub_matches)) => bridge(sub_matches)?,
+        Some(("queue", sub_matches)) => queue(sub_matches)?,
+        Some(("copy", sub_matches)) => copy(sub_matches)?,
+        Some(("bench", sub_matches)) => bench(sub_matches)?,
+        Some(("retained", sub_matches)) => retained(sub_matches)?,
+        Some(("record", sub_matches)) => record(sub_matches)?,
+        Some(("replay", sub_matches)) => replay(sub_matches)?,
+        Some(("broker", sub_matches)) => broker(sub_matches)?,
+        Some(("proxy", sub_matches)) => proxy(sub_matches)?,
+        Some(("discover", sub_matches)) => discover(sub_matches)?,
+        Some(("decode", sub_matches)) => decode(sub_matches)?,
+        Some(("sn", sub_matches)) => sn(sub_matches)?,
+        Some((name, _)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown command {}", name),
+            ))
+        }
+        None => unreachable!("subcommand required"),
     }
 
     Ok(())
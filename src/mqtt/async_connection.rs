@@ -0,0 +1,349 @@
+use crate::mqtt::{
+    AsyncTransport, Deserialize, Request, Response, Serialize, TransportError, VarInt,
+};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+/// Bytes requested from the transport at each read
+const READ_CHUNK: usize = 4096;
+
+/// Encoded requests held before `poll_ready` waits for the transport to
+/// take them, the backpressure of the connection
+const WRITE_HIGH_WATER: usize = 64 * 1024;
+
+fn invalid_data(err: TransportError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// A connection driven by an async runtime over an `AsyncTransport`. With
+/// the async feature it is a futures `Stream` of the responses received and
+/// a `Sink` of the requests to send, so it composes with their combinators.
+/// Only packets are moved, acks and keep alive are up to the caller
+#[derive(Debug)]
+pub struct AsyncConnection<T> {
+    transport: T,
+    read_buf: Vec<u8>,
+    /// Start of the bytes not decoded yet
+    start: usize,
+    write_buf: Vec<u8>,
+    /// Bytes of `write_buf` the transport already took
+    written: usize,
+    max_packet_size: Option<u32>,
+    eof: bool,
+}
+
+impl<T: AsyncTransport> AsyncConnection<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            read_buf: Vec::new(),
+            start: 0,
+            write_buf: Vec::new(),
+            written: 0,
+            max_packet_size: None,
+            eof: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// The transport, dropping the bytes buffered in either direction
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Reject incoming packets larger than `max` bytes, fixed header
+    /// included, before their payload is buffered
+    pub fn set_max_packet_size(&mut self, max: Option<u32>) {
+        self.max_packet_size = max;
+    }
+
+    /// Poll for the next response, `None` once the broker closed the
+    /// connection between two packets
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Response>>> {
+        loop {
+            match self.next_buffered() {
+                Ok(Some(response)) => return Poll::Ready(Some(Ok(response))),
+                Ok(None) => {}
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+            if self.eof {
+                return Poll::Ready(None);
+            }
+            // Reclaim the space of the packets already decoded
+            if self.start > 0 {
+                self.read_buf.drain(..self.start);
+                self.start = 0;
+            }
+            let mut chunk = [0; READ_CHUNK];
+            match ready!(Pin::new(&mut self.transport).poll_read(cx, &mut chunk)) {
+                Ok(0) => {
+                    self.eof = true;
+                    if !self.read_buf.is_empty() {
+                        return Poll::Ready(Some(Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Connection closed in the middle of a packet",
+                        ))));
+                    }
+                }
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+
+    /// Decode the next packet if it is completely buffered. Its size is
+    /// checked as soon as the fixed header arrives
+    fn next_buffered(&mut self) -> io::Result<Option<Response>> {
+        let buffered = &self.read_buf[self.start..];
+        let Some((_, rest)) = buffered.split_first() else {
+            return Ok(None);
+        };
+        let Some((len, size)) = VarInt::decode(rest).map_err(invalid_data)? else {
+            return Ok(None);
+        };
+        let len = 1 + size + len.value() as usize;
+        if let Some(max) = self.max_packet_size.filter(|&max| len > max as usize) {
+            return Err(invalid_data(TransportError::PacketTooLarge {
+                size: len,
+                max,
+            }));
+        }
+        if buffered.len() < len {
+            return Ok(None);
+        }
+        let response = Response::from_slice(&buffered[..len]);
+        self.start += len;
+        response.map(Some)
+    }
+
+    /// Ready once there's room to `queue` a request, writing out the ones
+    /// held past the high water mark
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write_buf.len() - self.written < WRITE_HIGH_WATER {
+            return Poll::Ready(Ok(()));
+        }
+        self.poll_flush(cx)
+    }
+
+    /// Encode `request` after those waiting to be written, nothing is sent
+    /// before `poll_flush`
+    pub fn queue(&mut self, request: &Request) -> io::Result<()> {
+        if self.written == self.write_buf.len() {
+            self.write_buf.clear();
+            self.written = 0;
+        }
+        let len = self.write_buf.len();
+        if let Err(e) = request.serialize(&mut self.write_buf) {
+            // A request refused half way leaves nothing behind
+            self.write_buf.truncate(len);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Write the queued requests out and flush the transport
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.write_buf.len() {
+            let pending = &self.write_buf[self.written..];
+            match ready!(Pin::new(&mut self.transport).poll_write(cx, pending)) {
+                Ok(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        Pin::new(&mut self.transport).poll_flush(cx)
+    }
+
+    /// Flush the queued requests, then close the writing direction
+    pub fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_flush(cx))?;
+        Pin::new(&mut self.transport).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncTransport> futures_core::Stream for AsyncConnection<T> {
+    type Item = io::Result<Response>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncTransport> futures_sink::Sink<Request> for AsyncConnection<T> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, request: Request) -> io::Result<()> {
+        self.get_mut().queue(&request)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod async_connection_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+    use crate::mqtt::{Qos, SubscriptionTopic};
+    use crate::testing::{duplex, Duplex};
+    use futures::executor::block_on;
+    use std::future::poll_fn;
+    use std::io::Write;
+
+    fn connect(client_id: &str) -> Request {
+        Request::Connect {
+            client_id: client_id.to_string(),
+            clean_session: true,
+            username: None,
+            password: None,
+            keepalive: 60,
+            will: None,
+        }
+    }
+
+    fn publish(packet_id: u16, topic: &str, payload: &[u8]) -> Request {
+        Request::Publish {
+            packet_id,
+            qos: 1,
+            dup: false,
+            retain: false,
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    /// A connection to a broker serving the other end of a duplex
+    fn broker() -> io::Result<AsyncConnection<Duplex>> {
+        let (client, server) = duplex();
+        let broker = Broker::new(BrokerOptions::default())?;
+        broker.serve_transport(server)?;
+        std::thread::spawn(move || broker.run());
+        Ok(AsyncConnection::new(client))
+    }
+
+    #[test]
+    fn test_async_connection() -> io::Result<()> {
+        let mut connection = broker()?;
+        block_on(async {
+            let subscribe = Request::Subscribe {
+                packet_id: 1,
+                subscription_topics: vec![SubscriptionTopic {
+                    qos: Qos::AtLeastOnce,
+                    topic: "async".to_string(),
+                }],
+            };
+            for request in [connect("async"), subscribe] {
+                poll_fn(|cx| connection.poll_ready(cx)).await?;
+                connection.queue(&request)?;
+            }
+            poll_fn(|cx| connection.poll_flush(cx)).await?;
+            let connack = poll_fn(|cx| connection.poll_recv(cx)).await.unwrap()?;
+            assert!(matches!(connack, Response::Connack { return_code: 0, .. }));
+            let suback = poll_fn(|cx| connection.poll_recv(cx)).await.unwrap()?;
+            assert!(matches!(suback, Response::Suback { packet_id: 1, .. }));
+            // Refused requests leave the queue as it was
+            let invalid = Request::Publish {
+                packet_id: 2,
+                qos: 3,
+                dup: false,
+                retain: false,
+                topic: "async".to_string(),
+                payload: vec![],
+            };
+            assert!(connection.queue(&invalid).is_err());
+            connection.queue(&publish(2, "async", b"polled"))?;
+            poll_fn(|cx| connection.poll_flush(cx)).await?;
+            let (mut received, mut acked) = (false, false);
+            while !(received && acked) {
+                match poll_fn(|cx| connection.poll_recv(cx)).await.unwrap()? {
+                    Response::Publish { payload, .. } => received = payload == b"polled",
+                    Response::Puback { packet_id: 2 } => acked = true,
+                    response => panic!("Unexpected response {}", response),
+                }
+            }
+            poll_fn(|cx| connection.poll_close(cx)).await?;
+            // The broker closes its end once ours is shut down
+            assert!(poll_fn(|cx| connection.poll_recv(cx)).await.is_none());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_truncated_packet() -> io::Result<()> {
+        let (client, mut server) = duplex();
+        let mut connection = AsyncConnection::new(client);
+        connection.set_max_packet_size(Some(16));
+        block_on(async {
+            // A PINGRESP, then a PUBLISH cut short by the end of the stream
+            server.write_all(&[0xD0, 0, 0x30, 9, 0, 1])?;
+            drop(server);
+            let pingresp = poll_fn(|cx| connection.poll_recv(cx)).await.unwrap()?;
+            assert!(matches!(pingresp, Response::Pingresp));
+            let err = poll_fn(|cx| connection.poll_recv(cx)).await.unwrap();
+            assert_eq!(err.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+            assert!(poll_fn(|cx| connection.poll_recv(cx)).await.is_none());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_packet_too_large() -> io::Result<()> {
+        let (client, mut server) = duplex();
+        let mut connection = AsyncConnection::new(client);
+        connection.set_max_packet_size(Some(16));
+        // Refused on the fixed header, before the payload arrives
+        server.write_all(&[0x30, 100])?;
+        let err = block_on(poll_fn(|cx| connection.poll_recv(cx))).unwrap();
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_stream_and_sink() -> io::Result<()> {
+        use futures::{stream, SinkExt, StreamExt};
+        let mut connection = broker()?;
+        block_on(async {
+            connection.send(connect("streamed")).await?;
+            let publishes = (1..=3).map(|id| Ok(publish(id, "streamed", &[id as u8])));
+            connection.send_all(&mut stream::iter(publishes)).await?;
+            let acks: Vec<u16> = connection
+                .by_ref()
+                .skip(1)
+                .take(3)
+                .map(|response| match response {
+                    Ok(Response::Puback { packet_id }) => packet_id,
+                    response => panic!("Unexpected response {:?}", response),
+                })
+                .collect()
+                .await;
+            assert_eq!(acks, vec![1, 2, 3]);
+            connection.close().await?;
+            assert!(connection.next().await.is_none());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,86 @@
+use crate::mqtt::{AckType, Protocol, Qos, Response};
+use std::collections::HashMap;
+use std::io;
+
+impl Protocol {
+    /// Publish every message with a single write, then wait for all of their
+    /// acknowledgements, completing the QoS 2 handshakes as PUBRECs arrive.
+    /// Returns the packet ids in the order of `messages`, 0 for QoS 0.
+    ///
+    /// Throughput is bound by the bandwidth rather than by one round trip
+    /// per message. As with `publish_with`, other packets received meanwhile
+    /// are discarded
+    pub fn publish_batch(&mut self, messages: &[(&str, &[u8], Qos)]) -> io::Result<Vec<u16>> {
+        let packet_ids = self.writer.publish_batch(messages)?;
+        let mut pending: HashMap<u16, Qos> = packet_ids
+            .iter()
+            .zip(messages)
+            .filter(|(_, (_, _, qos))| *qos != Qos::AtMostOnce)
+            .map(|(&packet_id, &(_, _, qos))| (packet_id, qos))
+            .collect();
+        while !pending.is_empty() {
+            match self.read_message::<Response>()? {
+                Response::Puback { packet_id }
+                    if pending.get(&packet_id) == Some(&Qos::AtLeastOnce) =>
+                {
+                    pending.remove(&packet_id);
+                }
+                Response::Pubrec { packet_id }
+                    if pending.get(&packet_id) == Some(&Qos::ExactlyOnce) =>
+                {
+                    self.ack(AckType::Pubrel(packet_id))?;
+                }
+                Response::Pubcomp { packet_id }
+                    if pending.get(&packet_id) == Some(&Qos::ExactlyOnce) =>
+                {
+                    pending.remove(&packet_id);
+                }
+                _ => {}
+            }
+        }
+        Ok(packet_ids)
+    }
+}
+
+#[cfg(test)]
+mod batch_tests {
+    use super::*;
+    use crate::testing::duplex;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    #[test]
+    fn test_publish_batch() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        let broker = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut received = vec![0; 22];
+            stream.read_exact(&mut received)?;
+            // PUBREC for the QoS 2 message and PUBACK for the QoS 1 one
+            stream.write_all(&[0x50, 2, 0, 2, 0x40, 2, 0, 1])?;
+            let mut pubrel = vec![0; 4];
+            stream.read_exact(&mut pubrel)?;
+            received.extend(pubrel);
+            stream.write_all(&[0x70, 2, 0, 2])?;
+            Ok(received)
+        });
+
+        let mut client = Protocol::with_transport(client)?;
+        let packet_ids = client.publish_batch(&[
+            ("a", b"1", Qos::AtLeastOnce),
+            ("b", b"2", Qos::AtMostOnce),
+            ("c", b"3", Qos::ExactlyOnce),
+        ])?;
+        assert_eq!(packet_ids, &[1, 0, 2]);
+        assert_eq!(client.stats().packets_sent, 4);
+        assert_eq!(
+            broker.join().unwrap()?,
+            &[
+                0x32, 6, 0, 1, b'a', 0, 1, b'1', // QoS 1
+                0x30, 4, 0, 1, b'b', b'2', // QoS 0
+                0x34, 6, 0, 1, b'c', 0, 2, b'3', // QoS 2
+                0x62, 2, 0, 2,
+            ]
+        );
+        Ok(())
+    }
+}
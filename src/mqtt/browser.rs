@@ -0,0 +1,175 @@
+use crate::mqtt::AsyncTransport;
+use js_sys::{ArrayBuffer, Uint8Array};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+/// Subprotocol brokers expect MQTT over WebSocket to ask for
+const MQTT_SUBPROTOCOL: &str = "mqtt";
+
+fn js_error(action: &str, err: JsValue) -> io::Error {
+    let reason = err.as_string().unwrap_or_else(|| format!("{:?}", err));
+    io::Error::other(format!("{}: {}", action, reason))
+}
+
+/// What the event handlers of the socket saw
+#[derive(Default)]
+struct State {
+    incoming: VecDeque<u8>,
+    open: bool,
+    closed: bool,
+    failed: bool,
+    /// Tasks waiting for the socket to open or for bytes to arrive
+    wakers: Vec<Waker>,
+}
+
+impl State {
+    fn wake(&mut self) {
+        self.wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+/// MQTT over the WebSocket of a browser, for an `AsyncConnection` in a
+/// page. Needs the wasm feature and the wasm32-unknown-unknown target, with
+/// `cargo build --lib` since the command line tool needs sockets and
+/// threads. Writes before the socket opens wait for it
+pub struct BrowserTransport {
+    socket: WebSocket,
+    state: Rc<RefCell<State>>,
+    // Called by the socket as long as it lives
+    _on_open: Closure<dyn FnMut(Event)>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+    _on_error: Closure<dyn FnMut(Event)>,
+}
+
+impl BrowserTransport {
+    /// Open a WebSocket to `url`, as `wss://broker.example:8884/mqtt`,
+    /// asking for the mqtt subprotocol
+    pub fn open(url: &str) -> io::Result<Self> {
+        let socket = WebSocket::new_with_str(url, MQTT_SUBPROTOCOL)
+            .map_err(|e| js_error("Invalid WebSocket URL", e))?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+        let state = Rc::new(RefCell::new(State::default()));
+        let handler = |update: fn(&mut State, JsValue)| {
+            let state = Rc::clone(&state);
+            move |event: JsValue| {
+                let mut state = state.borrow_mut();
+                update(&mut state, event);
+                state.wake();
+            }
+        };
+        let on_open = handler(|state, _| state.open = true);
+        let on_open = Closure::<dyn FnMut(Event)>::new(move |e: Event| on_open(e.into()));
+        let on_message = handler(|state, event| {
+            let data = event.unchecked_into::<MessageEvent>().data();
+            // Brokers only send binary frames, text ones are dropped
+            if let Ok(data) = data.dyn_into::<ArrayBuffer>() {
+                state.incoming.extend(Uint8Array::new(&data).to_vec());
+            }
+        });
+        let on_message =
+            Closure::<dyn FnMut(MessageEvent)>::new(move |e: MessageEvent| on_message(e.into()));
+        let on_close = handler(|state, _| state.closed = true);
+        let on_close =
+            Closure::<dyn FnMut(CloseEvent)>::new(move |e: CloseEvent| on_close(e.into()));
+        // Browsers tell nothing more about the error, a close follows
+        let on_error = handler(|state, _| state.failed = true);
+        let on_error = Closure::<dyn FnMut(Event)>::new(move |e: Event| on_error(e.into()));
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        Ok(Self {
+            socket,
+            state,
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_close: on_close,
+            _on_error: on_error,
+        })
+    }
+
+    /// Bytes sent but not handed to the network yet by the browser
+    pub fn buffered_amount(&self) -> u32 {
+        self.socket.buffered_amount()
+    }
+}
+
+impl Drop for BrowserTransport {
+    fn drop(&mut self) {
+        self.socket.set_onopen(None);
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        self.socket.set_onerror(None);
+        let _ = self.socket.close();
+    }
+}
+
+impl AsyncTransport for BrowserTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.state.borrow_mut();
+        if state.incoming.is_empty() && !buf.is_empty() {
+            if state.failed {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()));
+            }
+            if !state.closed {
+                state.wakers.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        let n = buf.len().min(state.incoming.len());
+        for (dst, src) in buf.iter_mut().zip(state.incoming.drain(..n)) {
+            *dst = src;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.state.borrow_mut();
+        if state.failed {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()));
+        }
+        if state.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if !state.open {
+            state.wakers.push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        // Each write goes out as a binary frame of its own
+        self.socket
+            .send_with_u8_array(buf)
+            .map_err(|e| js_error("WebSocket send failed", e))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// The browser sends what it buffered on its own
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// A WebSocket can't be half closed, both directions are
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.state.borrow_mut().closed = true;
+        Poll::Ready(
+            self.socket
+                .close()
+                .map_err(|e| js_error("WebSocket close failed", e)),
+        )
+    }
+}
@@ -0,0 +1,741 @@
+use crate::mqtt::connect::MQTT_V4;
+use crate::mqtt::v5::UNSUPPORTED_PROTOCOL_VERSION;
+use crate::mqtt::{
+    parse_server_reference, random_client_id, server_reference, validate_client_id, Capabilities,
+    ConnackV5, ConnectReturnCode, ConnectV5, Event, EventHook, IdleHook, PacketInterceptor,
+    Property, Protocol, RateLimit, Request, Response, Transport, Will, MQTT_V5,
+};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 1883;
+/// Followed by a random suffix when no client id is set
+const DEFAULT_CLIENT_ID_PREFIX: &str = "sake";
+const DEFAULT_KEEPALIVE: u16 = 60;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_DROP_GRACE: Duration = Duration::from_secs(1);
+
+/// The broker answered the CONNECT with a non-zero return code, carried as
+/// the inner error of an `io::Error` of kind `ConnectionRefused`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionRefused {
+    pub return_code: ConnectReturnCode,
+    /// Where MQTT 5 brokers refusing with `UseAnotherServer` or
+    /// `ServerMoved` send the client, see `parse_server_reference`
+    pub server_reference: Option<String>,
+}
+
+impl fmt::Display for ConnectionRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection refused: {}", self.return_code)?;
+        match &self.server_reference {
+            Some(reference) => write!(f, ", use {}", reference),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for ConnectionRefused {}
+
+impl From<ConnectionRefused> for io::Error {
+    fn from(err: ConnectionRefused) -> Self {
+        io::Error::new(io::ErrorKind::ConnectionRefused, err)
+    }
+}
+
+/// Configures and establishes an MQTT session, performing the whole
+/// CONNECT/CONNACK handshake:
+///
+/// ```no_run
+/// # use sake::mqtt::Protocol;
+/// let client = Protocol::builder()
+///     .host("broker.local")
+///     .client_id("sensor-1")
+///     .credentials("user", Some("secret"))
+///     .keepalive(30)
+///     .connect()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProtocolBuilder {
+    host: String,
+    port: u16,
+    addrs: Vec<SocketAddr>,
+    client_id: String,
+    username: Option<String>,
+    password: Option<String>,
+    keepalive: u16,
+    clean_session: bool,
+    will: Option<Will>,
+    timeout: Duration,
+    disconnect_on_drop: Option<Duration>,
+    max_incoming_packet_size: Option<u32>,
+    max_outgoing_packet_size: Option<u32>,
+    strict: bool,
+    rate_limit: RateLimit,
+    interceptors: Vec<Arc<dyn PacketInterceptor>>,
+    on_idle: Option<(Duration, IdleHook)>,
+    on_event: Option<EventHook>,
+    protocol_level: u8,
+    max_redirects: u8,
+}
+
+impl Default for ProtocolBuilder {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            addrs: vec![],
+            client_id: random_client_id(DEFAULT_CLIENT_ID_PREFIX),
+            username: None,
+            password: None,
+            keepalive: DEFAULT_KEEPALIVE,
+            clean_session: true,
+            will: None,
+            timeout: DEFAULT_TIMEOUT,
+            disconnect_on_drop: Some(DEFAULT_DROP_GRACE),
+            max_incoming_packet_size: None,
+            max_outgoing_packet_size: None,
+            strict: false,
+            rate_limit: RateLimit::default(),
+            interceptors: vec![],
+            on_idle: None,
+            on_event: None,
+            protocol_level: MQTT_V4,
+            max_redirects: 0,
+        }
+    }
+}
+
+impl ProtocolBuilder {
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = host.to_string();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Already resolved broker addresses, tried in order. Take precedence
+    /// over `host` and `port`
+    pub fn addrs(mut self, addrs: &[SocketAddr]) -> Self {
+        self.addrs = addrs.to_vec();
+        self
+    }
+
+    /// Defaults to `sake-` followed by a random suffix
+    pub fn client_id(mut self, client_id: &str) -> Self {
+        self.client_id = client_id.to_string();
+        self
+    }
+
+    pub fn credentials(mut self, username: &str, password: Option<&str>) -> Self {
+        self.username = Some(username.to_string());
+        self.password = password.map(|p| p.to_string());
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: u16) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    pub fn will(mut self, will: Will) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// Bounds both the TCP connection and the wait for the CONNACK
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Grace period allowed to send DISCONNECT when the client is dropped,
+    /// `None` closes the connection abruptly, triggering the will
+    pub fn disconnect_on_drop(mut self, grace: Option<Duration>) -> Self {
+        self.disconnect_on_drop = grace;
+        self
+    }
+
+    /// Largest packet accepted from the broker, fixed header included. Larger
+    /// ones fail with `TransportError::PacketTooLarge` before their payload
+    /// is allocated, the CONNACK included
+    pub fn max_incoming_packet_size(mut self, max: u32) -> Self {
+        self.max_incoming_packet_size = Some(max);
+        self
+    }
+
+    /// Largest packet sent to the broker, fixed header included. MQTT 3.1.1
+    /// brokers don't advertise a Maximum Packet Size in the CONNACK as v5
+    /// ones do, so the limit has to be configured here
+    pub fn max_outgoing_packet_size(mut self, max: u32) -> Self {
+        self.max_outgoing_packet_size = Some(max);
+        self
+    }
+
+    /// Validate every incoming packet: reserved fixed header flags, QoS 3,
+    /// zero packet ids and wildcards in PUBLISH topics fail with
+    /// `TransportError::ProtocolViolation` instead of being accepted.
+    /// Outgoing topic names and filters are checked too, see
+    /// `validate_topic_name` and `validate_topic_filter`
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Throttle the publishes of the client, see `Protocol::set_rate_limit`
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = limit;
+        self
+    }
+
+    /// Add an interceptor to the connection before the CONNECT is sent, see
+    /// `Protocol::add_interceptor`
+    pub fn interceptor(mut self, interceptor: Arc<dyn PacketInterceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Call `hook` when no message arrives for `timeout`, see
+    /// `Protocol::set_on_idle`
+    pub fn on_idle(
+        mut self,
+        timeout: Duration,
+        hook: impl Fn(Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_idle = Some((timeout, IdleHook::new(hook)));
+        self
+    }
+
+    /// Call `hook` with every event of the connection, from the CONNACK
+    /// accepting it on, and with the reconnections made by this builder
+    pub fn on_event(mut self, hook: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(EventHook::new(hook));
+        self
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(hook) = &self.on_event {
+            hook.call(&event);
+        }
+    }
+
+    /// 4 for MQTT 3.1.1, the default, or `MQTT_V5`. MQTT 5 sessions learn
+    /// the capabilities of the broker from its CONNACK, see
+    /// `Protocol::capabilities`, and carry properties on PUBLISH
+    pub fn protocol_level(mut self, level: u8) -> Self {
+        self.protocol_level = level;
+        self
+    }
+
+    /// Connect to the server a broker refusing the session with
+    /// `UseAnotherServer` or `ServerMoved` references instead, following at
+    /// most `max` redirects in a row. None are followed by default
+    pub fn follow_redirects(mut self, max: u8) -> Self {
+        self.max_redirects = max;
+        self
+    }
+
+    /// This builder pointed at the server `err` redirects to, for errors
+    /// of a CONNACK or DISCONNECT carrying a Server Reference. References
+    /// without a port keep the current one
+    pub fn redirected(&self, err: &io::Error) -> Option<ProtocolBuilder> {
+        let (host, port) = parse_server_reference(server_reference(err)?)?;
+        let port = port.unwrap_or_else(|| match self.addrs.first() {
+            Some(addr) => addr.port(),
+            None => self.port,
+        });
+        Some(self.clone().host(&host).port(port).addrs(&[]))
+    }
+
+    /// The CONNECT request this builder would send
+    pub fn connect_request(&self) -> Request {
+        Request::Connect {
+            client_id: self.client_id.clone(),
+            clean_session: self.clean_session,
+            username: self.username.clone(),
+            password: self.password.clone(),
+            keepalive: self.keepalive,
+            will: self.will.clone(),
+        }
+    }
+
+    fn connect_v5_request(&self) -> ConnectV5 {
+        ConnectV5 {
+            client_id: self.client_id.clone(),
+            clean_start: self.clean_session,
+            keepalive: self.keepalive,
+            username: self.username.clone(),
+            password: self.password.clone(),
+            will: self.will.clone(),
+            properties: vec![],
+        }
+    }
+
+    /// Connect to the broker and perform the handshake, returning a client
+    /// ready to be used or a `ConnectionRefused` error if the broker rejects
+    /// the session. Client ids no broker could accept fail with
+    /// `InvalidInput` before connecting
+    pub fn connect(self) -> io::Result<Protocol> {
+        let mut builder = self;
+        let mut redirects = 0;
+        loop {
+            match builder.connect_once() {
+                Err(e) if redirects < builder.max_redirects => match builder.redirected(&e) {
+                    Some(redirected) => builder = redirected,
+                    None => return Err(e),
+                },
+                client => return client,
+            }
+            redirects += 1;
+            builder.emit(Event::Reconnecting {
+                attempt: redirects as u32,
+            });
+        }
+    }
+
+    /// Perform the handshake over a stream the caller already opened, a
+    /// serial link or a tunnel. The host, addresses and redirects are
+    /// ignored since there is nothing to dial
+    pub fn connect_transport(self, transport: impl Transport + 'static) -> io::Result<Protocol> {
+        self.check()?;
+        self.handshake_over(Protocol::with_transport(transport)?)
+    }
+
+    fn connect_once(&self) -> io::Result<Protocol> {
+        self.check()?;
+        let client = Protocol::connect_failover(&self.resolve()?, self.timeout)?;
+        self.handshake_over(client)
+    }
+
+    /// Refuse settings no broker could accept before connecting
+    fn check(&self) -> io::Result<()> {
+        // MQTT 5 brokers assign an id to clients connecting without one,
+        // whether or not their session is to be kept
+        if !(self.protocol_level == MQTT_V5 && self.client_id.is_empty()) {
+            validate_client_id(&self.client_id, self.clean_session)?;
+        }
+        let will_properties = self
+            .will
+            .as_ref()
+            .is_some_and(|w| !w.properties().is_empty());
+        if will_properties && self.protocol_level != MQTT_V5 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Will properties need an MQTT 5 session",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Configure a freshly connected client and exchange CONNECT and CONNACK
+    fn handshake_over(&self, mut client: Protocol) -> io::Result<Protocol> {
+        for interceptor in &self.interceptors {
+            client.add_interceptor(Arc::clone(interceptor));
+        }
+        client.set_max_incoming_packet_size(self.max_incoming_packet_size);
+        client.set_max_outgoing_packet_size(self.max_outgoing_packet_size);
+        client.set_strict(self.strict);
+        client.set_rate_limit(self.rate_limit);
+        client.set_read_timeout(Some(self.timeout))?;
+        if self.protocol_level == MQTT_V5 {
+            client.send_message(&self.connect_v5_request())?;
+            let connack = match client.read_message::<ConnackV5>() {
+                // Some 3.1.1 brokers close the connection instead of answering
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => ConnackV5 {
+                    session_present: false,
+                    reason_code: UNSUPPORTED_PROTOCOL_VERSION,
+                    properties: vec![],
+                },
+                connack => connack?,
+            };
+            let return_code = match connack.reason_code {
+                0 => None,
+                // The 3.1.1 return code for an unacceptable protocol level
+                1 => Some(ConnectReturnCode::RefusedProtocolVersion),
+                reason_code => Some(ConnectReturnCode::from_reason_code(reason_code)),
+            };
+            if let Some(return_code) = return_code {
+                let server_reference = connack.properties.into_iter().find_map(|p| match p {
+                    Property::ServerReference(reference) => Some(reference),
+                    _ => None,
+                });
+                return Err(ConnectionRefused {
+                    return_code,
+                    server_reference,
+                }
+                .into());
+            }
+            client.session_present = connack.session_present;
+            let capabilities = Capabilities::from_properties(MQTT_V5, &connack.properties);
+            client.maximum_qos = capabilities.maximum_qos;
+            client.client_id = capabilities
+                .assigned_client_id
+                .clone()
+                .unwrap_or_else(|| self.client_id.clone());
+            client.capabilities = Some(capabilities);
+        } else {
+            self.handshake(&mut client)?;
+            client.client_id = self.client_id.clone();
+        }
+        client.set_read_timeout(None)?;
+        client.set_disconnect_on_drop(self.disconnect_on_drop);
+        let keepalive = match client.capabilities() {
+            Some(capabilities) => capabilities.keepalive(self.keepalive),
+            None => Duration::from_secs(self.keepalive as u64),
+        };
+        client.set_keepalive(keepalive);
+        if let Some((timeout, hook)) = &self.on_idle {
+            client.set_on_idle(*timeout, hook.clone());
+        }
+        if let Some(hook) = &self.on_event {
+            client.set_on_event(hook.clone());
+        }
+        client.emit(Event::Connected {
+            session_present: client.session_present,
+        });
+        Ok(client)
+    }
+
+    /// Connect again as `previous`, with the client id the broker assigned
+    /// it if any so that the session the broker kept is resumed
+    pub fn reconnect(&self, previous: &Protocol) -> io::Result<Protocol> {
+        let attempt = previous.reconnects() + 1;
+        self.emit(Event::Reconnecting { attempt });
+        let mut client = self.clone().client_id(previous.client_id()).connect()?;
+        client.reconnects = attempt;
+        Ok(client)
+    }
+
+    /// The MQTT 3.1.1 CONNECT and CONNACK
+    fn handshake(&self, client: &mut Protocol) -> io::Result<()> {
+        client.send_message(&self.connect_request())?;
+        match client.read_message::<Response>()? {
+            Response::Connack {
+                return_code: 0,
+                session_present,
+            } => client.session_present = session_present,
+            Response::Connack { return_code, .. } => {
+                return Err(ConnectionRefused {
+                    return_code: ConnectReturnCode::from(return_code),
+                    server_reference: None,
+                }
+                .into())
+            }
+            resp => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Expected CONNACK, received {}", resp),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        if self.addrs.is_empty() {
+            Ok((self.host.as_str(), self.port).to_socket_addrs()?.collect())
+        } else {
+            Ok(self.addrs.clone())
+        }
+    }
+
+    /// Connect with MQTT 5 to learn what the broker supports from its
+    /// CONNACK, then disconnect. Brokers refusing the protocol level are
+    /// connected to again with MQTT 3.1.1 to check that they accept the
+    /// client, and reported with protocol level 4 and the defaults
+    pub fn probe(self) -> io::Result<Capabilities> {
+        validate_client_id(&self.client_id, self.clean_session)?;
+        let mut client = Protocol::connect_failover(&self.resolve()?, self.timeout)?;
+        for interceptor in &self.interceptors {
+            client.add_interceptor(Arc::clone(interceptor));
+        }
+        client.set_read_timeout(Some(self.timeout))?;
+        client.send_message(&self.connect_v5_request())?;
+        let connack = match client.read_message::<ConnackV5>() {
+            Ok(connack) => connack,
+            // Some 3.1.1 brokers close the connection instead of answering
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => ConnackV5 {
+                session_present: false,
+                reason_code: UNSUPPORTED_PROTOCOL_VERSION,
+                properties: vec![],
+            },
+            Err(e) => return Err(e),
+        };
+        match connack.reason_code {
+            0 => {
+                client.disconnect()?;
+                Ok(Capabilities::from_properties(MQTT_V5, &connack.properties))
+            }
+            // 1 is the 3.1.1 return code for an unacceptable protocol level
+            1 | UNSUPPORTED_PROTOCOL_VERSION => {
+                drop(client);
+                self.protocol_level(MQTT_V4).connect()?.disconnect()?;
+                Ok(Capabilities::from_properties(4, &[]))
+            }
+            reason_code => Err(ConnectionRefused {
+                return_code: ConnectReturnCode::from_reason_code(reason_code),
+                server_reference: None,
+            }
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod builder_tests {
+    use super::*;
+    use crate::mqtt::Qos;
+
+    #[test]
+    fn test_connect_request() {
+        let will = Will::new("status", b"offline", Qos::AtLeastOnce, true);
+        let builder = Protocol::builder()
+            .client_id("id")
+            .credentials("user", None)
+            .keepalive(15)
+            .clean_session(false)
+            .will(will.clone());
+        match builder.connect_request() {
+            Request::Connect {
+                client_id,
+                clean_session,
+                username,
+                password,
+                keepalive,
+                will: connect_will,
+            } => {
+                assert_eq!(client_id, "id");
+                assert!(!clean_session);
+                assert_eq!(username.as_deref(), Some("user"));
+                assert_eq!(password, None);
+                assert_eq!(keepalive, 15);
+                assert_eq!(connect_will, Some(will));
+            }
+            req => panic!("Unexpected request {:?}", req),
+        }
+    }
+
+    #[test]
+    fn test_will_properties_need_v5() {
+        let will = Will {
+            delay_interval: Some(30),
+            ..Will::new("status", b"offline", Qos::AtLeastOnce, true)
+        };
+        let Err(err) = Protocol::builder().client_id("id").will(will).connect() else {
+            panic!("Connected with will properties over 3.1.1");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_connect_refused() -> io::Result<()> {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let broker = std::thread::spawn(move || -> io::Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let mut connect = [0u8; 64];
+            let _ = stream.read(&mut connect)?;
+            stream.write_all(&[0x20, 0x02, 0x00, 0x04])
+        });
+        let Err(err) = Protocol::builder().addrs(&[addr]).client_id("id").connect() else {
+            panic!("Connected despite the refusal");
+        };
+        broker.join().unwrap()?;
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            err.to_string(),
+            "Connection refused: Bad Username or Password"
+        );
+        let inner = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ConnectionRefused>())
+            .unwrap();
+        assert_eq!(inner.return_code, ConnectReturnCode::BadUserNamePassword);
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe() -> io::Result<()> {
+        use crate::broker::{Broker, BrokerOptions};
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let broker = std::thread::spawn(move || -> io::Result<Vec<u8>> {
+            let (mut stream, _) = listener.accept()?;
+            let mut connect = [0u8; 64];
+            let n = stream.read(&mut connect)?;
+            stream.write_all(&[0x20, 10, 0, 0, 7, 0x24, 1, 0x27, 0, 0, 0x10, 0])?;
+            // DISCONNECT
+            let mut disconnect = [0u8; 2];
+            stream.read_exact(&mut disconnect)?;
+            assert_eq!(disconnect, [0xE0, 0]);
+            Ok(connect[..n].to_vec())
+        });
+        let capabilities = Protocol::builder().addrs(&[addr]).client_id("id").probe()?;
+        let connect = broker.join().unwrap()?;
+        assert_eq!(connect[8], MQTT_V5);
+        assert_eq!(capabilities.protocol_level, MQTT_V5);
+        assert_eq!(capabilities.maximum_qos, Qos::AtLeastOnce);
+        assert_eq!(capabilities.maximum_packet_size, Some(4096));
+        assert!(capabilities.retain_available);
+
+        // The embedded broker only speaks 3.1.1
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let addr = broker.local_addr()?;
+        std::thread::spawn(move || broker.run());
+        let capabilities = Protocol::builder().addrs(&[addr]).client_id("id").probe()?;
+        assert_eq!(capabilities, Capabilities::from_properties(4, &[]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_v5() -> io::Result<()> {
+        use crate::mqtt::{Property, PublishOptions};
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let broker = std::thread::spawn(move || -> io::Result<Vec<u8>> {
+            let (mut stream, _) = listener.accept()?;
+            let mut connect = [0u8; 64];
+            let n = stream.read(&mut connect)?;
+            // Server Keep Alive of 5 seconds
+            stream.write_all(&[0x20, 6, 0, 0, 3, 0x13, 0, 5])?;
+            let mut publish = [0u8; 13];
+            stream.read_exact(&mut publish)?;
+            // No matching subscribers
+            stream.write_all(&[0x40, 3, 0, 1, 0x10])?;
+            Ok([&connect[..n], &publish].concat())
+        });
+        let mut client = Protocol::builder()
+            .addrs(&[addr])
+            .client_id("id")
+            .protocol_level(MQTT_V5)
+            .connect()?;
+        assert_eq!(client.protocol_level(), MQTT_V5);
+        assert_eq!(client.keepalive(), Duration::from_secs(5));
+        let properties = [Property::ResponseTopic("r".to_string())];
+        let options = PublishOptions::default();
+        assert_eq!(
+            client.publish_with_properties("a", b"x", &options, &properties)?,
+            1
+        );
+        let sent = broker.join().unwrap()?;
+        assert_eq!(sent[8], MQTT_V5);
+        assert_eq!(
+            &sent[sent.len() - 13..],
+            [0x32, 11, 0, 1, b'a', 0, 1, 4, 0x08, 0, 1, b'r', b'x']
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_assigned_client_id() -> io::Result<()> {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let broker = std::thread::spawn(move || -> io::Result<Vec<Vec<u8>>> {
+            let mut connects = vec![];
+            // Assigned Client Identifier, then the session resumed
+            let connacks: [&[u8]; 2] = [
+                &[
+                    0x20, 12, 0, 0, 9, 0x12, 0, 6, b'a', b'u', b't', b'o', b'-', b'1',
+                ],
+                &[0x20, 3, 1, 0, 0],
+            ];
+            for connack in connacks {
+                let (mut stream, _) = listener.accept()?;
+                let mut connect = [0u8; 64];
+                let n = stream.read(&mut connect)?;
+                connects.push(connect[..n].to_vec());
+                stream.write_all(connack)?;
+            }
+            Ok(connects)
+        });
+        let builder = Protocol::builder()
+            .addrs(&[addr])
+            .client_id("")
+            .clean_session(false)
+            .protocol_level(MQTT_V5);
+        let client = builder.clone().connect()?;
+        assert_eq!(client.client_id(), "auto-1");
+        let client = builder.reconnect(&client)?;
+        assert!(client.session_present());
+        assert_eq!(client.client_id(), "auto-1");
+        let connects = broker.join().unwrap()?;
+        assert!(connects[0].ends_with(&[0, 0]));
+        assert!(connects[1].ends_with(&[0, 6, b'a', b'u', b't', b'o', b'-', b'1']));
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_redirects() -> io::Result<()> {
+        use std::io::{Read, Write};
+        let moved_to = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let reference = moved_to.local_addr()?.to_string();
+        let mut connack = vec![0x20, 0, 0, 0x9D, 0, 0x1C, 0, reference.len() as u8];
+        connack.extend_from_slice(reference.as_bytes());
+        connack[1] = connack.len() as u8 - 2;
+        connack[4] = reference.len() as u8 + 3;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let read_connect = |stream: &mut std::net::TcpStream| -> io::Result<()> {
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header)?;
+            stream.read_exact(&mut vec![0; header[1] as usize])
+        };
+        std::thread::spawn(move || -> io::Result<()> {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept()?;
+                read_connect(&mut stream)?;
+                stream.write_all(&connack)?;
+            }
+            let (mut stream, _) = moved_to.accept()?;
+            read_connect(&mut stream)?;
+            stream.write_all(&[0x20, 3, 0, 0, 0])
+        });
+        let builder = Protocol::builder()
+            .addrs(&[addr])
+            .client_id("id")
+            .protocol_level(MQTT_V5);
+        let Err(err) = builder.clone().connect() else {
+            panic!("Connected despite the redirect");
+        };
+        assert_eq!(server_reference(&err), Some(reference.as_str()));
+        assert_eq!(
+            err.to_string(),
+            format!("Connection refused: Server Moved, use {}", reference)
+        );
+        builder.follow_redirects(1).connect()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_connection_refused_downcast() {
+        let err: io::Error = ConnectionRefused {
+            return_code: ConnectReturnCode::NotAuthorized,
+            server_reference: None,
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let inner = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ConnectionRefused>())
+            .unwrap();
+        assert_eq!(inner.return_code, ConnectReturnCode::NotAuthorized);
+    }
+}
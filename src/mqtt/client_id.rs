@@ -0,0 +1,93 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Longest client id every broker is required to accept, made of
+/// `0-9a-zA-Z` only, longer ones or other characters may be refused
+pub const MAX_PORTABLE_CLIENT_ID_LEN: usize = 23;
+
+/// Longest string a CONNECT can carry
+const MAX_CLIENT_ID_LEN: usize = u16::MAX as usize;
+
+/// `prefix-` followed by 8 random hex digits, so that concurrent clients
+/// sharing a prefix don't take over each other's session
+pub fn random_client_id(prefix: &str) -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    hasher.write_u128(nanos);
+    hasher.write_u32(std::process::id());
+    format!("{}-{:08x}", prefix, hasher.finish() as u32)
+}
+
+/// Whether every broker must accept the client id: 1 to 23 characters of
+/// `0-9a-zA-Z`
+pub fn is_portable_client_id(client_id: &str) -> bool {
+    (1..=MAX_PORTABLE_CLIENT_ID_LEN).contains(&client_id.len())
+        && client_id.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// Reject client ids no broker could accept: too long for a CONNECT,
+/// holding control characters MQTT strings must not carry, or empty while
+/// asking the broker to keep the session, which needs an id to find it again
+pub fn validate_client_id(client_id: &str, clean_session: bool) -> io::Result<()> {
+    let invalid = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid client id {:?}: {}", client_id, reason),
+        )
+    };
+    if client_id.len() > MAX_CLIENT_ID_LEN {
+        return Err(invalid(format!(
+            "{} bytes, at most {} fit in a CONNECT",
+            client_id.len(),
+            MAX_CLIENT_ID_LEN
+        )));
+    }
+    if let Some(c) = client_id.chars().find(|c| c.is_control()) {
+        return Err(invalid(format!(
+            "control character U+{:04X} is not allowed",
+            c as u32
+        )));
+    }
+    if client_id.is_empty() && !clean_session {
+        return Err(invalid(
+            "an empty id needs a clean session, the broker assigns a new one every time"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod client_id_tests {
+    use super::*;
+
+    #[test]
+    fn test_random_client_id() {
+        let (a, b) = (random_client_id("sake-cli"), random_client_id("sake-cli"));
+        assert_ne!(a, b);
+        assert_eq!(a.len(), "sake-cli-".len() + 8);
+        assert!(a.starts_with("sake-cli-"));
+    }
+
+    #[test]
+    fn test_validate_client_id() {
+        assert!(validate_client_id("sensor-1", true).is_ok());
+        assert!(validate_client_id("", true).is_ok());
+        assert!(validate_client_id("", false).is_err());
+        assert!(validate_client_id("a\nb", true).is_err());
+        assert!(validate_client_id("a\0b", true).is_err());
+        assert!(validate_client_id(&"x".repeat(65_535), true).is_ok());
+        let err = validate_client_id(&"x".repeat(65_536), true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        assert!(is_portable_client_id("sensor1"));
+        assert!(!is_portable_client_id("sensor-1"));
+        assert!(!is_portable_client_id(""));
+        assert!(!is_portable_client_id(&"x".repeat(24)));
+    }
+}
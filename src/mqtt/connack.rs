@@ -13,6 +13,41 @@ pub enum ConnectReturnCode {
     BadUserNamePassword,
     NotAuthorized,
     Unknown,
+    /// MQTT 5 only, connect to the Server Reference of the CONNACK instead
+    UseAnotherServer,
+    /// MQTT 5 only, as `UseAnotherServer` but for good
+    ServerMoved,
+}
+
+impl From<u8> for ConnectReturnCode {
+    fn from(orig: u8) -> Self {
+        match orig {
+            0 => ConnectReturnCode::Success,
+            1 => ConnectReturnCode::RefusedProtocolVersion,
+            2 => ConnectReturnCode::BadClientId,
+            3 => ConnectReturnCode::ServiceUnavailable,
+            4 => ConnectReturnCode::BadUserNamePassword,
+            5 => ConnectReturnCode::NotAuthorized,
+            _ => ConnectReturnCode::Unknown,
+        }
+    }
+}
+
+impl ConnectReturnCode {
+    /// The 3.1.1 return code closest to an MQTT 5 CONNACK reason code
+    pub fn from_reason_code(reason_code: u8) -> Self {
+        match reason_code {
+            0x00 => ConnectReturnCode::Success,
+            0x84 => ConnectReturnCode::RefusedProtocolVersion,
+            0x85 => ConnectReturnCode::BadClientId,
+            0x86 => ConnectReturnCode::BadUserNamePassword,
+            0x87 => ConnectReturnCode::NotAuthorized,
+            0x88 | 0x89 => ConnectReturnCode::ServiceUnavailable,
+            0x9C => ConnectReturnCode::UseAnotherServer,
+            0x9D => ConnectReturnCode::ServerMoved,
+            _ => ConnectReturnCode::Unknown,
+        }
+    }
 }
 
 impl fmt::Display for ConnectReturnCode {
@@ -25,6 +60,8 @@ impl fmt::Display for ConnectReturnCode {
             ConnectReturnCode::BadUserNamePassword => write!(f, "Bad Username or Password"),
             ConnectReturnCode::NotAuthorized => write!(f, "Not Authorized"),
             ConnectReturnCode::Unknown => write!(f, "Unknown"),
+            ConnectReturnCode::UseAnotherServer => write!(f, "Use Another Server"),
+            ConnectReturnCode::ServerMoved => write!(f, "Server Moved"),
         }
     }
 }
@@ -48,15 +85,7 @@ impl fmt::Display for ConnackPacket {
 impl ConnackPacket {
     pub fn from_bytes(bytes: &mut impl Read) -> io::Result<ConnackPacket> {
         let session_present = bytes.read_u8()? != 0;
-        let return_code = match bytes.read_u8()? {
-            0 => ConnectReturnCode::Success,
-            1 => ConnectReturnCode::RefusedProtocolVersion,
-            2 => ConnectReturnCode::BadClientId,
-            3 => ConnectReturnCode::ServiceUnavailable,
-            4 => ConnectReturnCode::BadUserNamePassword,
-            5 => ConnectReturnCode::NotAuthorized,
-            _ => ConnectReturnCode::Unknown,
-        };
+        let return_code = ConnectReturnCode::from(bytes.read_u8()?);
         Ok(ConnackPacket {
             session_present,
             return_code,
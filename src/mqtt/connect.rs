@@ -50,12 +50,81 @@
 /// | Byte N+M+K |                                                  |
 /// |------------|--------------------------------------------------|
 ///
-use crate::mqtt::protocol;
+use crate::mqtt::{protocol, Property, Qos};
 use byteorder::{NetworkEndian, WriteBytesExt};
 use std::fmt;
 use std::io::{self, Write};
 
-const MQTT_V4: u8 = 0x04;
+/// Protocol level of MQTT 3.1.1
+pub const MQTT_V4: u8 = 0x04;
+
+/// Last will message, published by the broker when the client disconnects
+/// ungracefully
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Will {
+    pub topic: String,
+    pub message: Vec<u8>,
+    pub qos: Qos,
+    pub retain: bool,
+    /// MQTT 5, seconds the broker waits before publishing the will, the
+    /// client reconnecting meanwhile cancels it. Left out with 3.1.1
+    pub delay_interval: Option<u32>,
+    /// MQTT 5 Content Type of the message, left out with 3.1.1
+    pub content_type: Option<String>,
+    /// MQTT 5 User Properties of the message, left out with 3.1.1
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl Will {
+    pub fn new(topic: &str, message: &[u8], qos: Qos, retain: bool) -> Self {
+        Self {
+            topic: topic.to_string(),
+            message: message.to_vec(),
+            qos,
+            retain,
+            delay_interval: None,
+            content_type: None,
+            user_properties: vec![],
+        }
+    }
+
+    /// The will properties block of the MQTT 5 CONNECT
+    pub fn properties(&self) -> Vec<Property> {
+        let mut properties = vec![];
+        if let Some(delay) = self.delay_interval {
+            properties.push(Property::WillDelayInterval(delay));
+        }
+        if let Some(content_type) = &self.content_type {
+            properties.push(Property::ContentType(content_type.clone()));
+        }
+        for (name, value) in &self.user_properties {
+            properties.push(Property::UserProperty(name.clone(), value.clone()));
+        }
+        properties
+    }
+
+    /// Set the fields carried by will `properties`, the others are ignored
+    pub fn set_properties(&mut self, properties: Vec<Property>) {
+        for property in properties {
+            match property {
+                Property::WillDelayInterval(delay) => self.delay_interval = Some(delay),
+                Property::ContentType(content_type) => self.content_type = Some(content_type),
+                Property::UserProperty(name, value) => self.user_properties.push((name, value)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Bytes taken by the will topic and message in the CONNECT payload
+    pub fn len(&self) -> usize {
+        2 + self.topic.len() + 2 + self.message.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.topic.is_empty() && self.message.is_empty()
+    }
+}
 
 #[derive(Debug, PartialEq)]
 struct ConnectFlags {
@@ -101,6 +170,10 @@ impl ConnectFlags {
         }
         if self.will {
             connect_flags |= 0x04;
+            connect_flags |= (self.will_qos & 0x03) << 3;
+            if self.will_retain {
+                connect_flags |= 0x20;
+            }
         }
         if self.username {
             connect_flags |= 0x80;
@@ -144,7 +217,7 @@ impl ConnectVariableHeader {
 pub struct ConnectPayload {
     client_id: Option<String>,
     will_topic: Option<String>,
-    will_message: Option<String>,
+    will_message: Option<Vec<u8>>,
     username: Option<String>,
     password: Option<String>,
 }
@@ -153,7 +226,7 @@ impl fmt::Display for ConnectPayload {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let cid = self.client_id.as_deref().unwrap_or("");
         let topic = self.will_topic.as_deref().unwrap_or("");
-        let message = self.will_message.as_deref().unwrap_or("");
+        let message = String::from_utf8_lossy(self.will_message.as_deref().unwrap_or(&[]));
         let user = self.username.as_deref().unwrap_or("");
         let pass = self.password.as_deref().unwrap_or("");
         write!(f, "{} {} {} {} {}", cid, topic, message, user, pass)
@@ -180,14 +253,15 @@ impl ConnectPayload {
             protocol::write_string(buf, will_topic)?;
         }
         if let Some(will_message) = &self.will_message {
-            protocol::write_string(buf, will_message)?;
+            buf.write_u16::<NetworkEndian>(will_message.len() as u16)?;
+            protocol::write_bytes(buf, will_message)?;
         }
 
-        if let Some(username) = &self.will_message {
+        if let Some(username) = &self.username {
             protocol::write_string(buf, username)?;
         }
 
-        if let Some(password) = &self.will_message {
+        if let Some(password) = &self.password {
             protocol::write_string(buf, password)?;
         }
         Ok(())
@@ -208,6 +282,31 @@ impl ConnectPacket {
         }
     }
 
+    /// Set username and password, updating the connect flags accordingly
+    pub fn with_credentials(mut self, username: Option<String>, password: Option<String>) -> Self {
+        self.variable_header.flags.username = username.is_some();
+        self.variable_header.flags.password = password.is_some();
+        self.payload.username = username;
+        self.payload.password = password;
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: u16) -> Self {
+        self.variable_header.keepalive = keepalive;
+        self
+    }
+
+    /// Set the last will, updating the connect flags accordingly
+    pub fn with_will(mut self, will: Option<&Will>) -> Self {
+        let flags = &mut self.variable_header.flags;
+        flags.will = will.is_some();
+        flags.will_qos = will.map_or(0, |w| u8::from(&w.qos));
+        flags.will_retain = will.is_some_and(|w| w.retain);
+        self.payload.will_topic = will.map(|w| w.topic.clone());
+        self.payload.will_message = will.map(|w| w.message.clone());
+        self
+    }
+
     pub fn write(&self, buf: &mut impl Write) -> io::Result<()> {
         protocol::write_string(buf, "MQTT")?;
         buf.write_u8(MQTT_V4)?;
@@ -243,4 +342,36 @@ mod connect_tests {
             &[0, 4, 77, 81, 84, 84, 4, 0, 0, 60, 0, 7, 116, 101, 115, 116, 45, 105, 100]
         );
     }
+
+    #[test]
+    fn test_write_will() {
+        let will = Will::new("w", b"bye", Qos::AtLeastOnce, true);
+        let connect = ConnectPacket::new("id".into(), false)
+            .with_keepalive(30)
+            .with_will(Some(&will));
+        let mut buffer = vec![];
+        connect.write(&mut buffer).unwrap();
+        assert_eq!(
+            buffer,
+            &[
+                0, 4, 77, 81, 84, 84, 4, 0x2C, 0, 30, 0, 2, b'i', b'd', 0, 1, b'w', 0, 3, b'b',
+                b'y', b'e'
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_credentials() {
+        let connect = ConnectPacket::new("id".into(), true)
+            .with_credentials(Some("user".into()), Some("pw".into()));
+        let mut buffer = vec![];
+        connect.write(&mut buffer).unwrap();
+        assert_eq!(
+            buffer,
+            &[
+                0, 4, 77, 81, 84, 84, 4, 0xC2, 0, 60, 0, 2, b'i', b'd', 0, 4, b'u', b's', b'e',
+                b'r', 0, 2, b'p', b'w'
+            ]
+        );
+    }
 }
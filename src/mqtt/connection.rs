@@ -0,0 +1,646 @@
+use crate::mqtt::{read_packet, AckType, Dedup, MqttReader, Protocol, Qos, Request, Response};
+use std::collections::HashMap;
+use std::io;
+use std::net::Shutdown;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+/// Requests and incoming packets queued by default, see `ChannelOptions`
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1000;
+
+/// What happens when a channel of a `Connection` or `ThreadedClient` is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Wait for room: `ClientHandle` calls block, and a `ThreadedClient`
+    /// stops reading the socket so that a slow consumer pushes back on the
+    /// broker
+    Block,
+    /// Refuse requests with `WouldBlock` and discard incoming packets,
+    /// counted by `ThreadedClient::dropped`. Acks are never discarded
+    DropNewest,
+}
+
+/// Bounds of the channels of a `Connection` and its `ThreadedClient`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelOptions {
+    /// Requests of the `ClientHandle`s waiting for the connection
+    pub requests: usize,
+    /// Packets read and not yet received from `ThreadedClient::incoming`
+    pub incoming: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        Self {
+            requests: DEFAULT_CHANNEL_CAPACITY,
+            incoming: DEFAULT_CHANNEL_CAPACITY,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+/// Outcome of a publish, the packet id on completion
+type Delivery = io::Result<u16>;
+
+/// Requests sent by `ClientHandle`s to the `Connection`, and packets read
+/// by the `PacketReader` of a `ThreadedClient`
+#[derive(Debug)]
+enum Command {
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        qos: Qos,
+        retain: bool,
+        done: Sender<Delivery>,
+    },
+    Subscribe {
+        topic: String,
+        qos: Qos,
+    },
+    Disconnect,
+    /// A packet, or why reading stopped
+    Received(io::Result<Response>),
+}
+
+/// Cheap, cloneable handle to a `Connection`, can be moved to any thread
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    commands: SyncSender<Command>,
+    overflow: Overflow,
+}
+
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        "Client connection has terminated",
+    )
+}
+
+impl ClientHandle {
+    fn send(&self, command: Command) -> io::Result<()> {
+        match self.overflow {
+            Overflow::Block => self.commands.send(command).map_err(|_| closed()),
+            Overflow::DropNewest => match self.commands.try_send(command) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Request queue of the client is full",
+                )),
+                Err(TrySendError::Disconnected(_)) => Err(closed()),
+            },
+        }
+    }
+
+    fn queue_publish(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: Qos,
+        retain: bool,
+    ) -> io::Result<DeliveryToken> {
+        let (done, delivery) = mpsc::channel();
+        self.send(Command::Publish {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos,
+            retain,
+            done,
+        })?;
+        Ok(DeliveryToken { delivery })
+    }
+
+    /// Queue a message for publishing, delivery happens in the background and
+    /// can be tracked through the returned token
+    pub fn publish(&self, topic: &str, payload: &[u8], qos: Qos) -> io::Result<DeliveryToken> {
+        self.queue_publish(topic, payload, qos, false)
+    }
+
+    /// Like `publish`, asking the broker to retain the message
+    pub fn publish_retained(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: Qos,
+    ) -> io::Result<DeliveryToken> {
+        self.queue_publish(topic, payload, qos, true)
+    }
+
+    /// Queue a subscription, the SUBACK is delivered on the incoming receiver
+    pub fn subscribe(&self, topic: &str, qos: Qos) -> io::Result<()> {
+        self.send(Command::Subscribe {
+            topic: topic.to_string(),
+            qos,
+        })
+    }
+
+    /// Send DISCONNECT after the requests queued before, ending the connection
+    pub fn disconnect(&self) -> io::Result<()> {
+        self.send(Command::Disconnect)
+    }
+}
+
+/// Resolves when the QoS flow of a publish completes: once written for QoS 0,
+/// on PUBACK for QoS 1 and on PUBCOMP for QoS 2
+#[derive(Debug)]
+pub struct DeliveryToken {
+    delivery: Receiver<Delivery>,
+}
+
+fn lost() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "Connection lost before delivery completed",
+    )
+}
+
+impl DeliveryToken {
+    /// Block until delivery completes, returning the packet id used
+    pub fn wait(self) -> io::Result<u16> {
+        self.delivery.recv().map_err(|_| lost())?
+    }
+
+    /// Block until delivery completes or `timeout` elapses
+    pub fn wait_timeout(self, timeout: Duration) -> io::Result<u16> {
+        match self.delivery.recv_timeout(timeout) {
+            Ok(delivery) => delivery,
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for delivery",
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(lost()),
+        }
+    }
+
+    /// Non blocking check, `None` while delivery is still in progress
+    pub fn try_result(&self) -> Option<io::Result<u16>> {
+        match self.delivery.try_recv() {
+            Ok(delivery) => Some(delivery),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(lost())),
+        }
+    }
+}
+
+/// A publish waiting for its acks
+#[derive(Debug)]
+struct InFlight {
+    publish: Request,
+    /// The PUBREC arrived, the PUBCOMP is due
+    released: bool,
+    deadline: Option<Instant>,
+    retries_left: u32,
+    done: Sender<Delivery>,
+}
+
+/// A connection driven by its owner one step at a time, without threads:
+/// every `poll` sends the requests queued through the `ClientHandle`s,
+/// retransmits the publishes whose acks timed out, pings the broker as the
+/// keepalive requires and reads the next packet, acking it. `ThreadedClient`
+/// runs one on a background thread, see `run`
+pub struct Connection {
+    protocol: Protocol,
+    requests: Receiver<Command>,
+    handle: ClientHandle,
+    options: ChannelOptions,
+    in_flight: HashMap<u16, InFlight>,
+    qos2: Dedup<u16>,
+    ack_timeout: Option<Duration>,
+    retries: u32,
+    closed: bool,
+    /// Tells the `PacketReader` what to deliver of the packet it read
+    replies: Option<SyncSender<Option<Response>>>,
+}
+
+impl Connection {
+    /// Take ownership of an already connected `Protocol`, usually obtained
+    /// through `Protocol::builder()`, with the default `ChannelOptions`
+    pub fn new(protocol: Protocol) -> Self {
+        Self::with_channels(protocol, ChannelOptions::default())
+    }
+
+    /// Like `new`, bounding the request queue as `options` say. The incoming
+    /// bound applies to the `ThreadedClient` driving the connection
+    pub fn with_channels(protocol: Protocol, options: ChannelOptions) -> Self {
+        let (commands, requests) = mpsc::sync_channel(options.requests.max(1));
+        Self {
+            protocol,
+            requests,
+            handle: ClientHandle {
+                commands,
+                overflow: options.overflow,
+            },
+            options,
+            in_flight: HashMap::new(),
+            qos2: Dedup::default(),
+            ack_timeout: None,
+            retries: 0,
+            closed: false,
+            replies: None,
+        }
+    }
+
+    /// Send publishes left unacknowledged for `timeout` again, flagged DUP,
+    /// up to `retries` times before their token fails with `TimedOut`. A
+    /// PUBREL left without PUBCOMP is sent again likewise. `None`, the
+    /// default, waits for acks as long as the connection lasts
+    pub fn set_ack_timeout(&mut self, timeout: Option<Duration>, retries: u32) {
+        self.ack_timeout = timeout;
+        self.retries = retries;
+    }
+
+    pub fn handle(&self) -> ClientHandle {
+        self.handle.clone()
+    }
+
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
+    }
+
+    pub fn channel_options(&self) -> ChannelOptions {
+        self.options
+    }
+
+    /// Whether DISCONNECT was sent, `poll` has nothing left to do
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Drive the connection one step, blocking on the socket until a packet
+    /// arrives, the next retransmission is due or the read timeout of the
+    /// `Protocol` elapses, pinging the broker meanwhile as the keepalive
+    /// requires. Returns the packet acked, or `None` when nothing arrived in
+    /// time or it was the retransmission of a QoS 2 message already
+    /// returned. Requests queued from other threads go out on the next call.
+    /// Fails once the connection is lost, and with `NotConnected` after
+    /// DISCONNECT
+    pub fn poll(&mut self) -> io::Result<Option<Response>> {
+        self.send_requests()?;
+        if self.closed {
+            return Ok(None);
+        }
+        let now = Instant::now();
+        self.retransmit(now)?;
+        let read_timeout = self.protocol.read_timeout;
+        // A zero timeout is refused by the socket
+        let wait = self.ack_deadline().map(|deadline| {
+            deadline
+                .saturating_duration_since(now)
+                .max(Duration::from_millis(1))
+        });
+        let timeout = match (wait, read_timeout) {
+            (Some(wait), Some(read_timeout)) => Some(wait.min(read_timeout)),
+            (wait, read_timeout) => wait.or(read_timeout),
+        };
+        self.protocol.set_read_timeout(timeout)?;
+        let response = self.protocol.next_message();
+        self.protocol.set_read_timeout(read_timeout)?;
+        match response {
+            Ok(response) => self.receive(response),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hand reading over to a `PacketReader`, to be run on another thread
+    /// while `run` drives the connection
+    pub(crate) fn reader(&mut self) -> io::Result<PacketReader> {
+        let reader = self.protocol.take_reader()?;
+        reader.set_read_timeout(None)?;
+        let (replies, delivered) = mpsc::sync_channel(1);
+        self.replies = Some(replies);
+        Ok(PacketReader {
+            reader,
+            v5: self.protocol.capabilities().is_some(),
+            commands: self.handle.commands.clone(),
+            delivered,
+        })
+    }
+
+    /// Drive the connection until DISCONNECT, after `reader`: requests and
+    /// packets arrive in order on the same channel, on which the connection
+    /// blocks until the next keepalive or retransmission deadline. The
+    /// socket is shut down on failure, for the `PacketReader` to stop too
+    pub(crate) fn run(mut self) -> io::Result<()> {
+        let result = self.run_until_closed();
+        if result.is_err() {
+            let _ = self.protocol.writer.transport().shutdown(Shutdown::Both);
+        }
+        result
+    }
+
+    fn run_until_closed(&mut self) -> io::Result<()> {
+        while !self.closed {
+            let now = Instant::now();
+            self.protocol.keep_alive(now)?;
+            self.retransmit(now)?;
+            let deadline = self
+                .protocol
+                .keepalive_deadline()
+                .into_iter()
+                .chain(self.ack_deadline())
+                .min();
+            let command = match deadline {
+                Some(deadline) => match self
+                    .requests
+                    .recv_timeout(deadline.saturating_duration_since(now))
+                {
+                    Ok(command) => command,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return Err(closed()),
+                },
+                None => self.requests.recv().map_err(|_| closed())?,
+            };
+            self.execute(command)?;
+        }
+        Ok(())
+    }
+
+    fn ack_deadline(&self) -> Option<Instant> {
+        self.in_flight
+            .values()
+            .filter_map(|in_flight| in_flight.deadline)
+            .min()
+    }
+
+    /// Send the queued requests without reading, for owners which can't
+    /// take more incoming packets for now
+    pub fn send_requests(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Err(closed());
+        }
+        while let Ok(command) = self.requests.try_recv() {
+            self.execute(command)?;
+            if self.closed {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, command: Command) -> io::Result<()> {
+        match command {
+            Command::Publish {
+                topic,
+                payload,
+                qos,
+                retain,
+                done,
+            } => {
+                let packet_id = match qos {
+                    Qos::AtMostOnce => 0,
+                    _ => self.protocol.next_packet_id(),
+                };
+                let publish = Request::Publish {
+                    packet_id,
+                    qos: u8::from(&qos),
+                    dup: false,
+                    retain,
+                    topic,
+                    payload,
+                };
+                if let Err(e) = self.protocol.send_message(&publish) {
+                    let _ = done.send(Err(io::Error::new(e.kind(), e.to_string())));
+                    return Err(e);
+                }
+                match qos {
+                    Qos::AtMostOnce => {
+                        let _ = done.send(Ok(packet_id));
+                    }
+                    _ => {
+                        let in_flight = InFlight {
+                            publish,
+                            released: false,
+                            deadline: self.ack_timeout.map(|timeout| Instant::now() + timeout),
+                            retries_left: self.retries,
+                            done,
+                        };
+                        self.in_flight.insert(packet_id, in_flight);
+                    }
+                }
+            }
+            Command::Subscribe { topic, qos } => {
+                self.protocol.subscribe(&topic, qos)?;
+            }
+            Command::Disconnect => {
+                self.closed = true;
+                self.protocol.disconnect()?;
+                self.protocol.writer.transport().shutdown(Shutdown::Both)?;
+            }
+            Command::Received(response) => {
+                let response = self.protocol.observe_response(response)?;
+                let delivered = match self.protocol.keepalive_received(response) {
+                    Some(response) => self.receive(response)?,
+                    None => None,
+                };
+                if let Some(replies) = &self.replies {
+                    replies.send(delivered).map_err(|_| closed())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn retransmit(&mut self, now: Instant) -> io::Result<()> {
+        let Some(timeout) = self.ack_timeout else {
+            return Ok(());
+        };
+        let expired: Vec<u16> = self
+            .in_flight
+            .iter()
+            .filter(|(_, in_flight)| in_flight.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(packet_id, _)| *packet_id)
+            .collect();
+        for packet_id in expired {
+            let Some(in_flight) = self.in_flight.get_mut(&packet_id) else {
+                continue;
+            };
+            if in_flight.retries_left == 0 {
+                let _ = in_flight.done.send(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No ack for publish {} after retransmitting it", packet_id),
+                )));
+                self.in_flight.remove(&packet_id);
+                continue;
+            }
+            in_flight.retries_left -= 1;
+            in_flight.deadline = Some(now + timeout);
+            match in_flight.released {
+                true => self.protocol.ack(AckType::Pubrel(packet_id))?,
+                false => {
+                    if let Request::Publish { dup, .. } = &mut in_flight.publish {
+                        *dup = true;
+                    }
+                    self.protocol.send_message(&in_flight.publish)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ack `response` and complete the deliveries it acknowledges
+    fn receive(&mut self, response: Response) -> io::Result<Option<Response>> {
+        match &response {
+            Response::Publish {
+                qos: 1, packet_id, ..
+            } => self.protocol.ack(AckType::Puback(*packet_id))?,
+            Response::Publish {
+                qos: 2, packet_id, ..
+            } => {
+                self.protocol.ack(AckType::Pubrec(*packet_id))?;
+                // Retransmitted QoS 2 messages are acked again but returned once
+                if !self.qos2.arrived(*packet_id) {
+                    return Ok(None);
+                }
+            }
+            Response::Pubrel { packet_id } => {
+                self.protocol.ack(AckType::Pubcomp(*packet_id))?;
+                self.qos2.released(packet_id);
+            }
+            Response::Pubrec { packet_id } => {
+                self.protocol.ack(AckType::Pubrel(*packet_id))?;
+                if let Some(in_flight) = self.in_flight.get_mut(packet_id) {
+                    in_flight.released = true;
+                    in_flight.deadline = self.ack_timeout.map(|timeout| Instant::now() + timeout);
+                }
+            }
+            Response::Puback { packet_id } | Response::Pubcomp { packet_id } => {
+                if let Some(in_flight) = self.in_flight.remove(packet_id) {
+                    let _ = in_flight.done.send(Ok(*packet_id));
+                }
+            }
+            _ => {}
+        }
+        Ok(Some(response))
+    }
+}
+
+/// Reads the packets of a connection `Connection::run` drives, on the
+/// other thread of a `ThreadedClient`
+pub(crate) struct PacketReader {
+    reader: MqttReader,
+    v5: bool,
+    commands: SyncSender<Command>,
+    delivered: Receiver<Option<Response>>,
+}
+
+impl PacketReader {
+    /// Block until the next packet is read and handled by the connection,
+    /// returning it unless it was consumed, as PINGRESP and retransmitted
+    /// QoS 2 messages are. Fails once the connection is gone
+    pub(crate) fn next(&mut self) -> io::Result<Option<Response>> {
+        let response = loop {
+            match read_packet(&mut self.reader, self.v5) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                response => break response,
+            }
+        };
+        let failed = response
+            .as_ref()
+            .err()
+            .map(|e| io::Error::new(e.kind(), e.to_string()));
+        self.commands
+            .send(Command::Received(response))
+            .map_err(|_| closed())?;
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        self.delivered.recv().map_err(|_| closed())
+    }
+}
+
+#[cfg(test)]
+mod connection_tests {
+    use super::*;
+    use crate::testing::{duplex, Duplex};
+    use std::io::{Read, Write};
+
+    /// A connection whose `poll` gives up after 10ms without packets
+    fn connection(link: Duplex) -> io::Result<Connection> {
+        let mut protocol = Protocol::with_transport(link)?;
+        protocol.set_read_timeout(Some(Duration::from_millis(10)))?;
+        Ok(Connection::new(protocol))
+    }
+
+    #[test]
+    fn test_poll() -> io::Result<()> {
+        let (link, mut broker) = duplex();
+        let mut connection = connection(link)?;
+        let handle = connection.handle();
+        assert!(connection.poll()?.is_none());
+
+        let token = handle.publish("b", b"x", Qos::AtLeastOnce)?;
+        // Nothing goes out until polled
+        assert_eq!(broker.pending(), 0);
+        assert!(connection.poll()?.is_none());
+        let mut publish = [0; 8];
+        broker.read_exact(&mut publish)?;
+        assert_eq!(publish, [0x32, 6, 0, 1, b'b', 0, 1, b'x']);
+        assert!(token.try_result().is_none());
+
+        // PUBACK, then a QoS 1 PUBLISH on "a" with packet id 7
+        broker.write_all(&[0x40, 2, 0, 1, 0x32, 5, 0, 1, b'a', 0, 7])?;
+        assert!(matches!(
+            connection.poll()?,
+            Some(Response::Puback { packet_id: 1 })
+        ));
+        assert_eq!(token.try_result().unwrap()?, 1);
+        assert!(matches!(
+            connection.poll()?,
+            Some(Response::Publish { packet_id: 7, .. })
+        ));
+        handle.disconnect()?;
+        assert!(connection.poll()?.is_none());
+        assert!(connection.is_closed());
+        assert_eq!(
+            connection.poll().unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        let mut rest = vec![];
+        broker.read_to_end(&mut rest)?;
+        assert_eq!(rest, [0x40, 2, 0, 7, 0xE0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ack_timeout() -> io::Result<()> {
+        let (link, mut broker) = duplex();
+        let mut connection = connection(link)?;
+        connection.set_ack_timeout(Some(Duration::from_millis(30)), 1);
+        let token = connection.handle().publish("b", b"", Qos::AtLeastOnce)?;
+        let delivery = loop {
+            connection.poll()?;
+            if let Some(delivery) = token.try_result() {
+                break delivery;
+            }
+        };
+        assert_eq!(delivery.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        // Sent once more with DUP, then given up on
+        let mut sent = [0; 14];
+        broker.read_exact(&mut sent)?;
+        assert_eq!(sent, [0x32, 5, 0, 1, b'b', 0, 1, 0x3A, 5, 0, 1, b'b', 0, 1]);
+        assert_eq!(broker.pending(), 0);
+
+        // A PUBREL left without PUBCOMP is sent again
+        let token = connection.handle().publish("b", b"", Qos::ExactlyOnce)?;
+        connection.poll()?;
+        broker.write_all(&[0x50, 2, 0, 2])?;
+        while broker.pending() < 15 {
+            connection.poll()?;
+        }
+        broker.write_all(&[0x70, 2, 0, 2])?;
+        connection.poll()?;
+        assert_eq!(token.try_result().unwrap()?, 2);
+        let mut sent = [0; 15];
+        broker.read_exact(&mut sent)?;
+        assert_eq!(
+            sent,
+            [0x34, 5, 0, 1, b'b', 0, 2, 0x62, 2, 0, 2, 0x62, 2, 0, 2]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+
+/// Default number of in-flight QoS 2 messages remembered per `Dedup`
+pub const DEFAULT_DEDUP_CAPACITY: usize = 1024;
+
+/// Bounded set of inbound QoS 2 messages received but not yet released.
+///
+/// The spec delivers a QoS 2 message once: the receiver stores the packet id
+/// on PUBLISH and forgets it on PUBREL, any PUBLISH with a stored id in
+/// between is a retransmission, whatever its DUP flag, and must be
+/// acknowledged but not delivered again. Keys are packet ids for a client or
+/// (client, packet id) pairs for a broker. When full the oldest entry is
+/// evicted, bounding memory against peers that never release
+#[derive(Debug)]
+pub struct Dedup<K> {
+    seen: HashSet<K>,
+    order: VecDeque<K>,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone> Default for Dedup<K> {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEDUP_CAPACITY)
+    }
+}
+
+impl<K: Hash + Eq + Clone> Dedup<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Record a QoS 2 PUBLISH, returns false if it is a duplicate that must
+    /// not be delivered
+    pub fn arrived(&mut self, key: K) -> bool {
+        if self.seen.contains(&key) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key.clone());
+        self.order.push_back(key);
+        true
+    }
+
+    /// Forget a message once its PUBREL arrived, the packet id can be reused
+    pub fn released(&mut self, key: &K) {
+        if self.seen.remove(key) {
+            self.order.retain(|k| k != key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod dedup_tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_until_released() {
+        let mut dedup = Dedup::default();
+        assert!(dedup.arrived(7u16));
+        assert!(!dedup.arrived(7));
+        dedup.released(&7);
+        assert!(dedup.is_empty());
+        assert!(dedup.arrived(7));
+    }
+
+    #[test]
+    fn test_capacity_evicts_oldest() {
+        let mut dedup = Dedup::new(2);
+        assert!(dedup.arrived(("a", 1u16)));
+        assert!(dedup.arrived(("b", 1)));
+        assert!(dedup.arrived(("a", 2)));
+        assert_eq!(dedup.len(), 2);
+        // ("a", 1) was evicted and is no longer recognised
+        assert!(dedup.arrived(("a", 1)));
+        assert!(!dedup.arrived(("a", 2)));
+    }
+}
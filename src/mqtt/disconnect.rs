@@ -0,0 +1,186 @@
+use crate::mqtt::properties::{properties_len, write_properties};
+use crate::mqtt::{Property, SERVER_MOVED, USE_ANOTHER_SERVER};
+use byteorder::WriteBytesExt;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+/// DISCONNECT reason code of a client leaving as asked
+pub const NORMAL_DISCONNECTION: u8 = 0x00;
+/// DISCONNECT reason code of a client leaving that still wants its will
+/// published
+pub const DISCONNECT_WITH_WILL: u8 = 0x04;
+
+/// Why an MQTT 5 client disconnects, see `Protocol::disconnect_with`
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DisconnectReason {
+    pub reason_code: u8,
+    /// Replaces the Session Expiry Interval of the CONNECT, brokers refuse
+    /// it when the CONNECT had none
+    pub session_expiry_interval: Option<u32>,
+    pub reason_string: Option<String>,
+}
+
+impl DisconnectReason {
+    pub fn new(reason_code: u8) -> Self {
+        Self {
+            reason_code,
+            ..Self::default()
+        }
+    }
+
+    pub fn properties(&self) -> Vec<Property> {
+        let mut properties = vec![];
+        if let Some(interval) = self.session_expiry_interval {
+            properties.push(Property::SessionExpiryInterval(interval));
+        }
+        if let Some(reason) = &self.reason_string {
+            properties.push(Property::ReasonString(reason.clone()));
+        }
+        properties
+    }
+
+    /// Bytes taken by the reason code and properties, none when both can
+    /// be left out
+    pub fn len(&self) -> usize {
+        let properties = self.properties();
+        match (self.reason_code, properties.is_empty()) {
+            (NORMAL_DISCONNECTION, true) => 0,
+            _ => 1 + properties_len(&properties),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The variable header of the DISCONNECT, see `len`
+    pub fn write(&self, buf: &mut impl Write) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        buf.write_u8(self.reason_code)?;
+        write_properties(buf, &self.properties())
+    }
+}
+
+/// The broker ended the session with a DISCONNECT, carried as the inner
+/// error of an `io::Error` of kind `ConnectionAborted`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disconnected {
+    pub reason_code: u8,
+    pub reason_string: Option<String>,
+    pub server_reference: Option<String>,
+}
+
+impl Disconnected {
+    pub fn new(reason_code: u8, properties: &[Property]) -> Self {
+        let mut disconnected = Self {
+            reason_code,
+            reason_string: None,
+            server_reference: None,
+        };
+        for property in properties {
+            match property {
+                Property::ReasonString(reason) => disconnected.reason_string = Some(reason.clone()),
+                Property::ServerReference(reference) => {
+                    disconnected.server_reference = Some(reference.clone())
+                }
+                _ => {}
+            }
+        }
+        disconnected
+    }
+
+    /// Whether the broker sends the client to its Server Reference
+    pub fn is_redirect(&self) -> bool {
+        matches!(self.reason_code, USE_ANOTHER_SERVER | SERVER_MOVED)
+    }
+}
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Disconnected by the broker: 0x{:02X}", self.reason_code)?;
+        if let Some(reason) = &self.reason_string {
+            write!(f, " ({})", reason)?;
+        }
+        match &self.server_reference {
+            Some(reference) => write!(f, ", use {}", reference),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for Disconnected {}
+
+impl From<Disconnected> for io::Error {
+    fn from(err: Disconnected) -> Self {
+        io::Error::new(io::ErrorKind::ConnectionAborted, err)
+    }
+}
+
+#[cfg(test)]
+mod disconnect_tests {
+    use super::*;
+    use crate::mqtt::{Protocol, Request, Serialize, MQTT_V5};
+    use crate::testing::duplex;
+    use std::io::Read;
+    use std::thread;
+
+    #[test]
+    fn test_serialize() -> io::Result<()> {
+        let normal = Request::Disconnect { reason: None };
+        assert_eq!(normal.to_bytes()?, [0xE0, 0]);
+        let normal = Request::Disconnect {
+            reason: Some(DisconnectReason::default()),
+        };
+        assert_eq!(normal.to_bytes()?, [0xE0, 0]);
+        let with_will = Request::Disconnect {
+            reason: Some(DisconnectReason {
+                reason_code: DISCONNECT_WITH_WILL,
+                session_expiry_interval: Some(0),
+                reason_string: Some("bye".to_string()),
+            }),
+        };
+        assert_eq!(
+            with_will.to_bytes()?,
+            [0xE0, 13, 0x04, 11, 0x11, 0, 0, 0, 0, 0x1F, 0, 3, b'b', b'y', b'e']
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_disconnected_by_broker() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        thread::spawn(move || -> io::Result<()> {
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header)?;
+            stream.read_exact(&mut vec![0; header[1] as usize])?;
+            stream.write_all(&[0x20, 3, 0, 0, 0])?;
+            // Session taken over, with a reason string
+            stream.write_all(&[0xE0, 8, 0x8E, 6, 0x1F, 0, 3, b'd', b'u', b'p'])
+        });
+        let mut client = Protocol::builder()
+            .client_id("id")
+            .protocol_level(MQTT_V5)
+            .connect_transport(client)?;
+        let err = client.next_message().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(err.to_string(), "Disconnected by the broker: 0x8E (dup)");
+        let disconnected = err.get_ref().and_then(|e| e.downcast_ref::<Disconnected>());
+        assert!(disconnected.is_some_and(|d| !d.is_redirect()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_disconnect_with_needs_v5() -> io::Result<()> {
+        let (client, _broker) = duplex();
+        let mut client = Protocol::with_transport(client)?;
+        let err = client
+            .disconnect_with(DisconnectReason::new(DISCONNECT_WITH_WILL))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        client.disconnect_with(DisconnectReason::default())
+    }
+}
@@ -0,0 +1,201 @@
+use crate::mqtt::Disconnected;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Why a connection ended, see `Event::Disconnected`
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectCause {
+    /// The client sent DISCONNECT
+    Client,
+    /// The broker sent DISCONNECT
+    Broker(Disconnected),
+    /// The connection failed or was closed without a DISCONNECT
+    Lost(io::ErrorKind),
+}
+
+impl fmt::Display for DisconnectCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectCause::Client => f.write_str("by the client"),
+            DisconnectCause::Broker(disconnected) => match &disconnected.reason_string {
+                Some(reason) => write!(
+                    f,
+                    "by the broker: 0x{:02X} ({})",
+                    disconnected.reason_code, reason
+                ),
+                None => write!(f, "by the broker: 0x{:02X}", disconnected.reason_code),
+            },
+            DisconnectCause::Lost(kind) => write!(f, "connection lost: {}", kind),
+        }
+    }
+}
+
+/// What happened to a connection, for following flaky links. See
+/// `ProtocolBuilder::on_event`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The broker accepted the CONNECT
+    Connected {
+        session_present: bool,
+    },
+    /// Connecting again, after a redirect or as `ProtocolBuilder::reconnect`.
+    /// Attempts count from 1 for the first reconnection of a client
+    Reconnecting {
+        attempt: u32,
+    },
+    /// A SUBACK arrived, with a return code per topic filter
+    SubscriptionGranted {
+        packet_id: u16,
+        return_codes: Vec<u8>,
+    },
+    /// A QoS 1 publish got its PUBACK, or a QoS 2 one its PUBCOMP
+    PublishAcked {
+        packet_id: u16,
+    },
+    /// A PINGREQ went unanswered for a whole keepalive
+    PingTimeout,
+    Disconnected {
+        reason: DisconnectCause,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Connected {
+                session_present: true,
+            } => f.write_str("Connected, session resumed"),
+            Event::Connected { .. } => f.write_str("Connected"),
+            Event::Reconnecting { attempt } => write!(f, "Reconnecting, attempt {}", attempt),
+            Event::SubscriptionGranted {
+                packet_id,
+                return_codes,
+            } => write!(f, "Subscription {} granted {:?}", packet_id, return_codes),
+            Event::PublishAcked { packet_id } => write!(f, "Publish {} acked", packet_id),
+            Event::PingTimeout => f.write_str("Ping timed out"),
+            Event::Disconnected { reason } => write!(f, "Disconnected {}", reason),
+        }
+    }
+}
+
+/// Called with every event of a connection, see `ProtocolBuilder::on_event`
+#[derive(Clone)]
+pub struct EventHook(Arc<dyn Fn(&Event) + Send + Sync>);
+
+impl EventHook {
+    pub fn new(hook: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub fn call(&self, event: &Event) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for EventHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventHook")
+    }
+}
+
+/// Errors ending the connection without a DISCONNECT, as opposed to
+/// timeouts and malformed packets
+pub(crate) fn is_connection_lost(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+#[cfg(test)]
+mod events_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+    use crate::mqtt::{Protocol, Qos, Response};
+    use crate::testing::duplex;
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    fn recorder() -> (Arc<Mutex<Vec<Event>>>, impl Fn(&Event) + Send + Sync) {
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = Arc::clone(&events);
+        (events, move |event: &Event| {
+            recorded.lock().unwrap().push(event.clone())
+        })
+    }
+
+    #[test]
+    fn test_events() -> io::Result<()> {
+        let (link, mut broker) = duplex();
+        // CONNACK, SUBACK granting QoS 1 and PUBACK, read as they're due
+        broker.write_all(&[0x20, 2, 0, 0, 0x90, 3, 0, 1, 1, 0x40, 2, 0, 2])?;
+        let (events, hook) = recorder();
+        let mut client = Protocol::builder()
+            .client_id("events")
+            .on_event(hook)
+            .connect_transport(link)?;
+        client.subscribe("a", Qos::AtLeastOnce)?;
+        client.publish("a", b"x")?;
+        assert!(matches!(client.next_message()?, Response::Suback { .. }));
+        assert!(matches!(client.next_message()?, Response::Puback { .. }));
+        drop(broker);
+        assert!(client.next_message().is_err());
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                Event::Connected {
+                    session_present: false
+                },
+                Event::SubscriptionGranted {
+                    packet_id: 1,
+                    return_codes: vec![1]
+                },
+                Event::PublishAcked { packet_id: 2 },
+                Event::Disconnected {
+                    reason: DisconnectCause::Lost(io::ErrorKind::UnexpectedEof)
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconnecting() -> io::Result<()> {
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let addr = broker.local_addr()?;
+        std::thread::spawn(move || broker.run());
+        let (events, hook) = recorder();
+        let builder = Protocol::builder()
+            .addrs(&[addr])
+            .client_id("reconnecting")
+            .on_event(hook);
+        let client = builder.clone().connect()?;
+        let client = builder.reconnect(&client)?;
+        let mut client = builder.reconnect(&client)?;
+        assert_eq!(client.reconnects(), 2);
+        client.disconnect()?;
+        let events = events.lock().unwrap();
+        let connected = Event::Connected {
+            session_present: false,
+        };
+        assert_eq!(
+            *events,
+            [
+                connected.clone(),
+                Event::Reconnecting { attempt: 1 },
+                connected.clone(),
+                Event::Reconnecting { attempt: 2 },
+                connected,
+                Event::Disconnected {
+                    reason: DisconnectCause::Client
+                },
+            ]
+        );
+        assert_eq!(events[5].to_string(), "Disconnected by the client");
+        Ok(())
+    }
+}
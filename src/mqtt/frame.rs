@@ -0,0 +1,145 @@
+use crate::mqtt::{TransportError, VarInt};
+use std::io::{self, Read};
+
+/// Bytes requested from the inner reader at each read
+const READ_CHUNK: usize = 4096;
+
+/// Buffers the bytes read from a stream until a whole packet is available.
+/// A read interrupted by a timeout or a TCP segment ending mid-packet keeps
+/// what was received so far, so the stream never loses sync
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    /// Start of the bytes not handed out yet
+    start: usize,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            start: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Bytes received but not returned as a frame yet
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+
+    /// First byte and total size of the next frame, as soon as its fixed
+    /// header is buffered, the rest of it may still be missing
+    pub fn header(&self) -> Result<Option<(u8, usize)>, TransportError> {
+        let Some((&byte, rest)) = self.buffered().split_first() else {
+            return Ok(None);
+        };
+        Ok(VarInt::decode(rest)?.map(|(len, size)| (byte, 1 + size + len.value() as usize)))
+    }
+
+    /// Hand out the next frame if it is completely buffered
+    pub fn next_frame(&mut self) -> Result<Option<&[u8]>, TransportError> {
+        let Some((_, len)) = self.header()? else {
+            return Ok(None);
+        };
+        if self.buffered().len() < len {
+            return Ok(None);
+        }
+        let frame = &self.buf[self.start..self.start + len];
+        self.start += len;
+        Ok(Some(frame))
+    }
+
+    /// Read once from the inner reader, returning the number of bytes
+    /// buffered. The end of the stream is an `UnexpectedEof` error
+    pub fn fill(&mut self) -> io::Result<usize> {
+        // Reclaim the space of the frames already handed out
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        let mut chunk = [0; READ_CHUNK];
+        let n = loop {
+            match self.inner.read(&mut chunk) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod frame_tests {
+    use super::*;
+
+    /// Hands out one byte per read, failing with `WouldBlock` in between
+    struct Trickle {
+        bytes: Vec<u8>,
+        pos: usize,
+        ready: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let Some(&byte) = self.bytes.get(self.pos) else {
+                return Ok(0);
+            };
+            buf[0] = byte;
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_fragmented_frames() {
+        let bytes = vec![0x40, 2, 0, 1, 0x30, 3, 0, 1, b'a'];
+        let mut frames = FrameReader::new(Trickle {
+            bytes,
+            pos: 0,
+            ready: false,
+        });
+        let mut received = vec![];
+        while received.len() < 2 {
+            match frames.fill() {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => panic!("Unexpected error {}", e),
+            }
+            if let Some(frame) = frames.next_frame().unwrap() {
+                received.push(frame.to_vec());
+            }
+        }
+        assert_eq!(received[0], &[0x40, 2, 0, 1]);
+        assert_eq!(received[1], &[0x30, 3, 0, 1, b'a']);
+        assert!(frames.buffered().is_empty());
+        let err = (0..2).find_map(|_| frames.fill().err()).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(
+            frames.fill().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_header() {
+        let mut frames = FrameReader::new(&[0x30, 0x80, 0x01][..]);
+        assert_eq!(frames.header(), Ok(None));
+        frames.fill().unwrap();
+        assert_eq!(frames.header(), Ok(Some((0x30, 131))));
+        assert_eq!(frames.next_frame(), Ok(None));
+    }
+}
@@ -0,0 +1,308 @@
+use crate::mqtt::{Deserialize, Serialize, WireDump, PACKET_NAMES};
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A whole packet as it goes on the wire, fixed header included, as handed
+/// to interceptors before it's written or decoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    bytes: Vec<u8>,
+    discarded: bool,
+}
+
+impl Packet {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            discarded: false,
+        }
+    }
+
+    /// Type of the fixed header, 1 for CONNECT up to 15 for AUTH
+    pub fn packet_type(&self) -> u8 {
+        self.bytes.first().map_or(0, |byte| byte >> 4)
+    }
+
+    /// Name of the packet type, as `PUBLISH`
+    pub fn name(&self) -> &'static str {
+        PACKET_NAMES[self.packet_type() as usize]
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Raw access to the packet, keeping its remaining length right is up
+    /// to the caller
+    pub fn bytes_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Decode the packet, as `packet.decode::<Response>()`
+    pub fn decode<T: Deserialize>(&self) -> io::Result<T::Output> {
+        T::from_slice(&self.bytes)
+    }
+
+    /// Replace the packet with another message
+    pub fn replace(&mut self, message: &impl Serialize) -> io::Result<()> {
+        self.bytes = message.to_bytes()?;
+        Ok(())
+    }
+
+    /// Neither write nor deliver the packet, as if it was lost on the way.
+    /// Interceptors added after the one discarding it don't see it
+    pub fn discard(&mut self) {
+        self.discarded = true;
+    }
+
+    pub fn is_discarded(&self) -> bool {
+        self.discarded
+    }
+}
+
+/// Hooks called with every packet of a connection, to log, count, alter or
+/// drop them, see `Protocol::add_interceptor`. Both do nothing by default
+pub trait PacketInterceptor: Send + Sync {
+    /// Called before a packet is written
+    fn on_send(&self, _packet: &mut Packet) {}
+
+    /// Called once a packet was read, before it's decoded
+    fn on_receive(&self, _packet: &mut Packet) {}
+}
+
+impl fmt::Debug for dyn PacketInterceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PacketInterceptor")
+    }
+}
+
+/// Interceptors of a connection in the order they were added, shared by
+/// its reader and writers
+#[derive(Default)]
+pub struct Interceptors {
+    chain: RwLock<Vec<Arc<dyn PacketInterceptor>>>,
+}
+
+impl fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interceptors({})", self.chain.read().unwrap().len())
+    }
+}
+
+impl Interceptors {
+    pub fn add(&self, interceptor: Arc<dyn PacketInterceptor>) {
+        self.chain.write().unwrap().push(interceptor);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chain.read().unwrap().is_empty()
+    }
+
+    pub fn on_send(&self, packet: &mut Packet) {
+        for interceptor in self.chain.read().unwrap().iter() {
+            if packet.is_discarded() {
+                break;
+            }
+            interceptor.on_send(packet);
+        }
+    }
+
+    pub fn on_receive(&self, packet: &mut Packet) {
+        for interceptor in self.chain.read().unwrap().iter() {
+            if packet.is_discarded() {
+                break;
+            }
+            interceptor.on_receive(packet);
+        }
+    }
+}
+
+/// Writes a line per packet, `>` sent and `<` received, with its type and
+/// size, followed by an annotated dump of its bytes when verbose
+pub struct LoggingInterceptor {
+    out: Mutex<Box<dyn Write + Send>>,
+    verbose: bool,
+}
+
+impl LoggingInterceptor {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+            verbose: false,
+        }
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(io::stderr())
+    }
+
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    fn log(&self, direction: char, packet: &Packet) {
+        let mut out = self.out.lock().unwrap();
+        // Logging never fails the connection
+        let _ = writeln!(
+            out,
+            "{} {} {} bytes",
+            direction,
+            packet.name(),
+            packet.bytes().len()
+        );
+        if self.verbose {
+            let _ = write!(out, "{}", WireDump::new(packet.bytes()));
+        }
+    }
+}
+
+impl PacketInterceptor for LoggingInterceptor {
+    fn on_send(&self, packet: &mut Packet) {
+        self.log('>', packet);
+    }
+
+    fn on_receive(&self, packet: &mut Packet) {
+        self.log('<', packet);
+    }
+}
+
+/// Counts the packets sent and received by type
+#[derive(Debug, Default)]
+pub struct CountingInterceptor {
+    sent: [AtomicU64; 16],
+    received: [AtomicU64; 16],
+}
+
+impl CountingInterceptor {
+    /// Packets of a type sent so far, 3 for PUBLISH
+    pub fn sent(&self, packet_type: u8) -> u64 {
+        self.sent[packet_type as usize & 0x0F].load(Ordering::Relaxed)
+    }
+
+    /// Packets of a type received so far, 3 for PUBLISH
+    pub fn received(&self, packet_type: u8) -> u64 {
+        self.received[packet_type as usize & 0x0F].load(Ordering::Relaxed)
+    }
+
+    pub fn total_sent(&self) -> u64 {
+        self.sent.iter().map(|n| n.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn total_received(&self) -> u64 {
+        self.received
+            .iter()
+            .map(|n| n.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+impl PacketInterceptor for CountingInterceptor {
+    fn on_send(&self, packet: &mut Packet) {
+        self.sent[packet.packet_type() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_receive(&self, packet: &mut Packet) {
+        self.received[packet.packet_type() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod intercept_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+    use crate::mqtt::{Protocol, Qos, Request, Response};
+    use crate::testing::{duplex, Duplex};
+    use std::time::Duration;
+
+    /// An in-memory connection to a broker of its own
+    fn dial() -> io::Result<Duplex> {
+        let broker = Broker::new(BrokerOptions::default())?;
+        let (client, server) = duplex();
+        broker.serve_transport(server)?;
+        Ok(client)
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Rewrites incoming payloads and drops outgoing PUBLISH to `drop/#`
+    struct Tamper;
+
+    impl PacketInterceptor for Tamper {
+        fn on_send(&self, packet: &mut Packet) {
+            if packet.name() == "PUBLISH" && packet.bytes()[4..].starts_with(b"drop/") {
+                packet.discard();
+            }
+        }
+
+        fn on_receive(&self, packet: &mut Packet) {
+            if let Ok(Response::Publish {
+                packet_id,
+                qos,
+                retain,
+                topic,
+                ..
+            }) = packet.decode::<Response>()
+            {
+                let tampered = Request::Publish {
+                    packet_id,
+                    qos,
+                    dup: false,
+                    retain,
+                    topic,
+                    payload: b"tampered".to_vec(),
+                };
+                packet.replace(&tampered).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_interceptors() -> io::Result<()> {
+        let counter = Arc::new(CountingInterceptor::default());
+        let log = Shared::default();
+        let mut client = Protocol::builder()
+            .client_id("intercepted")
+            .interceptor(counter.clone())
+            .interceptor(Arc::new(LoggingInterceptor::new(log.clone())))
+            .connect_transport(dial()?)?;
+        client.add_interceptor(Arc::new(Tamper));
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        client.subscribe("#", Qos::AtMostOnce)?;
+        client.publish("drop/a", b"lost")?;
+        client.publish("keep/a", b"original")?;
+        let mut delivered = vec![];
+        while delivered.is_empty() {
+            if let Response::Publish { topic, payload, .. } = client.read_message::<Response>()? {
+                delivered.push((topic, payload));
+            }
+        }
+        assert_eq!(delivered, [("keep/a".to_string(), b"tampered".to_vec())]);
+        // Both PUBLISH are counted, interceptors before Tamper see them all
+        assert_eq!(counter.sent(1), 1);
+        assert_eq!(counter.sent(3), 2);
+        assert_eq!(counter.received(2), 1);
+        assert_eq!(counter.received(9), 1);
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(log.starts_with("> CONNECT "));
+        assert!(log.contains("\n< CONNACK 4 bytes\n> SUBSCRIBE "));
+        Ok(())
+    }
+}
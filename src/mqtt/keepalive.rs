@@ -0,0 +1,218 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Called with how long the connection went without application messages,
+/// see `ProtocolBuilder::on_idle`
+#[derive(Clone)]
+pub struct IdleHook(Arc<dyn Fn(Duration) + Send + Sync>);
+
+impl IdleHook {
+    pub fn new(hook: impl Fn(Duration) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub fn call(&self, silence: Duration) {
+        (self.0)(silence)
+    }
+}
+
+impl fmt::Debug for IdleHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IdleHook")
+    }
+}
+
+/// What the connection has to do next, see `KeepAlive::poll`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeepAliveEvent {
+    /// Nothing was sent for a whole keepalive, send PINGREQ
+    Ping,
+    /// No application message arrived for the idle timeout, reported once
+    /// per silence
+    Idle(Duration),
+    /// The last PINGREQ went unanswered for a whole keepalive
+    Dead,
+}
+
+/// Tracks when a connection last sent a packet and received a message, to
+/// ping the broker before it considers the client gone and to notice data
+/// sources going silent while the connection itself is fine
+#[derive(Debug)]
+pub struct KeepAlive {
+    /// Zero disables pings
+    interval: Duration,
+    idle_timeout: Option<Duration>,
+    last_sent: Instant,
+    last_message: Instant,
+    ping_sent: Option<Instant>,
+    idle: bool,
+    /// Packets sent as last counted by `observe_sent`
+    packets_sent: u64,
+}
+
+impl KeepAlive {
+    pub fn new(interval: Duration, idle_timeout: Option<Duration>, now: Instant) -> Self {
+        Self {
+            interval,
+            idle_timeout,
+            last_sent: now,
+            last_message: now,
+            ping_sent: None,
+            idle: false,
+            packets_sent: 0,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Take note of the packets sent so far on the connection, any sent
+    /// since the last call count as sent `now`
+    pub fn observe_sent(&mut self, packets_sent: u64, now: Instant) {
+        if packets_sent != self.packets_sent {
+            self.packets_sent = packets_sent;
+            self.last_sent = now;
+        }
+    }
+
+    /// An application message arrived
+    pub fn on_message(&mut self, now: Instant) {
+        self.last_message = now;
+        self.idle = false;
+    }
+
+    pub fn on_pingresp(&mut self) {
+        self.ping_sent = None;
+    }
+
+    /// When `poll` has something to report next, `None` if never
+    pub fn deadline(&self) -> Option<Instant> {
+        let ping = match self.ping_sent {
+            _ if self.interval.is_zero() => None,
+            Some(sent) => Some(sent + self.interval),
+            None => Some(self.last_sent + self.interval),
+        };
+        let idle = self
+            .idle_timeout
+            .filter(|_| !self.idle)
+            .map(|timeout| self.last_message + timeout);
+        ping.into_iter().chain(idle).min()
+    }
+
+    /// The next thing to do, to be called until it returns `None`
+    pub fn poll(&mut self, now: Instant) -> Option<KeepAliveEvent> {
+        if let Some(sent) = self.ping_sent {
+            if now >= sent + self.interval {
+                return Some(KeepAliveEvent::Dead);
+            }
+        }
+        if let Some(timeout) = self.idle_timeout {
+            let silence = now.saturating_duration_since(self.last_message);
+            if !self.idle && silence >= timeout {
+                self.idle = true;
+                return Some(KeepAliveEvent::Idle(silence));
+            }
+        }
+        if !self.interval.is_zero()
+            && self.ping_sent.is_none()
+            && now >= self.last_sent + self.interval
+        {
+            self.ping_sent = Some(now);
+            return Some(KeepAliveEvent::Ping);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod keepalive_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+    use crate::mqtt::{CountingInterceptor, Protocol};
+    use crate::testing::duplex;
+    use std::io;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_ping() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut keepalive = KeepAlive::new(Duration::from_secs(10), None, start);
+        assert_eq!(keepalive.deadline(), Some(at(10)));
+        keepalive.observe_sent(1, at(4));
+        assert_eq!(keepalive.poll(at(10)), None);
+        assert_eq!(keepalive.poll(at(14)), Some(KeepAliveEvent::Ping));
+        assert_eq!(keepalive.poll(at(14)), None);
+        // Sending the PINGREQ itself doesn't postpone its deadline
+        keepalive.observe_sent(2, at(14));
+        assert_eq!(keepalive.deadline(), Some(at(24)));
+        keepalive.on_pingresp();
+        assert_eq!(keepalive.poll(at(24)), Some(KeepAliveEvent::Ping));
+        assert_eq!(keepalive.poll(at(34)), Some(KeepAliveEvent::Dead));
+
+        let mut disabled = KeepAlive::new(Duration::ZERO, None, start);
+        assert_eq!((disabled.deadline(), disabled.poll(at(1000))), (None, None));
+    }
+
+    #[test]
+    fn test_idle() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut keepalive = KeepAlive::new(Duration::ZERO, Some(Duration::from_secs(30)), start);
+        keepalive.on_message(at(10));
+        assert_eq!(keepalive.deadline(), Some(at(40)));
+        assert_eq!(keepalive.poll(at(39)), None);
+        assert_eq!(
+            keepalive.poll(at(41)),
+            Some(KeepAliveEvent::Idle(Duration::from_secs(31)))
+        );
+        // Reported once until messages flow again
+        assert_eq!(keepalive.deadline(), None);
+        assert_eq!(keepalive.poll(at(100)), None);
+        keepalive.on_message(at(100));
+        assert_eq!(
+            keepalive.poll(at(130)),
+            Some(KeepAliveEvent::Idle(Duration::from_secs(30)))
+        );
+    }
+
+    #[test]
+    fn test_next_message_pings() -> io::Result<()> {
+        let broker = Broker::new(BrokerOptions::default())?;
+        let (transport, server) = duplex();
+        broker.serve_transport(server)?;
+        let counter = Arc::new(CountingInterceptor::default());
+        let silences = Arc::new(Mutex::new(vec![]));
+        let idle = Arc::clone(&silences);
+        let mut client = Protocol::builder()
+            .client_id("pinger")
+            .keepalive(1)
+            .interceptor(counter.clone())
+            .on_idle(Duration::from_millis(1500), move |silence| {
+                idle.lock().unwrap().push(silence)
+            })
+            .connect_transport(transport)?;
+        client.set_read_timeout(Some(Duration::from_millis(2500)))?;
+        let err = client.next_message().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // PINGREQ after 1s and 2s, both answered
+        assert_eq!(counter.sent(12), 2);
+        assert_eq!(counter.received(13), 2);
+        assert_eq!(silences.lock().unwrap().len(), 1);
+        Ok(())
+    }
+}
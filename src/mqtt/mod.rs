@@ -1,35 +1,142 @@
+mod async_connection;
+mod batch;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+mod browser;
+mod builder;
+mod client_id;
 mod connack;
 mod connect;
+mod connection;
+mod dedup;
+mod disconnect;
+mod events;
+mod frame;
+mod intercept;
+mod keepalive;
+mod offline;
+mod properties;
 mod puback;
 mod pubcomp;
 mod publish;
 mod pubrec;
 mod pubrel;
+mod quic;
+mod ratelimit;
+mod redirect;
+mod retry;
+#[cfg(feature = "serial")]
+mod serial;
+mod slip;
+mod sockopt;
+mod split;
+mod ssh;
+mod stats;
+mod strict;
+mod suback;
 mod subscribe;
+mod threaded;
+mod tls;
+mod transport;
+mod v5;
+mod validate;
+mod varint;
+mod wire;
+pub use async_connection::AsyncConnection;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub use browser::BrowserTransport;
+pub use builder::{ConnectionRefused, ProtocolBuilder};
 use byteorder::{ReadBytesExt, WriteBytesExt};
+pub use client_id::{
+    is_portable_client_id, random_client_id, validate_client_id, MAX_PORTABLE_CLIENT_ID_LEN,
+};
 use connack::ConnackPacket;
+pub use connack::ConnectReturnCode;
 use connect::ConnectPacket;
+pub use connect::{Will, MQTT_V4};
+pub(crate) use connection::PacketReader;
+pub use connection::{
+    ChannelOptions, ClientHandle, Connection, DeliveryToken, Overflow, DEFAULT_CHANNEL_CAPACITY,
+};
 use core::fmt::{self, Display, Formatter};
+pub use dedup::{Dedup, DEFAULT_DEDUP_CAPACITY};
+pub use disconnect::{DisconnectReason, Disconnected, DISCONNECT_WITH_WILL, NORMAL_DISCONNECTION};
+use events::is_connection_lost;
+pub use events::{DisconnectCause, Event, EventHook};
+pub use frame::FrameReader;
+use intercept::Interceptors;
+pub use intercept::{CountingInterceptor, LoggingInterceptor, Packet, PacketInterceptor};
+pub use keepalive::{IdleHook, KeepAlive, KeepAliveEvent};
+pub use offline::{DropPolicy, OfflineOptions, OfflineQueue, QueuedPublish};
+pub use properties::Property;
 use puback::PubackPacket;
 use pubcomp::PubcompPacket;
 use publish::PublishPacket;
 use pubrec::PubrecPacket;
 use pubrel::PubrelPacket;
+#[cfg(all(test, feature = "quic"))]
+pub(crate) use quic::relay as quic_relay;
+#[cfg(feature = "quic")]
+pub use quic::QuicTransport;
+pub use quic::{QuicUrl, DEFAULT_QUIC_PORT};
+pub use ratelimit::RateLimit;
+pub(crate) use ratelimit::RateLimiter;
+pub use redirect::{parse_server_reference, server_reference, SERVER_MOVED, USE_ANOTHER_SERVER};
+pub use retry::PublishOptions;
+#[cfg(feature = "serial")]
+pub use serial::{open_serial, SerialTransport};
+pub use slip::Slip;
+use split::PacketIds;
+pub use split::{MqttReader, MqttWriter};
+pub use ssh::JumpHost;
+#[cfg(feature = "ssh")]
+pub use ssh::SshTransport;
+pub use stats::{Stats, StatsSnapshot};
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::{self, Read, Write};
 use std::net::SocketAddr;
 use std::net::TcpStream;
-use subscribe::{SubscribePacket, SubscriptionTopic};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+pub use strict::Violation;
+use suback::SubackPacket;
+pub use suback::SUBACK_FAILURE;
+use subscribe::SubscribePacket;
+pub use subscribe::SubscriptionTopic;
+pub use threaded::ThreadedClient;
+#[cfg(feature = "tls")]
+pub use tls::TlsTransport;
+pub use tls::{PreSharedKey, TlsConfig, DEFAULT_TLS_PORT};
+pub use transport::{AsyncTransport, Transport};
+pub use v5::{Capabilities, ConnackV5, ConnectV5, PublishV5, ResponseV5, SubscribeV5, MQTT_V5};
+pub use validate::{check_string, validate_topic_filter, validate_topic_name, InvalidTopic};
+pub use varint::VarInt;
+pub use wire::WireDump;
 
 /// Error during serialization and deserialization
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransportError {
     PayloadTooLong,
+    /// A packet of `size` bytes, fixed header included, exceeds the
+    /// configured maximum
+    PacketTooLarge {
+        size: usize,
+        max: u32,
+    },
+    /// A remaining length encoded on more than 4 bytes
+    MalformedRemainingLength,
+    /// A packet breaking the spec was received in strict mode
+    ProtocolViolation(Violation),
 }
 
 impl Display for TransportError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "Error = {:?}", self)
+        match self {
+            TransportError::ProtocolViolation(violation) => {
+                write!(f, "Protocol violation: {}", violation)
+            }
+            _ => write!(f, "Error = {:?}", self),
+        }
     }
 }
 
@@ -37,92 +144,23 @@ impl Error for TransportError {}
 
 pub mod protocol {
 
-    use crate::mqtt::TransportError;
+    use crate::mqtt::VarInt;
     use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
     use std::io::{self, Read, Write};
 
-    const MAX_PAYLOAD_SIZE: usize = 268_435_455;
     pub const MQTT_HEADER_LEN: usize = 2;
 
-    /// Parses variable byte integer in the stream and returns the length
-    /// and number of bytes that make it. Used for remaining length calculation
-    /// as well as for calculating property lengths
+    /// Reads the remaining length of a fixed header, see `VarInt`
     pub fn read_remaining_length(buf: &mut impl Read) -> io::Result<u32> {
-        let mut c = buf.read_u8()?;
-        let mut mul = 1u64;
-        let mut val = if c & 128 == 0 { (c & 127) as u32 } else { 0u32 };
-
-        // stop when continue bit is 0
-        while (c & 128) != 0 {
-            val += ((c & 127) as u64 * mul) as u32;
-            mul *= 128;
-            c = buf.read_u8()?;
-        }
-        Ok(val)
+        VarInt::read(buf).map(VarInt::value)
     }
 
-    /// Writes remaining length to stream and returns number of bytes for remaining length
-    /// following the OASIS specs, encode the total length of the packet excluding header
-    /// and the space for the encoding itself into a 1-4 bytes using the continuation
-    /// bit technique to allow a dynamic storing size:
-    ///
-    /// Using the first 7 bits of a byte we can store values till 127 and use the
-    /// last bit as a switch to notify if the subsequent byte is used to store
-    /// remaining length or not.
-    /// Returns the number of bytes used to store the value passed.
+    /// Writes the remaining length of a fixed header, see `VarInt`, and
+    /// returns the number of bytes used to store it
     pub fn write_remaining_length(buf: &mut impl Write, len: usize) -> io::Result<usize> {
-        if len > MAX_PAYLOAD_SIZE {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                TransportError::PayloadTooLong,
-            ));
-        }
-
-        let mut done = false;
-        let mut x = len;
-        let mut count = 0;
-
-        while !done {
-            let mut byte = (x % 128) as u8;
-            x /= 128;
-            if x > 0 {
-                byte |= 128;
-            }
-
-            buf.write_u8(byte)?;
-            count += 1;
-            done = x == 0;
-        }
-
-        Ok(count)
-    }
-
-    ///
-    /// Returns the size of a packet. Useful to pack functions to know the expected
-    /// buffer size of the packet based on the opcode. Accept an optional pointer
-    /// to get the len reserved for storing the remaining length of the full packet
-    /// excluding the fixed header (1 byte) and the bytes needed to store the
-    /// value itself (1 to 3 bytes).
-    ///
-    pub fn mqtt_remaining_length_size(len: usize) -> usize {
-        //
-        // Here we take into account the number of bytes needed to store the total
-        // amount of bytes size of the packet, excluding the encoding space to
-        // store the value itself and the fixed header, updating len pointer if
-        // not NULL.
-        //
-        if (len - 1) > 0x200000 {
-            // 3 bytes <= 128 * 128 * 128
-            3
-        } else if (len - 1) > 0x4000 {
-            // 2 bytes <= 128 * 128
-            2
-        } else if (len - 1) > 0x80 {
-            // 1 byte  <= 128
-            1
-        } else {
-            0
-        }
+        VarInt::new(len)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .write(buf)
     }
 
     /// Reads a series of bytes with a length from a byte stream
@@ -139,19 +177,39 @@ pub mod protocol {
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"))
     }
 
-    /// Serializes bytes to stream 
+    /// Serializes bytes to stream
     pub fn write_bytes(buf: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
-        buf.write_all(&bytes)
+        buf.write_all(bytes)
     }
 
     /// Serializes a string to stream (including length)
     pub fn write_string(buf: &mut impl Write, string: &str) -> io::Result<()> {
         let message = string.as_bytes();
         buf.write_u16::<NetworkEndian>(message.len() as u16)?;
-        buf.write_all(&message)
+        buf.write_all(message)
     }
 }
 
+/// MQTT 3.1.1 control packet names by type, AUTH is MQTT 5 only
+pub const PACKET_NAMES: [&str; 16] = [
+    "RESERVED",
+    "CONNECT",
+    "CONNACK",
+    "PUBLISH",
+    "PUBACK",
+    "PUBREC",
+    "PUBREL",
+    "PUBCOMP",
+    "SUBSCRIBE",
+    "SUBACK",
+    "UNSUBSCRIBE",
+    "UNSUBACK",
+    "PINGREQ",
+    "PINGRESP",
+    "DISCONNECT",
+    "AUTH",
+];
+
 #[repr(u8)]
 #[derive(PartialEq, PartialOrd, Debug, Copy, Clone)]
 pub enum PacketType {
@@ -163,16 +221,17 @@ pub enum PacketType {
     Pubrel,
     Pubcomp,
     Subscribe,
-    // Suback,
+    Suback,
     // Unsubscribe,
     // Unsuback,
-    // PingReq,
-    // PingResp,
+    Pingreq = 12,
+    Pingresp,
     Disconnect,
     Unknown,
 }
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AckType {
     Puback(u16),
     Pubrec(u16),
@@ -191,6 +250,9 @@ impl From<&PacketType> for u8 {
             PacketType::Pubrel => 0x06,
             PacketType::Pubcomp => 0x07,
             PacketType::Subscribe => 0x08,
+            PacketType::Suback => 0x09,
+            PacketType::Pingreq => 0x0c,
+            PacketType::Pingresp => 0x0d,
             PacketType::Disconnect => 0x0e,
             PacketType::Unknown => 0xFF,
         }
@@ -208,6 +270,9 @@ impl From<u8> for PacketType {
             0x6 => PacketType::Pubrel,
             0x7 => PacketType::Pubcomp,
             0x8 => PacketType::Subscribe,
+            0x9 => PacketType::Suback,
+            0xC => PacketType::Pingreq,
+            0xD => PacketType::Pingresp,
             0xE => PacketType::Disconnect,
             _ => PacketType::Unknown,
         }
@@ -215,13 +280,22 @@ impl From<u8> for PacketType {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Qos {
     AtMostOnce,
     AtLeastOnce,
     ExactlyOnce,
 }
 
+impl Qos {
+    /// The QoS of a value read off the wire, `None` past 2 where
+    /// `Qos::from` panics
+    pub fn checked(value: u8) -> Option<Self> {
+        (value <= 2).then(|| Self::from(value))
+    }
+}
+
 impl From<u8> for Qos {
     fn from(orig: u8) -> Self {
         match orig {
@@ -257,14 +331,15 @@ impl FixedHeaderFlags {
 
     pub fn from_byte(byte: u8) -> Self {
         let flag: Vec<bool> = (0..4).map(|i| byte & (u8::pow(2, i)) != 0).collect();
-        let qos = flag[1..3].iter().enumerate().fold(0, |acc, (i, &b)| {
-            acc + (u8::pow(2, i as u32) * b as u8) as u8
-        });
+        let qos = flag[1..3]
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, &b)| acc + u8::pow(2, i as u32) * b as u8);
         Self::new(flag[0], qos, flag[3])
     }
 
     pub fn to_byte(&self) -> u8 {
-        self.retain as u8 | (self.qos as u8) << 1 | (self.dup as u8) << 3
+        self.retain as u8 | self.qos << 1 | (self.dup as u8) << 3
     }
 }
 
@@ -274,6 +349,7 @@ impl FixedHeaderFlags {
 /// - dup flag
 /// - QoS
 /// - retain flag
+///
 /// It's followed by the remaining_len of the packet, encoded onto 1 to 4
 /// bytes starting at bytes 2.
 ///
@@ -295,15 +371,26 @@ pub struct FixedHeader {
 
 impl fmt::Display for FixedHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.packet_type {
-            PacketType::Connect => write!(
-                f,
-                "CONNECT: d:{} q:{} r:{}",
-                self.flags.dup, self.flags.qos, self.flags.retain
-            ),
-            PacketType::Connack => write!(f, "CONNACK"),
-            _ => write!(f, "UNKNOWN"),
-        }
+        let name = match self.packet_type {
+            PacketType::Connect => "CONNECT",
+            PacketType::Connack => "CONNACK",
+            PacketType::Publish => "PUBLISH",
+            PacketType::Puback => "PUBACK",
+            PacketType::Pubrec => "PUBREC",
+            PacketType::Pubrel => "PUBREL",
+            PacketType::Pubcomp => "PUBCOMP",
+            PacketType::Subscribe => "SUBSCRIBE",
+            PacketType::Suback => "SUBACK",
+            PacketType::Pingreq => "PINGREQ",
+            PacketType::Pingresp => "PINGRESP",
+            PacketType::Disconnect => "DISCONNECT",
+            PacketType::Unknown => return write!(f, "UNKNOWN"),
+        };
+        write!(
+            f,
+            "{}: d:{} q:{} r:{}",
+            name, self.flags.dup, self.flags.qos, self.flags.retain
+        )
     }
 }
 
@@ -323,7 +410,7 @@ impl FixedHeader {
     pub fn from_bytes(bytes: &mut impl Read) -> io::Result<FixedHeader> {
         let opcode = bytes.read_u8()?;
         let len = protocol::read_remaining_length(bytes)?;
-        Ok(FixedHeader::new(opcode, len as u32))
+        Ok(FixedHeader::new(opcode, len))
     }
 
     pub fn write(&self, buf: &mut impl Write) -> io::Result<()> {
@@ -340,6 +427,19 @@ impl FixedHeader {
 pub trait Serialize {
     /// Serialize to a `Write`able buffer
     fn serialize(&self, buf: &mut impl Write) -> io::Result<usize>;
+
+    /// Check the packet before it is sent in strict mode, rejecting
+    /// contents brokers may refuse
+    fn validate(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Serialize to a new buffer, handy for transports other than TcpStream
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.serialize(&mut bytes)?;
+        Ok(bytes)
+    }
 }
 /// Trait for something that can be converted from bytes (&[u8])
 pub trait Deserialize {
@@ -348,17 +448,44 @@ pub trait Deserialize {
 
     /// Deserialize from a `Read`able buffer
     fn deserialize(buf: &mut impl Read) -> io::Result<Self::Output>;
+
+    /// Check a decoded packet against the rules enforced in strict mode
+    fn validate(_output: &Self::Output) -> Result<(), Violation> {
+        Ok(())
+    }
+
+    /// Deserialize a slice holding exactly one packet
+    fn from_slice(bytes: &[u8]) -> io::Result<Self::Output> {
+        let mut buf = bytes;
+        let packet = Self::deserialize(&mut buf)?;
+        if !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} trailing bytes after packet", buf.len()),
+            ));
+        }
+        Ok(packet)
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Request {
     Connect {
         client_id: String,
         clean_session: bool,
+        username: Option<String>,
+        password: Option<String>,
+        keepalive: u16,
+        will: Option<Will>,
     },
     Publish {
         packet_id: u16,
         qos: u8,
+        /// Set on retransmissions of a QoS > 0 message
+        dup: bool,
+        /// Ask the broker to keep the message for future subscriptions
+        retain: bool,
         topic: String,
         payload: Vec<u8>,
     },
@@ -378,24 +505,103 @@ pub enum Request {
         packet_id: u16,
         subscription_topics: Vec<SubscriptionTopic>,
     },
-    Disconnect,
+    Pingreq,
+    Disconnect {
+        /// MQTT 5 only, `None` sends the DISCONNECT of 3.1.1, which is
+        /// also a normal MQTT 5 one
+        reason: Option<DisconnectReason>,
+    },
+}
+
+impl Request {
+    /// The PUBLISH borrowing the topic and payload of this one, `None` for
+    /// other packets
+    pub fn as_publish(&self) -> Option<PublishRef<'_>> {
+        match self {
+            Request::Publish {
+                packet_id,
+                qos,
+                dup,
+                retain,
+                topic,
+                payload,
+            } => Some(PublishRef {
+                packet_id: *packet_id,
+                qos: *qos,
+                dup: *dup,
+                retain: *retain,
+                topic,
+                payload,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A PUBLISH borrowing its topic and payload, encoded as `Request::Publish`
+/// is without copying them into one first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PublishRef<'a> {
+    pub packet_id: u16,
+    pub qos: u8,
+    /// Set on retransmissions of a QoS > 0 message
+    pub dup: bool,
+    /// Ask the broker to keep the message for future subscriptions
+    pub retain: bool,
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+}
+
+impl PublishRef<'_> {
+    /// Everything following the first byte of the fixed header
+    fn write_remaining(&self, buf: &mut impl Write) -> io::Result<()> {
+        let len = 2 + self.topic.len() + self.payload.len() + if self.qos > 0 { 2 } else { 0 };
+        protocol::write_remaining_length(buf, len)?;
+        PublishPacket::write(buf, self.packet_id, self.qos, self.topic, self.payload)
+    }
+}
+
+impl Serialize for PublishRef<'_> {
+    fn validate(&self) -> io::Result<()> {
+        Ok(validate_topic_name(self.topic)?)
+    }
+
+    fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+        buf.write_u8(publish_byte(checked_qos(self.qos)?, self.dup, self.retain))?;
+        self.write_remaining(buf)?;
+        Ok(1)
+    }
 }
 
 impl From<&Request> for u8 {
     fn from(req: &Request) -> Self {
         match req {
             Request::Connect { .. } => 0x10,
-            Request::Publish { qos, .. } => encode_qos(0x30, Qos::from(*qos)),
+            // Reserved bits kept as they are, `serialize` refuses QoS 3
+            Request::Publish {
+                qos, dup, retain, ..
+            } => 0x30 | (*qos & 0x03) << 1 | (*dup as u8) << 3 | *retain as u8,
             Request::Puback { .. } => 0x40,
             Request::Pubrec { .. } => 0x50,
             Request::Pubrel { .. } => 0x62,
             Request::Pubcomp { .. } => 0x70,
-            Request::Subscribe { .. } => 0x80,
-            Request::Disconnect => 0xE0,
+            Request::Subscribe { .. } => 0x82,
+            Request::Pingreq => 0xC0,
+            Request::Disconnect { .. } => 0xE0,
         }
     }
 }
 
+/// QoS of a packet about to be written, 3 is reserved
+fn checked_qos(qos: u8) -> io::Result<Qos> {
+    Qos::checked(qos)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid QoS {}", qos)))
+}
+
+fn publish_byte(qos: Qos, dup: bool, retain: bool) -> u8 {
+    encode_qos(0x30, qos) | (dup as u8) << 3 | retain as u8
+}
+
 fn encode_qos(byte: u8, qos: Qos) -> u8 {
     let mask1 = 1 << 1;
     let mask2 = 1 << 2;
@@ -407,29 +613,56 @@ fn encode_qos(byte: u8, qos: Qos) -> u8 {
 }
 
 impl Serialize for Request {
+    fn validate(&self) -> io::Result<()> {
+        match self {
+            Request::Connect {
+                will: Some(will), ..
+            } => validate_topic_name(&will.topic)?,
+            Request::Publish { topic, .. } => validate_topic_name(topic)?,
+            Request::Subscribe {
+                subscription_topics,
+                ..
+            } => {
+                for s in subscription_topics {
+                    validate_topic_filter(&s.topic)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+        if let Request::Publish { qos, .. } = self {
+            checked_qos(*qos)?;
+        }
         buf.write_u8(self.into())?;
         match self {
             Request::Connect {
                 client_id,
                 clean_session,
+                username,
+                password,
+                keepalive,
+                will,
             } => {
-                let len = 10 + 2 + client_id.len();
+                let len = 10
+                    + 2
+                    + client_id.len()
+                    + will.as_ref().map_or(0, |w| w.len())
+                    + username.as_ref().map_or(0, |u| 2 + u.len())
+                    + password.as_ref().map_or(0, |p| 2 + p.len());
                 protocol::write_remaining_length(buf, len)?;
-                let connect = ConnectPacket::new(client_id.to_string(), *clean_session);
+                let connect = ConnectPacket::new(client_id.to_string(), *clean_session)
+                    .with_keepalive(*keepalive)
+                    .with_will(will.as_ref())
+                    .with_credentials(username.clone(), password.clone());
                 connect.write(buf)?;
             }
-            Request::Publish {
-                packet_id,
-                qos,
-                topic,
-                payload,
-            } => {
-                let len = 2 + topic.len() + payload.len() + if *qos > 0 { 2 } else { 0 };
-                protocol::write_remaining_length(buf, len)?;
-                let publish =
-                    PublishPacket::new(*packet_id, topic.to_string(), payload.to_vec(), *qos);
-                publish.write(buf)?;
+            Request::Publish { .. } => {
+                if let Some(publish) = self.as_publish() {
+                    publish.write_remaining(buf)?;
+                }
             }
             Request::Puback { packet_id } => {
                 let len = 2;
@@ -469,25 +702,30 @@ impl Serialize for Request {
             } => {
                 let len = 2 + subscription_topics
                     .iter()
-                    .map(|s| 2 + s.topic.len())
+                    .map(|s| 2 + s.topic.len() + 1)
                     .sum::<usize>();
                 protocol::write_remaining_length(buf, len)?;
-                let subscribe = SubscribePacket {
-                    packet_id: *packet_id,
-                    subscription_topics: subscription_topics.to_vec(),
-                };
+                let subscribe = SubscribePacket::new(*packet_id, subscription_topics.to_vec());
                 subscribe.write(buf)?;
             }
-            Request::Disconnect => {
+            Request::Pingreq => {
                 let len = 0;
                 protocol::write_remaining_length(buf, len)?;
             }
+            Request::Disconnect { reason } => {
+                let len = reason.as_ref().map_or(0, |r| r.len());
+                protocol::write_remaining_length(buf, len)?;
+                if let Some(reason) = reason {
+                    reason.write(buf)?;
+                }
+            }
         }
         Ok(1)
     }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Response {
     Connack {
         session_present: bool,
@@ -496,8 +734,11 @@ pub enum Response {
     Publish {
         packet_id: u16,
         qos: u8,
+        /// Replayed from the messages the broker retained
+        retain: bool,
         topic: String,
         payload: Vec<u8>,
+        properties: Vec<Property>,
     },
     Puback {
         packet_id: u16,
@@ -511,7 +752,23 @@ pub enum Response {
     Pubcomp {
         packet_id: u16,
     },
-    Unknown,
+    Suback {
+        packet_id: u16,
+        return_codes: Vec<u8>,
+    },
+    Pingresp,
+    /// Sent by MQTT 5 brokers ending the session, `Protocol::next_message`
+    /// reports it as a `Disconnected` error
+    Disconnect {
+        reason_code: u8,
+        properties: Vec<Property>,
+    },
+    /// A packet type not expected from a broker, with its whole body read
+    /// so the stream stays in step
+    Unknown {
+        packet_type: u8,
+        body: Vec<u8>,
+    },
 }
 
 impl Display for Response {
@@ -531,7 +788,17 @@ impl Display for Response {
             Response::Pubrec { packet_id } => write!(f, "PUBREC {:?}", packet_id),
             Response::Pubrel { packet_id } => write!(f, "PUBREL {:?}", packet_id),
             Response::Pubcomp { packet_id } => write!(f, "PUBCOMP {:?}", packet_id),
-            Response::Unknown => write!(f, "UNKNOWN"),
+            Response::Suback {
+                packet_id,
+                return_codes,
+            } => write!(f, "SUBACK {:?} {:?}", packet_id, return_codes),
+            Response::Pingresp => write!(f, "PINGRESP"),
+            Response::Disconnect { reason_code, .. } => {
+                write!(f, "DISCONNECT 0x{:02X}", reason_code)
+            }
+            Response::Unknown { packet_type, body } => {
+                write!(f, "UNKNOWN 0x{:X} ({} bytes)", packet_type, body.len())
+            }
         }
     }
 }
@@ -539,8 +806,14 @@ impl Display for Response {
 impl Deserialize for Response {
     type Output = Response;
 
+    fn validate(output: &Self::Output) -> Result<(), Violation> {
+        strict::check_response(output)
+    }
+
     fn deserialize(buf: &mut impl Read) -> io::Result<Self::Output> {
-        let fixed_header = FixedHeader::from_bytes(buf)?;
+        let opcode = buf.read_u8()?;
+        let len = protocol::read_remaining_length(buf)?;
+        let fixed_header = FixedHeader::new(opcode, len);
         let packet = match fixed_header.packet_type {
             PacketType::Connack => {
                 let connack = ConnackPacket::from_bytes(buf)?;
@@ -554,8 +827,10 @@ impl Deserialize for Response {
                 Response::Publish {
                     packet_id: publish.packet_id,
                     qos: publish.qos,
+                    retain: fixed_header.flags.retain,
                     topic: publish.topic,
                     payload: publish.payload,
+                    properties: vec![],
                 }
             }
             PacketType::Puback => {
@@ -582,25 +857,128 @@ impl Deserialize for Response {
                     packet_id: pubcomp.packet_id,
                 }
             }
-            _ => Response::Unknown,
+            PacketType::Suback => {
+                let suback = SubackPacket::from_bytes(buf, &fixed_header)?;
+                Response::Suback {
+                    packet_id: suback.packet_id,
+                    return_codes: suback.return_codes,
+                }
+            }
+            PacketType::Pingresp => Response::Pingresp,
+            // No reason code nor properties before MQTT 5
+            PacketType::Disconnect => Response::Disconnect {
+                reason_code: NORMAL_DISCONNECTION,
+                properties: vec![],
+            },
+            _ => {
+                let mut body = vec![0; len as usize];
+                buf.read_exact(&mut body)?;
+                Response::Unknown {
+                    packet_type: opcode >> 4,
+                    body,
+                }
+            }
         };
         Ok(packet)
     }
 }
 
-/// Abstracted Protocol that wraps a TcpStream and manages
+/// Sends DISCONNECT when the owning `Protocol` goes out of scope, so the
+/// broker doesn't consider the client dead and publish its will
+#[derive(Debug)]
+struct DisconnectOnDrop {
+    stream: Box<dyn Transport>,
+    grace: Option<Duration>,
+}
+
+impl Drop for DisconnectOnDrop {
+    fn drop(&mut self) {
+        let Some(grace) = self.grace else {
+            return;
+        };
+        // Best effort, never block the drop for longer than the grace period
+        if self.stream.set_write_timeout(Some(grace)).is_ok()
+            && (Request::Disconnect { reason: None })
+                .serialize(&mut self.stream)
+                .is_ok()
+        {
+            let _ = self.stream.flush();
+            let _ = self.stream.shutdown(std::net::Shutdown::Write);
+        }
+    }
+}
+
+/// Read the next packet with the decoder of MQTT 5 or 3.1.1
+pub(crate) fn read_packet(reader: &mut MqttReader, v5: bool) -> io::Result<Response> {
+    match v5 {
+        true => reader.read_message::<ResponseV5>(),
+        false => reader.read_message::<Response>(),
+    }
+}
+
+/// Abstracted Protocol that wraps a transport and manages
 /// sending & receiving of messages
 pub struct Protocol {
-    reader: io::BufReader<TcpStream>,
-    stream: TcpStream,
+    reader: MqttReader,
+    writer: MqttWriter,
+    on_drop: DisconnectOnDrop,
+    session_present: bool,
+    keepalive: KeepAlive,
+    on_idle: Option<IdleHook>,
+    on_event: Option<EventHook>,
+    /// Reconnections leading to this client, see `ProtocolBuilder::reconnect`
+    reconnects: u32,
+    /// As set by `set_read_timeout`, `next_message` changes the one of the
+    /// socket to wake up for pings
+    read_timeout: Option<Duration>,
+    /// Advertised in the CONNACK of MQTT 5 sessions, `None` with 3.1.1
+    capabilities: Option<Capabilities>,
+    client_id: String,
+    /// Highest QoS the broker accepts, advertised by MQTT 5 brokers and
+    /// inferred from SUBACKs granting less than requested with 3.1.1
+    maximum_qos: Qos,
+    /// QoS requested by the SUBSCRIBEs awaiting their SUBACK
+    requested_qos: HashMap<u16, Qos>,
+    /// Whether `publish_with` already warned of a lowered QoS
+    qos_downgraded: bool,
 }
 
 impl Protocol {
+    /// Start configuring a connection, see `ProtocolBuilder`
+    pub fn builder() -> ProtocolBuilder {
+        ProtocolBuilder::default()
+    }
+
     /// Wrap a TcpStream with Protocol
     pub fn with_stream(stream: TcpStream) -> io::Result<Self> {
+        Self::with_transport(stream)
+    }
+
+    /// Wrap a stream the caller already set up, a serial port or a tunnel,
+    /// with Protocol. No CONNECT is sent, see `ProtocolBuilder::connect_transport`
+    pub fn with_transport(transport: impl Transport + 'static) -> io::Result<Self> {
+        let stats = Arc::new(Stats::default());
+        let interceptors = Arc::new(Interceptors::default());
         Ok(Self {
-            reader: io::BufReader::new(stream.try_clone()?),
-            stream,
+            reader: MqttReader::from_boxed(transport.try_clone()?, Arc::clone(&stats))
+                .with_interceptors(Arc::clone(&interceptors)),
+            on_drop: DisconnectOnDrop {
+                stream: transport.try_clone()?,
+                grace: None,
+            },
+            writer: MqttWriter::new(transport, Arc::new(PacketIds::default()), stats)
+                .with_interceptors(interceptors),
+            session_present: false,
+            keepalive: KeepAlive::new(Duration::ZERO, None, Instant::now()),
+            on_idle: None,
+            on_event: None,
+            reconnects: 0,
+            read_timeout: None,
+            capabilities: None,
+            client_id: String::new(),
+            maximum_qos: Qos::ExactlyOnce,
+            requested_qos: HashMap::new(),
+            qos_downgraded: false,
         })
     }
 
@@ -611,43 +989,459 @@ impl Protocol {
         Self::with_stream(stream)
     }
 
-    pub fn disconnect(&mut self) -> io::Result<()> {
-        let disconnect_request = Request::Disconnect;
-        self.send_message(&disconnect_request)
+    /// Establish a connection, failing if it can't be done within `timeout`
+    pub fn connect_timeout(dest: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect_timeout(&dest, timeout)?;
+        eprintln!("Connecting to {}", dest);
+        Self::with_stream(stream)
+    }
+
+    /// Try to connect to each address in order, returning the first connection
+    /// established or the last error if none succeeds
+    pub fn connect_failover(addrs: &[SocketAddr], timeout: Duration) -> io::Result<Self> {
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+        for addr in addrs {
+            match Self::connect_timeout(*addr, timeout) {
+                Ok(protocol) => return Ok(protocol),
+                Err(e) => {
+                    eprintln!("Failed connecting to {}: {}", addr, e);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Split the connection into independent reading and writing halves, so
+    /// that messages can be read on one thread while others publish
+    pub fn split(self) -> (MqttReader, MqttWriter) {
+        let Self {
+            reader,
+            writer,
+            mut on_drop,
+            ..
+        } = self;
+        on_drop.grace = None;
+        (reader, writer)
+    }
+
+    /// Hand the reading half over to another thread, which passes what it
+    /// reads to `observe_response`. The protocol itself can't read anymore
+    pub(crate) fn take_reader(&mut self) -> io::Result<MqttReader> {
+        let unused = MqttReader::from_boxed(self.writer.transport().try_clone()?, Arc::default());
+        Ok(std::mem::replace(&mut self.reader, unused))
+    }
+
+    /// Send DISCONNECT when dropped, waiting at most `grace` for it to be
+    /// written. `None` drops the connection silently, which is the default
+    /// for connections not established through `ProtocolBuilder`
+    pub fn set_disconnect_on_drop(&mut self, grace: Option<Duration>) {
+        self.on_drop.grace = grace;
+    }
+
+    pub fn disconnect_on_drop(&self) -> Option<Duration> {
+        self.on_drop.grace
+    }
+
+    /// Hold publishes back to `limit`, whichever method sends them. Both
+    /// halves of `split` share it, as the handles of a `ThreadedClient`
+    pub fn set_rate_limit(&mut self, limit: RateLimit) {
+        self.writer.set_rate_limit(Some(limit));
+    }
+
+    /// Reject packets breaking the spec, see `ProtocolBuilder::strict`
+    pub fn set_strict(&mut self, strict: bool) {
+        self.reader.set_strict(strict);
+        self.writer.set_strict(strict);
+    }
+
+    /// Run `interceptor` on every packet of the connection, sent or
+    /// received, after those added before. Both halves of `split` keep
+    /// them, so they also apply to a `ThreadedClient` spawned afterwards
+    pub fn add_interceptor(&self, interceptor: Arc<dyn PacketInterceptor>) {
+        self.writer.add_interceptor(interceptor);
+    }
+
+    /// Whether the broker resumed a session kept from a previous connection,
+    /// subscriptions included, as the CONNACK received by `ProtocolBuilder`
+    /// said. Always false with a clean session
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// Client id of the session as set by `ProtocolBuilder`, or the one an
+    /// MQTT 5 broker assigned when connecting with an empty one. Empty for
+    /// connections not established through `ProtocolBuilder`
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// What the broker advertised in its CONNACK, `None` unless connected
+    /// with MQTT 5 by `ProtocolBuilder::protocol_level`
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// Highest QoS the broker accepts for publishes. MQTT 5 brokers
+    /// advertise it, 3.1.1 ones don't so it is lowered whenever a SUBACK
+    /// grants less than requested, QoS 2 until then
+    pub fn maximum_qos(&self) -> Qos {
+        self.maximum_qos
+    }
+
+    /// QoS `publish_with` publishes with when asked for `qos`, at most the
+    /// maximum of the broker as brokers disconnect clients exceeding it
+    pub fn effective_qos(&self, qos: Qos) -> Qos {
+        match u8::from(&qos) > u8::from(&self.maximum_qos) {
+            true => self.maximum_qos,
+            false => qos,
+        }
+    }
+
+    /// `effective_qos`, warning the first time it is lower than asked
+    fn downgrade_qos(&mut self, qos: Qos) -> Qos {
+        let effective = self.effective_qos(qos);
+        if effective != qos && !self.qos_downgraded {
+            self.qos_downgraded = true;
+            eprintln!(
+                "Broker maximum QoS is {}, publishing with it instead of QoS {}",
+                u8::from(&effective),
+                u8::from(&qos)
+            );
+        }
+        effective
+    }
+
+    /// 5 when connected with MQTT 5, 4 otherwise
+    pub fn protocol_level(&self) -> u8 {
+        self.capabilities
+            .as_ref()
+            .map_or(connect::MQTT_V4, |c| c.protocol_level)
+    }
+
+    /// Call `hook` with the events of the connection from now on, see
+    /// `ProtocolBuilder::on_event`
+    pub fn set_on_event(&mut self, hook: EventHook) {
+        self.on_event = Some(hook);
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        if let Some(hook) = &self.on_event {
+            hook.call(&event);
+        }
     }
 
-    pub fn publish(&mut self, topic: &str, message: &[u8]) -> io::Result<()> {
-        let pub_req = Request::Publish {
-            packet_id: 1,
-            qos: 1,
-            topic: topic.to_string(),
-            payload: message.to_vec(),
+    /// Reconnections made by `ProtocolBuilder::reconnect` before this
+    /// client, zero for the first connection
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects
+    }
+
+    /// Read the next packet with the decoder of the protocol level
+    pub(crate) fn read_response(&mut self) -> io::Result<Response> {
+        let response = read_packet(&mut self.reader, self.capabilities.is_some());
+        self.observe_response(response)
+    }
+
+    /// Take note of a packet read, or of why reading failed, for the events
+    /// and the maximum QoS. A DISCONNECT from the broker becomes an error
+    pub(crate) fn observe_response(
+        &mut self,
+        response: io::Result<Response>,
+    ) -> io::Result<Response> {
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                if is_connection_lost(&e) {
+                    self.emit(Event::Disconnected {
+                        reason: DisconnectCause::Lost(e.kind()),
+                    });
+                }
+                return Err(e);
+            }
         };
-        self.send_message(&pub_req)
+        match &response {
+            Response::Disconnect {
+                reason_code,
+                properties,
+            } => {
+                let disconnected = Disconnected::new(*reason_code, properties);
+                self.emit(Event::Disconnected {
+                    reason: DisconnectCause::Broker(disconnected.clone()),
+                });
+                return Err(disconnected.into());
+            }
+            Response::Suback {
+                packet_id,
+                return_codes,
+            } => {
+                self.observe_suback(*packet_id, return_codes);
+                self.emit(Event::SubscriptionGranted {
+                    packet_id: *packet_id,
+                    return_codes: return_codes.clone(),
+                });
+            }
+            Response::Puback { packet_id } | Response::Pubcomp { packet_id } => {
+                self.emit(Event::PublishAcked {
+                    packet_id: *packet_id,
+                })
+            }
+            _ => {}
+        }
+        Ok(response)
     }
 
-    pub fn ack(&mut self, ack_type: AckType) -> io::Result<()> {
-        let ack_request = match ack_type {
-            AckType::Puback(pkt_id) => Request::Puback { packet_id: pkt_id },
-            AckType::Pubrec(pkt_id) => Request::Pubrec { packet_id: pkt_id },
-            AckType::Pubrel(pkt_id) => Request::Pubrel { packet_id: pkt_id },
-            AckType::Pubcomp(pkt_id) => Request::Pubcomp { packet_id: pkt_id },
+    /// Lower the maximum QoS of 3.1.1 brokers to the one a SUBACK granted
+    /// when less than requested, the closest they come to advertising it
+    fn observe_suback(&mut self, packet_id: u16, return_codes: &[u8]) {
+        let Some(requested) = self.requested_qos.remove(&packet_id) else {
+            return;
         };
-        self.send_message(&ack_request)
+        let granted = return_codes.iter().copied().filter(|code| *code <= 2).min();
+        if let Some(granted) = granted {
+            if granted < u8::from(&requested) {
+                self.maximum_qos = self.effective_qos(Qos::from(granted));
+            }
+        }
     }
 
-    /// Serialize a message to the server and write it to the TcpStream
+    /// Reject incoming packets larger than `max` bytes before allocating
+    /// them, protecting against hostile brokers
+    pub fn set_max_incoming_packet_size(&mut self, max: Option<u32>) {
+        self.reader.set_max_packet_size(max);
+    }
+
+    /// Refuse to send packets larger than `max` bytes, the limit a broker
+    /// advertises as Maximum Packet Size
+    pub fn set_max_outgoing_packet_size(&mut self, max: Option<u32>) {
+        self.writer.set_max_packet_size(max);
+    }
+
+    /// Address of the broker this client is connected to, `Unsupported`
+    /// over transports without one
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.writer.transport().peer_addr()
+    }
+
+    /// Packets and bytes exchanged so far on this connection
+    pub fn stats(&self) -> StatsSnapshot {
+        self.writer.stats().snapshot()
+    }
+
+    /// Shrink or grow the kernel receive buffer of the connection, a small
+    /// one makes a slow reader push back on the broker sooner. Only TCP
+    /// connections have one, other transports fail with `Unsupported`
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        sockopt::set_recv_buffer_size(self.tcp_stream()?, size)
+    }
+
+    /// Kernel receive buffer of the connection, as the kernel rounded it
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        sockopt::recv_buffer_size(self.tcp_stream()?)
+    }
+
+    fn tcp_stream(&self) -> io::Result<&TcpStream> {
+        self.writer.transport().as_tcp().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Socket options need a TCP transport",
+            )
+        })
+    }
+
+    /// Bound the time `read_message` is allowed to block, `None` blocks forever
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.reader.set_read_timeout(timeout)?;
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Keepalive after which `next_message` pings the broker if nothing was
+    /// sent, zero disables pings. `ProtocolBuilder` sets the one of the
+    /// CONNECT, to be replaced by the Server Keep Alive a v5 broker imposes,
+    /// see `Capabilities::keepalive`
+    pub fn set_keepalive(&mut self, keepalive: Duration) {
+        self.keepalive.set_interval(keepalive);
+    }
+
+    pub fn keepalive(&self) -> Duration {
+        self.keepalive.interval()
+    }
+
+    /// Call `hook` from `next_message` when no PUBLISH arrived for `timeout`,
+    /// once per silence, even though the broker still answers pings
+    pub fn set_on_idle(&mut self, timeout: Duration, hook: IdleHook) {
+        self.keepalive.set_idle_timeout(Some(timeout));
+        self.on_idle = Some(hook);
+    }
+
+    /// Read the next packet like `read_message::<Response>`, keeping the
+    /// connection alive meanwhile: PINGREQ is sent whenever nothing was sent
+    /// for the keepalive, PINGRESP are consumed and the idle hook is called
+    /// on silences. Fails with `TimedOut` if a PINGREQ goes unanswered for a
+    /// whole keepalive, and with `WouldBlock` once the read timeout elapses
+    pub fn next_message(&mut self) -> io::Result<Response> {
+        let result = self.next_message_keepalive();
+        self.reader.set_read_timeout(self.read_timeout)?;
+        result
+    }
+
+    /// Ping the broker if nothing was sent for the keepalive and call the
+    /// idle hook on silences, failing with `TimedOut` if a PINGREQ went
+    /// unanswered for a whole keepalive
+    pub(crate) fn keep_alive(&mut self, now: Instant) -> io::Result<()> {
+        let packets_sent = self.writer.stats().snapshot().packets_sent;
+        self.keepalive.observe_sent(packets_sent, now);
+        while let Some(event) = self.keepalive.poll(now) {
+            match event {
+                KeepAliveEvent::Ping => self.writer.send_message(&Request::Pingreq)?,
+                KeepAliveEvent::Idle(silence) => {
+                    if let Some(hook) = &self.on_idle {
+                        hook.call(silence);
+                    }
+                }
+                KeepAliveEvent::Dead => {
+                    self.emit(Event::PingTimeout);
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "No PINGRESP from the broker within the keepalive",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// When `keep_alive` has something to do next, `None` if never
+    pub(crate) fn keepalive_deadline(&self) -> Option<Instant> {
+        self.keepalive.deadline()
+    }
+
+    /// Take note of `response` for the keepalive, PINGRESP is consumed
+    pub(crate) fn keepalive_received(&mut self, response: Response) -> Option<Response> {
+        match response {
+            Response::Pingresp => {
+                self.keepalive.on_pingresp();
+                None
+            }
+            response => {
+                if let Response::Publish { .. } = response {
+                    self.keepalive.on_message(Instant::now());
+                }
+                Some(response)
+            }
+        }
+    }
+
+    fn next_message_keepalive(&mut self) -> io::Result<Response> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let now = Instant::now();
+            self.keep_alive(now)?;
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "No message within the read timeout",
+                ));
+            }
+            let wake = deadline.into_iter().chain(self.keepalive.deadline()).min();
+            // A zero timeout is refused by the socket
+            let timeout = wake.map(|wake| (wake - now).max(Duration::from_millis(1)));
+            self.reader.set_read_timeout(timeout)?;
+            match self.read_response() {
+                Ok(response) => {
+                    if let Some(response) = self.keepalive_received(response) {
+                        return Ok(response);
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        self.on_drop.grace = None;
+        self.writer.disconnect()?;
+        self.emit(Event::Disconnected {
+            reason: DisconnectCause::Client,
+        });
+        Ok(())
+    }
+
+    /// Disconnect telling an MQTT 5 broker why, `DISCONNECT_WITH_WILL`
+    /// having it publish the will anyway. 3.1.1 sessions only accept the
+    /// empty reason of a normal disconnection
+    pub fn disconnect_with(&mut self, reason: DisconnectReason) -> io::Result<()> {
+        if !reason.is_empty() && self.capabilities.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "DISCONNECT reasons need an MQTT 5 session",
+            ));
+        }
+        self.on_drop.grace = None;
+        self.writer.send_message(&Request::Disconnect {
+            reason: Some(reason),
+        })?;
+        self.emit(Event::Disconnected {
+            reason: DisconnectCause::Client,
+        });
+        Ok(())
+    }
+
+    /// Publish a QoS 1 message, returning the packet id the PUBACK will carry
+    pub fn publish(&mut self, topic: &str, message: &[u8]) -> io::Result<u16> {
+        self.writer.publish(topic, message)
+    }
+
+    /// Subscribe to a single topic, returning the packet id the SUBACK will carry
+    pub fn subscribe(&mut self, topic: &str, qos: Qos) -> io::Result<u16> {
+        if self.capabilities.is_none() {
+            let packet_id = self.writer.subscribe(topic, qos)?;
+            self.requested_qos.insert(packet_id, qos);
+            return Ok(packet_id);
+        }
+        let packet_id = self.next_packet_id();
+        self.send_message(&SubscribeV5 {
+            packet_id,
+            subscription_topics: vec![SubscriptionTopic {
+                qos,
+                topic: topic.to_string(),
+            }],
+            properties: vec![],
+        })?;
+        Ok(packet_id)
+    }
+
+    pub fn ack(&mut self, ack_type: AckType) -> io::Result<()> {
+        self.writer.ack(ack_type)
+    }
+
+    /// Allocate the next packet identifier
+    pub fn next_packet_id(&self) -> u16 {
+        self.writer.next_packet_id()
+    }
+
+    /// Serialize a message to the server and write it to the transport
     pub fn send_message(&mut self, message: &impl Serialize) -> io::Result<()> {
-        message.serialize(&mut self.stream)?;
-        self.stream.flush()
+        self.writer.send_message(message)
     }
 
-    /// Read a message from the inner TcpStream
+    /// Read a message from the transport
     ///
     /// NOTE: Will block until there's data to read (or deserialize fails with io::ErrorKind::Interrupted)
     ///       so only use when a message is expected to arrive
     pub fn read_message<T: Deserialize>(&mut self) -> io::Result<T::Output> {
-        T::deserialize(&mut self.reader)
+        self.reader.read_message::<T>()
+    }
+
+    /// Read a message if a complete one is available, without blocking
+    pub fn try_read_message<T: Deserialize>(&mut self) -> io::Result<Option<T::Output>> {
+        self.reader.try_read_message::<T>()
     }
 }
 
@@ -698,3 +1492,159 @@ mod fixed_headers_tests {
         assert_eq!(buffer, &[16, 18]);
     }
 }
+
+#[cfg(test)]
+mod codec_tests {
+    use super::*;
+
+    #[test]
+    fn test_to_bytes() -> io::Result<()> {
+        let bytes = Request::Puback { packet_id: 10 }.to_bytes()?;
+        assert_eq!(bytes, &[0x40, 2, 0, 10]);
+        assert_eq!(Request::Pingreq.to_bytes()?, &[0xC0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_slice() -> io::Result<()> {
+        match Response::from_slice(&[0x40, 2, 0, 10])? {
+            Response::Puback { packet_id } => assert_eq!(packet_id, 10),
+            resp => panic!("Unexpected response {}", resp),
+        }
+        let err = Response::from_slice(&[0x40, 2, 0, 10, 0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(Response::from_slice(&[0x40, 2, 0]).is_err());
+        assert!(matches!(
+            Response::from_slice(&[0xD0, 0])?,
+            Response::Pingresp
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_packet() -> io::Result<()> {
+        // An UNSUBACK then a PINGRESP, the first one read whole
+        let bytes = [0xB0, 3, 0, 7, 0, 0xD0, 0];
+        let mut buf = &bytes[..];
+        match Response::deserialize(&mut buf)? {
+            Response::Unknown { packet_type, body } => {
+                assert_eq!(packet_type, 0x0B);
+                assert_eq!(body, [0, 7, 0]);
+            }
+            resp => panic!("Unexpected response {}", resp),
+        }
+        assert!(matches!(
+            Response::deserialize(&mut buf)?,
+            Response::Pingresp
+        ));
+        let auth = ResponseV5::from_slice(&[0xF0, 2, 0x18, 0])?;
+        assert_eq!(auth.to_string(), "UNKNOWN 0xF (2 bytes)");
+        assert!(Response::from_slice(&[0xB0, 3, 0, 7]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_retain_flag() -> io::Result<()> {
+        let request = Request::Publish {
+            packet_id: 0,
+            qos: 0,
+            dup: false,
+            retain: true,
+            topic: "a".to_string(),
+            payload: b"x".to_vec(),
+        };
+        let bytes = request.to_bytes()?;
+        assert_eq!(bytes, &[0x31, 4, 0, 1, b'a', b'x']);
+        match Response::from_slice(&bytes)? {
+            Response::Publish { retain, .. } => assert!(retain),
+            resp => panic!("Unexpected response {}", resp),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_publish_ref() -> io::Result<()> {
+        let request = Request::Publish {
+            packet_id: 7,
+            qos: 2,
+            dup: true,
+            retain: false,
+            topic: "a/b".to_string(),
+            payload: b"xyz".to_vec(),
+        };
+        let publish = request.as_publish().unwrap();
+        assert_eq!((publish.topic, publish.payload), ("a/b", &b"xyz"[..]));
+        assert_eq!(publish.to_bytes()?, request.to_bytes()?);
+        assert_eq!(&publish.to_bytes()?[..2], &[0x3C, 10]);
+        assert!(Request::Pingreq.as_publish().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_publish_invalid_qos() {
+        let request = Request::Publish {
+            packet_id: 7,
+            qos: 3,
+            dup: false,
+            retain: false,
+            topic: "a".to_string(),
+            payload: vec![],
+        };
+        let err = request.to_bytes().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = request.as_publish().unwrap().to_bytes().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() -> io::Result<()> {
+        let mut will = Will::new("w", b"bye", Qos::ExactlyOnce, true);
+        will.user_properties = vec![("k".into(), "v".into())];
+        let requests = [
+            Request::Connect {
+                client_id: "c".into(),
+                clean_session: true,
+                username: Some("u".into()),
+                password: None,
+                keepalive: 60,
+                will: Some(will),
+            },
+            Request::Publish {
+                packet_id: 1,
+                qos: 1,
+                dup: false,
+                retain: true,
+                topic: "a".into(),
+                payload: vec![0, 0xFF],
+            },
+            Request::Subscribe {
+                packet_id: 2,
+                subscription_topics: vec![SubscriptionTopic {
+                    qos: Qos::AtLeastOnce,
+                    topic: "a/#".into(),
+                }],
+            },
+            Request::Disconnect {
+                reason: Some(DisconnectReason::default()),
+            },
+        ];
+        for request in requests {
+            let json = serde_json::to_string(&request)?;
+            let decoded: Request = serde_json::from_str(&json)?;
+            assert_eq!(decoded.to_bytes()?, request.to_bytes()?);
+        }
+        let response = Response::Publish {
+            packet_id: 3,
+            qos: 2,
+            retain: false,
+            topic: "b".into(),
+            payload: b"x".to_vec(),
+            properties: vec![Property::ContentType("text/plain".into())],
+        };
+        let json = serde_json::to_string(&response)?;
+        let decoded: Response = serde_json::from_str(&json)?;
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", response));
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+use crate::mqtt::{MqttWriter, Qos, Request};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+const DEFAULT_DEPTH: usize = 1000;
+
+/// What to do with a publish when the in-memory queue is full and no
+/// spillover log is configured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropPolicy {
+    /// Discard the oldest queued message to make room
+    DropOldest,
+    /// Discard the message being queued
+    DropNewest,
+    /// Wait until the queue is drained
+    Block,
+}
+
+/// Options of an `OfflineQueue`
+#[derive(Debug, Clone)]
+pub struct OfflineOptions {
+    /// Maximum number of messages kept in memory
+    pub depth: usize,
+    pub policy: DropPolicy,
+    /// Log file receiving the messages exceeding `depth`, when set the drop
+    /// policy never applies
+    pub spillover: Option<PathBuf>,
+}
+
+impl Default for OfflineOptions {
+    fn default() -> Self {
+        Self {
+            depth: DEFAULT_DEPTH,
+            policy: DropPolicy::DropOldest,
+            spillover: None,
+        }
+    }
+}
+
+/// A publish waiting for the connection to come back
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedPublish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: Qos,
+}
+
+impl QueuedPublish {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_u16::<BigEndian>(self.topic.len() as u16)?;
+        writer.write_all(self.topic.as_bytes())?;
+        writer.write_u32::<BigEndian>(self.payload.len() as u32)?;
+        writer.write_all(&self.payload)?;
+        writer.write_u8(u8::from(&self.qos))
+    }
+
+    /// Read the next record of a spillover log, `None` at the end of it
+    fn read(reader: &mut impl io::BufRead) -> io::Result<Option<Self>> {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut topic = vec![0; reader.read_u16::<BigEndian>()? as usize];
+        reader.read_exact(&mut topic)?;
+        let topic =
+            String::from_utf8(topic).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut payload = vec![0; reader.read_u32::<BigEndian>()? as usize];
+        reader.read_exact(&mut payload)?;
+        let qos = reader.read_u8()?;
+        let qos = Qos::checked(qos).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid QoS {} in the spillover log", qos),
+            )
+        })?;
+        Ok(Some(Self {
+            topic,
+            payload,
+            qos,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    memory: VecDeque<QueuedPublish>,
+    /// Number of messages currently in the spillover log, all of them newer
+    /// than the ones in memory
+    spilled: usize,
+    dropped: u64,
+}
+
+/// Bounded buffer of publishes issued while disconnected, drained in order
+/// once the connection is re-established. Can be shared between threads
+#[derive(Debug)]
+pub struct OfflineQueue {
+    options: OfflineOptions,
+    state: Mutex<State>,
+    drained: Condvar,
+}
+
+impl OfflineQueue {
+    pub fn new(options: OfflineOptions) -> io::Result<Self> {
+        // Messages left over by a previous run are still to be delivered
+        let spilled = match &options.spillover {
+            Some(path) if path.exists() => read_log(path)?.len(),
+            _ => 0,
+        };
+        Ok(Self {
+            options,
+            state: Mutex::new(State {
+                memory: VecDeque::new(),
+                spilled,
+                dropped: 0,
+            }),
+            drained: Condvar::new(),
+        })
+    }
+
+    /// Number of messages waiting, both in memory and spilled to disk
+    pub fn len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.memory.len() + state.spilled
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of messages discarded by the drop policy so far
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    /// Queue a publish, applying the drop policy if the queue is full
+    pub fn push(&self, publish: QueuedPublish) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(path) = &self.options.spillover {
+            // Once spilling started everything goes to disk to keep the order
+            if state.spilled > 0 || state.memory.len() >= self.options.depth {
+                append_log(path, &publish)?;
+                state.spilled += 1;
+                return Ok(());
+            }
+        }
+        while state.memory.len() >= self.options.depth {
+            match self.options.policy {
+                DropPolicy::DropOldest => {
+                    state.memory.pop_front();
+                    state.dropped += 1;
+                }
+                DropPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return Ok(());
+                }
+                DropPolicy::Block => state = self.drained.wait(state).unwrap(),
+            }
+        }
+        state.memory.push_back(publish);
+        Ok(())
+    }
+
+    /// Publish every queued message in order through `writer`. Stops at the
+    /// first error, leaving the message that failed at the head of the queue
+    pub fn drain(&self, writer: &mut MqttWriter) -> io::Result<usize> {
+        self.drain_with(|publish| {
+            let packet_id = match publish.qos {
+                Qos::AtMostOnce => 0,
+                _ => writer.next_packet_id(),
+            };
+            writer.send_message(&Request::Publish {
+                packet_id,
+                qos: u8::from(&publish.qos),
+                dup: false,
+                retain: false,
+                topic: publish.topic.clone(),
+                payload: publish.payload.clone(),
+            })
+        })
+    }
+
+    /// Like `drain`, handing each message to `send`
+    pub fn drain_with<F>(&self, mut send: F) -> io::Result<usize>
+    where
+        F: FnMut(&QueuedPublish) -> io::Result<()>,
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.spilled > 0 {
+            if let Some(path) = &self.options.spillover {
+                // Load the log behind the in-memory messages, it's rewritten
+                // only if the drain fails halfway
+                state.memory.extend(read_log(path)?);
+                File::create(path)?;
+                state.spilled = 0;
+            }
+        }
+        let mut sent = 0;
+        let result = loop {
+            let Some(publish) = state.memory.front() else {
+                break Ok(sent);
+            };
+            if let Err(e) = send(publish) {
+                break Err(e);
+            }
+            state.memory.pop_front();
+            sent += 1;
+        };
+        // Whatever didn't fit in memory goes back to the log
+        if let Some(path) = &self.options.spillover {
+            if state.memory.len() > self.options.depth {
+                let overflow: Vec<_> = state.memory.drain(self.options.depth..).collect();
+                for publish in &overflow {
+                    append_log(path, publish)?;
+                }
+                state.spilled = overflow.len();
+            }
+        }
+        self.drained.notify_all();
+        result
+    }
+}
+
+fn append_log(path: &Path, publish: &QueuedPublish) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    publish.write(&mut writer)?;
+    writer.flush()
+}
+
+fn read_log(path: &Path) -> io::Result<Vec<QueuedPublish>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut publishes = vec![];
+    while let Some(publish) = QueuedPublish::read(&mut reader)? {
+        publishes.push(publish);
+    }
+    Ok(publishes)
+}
+
+#[cfg(test)]
+mod offline_tests {
+    use super::*;
+
+    fn publish(n: u8) -> QueuedPublish {
+        QueuedPublish {
+            topic: "t".to_string(),
+            payload: vec![n],
+            qos: Qos::AtLeastOnce,
+        }
+    }
+
+    fn drain_all(queue: &OfflineQueue) -> io::Result<Vec<u8>> {
+        let mut payloads = vec![];
+        queue.drain_with(|p| {
+            payloads.push(p.payload[0]);
+            Ok(())
+        })?;
+        Ok(payloads)
+    }
+
+    #[test]
+    fn test_drop_policies() -> io::Result<()> {
+        for (policy, expected) in [
+            (DropPolicy::DropOldest, vec![1, 2]),
+            (DropPolicy::DropNewest, vec![0, 1]),
+        ] {
+            let queue = OfflineQueue::new(OfflineOptions {
+                depth: 2,
+                policy,
+                spillover: None,
+            })?;
+            for n in 0..3 {
+                queue.push(publish(n))?;
+            }
+            assert_eq!(queue.dropped(), 1);
+            assert_eq!(drain_all(&queue)?, expected);
+            assert!(queue.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_spillover_keeps_order() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("sake-offline-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let queue = OfflineQueue::new(OfflineOptions {
+            depth: 2,
+            policy: DropPolicy::DropNewest,
+            spillover: Some(path.clone()),
+        })?;
+        for n in 0..5 {
+            queue.push(publish(n))?;
+        }
+        assert_eq!(queue.len(), 5);
+        assert_eq!(queue.dropped(), 0);
+
+        // A failing drain keeps the undelivered messages
+        let mut budget = 1;
+        let result = queue.drain_with(|_| {
+            if budget == 0 {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+            budget -= 1;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(queue.len(), 4);
+        assert_eq!(drain_all(&queue)?, vec![1, 2, 3, 4]);
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_corrupt_spillover() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("sake-corrupt-{}.log", std::process::id()));
+        let mut bytes = vec![];
+        publish(0).write(&mut bytes)?;
+        *bytes.last_mut().unwrap() = 3;
+        std::fs::write(&path, &bytes)?;
+        let err = OfflineQueue::new(OfflineOptions {
+            spillover: Some(path.clone()),
+            ..OfflineOptions::default()
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path)
+    }
+}
@@ -0,0 +1,301 @@
+use crate::mqtt::{protocol, VarInt};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+/// MQTT 5 property, the optional metadata following the variable header of
+/// most packets. Which ones a packet may carry is up to the packet
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Property {
+    PayloadFormatIndicator(u8),
+    MessageExpiryInterval(u32),
+    ContentType(String),
+    ResponseTopic(String),
+    CorrelationData(Vec<u8>),
+    SubscriptionIdentifier(u32),
+    SessionExpiryInterval(u32),
+    AssignedClientIdentifier(String),
+    ServerKeepAlive(u16),
+    AuthenticationMethod(String),
+    AuthenticationData(Vec<u8>),
+    RequestProblemInformation(u8),
+    WillDelayInterval(u32),
+    RequestResponseInformation(u8),
+    ResponseInformation(String),
+    ServerReference(String),
+    ReasonString(String),
+    ReceiveMaximum(u16),
+    TopicAliasMaximum(u16),
+    TopicAlias(u16),
+    MaximumQos(u8),
+    RetainAvailable(bool),
+    UserProperty(String, String),
+    MaximumPacketSize(u32),
+    WildcardSubscriptionAvailable(bool),
+    SubscriptionIdentifierAvailable(bool),
+    SharedSubscriptionAvailable(bool),
+}
+
+fn invalid(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid property: {}", what),
+    )
+}
+
+fn read_binary(buf: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = buf.read_u16::<NetworkEndian>()?;
+    let mut bytes = vec![0; len as usize];
+    buf.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_binary(buf: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    buf.write_u16::<NetworkEndian>(bytes.len() as u16)?;
+    buf.write_all(bytes)
+}
+
+fn varint_len(value: usize) -> usize {
+    VarInt::new(value).map_or(4, VarInt::len)
+}
+
+fn write_varint(buf: &mut impl Write, value: usize) -> io::Result<()> {
+    VarInt::new(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        .write(buf)
+        .map(|_| ())
+}
+
+fn read_bool(buf: &mut impl Read, id: u8) -> io::Result<bool> {
+    match buf.read_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        byte => Err(invalid(format!("0x{:02X} holds {}, not 0 or 1", id, byte))),
+    }
+}
+
+impl Property {
+    /// Identifier preceding the value on the wire
+    pub fn id(&self) -> u8 {
+        match self {
+            Property::PayloadFormatIndicator(_) => 0x01,
+            Property::MessageExpiryInterval(_) => 0x02,
+            Property::ContentType(_) => 0x03,
+            Property::ResponseTopic(_) => 0x08,
+            Property::CorrelationData(_) => 0x09,
+            Property::SubscriptionIdentifier(_) => 0x0B,
+            Property::SessionExpiryInterval(_) => 0x11,
+            Property::AssignedClientIdentifier(_) => 0x12,
+            Property::ServerKeepAlive(_) => 0x13,
+            Property::AuthenticationMethod(_) => 0x15,
+            Property::AuthenticationData(_) => 0x16,
+            Property::RequestProblemInformation(_) => 0x17,
+            Property::WillDelayInterval(_) => 0x18,
+            Property::RequestResponseInformation(_) => 0x19,
+            Property::ResponseInformation(_) => 0x1A,
+            Property::ServerReference(_) => 0x1C,
+            Property::ReasonString(_) => 0x1F,
+            Property::ReceiveMaximum(_) => 0x21,
+            Property::TopicAliasMaximum(_) => 0x22,
+            Property::TopicAlias(_) => 0x23,
+            Property::MaximumQos(_) => 0x24,
+            Property::RetainAvailable(_) => 0x25,
+            Property::UserProperty(..) => 0x26,
+            Property::MaximumPacketSize(_) => 0x27,
+            Property::WildcardSubscriptionAvailable(_) => 0x28,
+            Property::SubscriptionIdentifierAvailable(_) => 0x29,
+            Property::SharedSubscriptionAvailable(_) => 0x2A,
+        }
+    }
+
+    /// Bytes taken on the wire, identifier included
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        1 + match self {
+            Property::PayloadFormatIndicator(_)
+            | Property::RequestProblemInformation(_)
+            | Property::RequestResponseInformation(_)
+            | Property::MaximumQos(_)
+            | Property::RetainAvailable(_)
+            | Property::WildcardSubscriptionAvailable(_)
+            | Property::SubscriptionIdentifierAvailable(_)
+            | Property::SharedSubscriptionAvailable(_) => 1,
+            Property::ServerKeepAlive(_)
+            | Property::ReceiveMaximum(_)
+            | Property::TopicAliasMaximum(_)
+            | Property::TopicAlias(_) => 2,
+            Property::MessageExpiryInterval(_)
+            | Property::SessionExpiryInterval(_)
+            | Property::WillDelayInterval(_)
+            | Property::MaximumPacketSize(_) => 4,
+            Property::SubscriptionIdentifier(value) => varint_len(*value as usize),
+            Property::ContentType(s)
+            | Property::ResponseTopic(s)
+            | Property::AssignedClientIdentifier(s)
+            | Property::AuthenticationMethod(s)
+            | Property::ResponseInformation(s)
+            | Property::ServerReference(s)
+            | Property::ReasonString(s) => 2 + s.len(),
+            Property::CorrelationData(bytes) | Property::AuthenticationData(bytes) => {
+                2 + bytes.len()
+            }
+            Property::UserProperty(key, value) => 4 + key.len() + value.len(),
+        }
+    }
+
+    pub fn write(&self, buf: &mut impl Write) -> io::Result<()> {
+        buf.write_u8(self.id())?;
+        match self {
+            Property::PayloadFormatIndicator(byte)
+            | Property::RequestProblemInformation(byte)
+            | Property::RequestResponseInformation(byte)
+            | Property::MaximumQos(byte) => buf.write_u8(*byte),
+            Property::RetainAvailable(flag)
+            | Property::WildcardSubscriptionAvailable(flag)
+            | Property::SubscriptionIdentifierAvailable(flag)
+            | Property::SharedSubscriptionAvailable(flag) => buf.write_u8(*flag as u8),
+            Property::ServerKeepAlive(value)
+            | Property::ReceiveMaximum(value)
+            | Property::TopicAliasMaximum(value)
+            | Property::TopicAlias(value) => buf.write_u16::<NetworkEndian>(*value),
+            Property::MessageExpiryInterval(value)
+            | Property::SessionExpiryInterval(value)
+            | Property::WillDelayInterval(value)
+            | Property::MaximumPacketSize(value) => buf.write_u32::<NetworkEndian>(*value),
+            Property::SubscriptionIdentifier(value) => write_varint(buf, *value as usize),
+            Property::ContentType(s)
+            | Property::ResponseTopic(s)
+            | Property::AssignedClientIdentifier(s)
+            | Property::AuthenticationMethod(s)
+            | Property::ResponseInformation(s)
+            | Property::ServerReference(s)
+            | Property::ReasonString(s) => protocol::write_string(buf, s),
+            Property::CorrelationData(bytes) | Property::AuthenticationData(bytes) => {
+                write_binary(buf, bytes)
+            }
+            Property::UserProperty(key, value) => {
+                protocol::write_string(buf, key)?;
+                protocol::write_string(buf, value)
+            }
+        }
+    }
+
+    pub fn read(buf: &mut impl Read) -> io::Result<Self> {
+        let id = buf.read_u8()?;
+        let property = match id {
+            0x01 => Property::PayloadFormatIndicator(buf.read_u8()?),
+            0x02 => Property::MessageExpiryInterval(buf.read_u32::<NetworkEndian>()?),
+            0x03 => Property::ContentType(protocol::read_string(buf)?),
+            0x08 => Property::ResponseTopic(protocol::read_string(buf)?),
+            0x09 => Property::CorrelationData(read_binary(buf)?),
+            0x0B => Property::SubscriptionIdentifier(VarInt::read(buf)?.value()),
+            0x11 => Property::SessionExpiryInterval(buf.read_u32::<NetworkEndian>()?),
+            0x12 => Property::AssignedClientIdentifier(protocol::read_string(buf)?),
+            0x13 => Property::ServerKeepAlive(buf.read_u16::<NetworkEndian>()?),
+            0x15 => Property::AuthenticationMethod(protocol::read_string(buf)?),
+            0x16 => Property::AuthenticationData(read_binary(buf)?),
+            0x17 => Property::RequestProblemInformation(buf.read_u8()?),
+            0x18 => Property::WillDelayInterval(buf.read_u32::<NetworkEndian>()?),
+            0x19 => Property::RequestResponseInformation(buf.read_u8()?),
+            0x1A => Property::ResponseInformation(protocol::read_string(buf)?),
+            0x1C => Property::ServerReference(protocol::read_string(buf)?),
+            0x1F => Property::ReasonString(protocol::read_string(buf)?),
+            0x21 => Property::ReceiveMaximum(buf.read_u16::<NetworkEndian>()?),
+            0x22 => Property::TopicAliasMaximum(buf.read_u16::<NetworkEndian>()?),
+            0x23 => Property::TopicAlias(buf.read_u16::<NetworkEndian>()?),
+            0x24 => match buf.read_u8()? {
+                qos @ (0 | 1) => Property::MaximumQos(qos),
+                qos => return Err(invalid(format!("0x24 holds {}, not 0 or 1", qos))),
+            },
+            0x25 => Property::RetainAvailable(read_bool(buf, id)?),
+            0x26 => {
+                Property::UserProperty(protocol::read_string(buf)?, protocol::read_string(buf)?)
+            }
+            0x27 => Property::MaximumPacketSize(buf.read_u32::<NetworkEndian>()?),
+            0x28 => Property::WildcardSubscriptionAvailable(read_bool(buf, id)?),
+            0x29 => Property::SubscriptionIdentifierAvailable(read_bool(buf, id)?),
+            0x2A => Property::SharedSubscriptionAvailable(read_bool(buf, id)?),
+            id => return Err(invalid(format!("unknown identifier 0x{:02X}", id))),
+        };
+        Ok(property)
+    }
+}
+
+/// Bytes taken by a property block, its length prefix included
+pub fn properties_len(properties: &[Property]) -> usize {
+    let len: usize = properties.iter().map(Property::len).sum();
+    varint_len(len) + len
+}
+
+/// Write a property block: its length as a variable byte integer, then the
+/// properties
+pub fn write_properties(buf: &mut impl Write, properties: &[Property]) -> io::Result<()> {
+    let len: usize = properties.iter().map(Property::len).sum();
+    write_varint(buf, len)?;
+    properties
+        .iter()
+        .try_for_each(|property| property.write(buf))
+}
+
+/// Read a whole property block, failing if a property runs past its end
+pub fn read_properties(buf: &mut impl Read) -> io::Result<Vec<Property>> {
+    let len = VarInt::read(buf)?.value() as usize;
+    let mut block = vec![0; len];
+    buf.read_exact(&mut block)?;
+    let mut block = block.as_slice();
+    let mut properties = vec![];
+    while !block.is_empty() {
+        let property = Property::read(&mut block).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("truncated by the block length".to_string()),
+            _ => e,
+        })?;
+        properties.push(property);
+    }
+    Ok(properties)
+}
+
+#[cfg(test)]
+mod properties_tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() -> io::Result<()> {
+        let properties = vec![
+            Property::SessionExpiryInterval(3600),
+            Property::ReceiveMaximum(20),
+            Property::MaximumQos(1),
+            Property::RetainAvailable(false),
+            Property::AssignedClientIdentifier("auto-1".to_string()),
+            Property::SubscriptionIdentifier(300),
+            Property::CorrelationData(vec![0, 1, 2]),
+            Property::UserProperty("region".to_string(), "eu".to_string()),
+        ];
+        let mut buf = vec![];
+        write_properties(&mut buf, &properties)?;
+        assert_eq!(buf.len(), properties_len(&properties));
+        assert_eq!(&buf[..6], &[43, 0x11, 0, 0, 0x0E, 0x10]);
+        assert_eq!(read_properties(&mut buf.as_slice())?, properties);
+
+        let mut empty = vec![];
+        write_properties(&mut empty, &[])?;
+        assert_eq!(empty, [0]);
+        assert_eq!(read_properties(&mut empty.as_slice())?, []);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        // Unknown identifier
+        assert!(read_properties(&mut [2, 0x7F, 0].as_slice()).is_err());
+        // Boolean other than 0 or 1
+        assert!(read_properties(&mut [2, 0x25, 2].as_slice()).is_err());
+        // Maximum QoS is 0 or 1, a server supporting QoS 2 leaves it out
+        for qos in [2, 3, 0xFF] {
+            let err = read_properties(&mut [2, 0x24, qos].as_slice()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        // A two byte value cut short by the block length
+        let err = read_properties(&mut [2, 0x21, 0, 0].as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
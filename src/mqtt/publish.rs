@@ -50,21 +50,20 @@ impl fmt::Display for PublishPacket {
 }
 
 impl PublishPacket {
-    pub fn new(packet_id: u16, topic: String, payload: Vec<u8>, qos: u8) -> Self {
-        Self {
-            packet_id,
-            qos,
-            topic,
-            payload,
-        }
-    }
-
-    pub fn write(&self, buf: &mut impl Write) -> io::Result<()> {
-        protocol::write_string(buf, &self.topic)?;
-        if self.qos > 0 {
-            buf.write_u16::<NetworkEndian>(self.packet_id)?;
+    /// Write a PUBLISH variable header and payload borrowing topic and
+    /// payload, so that publishing doesn't copy every message
+    pub fn write(
+        buf: &mut impl Write,
+        packet_id: u16,
+        qos: u8,
+        topic: &str,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        protocol::write_string(buf, topic)?;
+        if qos > 0 {
+            buf.write_u16::<NetworkEndian>(packet_id)?;
         }
-        protocol::write_bytes(buf, &self.payload)?;
+        protocol::write_bytes(buf, payload)?;
         Ok(())
     }
 
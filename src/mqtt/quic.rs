@@ -0,0 +1,477 @@
+use std::fmt;
+use std::str::FromStr;
+
+const SCHEME: &str = "quic://";
+
+/// Port of MQTT over QUIC on the brokers supporting it, EMQX first
+pub const DEFAULT_QUIC_PORT: u16 = 14567;
+
+/// A broker reached over QUIC, as `quic://host` or `quic://host:port`
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuicUrl {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for QuicUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let authority = s
+            .strip_prefix(SCHEME)
+            .map(|rest| rest.trim_end_matches('/'))
+            .filter(|authority| !authority.is_empty() && !authority.contains('/'))
+            .ok_or_else(|| format!("Expected quic://host[:port], got {:?}", s))?;
+        // A bracketed IPv6 address, or a host without a port
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid QUIC port {:?}", port))?;
+                (host, port)
+            }
+            _ => (authority, DEFAULT_QUIC_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("No host in {:?}", s));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for QuicUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "{}[{}]:{}", SCHEME, self.host, self.port),
+            false => write!(f, "{}{}:{}", SCHEME, self.host, self.port),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "quic"))]
+pub(crate) use stream::server::relay;
+#[cfg(feature = "quic")]
+pub use stream::QuicTransport;
+
+#[cfg(feature = "quic")]
+mod stream {
+    use super::QuicUrl;
+    use crate::mqtt::Transport;
+    use quinn::crypto::rustls::QuicClientConfig;
+    use quinn::rustls::pki_types::pem::PemObject;
+    use quinn::rustls::pki_types::CertificateDer;
+    use quinn::rustls::{self, RootCertStore};
+    use quinn::{Connection, Endpoint, RecvStream, SendStream};
+    use std::future::Future;
+    use std::io::{self, Read, Write};
+    use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, ToSocketAddrs};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+    use tokio::sync::watch;
+
+    /// Application protocol negotiated with the broker, as EMQX expects
+    const ALPN: &[u8] = b"mqtt";
+
+    /// Longest the last handle waits on drop for the broker to read what
+    /// was written, a DISCONNECT usually, before closing the connection
+    const LINGER: Duration = Duration::from_secs(1);
+
+    /// Drives the endpoints of every connection, blocking reads and writes
+    /// wait on it from the caller's thread
+    fn runtime() -> io::Result<&'static Runtime> {
+        static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+        if let Some(runtime) = RUNTIME.get() {
+            return Ok(runtime);
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("sake-quic")
+            .enable_all()
+            .build()?;
+        Ok(RUNTIME.get_or_init(|| runtime))
+    }
+
+    /// Fails with `WouldBlock` once `timeout` elapses, as sockets with a
+    /// read or write timeout do. A zero timeout still completes an
+    /// operation that is ready
+    async fn bounded<T>(
+        timeout: Option<Duration>,
+        op: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        match timeout {
+            None => op.await,
+            Some(timeout) => tokio::time::timeout(timeout, op)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::WouldBlock.into())),
+        }
+    }
+
+    /// Shared by every handle on the connection, closed with the last one
+    struct Shared {
+        endpoint: Endpoint,
+        connection: Connection,
+        send: tokio::sync::Mutex<SendStream>,
+        recv: tokio::sync::Mutex<RecvStream>,
+        read_timeout: Mutex<Option<Duration>>,
+        write_timeout: Mutex<Option<Duration>>,
+        nonblocking: AtomicBool,
+        read_closed: watch::Sender<bool>,
+    }
+
+    impl Drop for Shared {
+        fn drop(&mut self) {
+            let Ok(runtime) = runtime() else { return };
+            let _guard = runtime.enter();
+            let send = self.send.get_mut();
+            let _ = send.finish();
+            runtime.block_on(async {
+                let _ = tokio::time::timeout(LINGER, send.stopped()).await;
+                self.connection.close(0u32.into(), b"");
+                let _ = tokio::time::timeout(LINGER, self.endpoint.wait_idle()).await;
+            });
+        }
+    }
+
+    /// MQTT over QUIC, the session carried on one bidirectional stream of
+    /// its own connection as with TCP. Each connection has its own endpoint,
+    /// so that a bench compares them one to one with TCP connections
+    pub struct QuicTransport(Arc<Shared>);
+
+    impl QuicTransport {
+        /// Connect to the broker at `url` and open the stream. Its
+        /// certificate must chain to the PEM certificates of `ca` if given,
+        /// else to the web PKI roots
+        pub fn open(url: &QuicUrl, ca: Option<&Path>, timeout: Duration) -> io::Result<Self> {
+            let addr = (url.host.as_str(), url.port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no QUIC broker address found")
+                })?;
+            let config = client_config(ca)?;
+            let runtime = runtime()?;
+            let bind: SocketAddr = match addr {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            // Quinn spawns the tasks driving the connection on the runtime
+            let _guard = runtime.enter();
+            let mut endpoint = Endpoint::client(bind)?;
+            endpoint.set_default_client_config(config);
+            let connecting = endpoint
+                .connect(addr, &url.host)
+                .map_err(io::Error::other)?;
+            let handshake = async {
+                let connection = connecting.await?;
+                let (send, recv) = connection.open_bi().await?;
+                io::Result::Ok((connection, send, recv))
+            };
+            let (connection, send, recv) = runtime
+                .block_on(tokio::time::timeout(timeout, handshake))
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("No QUIC handshake with {} within {:?}", url, timeout),
+                    )
+                })??;
+            Ok(Self(Arc::new(Shared {
+                endpoint,
+                connection,
+                send: tokio::sync::Mutex::new(send),
+                recv: tokio::sync::Mutex::new(recv),
+                read_timeout: Mutex::new(None),
+                write_timeout: Mutex::new(None),
+                nonblocking: AtomicBool::new(false),
+                read_closed: watch::Sender::new(false),
+            })))
+        }
+
+        fn timeout(&self, timeout: &Mutex<Option<Duration>>) -> Option<Duration> {
+            match self.0.nonblocking.load(Ordering::Relaxed) {
+                true => Some(Duration::ZERO),
+                false => *timeout.lock().unwrap(),
+            }
+        }
+    }
+
+    fn client_config(ca: Option<&Path>) -> io::Result<quinn::ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        match ca {
+            Some(path) => {
+                let certs = CertificateDer::pem_file_iter(path)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                for cert in certs {
+                    let cert = cert.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    roots
+                        .add(cert)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                }
+                if roots.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("No certificate in {}", path.display()),
+                    ));
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(io::Error::other)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = QuicClientConfig::try_from(tls).map_err(io::Error::other)?;
+        Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+    }
+
+    impl Read for QuicTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let timeout = self.timeout(&self.0.read_timeout);
+            let shared = &*self.0;
+            let mut closed = shared.read_closed.subscribe();
+            runtime()?.block_on(async {
+                let read = async {
+                    let mut recv = shared.recv.lock().await;
+                    // A finished stream is the EOF of a closed socket
+                    Ok(recv.read(buf).await?.unwrap_or(0))
+                };
+                tokio::select! {
+                    biased;
+                    _ = closed.wait_for(|closed| *closed) => Ok(0),
+                    read = bounded(timeout, read) => read,
+                }
+            })
+        }
+    }
+
+    impl Write for QuicTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let timeout = self.timeout(&self.0.write_timeout);
+            let shared = &*self.0;
+            runtime()?.block_on(bounded(timeout, async {
+                Ok(shared.send.lock().await.write(buf).await?)
+            }))
+        }
+
+        /// Written bytes are handed to the connection right away
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for QuicTransport {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(Self(Arc::clone(&self.0))))
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            *self.0.read_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn read_timeout(&self) -> io::Result<Option<Duration>> {
+            Ok(*self.0.read_timeout.lock().unwrap())
+        }
+
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            *self.0.write_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.0.nonblocking.store(nonblocking, Ordering::Relaxed);
+            Ok(())
+        }
+
+        /// Reads blocked on the stream return EOF, and the broker reads an
+        /// EOF once the stream is finished
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            if matches!(how, Shutdown::Read | Shutdown::Both) {
+                self.0.read_closed.send_replace(true);
+            }
+            if matches!(how, Shutdown::Write | Shutdown::Both) {
+                let _ = runtime()?.block_on(self.0.send.lock()).finish();
+            }
+            Ok(())
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.0.connection.remote_address())
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) mod server {
+        use super::{runtime, ALPN};
+        use quinn::crypto::rustls::QuicServerConfig;
+        use quinn::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use quinn::rustls::{self};
+        use quinn::Endpoint;
+        use std::io::{self, Read, Write};
+        use std::net::{SocketAddr, TcpStream};
+        use std::sync::Arc;
+        use std::thread;
+
+        /// A QUIC front for the TCP broker at `broker`, relaying the stream
+        /// of each connection to a TCP connection of its own. Returns its
+        /// address and the PEM of its self-signed certificate for localhost
+        pub fn relay(broker: SocketAddr) -> io::Result<(SocketAddr, String)> {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+                .map_err(io::Error::other)?;
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .map_err(io::Error::other)?
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.cert.der().clone()], key)
+                .map_err(io::Error::other)?;
+            tls.alpn_protocols = vec![ALPN.to_vec()];
+            let crypto = QuicServerConfig::try_from(tls).map_err(io::Error::other)?;
+            let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+            let runtime = runtime()?;
+            let endpoint = {
+                let _guard = runtime.enter();
+                Endpoint::server(config, "127.0.0.1:0".parse().unwrap())?
+            };
+            let addr = endpoint.local_addr()?;
+            runtime.spawn(async move {
+                while let Some(incoming) = endpoint.accept().await {
+                    let Ok(connection) = incoming.await else {
+                        continue;
+                    };
+                    let Ok((mut send, mut recv)) = connection.accept_bi().await else {
+                        continue;
+                    };
+                    let Ok(mut upstream) = TcpStream::connect(broker) else {
+                        continue;
+                    };
+                    let mut downstream = upstream.try_clone().unwrap();
+                    thread::spawn(move || {
+                        let mut buf = [0; 4096];
+                        while let Ok(Some(n)) = runtime.block_on(recv.read(&mut buf)) {
+                            if upstream.write_all(&buf[..n]).is_err() {
+                                break;
+                            }
+                        }
+                        let _ = upstream.shutdown(std::net::Shutdown::Write);
+                    });
+                    thread::spawn(move || {
+                        let mut buf = [0; 4096];
+                        while let Ok(n @ 1..) = downstream.read(&mut buf) {
+                            if runtime.block_on(send.write_all(&buf[..n])).is_err() {
+                                break;
+                            }
+                        }
+                        let _ = send.finish();
+                        let _ = runtime.block_on(send.stopped());
+                        drop(connection);
+                    });
+                }
+            });
+            Ok((addr, cert.cert.pem()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod quic_tests {
+    use super::*;
+
+    #[test]
+    fn test_quic_url() {
+        let parse = |s: &str| s.parse::<QuicUrl>();
+        assert_eq!(
+            parse("quic://broker"),
+            Ok(QuicUrl {
+                host: "broker".to_string(),
+                port: DEFAULT_QUIC_PORT,
+            })
+        );
+        assert_eq!(parse("quic://broker:1234/").unwrap().port, 1234);
+        let v6 = parse("quic://[fd00::1]:1234").unwrap();
+        assert_eq!((v6.host.as_str(), v6.port), ("fd00::1", 1234));
+        assert_eq!(v6.to_string(), "quic://[fd00::1]:1234");
+        assert_eq!(parse("quic://fd00::1").unwrap().host, "fd00::1");
+        assert!(parse("broker:1234").is_err());
+        assert!(parse("tcp://broker").is_err());
+        assert!(parse("quic://").is_err());
+        assert!(parse("quic://:1234").is_err());
+        assert!(parse("quic://broker:mqtt").is_err());
+        assert!(parse("quic://broker/topic").is_err());
+    }
+
+    #[cfg(feature = "quic")]
+    #[test]
+    fn test_quic_transport() -> std::io::Result<()> {
+        use crate::broker::{Broker, BrokerOptions};
+        use crate::mqtt::{Protocol, Qos, Response};
+        use std::time::Duration;
+
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let broker_addr = broker.local_addr()?;
+        std::thread::spawn(move || broker.run());
+        let (addr, pem) = stream::server::relay(broker_addr)?;
+        let ca = std::env::temp_dir().join(format!("sake-quic-{}.pem", std::process::id()));
+        std::fs::write(&ca, pem)?;
+        let url = QuicUrl {
+            host: "localhost".to_string(),
+            port: addr.port(),
+        };
+        let transport = QuicTransport::open(&url, Some(&ca), Duration::from_secs(5));
+        std::fs::remove_file(&ca)?;
+        let transport = transport?;
+        let mut client = Protocol::builder()
+            .client_id("quic")
+            .connect_transport(transport)?;
+        assert_eq!(client.peer_addr()?, addr);
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        client.subscribe("quic", Qos::AtLeastOnce)?;
+        assert!(matches!(client.next_message()?, Response::Suback { .. }));
+        let packet_id = client.publish("quic", b"over quic")?;
+        let (mut received, mut acked) = (false, false);
+        while !(received && acked) {
+            match client.next_message()? {
+                Response::Publish { topic, payload, .. } => {
+                    assert_eq!(topic, "quic");
+                    assert_eq!(payload, b"over quic");
+                    received = true;
+                }
+                Response::Puback { packet_id: id } => {
+                    assert_eq!(id, packet_id);
+                    acked = true;
+                }
+                response => panic!("Unexpected response {}", response),
+            }
+        }
+        // Without data the read times out as a socket would
+        client.set_read_timeout(Some(Duration::from_millis(50)))?;
+        assert_eq!(
+            client.next_message().unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+        client.disconnect()
+    }
+
+    #[cfg(feature = "quic")]
+    #[test]
+    fn test_untrusted_certificate() -> std::io::Result<()> {
+        let (addr, _pem) = stream::server::relay("127.0.0.1:9".parse().unwrap())?;
+        let url = QuicUrl {
+            host: "localhost".to_string(),
+            port: addr.port(),
+        };
+        // The self-signed certificate doesn't chain to the web PKI roots
+        assert!(QuicTransport::open(&url, None, std::time::Duration::from_secs(5)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Throughput allowed to the publishes of a client, whichever path sends
+/// them, see `ProtocolBuilder::rate_limit`. Other packets are never held
+/// back, so acks and pings keep flowing while publishes wait
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RateLimit {
+    pub messages_per_sec: Option<f64>,
+    /// Counts whole PUBLISH packets, headers included
+    pub bytes_per_sec: Option<f64>,
+    /// How long unused throughput accumulates for, to be sent at once after
+    /// a pause. Zero, the default, spaces every publish evenly
+    pub burst: Duration,
+}
+
+impl RateLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.messages_per_sec.is_none() && self.bytes_per_sec.is_none()
+    }
+}
+
+/// Tokens refilled at `rate` per second up to `capacity`. They go negative
+/// when more is taken than available, the next taker waiting for the debt
+/// to be paid back. The first publish is never delayed that way, whatever
+/// its size
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: Duration, now: Instant) -> Self {
+        let capacity = rate * burst.as_secs_f64();
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    /// Take `cost` tokens, returning how long to wait before using them
+    fn reserve(&mut self, cost: f64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+        let wait = match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        };
+        self.tokens -= cost;
+        wait
+    }
+}
+
+/// The buckets of a `RateLimit`, shared by the clones of a writer so that
+/// the limit holds for the whole client
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    messages: Option<Mutex<TokenBucket>>,
+    bytes: Option<Mutex<TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> Self {
+        let now = Instant::now();
+        let bucket = |rate: Option<f64>| {
+            rate.filter(|rate| *rate > 0.0)
+                .map(|rate| Mutex::new(TokenBucket::new(rate, limit.burst, now)))
+        };
+        Self {
+            messages: bucket(limit.messages_per_sec),
+            bytes: bucket(limit.bytes_per_sec),
+        }
+    }
+
+    /// Block until `messages` publishes of `bytes` in total may be sent.
+    /// Callers are served in the order they reserved
+    pub fn acquire(&self, messages: usize, bytes: usize) {
+        let now = Instant::now();
+        let reserve = |bucket: &Option<Mutex<TokenBucket>>, cost: usize| {
+            bucket.as_ref().map_or(Duration::ZERO, |bucket| {
+                bucket.lock().unwrap().reserve(cost as f64, now)
+            })
+        };
+        let wait = reserve(&self.messages, messages).max(reserve(&self.bytes, bytes));
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod ratelimit_tests {
+    use super::*;
+    use crate::mqtt::{Protocol, PublishOptions, Qos};
+    use crate::testing::duplex;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        // 10 per second, no burst: publishes go 100ms after one another
+        let mut bucket = TokenBucket::new(10.0, Duration::ZERO, start);
+        assert_eq!(bucket.reserve(1.0, at(0)), Duration::ZERO);
+        assert_eq!(bucket.reserve(1.0, at(0)), Duration::from_millis(100));
+        assert_eq!(bucket.reserve(1.0, at(0)), Duration::from_millis(200));
+        assert_eq!(bucket.reserve(1.0, at(300)), Duration::ZERO);
+        // Idling doesn't build up a burst
+        assert_eq!(bucket.reserve(1.0, at(2000)), Duration::ZERO);
+        assert_eq!(bucket.reserve(1.0, at(2000)), Duration::from_millis(100));
+
+        let mut bucket = TokenBucket::new(10.0, Duration::from_millis(500), start);
+        for _ in 0..6 {
+            assert_eq!(bucket.reserve(1.0, at(0)), Duration::ZERO);
+        }
+        assert_eq!(bucket.reserve(1.0, at(0)), Duration::from_millis(100));
+        // More than the burst at once delays the next publish
+        assert_eq!(bucket.reserve(8.0, at(1000)), Duration::ZERO);
+        assert_eq!(bucket.reserve(1.0, at(1000)), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_publishes_throttled() -> std::io::Result<()> {
+        let (link, _broker) = duplex();
+        let mut client = Protocol::with_transport(link)?;
+        client.set_rate_limit(RateLimit {
+            messages_per_sec: Some(100.0),
+            ..RateLimit::default()
+        });
+        let options = PublishOptions {
+            qos: Qos::AtMostOnce,
+            ..PublishOptions::default()
+        };
+        let start = Instant::now();
+        for _ in 0..5 {
+            client.publish_with("t", b"x", &options)?;
+        }
+        // Subscribing is not a publish, it doesn't wait
+        client.subscribe("t", Qos::AtMostOnce)?;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(40), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+
+        client.set_rate_limit(RateLimit {
+            bytes_per_sec: Some(1000.0),
+            ..RateLimit::default()
+        });
+        let start = Instant::now();
+        // 60 bytes with the headers, the next publish waits for them
+        let payload = [0; 25];
+        client.publish_batch(&[("t", &payload[..], Qos::AtMostOnce); 2])?;
+        client.publish_with("t", b"x", &options)?;
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        Ok(())
+    }
+}
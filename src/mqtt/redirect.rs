@@ -0,0 +1,96 @@
+use crate::mqtt::{ConnectReturnCode, ConnectionRefused, Disconnected};
+use std::io;
+
+/// MQTT 5 reason code of a CONNACK or DISCONNECT asking the client to
+/// connect to the Server Reference for now
+pub const USE_ANOTHER_SERVER: u8 = 0x9C;
+/// Same as `USE_ANOTHER_SERVER`, for good
+pub const SERVER_MOVED: u8 = 0x9D;
+
+/// Server Reference of a refused CONNECT or of a DISCONNECT sending the
+/// client to another server, `None` for any other error
+pub fn server_reference(err: &io::Error) -> Option<&str> {
+    let inner = err.get_ref()?;
+    if let Some(refused) = inner.downcast_ref::<ConnectionRefused>() {
+        return match refused.return_code {
+            ConnectReturnCode::UseAnotherServer | ConnectReturnCode::ServerMoved => {
+                refused.server_reference.as_deref()
+            }
+            _ => None,
+        };
+    }
+    let disconnected = inner.downcast_ref::<Disconnected>()?;
+    match disconnected.is_redirect() {
+        true => disconnected.server_reference.as_deref(),
+        false => None,
+    }
+}
+
+/// Host and port of the first server of a Server Reference. The spec
+/// leaves its format open, brokers send a space separated list of `host`,
+/// `host:port` or `[ipv6]:port`
+pub fn parse_server_reference(reference: &str) -> Option<(String, Option<u16>)> {
+    let first = reference.split_whitespace().next()?;
+    if let Some(rest) = first.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => Some(port.parse().ok()?),
+            None if rest.is_empty() => None,
+            None => return None,
+        };
+        return Some((host.to_string(), port));
+    }
+    match first.split_once(':') {
+        // More than one colon is a bare IPv6 address
+        Some((host, port)) if !port.contains(':') => {
+            Some((host.to_string(), Some(port.parse().ok()?)))
+        }
+        _ => Some((first.to_string(), None)),
+    }
+}
+
+#[cfg(test)]
+mod redirect_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_server_reference() {
+        let parse = |reference| parse_server_reference(reference);
+        assert_eq!(parse("broker2"), Some(("broker2".to_string(), None)));
+        assert_eq!(
+            parse("broker2:8883 broker3:8883"),
+            Some(("broker2".to_string(), Some(8883)))
+        );
+        assert_eq!(parse("[::1]:1884"), Some(("::1".to_string(), Some(1884))));
+        assert_eq!(parse("[::1]"), Some(("::1".to_string(), None)));
+        assert_eq!(parse("::1"), Some(("::1".to_string(), None)));
+        assert_eq!(parse("broker2:port"), None);
+        assert_eq!(parse("  "), None);
+    }
+
+    #[test]
+    fn test_server_reference() {
+        let refused = |return_code| -> io::Error {
+            ConnectionRefused {
+                return_code,
+                server_reference: Some("broker2".to_string()),
+            }
+            .into()
+        };
+        let moved = refused(ConnectReturnCode::ServerMoved);
+        assert_eq!(server_reference(&moved), Some("broker2"));
+        assert_eq!(
+            server_reference(&refused(ConnectReturnCode::NotAuthorized)),
+            None
+        );
+        let disconnected: io::Error = Disconnected {
+            reason_code: USE_ANOTHER_SERVER,
+            reason_string: None,
+            server_reference: Some("broker3:1884".to_string()),
+        }
+        .into();
+        assert_eq!(disconnected.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(server_reference(&disconnected), Some("broker3:1884"));
+        assert_eq!(server_reference(&io::Error::other("x")), None);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::mqtt::{AckType, Property, Protocol, PublishRef, PublishV5, Qos, Response};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Delivery policy of `Protocol::publish_with`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PublishOptions {
+    pub qos: Qos,
+    /// How long to wait for each PUBACK, PUBREC or PUBCOMP, `None` waits
+    /// forever and never retransmits
+    pub ack_timeout: Option<Duration>,
+    /// Retransmissions attempted after the first timeout before giving up
+    pub retries: u32,
+    /// Ask the broker to keep the message for future subscriptions
+    pub retain: bool,
+}
+
+impl Default for PublishOptions {
+    fn default() -> Self {
+        Self {
+            qos: Qos::AtLeastOnce,
+            ack_timeout: None,
+            retries: 0,
+            retain: false,
+        }
+    }
+}
+
+impl Protocol {
+    /// Publish a message and wait for the broker to acknowledge it according
+    /// to `options`. A PUBLISH left unacknowledged is retransmitted with the
+    /// DUP flag set, a PUBREL once PUBREC arrived, until the retries are
+    /// exhausted and a `TimedOut` error is returned. Returns the packet id,
+    /// 0 for QoS 0 which is not acknowledged. The QoS is lowered to the
+    /// maximum of the broker, see `effective_qos`.
+    ///
+    /// Other packets received meanwhile are discarded, use a
+    /// `ThreadedClient` to publish while subscribed
+    pub fn publish_with(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        options: &PublishOptions,
+    ) -> io::Result<u16> {
+        self.publish_with_properties(topic, payload, options, &[])
+    }
+
+    /// Like `publish_with`, the PUBLISH carrying `properties` on MQTT 5
+    /// sessions, where they must be empty with 3.1.1
+    pub fn publish_with_properties(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        options: &PublishOptions,
+        properties: &[Property],
+    ) -> io::Result<u16> {
+        if !properties.is_empty() && self.capabilities().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "PUBLISH properties need an MQTT 5 session",
+            ));
+        }
+        let qos = u8::from(&self.downgrade_qos(options.qos));
+        let packet_id = if qos > 0 { self.next_packet_id() } else { 0 };
+        let mut publish = PublishRef {
+            packet_id,
+            qos,
+            dup: false,
+            retain: options.retain,
+            topic,
+            payload,
+        };
+        self.send_publish(&publish, properties)?;
+        if qos == 0 {
+            return Ok(packet_id);
+        }
+        let read_timeout = self.reader.read_timeout()?;
+        let result = self.await_ack(&mut publish, options, properties);
+        self.set_read_timeout(read_timeout)?;
+        result.map(|_| packet_id)
+    }
+
+    fn send_publish(&mut self, publish: &PublishRef, properties: &[Property]) -> io::Result<()> {
+        match self.capabilities() {
+            Some(_) => self.send_message(&PublishV5 {
+                packet_id: publish.packet_id,
+                qos: publish.qos,
+                dup: publish.dup,
+                retain: publish.retain,
+                topic: publish.topic.to_string(),
+                payload: publish.payload.to_vec(),
+                properties: properties.to_vec(),
+            }),
+            None => self.send_message(publish),
+        }
+    }
+
+    fn await_ack(
+        &mut self,
+        publish: &mut PublishRef,
+        options: &PublishOptions,
+        properties: &[Property],
+    ) -> io::Result<()> {
+        let PublishRef { packet_id, qos, .. } = *publish;
+        let mut retries = 0;
+        let mut received = false;
+        let mut deadline = options.ack_timeout.map(|t| Instant::now() + t);
+        loop {
+            let response = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => {
+                        self.set_read_timeout(Some(left))?;
+                        self.read_response()
+                    }
+                    _ => Err(io::ErrorKind::TimedOut.into()),
+                },
+                None => self.read_response(),
+            };
+            match response {
+                Ok(Response::Puback { packet_id: id }) if qos == 1 && id == packet_id => {
+                    return Ok(())
+                }
+                Ok(Response::Pubrec { packet_id: id }) if qos == 2 && id == packet_id => {
+                    received = true;
+                    self.ack(AckType::Pubrel(packet_id))?;
+                    deadline = options.ack_timeout.map(|t| Instant::now() + t);
+                }
+                Ok(Response::Pubcomp { packet_id: id }) if qos == 2 && id == packet_id => {
+                    return Ok(())
+                }
+                // Answer to a PINGREQ `next_message` sent before publishing
+                Ok(Response::Pingresp) => self.keepalive.on_pingresp(),
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                    ) =>
+                {
+                    if retries == options.retries {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!(
+                                "No acknowledgement for packet {} after {} retries",
+                                packet_id, retries
+                            ),
+                        ));
+                    }
+                    retries += 1;
+                    if received {
+                        self.ack(AckType::Pubrel(packet_id))?;
+                    } else {
+                        publish.dup = true;
+                        self.send_publish(publish, properties)?;
+                    }
+                    deadline = options.ack_timeout.map(|t| Instant::now() + t);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod retry_tests {
+    use super::*;
+    use crate::mqtt::{KeepAliveEvent, Request};
+    use crate::testing::duplex;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    #[test]
+    fn test_retransmit_with_dup() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        let broker = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut received = vec![0; 16];
+            // Ignore the first PUBLISH and ack the retransmission
+            stream.read_exact(&mut received)?;
+            stream.write_all(&[0x40, 2, 0, 1])?;
+            Ok(received)
+        });
+
+        let mut client = Protocol::with_transport(client)?;
+        let options = PublishOptions {
+            ack_timeout: Some(Duration::from_millis(50)),
+            retries: 1,
+            ..PublishOptions::default()
+        };
+        assert_eq!(client.publish_with("a", b"x", &options)?, 1);
+        assert_eq!(
+            broker.join().unwrap()?,
+            &[0x32, 6, 0, 1, b'a', 0, 1, b'x', 0x3A, 6, 0, 1, b'a', 0, 1, b'x']
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout_after_retries() -> io::Result<()> {
+        let (client, _broker) = duplex();
+        let mut client = Protocol::with_transport(client)?;
+        let options = PublishOptions {
+            qos: Qos::ExactlyOnce,
+            ack_timeout: Some(Duration::from_millis(10)),
+            retries: 2,
+            retain: false,
+        };
+        let err = client.publish_with("a", b"x", &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pingresp_while_awaiting_ack() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        let broker = thread::spawn(move || -> io::Result<()> {
+            // PINGREQ then PUBLISH
+            stream.read_exact(&mut [0; 10])?;
+            stream.write_all(&[0xD0, 0, 0x40, 2, 0, 1])
+        });
+
+        let mut client = Protocol::with_transport(client)?;
+        client.set_keepalive(Duration::from_secs(1));
+        let later = Instant::now() + Duration::from_secs(2);
+        assert_eq!(client.keepalive.poll(later), Some(KeepAliveEvent::Ping));
+        client.send_message(&Request::Pingreq)?;
+        client.publish_with("a", b"x", &PublishOptions::default())?;
+        broker.join().unwrap()?;
+        // The PINGREQ was answered, the connection isn't taken for dead
+        let after = later + Duration::from_secs(1);
+        assert_eq!(client.keepalive.poll(after), Some(KeepAliveEvent::Ping));
+        Ok(())
+    }
+
+    #[test]
+    fn test_maximum_qos() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        let broker = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header)?;
+            stream.read_exact(&mut vec![0; header[1] as usize])?;
+            // Maximum QoS 0
+            stream.write_all(&[0x20, 5, 0, 0, 2, 0x24, 0])?;
+            let mut publish = vec![0; 7];
+            stream.read_exact(&mut publish)?;
+            Ok(publish)
+        });
+        let mut client = Protocol::builder()
+            .client_id("id")
+            .protocol_level(crate::mqtt::MQTT_V5)
+            .connect_transport(client)?;
+        assert_eq!(client.effective_qos(Qos::ExactlyOnce), Qos::AtMostOnce);
+        // Published with QoS 0 rather than waiting for a PUBACK
+        assert_eq!(
+            client.publish_with("a", b"x", &PublishOptions::default())?,
+            0
+        );
+        assert_eq!(broker.join().unwrap()?, [0x30, 5, 0, 1, b'a', 0, b'x']);
+        Ok(())
+    }
+
+    #[test]
+    fn test_maximum_qos_from_suback() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        thread::spawn(move || -> io::Result<()> {
+            stream.read_exact(&mut [0; 8])?;
+            // QoS 1 granted for QoS 2
+            stream.write_all(&[0x90, 3, 0, 1, 1])
+        });
+        let mut client = Protocol::with_transport(client)?;
+        assert_eq!(client.maximum_qos(), Qos::ExactlyOnce);
+        client.subscribe("a", Qos::ExactlyOnce)?;
+        client.next_message()?;
+        assert_eq!(client.maximum_qos(), Qos::AtLeastOnce);
+        assert_eq!(client.effective_qos(Qos::ExactlyOnce), Qos::AtLeastOnce);
+        assert_eq!(client.effective_qos(Qos::AtMostOnce), Qos::AtMostOnce);
+        Ok(())
+    }
+}
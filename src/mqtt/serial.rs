@@ -0,0 +1,100 @@
+use crate::mqtt::{Slip, Transport};
+use serialport::SerialPort;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a read or write waits on the port before checking again, when
+/// no timeout is set
+const POLL: Duration = Duration::from_secs(1);
+
+/// Timeouts shared by every handle on a port, as with sockets
+#[derive(Debug, Default)]
+struct Timeouts {
+    read: Option<Duration>,
+    write: Option<Duration>,
+}
+
+/// A serial port as a transport, unframed. See `open_serial` for the SLIP
+/// framed link gateways expect
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+    timeouts: Arc<Mutex<Timeouts>>,
+}
+
+impl SerialTransport {
+    /// Open `path` at `baud` bits per second, 8N1 without flow control
+    pub fn open(path: &str, baud: u32) -> io::Result<Self> {
+        Ok(Self {
+            port: serialport::new(path, baud).timeout(POLL).open()?,
+            timeouts: Arc::default(),
+        })
+    }
+
+    /// Run `op` on the port with `timeout`, retrying the ones expiring when
+    /// there is none. Expired timeouts are reported as `WouldBlock`, as
+    /// sockets do
+    fn with_timeout<T>(
+        &mut self,
+        timeout: Option<Duration>,
+        mut op: impl FnMut(&mut dyn SerialPort) -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.port.set_timeout(timeout.unwrap_or(POLL))?;
+        loop {
+            match op(self.port.as_mut()) {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => match timeout {
+                    None => continue,
+                    Some(_) => return Err(io::ErrorKind::WouldBlock.into()),
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Open the serial link to a gateway tunnelling MQTT in SLIP frames
+pub fn open_serial(path: &str, baud: u32) -> io::Result<Slip> {
+    Ok(Slip::new(SerialTransport::open(path, baud)?))
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = self.timeouts.lock().unwrap().read;
+        self.with_timeout(timeout, |port| port.read(buf))
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let timeout = self.timeouts.lock().unwrap().write;
+        self.with_timeout(timeout, |port| port.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let timeout = self.timeouts.lock().unwrap().write;
+        self.with_timeout(timeout, |port| port.flush())
+    }
+}
+
+impl Transport for SerialTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            port: self.port.try_clone()?,
+            timeouts: Arc::clone(&self.timeouts),
+        }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeouts.lock().unwrap().read = timeout;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.timeouts.lock().unwrap().read)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeouts.lock().unwrap().write = timeout;
+        Ok(())
+    }
+}
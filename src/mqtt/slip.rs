@@ -0,0 +1,187 @@
+use crate::mqtt::Transport;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::time::Duration;
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// Bytes read from the link at once, decoding never yields more
+const READ_CHUNK: usize = 1024;
+
+/// SLIP framing (RFC 1055) over another transport, as gateways tunnelling
+/// MQTT over a serial link expect it. Every write goes out as a frame
+/// opened and closed by END, so line noise before a frame is flushed by the
+/// receiver. Frames are unwrapped into a plain stream on reads
+pub struct Slip {
+    inner: Box<dyn Transport>,
+    /// An ESC ended the last chunk read, its byte follows
+    escaped: bool,
+    frame: Vec<u8>,
+}
+
+impl Slip {
+    pub fn new(inner: impl Transport + 'static) -> Self {
+        Self::from_boxed(Box::new(inner))
+    }
+
+    fn from_boxed(inner: Box<dyn Transport>) -> Self {
+        Self {
+            inner,
+            escaped: false,
+            frame: Vec::new(),
+        }
+    }
+}
+
+/// Append `bytes` to `frame` as a SLIP frame
+fn encode(bytes: &[u8], frame: &mut Vec<u8>) {
+    frame.push(END);
+    for &byte in bytes {
+        match byte {
+            END => frame.extend_from_slice(&[ESC, ESC_END]),
+            ESC => frame.extend_from_slice(&[ESC, ESC_ESC]),
+            byte => frame.push(byte),
+        }
+    }
+    frame.push(END);
+}
+
+impl Read for Slip {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK];
+        let max = buf.len().min(READ_CHUNK);
+        if max == 0 {
+            return Ok(0);
+        }
+        loop {
+            let n = self.inner.read(&mut chunk[..max])?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut len = 0;
+            for &byte in &chunk[..n] {
+                let decoded = match (self.escaped, byte) {
+                    (false, END) => continue,
+                    (false, ESC) => {
+                        self.escaped = true;
+                        continue;
+                    }
+                    (false, byte) => byte,
+                    (true, ESC_END) => END,
+                    (true, ESC_ESC) => ESC,
+                    (true, byte) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Invalid SLIP escape 0x{:02X}", byte),
+                        ))
+                    }
+                };
+                self.escaped = false;
+                buf[len] = decoded;
+                len += 1;
+            }
+            // A chunk of END and ESC only, nothing to hand out yet
+            if len > 0 {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+impl Write for Slip {
+    /// Send `buf` whole as a single frame
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.frame.clear();
+        encode(buf, &mut self.frame);
+        self.inner.write_all(&self.frame)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for Slip {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self::from_boxed(self.inner.try_clone()?)))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.inner.read_timeout()
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod slip_tests {
+    use super::*;
+    use crate::mqtt::{Protocol, Request, Response};
+    use crate::testing::duplex;
+
+    #[test]
+    fn test_encode() {
+        let mut frame = vec![];
+        encode(&[1, END, 2, ESC, 3], &mut frame);
+        assert_eq!(frame, [END, 1, ESC, ESC_END, 2, ESC, ESC_ESC, 3, END]);
+    }
+
+    #[test]
+    fn test_decode() -> io::Result<()> {
+        let (link, mut gateway) = duplex();
+        let mut slip = Slip::new(link);
+        // Noise flushed by the opening END, an escape split across reads
+        gateway.write_all(&[END, 1, ESC])?;
+        let mut buf = [0; 8];
+        assert_eq!(slip.read(&mut buf)?, 1);
+        assert_eq!(buf[0], 1);
+        gateway.write_all(&[ESC_END, END, END, 2, END])?;
+        assert_eq!(slip.read(&mut buf)?, 2);
+        assert_eq!(&buf[..2], &[END, 2]);
+        gateway.write_all(&[ESC, 0x01])?;
+        assert_eq!(
+            slip.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mqtt_over_slip() -> io::Result<()> {
+        let (link, mut gateway) = duplex();
+        let mut client = Protocol::with_transport(Slip::new(link))?;
+        // The PINGREQ type byte is END itself
+        client.send_message(&Request::Pingreq)?;
+        let mut frame = [0; 5];
+        gateway.read_exact(&mut frame)?;
+        assert_eq!(frame, [END, ESC, ESC_END, 0, END]);
+        gateway.write_all(&[END, 0xD0, 0, END])?;
+        assert!(matches!(
+            client.read_message::<Response>()?,
+            Response::Pingresp
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::net::TcpStream;
+    use std::os::raw::{c_int, c_short, c_void};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    const POLLIN: c_short = 0x1;
+    const POLLOUT: c_short = 0x4;
+
+    #[repr(C)]
+    struct PollFd {
+        fd: c_int,
+        events: c_short,
+        revents: c_short,
+    }
+
+    #[cfg(target_os = "linux")]
+    const SOL_SOCKET: c_int = 1;
+    #[cfg(target_os = "linux")]
+    const SO_RCVBUF: c_int = 8;
+    // BSDs and macOS
+    #[cfg(not(target_os = "linux"))]
+    const SOL_SOCKET: c_int = 0xFFFF;
+    #[cfg(not(target_os = "linux"))]
+    const SO_RCVBUF: c_int = 0x1002;
+
+    extern "C" {
+        fn setsockopt(
+            socket: c_int,
+            level: c_int,
+            name: c_int,
+            value: *const c_void,
+            len: u32,
+        ) -> c_int;
+        fn getsockopt(
+            socket: c_int,
+            level: c_int,
+            name: c_int,
+            value: *mut c_void,
+            len: *mut u32,
+        ) -> c_int;
+        fn poll(fds: *mut PollFd, nfds: u32, timeout: c_int) -> c_int;
+    }
+
+    pub fn set_recv_buffer_size(stream: &TcpStream, size: usize) -> io::Result<()> {
+        let value = c_int::try_from(size)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Receive buffer too large"))?;
+        // SAFETY: the descriptor is open for the lifetime of `stream` and
+        // `value` outlives the call
+        let ret = unsafe {
+            setsockopt(
+                stream.as_raw_fd(),
+                SOL_SOCKET,
+                SO_RCVBUF,
+                &value as *const c_int as *const c_void,
+                std::mem::size_of::<c_int>() as u32,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub fn recv_buffer_size(stream: &TcpStream) -> io::Result<usize> {
+        let mut value: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as u32;
+        // SAFETY: as above, `len` holds the size of `value`
+        let ret = unsafe {
+            getsockopt(
+                stream.as_raw_fd(),
+                SOL_SOCKET,
+                SO_RCVBUF,
+                &mut value as *mut c_int as *mut c_void,
+                &mut len,
+            )
+        };
+        match ret {
+            0 => Ok(value as usize),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub fn wait(stream: &TcpStream, read: bool, write: bool, timeout: Duration) -> io::Result<()> {
+        let mut fd = PollFd {
+            fd: stream.as_raw_fd(),
+            events: if read { POLLIN } else { 0 } | if write { POLLOUT } else { 0 },
+            revents: 0,
+        };
+        let timeout = c_int::try_from(timeout.as_millis()).unwrap_or(c_int::MAX);
+        // SAFETY: a single valid `PollFd`, for an open descriptor
+        match unsafe { poll(&mut fd, 1, timeout) } {
+            -1 => match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+                e => Err(e),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Receive buffer size only supported on unix",
+        )
+    }
+
+    pub fn set_recv_buffer_size(_stream: &TcpStream, _size: usize) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn recv_buffer_size(_stream: &TcpStream) -> io::Result<usize> {
+        Err(unsupported())
+    }
+
+    /// No portable poll, waiting a little is all callers need
+    pub fn wait(
+        _stream: &TcpStream,
+        _read: bool,
+        _write: bool,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        std::thread::sleep(timeout.min(Duration::from_millis(10)));
+        Ok(())
+    }
+}
+
+/// Set the kernel receive buffer of `stream`, SO_RCVBUF. The kernel may
+/// round it, Linux doubles it for its own bookkeeping
+pub fn set_recv_buffer_size(stream: &TcpStream, size: usize) -> io::Result<()> {
+    sys::set_recv_buffer_size(stream, size)
+}
+
+pub fn recv_buffer_size(stream: &TcpStream) -> io::Result<usize> {
+    sys::recv_buffer_size(stream)
+}
+
+/// Block until `stream` is readable or writable, as asked, or `timeout`
+/// elapses, for non-blocking sockets. Returns early on signals
+#[cfg_attr(not(any(feature = "ssh", feature = "tls")), allow(dead_code))]
+pub fn wait(stream: &TcpStream, read: bool, write: bool, timeout: Duration) -> io::Result<()> {
+    sys::wait(stream, read, write, timeout)
+}
+
+#[cfg(all(test, unix))]
+mod sockopt_tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_recv_buffer_size() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        set_recv_buffer_size(&stream, 4096)?;
+        let small = recv_buffer_size(&stream)?;
+        set_recv_buffer_size(&stream, 65536)?;
+        assert!(small >= 4096 && small < recv_buffer_size(&stream)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait() -> io::Result<()> {
+        use std::io::Write;
+        use std::time::Instant;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut peer, _) = listener.accept()?;
+        let start = Instant::now();
+        wait(&stream, true, false, Duration::from_millis(50))?;
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        peer.write_all(b"x")?;
+        let start = Instant::now();
+        wait(&stream, true, false, Duration::from_secs(5))?;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+}
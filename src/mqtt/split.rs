@@ -0,0 +1,555 @@
+use crate::mqtt::intercept::Interceptors;
+use crate::mqtt::ratelimit::RateLimiter;
+use crate::mqtt::{
+    strict, AckType, Deserialize, FrameReader, Packet, PacketInterceptor, PacketType, PublishRef,
+    Qos, RateLimit, Request, Serialize, Stats, SubscriptionTopic, Transport, TransportError,
+    Violation,
+};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Hands out packet identifiers, shared by every writer of a connection.
+/// Identifiers wrap around skipping 0, which is not a valid packet id
+#[derive(Debug, Default)]
+pub struct PacketIds {
+    last: AtomicU16,
+}
+
+impl PacketIds {
+    pub fn next(&self) -> u16 {
+        loop {
+            let id = self.last.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+}
+
+fn packet_too_large(size: usize, max: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        TransportError::PacketTooLarge { size, max },
+    )
+}
+
+fn violation(violation: Violation) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        TransportError::ProtocolViolation(violation),
+    )
+}
+
+fn invalid_data(err: TransportError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Receiving half of a connection, see `Protocol::split`
+#[derive(Debug)]
+pub struct MqttReader {
+    frames: FrameReader<Box<dyn Transport>>,
+    stats: Arc<Stats>,
+    interceptors: Arc<Interceptors>,
+    max_packet_size: Option<u32>,
+    strict: bool,
+}
+
+impl MqttReader {
+    pub fn new(transport: impl Transport + 'static, stats: Arc<Stats>) -> Self {
+        Self::from_boxed(Box::new(transport), stats)
+    }
+
+    pub(crate) fn from_boxed(transport: Box<dyn Transport>, stats: Arc<Stats>) -> Self {
+        Self {
+            frames: FrameReader::new(transport),
+            stats,
+            interceptors: Arc::default(),
+            max_packet_size: None,
+            strict: false,
+        }
+    }
+
+    /// Share the interceptors of another half of the connection
+    pub(crate) fn with_interceptors(mut self, interceptors: Arc<Interceptors>) -> Self {
+        self.interceptors = interceptors;
+        self
+    }
+
+    /// Run `interceptor` on every packet read, and sent if the writers of
+    /// the connection share the interceptors, see `Protocol::add_interceptor`
+    pub fn add_interceptor(&self, interceptor: Arc<dyn PacketInterceptor>) {
+        self.interceptors.add(interceptor);
+    }
+
+    /// Reject packets breaking the spec with a `ProtocolViolation` instead
+    /// of decoding them leniently
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Reject incoming packets larger than `max` bytes, fixed header
+    /// included, before their payload is buffered. The rest of an oversized
+    /// packet is left unread, so the connection should be dropped
+    pub fn set_max_packet_size(&mut self, max: Option<u32>) {
+        self.max_packet_size = max;
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Bound the time `read_message` is allowed to block, `None` blocks forever.
+    /// The timeout is a property of the transport, so it applies to both halves.
+    /// A packet partially received when the timeout expires is kept and
+    /// completed by the next read
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.frames.get_ref().set_read_timeout(timeout)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.frames.get_ref().read_timeout()
+    }
+
+    /// Read a message from the transport, blocking until one arrives
+    pub fn read_message<T: Deserialize>(&mut self) -> io::Result<T::Output> {
+        loop {
+            if let Some(message) = self.next_buffered::<T>()? {
+                return Ok(message);
+            }
+            self.frames.fill()?;
+        }
+    }
+
+    /// Read a message if one is available without blocking, `None` if no
+    /// complete packet arrived yet. The transport is non-blocking for the
+    /// duration of the call, which a writer on another thread would observe,
+    /// and transports that can't be fail with `Unsupported`
+    pub fn try_read_message<T: Deserialize>(&mut self) -> io::Result<Option<T::Output>> {
+        if let Some(message) = self.next_buffered::<T>()? {
+            return Ok(Some(message));
+        }
+        self.frames.get_ref().set_nonblocking(true)?;
+        let result = loop {
+            match self.frames.fill() {
+                Ok(_) => match self.next_buffered::<T>() {
+                    Ok(None) => continue,
+                    result => break result,
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(None),
+                Err(e) => break Err(e),
+            }
+        };
+        self.frames.get_ref().set_nonblocking(false)?;
+        result
+    }
+
+    /// Decode the next packet if it is completely buffered. The fixed header
+    /// is checked as soon as it arrives, before the rest is buffered
+    fn next_buffered<T: Deserialize>(&mut self) -> io::Result<Option<T::Output>> {
+        loop {
+            let Some((byte, len)) = self.frames.header().map_err(invalid_data)? else {
+                return Ok(None);
+            };
+            if self.strict {
+                strict::check_header(byte).map_err(violation)?;
+            }
+            if let Some(max) = self.max_packet_size.filter(|&max| len > max as usize) {
+                return Err(packet_too_large(len, max));
+            }
+            let Some(mut frame) = self.frames.next_frame().map_err(invalid_data)? else {
+                return Ok(None);
+            };
+            // Bytes of packets the decoder doesn't know are simply skipped
+            let message = match self.interceptors.is_empty() {
+                true => T::deserialize(&mut frame)?,
+                false => {
+                    let mut packet = Packet::new(frame.to_vec());
+                    self.interceptors.on_receive(&mut packet);
+                    if packet.is_discarded() {
+                        self.stats.record_received(len);
+                        continue;
+                    }
+                    T::deserialize(&mut packet.bytes())?
+                }
+            };
+            self.stats.record_received(len);
+            if self.strict {
+                T::validate(&message).map_err(violation)?;
+            }
+            return Ok(Some(message));
+        }
+    }
+}
+
+/// Sending half of a connection, see `Protocol::split`. Additional writers
+/// for other threads can be obtained with `try_clone`, all of them share the
+/// same packet id allocator
+#[derive(Debug)]
+pub struct MqttWriter {
+    stream: Box<dyn Transport>,
+    packet_ids: Arc<PacketIds>,
+    stats: Arc<Stats>,
+    interceptors: Arc<Interceptors>,
+    /// Encode buffer reused across messages, so that each one is written
+    /// with a single syscall and no allocation once it has grown enough
+    buf: Vec<u8>,
+    max_packet_size: Option<u32>,
+    strict: bool,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+/// Whether `packet` is a PUBLISH, by its fixed header
+fn is_publish(packet: &[u8]) -> bool {
+    packet.first().map(|byte| byte >> 4) == Some(PacketType::Publish as u8)
+}
+
+impl MqttWriter {
+    pub fn new(
+        transport: impl Transport + 'static,
+        packet_ids: Arc<PacketIds>,
+        stats: Arc<Stats>,
+    ) -> Self {
+        Self::from_boxed(Box::new(transport), packet_ids, stats)
+    }
+
+    pub(crate) fn from_boxed(
+        stream: Box<dyn Transport>,
+        packet_ids: Arc<PacketIds>,
+        stats: Arc<Stats>,
+    ) -> Self {
+        Self {
+            stream,
+            packet_ids,
+            stats,
+            interceptors: Arc::default(),
+            buf: Vec::new(),
+            max_packet_size: None,
+            strict: false,
+            rate_limiter: None,
+        }
+    }
+
+    /// Share the interceptors of another half of the connection
+    pub(crate) fn with_interceptors(mut self, interceptors: Arc<Interceptors>) -> Self {
+        self.interceptors = interceptors;
+        self
+    }
+
+    /// Run `interceptor` on every packet sent by this writer and its clones,
+    /// see `Protocol::add_interceptor`
+    pub fn add_interceptor(&self, interceptor: Arc<dyn PacketInterceptor>) {
+        self.interceptors.add(interceptor);
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let mut writer = Self::from_boxed(
+            self.stream.try_clone()?,
+            Arc::clone(&self.packet_ids),
+            Arc::clone(&self.stats),
+        );
+        writer.max_packet_size = self.max_packet_size;
+        writer.strict = self.strict;
+        writer.interceptors = Arc::clone(&self.interceptors);
+        writer.rate_limiter = self.rate_limiter.clone();
+        Ok(writer)
+    }
+
+    /// Refuse to send packets larger than `max` bytes, fixed header
+    /// included, nothing is written to the stream in that case
+    pub fn set_max_packet_size(&mut self, max: Option<u32>) {
+        self.max_packet_size = max;
+    }
+
+    /// Check every packet with `Serialize::validate` before sending it,
+    /// nothing is written for those failing
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Throttle the publishes of this writer and of the clones made
+    /// afterwards, which share the limit. `None` lifts it
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.rate_limiter = limit
+            .filter(|limit| !limit.is_unlimited())
+            .map(|limit| Arc::new(RateLimiter::new(&limit)));
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// The transport both halves of the connection share
+    pub fn transport(&self) -> &dyn Transport {
+        self.stream.as_ref()
+    }
+
+    /// Allocate the next packet identifier
+    pub fn next_packet_id(&self) -> u16 {
+        self.packet_ids.next()
+    }
+
+    /// Serialize a message to the server and write it to the transport
+    pub fn send_message(&mut self, message: &impl Serialize) -> io::Result<()> {
+        if self.strict {
+            message.validate()?;
+        }
+        self.buf.clear();
+        message.serialize(&mut self.buf)?;
+        if !self.interceptors.is_empty() {
+            let mut packet = Packet::new(std::mem::take(&mut self.buf));
+            self.interceptors.on_send(&mut packet);
+            let discarded = packet.is_discarded();
+            self.buf = packet.into_bytes();
+            if discarded {
+                return Ok(());
+            }
+        }
+        if let Some(max) = self
+            .max_packet_size
+            .filter(|&max| self.buf.len() > max as usize)
+        {
+            return Err(packet_too_large(self.buf.len(), max));
+        }
+        if let Some(limiter) = self.rate_limiter.as_ref().filter(|_| is_publish(&self.buf)) {
+            limiter.acquire(1, self.buf.len());
+        }
+        self.stream.write_all(&self.buf)?;
+        self.stats.record_sent(self.buf.len());
+        self.stream.flush()
+    }
+
+    /// Serialize every message into the encode buffer and write them with
+    /// a single syscall. Nothing is written if any of them is too large
+    pub fn send_batch(&mut self, messages: &[impl Serialize]) -> io::Result<()> {
+        self.buf.clear();
+        if self.strict {
+            for message in messages {
+                message.validate()?;
+            }
+        }
+        let mut sizes = Vec::with_capacity(messages.len());
+        let (mut publishes, mut publish_bytes) = (0, 0);
+        for message in messages {
+            let start = self.buf.len();
+            message.serialize(&mut self.buf)?;
+            if !self.interceptors.is_empty() {
+                let mut packet = Packet::new(self.buf.split_off(start));
+                self.interceptors.on_send(&mut packet);
+                if packet.is_discarded() {
+                    continue;
+                }
+                self.buf.extend_from_slice(packet.bytes());
+            }
+            let size = self.buf.len() - start;
+            if let Some(max) = self.max_packet_size.filter(|&max| size > max as usize) {
+                return Err(packet_too_large(size, max));
+            }
+            if is_publish(&self.buf[start..]) {
+                publishes += 1;
+                publish_bytes += size;
+            }
+            sizes.push(size);
+        }
+        if let Some(limiter) = self.rate_limiter.as_ref().filter(|_| publishes > 0) {
+            limiter.acquire(publishes, publish_bytes);
+        }
+        self.stream.write_all(&self.buf)?;
+        for size in sizes {
+            self.stats.record_sent(size);
+        }
+        self.stream.flush()
+    }
+
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        self.send_message(&Request::Disconnect { reason: None })
+    }
+
+    /// Publish a QoS 1 message, returning the packet id the PUBACK will carry
+    pub fn publish(&mut self, topic: &str, message: &[u8]) -> io::Result<u16> {
+        let packet_id = self.next_packet_id();
+        self.send_message(&PublishRef {
+            packet_id,
+            qos: 1,
+            dup: false,
+            retain: false,
+            topic,
+            payload: message,
+        })?;
+        Ok(packet_id)
+    }
+
+    /// Publish several messages with a single write, returning their packet
+    /// ids in order, 0 for QoS 0 messages. See `Protocol::publish_batch`
+    pub fn publish_batch(&mut self, messages: &[(&str, &[u8], Qos)]) -> io::Result<Vec<u16>> {
+        let packet_ids: Vec<u16> = messages
+            .iter()
+            .map(|(_, _, qos)| match qos {
+                Qos::AtMostOnce => 0,
+                _ => self.next_packet_id(),
+            })
+            .collect();
+        let requests: Vec<PublishRef> = messages
+            .iter()
+            .zip(&packet_ids)
+            .map(|(&(topic, payload, qos), &packet_id)| PublishRef {
+                packet_id,
+                qos: u8::from(&qos),
+                dup: false,
+                retain: false,
+                topic,
+                payload,
+            })
+            .collect();
+        self.send_batch(&requests)?;
+        Ok(packet_ids)
+    }
+
+    /// Subscribe to a single topic, returning the packet id the SUBACK will carry
+    pub fn subscribe(&mut self, topic: &str, qos: Qos) -> io::Result<u16> {
+        let packet_id = self.next_packet_id();
+        let sub_req = Request::Subscribe {
+            packet_id,
+            subscription_topics: vec![SubscriptionTopic {
+                qos,
+                topic: topic.to_string(),
+            }],
+        };
+        self.send_message(&sub_req)?;
+        Ok(packet_id)
+    }
+
+    pub fn ack(&mut self, ack_type: AckType) -> io::Result<()> {
+        let ack_request = match ack_type {
+            AckType::Puback(pkt_id) => Request::Puback { packet_id: pkt_id },
+            AckType::Pubrec(pkt_id) => Request::Pubrec { packet_id: pkt_id },
+            AckType::Pubrel(pkt_id) => Request::Pubrel { packet_id: pkt_id },
+            AckType::Pubcomp(pkt_id) => Request::Pubcomp { packet_id: pkt_id },
+        };
+        self.send_message(&ack_request)
+    }
+}
+
+#[cfg(test)]
+mod split_tests {
+    use super::*;
+    use crate::mqtt::Response;
+    use crate::testing::duplex;
+
+    fn too_large(err: io::Error) -> Option<TransportError> {
+        err.into_inner()?
+            .downcast::<TransportError>()
+            .ok()
+            .map(|e| *e)
+    }
+
+    #[test]
+    fn test_packet_ids_skip_zero() {
+        let ids = PacketIds {
+            last: AtomicU16::new(u16::MAX - 1),
+        };
+        assert_eq!(ids.next(), u16::MAX);
+        assert_eq!(ids.next(), 1);
+        assert_eq!(ids.next(), 2);
+    }
+
+    #[test]
+    fn test_packet_ids_shared() {
+        let ids = Arc::new(PacketIds::default());
+        let other = Arc::clone(&ids);
+        let handle = std::thread::spawn(move || (0..100).map(|_| other.next()).collect::<Vec<_>>());
+        let mut seen: Vec<u16> = (0..100).map(|_| ids.next()).collect();
+        seen.extend(handle.join().unwrap());
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 200);
+    }
+
+    #[test]
+    fn test_max_incoming_packet_size() -> io::Result<()> {
+        let (client, mut broker) = duplex();
+        let mut reader = MqttReader::new(client, Arc::new(Stats::default()));
+        reader.set_max_packet_size(Some(8));
+        // PUBACK fits, then a PUBLISH announcing 100 bytes never sent
+        broker.write_all(&[0x40, 2, 0, 1, 0x30, 100])?;
+        match reader.read_message::<Response>()? {
+            Response::Puback { packet_id } => assert_eq!(packet_id, 1),
+            resp => panic!("Unexpected response {}", resp),
+        }
+        let err = reader.read_message::<Response>().unwrap_err();
+        assert_eq!(
+            too_large(err),
+            Some(TransportError::PacketTooLarge { size: 102, max: 8 })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_outgoing_packet_size() -> io::Result<()> {
+        let (client, _broker) = duplex();
+        let mut writer = MqttWriter::new(client, Arc::default(), Arc::default());
+        writer.set_max_packet_size(Some(8));
+        writer.ack(AckType::Puback(1))?;
+        let err = writer.publish("topic", b"payload").unwrap_err();
+        assert_eq!(
+            too_large(err),
+            Some(TransportError::PacketTooLarge { size: 18, max: 8 })
+        );
+        assert_eq!(writer.stats().snapshot().packets_sent, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict() -> io::Result<()> {
+        let (client, mut broker) = duplex();
+        let mut reader = MqttReader::new(client, Arc::new(Stats::default()));
+        reader.set_strict(true);
+        // PUBREL with the reserved flags cleared
+        broker.write_all(&[0x60, 2, 0, 1])?;
+        let err = reader.read_message::<Response>().unwrap_err();
+        assert_eq!(
+            err.into_inner().unwrap().downcast_ref::<TransportError>(),
+            Some(&TransportError::ProtocolViolation(
+                Violation::ReservedFlags(0x60)
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_writer() -> io::Result<()> {
+        let (client, _broker) = duplex();
+        let mut writer = MqttWriter::new(client, Arc::default(), Arc::default());
+        writer.publish("a/+", b"lenient")?;
+        writer.set_strict(true);
+        let err = writer.publish("a/+", b"strict").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let subscribe = Request::Subscribe {
+            packet_id: 1,
+            subscription_topics: vec![SubscriptionTopic {
+                topic: "a/#/b".to_string(),
+                qos: Qos::AtMostOnce,
+            }],
+        };
+        assert!(writer.send_batch(&[Request::Pingreq, subscribe]).is_err());
+        assert_eq!(writer.stats().snapshot().packets_sent, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_read_message_partial() -> io::Result<()> {
+        let (client, mut broker) = duplex();
+        let mut reader = MqttReader::new(client, Arc::new(Stats::default()));
+        assert!(reader.try_read_message::<Response>()?.is_none());
+        broker.write_all(&[0x40, 2])?;
+        assert!(reader.try_read_message::<Response>()?.is_none());
+        broker.write_all(&[0, 1])?;
+        match reader.read_message::<Response>()? {
+            Response::Puback { packet_id } => assert_eq!(packet_id, 1),
+            resp => panic!("Unexpected response {}", resp),
+        }
+        assert_eq!(reader.stats().snapshot().bytes_received, 4);
+        Ok(())
+    }
+}
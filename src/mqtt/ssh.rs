@@ -0,0 +1,349 @@
+use std::fmt;
+use std::str::FromStr;
+
+const DEFAULT_SSH_PORT: u16 = 22;
+
+/// The jump host of `--ssh`, as `user@host` or `user@host:port`
+#[derive(Debug, Clone, PartialEq)]
+pub struct JumpHost {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for JumpHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, host) = s
+            .split_once('@')
+            .filter(|(user, host)| !user.is_empty() && !host.is_empty())
+            .ok_or_else(|| format!("Expected user@host[:port], got {:?}", s))?;
+        // A bracketed IPv6 address, or a host without a port
+        let (host, port) = match host.rsplit_once(':') {
+            Some((name, port)) if !name.contains(':') || name.ends_with(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid SSH port {:?}", port))?;
+                (name, port)
+            }
+            _ => (host, DEFAULT_SSH_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Ok(Self {
+            user: user.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for JumpHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "{}@[{}]:{}", self.user, self.host, self.port),
+            false => write!(f, "{}@{}:{}", self.user, self.host, self.port),
+        }
+    }
+}
+
+#[cfg(feature = "ssh")]
+pub use tunnel::SshTransport;
+
+#[cfg(feature = "ssh")]
+mod tunnel {
+    use super::JumpHost;
+    use crate::mqtt::{sockopt, Transport};
+    use ssh2::{CheckResult, KnownHostFileKind, Session};
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// Longest a read or write sleeps on the socket before checking whether
+    /// the tunnel was shut down
+    const POLL: Duration = Duration::from_millis(200);
+
+    /// Keys tried after the agent when none is given, as OpenSSH does
+    const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+    fn ssh_error(err: ssh2::Error) -> io::Error {
+        io::Error::other(err)
+    }
+
+    /// Shared by every handle on the tunnel
+    #[derive(Debug, Default)]
+    struct State {
+        read_timeout: Mutex<Option<Duration>>,
+        write_timeout: Mutex<Option<Duration>>,
+        nonblocking: AtomicBool,
+        read_closed: AtomicBool,
+        write_closed: AtomicBool,
+    }
+
+    /// A direct-tcpip channel opened through a jump host, carrying the
+    /// connection to a broker only the jump host reaches, as `ssh -J` or
+    /// `ssh -L` would. The session is non-blocking so that its handles can
+    /// read and write from different threads
+    pub struct SshTransport {
+        session: Session,
+        stream: ssh2::Stream,
+        channel: Arc<Mutex<ssh2::Channel>>,
+        /// The connection to the jump host, polled while the session would
+        /// block
+        socket: TcpStream,
+        state: Arc<State>,
+    }
+
+    impl SshTransport {
+        /// Log into `jump` and open a channel to `host:port` as resolved by
+        /// it. The host key must be in `~/.ssh/known_hosts`, and the user is
+        /// authenticated with `key` if given, else with the SSH agent or the
+        /// default keys of `~/.ssh`
+        pub fn open(
+            jump: &JumpHost,
+            key: Option<&Path>,
+            host: &str,
+            port: u16,
+            timeout: Duration,
+        ) -> io::Result<Self> {
+            let addr = (jump.host.as_str(), jump.port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no jump host address found")
+                })?;
+            let socket = TcpStream::connect_timeout(&addr, timeout)?;
+            let mut session = Session::new().map_err(ssh_error)?;
+            session.set_tcp_stream(socket.try_clone()?);
+            session.set_timeout(timeout.as_millis().try_into().unwrap_or(u32::MAX));
+            session.handshake().map_err(ssh_error)?;
+            check_host_key(&session, jump)?;
+            authenticate(&session, &jump.user, key)?;
+            let channel = session
+                .channel_direct_tcpip(host, port, None)
+                .map_err(ssh_error)?;
+            session.set_blocking(false);
+            Ok(Self {
+                stream: channel.stream(0),
+                channel: Arc::new(Mutex::new(channel)),
+                session,
+                socket,
+                state: Arc::default(),
+            })
+        }
+
+        /// Run `op` until the session doesn't block anymore, waiting for the
+        /// socket in between. Fails with `WouldBlock` once `timeout` elapses
+        fn retry<T>(
+            &mut self,
+            timeout: Option<Duration>,
+            closed: &AtomicBool,
+            mut op: impl FnMut(&mut ssh2::Stream) -> io::Result<T>,
+            on_closed: impl Fn() -> io::Result<T>,
+        ) -> io::Result<T> {
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            loop {
+                if closed.load(Ordering::Relaxed) {
+                    return on_closed();
+                }
+                match op(&mut self.stream) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                if self.state.nonblocking.load(Ordering::Relaxed) {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                let wait = match deadline {
+                    None => POLL,
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(left) if !left.is_zero() => left.min(POLL),
+                        _ => return Err(io::ErrorKind::WouldBlock.into()),
+                    },
+                };
+                let directions = self.session.block_directions();
+                let read = matches!(
+                    directions,
+                    ssh2::BlockDirections::Inbound | ssh2::BlockDirections::Both
+                );
+                let write = matches!(
+                    directions,
+                    ssh2::BlockDirections::Outbound | ssh2::BlockDirections::Both
+                );
+                // Another handle may have consumed what unblocks this one,
+                // hence the bounded wait
+                sockopt::wait(&self.socket, read || !write, write, wait)?;
+            }
+        }
+    }
+
+    fn check_host_key(session: &Session, jump: &JumpHost) -> io::Result<()> {
+        let (key, _) = session.host_key().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Jump host sent no host key")
+        })?;
+        let mut known_hosts = session.known_hosts().map_err(ssh_error)?;
+        let path = ssh_dir()?.join("known_hosts");
+        if path.exists() {
+            known_hosts
+                .read_file(&path, KnownHostFileKind::OpenSSH)
+                .map_err(ssh_error)?;
+        }
+        let refused = |reason| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Host key of {} {}", jump.host, reason),
+            )
+        };
+        match known_hosts.check_port(&jump.host, jump.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(refused("differs from known_hosts")),
+            CheckResult::NotFound => Err(refused(
+                "not in known_hosts, connect once with ssh to check and add it",
+            )),
+            CheckResult::Failure => Err(refused("could not be checked")),
+        }
+    }
+
+    fn authenticate(session: &Session, user: &str, key: Option<&Path>) -> io::Result<()> {
+        match key {
+            Some(key) => session
+                .userauth_pubkey_file(user, None, key, None)
+                .map_err(ssh_error)?,
+            None => {
+                if session.userauth_agent(user).is_err() {
+                    let dir = ssh_dir()?;
+                    for key in DEFAULT_KEYS.iter().map(|name| dir.join(name)) {
+                        if key.exists()
+                            && session.userauth_pubkey_file(user, None, &key, None).is_ok()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        match session.authenticated() {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("SSH authentication of {} failed", user),
+            )),
+        }
+    }
+
+    fn ssh_dir() -> io::Result<PathBuf> {
+        std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".ssh"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))
+    }
+
+    impl Read for SshTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let timeout = *self.state.read_timeout.lock().unwrap();
+            let state = Arc::clone(&self.state);
+            self.retry(
+                timeout,
+                &state.read_closed,
+                |stream| stream.read(buf),
+                || Ok(0),
+            )
+        }
+    }
+
+    impl Write for SshTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let timeout = *self.state.write_timeout.lock().unwrap();
+            let state = Arc::clone(&self.state);
+            self.retry(
+                timeout,
+                &state.write_closed,
+                |stream| stream.write(buf),
+                || Err(io::ErrorKind::BrokenPipe.into()),
+            )
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let timeout = *self.state.write_timeout.lock().unwrap();
+            let state = Arc::clone(&self.state);
+            self.retry(
+                timeout,
+                &state.write_closed,
+                |stream| stream.flush(),
+                || Ok(()),
+            )
+        }
+    }
+
+    impl Transport for SshTransport {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(Self {
+                session: self.session.clone(),
+                stream: self.stream.clone(),
+                channel: Arc::clone(&self.channel),
+                socket: self.socket.try_clone()?,
+                state: Arc::clone(&self.state),
+            }))
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            *self.state.read_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn read_timeout(&self) -> io::Result<Option<Duration>> {
+            Ok(*self.state.read_timeout.lock().unwrap())
+        }
+
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            *self.state.write_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.state.nonblocking.store(nonblocking, Ordering::Relaxed);
+            Ok(())
+        }
+
+        /// Handles blocked on the tunnel notice within `POLL`. The broker is
+        /// told with an EOF, best effort as the session may be busy
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            if matches!(how, Shutdown::Read | Shutdown::Both) {
+                self.state.read_closed.store(true, Ordering::Relaxed);
+            }
+            if matches!(how, Shutdown::Write | Shutdown::Both)
+                && !self.state.write_closed.swap(true, Ordering::Relaxed)
+            {
+                let _ = self.channel.lock().unwrap().send_eof();
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod ssh_tests {
+    use super::*;
+
+    #[test]
+    fn test_jump_host() {
+        let parse = |s: &str| s.parse::<JumpHost>();
+        assert_eq!(
+            parse("pi@bastion"),
+            Ok(JumpHost {
+                user: "pi".to_string(),
+                host: "bastion".to_string(),
+                port: 22,
+            })
+        );
+        assert_eq!(parse("pi@bastion:2222").unwrap().port, 2222);
+        let v6 = parse("pi@[fd00::1]:2222").unwrap();
+        assert_eq!((v6.host.as_str(), v6.port), ("fd00::1", 2222));
+        assert_eq!(v6.to_string(), "pi@[fd00::1]:2222");
+        assert_eq!(parse("pi@fd00::1").unwrap().host, "fd00::1");
+        assert!(parse("bastion").is_err());
+        assert!(parse("@bastion").is_err());
+        assert!(parse("pi@bastion:ssh").is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Packet and byte counters of a connection, shared by its reader and writer
+#[derive(Debug, Default)]
+pub struct Stats {
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl Stats {
+    pub fn record_sent(&self, bytes: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point in time copy of `Stats`, snapshots of several connections can be
+/// summed to get totals
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StatsSnapshot {
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl std::ops::AddAssign for StatsSnapshot {
+    fn add_assign(&mut self, other: Self) {
+        self.packets_sent += other.packets_sent;
+        self.packets_received += other.packets_received;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "packets sent:     {} ({} bytes)",
+            self.packets_sent, self.bytes_sent
+        )?;
+        write!(
+            f,
+            "packets received: {} ({} bytes)",
+            self.packets_received, self.bytes_received
+        )
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_totals() {
+        let stats = Stats::default();
+        stats.record_sent(4);
+        stats.record_sent(10);
+        stats.record_received(2);
+        let mut total = StatsSnapshot::default();
+        total += stats.snapshot();
+        total += stats.snapshot();
+        assert_eq!(
+            total,
+            StatsSnapshot {
+                packets_sent: 4,
+                packets_received: 2,
+                bytes_sent: 28,
+                bytes_received: 4,
+            }
+        );
+    }
+}
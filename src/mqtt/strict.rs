@@ -0,0 +1,131 @@
+use crate::mqtt::Response;
+use std::fmt;
+
+/// A packet that decodes but breaks the MQTT 3.1.1 spec, only reported when
+/// strict validation is enabled, see `ProtocolBuilder::strict`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Packet type 0 or 15, both reserved
+    ReservedPacketType(u8),
+    /// Fixed header flags not matching the ones mandated for the packet type
+    ReservedFlags(u8),
+    /// QoS 3 in a PUBLISH fixed header
+    InvalidQos(u8),
+    /// Packet id 0 on a packet that requires a non-zero one
+    ZeroPacketId(u8),
+    /// PUBLISH topic name containing `+` or `#`
+    WildcardTopic,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::ReservedPacketType(byte) => {
+                write!(f, "reserved packet type in fixed header 0x{:02X}", byte)
+            }
+            Violation::ReservedFlags(byte) => {
+                write!(f, "invalid flags in fixed header 0x{:02X}", byte)
+            }
+            Violation::InvalidQos(byte) => {
+                write!(f, "QoS 3 in PUBLISH fixed header 0x{:02X}", byte)
+            }
+            Violation::ZeroPacketId(byte) => {
+                write!(f, "packet id 0 in packet with fixed header 0x{:02X}", byte)
+            }
+            Violation::WildcardTopic => write!(f, "wildcard in PUBLISH topic name"),
+        }
+    }
+}
+
+/// Validate the first byte of a fixed header: PUBREL, SUBSCRIBE and
+/// UNSUBSCRIBE must carry flags 0b0010, PUBLISH any flags but QoS 3 or DUP
+/// on QoS 0, every other packet type no flags at all
+pub fn check_header(byte: u8) -> Result<(), Violation> {
+    let flags = byte & 0x0F;
+    match byte >> 4 {
+        0 | 15 => Err(Violation::ReservedPacketType(byte)),
+        3 if flags & 0b0110 == 0b0110 => Err(Violation::InvalidQos(byte)),
+        3 if flags & 0b1110 == 0b1000 => Err(Violation::ReservedFlags(byte)),
+        3 => Ok(()),
+        6 | 8 | 10 if flags == 0b0010 => Ok(()),
+        6 | 8 | 10 => Err(Violation::ReservedFlags(byte)),
+        _ if flags == 0 => Ok(()),
+        _ => Err(Violation::ReservedFlags(byte)),
+    }
+}
+
+/// Validate the content of a decoded response
+pub fn check_response(response: &Response) -> Result<(), Violation> {
+    let (byte, packet_id) = match response {
+        Response::Publish {
+            qos,
+            packet_id,
+            topic,
+            ..
+        } => {
+            if topic.contains(['+', '#']) {
+                return Err(Violation::WildcardTopic);
+            }
+            if *qos == 0 {
+                return Ok(());
+            }
+            (0x30 | qos << 1, *packet_id)
+        }
+        Response::Puback { packet_id } => (0x40, *packet_id),
+        Response::Pubrec { packet_id } => (0x50, *packet_id),
+        Response::Pubrel { packet_id } => (0x62, *packet_id),
+        Response::Pubcomp { packet_id } => (0x70, *packet_id),
+        Response::Suback { packet_id, .. } => (0x90, *packet_id),
+        Response::Connack { .. }
+        | Response::Pingresp
+        | Response::Disconnect { .. }
+        | Response::Unknown { .. } => return Ok(()),
+    };
+    if packet_id == 0 {
+        return Err(Violation::ZeroPacketId(byte));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod strict_tests {
+    use super::*;
+
+    #[test]
+    fn test_check_header() {
+        for byte in [0x10, 0x20, 0x30, 0x3B, 0x40, 0x62, 0x82, 0x90, 0xA2, 0xE0] {
+            assert_eq!(check_header(byte), Ok(()), "0x{:02X}", byte);
+        }
+        assert_eq!(check_header(0x60), Err(Violation::ReservedFlags(0x60)));
+        assert_eq!(check_header(0x80), Err(Violation::ReservedFlags(0x80)));
+        assert_eq!(check_header(0x41), Err(Violation::ReservedFlags(0x41)));
+        assert_eq!(check_header(0x38), Err(Violation::ReservedFlags(0x38)));
+        assert_eq!(check_header(0x36), Err(Violation::InvalidQos(0x36)));
+        assert_eq!(check_header(0xF0), Err(Violation::ReservedPacketType(0xF0)));
+    }
+
+    #[test]
+    fn test_check_response() {
+        let publish = |qos, packet_id, topic: &str| Response::Publish {
+            packet_id,
+            qos,
+            retain: false,
+            topic: topic.to_string(),
+            payload: vec![],
+            properties: vec![],
+        };
+        assert_eq!(check_response(&publish(0, 0, "a/b")), Ok(()));
+        assert_eq!(
+            check_response(&publish(1, 0, "a/b")),
+            Err(Violation::ZeroPacketId(0x32))
+        );
+        assert_eq!(
+            check_response(&publish(0, 0, "a/+")),
+            Err(Violation::WildcardTopic)
+        );
+        assert_eq!(
+            check_response(&Response::Pubrel { packet_id: 0 }),
+            Err(Violation::ZeroPacketId(0x62))
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use crate::mqtt::FixedHeader;
+use byteorder::{NetworkEndian, ReadBytesExt};
+use std::fmt;
+use std::io::{self, Read};
+
+/// Return code signalling a refused subscription in a SUBACK payload
+pub const SUBACK_FAILURE: u8 = 0x80;
+
+///
+/// MQTT Suback packet, carries the packet identifier of the SUBSCRIBE it's
+/// acknowledging followed by a return code for each topic requested, in the
+/// same order:
+///
+/// |----------|--------------------------------------------------|<-- Variable Header
+/// | Byte 3   |            Packet Identifier MSB                 |  [UINT16]
+/// | Byte 4   |            Packet Identifier LSB                 |
+/// |----------|--------------------------------------------------|<-- Payload
+/// | Byte 5   |                                                  |
+/// |   .      |       Return codes (granted QoS or 0x80)         |
+/// | Byte N   |                                                  |
+///
+#[derive(Debug, PartialEq)]
+pub struct SubackPacket {
+    pub packet_id: u16,
+    pub return_codes: Vec<u8>,
+}
+
+impl fmt::Display for SubackPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SUBACK: packet ID {} return codes {:?}",
+            self.packet_id, self.return_codes
+        )
+    }
+}
+
+impl SubackPacket {
+    pub fn from_bytes(bytes: &mut impl Read, fixed_header: &FixedHeader) -> io::Result<Self> {
+        let packet_id = bytes.read_u16::<NetworkEndian>()?;
+        let len = (fixed_header.remaining_length() as usize).saturating_sub(2);
+        let mut return_codes = vec![0u8; len];
+        bytes.read_exact(&mut return_codes)?;
+        Ok(Self {
+            packet_id,
+            return_codes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod suback_tests {
+    use super::*;
+
+    #[test]
+    fn test_from_bytes() -> io::Result<()> {
+        let fixed_header = FixedHeader::new(0x90, 4);
+        let bytes = &[2, 6, 1, 0x80];
+        let suback = SubackPacket::from_bytes(&mut bytes.as_slice(), &fixed_header)?;
+        assert_eq!(
+            suback,
+            SubackPacket {
+                packet_id: 518,
+                return_codes: vec![1, SUBACK_FAILURE]
+            }
+        );
+        Ok(())
+    }
+}
@@ -3,6 +3,7 @@ use byteorder::{NetworkEndian, WriteBytesExt};
 use std::io::{self, Write};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubscriptionTopic {
     pub qos: Qos,
     pub topic: String,
@@ -24,12 +25,10 @@ impl SubscribePacket {
 
     pub fn write(&self, buf: &mut impl Write) -> io::Result<()> {
         buf.write_u16::<NetworkEndian>(self.packet_id)?;
-        self.subscription_topics
-            .iter()
-            .for_each(|s: &SubscriptionTopic| {
-                protocol::write_string(buf, &s.topic);
-                buf.write_u8(s.qos as u8);
-            });
+        for s in &self.subscription_topics {
+            protocol::write_string(buf, &s.topic)?;
+            buf.write_u8(s.qos as u8)?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,357 @@
+use crate::mqtt::{
+    ChannelOptions, ClientHandle, Connection, Overflow, PacketReader, Protocol, Response,
+};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Client driving a `Connection` on a background thread, which executes the
+/// requests sent through the `ClientHandle`s as they come. Another thread
+/// reads the socket and hands each packet over to the connection, which
+/// acks it, then forwards it to the `incoming` receiver.
+///
+/// Dropping the client without calling `join` still sends DISCONNECT after
+/// the requests already queued, if the `Protocol` it was spawned from was
+/// configured to disconnect on drop
+pub struct ThreadedClient {
+    pub handle: ClientHandle,
+    pub incoming: Receiver<Response>,
+    dropped: Arc<AtomicU64>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+    reader_thread: Option<JoinHandle<()>>,
+    /// Disconnected once the connection thread is done
+    finished: Receiver<()>,
+    drop_grace: Option<Duration>,
+}
+
+impl ThreadedClient {
+    /// Take ownership of an already connected `Protocol`, usually obtained
+    /// through `Protocol::builder()`, with the default `ChannelOptions`
+    pub fn spawn(protocol: Protocol) -> io::Result<Self> {
+        Self::spawn_connection(Connection::new(protocol))
+    }
+
+    /// Like `spawn`, bounding the channels as `options` say. Capacities
+    /// are at least 1
+    pub fn spawn_with(protocol: Protocol, options: ChannelOptions) -> io::Result<Self> {
+        Self::spawn_connection(Connection::with_channels(protocol, options))
+    }
+
+    /// Drive `connection` in the background, for one configured beyond
+    /// what `spawn_with` does, like its ack timeout
+    pub fn spawn_connection(mut connection: Connection) -> io::Result<Self> {
+        let drop_grace = connection.protocol().disconnect_on_drop();
+        let options = connection.channel_options();
+        let handle = connection.handle();
+        let reader = connection.reader()?;
+        let (sender, incoming) = mpsc::sync_channel(options.incoming.max(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let incoming_tx = Incoming {
+            sender,
+            overflow: options.overflow,
+            dropped: Arc::clone(&dropped),
+        };
+        let (done, finished) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("sake-client".into())
+            .spawn(move || {
+                let _done = done;
+                connection.run()
+            })?;
+        let reader_thread = thread::Builder::new()
+            .name("sake-reader".into())
+            .spawn(move || forward(reader, incoming_tx))?;
+        Ok(Self {
+            handle,
+            incoming,
+            dropped,
+            thread: Some(thread),
+            reader_thread: Some(reader_thread),
+            finished,
+            drop_grace,
+        })
+    }
+
+    /// Incoming packets discarded so far because `incoming` was full, with
+    /// `Overflow::DropNewest`
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Disconnect and wait for the background thread to terminate
+    pub fn join(mut self) -> io::Result<()> {
+        // The thread may already be gone if the connection dropped
+        let _ = self.handle.disconnect();
+        let Some(thread) = self.thread.take() else {
+            return Err(closed());
+        };
+        // A reader waiting for room in `incoming` gives up once nobody receives
+        let (_, unused) = mpsc::sync_channel(0);
+        drop(std::mem::replace(&mut self.incoming, unused));
+        let result = thread.join().map_err(|_| closed())?;
+        // The socket is shut down by now, whichever way the connection ended
+        if let Some(reader_thread) = self.reader_thread.take() {
+            let _ = reader_thread.join();
+        }
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            result => result,
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Client thread has terminated")
+}
+
+impl Drop for ThreadedClient {
+    fn drop(&mut self) {
+        let (Some(grace), Some(_)) = (self.drop_grace, self.thread.take()) else {
+            return;
+        };
+        if self.handle.disconnect().is_err() {
+            return;
+        }
+        // JoinHandle can't be joined with a timeout, wait for the thread to
+        // go through the queue or for the grace period to expire
+        let _ = self.finished.recv_timeout(grace);
+    }
+}
+
+/// Sending end of `ThreadedClient::incoming`
+struct Incoming {
+    sender: SyncSender<Response>,
+    overflow: Overflow,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Incoming {
+    /// Hand `response` over, waiting for room if the overflow says so.
+    /// Nobody listening anymore is not an error, the connection is still
+    /// driven
+    fn deliver(&self, response: Response) {
+        let response = match self.overflow {
+            Overflow::Block => {
+                let _ = self.sender.send(response);
+                return;
+            }
+            Overflow::DropNewest => response,
+        };
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(response) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Forward the packets read until the connection ends. The socket isn't
+/// read while waiting for room in `incoming`, so that a slow consumer
+/// pushes back on the broker, requests still go out meanwhile
+fn forward(mut reader: PacketReader, incoming: Incoming) {
+    while let Ok(response) = reader.next() {
+        if let Some(response) = response {
+            incoming.deliver(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod threaded_tests {
+    use super::*;
+    use crate::mqtt::Qos;
+    use crate::testing::duplex;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_incoming_publish_is_acked() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        let broker = thread::spawn(move || -> io::Result<Vec<u8>> {
+            // PUBLISH QoS 1 on topic "a" with packet id 7 and payload "hi"
+            stream.write_all(&[0x32, 7, 0, 1, b'a', 0, 7, b'h', b'i'])?;
+            let mut received = vec![];
+            stream.read_to_end(&mut received)?;
+            Ok(received)
+        });
+
+        let client = ThreadedClient::spawn(Protocol::with_transport(client)?)?;
+        match client.incoming.recv().unwrap() {
+            Response::Publish {
+                packet_id,
+                topic,
+                payload,
+                ..
+            } => {
+                assert_eq!(packet_id, 7);
+                assert_eq!(topic, "a");
+                assert_eq!(payload, b"hi");
+            }
+            resp => panic!("Unexpected response {}", resp),
+        }
+        let token = client.handle.publish("b", b"x", Qos::AtMostOnce)?;
+        assert_eq!(token.wait_timeout(Duration::from_secs(1))?, 0);
+        client.join()?;
+
+        let received = broker.join().unwrap()?;
+        assert_eq!(
+            received,
+            &[0x40, 2, 0, 7, 0x30, 4, 0, 1, b'b', b'x', 0xE0, 0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_qos2_duplicates_delivered_once() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        let broker = thread::spawn(move || -> io::Result<Vec<u8>> {
+            // PUBLISH QoS 2 id 5, its DUP retransmission, PUBREL, then a new
+            // message reusing id 5
+            stream.write_all(&[0x34, 6, 0, 1, b'a', 0, 5, b'1'])?;
+            stream.write_all(&[0x3C, 6, 0, 1, b'a', 0, 5, b'1'])?;
+            stream.write_all(&[0x62, 2, 0, 5])?;
+            stream.write_all(&[0x34, 6, 0, 1, b'a', 0, 5, b'2'])?;
+            let mut received = vec![];
+            stream.read_to_end(&mut received)?;
+            Ok(received)
+        });
+
+        let client = ThreadedClient::spawn(Protocol::with_transport(client)?)?;
+        let mut payloads = vec![];
+        while payloads.len() < 2 {
+            if let Response::Publish { payload, .. } = client.incoming.recv().unwrap() {
+                payloads.push(payload);
+            }
+        }
+        assert_eq!(payloads, vec![b"1".to_vec(), b"2".to_vec()]);
+        client.join()?;
+        let received = broker.join().unwrap()?;
+        // Every PUBLISH is acknowledged, duplicates included
+        assert_eq!(
+            received,
+            &[0x50, 2, 0, 5, 0x50, 2, 0, 5, 0x70, 2, 0, 5, 0x50, 2, 0, 5, 0xE0, 0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delivery_token_resolves_on_puback() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        let broker = thread::spawn(move || -> io::Result<()> {
+            let mut publish = [0u8; 7];
+            stream.read_exact(&mut publish)?;
+            assert_eq!(publish, [0x32, 5, 0, 1, b'b', 0, 1]);
+            stream.write_all(&[0x40, 2, 0, 1])?;
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest)?;
+            Ok(())
+        });
+
+        let client = ThreadedClient::spawn(Protocol::with_transport(client)?)?;
+        let token = client.handle.publish("b", b"", Qos::AtLeastOnce)?;
+        assert_eq!(token.wait_timeout(Duration::from_secs(1))?, 1);
+        client.join()?;
+        broker.join().unwrap()
+    }
+
+    #[test]
+    fn test_drop_sends_disconnect() -> io::Result<()> {
+        let (client, mut stream) = duplex();
+        let broker = thread::spawn(move || -> io::Result<Vec<u8>> {
+            // PUBLISH QoS 2 with packet id 3, the PUBREC must precede DISCONNECT
+            stream.write_all(&[0x34, 5, 0, 1, b'a', 0, 3])?;
+            let mut received = vec![];
+            stream.read_to_end(&mut received)?;
+            Ok(received)
+        });
+
+        let mut protocol = Protocol::with_transport(client)?;
+        protocol.set_disconnect_on_drop(Some(Duration::from_secs(1)));
+        let client = ThreadedClient::spawn(protocol)?;
+        client.incoming.recv().unwrap();
+        drop(client);
+        assert_eq!(broker.join().unwrap()?, &[0x50, 2, 0, 3, 0xE0, 0]);
+        Ok(())
+    }
+
+    /// QoS 0 publishes on "a" with a 1000 bytes payload, back to back
+    fn publishes(count: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        for _ in 0..count {
+            bytes.extend_from_slice(&[0x30, 0xEB, 0x07, 0, 1, b'a']);
+            bytes.extend_from_slice(&[0; 1000]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_slow_consumer_blocks_reader() -> io::Result<()> {
+        let (client, mut broker) = duplex();
+        let socket = client.clone();
+        broker.write_all(&publishes(100))?;
+        let options = ChannelOptions {
+            incoming: 2,
+            ..ChannelOptions::default()
+        };
+        let client = ThreadedClient::spawn_with(Protocol::with_transport(client)?, options)?;
+        for received in 1..=100 {
+            client
+                .incoming
+                .recv_timeout(Duration::from_secs(1))
+                .unwrap();
+            // Two packets in `incoming`, one waiting for room and a chunk
+            // read ahead at most, the rest left on the socket
+            let unread = (100usize.saturating_sub(received + 3) * 1006).saturating_sub(4096);
+            assert!(socket.pending() >= unread, "{}", socket.pending());
+        }
+        assert_eq!(socket.pending(), 0);
+        assert_eq!(client.dropped(), 0);
+        // Joining with packets left unreceived doesn't hang
+        broker.write_all(&publishes(10))?;
+        client
+            .incoming
+            .recv_timeout(Duration::from_secs(1))
+            .unwrap();
+        client.join()
+    }
+
+    #[test]
+    fn test_slow_consumer_drops_newest() -> io::Result<()> {
+        let (client, mut broker) = duplex();
+        let socket = client.clone();
+        // A QoS 2 PUBLISH with packet id 5, then 9 QoS 0 and the DUP of the
+        // first one, which is acked but not delivered again
+        broker.write_all(&[0x34, 5, 0, 1, b'a', 0, 5])?;
+        broker.write_all(&publishes(9))?;
+        broker.write_all(&[0x3C, 5, 0, 1, b'a', 0, 5])?;
+        let options = ChannelOptions {
+            incoming: 3,
+            overflow: Overflow::DropNewest,
+            ..ChannelOptions::default()
+        };
+        let client = ThreadedClient::spawn_with(Protocol::with_transport(client)?, options)?;
+        // The second PUBREC is only sent once the packets before were offered
+        let mut pubrecs = [0; 8];
+        broker.read_exact(&mut pubrecs)?;
+        assert_eq!(pubrecs, [0x50, 2, 0, 5, 0x50, 2, 0, 5]);
+        assert_eq!(client.dropped(), 7);
+        assert_eq!(socket.pending(), 0);
+        assert_eq!(client.incoming.try_iter().count(), 3);
+        client.join()
+    }
+
+    #[test]
+    fn test_delivery_token_lost_connection() -> io::Result<()> {
+        let (client, broker) = duplex();
+        let client = ThreadedClient::spawn(Protocol::with_transport(client)?)?;
+        // Hang up straight away
+        drop(broker);
+        // Refused once the client noticed, failed in flight otherwise
+        let delivery = client
+            .handle
+            .publish("b", b"", Qos::AtLeastOnce)
+            .and_then(|token| token.wait_timeout(Duration::from_secs(1)));
+        assert!(delivery.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,847 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Port of MQTT over TLS
+pub const DEFAULT_TLS_PORT: u16 = 8883;
+
+/// Longest PSK identity sent to the broker
+const MAX_PSK_IDENTITY_LEN: usize = 128;
+
+/// Longest pre-shared key, 512 bits
+const MAX_PSK_LEN: usize = 64;
+
+/// How a TLS connection to a broker is set up
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsConfig {
+    /// PEM certificates the broker must chain to, the web PKI roots if
+    /// `None`
+    pub ca: Option<PathBuf>,
+    /// Name sent in SNI and checked against the certificate, instead of the
+    /// host connected to. Brokers behind a shared load balancer need it
+    pub server_name: Option<String>,
+    /// Protocols offered through ALPN, in order of preference, as `mqtt`
+    pub alpn: Vec<String>,
+    /// Accept any certificate, the connection is then encrypted but anyone
+    /// on the path may impersonate the broker
+    pub insecure: bool,
+    /// Authenticate both ends with a key shared beforehand instead of
+    /// certificates, as constrained devices do. Needs the psk feature
+    pub psk: Option<PreSharedKey>,
+}
+
+/// An identity and the key the broker knows it by, for TLS-PSK
+#[derive(Clone, PartialEq)]
+pub struct PreSharedKey {
+    pub identity: String,
+    pub key: Vec<u8>,
+}
+
+impl PreSharedKey {
+    /// The key as `hex` digits, as brokers usually take it
+    pub fn from_hex(identity: &str, hex: &str) -> io::Result<Self> {
+        let invalid =
+            |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, reason.to_string());
+        if identity.is_empty() || identity.len() > MAX_PSK_IDENTITY_LEN || identity.contains('\0') {
+            return Err(invalid("PSK identity must be 1 to 128 bytes, without NUL"));
+        }
+        let digits = hex.as_bytes();
+        if digits.is_empty() || !digits.len().is_multiple_of(2) || digits.len() / 2 > MAX_PSK_LEN {
+            return Err(invalid("PSK key must be 1 to 64 bytes as hex digits"));
+        }
+        let key = digits
+            .chunks(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| invalid("PSK key must be hex digits"))?;
+        Ok(Self {
+            identity: identity.to_string(),
+            key,
+        })
+    }
+}
+
+/// The key stays out of logs
+impl fmt::Debug for PreSharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PreSharedKey")
+            .field("identity", &self.identity)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "tls")]
+pub use stream::TlsTransport;
+
+#[cfg(feature = "tls")]
+mod stream {
+    use super::TlsConfig;
+    use crate::mqtt::{sockopt, Transport};
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::crypto::{self, CryptoProvider};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore};
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// Longest a read or write sleeps on the socket before checking whether
+    /// the connection was shut down
+    const POLL: Duration = Duration::from_millis(200);
+
+    fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+
+    /// The TLS state of a connection, rustls authenticating with
+    /// certificates or OpenSSL with a pre-shared key, which rustls lacks.
+    /// Every call works on the non-blocking socket, `WouldBlock` tells to
+    /// wait for it
+    enum Session {
+        Rustls(Box<ClientConnection>),
+        #[cfg(feature = "psk")]
+        OpenSsl(openssl::ssl::SslStream<TcpStream>),
+    }
+
+    impl Session {
+        fn read(&mut self, mut socket: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+            match self {
+                Session::Rustls(connection) => loop {
+                    match connection.reader().read(buf) {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        result => return result,
+                    }
+                    connection.read_tls(&mut socket)?;
+                    connection.process_new_packets().map_err(invalid_data)?;
+                },
+                #[cfg(feature = "psk")]
+                Session::OpenSsl(stream) => stream.read(buf),
+            }
+        }
+
+        /// Takes what of `buf` fits in the connection, sending as many
+        /// records as the socket accepts
+        fn write(&mut self, socket: &TcpStream, buf: &[u8]) -> io::Result<usize> {
+            match self {
+                Session::Rustls(connection) => {
+                    // Room for the plaintext, the connection buffers a
+                    // bounded amount
+                    match Self::send_records(connection, socket) {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        result => result?,
+                    }
+                    let written = connection.writer().write(buf)?;
+                    if written == 0 && !buf.is_empty() {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    // What's left goes out with the next write or flush
+                    match Self::send_records(connection, socket) {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(written),
+                        result => result.map(|_| written),
+                    }
+                }
+                #[cfg(feature = "psk")]
+                Session::OpenSsl(stream) => stream.write(buf),
+            }
+        }
+
+        /// Send the records the connection holds
+        fn flush(&mut self, socket: &TcpStream) -> io::Result<()> {
+            match self {
+                Session::Rustls(connection) => Self::send_records(connection, socket),
+                // OpenSSL writes its records as they are made
+                #[cfg(feature = "psk")]
+                Session::OpenSsl(_) => Ok(()),
+            }
+        }
+
+        fn send_records(
+            connection: &mut ClientConnection,
+            mut socket: &TcpStream,
+        ) -> io::Result<()> {
+            while connection.wants_write() {
+                connection.write_tls(&mut socket)?;
+            }
+            Ok(())
+        }
+
+        fn send_close_notify(&mut self) {
+            match self {
+                Session::Rustls(connection) => connection.send_close_notify(),
+                // Sent right away, a full socket loses it as rustls would
+                #[cfg(feature = "psk")]
+                Session::OpenSsl(stream) => {
+                    let _ = stream.shutdown();
+                }
+            }
+        }
+
+        fn alpn_protocol(&self) -> Option<Vec<u8>> {
+            let protocol = match self {
+                Session::Rustls(connection) => connection.alpn_protocol(),
+                #[cfg(feature = "psk")]
+                Session::OpenSsl(stream) => stream.ssl().selected_alpn_protocol(),
+            };
+            protocol.map(|protocol| protocol.to_vec())
+        }
+    }
+
+    /// Shared by every handle on the connection
+    struct Shared {
+        session: Mutex<Session>,
+        /// Non-blocking once the handshake is done, so that a handle waits
+        /// for the socket without holding the session
+        socket: TcpStream,
+        read_timeout: Mutex<Option<Duration>>,
+        write_timeout: Mutex<Option<Duration>>,
+        nonblocking: AtomicBool,
+        read_closed: AtomicBool,
+        write_closed: AtomicBool,
+    }
+
+    /// MQTT over TLS, the records written to and read from a TCP
+    /// connection. Handles may read and write from different threads
+    pub struct TlsTransport(Arc<Shared>);
+
+    impl TlsTransport {
+        /// Connect to `host:port` and complete the handshake, the broker is
+        /// authenticated as `config` says
+        pub fn open(
+            host: &str,
+            port: u16,
+            config: &TlsConfig,
+            timeout: Duration,
+        ) -> io::Result<Self> {
+            let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+            let mut last_err =
+                io::Error::new(io::ErrorKind::NotFound, "no TLS broker address found");
+            let mut socket = None;
+            for addr in addrs {
+                match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(stream) => {
+                        socket = Some(stream);
+                        break;
+                    }
+                    Err(e) => last_err = e,
+                }
+            }
+            let mut socket = socket.ok_or(last_err)?;
+            socket.set_nodelay(true)?;
+            socket.set_read_timeout(Some(timeout))?;
+            socket.set_write_timeout(Some(timeout))?;
+            let session = match &config.psk {
+                None => Session::Rustls(Box::new(handshake(host, config, &mut socket)?)),
+                #[cfg(feature = "psk")]
+                Some(key) => {
+                    Session::OpenSsl(psk::handshake(host, config, key, socket.try_clone()?)?)
+                }
+                #[cfg(not(feature = "psk"))]
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "TLS-PSK needs sake built with the psk feature",
+                    ))
+                }
+            };
+            socket.set_nonblocking(true)?;
+            Ok(Self(Arc::new(Shared {
+                session: Mutex::new(session),
+                socket,
+                read_timeout: Mutex::new(None),
+                write_timeout: Mutex::new(None),
+                nonblocking: AtomicBool::new(false),
+                read_closed: AtomicBool::new(false),
+                write_closed: AtomicBool::new(false),
+            })))
+        }
+
+        /// The protocol agreed through ALPN, if the broker picked one
+        pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+            self.0.session.lock().unwrap().alpn_protocol()
+        }
+
+        /// Wait for the socket, fails with `WouldBlock` past `deadline` or
+        /// right away in non-blocking mode
+        fn wait(&self, read: bool, deadline: Option<Instant>) -> io::Result<()> {
+            if self.0.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let wait = match deadline {
+                None => POLL,
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => left.min(POLL),
+                    _ => return Err(io::ErrorKind::WouldBlock.into()),
+                },
+            };
+            sockopt::wait(&self.0.socket, read, !read, wait)
+        }
+
+        /// Send the records the session holds, waiting for the socket as
+        /// long as the write timeout allows
+        fn write_records(&self) -> io::Result<()> {
+            let timeout = *self.0.write_timeout.lock().unwrap();
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            loop {
+                match self.0.session.lock().unwrap().flush(&self.0.socket) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                // What's left goes out with the next write or flush
+                if self.0.nonblocking.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                self.wait(false, deadline)?;
+            }
+        }
+    }
+
+    /// Certificates authenticate the broker
+    fn handshake(
+        host: &str,
+        config: &TlsConfig,
+        socket: &mut TcpStream,
+    ) -> io::Result<ClientConnection> {
+        let name = config.server_name.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(name.to_string()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid TLS server name {}", name),
+            )
+        })?;
+        let mut connection = ClientConnection::new(Arc::new(client_config(config)?), server_name)
+            .map_err(io::Error::other)?;
+        while connection.is_handshaking() {
+            connection.complete_io(socket)?;
+        }
+        Ok(connection)
+    }
+
+    fn client_config(config: &TlsConfig) -> io::Result<ClientConfig> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+        let mut tls = match config.insecure {
+            true => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+                .with_no_client_auth(),
+            false => builder
+                .with_root_certificates(root_certificates(config)?)
+                .with_no_client_auth(),
+        };
+        tls.alpn_protocols = config
+            .alpn
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        Ok(tls)
+    }
+
+    fn root_certificates(config: &TlsConfig) -> io::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        let Some(path) = &config.ca else {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            return Ok(roots);
+        };
+        for cert in CertificateDer::pem_file_iter(path).map_err(invalid_data)? {
+            roots
+                .add(cert.map_err(invalid_data)?)
+                .map_err(invalid_data)?;
+        }
+        if roots.is_empty() {
+            return Err(invalid_data(format!(
+                "No certificate in {}",
+                path.display()
+            )));
+        }
+        Ok(roots)
+    }
+
+    /// Accepts any certificate, still checking that the broker holds its
+    /// key
+    #[derive(Debug)]
+    struct NoVerification(Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for NoVerification {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            crypto::verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            crypto::verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    #[cfg(feature = "psk")]
+    mod psk {
+        use super::super::{PreSharedKey, TlsConfig};
+        use openssl::error::ErrorStack;
+        use openssl::ssl::{HandshakeError, SslConnector, SslMethod, SslStream, SslVerifyMode};
+        use std::io;
+        use std::net::TcpStream;
+
+        /// Up to TLS 1.2 the key is mixed with an ephemeral exchange when
+        /// the broker allows, TLS 1.3 takes it with its own suites
+        const CIPHERS: &str = "ECDHE-PSK:DHE-PSK:PSK";
+
+        /// The key authenticates both ends, no certificate is involved
+        pub fn handshake(
+            host: &str,
+            config: &TlsConfig,
+            psk: &PreSharedKey,
+            socket: TcpStream,
+        ) -> io::Result<SslStream<TcpStream>> {
+            let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+            builder.set_cipher_list(CIPHERS)?;
+            builder.set_verify(SslVerifyMode::NONE);
+            if !config.alpn.is_empty() {
+                builder.set_alpn_protos(&alpn_protos(&config.alpn)?)?;
+            }
+            let (identity, key) = (psk.identity.clone(), psk.key.clone());
+            builder.set_psk_client_callback(move |_, _hint, identity_out, key_out| {
+                // PreSharedKey bounds both below what OpenSSL has room for
+                if identity.len() >= identity_out.len() || key.len() > key_out.len() {
+                    return Err(ErrorStack::get());
+                }
+                identity_out[..identity.len()].copy_from_slice(identity.as_bytes());
+                identity_out[identity.len()] = 0;
+                key_out[..key.len()].copy_from_slice(&key);
+                Ok(key.len())
+            });
+            let name = config.server_name.as_deref().unwrap_or(host);
+            builder
+                .build()
+                .configure()?
+                .verify_hostname(false)
+                .connect(name, socket)
+                .map_err(|e| match e {
+                    HandshakeError::SetupFailure(e) => e.into(),
+                    HandshakeError::Failure(e) => io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("TLS-PSK handshake failed: {}", e.error()),
+                    ),
+                    HandshakeError::WouldBlock(_) => {
+                        io::Error::new(io::ErrorKind::TimedOut, "TLS-PSK handshake timed out")
+                    }
+                })
+        }
+
+        /// `protocols` in the wire format of ALPN, each after its length
+        fn alpn_protos(protocols: &[String]) -> io::Result<Vec<u8>> {
+            let mut wire = vec![];
+            for protocol in protocols {
+                let len = u8::try_from(protocol.len()).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("ALPN protocol {} is too long", protocol),
+                    )
+                })?;
+                wire.push(len);
+                wire.extend_from_slice(protocol.as_bytes());
+            }
+            Ok(wire)
+        }
+    }
+
+    impl Read for TlsTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let timeout = *self.0.read_timeout.lock().unwrap();
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            loop {
+                if self.0.read_closed.load(Ordering::Relaxed) {
+                    return Ok(0);
+                }
+                match self.0.session.lock().unwrap().read(&self.0.socket, buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                self.wait(true, deadline)?;
+            }
+        }
+    }
+
+    impl Write for TlsTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let timeout = *self.0.write_timeout.lock().unwrap();
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            loop {
+                if self.0.write_closed.load(Ordering::Relaxed) {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                match self.0.session.lock().unwrap().write(&self.0.socket, buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                self.wait(false, deadline)?;
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.write_records()
+        }
+    }
+
+    impl Transport for TlsTransport {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(Self(Arc::clone(&self.0))))
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            *self.0.read_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn read_timeout(&self) -> io::Result<Option<Duration>> {
+            Ok(*self.0.read_timeout.lock().unwrap())
+        }
+
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            *self.0.write_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.0.nonblocking.store(nonblocking, Ordering::Relaxed);
+            Ok(())
+        }
+
+        /// Handles blocked on the connection notice within `POLL`. The
+        /// broker is told with a close_notify alert before the EOF
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            if matches!(how, Shutdown::Read | Shutdown::Both) {
+                self.0.read_closed.store(true, Ordering::Relaxed);
+            }
+            if matches!(how, Shutdown::Write | Shutdown::Both)
+                && !self.0.write_closed.swap(true, Ordering::Relaxed)
+            {
+                self.0.session.lock().unwrap().send_close_notify();
+                let _ = self.write_records();
+                self.0.socket.shutdown(Shutdown::Write)?;
+            }
+            Ok(())
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.0.socket.peer_addr()
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) mod server {
+        use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use rustls::{ServerConfig, ServerConnection, StreamOwned};
+        use std::io::{self, Read, Write};
+        use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+
+        /// A TLS front for the TCP broker at `broker`, relaying each
+        /// connection to a TCP connection of its own. It offers the `alpn`
+        /// protocols and returns its address and the PEM of its self-signed
+        /// certificate for localhost
+        pub fn relay(broker: SocketAddr, alpn: &[&str]) -> io::Result<(SocketAddr, String)> {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+                .map_err(io::Error::other)?;
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let mut tls = ServerConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .map_err(io::Error::other)?
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.cert.der().clone()], key)
+                .map_err(io::Error::other)?;
+            tls.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+            let tls = Arc::new(tls);
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            thread::spawn(move || {
+                for socket in listener.incoming().flatten() {
+                    let Ok(connection) = ServerConnection::new(Arc::clone(&tls)) else {
+                        continue;
+                    };
+                    let Ok(socket_clone) = socket.try_clone() else {
+                        continue;
+                    };
+                    let stream = StreamOwned::new(connection, socket);
+                    splice(stream, socket_clone, broker, |stream| {
+                        stream.conn.send_close_notify();
+                        let _ = stream.flush();
+                    });
+                }
+            });
+            Ok((addr, cert.cert.pem()))
+        }
+
+        /// A TLS-PSK front for the TCP broker at `broker`, knowing only
+        /// `identity` and its `key`
+        #[cfg(feature = "psk")]
+        pub fn psk_relay(broker: SocketAddr, identity: &str, key: &[u8]) -> io::Result<SocketAddr> {
+            use openssl::ssl::{Ssl, SslContext, SslMethod};
+            let mut context = SslContext::builder(SslMethod::tls_server())?;
+            context.set_cipher_list("ECDHE-PSK:DHE-PSK:PSK")?;
+            let (identity, key) = (identity.as_bytes().to_vec(), key.to_vec());
+            context.set_psk_server_callback(move |_, client, key_out| {
+                match client == Some(&identity[..]) {
+                    true => {
+                        key_out[..key.len()].copy_from_slice(&key);
+                        Ok(key.len())
+                    }
+                    // Unknown identities fail the handshake
+                    false => Ok(0),
+                }
+            });
+            let context = context.build();
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            thread::spawn(move || {
+                for socket in listener.incoming().flatten() {
+                    let Ok(socket_clone) = socket.try_clone() else {
+                        continue;
+                    };
+                    let Ok(Ok(stream)) = Ssl::new(&context).map(|ssl| ssl.accept(socket)) else {
+                        continue;
+                    };
+                    splice(stream, socket_clone, broker, |stream| {
+                        let _ = stream.shutdown();
+                    });
+                }
+            });
+            Ok(addr)
+        }
+
+        /// Relay the plaintext of `stream`, over `socket`, to a connection
+        /// of its own to `broker`. `close` tells the client it's over
+        fn splice<S: Read + Write + Send + 'static>(
+            stream: S,
+            socket: TcpStream,
+            broker: SocketAddr,
+            close: fn(&mut S),
+        ) {
+            let Ok(mut upstream) = TcpStream::connect(broker) else {
+                return;
+            };
+            let mut downstream = upstream.try_clone().unwrap();
+            let stream = Arc::new(Mutex::new(stream));
+            let reader = Arc::clone(&stream);
+            socket
+                .set_read_timeout(Some(std::time::Duration::from_millis(20)))
+                .unwrap();
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+                loop {
+                    let read = reader.lock().unwrap().read(&mut buf);
+                    match read {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            if upstream.write_all(&buf[..n]).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e)
+                            if matches!(
+                                e.kind(),
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                            ) =>
+                        {
+                            thread::yield_now()
+                        }
+                        Err(_) => break,
+                    }
+                }
+                let _ = upstream.shutdown(Shutdown::Write);
+            });
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+                while let Ok(n @ 1..) = downstream.read(&mut buf) {
+                    let mut stream = stream.lock().unwrap();
+                    if stream.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+                close(&mut stream.lock().unwrap());
+                let _ = socket.shutdown(Shutdown::Write);
+            });
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tls_tests {
+    use super::stream::server::relay;
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+    use crate::mqtt::{Protocol, Qos, Response, Transport};
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn broker() -> io::Result<SocketAddr> {
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let addr = broker.local_addr()?;
+        std::thread::spawn(move || broker.run());
+        Ok(addr)
+    }
+
+    fn round_trip(transport: TlsTransport, topic: &str) -> io::Result<()> {
+        let mut client = Protocol::builder()
+            .client_id(topic)
+            .connect_transport(transport)?;
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        client.subscribe(topic, Qos::AtLeastOnce)?;
+        assert!(matches!(client.next_message()?, Response::Suback { .. }));
+        let packet_id = client.publish(topic, b"over tls")?;
+        let (mut received, mut acked) = (false, false);
+        while !(received && acked) {
+            match client.next_message()? {
+                Response::Publish { payload, .. } => {
+                    assert_eq!(payload, b"over tls");
+                    received = true;
+                }
+                Response::Puback { packet_id: id } => {
+                    assert_eq!(id, packet_id);
+                    acked = true;
+                }
+                response => panic!("Unexpected response {}", response),
+            }
+        }
+        // Without data the read times out as a socket would
+        client.set_read_timeout(Some(Duration::from_millis(50)))?;
+        assert_eq!(
+            client.next_message().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        client.disconnect()
+    }
+
+    #[test]
+    fn test_tls_transport() -> io::Result<()> {
+        let (addr, pem) = relay(broker()?, &["mqtt"])?;
+        let ca = std::env::temp_dir().join(format!("sake-tls-{}.pem", std::process::id()));
+        std::fs::write(&ca, pem)?;
+        // Reached by address, verified as localhost
+        let config = TlsConfig {
+            ca: Some(ca.clone()),
+            server_name: Some("localhost".to_string()),
+            alpn: vec!["x-other".to_string(), "mqtt".to_string()],
+            insecure: false,
+            psk: None,
+        };
+        let transport =
+            TlsTransport::open("127.0.0.1", addr.port(), &config, Duration::from_secs(5));
+        std::fs::remove_file(&ca)?;
+        let transport = transport?;
+        assert_eq!(transport.alpn_protocol().as_deref(), Some(&b"mqtt"[..]));
+        assert_eq!(transport.peer_addr()?, addr);
+        round_trip(transport, "tls")
+    }
+
+    #[test]
+    fn test_untrusted_certificate() -> io::Result<()> {
+        let (addr, _pem) = relay(broker()?, &[])?;
+        // The self-signed certificate doesn't chain to the web PKI roots
+        let config = TlsConfig::default();
+        let timeout = Duration::from_secs(5);
+        assert!(TlsTransport::open("localhost", addr.port(), &config, timeout).is_err());
+        let insecure = TlsConfig {
+            insecure: true,
+            ..TlsConfig::default()
+        };
+        let transport = TlsTransport::open("localhost", addr.port(), &insecure, timeout)?;
+        assert_eq!(transport.alpn_protocol(), None);
+        round_trip(transport, "insecure")
+    }
+
+    #[test]
+    fn test_pre_shared_key() {
+        let psk = PreSharedKey::from_hex("sensor", "00ff1A").unwrap();
+        assert_eq!(psk.key, vec![0x00, 0xff, 0x1a]);
+        assert!(!format!("{:?}", psk).contains("255"));
+        for (identity, key) in [
+            ("sensor", ""),
+            ("sensor", "abc"),
+            ("sensor", "zz"),
+            ("", "00"),
+        ] {
+            let err = PreSharedKey::from_hex(identity, key).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(PreSharedKey::from_hex("sensor", &"00".repeat(65)).is_err());
+    }
+
+    #[cfg(feature = "psk")]
+    #[test]
+    fn test_psk_transport() -> io::Result<()> {
+        let key = [0x5a; 32];
+        let addr = super::stream::server::psk_relay(broker()?, "sensor", &key)?;
+        let timeout = Duration::from_secs(5);
+        let config = |hex: &str| TlsConfig {
+            psk: Some(PreSharedKey::from_hex("sensor", hex).unwrap()),
+            ..TlsConfig::default()
+        };
+        // No certificate to check, the key is the only credential
+        let wrong = config(&"a5".repeat(32));
+        assert!(TlsTransport::open("127.0.0.1", addr.port(), &wrong, timeout).is_err());
+        let transport =
+            TlsTransport::open("127.0.0.1", addr.port(), &config(&"5a".repeat(32)), timeout)?;
+        round_trip(transport, "psk")
+    }
+
+    #[cfg(not(feature = "psk"))]
+    #[test]
+    fn test_psk_unsupported() -> io::Result<()> {
+        let (addr, _pem) = relay(broker()?, &[])?;
+        let config = TlsConfig {
+            psk: Some(PreSharedKey::from_hex("sensor", "00")?),
+            ..TlsConfig::default()
+        };
+        let timeout = Duration::from_secs(5);
+        let err = TlsTransport::open("127.0.0.1", addr.port(), &config, timeout)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is not supported by this transport", what),
+    )
+}
+
+/// A byte stream MQTT packets are exchanged over, see
+/// `Protocol::with_transport`. Only `try_clone` is required: transports
+/// without timeouts block in reads, and without non-blocking mode fail
+/// `try_read_message` with `Unsupported`
+pub trait Transport: Read + Write + Send {
+    /// Another handle on the same stream, for the other half of the
+    /// connection. Bytes written by either handle go out in order and each
+    /// byte read is seen by only one of them, as with `TcpStream::try_clone`
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Bound the time a read blocks, shared by every handle of the stream
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    /// Bound the time a write blocks, shared by every handle of the stream
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Make reads return `WouldBlock` instead of waiting for data
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        Err(unsupported("Non-blocking mode"))
+    }
+
+    /// Close one or both directions of the stream, unblocking the handles
+    /// waiting on it
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Address of the other end, for transports reaching a network peer
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(unsupported("Peer address"))
+    }
+
+    /// The socket under the transport, for socket options only TCP has
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_tcp() {
+            Some(stream) => stream.fmt(f),
+            None => f.write_str("Transport"),
+        }
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Self::set_read_timeout(self, timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Self::read_timeout(self)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Self::set_write_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        Self::set_nonblocking(self, nonblocking)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        Self::shutdown(self, how)
+    }
+}
+
+/// `Send`, except in the browser where there is a single thread and the
+/// JavaScript objects behind a transport can't leave it
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSend: Send {}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSend {}
+
+#[cfg(target_arch = "wasm32")]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// Counterpart of `Transport` for streams driven by an async runtime, with
+/// the shape of the `AsyncRead` and `AsyncWrite` traits of the runtimes so
+/// that adapting their streams takes a few lines
+pub trait AsyncTransport: MaybeSend + Unpin {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>>;
+
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>>;
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+
+    /// Flush and close the writing direction
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+    use crate::mqtt::{Protocol, Qos, Response};
+
+    /// A channel sake knows nothing about, implementing only what
+    /// `Transport` requires
+    struct Tunnel(TcpStream);
+
+    impl Read for Tunnel {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Tunnel {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Transport for Tunnel {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(Tunnel(self.0.try_clone()?)))
+        }
+    }
+
+    #[test]
+    fn test_user_transport() -> io::Result<()> {
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let addr = broker.local_addr()?;
+        std::thread::spawn(move || broker.run());
+        let tunnel = Tunnel(TcpStream::connect(addr)?);
+        let mut client = Protocol::builder()
+            .client_id("tunnelled")
+            .connect_transport(tunnel)?;
+        assert_eq!(
+            client.peer_addr().unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+        assert_eq!(
+            client.recv_buffer_size().unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+        client.subscribe("tunnel", Qos::AtMostOnce)?;
+        assert!(matches!(client.next_message()?, Response::Suback { .. }));
+        let packet_id = client.publish("tunnel", b"hello")?;
+        let (mut received, mut acked) = (false, false);
+        while !(received && acked) {
+            match client.next_message()? {
+                Response::Publish { topic, payload, .. } => {
+                    assert_eq!(topic, "tunnel");
+                    assert_eq!(payload, b"hello");
+                    received = true;
+                }
+                Response::Puback { packet_id: id } => {
+                    assert_eq!(id, packet_id);
+                    acked = true;
+                }
+                response => panic!("Unexpected response {}", response),
+            }
+        }
+        client.disconnect()
+    }
+}
@@ -0,0 +1,620 @@
+use crate::mqtt::properties::{properties_len, read_properties, write_properties};
+use crate::mqtt::{
+    checked_qos, encode_qos, protocol, validate_topic_filter, validate_topic_name, Deserialize,
+    FixedHeader, PacketType, Property, Qos, Response, Serialize, SubscriptionTopic, Will,
+};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+pub const MQTT_V5: u8 = 0x05;
+
+/// CONNECT reason code of MQTT 5 brokers refusing the protocol level
+pub const UNSUPPORTED_PROTOCOL_VERSION: u8 = 0x84;
+
+/// MQTT 5 CONNECT, which unlike the 3.1.1 one carries properties
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectV5 {
+    pub client_id: String,
+    pub clean_start: bool,
+    pub keepalive: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub will: Option<Will>,
+    pub properties: Vec<Property>,
+}
+
+impl Serialize for ConnectV5 {
+    fn validate(&self) -> io::Result<()> {
+        match &self.will {
+            Some(will) => Ok(validate_topic_name(&will.topic)?),
+            None => Ok(()),
+        }
+    }
+
+    fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+        let len = 10
+            + properties_len(&self.properties)
+            + 2
+            + self.client_id.len()
+            + self
+                .will
+                .as_ref()
+                .map_or(0, |w| properties_len(&w.properties()) + w.len())
+            + self.username.as_ref().map_or(0, |u| 2 + u.len())
+            + self.password.as_ref().map_or(0, |p| 2 + p.len());
+        buf.write_u8(0x10)?;
+        let header_len = 1 + protocol::write_remaining_length(buf, len)?;
+        protocol::write_string(buf, "MQTT")?;
+        buf.write_u8(MQTT_V5)?;
+        let mut flags = 0;
+        if self.clean_start {
+            flags |= 0x02;
+        }
+        if let Some(will) = &self.will {
+            flags |= 0x04 | u8::from(&will.qos) << 3 | (will.retain as u8) << 5;
+        }
+        if self.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.password.is_some() {
+            flags |= 0x40;
+        }
+        buf.write_u8(flags)?;
+        buf.write_u16::<NetworkEndian>(self.keepalive)?;
+        write_properties(buf, &self.properties)?;
+        protocol::write_string(buf, &self.client_id)?;
+        if let Some(will) = &self.will {
+            write_properties(buf, &will.properties())?;
+            protocol::write_string(buf, &will.topic)?;
+            buf.write_u16::<NetworkEndian>(will.message.len() as u16)?;
+            buf.write_all(&will.message)?;
+        }
+        for field in [&self.username, &self.password].into_iter().flatten() {
+            protocol::write_string(buf, field)?;
+        }
+        Ok(header_len + len)
+    }
+}
+
+impl Deserialize for ConnectV5 {
+    type Output = Self;
+
+    fn deserialize(buf: &mut impl Read) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+        let fixed_header = FixedHeader::from_bytes(buf)?;
+        if fixed_header.packet_type != PacketType::Connect {
+            return Err(invalid(&format!(
+                "Expected CONNECT, received {}",
+                fixed_header
+            )));
+        }
+        if protocol::read_string(buf)? != "MQTT" || buf.read_u8()? != MQTT_V5 {
+            return Err(invalid("Expected an MQTT 5 CONNECT"));
+        }
+        let flags = buf.read_u8()?;
+        let keepalive = buf.read_u16::<NetworkEndian>()?;
+        let properties = read_properties(buf)?;
+        let client_id = protocol::read_string(buf)?;
+        let will = match flags & 0x04 {
+            0 => None,
+            _ => {
+                let will_properties = read_properties(buf)?;
+                let topic = protocol::read_string(buf)?;
+                let mut message = vec![0; buf.read_u16::<NetworkEndian>()? as usize];
+                buf.read_exact(&mut message)?;
+                let qos = match (flags >> 3) & 0x03 {
+                    3 => return Err(invalid("Invalid will QoS 3")),
+                    qos => Qos::from(qos),
+                };
+                let mut will = Will::new(&topic, &message, qos, flags & 0x20 != 0);
+                will.set_properties(will_properties);
+                Some(will)
+            }
+        };
+        let username = match flags & 0x80 {
+            0 => None,
+            _ => Some(protocol::read_string(buf)?),
+        };
+        let password = match flags & 0x40 {
+            0 => None,
+            _ => Some(protocol::read_string(buf)?),
+        };
+        Ok(Self {
+            client_id,
+            clean_start: flags & 0x02 != 0,
+            keepalive,
+            username,
+            password,
+            will,
+            properties,
+        })
+    }
+}
+
+/// MQTT 5 CONNACK. Brokers that don't speak MQTT 5 answer with a 3.1.1
+/// CONNACK, decoded with its return code as reason code and no properties
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnackV5 {
+    pub session_present: bool,
+    pub reason_code: u8,
+    pub properties: Vec<Property>,
+}
+
+impl Deserialize for ConnackV5 {
+    type Output = Self;
+
+    fn deserialize(buf: &mut impl Read) -> io::Result<Self> {
+        let fixed_header = FixedHeader::from_bytes(buf)?;
+        if fixed_header.packet_type != PacketType::Connack {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected CONNACK, received {}", fixed_header),
+            ));
+        }
+        let session_present = buf.read_u8()? & 0x01 != 0;
+        let reason_code = buf.read_u8()?;
+        let properties = match fixed_header.remaining_length {
+            2 => vec![],
+            _ => read_properties(buf)?,
+        };
+        Ok(Self {
+            session_present,
+            reason_code,
+            properties,
+        })
+    }
+}
+
+/// MQTT 5 PUBLISH, which carries properties between the packet id and the
+/// payload, as the Response Topic and Correlation Data of requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishV5 {
+    pub packet_id: u16,
+    pub qos: u8,
+    pub dup: bool,
+    pub retain: bool,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub properties: Vec<Property>,
+}
+
+impl Serialize for PublishV5 {
+    fn validate(&self) -> io::Result<()> {
+        // A Topic Alias stands for the topic name, which may then be empty
+        let aliased = self
+            .properties
+            .iter()
+            .any(|p| matches!(p, Property::TopicAlias(_)));
+        if !(aliased && self.topic.is_empty()) {
+            validate_topic_name(&self.topic)?;
+        }
+        Ok(())
+    }
+
+    fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+        let len = 2
+            + self.topic.len()
+            + if self.qos > 0 { 2 } else { 0 }
+            + properties_len(&self.properties)
+            + self.payload.len();
+        let byte =
+            encode_qos(0x30, checked_qos(self.qos)?) | (self.dup as u8) << 3 | self.retain as u8;
+        buf.write_u8(byte)?;
+        let header_len = 1 + protocol::write_remaining_length(buf, len)?;
+        protocol::write_string(buf, &self.topic)?;
+        if self.qos > 0 {
+            buf.write_u16::<NetworkEndian>(self.packet_id)?;
+        }
+        write_properties(buf, &self.properties)?;
+        buf.write_all(&self.payload)?;
+        Ok(header_len + len)
+    }
+}
+
+/// MQTT 5 SUBSCRIBE, a property block after the packet id and a byte of
+/// subscription options per filter of which only the QoS is set
+#[derive(Debug, Clone)]
+pub struct SubscribeV5 {
+    pub packet_id: u16,
+    pub subscription_topics: Vec<SubscriptionTopic>,
+    pub properties: Vec<Property>,
+}
+
+impl Serialize for SubscribeV5 {
+    fn validate(&self) -> io::Result<()> {
+        for s in &self.subscription_topics {
+            validate_topic_filter(&s.topic)?;
+        }
+        Ok(())
+    }
+
+    fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+        let len = 2
+            + properties_len(&self.properties)
+            + self
+                .subscription_topics
+                .iter()
+                .map(|s| 2 + s.topic.len() + 1)
+                .sum::<usize>();
+        buf.write_u8(0x82)?;
+        let header_len = 1 + protocol::write_remaining_length(buf, len)?;
+        buf.write_u16::<NetworkEndian>(self.packet_id)?;
+        write_properties(buf, &self.properties)?;
+        for s in &self.subscription_topics {
+            protocol::write_string(buf, &s.topic)?;
+            buf.write_u8(u8::from(&s.qos))?;
+        }
+        Ok(header_len + len)
+    }
+}
+
+/// Decodes the packets of an MQTT 5 broker into `Response`. PUBLISH keeps
+/// its properties, SUBACK carries reason codes rather than return codes,
+/// any of them from 0x80 being a failure, and the reason codes and
+/// properties of acknowledgements are skipped
+#[derive(Debug)]
+pub struct ResponseV5;
+
+impl Deserialize for ResponseV5 {
+    type Output = Response;
+
+    fn deserialize(buf: &mut impl Read) -> io::Result<Response> {
+        let opcode = buf.read_u8()?;
+        let len = protocol::read_remaining_length(buf)?;
+        let fixed_header = FixedHeader::new(opcode, len);
+        let mut body = vec![0; len as usize];
+        buf.read_exact(&mut body)?;
+        let mut body = body.as_slice();
+        let packet = match fixed_header.packet_type {
+            PacketType::Connack => {
+                let session_present = body.read_u8()? & 0x01 != 0;
+                let return_code = body.read_u8()?;
+                Response::Connack {
+                    session_present,
+                    return_code,
+                }
+            }
+            PacketType::Publish => {
+                let qos = fixed_header.flags.qos;
+                let topic = protocol::read_string(&mut body)?;
+                let packet_id = match qos {
+                    0 => 0,
+                    _ => body.read_u16::<NetworkEndian>()?,
+                };
+                let properties = read_properties(&mut body)?;
+                Response::Publish {
+                    packet_id,
+                    qos,
+                    retain: fixed_header.flags.retain,
+                    topic,
+                    payload: body.to_vec(),
+                    properties,
+                }
+            }
+            PacketType::Puback => Response::Puback {
+                packet_id: body.read_u16::<NetworkEndian>()?,
+            },
+            PacketType::Pubrec => Response::Pubrec {
+                packet_id: body.read_u16::<NetworkEndian>()?,
+            },
+            PacketType::Pubrel => Response::Pubrel {
+                packet_id: body.read_u16::<NetworkEndian>()?,
+            },
+            PacketType::Pubcomp => Response::Pubcomp {
+                packet_id: body.read_u16::<NetworkEndian>()?,
+            },
+            PacketType::Suback => {
+                let packet_id = body.read_u16::<NetworkEndian>()?;
+                read_properties(&mut body)?;
+                Response::Suback {
+                    packet_id,
+                    return_codes: body.to_vec(),
+                }
+            }
+            PacketType::Pingresp => Response::Pingresp,
+            PacketType::Disconnect => {
+                // Both the reason code and the properties may be left out
+                let reason_code = body.read_u8().unwrap_or(0);
+                let properties = match body.is_empty() {
+                    true => vec![],
+                    false => read_properties(&mut body)?,
+                };
+                Response::Disconnect {
+                    reason_code,
+                    properties,
+                }
+            }
+            _ => Response::Unknown {
+                packet_type: opcode >> 4,
+                body: body.to_vec(),
+            },
+        };
+        Ok(packet)
+    }
+}
+
+/// What a broker supports, from the properties of its MQTT 5 CONNACK and
+/// the defaults of the spec for those it leaves out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// 5, or 4 for brokers only speaking MQTT 3.1.1 which advertise
+    /// nothing, every other field is then left to its default
+    pub protocol_level: u8,
+    pub maximum_qos: Qos,
+    pub retain_available: bool,
+    /// Largest packet the broker accepts, `None` if only the protocol limits it
+    pub maximum_packet_size: Option<u32>,
+    pub topic_alias_maximum: u16,
+    pub receive_maximum: u16,
+    /// Keepalive the broker imposes instead of the one requested
+    pub server_keep_alive: Option<u16>,
+    /// Client id chosen by the broker when connecting with an empty one
+    pub assigned_client_id: Option<String>,
+    pub session_expiry_interval: Option<u32>,
+    pub wildcard_subscriptions: bool,
+    pub subscription_identifiers: bool,
+    pub shared_subscriptions: bool,
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl Capabilities {
+    pub fn from_properties(protocol_level: u8, properties: &[Property]) -> Self {
+        let mut capabilities = Self {
+            protocol_level,
+            maximum_qos: Qos::ExactlyOnce,
+            retain_available: true,
+            maximum_packet_size: None,
+            topic_alias_maximum: 0,
+            receive_maximum: u16::MAX,
+            server_keep_alive: None,
+            assigned_client_id: None,
+            session_expiry_interval: None,
+            wildcard_subscriptions: true,
+            subscription_identifiers: true,
+            shared_subscriptions: true,
+            user_properties: vec![],
+        };
+        for property in properties {
+            match property {
+                // Only 0 or 1 get past the decoder
+                Property::MaximumQos(qos) if *qos <= 2 => {
+                    capabilities.maximum_qos = Qos::from(*qos)
+                }
+                Property::RetainAvailable(flag) => capabilities.retain_available = *flag,
+                Property::MaximumPacketSize(size) => capabilities.maximum_packet_size = Some(*size),
+                Property::TopicAliasMaximum(max) => capabilities.topic_alias_maximum = *max,
+                Property::ReceiveMaximum(max) => capabilities.receive_maximum = *max,
+                Property::ServerKeepAlive(secs) => capabilities.server_keep_alive = Some(*secs),
+                Property::AssignedClientIdentifier(id) => {
+                    capabilities.assigned_client_id = Some(id.clone())
+                }
+                Property::SessionExpiryInterval(secs) => {
+                    capabilities.session_expiry_interval = Some(*secs)
+                }
+                Property::WildcardSubscriptionAvailable(flag) => {
+                    capabilities.wildcard_subscriptions = *flag
+                }
+                Property::SubscriptionIdentifierAvailable(flag) => {
+                    capabilities.subscription_identifiers = *flag
+                }
+                Property::SharedSubscriptionAvailable(flag) => {
+                    capabilities.shared_subscriptions = *flag
+                }
+                Property::UserProperty(key, value) => {
+                    capabilities
+                        .user_properties
+                        .push((key.clone(), value.clone()));
+                }
+                _ => {}
+            }
+        }
+        capabilities
+    }
+
+    /// Keepalive to ping the broker by, the Server Keep Alive when the
+    /// broker imposes one rather than the `requested` one
+    pub fn keepalive(&self, requested: u16) -> Duration {
+        Duration::from_secs(self.server_keep_alive.unwrap_or(requested) as u64)
+    }
+}
+
+#[cfg(test)]
+mod v5_tests {
+    use super::*;
+
+    #[test]
+    fn test_connect() -> io::Result<()> {
+        let connect = ConnectV5 {
+            client_id: "id".to_string(),
+            clean_start: true,
+            keepalive: 30,
+            username: Some("u".to_string()),
+            password: None,
+            will: None,
+            properties: vec![Property::SessionExpiryInterval(60)],
+        };
+        let bytes = connect.to_bytes()?;
+        assert_eq!(
+            bytes,
+            [
+                0x10, 23, 0, 4, b'M', b'Q', b'T', b'T', 5, 0x82, 0, 30, 5, 0x11, 0, 0, 0, 60, 0, 2,
+                b'i', b'd', 0, 1, b'u'
+            ]
+        );
+        assert_eq!(connect.serialize(&mut vec![])?, bytes.len());
+        assert_eq!(ConnectV5::from_slice(&bytes)?, connect);
+
+        let connect = ConnectV5 {
+            will: Some(Will::new("w", b"bye", Qos::AtLeastOnce, true)),
+            username: None,
+            properties: vec![],
+            ..connect
+        };
+        let bytes = connect.to_bytes()?;
+        assert_eq!(bytes[1] as usize, bytes.len() - 2);
+        assert_eq!(bytes[9], 0x2E);
+        assert_eq!(&bytes[17..], [0, 0, 1, b'w', 0, 3, b'b', b'y', b'e']);
+        assert_eq!(ConnectV5::from_slice(&bytes)?, connect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_will_properties() -> io::Result<()> {
+        let will = Will {
+            delay_interval: Some(30),
+            content_type: Some("t".to_string()),
+            user_properties: vec![("k".to_string(), "v".to_string())],
+            ..Will::new("w", b"bye", Qos::ExactlyOnce, false)
+        };
+        let connect = ConnectV5 {
+            client_id: "id".to_string(),
+            clean_start: false,
+            keepalive: 0,
+            username: None,
+            password: Some("p".to_string()),
+            will: Some(will),
+            properties: vec![],
+        };
+        let bytes = connect.to_bytes()?;
+        assert_eq!(bytes[1] as usize, bytes.len() - 2);
+        assert_eq!(bytes[9], 0x54);
+        assert_eq!(
+            &bytes[17..],
+            [
+                16, 0x18, 0, 0, 0, 30, 0x03, 0, 1, b't', 0x26, 0, 1, b'k', 0, 1, b'v', 0, 1, b'w',
+                0, 3, b'b', b'y', b'e', 0, 1, b'p'
+            ]
+        );
+        assert_eq!(ConnectV5::from_slice(&bytes)?, connect);
+
+        // Only the will properties are kept
+        let mut will = Will::new("w", b"", Qos::AtMostOnce, false);
+        will.set_properties(vec![
+            Property::WillDelayInterval(5),
+            Property::MessageExpiryInterval(60),
+        ]);
+        assert_eq!(will.properties(), [Property::WillDelayInterval(5)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_publish() -> io::Result<()> {
+        let publish = PublishV5 {
+            packet_id: 7,
+            qos: 1,
+            dup: false,
+            retain: true,
+            topic: "rpc".to_string(),
+            payload: b"hi".to_vec(),
+            properties: vec![
+                Property::ResponseTopic("re".to_string()),
+                Property::CorrelationData(vec![1]),
+            ],
+        };
+        let bytes = publish.to_bytes()?;
+        assert_eq!(
+            bytes,
+            [
+                0x33, 19, 0, 3, b'r', b'p', b'c', 0, 7, 9, 0x08, 0, 2, b'r', b'e', 0x09, 0, 1, 1,
+                b'h', b'i'
+            ]
+        );
+        assert_eq!(publish.serialize(&mut vec![])?, bytes.len());
+        match ResponseV5::from_slice(&bytes)? {
+            Response::Publish {
+                packet_id,
+                retain,
+                topic,
+                payload,
+                properties,
+                ..
+            } => {
+                assert_eq!((packet_id, retain), (7, true));
+                assert_eq!((topic.as_str(), payload.as_slice()), ("rpc", &b"hi"[..]));
+                assert_eq!(properties, publish.properties);
+            }
+            response => panic!("Unexpected response {}", response),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe() -> io::Result<()> {
+        let subscribe = SubscribeV5 {
+            packet_id: 1,
+            subscription_topics: vec![SubscriptionTopic {
+                qos: Qos::AtLeastOnce,
+                topic: "a".to_string(),
+            }],
+            properties: vec![],
+        };
+        assert_eq!(subscribe.to_bytes()?, [0x82, 7, 0, 1, 0, 0, 1, b'a', 1]);
+        // Reason string property before the reason codes
+        let suback = [0x90, 9, 0, 1, 4, 0x1F, 0, 1, b'x', 0x01, 0x87];
+        match ResponseV5::from_slice(&suback)? {
+            Response::Suback {
+                packet_id,
+                return_codes,
+            } => assert_eq!((packet_id, return_codes), (1, vec![0x01, 0x87])),
+            response => panic!("Unexpected response {}", response),
+        }
+        // Acknowledgements with a reason code and properties
+        assert!(matches!(
+            ResponseV5::from_slice(&[0x40, 4, 0, 9, 0x10, 0])?,
+            Response::Puback { packet_id: 9 }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_disconnect() -> io::Result<()> {
+        // Server Moved to broker2
+        match ResponseV5::from_slice(&[
+            0xE0, 12, 0x9D, 10, 0x1C, 0, 7, b'b', b'r', b'o', b'k', b'e', b'r', b'2',
+        ])? {
+            Response::Disconnect {
+                reason_code,
+                properties,
+            } => {
+                assert_eq!(reason_code, 0x9D);
+                assert_eq!(
+                    properties,
+                    [Property::ServerReference("broker2".to_string())]
+                );
+            }
+            response => panic!("Unexpected response {}", response),
+        }
+        assert!(matches!(
+            ResponseV5::from_slice(&[0xE0, 0])?,
+            Response::Disconnect { reason_code: 0, .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_connack() -> io::Result<()> {
+        let connack = ConnackV5::from_slice(&[0x20, 8, 1, 0, 5, 0x24, 1, 0x22, 0, 10])?;
+        assert_eq!(
+            connack,
+            ConnackV5 {
+                session_present: true,
+                reason_code: 0,
+                properties: vec![Property::MaximumQos(1), Property::TopicAliasMaximum(10)],
+            }
+        );
+        let capabilities = Capabilities::from_properties(MQTT_V5, &connack.properties);
+        assert_eq!(capabilities.maximum_qos, Qos::AtLeastOnce);
+        assert_eq!(capabilities.topic_alias_maximum, 10);
+        assert!(capabilities.retain_available);
+        assert_eq!(capabilities.keepalive(60), Duration::from_secs(60));
+        let capabilities = Capabilities::from_properties(MQTT_V5, &[Property::ServerKeepAlive(20)]);
+        assert_eq!(capabilities.keepalive(60), Duration::from_secs(20));
+
+        // A 3.1.1 broker refusing the protocol level
+        let connack = ConnackV5::from_slice(&[0x20, 2, 0, 1])?;
+        assert_eq!((connack.reason_code, connack.properties.len()), (1, 0));
+        assert!(ConnackV5::from_slice(&[0x90, 3, 0, 1, 0]).is_err());
+        Ok(())
+    }
+}
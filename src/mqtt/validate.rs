@@ -0,0 +1,180 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Longest string an MQTT packet can carry, in bytes
+const MAX_STRING_LEN: usize = u16::MAX as usize;
+
+/// A topic name or filter no broker has to accept, carried as the inner
+/// error of an `io::Error` of kind `InvalidInput`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidTopic {
+    /// `"topic name"` or `"topic filter"`
+    pub what: &'static str,
+    pub topic: String,
+    /// Character offset of the first offending character, from 0
+    pub position: usize,
+    pub reason: String,
+}
+
+impl InvalidTopic {
+    fn new(what: &'static str, topic: &str, position: usize, reason: impl Into<String>) -> Self {
+        Self {
+            what,
+            topic: topic.to_string(),
+            position,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for InvalidTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid {} {:?}: {} at character {}",
+            self.what, self.topic, self.reason, self.position
+        )
+    }
+}
+
+impl Error for InvalidTopic {}
+
+impl From<InvalidTopic> for io::Error {
+    fn from(err: InvalidTopic) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// First character an MQTT string must not carry, with its offset and why:
+/// U+0000, control characters and Unicode non-characters, or any character
+/// past the 65535 bytes a string can hold
+pub fn check_string(s: &str) -> Option<(usize, String)> {
+    for (position, (offset, c)) in s.char_indices().enumerate() {
+        if offset + c.len_utf8() > MAX_STRING_LEN {
+            return Some((
+                position,
+                format!("{} bytes, at most {} fit", s.len(), MAX_STRING_LEN),
+            ));
+        }
+        let code = c as u32;
+        if code == 0 {
+            return Some((position, "U+0000 is not allowed".to_string()));
+        }
+        if c.is_control() {
+            return Some((position, format!("control character U+{:04X}", code)));
+        }
+        if (0xFDD0..=0xFDEF).contains(&code) || code & 0xFFFE == 0xFFFE {
+            return Some((position, format!("non-character U+{:04X}", code)));
+        }
+    }
+    None
+}
+
+/// Reject topic names a PUBLISH can't carry: empty, holding a wildcard or
+/// characters `check_string` refuses
+pub fn validate_topic_name(topic: &str) -> Result<(), InvalidTopic> {
+    let invalid =
+        |position, reason: String| InvalidTopic::new("topic name", topic, position, reason);
+    if topic.is_empty() {
+        return Err(invalid(0, "empty topic".to_string()));
+    }
+    if let Some((position, reason)) = check_string(topic) {
+        return Err(invalid(position, reason));
+    }
+    match topic.chars().position(|c| c == '+' || c == '#') {
+        Some(position) => Err(invalid(position, "wildcard in a topic name".to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Reject subscription filters: empty, with characters `check_string`
+/// refuses, `+` not standing for a whole level or `#` not being the whole
+/// last level
+pub fn validate_topic_filter(filter: &str) -> Result<(), InvalidTopic> {
+    let invalid =
+        |position, reason: &str| InvalidTopic::new("topic filter", filter, position, reason);
+    if filter.is_empty() {
+        return Err(invalid(0, "empty filter"));
+    }
+    if let Some((position, reason)) = check_string(filter) {
+        return Err(InvalidTopic::new("topic filter", filter, position, reason));
+    }
+    let levels = filter.split('/').count();
+    let mut start = 0;
+    for (i, level) in filter.split('/').enumerate() {
+        if let Some(offset) = level.chars().position(|c| c == '+' || c == '#') {
+            let position = start + offset;
+            match level {
+                "+" => {}
+                "#" if i + 1 == levels => {}
+                "#" => return Err(invalid(position, "'#' must be the last level")),
+                _ => return Err(invalid(position, "wildcard not standing for a whole level")),
+            }
+        }
+        start += level.chars().count() + 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod validate_tests {
+    use super::*;
+
+    #[test]
+    fn test_check_string() {
+        assert_eq!(check_string("sensors/température"), None);
+        assert_eq!(
+            check_string("a\0b"),
+            Some((1, "U+0000 is not allowed".to_string()))
+        );
+        assert_eq!(
+            check_string("é\u{7F}"),
+            Some((1, "control character U+007F".to_string()))
+        );
+        assert_eq!(
+            check_string("a\u{FFFF}"),
+            Some((1, "non-character U+FFFF".to_string()))
+        );
+        assert_eq!(
+            check_string("\u{1FFFE}"),
+            Some((0, "non-character U+1FFFE".to_string()))
+        );
+        let long = "a".repeat(MAX_STRING_LEN + 1);
+        assert_eq!(check_string(&long).map(|(p, _)| p), Some(MAX_STRING_LEN));
+    }
+
+    #[test]
+    fn test_validate_topic_name() {
+        assert!(validate_topic_name("sensors/1/temp").is_ok());
+        assert!(validate_topic_name("/").is_ok());
+        let err = validate_topic_name("sensors/+/temp").unwrap_err();
+        assert_eq!(err.position, 8);
+        assert_eq!(
+            err.to_string(),
+            "Invalid topic name \"sensors/+/temp\": wildcard in a topic name at character 8"
+        );
+        assert_eq!(validate_topic_name("é/#").unwrap_err().position, 2);
+        assert_eq!(validate_topic_name("a\0").unwrap_err().position, 1);
+        assert!(validate_topic_name("").is_err());
+        let err: io::Error = validate_topic_name("").unwrap_err().into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_validate_topic_filter() {
+        for filter in ["#", "+", "a/+/c", "a/#", "+/+", "$share/g/a/#", "/"] {
+            assert!(validate_topic_filter(filter).is_ok(), "{}", filter);
+        }
+        let position = |filter| validate_topic_filter(filter).unwrap_err().position;
+        assert_eq!(position("a/#/c"), 2);
+        assert_eq!(position("a/b#"), 3);
+        assert_eq!(position("ü/x+/c"), 3);
+        assert_eq!(position("a/\u{1}"), 2);
+        assert_eq!(position(""), 0);
+        assert_eq!(
+            validate_topic_filter("a/#/c").unwrap_err().reason,
+            "'#' must be the last level"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::mqtt::TransportError;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+/// Encoded size limit of a variable byte integer
+const MAX_LEN: usize = 4;
+
+/// MQTT variable byte integer, the encoding of the remaining length:
+/// 7 bits per byte, least significant group first, with the high bit set on
+/// every byte but the last. At most 4 bytes are allowed, which caps the
+/// value to `VarInt::MAX`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VarInt(u32);
+
+impl VarInt {
+    pub const MAX: u32 = 268_435_455;
+
+    /// Fails with `PayloadTooLong` if `value` doesn't fit in 4 bytes
+    pub fn new(value: usize) -> Result<Self, TransportError> {
+        if value > Self::MAX as usize {
+            return Err(TransportError::PayloadTooLong);
+        }
+        Ok(Self(value as u32))
+    }
+
+    pub fn value(self) -> u32 {
+        self.0
+    }
+
+    /// Number of bytes of the encoding, 1 to 4
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(self) -> usize {
+        match self.0 {
+            0..=0x7F => 1,
+            0x80..=0x3FFF => 2,
+            0x4000..=0x1F_FFFF => 3,
+            _ => 4,
+        }
+    }
+
+    /// Decode from the start of `bytes`, returning the value and the number
+    /// of bytes it took, or `None` if `bytes` ends before the last byte
+    pub fn decode(bytes: &[u8]) -> Result<Option<(Self, usize)>, TransportError> {
+        let mut value = 0;
+        for (i, byte) in bytes.iter().take(MAX_LEN).enumerate() {
+            value |= ((byte & 0x7F) as u32) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(Some((Self(value), i + 1)));
+            }
+        }
+        if bytes.len() >= MAX_LEN {
+            return Err(TransportError::MalformedRemainingLength);
+        }
+        Ok(None)
+    }
+
+    /// Read from a stream, never consuming more than 4 bytes
+    pub fn read(buf: &mut impl Read) -> io::Result<Self> {
+        let mut value = 0;
+        for i in 0..MAX_LEN {
+            let byte = buf.read_u8()?;
+            value |= ((byte & 0x7F) as u32) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(Self(value));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            TransportError::MalformedRemainingLength,
+        ))
+    }
+
+    /// Write the encoding, returning the number of bytes written
+    pub fn write(self, buf: &mut impl Write) -> io::Result<usize> {
+        let mut value = self.0;
+        for i in 1..=MAX_LEN {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                buf.write_u8(byte)?;
+                return Ok(i);
+            }
+            buf.write_u8(byte | 0x80)?;
+        }
+        unreachable!("VarInt larger than {}", Self::MAX)
+    }
+}
+
+#[cfg(test)]
+mod varint_tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> io::Result<()> {
+        let cases: [(usize, &[u8]); 8] = [
+            (0, &[0x00]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xFF, 0x7F]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xFF, 0xFF, 0x7F]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+            (268_435_455, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ];
+        for (value, encoded) in cases {
+            let varint = VarInt::new(value).unwrap();
+            let mut buf = vec![];
+            assert_eq!(varint.write(&mut buf)?, encoded.len());
+            assert_eq!(buf, encoded);
+            assert_eq!(varint.len(), encoded.len());
+            assert_eq!(VarInt::read(&mut &buf[..])?, varint);
+            assert_eq!(VarInt::decode(encoded), Ok(Some((varint, encoded.len()))));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert_eq!(
+            VarInt::new(268_435_456),
+            Err(TransportError::PayloadTooLong)
+        );
+        let five = [0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+        let err = VarInt::read(&mut &five[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            VarInt::decode(&five),
+            Err(TransportError::MalformedRemainingLength)
+        );
+        // Truncated encodings need more bytes
+        assert_eq!(VarInt::decode(&[]), Ok(None));
+        assert_eq!(VarInt::decode(&[0x80, 0x80]), Ok(None));
+    }
+}
@@ -0,0 +1,290 @@
+use crate::mqtt::{ConnectReturnCode, FixedHeader, PacketType};
+use std::fmt;
+
+/// Hex bytes shown per field before eliding the rest
+const MAX_HEX_BYTES: usize = 8;
+
+/// Annotated hexdump of a raw packet, one line per field with its offset,
+/// bytes and meaning:
+///
+/// ```text
+/// 0000  30                         PUBLISH: d:false q:0 r:false
+/// 0001  05                         remaining length 5
+/// 0002  00 01                      topic length 1
+/// 0004  61                         topic "a"
+/// 0005  68 69                      payload 2 bytes "hi"
+/// ```
+pub struct WireDump<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> WireDump<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+/// A field spanning `len` bytes from `offset`
+struct Field {
+    offset: usize,
+    len: usize,
+    meaning: String,
+}
+
+/// Walks the packet bytes collecting the fields, stops quietly when the
+/// packet is truncated, the remaining bytes are reported as such
+struct Annotator<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    fields: Vec<Field>,
+}
+
+impl<'a> Annotator<'a> {
+    fn take(&mut self, len: usize, meaning: String) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos + len)?;
+        self.fields.push(Field {
+            offset: self.pos,
+            len,
+            meaning,
+        });
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn peek_u8(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn peek_u16(&self) -> Option<u16> {
+        let b = self.bytes.get(self.pos..self.pos + 2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u8(&mut self, name: &str) -> Option<u8> {
+        let value = self.peek_u8()?;
+        self.take(1, format!("{} {}", name, value))?;
+        Some(value)
+    }
+
+    fn u16(&mut self, name: &str) -> Option<u16> {
+        let value = self.peek_u16()?;
+        self.take(2, format!("{} {}", name, value))?;
+        Some(value)
+    }
+
+    /// Length prefixed string, `hide` masks secrets like passwords
+    fn string(&mut self, name: &str, hide: bool) -> Option<()> {
+        let len = self.u16(&format!("{} length", name))? as usize;
+        let value = self.bytes.get(self.pos..self.pos + len)?;
+        let shown = if hide {
+            "****".to_string()
+        } else {
+            format!("{:?}", String::from_utf8_lossy(value))
+        };
+        self.take(len, format!("{} {}", name, shown))?;
+        Some(())
+    }
+
+    fn remaining_length(&mut self) -> Option<usize> {
+        let (mut value, mut mul, mut len) = (0usize, 1usize, 0);
+        loop {
+            let byte = *self.bytes.get(self.pos + len)?;
+            value += (byte & 127) as usize * mul;
+            mul *= 128;
+            len += 1;
+            if byte & 128 == 0 || len == 4 {
+                break;
+            }
+        }
+        self.take(len, format!("remaining length {}", value))?;
+        Some(value)
+    }
+
+    fn packet(&mut self) -> Option<()> {
+        let byte = self.peek_u8()?;
+        let header = FixedHeader::new(byte, 0);
+        self.take(1, header.to_string())?;
+        let remaining_length = self.remaining_length()?;
+        let end = self.pos + remaining_length;
+        match header.packet_type {
+            PacketType::Connect => {
+                self.string("protocol name", false)?;
+                self.u8("protocol level")?;
+                let flags = self.peek_u8()?;
+                self.take(1, connect_flags(flags))?;
+                self.u16("keepalive")?;
+                self.string("client id", false)?;
+                if flags & 0x04 != 0 {
+                    self.string("will topic", false)?;
+                    self.string("will message", false)?;
+                }
+                if flags & 0x80 != 0 {
+                    self.string("username", false)?;
+                }
+                if flags & 0x40 != 0 {
+                    self.string("password", true)?;
+                }
+            }
+            PacketType::Connack => {
+                let flags = self.peek_u8()?;
+                self.take(1, format!("session present {}", flags & 0x01 != 0))?;
+                let code = self.peek_u8()?;
+                self.take(1, format!("return code {}", ConnectReturnCode::from(code)))?;
+            }
+            PacketType::Publish => {
+                self.string("topic", false)?;
+                if header.flags.qos > 0 {
+                    self.u16("packet id")?;
+                }
+                let payload = self.bytes.get(self.pos..end.min(self.bytes.len()))?;
+                if !payload.is_empty() {
+                    let meaning = format!(
+                        "payload {} bytes {:?}",
+                        payload.len(),
+                        String::from_utf8_lossy(payload)
+                    );
+                    self.take(payload.len(), meaning)?;
+                }
+            }
+            PacketType::Puback | PacketType::Pubrec | PacketType::Pubrel | PacketType::Pubcomp => {
+                self.u16("packet id")?;
+            }
+            PacketType::Subscribe => {
+                self.u16("packet id")?;
+                while self.pos < end {
+                    self.string("topic filter", false)?;
+                    self.u8("requested qos")?;
+                }
+            }
+            PacketType::Suback => {
+                self.u16("packet id")?;
+                while self.pos < end {
+                    let code = self.peek_u8()?;
+                    let meaning = match code {
+                        0x80 => "return code failure".to_string(),
+                        qos => format!("granted qos {}", qos),
+                    };
+                    self.take(1, meaning)?;
+                }
+            }
+            PacketType::Pingreq | PacketType::Pingresp | PacketType::Disconnect => {}
+            PacketType::Unknown => {
+                let len = end.min(self.bytes.len()) - self.pos;
+                if len > 0 {
+                    self.take(len, "unknown packet body".to_string())?;
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+fn connect_flags(flags: u8) -> String {
+    let mut names = vec![];
+    for (mask, name) in [
+        (0x80, "username"),
+        (0x40, "password"),
+        (0x20, "will retain"),
+        (0x04, "will"),
+        (0x02, "clean session"),
+    ] {
+        if flags & mask != 0 {
+            names.push(name.to_string());
+        }
+    }
+    if flags & 0x04 != 0 {
+        names.push(format!("will qos {}", (flags >> 3) & 0x03));
+    }
+    format!("connect flags [{}]", names.join(", "))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out: Vec<String> = bytes
+        .iter()
+        .take(MAX_HEX_BYTES)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if bytes.len() > MAX_HEX_BYTES {
+        out.push("..".to_string());
+    }
+    out.join(" ")
+}
+
+impl fmt::Display for WireDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut annotator = Annotator {
+            bytes: self.bytes,
+            pos: 0,
+            fields: vec![],
+        };
+        annotator.packet();
+        for field in &annotator.fields {
+            let bytes = &self.bytes[field.offset..field.offset + field.len];
+            writeln!(
+                f,
+                "{:04x}  {:<26} {}",
+                field.offset,
+                hex(bytes),
+                field.meaning
+            )?;
+        }
+        if annotator.pos < self.bytes.len() {
+            let rest = &self.bytes[annotator.pos..];
+            writeln!(
+                f,
+                "{:04x}  {:<26} {} bytes not decoded",
+                annotator.pos,
+                hex(rest),
+                rest.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod wire_tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_dump() {
+        let dump = WireDump::new(&[0x32, 7, 0, 1, b'a', 0, 7, b'h', b'i']).to_string();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            "0000  32                         PUBLISH: d:false q:1 r:false"
+        );
+        assert_eq!(lines[4], "0005  00 07                      packet id 7");
+        assert_eq!(
+            lines[5],
+            "0007  68 69                      payload 2 bytes \"hi\""
+        );
+    }
+
+    #[test]
+    fn test_truncated_dump() {
+        let dump = WireDump::new(&[0x40, 2, 0]).to_string();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines[1],
+            "0001  02                         remaining length 2"
+        );
+        assert_eq!(
+            lines[2],
+            "0002  00                         1 bytes not decoded"
+        );
+    }
+
+    #[test]
+    fn test_password_hidden() {
+        let mut bytes = vec![0x10, 0];
+        bytes.extend_from_slice(&[0, 4, b'M', b'Q', b'T', b'T', 4, 0xC2, 0, 60]);
+        bytes.extend_from_slice(&[0, 1, b'c', 0, 1, b'u', 0, 2, b'p', b'w']);
+        bytes[1] = bytes.len() as u8 - 2;
+        let dump = WireDump::new(&bytes).to_string();
+        assert!(dump.contains("connect flags [username, password, clean session]"));
+        assert!(dump.contains("password ****"));
+        assert!(!dump.contains("\"pw\""));
+    }
+}
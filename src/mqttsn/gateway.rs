@@ -0,0 +1,224 @@
+use crate::mqtt::{ConnectionRefused, Protocol, Request, Response};
+use crate::mqttsn::{
+    SnPacket, MAX_DATAGRAM_SIZE, RETURN_ACCEPTED, RETURN_CONGESTION, RETURN_INVALID_TOPIC_ID,
+    RETURN_NOT_SUPPORTED,
+};
+use crate::topic::{self, RewriteRule};
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// Topic id type for ids previously assigned through REGISTER
+const TOPIC_ID_NORMAL: u8 = 0x00;
+/// Topic id type for 2 characters topic names carried in the topic id field
+const TOPIC_ID_SHORT: u8 = 0x02;
+
+/// State kept for each MQTT-SN client, its broker connection and the topics
+/// it registered, topic ids are positions in `topics` starting from 1
+struct GatewayClient {
+    protocol: Protocol,
+    topics: Vec<String>,
+}
+
+impl GatewayClient {
+    fn register(&mut self, topic_name: &str) -> u16 {
+        match self.topics.iter().position(|t| t == topic_name) {
+            Some(i) => (i + 1) as u16,
+            None => {
+                self.topics.push(topic_name.to_string());
+                self.topics.len() as u16
+            }
+        }
+    }
+
+    fn topic(&self, topic_id_type: u8, topic_id: u16) -> Option<String> {
+        match topic_id_type {
+            TOPIC_ID_NORMAL => self
+                .topics
+                .get((topic_id as usize).checked_sub(1)?)
+                .cloned(),
+            TOPIC_ID_SHORT => String::from_utf8(topic_id.to_be_bytes().to_vec()).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Transparent MQTT-SN to MQTT gateway, every MQTT-SN client gets its own TCP
+/// connection to the broker, packets are translated one to one
+pub struct Gateway {
+    socket: UdpSocket,
+    broker: Vec<SocketAddr>,
+    timeout: Duration,
+    clients: HashMap<SocketAddr, GatewayClient>,
+    rewrites: Vec<RewriteRule>,
+    /// Log every packet received
+    verbose: bool,
+}
+
+impl Gateway {
+    pub fn bind(
+        listen: SocketAddr,
+        broker: Vec<SocketAddr>,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(listen)?,
+            broker,
+            timeout,
+            clients: HashMap::new(),
+            rewrites: vec![],
+            verbose: false,
+        })
+    }
+
+    /// Rewrite the topics of the messages forwarded to the broker, the first
+    /// matching rule applies
+    pub fn set_rewrites(&mut self, rewrites: Vec<RewriteRule>) {
+        self.rewrites = rewrites;
+    }
+
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Serve clients forever, errors tied to a single client are logged and
+    /// don't stop the gateway
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, peer) = self.socket.recv_from(&mut buf)?;
+            let reply =
+                SnPacket::from_bytes(&buf[..len]).and_then(|packet| self.handle(peer, packet));
+            let sent = match reply {
+                Ok(Some(reply)) => reply
+                    .to_bytes()
+                    .and_then(|bytes| self.socket.send_to(&bytes, peer))
+                    .map(drop),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                eprintln!("{}: {}", peer, e);
+                self.clients.remove(&peer);
+            }
+        }
+    }
+
+    fn handle(&mut self, peer: SocketAddr, packet: SnPacket) -> io::Result<Option<SnPacket>> {
+        if self.verbose {
+            eprintln!("{}: {}", peer, packet);
+        }
+        if let SnPacket::Connect {
+            flags, client_id, ..
+        } = packet
+        {
+            return Ok(Some(self.connect(peer, client_id, flags.clean_session)));
+        }
+        let client = match self.clients.get_mut(&peer) {
+            Some(client) => client,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("{} before CONNECT", packet),
+                ))
+            }
+        };
+        let reply = match packet {
+            SnPacket::Register {
+                msg_id, topic_name, ..
+            } => Some(SnPacket::Regack {
+                topic_id: client.register(&topic_name),
+                msg_id,
+                return_code: RETURN_ACCEPTED,
+            }),
+            SnPacket::Publish {
+                flags,
+                topic_id,
+                msg_id,
+                data,
+            } => {
+                let puback = |return_code| SnPacket::Puback {
+                    topic_id,
+                    msg_id,
+                    return_code,
+                };
+                match client.topic(flags.topic_id_type, topic_id) {
+                    None => Some(puback(RETURN_INVALID_TOPIC_ID)),
+                    Some(_) if flags.qos > 1 => Some(puback(RETURN_NOT_SUPPORTED)),
+                    Some(topic) => {
+                        client.protocol.send_message(&Request::Publish {
+                            packet_id: msg_id,
+                            qos: flags.qos,
+                            dup: false,
+                            retain: flags.retain,
+                            topic: topic::rewrite(&self.rewrites, &topic),
+                            payload: data,
+                        })?;
+                        if flags.qos == 0 {
+                            None
+                        } else {
+                            match client.protocol.read_message::<Response>()? {
+                                Response::Puback { .. } => Some(puback(RETURN_ACCEPTED)),
+                                _ => Some(puback(RETURN_CONGESTION)),
+                            }
+                        }
+                    }
+                }
+            }
+            SnPacket::Disconnect { .. } => {
+                client.protocol.disconnect()?;
+                self.clients.remove(&peer);
+                Some(SnPacket::Disconnect { duration: None })
+            }
+            packet => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected packet: {}", packet),
+                ))
+            }
+        };
+        Ok(reply)
+    }
+
+    fn connect(&mut self, peer: SocketAddr, client_id: String, clean_session: bool) -> SnPacket {
+        let connection = Protocol::builder()
+            .addrs(&self.broker)
+            .client_id(&client_id)
+            .clean_session(clean_session)
+            .timeout(self.timeout)
+            .connect()
+            .and_then(|mut protocol| {
+                protocol.set_read_timeout(Some(self.timeout))?;
+                Ok(protocol)
+            });
+        let return_code = match connection {
+            Ok(protocol) => {
+                self.clients.insert(
+                    peer,
+                    GatewayClient {
+                        protocol,
+                        topics: vec![],
+                    },
+                );
+                RETURN_ACCEPTED
+            }
+            Err(e)
+                if e.get_ref()
+                    .is_some_and(|inner| inner.is::<ConnectionRefused>()) =>
+            {
+                eprintln!("{}: {}", peer, e);
+                RETURN_NOT_SUPPORTED
+            }
+            Err(e) => {
+                eprintln!("{}: broker unreachable: {}", peer, e);
+                RETURN_CONGESTION
+            }
+        };
+        SnPacket::Connack { return_code }
+    }
+}
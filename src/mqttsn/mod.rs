@@ -0,0 +1,512 @@
+mod gateway;
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use core::fmt::{self, Display, Formatter};
+pub use gateway::Gateway;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// MQTT-SN v1.2 protocol identifier carried in CONNECT
+const PROTOCOL_ID: u8 = 0x01;
+/// Largest datagram accepted, the length field allows up to 65535 bytes
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+pub const RETURN_ACCEPTED: u8 = 0x00;
+pub const RETURN_CONGESTION: u8 = 0x01;
+pub const RETURN_INVALID_TOPIC_ID: u8 = 0x02;
+pub const RETURN_NOT_SUPPORTED: u8 = 0x03;
+
+#[repr(u8)]
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum SnPacketType {
+    Connect = 0x04,
+    Connack = 0x05,
+    Register = 0x0A,
+    Regack = 0x0B,
+    Publish = 0x0C,
+    Puback = 0x0D,
+    Disconnect = 0x18,
+}
+
+impl TryFrom<u8> for SnPacketType {
+    type Error = io::Error;
+
+    fn try_from(orig: u8) -> io::Result<Self> {
+        match orig {
+            0x04 => Ok(SnPacketType::Connect),
+            0x05 => Ok(SnPacketType::Connack),
+            0x0A => Ok(SnPacketType::Register),
+            0x0B => Ok(SnPacketType::Regack),
+            0x0C => Ok(SnPacketType::Publish),
+            0x0D => Ok(SnPacketType::Puback),
+            0x18 => Ok(SnPacketType::Disconnect),
+            n => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported MQTT-SN message type: {:#04x}", n),
+            )),
+        }
+    }
+}
+
+/// MQTT-SN flags byte, shared by CONNECT and PUBLISH
+///
+/// |   Bit    |  7  |  6  |  5  |   4    |  3   |   2   |  1  |  0  |
+/// |----------|-----|-----------|--------|------|-------|-----------|
+/// | Flags    | dup |    QoS    | retain | will | clean | topic id  |
+/// |          |     |           |        |      | sess. |   type    |
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SnFlags {
+    pub dup: bool,
+    pub qos: u8,
+    pub retain: bool,
+    pub will: bool,
+    pub clean_session: bool,
+    pub topic_id_type: u8,
+}
+
+impl SnFlags {
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            dup: byte & 0x80 != 0,
+            qos: (byte >> 5) & 0x03,
+            retain: byte & 0x10 != 0,
+            will: byte & 0x08 != 0,
+            clean_session: byte & 0x04 != 0,
+            topic_id_type: byte & 0x03,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        (self.dup as u8) << 7
+            | (self.qos & 0x03) << 5
+            | (self.retain as u8) << 4
+            | (self.will as u8) << 3
+            | (self.clean_session as u8) << 2
+            | (self.topic_id_type & 0x03)
+    }
+}
+
+/// The subset of MQTT-SN messages needed to connect, register topics and
+/// publish to them
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnPacket {
+    Connect {
+        flags: SnFlags,
+        duration: u16,
+        client_id: String,
+    },
+    Connack {
+        return_code: u8,
+    },
+    Register {
+        topic_id: u16,
+        msg_id: u16,
+        topic_name: String,
+    },
+    Regack {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    Publish {
+        flags: SnFlags,
+        topic_id: u16,
+        msg_id: u16,
+        data: Vec<u8>,
+    },
+    Puback {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    Disconnect {
+        duration: Option<u16>,
+    },
+}
+
+impl Display for SnPacket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SnPacket::Connect { client_id, .. } => write!(f, "SN CONNECT {}", client_id),
+            SnPacket::Connack { return_code } => write!(f, "SN CONNACK {}", return_code),
+            SnPacket::Register {
+                topic_id,
+                topic_name,
+                ..
+            } => write!(f, "SN REGISTER {} {}", topic_id, topic_name),
+            SnPacket::Regack {
+                topic_id,
+                return_code,
+                ..
+            } => write!(f, "SN REGACK {} {}", topic_id, return_code),
+            SnPacket::Publish {
+                topic_id, msg_id, ..
+            } => write!(f, "SN PUBLISH {} {}", topic_id, msg_id),
+            SnPacket::Puback {
+                topic_id,
+                msg_id,
+                return_code,
+            } => write!(f, "SN PUBACK {} {} {}", topic_id, msg_id, return_code),
+            SnPacket::Disconnect { .. } => write!(f, "SN DISCONNECT"),
+        }
+    }
+}
+
+impl SnPacket {
+    fn packet_type(&self) -> SnPacketType {
+        match self {
+            SnPacket::Connect { .. } => SnPacketType::Connect,
+            SnPacket::Connack { .. } => SnPacketType::Connack,
+            SnPacket::Register { .. } => SnPacketType::Register,
+            SnPacket::Regack { .. } => SnPacketType::Regack,
+            SnPacket::Publish { .. } => SnPacketType::Publish,
+            SnPacket::Puback { .. } => SnPacketType::Puback,
+            SnPacket::Disconnect { .. } => SnPacketType::Disconnect,
+        }
+    }
+
+    /// Serializes the packet, the length header is 1 byte long up to 255
+    /// bytes, otherwise 0x01 followed by the length on 2 bytes
+    pub fn write(&self, buf: &mut impl Write) -> io::Result<()> {
+        let mut body = vec![];
+        match self {
+            SnPacket::Connect {
+                flags,
+                duration,
+                client_id,
+            } => {
+                body.write_u8(flags.to_byte())?;
+                body.write_u8(PROTOCOL_ID)?;
+                body.write_u16::<NetworkEndian>(*duration)?;
+                body.write_all(client_id.as_bytes())?;
+            }
+            SnPacket::Connack { return_code } => body.write_u8(*return_code)?,
+            SnPacket::Register {
+                topic_id,
+                msg_id,
+                topic_name,
+            } => {
+                body.write_u16::<NetworkEndian>(*topic_id)?;
+                body.write_u16::<NetworkEndian>(*msg_id)?;
+                body.write_all(topic_name.as_bytes())?;
+            }
+            SnPacket::Regack {
+                topic_id,
+                msg_id,
+                return_code,
+            }
+            | SnPacket::Puback {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                body.write_u16::<NetworkEndian>(*topic_id)?;
+                body.write_u16::<NetworkEndian>(*msg_id)?;
+                body.write_u8(*return_code)?;
+            }
+            SnPacket::Publish {
+                flags,
+                topic_id,
+                msg_id,
+                data,
+            } => {
+                body.write_u8(flags.to_byte())?;
+                body.write_u16::<NetworkEndian>(*topic_id)?;
+                body.write_u16::<NetworkEndian>(*msg_id)?;
+                body.write_all(data)?;
+            }
+            SnPacket::Disconnect { duration } => {
+                if let Some(duration) = duration {
+                    body.write_u16::<NetworkEndian>(*duration)?;
+                }
+            }
+        }
+        // Length includes itself and the message type byte
+        let len = body.len() + 2;
+        if len <= 255 {
+            buf.write_u8(len as u8)?;
+        } else if len + 2 <= MAX_DATAGRAM_SIZE {
+            buf.write_u8(0x01)?;
+            buf.write_u16::<NetworkEndian>((len + 2) as u16)?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "MQTT-SN packet too long",
+            ));
+        }
+        buf.write_u8(self.packet_type() as u8)?;
+        buf.write_all(&body)
+    }
+
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.write(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Parses a whole datagram into a packet
+    pub fn from_bytes(datagram: &[u8]) -> io::Result<Self> {
+        let mut buf = datagram;
+        let (len, header_len) = match buf.read_u8()? {
+            0x01 => (buf.read_u16::<NetworkEndian>()? as usize, 3),
+            n => (n as usize, 1),
+        };
+        if len != datagram.len() || len < header_len + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "MQTT-SN length mismatch: header {} datagram {}",
+                    len,
+                    datagram.len()
+                ),
+            ));
+        }
+        let packet_type = SnPacketType::try_from(buf.read_u8()?)?;
+        let packet = match packet_type {
+            SnPacketType::Connect => {
+                let flags = SnFlags::from_byte(buf.read_u8()?);
+                let _protocol_id = buf.read_u8()?;
+                let duration = buf.read_u16::<NetworkEndian>()?;
+                SnPacket::Connect {
+                    flags,
+                    duration,
+                    client_id: read_utf8(&mut buf)?,
+                }
+            }
+            SnPacketType::Connack => SnPacket::Connack {
+                return_code: buf.read_u8()?,
+            },
+            SnPacketType::Register => SnPacket::Register {
+                topic_id: buf.read_u16::<NetworkEndian>()?,
+                msg_id: buf.read_u16::<NetworkEndian>()?,
+                topic_name: read_utf8(&mut buf)?,
+            },
+            SnPacketType::Regack => SnPacket::Regack {
+                topic_id: buf.read_u16::<NetworkEndian>()?,
+                msg_id: buf.read_u16::<NetworkEndian>()?,
+                return_code: buf.read_u8()?,
+            },
+            SnPacketType::Publish => SnPacket::Publish {
+                flags: SnFlags::from_byte(buf.read_u8()?),
+                topic_id: buf.read_u16::<NetworkEndian>()?,
+                msg_id: buf.read_u16::<NetworkEndian>()?,
+                data: buf.to_vec(),
+            },
+            SnPacketType::Puback => SnPacket::Puback {
+                topic_id: buf.read_u16::<NetworkEndian>()?,
+                msg_id: buf.read_u16::<NetworkEndian>()?,
+                return_code: buf.read_u8()?,
+            },
+            SnPacketType::Disconnect => SnPacket::Disconnect {
+                duration: buf.read_u16::<NetworkEndian>().ok(),
+            },
+        };
+        Ok(packet)
+    }
+}
+
+/// Reads the rest of the datagram as an UTF-8 string
+fn read_utf8(buf: &mut impl Read) -> io::Result<String> {
+    let mut bytes = vec![];
+    buf.read_to_end(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"))
+}
+
+fn check_return_code(what: &str, return_code: u8) -> io::Result<()> {
+    match return_code {
+        RETURN_ACCEPTED => Ok(()),
+        code => Err(io::Error::other(format!(
+            "{} rejected, return code {}",
+            what, code
+        ))),
+    }
+}
+
+/// Minimal MQTT-SN client, talks to a gateway over UDP
+pub struct SnClient {
+    socket: UdpSocket,
+    next_msg_id: u16,
+}
+
+impl SnClient {
+    /// Bind a local UDP socket and associate it to the gateway address
+    pub fn connect(gateway: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        let bind_addr: SocketAddr = if gateway.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.set_read_timeout(Some(timeout))?;
+        socket.connect(gateway)?;
+        Ok(Self {
+            socket,
+            next_msg_id: 1,
+        })
+    }
+
+    fn msg_id(&mut self) -> u16 {
+        let id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    pub fn send(&self, packet: &SnPacket) -> io::Result<()> {
+        self.socket.send(&packet.to_bytes()?)?;
+        Ok(())
+    }
+
+    pub fn recv(&self) -> io::Result<SnPacket> {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let len = self.socket.recv(&mut buf)?;
+        SnPacket::from_bytes(&buf[..len])
+    }
+
+    /// Sends CONNECT and waits for the CONNACK
+    pub fn handshake(
+        &mut self,
+        client_id: &str,
+        clean_session: bool,
+        duration: u16,
+    ) -> io::Result<()> {
+        self.send(&SnPacket::Connect {
+            flags: SnFlags {
+                clean_session,
+                ..SnFlags::default()
+            },
+            duration,
+            client_id: client_id.to_string(),
+        })?;
+        match self.recv()? {
+            SnPacket::Connack { return_code } => check_return_code("CONNECT", return_code),
+            packet => Err(unexpected(&packet)),
+        }
+    }
+
+    /// Registers a topic name, returning the topic id assigned by the gateway
+    pub fn register(&mut self, topic_name: &str) -> io::Result<u16> {
+        let msg_id = self.msg_id();
+        self.send(&SnPacket::Register {
+            topic_id: 0,
+            msg_id,
+            topic_name: topic_name.to_string(),
+        })?;
+        match self.recv()? {
+            SnPacket::Regack {
+                topic_id,
+                msg_id: ack_id,
+                return_code,
+            } if ack_id == msg_id => {
+                check_return_code("REGISTER", return_code)?;
+                Ok(topic_id)
+            }
+            packet => Err(unexpected(&packet)),
+        }
+    }
+
+    /// Publishes to a registered topic id, waiting for the PUBACK on QoS 1
+    pub fn publish(&mut self, topic_id: u16, data: &[u8], qos: u8) -> io::Result<()> {
+        let msg_id = if qos > 0 { self.msg_id() } else { 0 };
+        self.send(&SnPacket::Publish {
+            flags: SnFlags {
+                qos,
+                ..SnFlags::default()
+            },
+            topic_id,
+            msg_id,
+            data: data.to_vec(),
+        })?;
+        if qos == 0 {
+            return Ok(());
+        }
+        match self.recv()? {
+            SnPacket::Puback {
+                msg_id: ack_id,
+                return_code,
+                ..
+            } if ack_id == msg_id => check_return_code("PUBLISH", return_code),
+            packet => Err(unexpected(&packet)),
+        }
+    }
+
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        self.send(&SnPacket::Disconnect { duration: None })
+    }
+}
+
+fn unexpected(packet: &SnPacket) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unexpected packet: {}", packet),
+    )
+}
+
+#[cfg(test)]
+mod mqttsn_tests {
+    use super::*;
+
+    #[test]
+    fn test_flags() {
+        let flags = SnFlags {
+            qos: 1,
+            clean_session: true,
+            topic_id_type: 2,
+            ..SnFlags::default()
+        };
+        assert_eq!(flags.to_byte(), 0x26);
+        assert_eq!(SnFlags::from_byte(0x26), flags);
+    }
+
+    #[test]
+    fn test_connect_write() -> io::Result<()> {
+        let connect = SnPacket::Connect {
+            flags: SnFlags {
+                clean_session: true,
+                ..SnFlags::default()
+            },
+            duration: 60,
+            client_id: "sn".into(),
+        };
+        assert_eq!(
+            connect.to_bytes()?,
+            &[8, 0x04, 0x04, 0x01, 0, 60, b's', b'n']
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_publish_roundtrip() -> io::Result<()> {
+        let publish = SnPacket::Publish {
+            flags: SnFlags {
+                qos: 1,
+                ..SnFlags::default()
+            },
+            topic_id: 3,
+            msg_id: 7,
+            data: b"21.5".to_vec(),
+        };
+        let bytes = publish.to_bytes()?;
+        assert_eq!(bytes, &[11, 0x0C, 0x20, 0, 3, 0, 7, b'2', b'1', b'.', b'5']);
+        assert_eq!(SnPacket::from_bytes(&bytes)?, publish);
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_length_header() -> io::Result<()> {
+        let publish = SnPacket::Publish {
+            flags: SnFlags::default(),
+            topic_id: 1,
+            msg_id: 0,
+            data: vec![0xAB; 300],
+        };
+        let bytes = publish.to_bytes()?;
+        assert_eq!(&bytes[..4], &[0x01, 0x01, 0x35, 0x0C]);
+        assert_eq!(SnPacket::from_bytes(&bytes)?, publish);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_bytes_length_mismatch() {
+        assert!(SnPacket::from_bytes(&[5, 0x05, 0]).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::io;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Characters of the payload shown in a notification
+const PREVIEW_LEN: usize = 120;
+
+/// How notifications are raised
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    /// `notify-send TITLE BODY`, from libnotify on Linux and BSDs
+    NotifySend,
+    /// `display notification` through `osascript` on macOS
+    Osascript,
+    /// Any program taking the title and body as arguments, like notify-send
+    Command(String),
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        if cfg!(target_os = "macos") {
+            Backend::Osascript
+        } else {
+            Backend::NotifySend
+        }
+    }
+}
+
+/// Quote a string for AppleScript
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Backend {
+    fn command(&self, title: &str, body: &str) -> Command {
+        let (program, args) = match self {
+            Backend::NotifySend => ("notify-send", vec![title.to_string(), body.to_string()]),
+            Backend::Osascript => (
+                "osascript",
+                vec![
+                    "-e".to_string(),
+                    format!(
+                        "display notification {} with title {}",
+                        applescript_string(body),
+                        applescript_string(title)
+                    ),
+                ],
+            ),
+            Backend::Command(program) => {
+                (program.as_str(), vec![title.to_string(), body.to_string()])
+            }
+        };
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        command
+    }
+}
+
+/// The payload on a single line, cut to `max` characters
+pub fn preview(payload: &[u8], max: usize) -> String {
+    let text = String::from_utf8_lossy(payload);
+    let mut line: String = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if let Some((cut, _)) = line.char_indices().nth(max) {
+        line.truncate(cut);
+        line.push('…');
+    }
+    line
+}
+
+/// Raises a desktop notification per message, at most one per `interval`.
+/// Messages arriving in between are counted and mentioned by the next one,
+/// so that a burst doesn't flood the desktop
+#[derive(Debug)]
+pub struct Notifier {
+    backend: Backend,
+    interval: Duration,
+    last: Option<Instant>,
+    skipped: u64,
+    /// Notification processes not reaped yet, they exit on their own
+    children: Vec<Child>,
+}
+
+impl Notifier {
+    pub fn new(backend: Backend, interval: Duration) -> Self {
+        Self {
+            backend,
+            interval,
+            last: None,
+            skipped: 0,
+            children: vec![],
+        }
+    }
+
+    /// Notify about a message unless one was raised less than `interval`
+    /// ago, returning whether it was. Fails if the notification program
+    /// can't be started
+    pub fn notify(&mut self, topic: &str, payload: &[u8], now: Instant) -> io::Result<bool> {
+        self.children
+            .retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+        if self
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) < self.interval)
+        {
+            self.skipped += 1;
+            return Ok(false);
+        }
+        let mut body = preview(payload, PREVIEW_LEN);
+        if self.skipped > 0 {
+            body.push_str(&format!(" (+{} more)", self.skipped));
+        }
+        let mut command = self.backend.command(topic, &body);
+        let child = command.spawn().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Cannot run {}: {}",
+                    command.get_program().to_string_lossy(),
+                    e
+                ),
+            )
+        })?;
+        self.children.push(child);
+        self.last = Some(now);
+        self.skipped = 0;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod notify_tests {
+    use super::*;
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview(b"{\n  \"on\": true\n}", 20), "{ \"on\": true }");
+        assert_eq!(preview("héllo wörld".as_bytes(), 5), "héllo…");
+        assert_eq!(preview(&[0xFF, b'a'], 5), "\u{FFFD}a");
+    }
+
+    #[test]
+    fn test_rate_limit() -> io::Result<()> {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut notifier = Notifier::new(
+            Backend::Command("true".to_string()),
+            Duration::from_secs(10),
+        );
+        assert!(notifier.notify("a", b"1", at(0))?);
+        assert!(!notifier.notify("a", b"2", at(3))?);
+        assert!(!notifier.notify("b", b"3", at(9))?);
+        assert_eq!(notifier.skipped, 2);
+        assert!(notifier.notify("a", b"4", at(10))?);
+        assert_eq!(notifier.skipped, 0);
+
+        let mut missing = Notifier::new(
+            Backend::Command("sake-no-such-notifier".to_string()),
+            Duration::ZERO,
+        );
+        assert!(missing.notify("a", b"1", at(0)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_osascript_quoting() {
+        let command = Backend::Osascript.command("a/\"b\"", "x\\y");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args[1],
+            "display notification \"x\\\\y\" with title \"a/\\\"b\\\"\""
+        );
+    }
+}
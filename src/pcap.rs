@@ -0,0 +1,885 @@
+use crate::csv;
+use crate::mqtt::{VarInt, PACKET_NAMES};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+const PCAP_MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+const PCAPNG_SECTION: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER: u32 = 0x1A2B_3C4D;
+const PCAPNG_INTERFACE: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+/// Option of an interface description block giving its timestamp units
+const IF_TSRESOL: u16 = 9;
+
+const LINKTYPE_NULL: u16 = 0;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_LINUX_SLL: u16 = 113;
+const LINKTYPE_IPV4: u16 = 228;
+const LINKTYPE_IPV6: u16 = 229;
+const LINKTYPE_LINUX_SLL2: u16 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88A8;
+const IPPROTO_TCP: u8 = 6;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+
+/// Segments kept per direction while waiting for a missing one, a capture
+/// that dropped it would otherwise grow them forever
+const MAX_OUT_OF_ORDER: usize = 1024;
+
+/// An MQTT packet carved out of a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Capture time of the segment completing the packet, since the Unix
+    /// epoch
+    pub timestamp: Duration,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// Whole packet, fixed header included
+    pub bytes: Vec<u8>,
+}
+
+/// Header of `summary_csv`
+pub const SUMMARY_HEADER: &str =
+    "timestamp,src,dst,direction,type,flags,packet_id,topic,payload_size";
+
+impl Frame {
+    /// The packet after its fixed header
+    fn body(&self) -> &[u8] {
+        VarInt::decode(&self.bytes[1..])
+            .ok()
+            .flatten()
+            .map_or(&[][..], |(_, size)| &self.bytes[1 + size..])
+    }
+
+    /// One CSV row, as Wireshark's packet list: capture time, endpoints,
+    /// direction relative to the broker on `port`, packet type and fixed
+    /// header flags, then the packet id, topic and payload size of the
+    /// packets that have them
+    pub fn summary(&self, port: u16) -> String {
+        let kind = self.bytes[0] >> 4;
+        let flags = self.bytes[0] & 0x0F;
+        let body = self.body();
+        let u16_at = |offset: usize| u16_at(body, offset, false);
+        let (mut packet_id, mut topic, mut payload_size) = (None, None, None);
+        match kind {
+            3 => {
+                let len = u16_at(0).unwrap_or_default() as usize;
+                topic = body.get(2..2 + len).map(String::from_utf8_lossy);
+                let qos = (flags >> 1) & 0x03;
+                let id_len = if qos > 0 { 2 } else { 0 };
+                if qos > 0 {
+                    packet_id = u16_at(2 + len);
+                }
+                payload_size = Some(body.len().saturating_sub(2 + len + id_len));
+            }
+            4..=11 => packet_id = u16_at(0),
+            _ => {}
+        }
+        let direction = match self.dst.port() == port {
+            true => "to_broker",
+            false => "to_client",
+        };
+        let optional = |v: Option<String>| v.unwrap_or_default();
+        format!(
+            "{:.6},{},{},{},{},0x{:x},{},{},{}",
+            self.timestamp.as_secs_f64(),
+            self.src,
+            self.dst,
+            direction,
+            PACKET_NAMES[kind as usize],
+            flags,
+            optional(packet_id.map(|id| id.to_string())),
+            optional(topic.map(|t| csv::field(&t))),
+            optional(payload_size.map(|s| s.to_string()))
+        )
+    }
+}
+
+/// `SUMMARY_HEADER` and a row per frame, see `Frame::summary`
+pub fn summary_csv(frames: &[Frame], port: u16) -> String {
+    let mut out = format!("{}\n", SUMMARY_HEADER);
+    for frame in frames {
+        out.push_str(&frame.summary(port));
+        out.push('\n');
+    }
+    out
+}
+
+/// Traffic of one client connection of a capture, see `profile`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientProfile {
+    /// Endpoint of the client, the side not on the broker port
+    pub client: SocketAddr,
+    /// From the CONNECT, if the capture has it
+    pub client_id: Option<String>,
+    /// Keepalive asked for in the CONNECT, in seconds
+    pub keepalive: Option<u16>,
+    /// Packets the client sent, indexed by packet type
+    pub sent: [u64; 16],
+    /// Packets the broker sent to the client, indexed by packet type
+    pub received: [u64; 16],
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub payload_sent: u64,
+    pub payload_received: u64,
+    pub first_seen: Duration,
+    pub last_seen: Duration,
+    /// Sum and count of the intervals between the PINGREQs of the client
+    ping_intervals: (Duration, u32),
+    last_ping: Option<Duration>,
+}
+
+impl ClientProfile {
+    fn new(client: SocketAddr, timestamp: Duration) -> Self {
+        Self {
+            client,
+            client_id: None,
+            keepalive: None,
+            sent: [0; 16],
+            received: [0; 16],
+            bytes_sent: 0,
+            bytes_received: 0,
+            payload_sent: 0,
+            payload_received: 0,
+            first_seen: timestamp,
+            last_seen: timestamp,
+            ping_intervals: (Duration::ZERO, 0),
+            last_ping: None,
+        }
+    }
+
+    /// Average interval between PINGREQs, `None` below two of them
+    pub fn ping_interval(&self) -> Option<Duration> {
+        let (total, count) = self.ping_intervals;
+        (count > 0).then(|| total / count)
+    }
+
+    /// Average PUBLISH payload size, both directions together
+    pub fn average_publish_size(&self) -> Option<f64> {
+        let publishes = self.sent[3] + self.received[3];
+        (publishes > 0)
+            .then(|| (self.payload_sent + self.payload_received) as f64 / publishes as f64)
+    }
+
+    fn observe(&mut self, frame: &Frame, to_broker: bool) {
+        let kind = (frame.bytes[0] >> 4) as usize;
+        let body = frame.body();
+        let payload = match kind {
+            3 => {
+                let topic_len = u16_at(body, 0, false).unwrap_or_default() as usize;
+                let id_len = if (frame.bytes[0] >> 1) & 0x03 > 0 {
+                    2
+                } else {
+                    0
+                };
+                body.len().saturating_sub(2 + topic_len + id_len) as u64
+            }
+            _ => 0,
+        };
+        self.last_seen = frame.timestamp;
+        if !to_broker {
+            self.received[kind] += 1;
+            self.bytes_received += frame.bytes.len() as u64;
+            self.payload_received += payload;
+            return;
+        }
+        self.sent[kind] += 1;
+        self.bytes_sent += frame.bytes.len() as u64;
+        self.payload_sent += payload;
+        match kind {
+            1 => self.connect(body),
+            12 => {
+                if let Some(last) = self.last_ping.replace(frame.timestamp) {
+                    self.ping_intervals.0 += frame.timestamp.saturating_sub(last);
+                    self.ping_intervals.1 += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Keepalive and client id of a CONNECT body, 3.1.1 or 5
+    fn connect(&mut self, body: &[u8]) {
+        let Some(name_len) = u16_at(body, 0, false) else {
+            return;
+        };
+        let level_at = 2 + name_len as usize;
+        let Some(&level) = body.get(level_at) else {
+            return;
+        };
+        self.keepalive = u16_at(body, level_at + 2, false);
+        let mut id_at = level_at + 4;
+        if level == 5 {
+            match body.get(id_at..).map(VarInt::decode) {
+                Some(Ok(Some((len, size)))) => id_at += size + len.value() as usize,
+                _ => return,
+            }
+        }
+        let id = u16_at(body, id_at, false)
+            .and_then(|len| body.get(id_at + 2..id_at + 2 + len as usize));
+        self.client_id = id.map(|id| String::from_utf8_lossy(id).into_owned());
+        // A new connection from the same endpoint pings afresh
+        self.last_ping = None;
+    }
+}
+
+impl std::fmt::Display for ClientProfile {
+    /// A header line with the client, then a line for each direction with
+    /// its bytes and packet counts by type
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.client)?;
+        if let Some(client_id) = &self.client_id {
+            write!(f, " {:?}", client_id)?;
+        }
+        write!(
+            f,
+            ", {:.3} s",
+            self.last_seen.saturating_sub(self.first_seen).as_secs_f64()
+        )?;
+        if let Some(keepalive) = self.keepalive {
+            write!(f, ", keepalive {} s", keepalive)?;
+        }
+        if let Some(interval) = self.ping_interval() {
+            write!(f, ", pings every {:.1} s", interval.as_secs_f64())?;
+        }
+        if let Some(size) = self.average_publish_size() {
+            write!(f, ", publishes {:.1} bytes on average", size)?;
+        }
+        let counts = |packets: &[u64; 16]| {
+            let counts: Vec<String> = packets
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(kind, count)| format!("{} {}", PACKET_NAMES[kind], count))
+                .collect();
+            counts.join(", ")
+        };
+        writeln!(f)?;
+        writeln!(
+            f,
+            "  sent {} bytes: {}",
+            self.bytes_sent,
+            counts(&self.sent)
+        )?;
+        write!(
+            f,
+            "  received {} bytes: {}",
+            self.bytes_received,
+            counts(&self.received)
+        )
+    }
+}
+
+/// Aggregate the frames of a capture per client, in the order the clients
+/// first appear. Clients are told from the broker by `port`
+pub fn profile(frames: &[Frame], port: u16) -> Vec<ClientProfile> {
+    let mut profiles: Vec<ClientProfile> = vec![];
+    let mut index = HashMap::new();
+    for frame in frames {
+        let to_broker = frame.dst.port() == port;
+        let client = if to_broker { frame.src } else { frame.dst };
+        let i = *index.entry(client).or_insert_with(|| {
+            profiles.push(ClientProfile::new(client, frame.timestamp));
+            profiles.len() - 1
+        });
+        profiles[i].observe(frame, to_broker);
+    }
+    profiles
+}
+
+fn malformed(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("pcap: {}", reason))
+}
+
+fn u16_at(bytes: &[u8], offset: usize, le: bool) -> Option<u16> {
+    let b: [u8; 2] = bytes.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if le {
+        u16::from_le_bytes(b)
+    } else {
+        u16::from_be_bytes(b)
+    })
+}
+
+fn u32_at(bytes: &[u8], offset: usize, le: bool) -> Option<u32> {
+    let b: [u8; 4] = bytes.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if le {
+        u32::from_le_bytes(b)
+    } else {
+        u32::from_be_bytes(b)
+    })
+}
+
+/// A captured link layer frame
+struct Packet<'a> {
+    timestamp: Duration,
+    linktype: u16,
+    data: &'a [u8],
+}
+
+/// Classic pcap, in either byte order and with micro or nanosecond
+/// timestamps
+fn pcap_packets(capture: &[u8]) -> io::Result<Vec<Packet<'_>>> {
+    let header =
+        |offset| u32_at(capture, offset, true).ok_or_else(|| malformed("truncated header"));
+    let (le, nanos) = match header(0)? {
+        PCAP_MAGIC_MICROS => (true, false),
+        PCAP_MAGIC_NANOS => (true, true),
+        magic if magic.swap_bytes() == PCAP_MAGIC_MICROS => (false, false),
+        magic if magic.swap_bytes() == PCAP_MAGIC_NANOS => (false, true),
+        _ => return Err(malformed("not a capture file")),
+    };
+    let linktype = u32_at(capture, 20, le).ok_or_else(|| malformed("truncated header"))? as u16;
+    let mut packets = vec![];
+    let mut pos = 24;
+    while pos < capture.len() {
+        let field = |i: usize| {
+            u32_at(capture, pos + 4 * i, le).ok_or_else(|| malformed("truncated record"))
+        };
+        let (secs, frac, len) = (field(0)?, field(1)?, field(2)? as usize);
+        let data = capture
+            .get(pos + 16..pos + 16 + len)
+            .ok_or_else(|| malformed("truncated record"))?;
+        let frac = match nanos {
+            true => Duration::from_nanos(frac as u64),
+            false => Duration::from_micros(frac as u64),
+        };
+        packets.push(Packet {
+            timestamp: Duration::from_secs(secs as u64) + frac,
+            linktype,
+            data,
+        });
+        pos += 16 + len;
+    }
+    Ok(packets)
+}
+
+/// Link type and timestamp units per second of a pcapng interface
+#[derive(Clone, Copy)]
+struct Interface {
+    linktype: u16,
+    units: u64,
+}
+
+impl Interface {
+    fn timestamp(&self, units: u64) -> Duration {
+        let secs = units / self.units;
+        let frac = (units % self.units) as u128 * 1_000_000_000 / self.units as u128;
+        Duration::from_secs(secs) + Duration::from_nanos(frac as u64)
+    }
+}
+
+/// The units per second of an interface, from its options
+fn tsresol(options: &[u8], le: bool) -> u64 {
+    let mut pos = 0;
+    while let (Some(code), Some(len)) = (u16_at(options, pos, le), u16_at(options, pos + 2, le)) {
+        if code == IF_TSRESOL {
+            return match options.get(pos + 4) {
+                Some(&v) if v & 0x80 != 0 => 1u64.checked_shl((v & 0x7F) as u32).unwrap_or(1),
+                Some(&v) => 10u64.checked_pow(v as u32).unwrap_or(1),
+                None => 1_000_000,
+            };
+        }
+        if code == 0 {
+            break;
+        }
+        pos += 4 + (len as usize).next_multiple_of(4);
+    }
+    1_000_000
+}
+
+/// pcapng, as written by Wireshark and recent tcpdump. Every section may
+/// have its own byte order and interfaces
+fn pcapng_packets(capture: &[u8]) -> io::Result<Vec<Packet<'_>>> {
+    let mut packets = vec![];
+    let mut interfaces: Vec<Interface> = vec![];
+    let mut le = true;
+    let mut pos = 0;
+    while pos < capture.len() {
+        if u32_at(capture, pos, true) == Some(PCAPNG_SECTION) {
+            le = match u32_at(capture, pos + 8, true) {
+                Some(PCAPNG_BYTE_ORDER) => true,
+                Some(magic) if magic.swap_bytes() == PCAPNG_BYTE_ORDER => false,
+                _ => return Err(malformed("bad section byte order")),
+            };
+            interfaces.clear();
+        }
+        let kind = u32_at(capture, pos, le).ok_or_else(|| malformed("truncated block"))?;
+        let len =
+            u32_at(capture, pos + 4, le).ok_or_else(|| malformed("truncated block"))? as usize;
+        if len < 12 || !len.is_multiple_of(4) {
+            return Err(malformed("bad block length"));
+        }
+        let body = capture
+            .get(pos + 8..pos + len - 4)
+            .ok_or_else(|| malformed("truncated block"))?;
+        let field = |offset| u32_at(body, offset, le).ok_or_else(|| malformed("truncated block"));
+        match kind {
+            PCAPNG_INTERFACE => interfaces.push(Interface {
+                linktype: u16_at(body, 0, le).ok_or_else(|| malformed("truncated block"))?,
+                units: tsresol(body.get(8..).unwrap_or_default(), le),
+            }),
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = *interfaces
+                    .get(field(0)? as usize)
+                    .ok_or_else(|| malformed("packet of an unknown interface"))?;
+                let units = (field(4)? as u64) << 32 | field(8)? as u64;
+                let captured = field(12)? as usize;
+                packets.push(Packet {
+                    timestamp: interface.timestamp(units),
+                    linktype: interface.linktype,
+                    data: body
+                        .get(20..20 + captured)
+                        .ok_or_else(|| malformed("truncated packet"))?,
+                });
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                let interface = *interfaces
+                    .first()
+                    .ok_or_else(|| malformed("packet of an unknown interface"))?;
+                let data = body.get(4..).ok_or_else(|| malformed("truncated block"))?;
+                let captured = (field(0)? as usize).min(data.len());
+                packets.push(Packet {
+                    // Simple packets carry no timestamp
+                    timestamp: Duration::ZERO,
+                    linktype: interface.linktype,
+                    data: &data[..captured],
+                });
+            }
+            _ => {}
+        }
+        pos += len;
+    }
+    Ok(packets)
+}
+
+/// The IP packet of a link layer frame, `None` for anything else
+fn ip_packet(linktype: u16, data: &[u8]) -> io::Result<Option<&[u8]>> {
+    let ethertype = |offset| u16_at(data, offset, false);
+    let (ethertype, offset) = match linktype {
+        LINKTYPE_NULL | LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => {
+            // The IP version tells them apart, whatever the family field
+            let offset = if linktype == LINKTYPE_NULL { 4 } else { 0 };
+            return Ok(data.get(offset..));
+        }
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            while matches!(ethertype(offset), Some(ETHERTYPE_VLAN | ETHERTYPE_QINQ)) {
+                offset += 4;
+            }
+            (ethertype(offset), offset + 2)
+        }
+        LINKTYPE_LINUX_SLL => (ethertype(14), 16),
+        LINKTYPE_LINUX_SLL2 => (ethertype(0), 20),
+        linktype => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("pcap: unsupported link type {}", linktype),
+            ))
+        }
+    };
+    match ethertype {
+        Some(ETHERTYPE_IPV4 | ETHERTYPE_IPV6) => Ok(data.get(offset..)),
+        _ => Ok(None),
+    }
+}
+
+/// A TCP segment with the addresses of its IP packet
+struct Segment<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    flags: u8,
+    payload: &'a [u8],
+}
+
+/// Parse the TCP segment of an IPv4 or IPv6 packet. Fragments and other
+/// protocols are skipped
+fn tcp_segment(ip: &[u8]) -> Option<Segment<'_>> {
+    let (src, dst, tcp) = match ip.first()? >> 4 {
+        4 => {
+            let header = ((ip.first()? & 0x0F) as usize) * 4;
+            let total = u16_at(ip, 2, false)? as usize;
+            let fragment = u16_at(ip, 6, false)?;
+            if *ip.get(9)? != IPPROTO_TCP || fragment & 0x3FFF != 0 {
+                return None;
+            }
+            let addr = |offset: usize| -> Option<IpAddr> {
+                let b: [u8; 4] = ip.get(offset..offset + 4)?.try_into().ok()?;
+                Some(Ipv4Addr::from(b).into())
+            };
+            // Ethernet pads short frames past the IP total length
+            (addr(12)?, addr(16)?, ip.get(header..total.min(ip.len()))?)
+        }
+        6 => {
+            let addr = |offset: usize| -> Option<IpAddr> {
+                let b: [u8; 16] = ip.get(offset..offset + 16)?.try_into().ok()?;
+                Some(Ipv6Addr::from(b).into())
+            };
+            let end = (40 + u16_at(ip, 4, false)? as usize).min(ip.len());
+            let mut next = *ip.get(6)?;
+            let mut offset = 40;
+            // Hop-by-hop, routing and destination options
+            while matches!(next, 0 | 43 | 60) {
+                next = *ip.get(offset)?;
+                offset += (*ip.get(offset + 1)? as usize + 1) * 8;
+            }
+            if next != IPPROTO_TCP {
+                return None;
+            }
+            (addr(8)?, addr(24)?, ip.get(offset..end)?)
+        }
+        _ => return None,
+    };
+    let header = ((tcp.get(12)? >> 4) as usize) * 4;
+    Some(Segment {
+        src: SocketAddr::new(src, u16_at(tcp, 0, false)?),
+        dst: SocketAddr::new(dst, u16_at(tcp, 2, false)?),
+        seq: u32_at(tcp, 4, false)?,
+        flags: *tcp.get(13)?,
+        payload: tcp.get(header..)?,
+    })
+}
+
+/// One direction of a TCP connection being put back in order
+#[derive(Default)]
+struct Stream {
+    /// Sequence number of the next byte expected, set by the SYN or the
+    /// first segment seen if the capture started mid-connection
+    next_seq: Option<u32>,
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    buf: Vec<u8>,
+}
+
+impl Stream {
+    /// Add a segment, returns whether it made the stream longer
+    fn push(&mut self, seq: u32, payload: &[u8]) -> bool {
+        let next = *self.next_seq.get_or_insert(seq);
+        // Wrapping distance, negative for retransmitted bytes
+        let ahead = seq.wrapping_sub(next) as i32;
+        if ahead > 0 {
+            if self.out_of_order.len() < MAX_OUT_OF_ORDER {
+                self.out_of_order.push((seq, payload.to_vec()));
+            }
+            return false;
+        }
+        let Some(new) = payload.get(ahead.unsigned_abs() as usize..) else {
+            return false;
+        };
+        self.buf.extend_from_slice(new);
+        self.next_seq = Some(next.wrapping_add(new.len() as u32));
+        // The segment may have filled the hole some others were waiting on
+        while let Some(i) = self
+            .out_of_order
+            .iter()
+            .position(|(seq, _)| seq.wrapping_sub(self.next_seq.unwrap()) as i32 <= 0)
+        {
+            let (seq, payload) = self.out_of_order.swap_remove(i);
+            self.push(seq, &payload);
+        }
+        !new.is_empty()
+    }
+
+    /// Take the next complete MQTT packet off the stream. Bytes that can't
+    /// be a packet are dropped, the stream is lost
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let (len, size) = match VarInt::decode(self.buf.get(1..)?) {
+            Ok(Some(header)) => header,
+            Ok(None) => return None,
+            Err(_) => {
+                self.buf.clear();
+                return None;
+            }
+        };
+        let total = 1 + size + len.value() as usize;
+        (self.buf.len() >= total).then(|| self.buf.drain(..total).collect())
+    }
+}
+
+/// Extract the MQTT packets sent to or from `port` in a pcap or pcapng
+/// capture, in the order they were completed on the wire. Each direction of
+/// each connection is reassembled, so packets split across segments or
+/// retransmitted come out once and whole
+pub fn mqtt_frames(capture: &[u8], port: u16) -> io::Result<Vec<Frame>> {
+    let packets = match u32_at(capture, 0, true) {
+        Some(PCAPNG_SECTION) => pcapng_packets(capture)?,
+        _ => pcap_packets(capture)?,
+    };
+    let mut streams: HashMap<(SocketAddr, SocketAddr), Stream> = HashMap::new();
+    let mut frames = vec![];
+    for packet in packets {
+        let Some(ip) = ip_packet(packet.linktype, packet.data)? else {
+            continue;
+        };
+        let Some(segment) = tcp_segment(ip) else {
+            continue;
+        };
+        if segment.src.port() != port && segment.dst.port() != port {
+            continue;
+        }
+        let key = (segment.src, segment.dst);
+        if segment.flags & TCP_SYN != 0 {
+            streams.insert(
+                key,
+                Stream {
+                    next_seq: Some(segment.seq.wrapping_add(1)),
+                    ..Stream::default()
+                },
+            );
+            continue;
+        }
+        let stream = streams.entry(key).or_default();
+        if !segment.payload.is_empty() && stream.push(segment.seq, segment.payload) {
+            while let Some(bytes) = stream.next_frame() {
+                frames.push(Frame {
+                    timestamp: packet.timestamp,
+                    src: segment.src,
+                    dst: segment.dst,
+                    bytes,
+                });
+            }
+        }
+        if segment.flags & (TCP_FIN | TCP_RST) != 0 {
+            streams.remove(&key);
+        }
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod pcap_tests {
+    use super::*;
+
+    const CLIENT: [u8; 4] = [10, 0, 0, 1];
+    const BROKER: [u8; 4] = [10, 0, 0, 2];
+
+    /// Ethernet, IPv4 and TCP headers around `payload`
+    fn ethernet(
+        src: ([u8; 4], u16),
+        dst: ([u8; 4], u16),
+        seq: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0, 64, IPPROTO_TCP, 0, 0]);
+        frame.extend_from_slice(&src.0);
+        frame.extend_from_slice(&dst.0);
+        frame.extend_from_slice(&src.1.to_be_bytes());
+        frame.extend_from_slice(&dst.1.to_be_bytes());
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xFF, 0xFF, 0, 0, 0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn pcap(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut capture = vec![];
+        for field in [
+            PCAP_MAGIC_MICROS,
+            0x0004_0002,
+            0,
+            0,
+            65535,
+            LINKTYPE_ETHERNET as u32,
+        ] {
+            capture.extend_from_slice(&field.to_le_bytes());
+        }
+        for (i, frame) in frames.iter().enumerate() {
+            for field in [
+                1_700_000_000,
+                i as u32 * 1000,
+                frame.len() as u32,
+                frame.len() as u32,
+            ] {
+                capture.extend_from_slice(&field.to_le_bytes());
+            }
+            capture.extend_from_slice(frame);
+        }
+        capture
+    }
+
+    fn pcapng(frames: &[Vec<u8>]) -> Vec<u8> {
+        let block = |kind: u32, body: &[u8]| {
+            let body = [body, &vec![0; body.len().next_multiple_of(4) - body.len()]].concat();
+            let len = (body.len() + 12) as u32;
+            [
+                &kind.to_be_bytes()[..],
+                &len.to_be_bytes(),
+                &body,
+                &len.to_be_bytes(),
+            ]
+            .concat()
+        };
+        let mut section = PCAPNG_BYTE_ORDER.to_be_bytes().to_vec();
+        section.extend_from_slice(&[0, 1, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let mut capture = block(PCAPNG_SECTION, &section);
+        // Nanosecond timestamps
+        let mut interface = vec![0, LINKTYPE_ETHERNET as u8, 0, 0, 0, 0, 0xFF, 0xFF];
+        interface.extend_from_slice(&[0, IF_TSRESOL as u8, 0, 1, 9, 0, 0, 0, 0, 0, 0, 0]);
+        capture.extend(block(PCAPNG_INTERFACE, &interface));
+        for frame in frames {
+            let units: u64 = 1_700_000_000_123_456_789;
+            let mut body = vec![];
+            for field in [
+                0,
+                (units >> 32) as u32,
+                units as u32,
+                frame.len() as u32,
+                frame.len() as u32,
+            ] {
+                body.extend_from_slice(&field.to_be_bytes());
+            }
+            body.extend_from_slice(frame);
+            capture.extend(block(PCAPNG_ENHANCED_PACKET, &body));
+        }
+        capture
+    }
+
+    const CONNECT: [u8; 14] = [0x10, 12, 0, 4, b'M', b'Q', b'T', b'T', 4, 2, 0, 60, 0, 0];
+    const CONNACK: [u8; 4] = [0x20, 2, 0, 0];
+    const PUBLISH: [u8; 7] = [0x30, 5, 0, 1, b'a', b'h', b'i'];
+
+    #[test]
+    fn test_pcap_reassembly() -> io::Result<()> {
+        let (c, b) = ((CLIENT, 50000), (BROKER, 1883));
+        let stream = [&CONNECT[..], &PUBLISH].concat();
+        let frames = [
+            ethernet(c, b, 99, TCP_SYN, &[]),
+            ethernet(b, c, 499, TCP_SYN, &[]),
+            // The second half of the stream overtakes the first
+            ethernet(c, b, 110, 0, &stream[10..]),
+            ethernet(c, b, 100, 0, &stream[..10]),
+            ethernet(b, c, 500, 0, &CONNACK),
+            // Retransmission
+            ethernet(c, b, 100, 0, &stream[..10]),
+            ethernet(c, (BROKER, 8883), 1, 0, &PUBLISH),
+        ];
+        let extracted = mqtt_frames(&pcap(&frames), 1883)?;
+        let packets: Vec<&[u8]> = extracted.iter().map(|f| &f.bytes[..]).collect();
+        assert_eq!(packets, [&CONNECT[..], &PUBLISH, &CONNACK]);
+        assert_eq!(extracted[0].src, "10.0.0.1:50000".parse().unwrap());
+        assert_eq!(extracted[2].dst, "10.0.0.1:50000".parse().unwrap());
+        assert_eq!(
+            extracted[2].timestamp,
+            Duration::from_secs(1_700_000_000) + Duration::from_millis(4)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pcapng() -> io::Result<()> {
+        let (c, b) = ((CLIENT, 50000), (BROKER, 1883));
+        // No SYN, the capture started mid-connection
+        let frames = [ethernet(
+            c,
+            b,
+            7,
+            0,
+            &[&PUBLISH[..], &PUBLISH[..3]].concat(),
+        )];
+        let extracted = mqtt_frames(&pcapng(&frames), 1883)?;
+        assert_eq!(extracted.len(), 1);
+        assert_eq!(extracted[0].bytes, PUBLISH);
+        assert_eq!(
+            extracted[0].timestamp,
+            Duration::new(1_700_000_000, 123_456_789)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary() {
+        let frame = |src: &str, dst: &str, bytes: &[u8]| Frame {
+            timestamp: Duration::new(1_700_000_000, 1_500_000),
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            bytes: bytes.to_vec(),
+        };
+        let client = "10.0.0.1:50000";
+        let broker = "10.0.0.2:1883";
+        let frames = [
+            frame(client, broker, &PUBLISH),
+            frame(
+                broker,
+                client,
+                &[0x32, 9, 0, 3, b'a', b',', b'b', 0, 7, b'h', b'i'],
+            ),
+            frame(client, broker, &[0x40, 2, 0, 7]),
+            frame(client, broker, &[0xC0, 0]),
+        ];
+        assert_eq!(
+            summary_csv(&frames, 1883),
+            "timestamp,src,dst,direction,type,flags,packet_id,topic,payload_size\n\
+             1700000000.001500,10.0.0.1:50000,10.0.0.2:1883,to_broker,PUBLISH,0x0,,a,2\n\
+             1700000000.001500,10.0.0.2:1883,10.0.0.1:50000,to_client,PUBLISH,0x2,7,\"a,b\",2\n\
+             1700000000.001500,10.0.0.1:50000,10.0.0.2:1883,to_broker,PUBACK,0x0,7,,\n\
+             1700000000.001500,10.0.0.1:50000,10.0.0.2:1883,to_broker,PINGREQ,0x0,,,\n"
+        );
+    }
+
+    #[test]
+    fn test_profile() {
+        let client = "10.0.0.1:50000".parse().unwrap();
+        let broker = "10.0.0.2:1883".parse().unwrap();
+        let frame = |secs: u64, to_broker: bool, bytes: &[u8]| Frame {
+            timestamp: Duration::from_secs(secs),
+            src: if to_broker { client } else { broker },
+            dst: if to_broker { broker } else { client },
+            bytes: bytes.to_vec(),
+        };
+        // MQTT 5 CONNECT of client "c" with a 30 seconds keepalive
+        let connect = [
+            0x10, 14, 0, 4, b'M', b'Q', b'T', b'T', 5, 2, 0, 30, 0, 0, 1, b'c',
+        ];
+        let frames = [
+            frame(100, true, &connect),
+            frame(100, false, &CONNACK),
+            frame(101, true, &PUBLISH),
+            frame(102, false, &[0x32, 8, 0, 1, b'a', 0, 1, b'x', b'y', b'z']),
+            frame(120, true, &[0xC0, 0]),
+            frame(150, true, &[0xC0, 0]),
+            frame(170, true, &[0xC0, 0]),
+            frame(170, false, &[0xD0, 0]),
+        ];
+        let profiles = profile(&frames, 1883);
+        assert_eq!(profiles.len(), 1);
+        let profile = &profiles[0];
+        assert_eq!(profile.client_id.as_deref(), Some("c"));
+        assert_eq!(profile.keepalive, Some(30));
+        assert_eq!(profile.sent[12], 3);
+        assert_eq!(profile.bytes_sent, 16 + 7 + 6);
+        assert_eq!(profile.ping_interval(), Some(Duration::from_secs(25)));
+        assert_eq!(profile.average_publish_size(), Some(2.5));
+        assert_eq!(
+            profile.to_string(),
+            "10.0.0.1:50000 \"c\", 70.000 s, keepalive 30 s, pings every 25.0 s, \
+             publishes 2.5 bytes on average\n  \
+             sent 29 bytes: CONNECT 1, PUBLISH 1, PINGREQ 3\n  \
+             received 16 bytes: CONNACK 1, PUBLISH 1, PINGRESP 1"
+        );
+    }
+
+    #[test]
+    fn test_not_a_capture() {
+        assert_eq!(
+            mqtt_frames(b"hello world, not a capture", 1883)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::encoding::Encoding;
+use crate::json;
+use std::io;
+
+/// Bytes per line of a hex dump
+const HEX_LINE: usize = 16;
+
+/// How `subscribe` shows payloads, picked from the MQTT 5 Content Type
+/// property they were published with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Printer {
+    /// JSON indented, see `json::Value::pretty`
+    Json,
+    /// Decoded to JSON, then indented
+    Decoded(Encoding),
+    Text,
+    /// Offsets, bytes and their printable characters
+    Hex,
+}
+
+impl Printer {
+    /// The printer of a MIME type, its parameters and case ignored, `None`
+    /// for types without one
+    pub fn for_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        let (kind, subtype) = essence.split_once('/')?;
+        match (kind, subtype) {
+            (_, "json") => Some(Printer::Json),
+            (_, subtype) if subtype.ends_with("+json") => Some(Printer::Json),
+            (_, "cbor") => Some(Printer::Decoded(Encoding::Cbor)),
+            (_, subtype) if subtype.ends_with("+cbor") => Some(Printer::Decoded(Encoding::Cbor)),
+            ("application", "msgpack" | "x-msgpack" | "vnd.msgpack") => {
+                Some(Printer::Decoded(Encoding::MsgPack))
+            }
+            ("text", _) => Some(Printer::Text),
+            ("application", "octet-stream") => Some(Printer::Hex),
+            _ => None,
+        }
+    }
+
+    /// Fails when the payload isn't what the printer expects
+    pub fn print(&self, payload: &[u8]) -> io::Result<String> {
+        let text = || {
+            std::str::from_utf8(payload)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Payload is not UTF-8"))
+        };
+        match self {
+            Printer::Json => Ok(json::parse(text()?)?.pretty()),
+            Printer::Decoded(encoding) => Ok(encoding.decode(payload)?.pretty()),
+            Printer::Text => text().map(str::to_string),
+            Printer::Hex => Ok(hex_dump(payload)),
+        }
+    }
+}
+
+fn hex_dump(payload: &[u8]) -> String {
+    payload
+        .chunks(HEX_LINE)
+        .enumerate()
+        .map(|(i, line)| {
+            let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let chars: String = line
+                .iter()
+                .map(|b| match b.is_ascii_graphic() || *b == b' ' {
+                    true => *b as char,
+                    false => '.',
+                })
+                .collect();
+            format!(
+                "{:08x}  {:<width$}  {}",
+                i * HEX_LINE,
+                bytes.join(" "),
+                chars,
+                width = HEX_LINE * 3 - 1
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod pretty_tests {
+    use super::*;
+
+    #[test]
+    fn test_for_content_type() {
+        let printer = Printer::for_content_type;
+        assert_eq!(printer("application/json"), Some(Printer::Json));
+        assert_eq!(
+            printer("Application/Problem+JSON; charset=utf-8"),
+            Some(Printer::Json)
+        );
+        assert_eq!(
+            printer("application/cbor"),
+            Some(Printer::Decoded(Encoding::Cbor))
+        );
+        assert_eq!(
+            printer("application/x-msgpack"),
+            Some(Printer::Decoded(Encoding::MsgPack))
+        );
+        assert_eq!(printer("text/csv"), Some(Printer::Text));
+        assert_eq!(printer("application/octet-stream"), Some(Printer::Hex));
+        assert_eq!(printer("image/png"), None);
+        assert_eq!(printer("json"), None);
+    }
+
+    #[test]
+    fn test_print() -> io::Result<()> {
+        assert_eq!(
+            Printer::Json.print(br#"{"a":[1]}"#)?,
+            "{\n  \"a\": [\n    1\n  ]\n}"
+        );
+        let msgpack = Printer::Decoded(Encoding::MsgPack);
+        assert_eq!(
+            msgpack.print(&[0x81, 0xA1, b'a', 0x01])?,
+            "{\n  \"a\": 1\n}"
+        );
+        assert!(Printer::Json.print(b"{").is_err());
+        assert!(Printer::Text.print(&[0xFF]).is_err());
+        assert_eq!(
+            Printer::Hex.print(b"0123456789abcdef\x00!")?,
+            "00000000  30 31 32 33 34 35 36 37 38 39 61 62 63 64 65 66  0123456789abcdef\n\
+             00000010  00 21                                            .!"
+        );
+        Ok(())
+    }
+}
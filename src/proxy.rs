@@ -0,0 +1,331 @@
+use crate::bench::Rng;
+use crate::mqtt::{FrameReader, RateLimit, RateLimiter};
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest a PUBLISH picked for reordering waits for a packet to overtake
+/// it
+const REORDER_WAIT: Duration = Duration::from_millis(100);
+
+const PUBLISH: u8 = 3;
+
+/// Network conditions applied to one direction of the proxied connections
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Impairment {
+    /// One-way delay added to every packet
+    pub latency: Duration,
+    /// Random extra delay, up to it. Packets keep their order
+    pub jitter: Duration,
+    /// Bytes per second, unlimited if `None`
+    pub bandwidth: Option<f64>,
+    /// Share of the PUBLISH packets held back until the next packet went
+    /// through, between 0 and 1
+    pub reorder: f64,
+}
+
+/// Where a `Proxy` connects its clients and what it does to their traffic
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    pub upstream: Vec<SocketAddr>,
+    /// Packets from the clients
+    pub to_broker: Impairment,
+    /// Packets from the broker
+    pub to_client: Impairment,
+    /// Of the jitter and the reordering, each connection adds its number to
+    /// it
+    pub seed: u64,
+    pub timeout: Duration,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            upstream: vec![],
+            to_broker: Impairment::default(),
+            to_client: Impairment::default(),
+            seed: Rng::from_time().next_u64(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A TCP proxy in front of a broker degrading the link like a WAN would,
+/// to see how clients cope with slow, jittery or reordered traffic. It
+/// works on whole MQTT packets, which are never altered
+pub struct Proxy {
+    listener: TcpListener,
+    options: Arc<ProxyOptions>,
+}
+
+impl Proxy {
+    pub fn bind(addr: impl ToSocketAddrs, options: ProxyOptions) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            options: Arc::new(options),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept clients forever, each connected to the broker on its own.
+    /// Errors tied to a single client are logged and only close its
+    /// connection
+    pub fn run(&self) -> io::Result<()> {
+        for (n, client) in self.listener.incoming().enumerate() {
+            let client = client?;
+            let options = Arc::clone(&self.options);
+            thread::spawn(move || {
+                let peer = client.peer_addr();
+                if let Err(e) = relay(client, &options, options.seed.wrapping_add(n as u64)) {
+                    match peer {
+                        Ok(peer) => eprintln!("{}: {}", peer, e),
+                        Err(_) => eprintln!("{}", e),
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn connect(addrs: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Connect a client to the broker and pass their packets both ways until
+/// either side closes
+fn relay(client: TcpStream, options: &ProxyOptions, seed: u64) -> io::Result<()> {
+    let broker = connect(&options.upstream, options.timeout)?;
+    // Delays are the proxy's job, Nagle's would add to them
+    client.set_nodelay(true)?;
+    broker.set_nodelay(true)?;
+    let upstream = {
+        let (from, to) = (client.try_clone()?, broker.try_clone()?);
+        let impairment = options.to_broker;
+        thread::spawn(move || pipe(from, to, &impairment, Rng::new(seed)))
+    };
+    let downstream = pipe(broker, client, &options.to_client, Rng::new(!seed));
+    let upstream = upstream.join().unwrap();
+    downstream.and(upstream)
+}
+
+/// Relay the packets read from `from` to `to` as `impairment` says, until
+/// `from` ends. A packet is delayed from the time it was read, a writer
+/// thread sending it when due
+fn pipe(from: TcpStream, to: TcpStream, impairment: &Impairment, mut rng: Rng) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let writer = {
+        let (to, impairment) = (to.try_clone()?, *impairment);
+        let reorder = Rng::new(rng.next_u64());
+        thread::spawn(move || write_due(to, &rx, &impairment, reorder))
+    };
+    let mut frames = FrameReader::new(from.try_clone()?);
+    let result = loop {
+        match frames.next_frame() {
+            Ok(Some(frame)) => {
+                let jitter = impairment.jitter.mul_f64(unit(&mut rng));
+                let due = Instant::now() + impairment.latency + jitter;
+                if tx.send((due, frame.to_vec())).is_err() {
+                    break Ok(());
+                }
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => break Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+        match frames.fill() {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    drop(tx);
+    let written = writer.join().unwrap();
+    // The other direction ends with this one, as a closed TCP connection
+    // would
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+    result.and(written)
+}
+
+/// Uniform in [0, 1)
+fn unit(rng: &mut Rng) -> f64 {
+    (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Send the packets of `rx` when they're due, within the bandwidth, and
+/// swap PUBLISH packets with the next packet at the reorder rate
+fn write_due(
+    mut to: TcpStream,
+    rx: &Receiver<(Instant, Vec<u8>)>,
+    impairment: &Impairment,
+    mut rng: Rng,
+) -> io::Result<()> {
+    let limiter = RateLimiter::new(&RateLimit {
+        bytes_per_sec: impairment.bandwidth,
+        ..RateLimit::default()
+    });
+    let mut send = |frame: &[u8]| {
+        limiter.acquire(0, frame.len());
+        to.write_all(frame)
+    };
+    let mut held: Option<Vec<u8>> = None;
+    loop {
+        let next = match held {
+            Some(_) => rx.recv_timeout(REORDER_WAIT),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let (due, frame) = match next {
+            Ok(packet) => packet,
+            Err(RecvTimeoutError::Timeout) => {
+                send(&held.take().unwrap())?;
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        let publish = frame[0] >> 4 == PUBLISH;
+        if held.is_none() && publish && unit(&mut rng) < impairment.reorder {
+            held = Some(frame);
+            continue;
+        }
+        send(&frame)?;
+        if let Some(frame) = held.take() {
+            send(&frame)?;
+        }
+    }
+    match held {
+        Some(frame) => send(&frame),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod proxy_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+    use crate::mqtt::{Protocol, Qos, Response};
+
+    fn start(options: ProxyOptions) -> io::Result<SocketAddr> {
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let upstream = vec![broker.local_addr()?];
+        thread::spawn(move || broker.run());
+        let proxy = Proxy::bind(
+            "127.0.0.1:0",
+            ProxyOptions {
+                upstream,
+                ..options
+            },
+        )?;
+        let addr = proxy.local_addr()?;
+        thread::spawn(move || proxy.run());
+        Ok(addr)
+    }
+
+    fn connect(addr: SocketAddr, client_id: &str) -> io::Result<Protocol> {
+        Protocol::builder()
+            .addrs(&[addr])
+            .client_id(client_id)
+            .timeout(Duration::from_secs(5))
+            .connect()
+    }
+
+    fn subscribe(client: &mut Protocol, topic: &str) -> io::Result<()> {
+        client.subscribe(topic, Qos::AtMostOnce)?;
+        while !matches!(client.read_message::<Response>()?, Response::Suback { .. }) {}
+        Ok(())
+    }
+
+    fn payload(client: &mut Protocol) -> io::Result<Vec<u8>> {
+        loop {
+            if let Response::Publish { payload, .. } = client.read_message::<Response>()? {
+                return Ok(payload);
+            }
+        }
+    }
+
+    #[test]
+    fn test_latency() -> io::Result<()> {
+        let addr = start(ProxyOptions {
+            to_broker: Impairment {
+                latency: Duration::from_millis(100),
+                ..Impairment::default()
+            },
+            to_client: Impairment {
+                latency: Duration::from_millis(50),
+                jitter: Duration::from_millis(20),
+                ..Impairment::default()
+            },
+            ..ProxyOptions::default()
+        })?;
+        let started = Instant::now();
+        let mut client = connect(addr, "proxy-latency")?;
+        let round_trip = started.elapsed();
+        assert!(round_trip >= Duration::from_millis(150), "{:?}", round_trip);
+        assert!(round_trip < Duration::from_secs(2), "{:?}", round_trip);
+        subscribe(&mut client, "proxy/latency")?;
+        client.publish("proxy/latency", b"through")?;
+        assert_eq!(payload(&mut client)?, b"through");
+        client.disconnect()
+    }
+
+    #[test]
+    fn test_reorder() -> io::Result<()> {
+        let addr = start(ProxyOptions {
+            to_client: Impairment {
+                reorder: 1.0,
+                ..Impairment::default()
+            },
+            ..ProxyOptions::default()
+        })?;
+        let mut subscriber = connect(addr, "proxy-reorder-sub")?;
+        subscribe(&mut subscriber, "proxy/reorder")?;
+        let mut publisher = connect(addr, "proxy-reorder-pub")?;
+        for payload in [b"1", b"2", b"3"] {
+            publisher.publish("proxy/reorder", payload)?;
+        }
+        // Every other PUBLISH is overtaken, the last one goes out alone
+        let received = (0..3)
+            .map(|_| payload(&mut subscriber))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(received, [b"2", b"1", b"3"]);
+        subscriber.disconnect()
+    }
+
+    #[test]
+    fn test_bandwidth() -> io::Result<()> {
+        let addr = start(ProxyOptions {
+            to_client: Impairment {
+                bandwidth: Some(10_000.0),
+                ..Impairment::default()
+            },
+            ..ProxyOptions::default()
+        })?;
+        let mut client = connect(addr, "proxy-bandwidth")?;
+        subscribe(&mut client, "proxy/bandwidth")?;
+        let started = Instant::now();
+        for _ in 0..3 {
+            client.publish("proxy/bandwidth", &[0; 1000])?;
+        }
+        for _ in 0..3 {
+            assert_eq!(payload(&mut client)?.len(), 1000);
+        }
+        // The first kilobyte goes at once, the others wait 100ms each
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        client.disconnect()
+    }
+}
@@ -0,0 +1,595 @@
+use crate::compress::crc32;
+use crate::mqtt::Qos;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BTreeSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Start of every segment, the last byte being the format version
+const MAGIC: &[u8; 4] = b"SKQ\x01";
+const EXTENSION: &str = "seg";
+
+const TAG_MESSAGE: u8 = 1;
+const TAG_ACK: u8 = 2;
+
+const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Size of the segments and how long messages never acknowledged are kept
+#[derive(Debug, Clone)]
+pub struct QueueOptions {
+    /// A new segment starts once the current one grows past this many bytes
+    pub segment_size: u64,
+    /// Drop the segments whose newest message is older than this
+    pub max_age: Option<Duration>,
+    /// Drop the oldest segments while all of them take more bytes than this
+    pub max_size: Option<u64>,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            max_age: None,
+            max_size: None,
+        }
+    }
+}
+
+/// A message accepted into a `Queue`, `seq` acknowledges it
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedMessage {
+    pub seq: u64,
+    pub timestamp: SystemTime,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: Qos,
+    pub retain: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Message(QueuedMessage),
+    Ack(u64),
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl Record {
+    /// Length and checksum of the body, then the body
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut body = vec![];
+        match self {
+            Record::Message(message) => {
+                let timestamp = message
+                    .timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                body.write_u8(TAG_MESSAGE)?;
+                body.write_u64::<BigEndian>(message.seq)?;
+                body.write_u64::<BigEndian>(timestamp.as_millis() as u64)?;
+                body.write_u8(u8::from(&message.qos))?;
+                body.write_u8(message.retain as u8)?;
+                body.write_u16::<BigEndian>(message.topic.len() as u16)?;
+                body.write_all(message.topic.as_bytes())?;
+                body.write_u32::<BigEndian>(message.payload.len() as u32)?;
+                body.write_all(&message.payload)?;
+            }
+            Record::Ack(seq) => {
+                body.write_u8(TAG_ACK)?;
+                body.write_u64::<BigEndian>(*seq)?;
+            }
+        }
+        writer.write_u32::<BigEndian>(body.len() as u32)?;
+        writer.write_u32::<BigEndian>(crc32(&body))?;
+        writer.write_all(&body)
+    }
+
+    fn parse(mut body: &[u8]) -> io::Result<Self> {
+        let record = match body.read_u8()? {
+            TAG_MESSAGE => {
+                let seq = body.read_u64::<BigEndian>()?;
+                let timestamp = UNIX_EPOCH + Duration::from_millis(body.read_u64::<BigEndian>()?);
+                let qos = match body.read_u8()? {
+                    qos @ 0..=2 => Qos::from(qos),
+                    _ => return Err(invalid("Invalid QoS")),
+                };
+                let retain = body.read_u8()? != 0;
+                let mut topic = vec![0; body.read_u16::<BigEndian>()? as usize];
+                body.read_exact(&mut topic)?;
+                let topic = String::from_utf8(topic)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let mut payload = vec![0; body.read_u32::<BigEndian>()? as usize];
+                body.read_exact(&mut payload)?;
+                Record::Message(QueuedMessage {
+                    seq,
+                    timestamp,
+                    topic,
+                    payload,
+                    qos,
+                    retain,
+                })
+            }
+            TAG_ACK => Record::Ack(body.read_u64::<BigEndian>()?),
+            tag => return Err(invalid(&format!("Unknown queue record 0x{:02X}", tag))),
+        };
+        if !body.is_empty() {
+            return Err(invalid("Trailing bytes in queue record"));
+        }
+        Ok(record)
+    }
+}
+
+/// What a segment holds, as read back
+#[derive(Debug, Default)]
+struct Scan {
+    records: Vec<Record>,
+    /// Bytes up to the end of the last complete record
+    valid_len: u64,
+    /// Records failing their checksum, skipped
+    corrupt: u64,
+    /// The segment ends with a record cut short, usually by a crash
+    truncated: bool,
+}
+
+fn scan(path: &Path) -> io::Result<Scan> {
+    let bytes = fs::read(path)?;
+    let Some(mut rest) = bytes.strip_prefix(MAGIC) else {
+        return Err(invalid(&format!("{}: not a queue segment", path.display())));
+    };
+    let mut scan = Scan {
+        valid_len: MAGIC.len() as u64,
+        ..Scan::default()
+    };
+    while !rest.is_empty() {
+        if rest.len() < 8 {
+            scan.truncated = true;
+            break;
+        }
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_be_bytes(rest[4..8].try_into().unwrap());
+        let Some(body) = rest[8..].get(..len) else {
+            scan.truncated = true;
+            break;
+        };
+        match Record::parse(body) {
+            Ok(record) if crc32(body) == crc => scan.records.push(record),
+            _ => scan.corrupt += 1,
+        }
+        rest = &rest[8 + len..];
+        scan.valid_len += 8 + len as u64;
+    }
+    Ok(scan)
+}
+
+/// Segments of a queue directory, oldest first, with the sequence number
+/// of their first message
+fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == EXTENSION) {
+            if let Some(first_seq) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                segments.push((first_seq, path));
+            }
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_seq, EXTENSION))
+}
+
+#[derive(Debug)]
+struct Segment {
+    first_seq: u64,
+    path: PathBuf,
+    bytes: u64,
+    messages: u64,
+    newest: Option<SystemTime>,
+}
+
+/// Durable FIFO of messages accepted but not yet delivered, surviving
+/// restarts. Messages are appended to segment files along with their
+/// acknowledgements, and a segment is deleted once every message up to its
+/// last one was acknowledged or dropped by the retention
+#[derive(Debug)]
+pub struct Queue {
+    dir: PathBuf,
+    options: QueueOptions,
+    /// Oldest first, the last one being appended to
+    segments: VecDeque<Segment>,
+    file: File,
+    next_seq: u64,
+    /// Sequence numbers of the messages not acknowledged yet
+    pending: BTreeSet<u64>,
+    /// Messages dropped by the retention before being acknowledged
+    expired: u64,
+}
+
+impl Queue {
+    /// Open the queue in `dir`, created if missing, along with the messages
+    /// still to deliver in the order they were pushed. Corrupt records are
+    /// skipped and a record cut short by a crash ends its segment
+    pub fn open(
+        dir: impl AsRef<Path>,
+        options: QueueOptions,
+    ) -> io::Result<(Self, Vec<QueuedMessage>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut segments = VecDeque::new();
+        let mut messages = vec![];
+        let mut acked = BTreeSet::new();
+        let mut next_seq = 0;
+        let mut last_len = 0;
+        for (first_seq, path) in self::segments(&dir)? {
+            let scan = scan(&path)?;
+            if scan.corrupt > 0 {
+                eprintln!(
+                    "{}: skipping {} corrupt records",
+                    path.display(),
+                    scan.corrupt
+                );
+            }
+            if scan.truncated {
+                eprintln!("{}: ignoring a truncated record", path.display());
+            }
+            let mut segment = Segment {
+                first_seq,
+                path,
+                bytes: scan.valid_len,
+                messages: 0,
+                newest: None,
+            };
+            for record in scan.records {
+                match record {
+                    Record::Message(message) => {
+                        segment.messages += 1;
+                        segment.newest = segment.newest.max(Some(message.timestamp));
+                        next_seq = next_seq.max(message.seq + 1);
+                        messages.push(message);
+                    }
+                    Record::Ack(seq) => {
+                        acked.insert(seq);
+                    }
+                }
+            }
+            // Segments are named after the message they start with
+            next_seq = next_seq.max(first_seq);
+            last_len = scan.valid_len;
+            segments.push_back(segment);
+        }
+        messages.retain(|message| !acked.contains(&message.seq));
+        let pending = messages.iter().map(|message| message.seq).collect();
+        let file = match segments.back() {
+            Some(segment) => {
+                // Whatever follows the last complete record would hide the
+                // ones appended next
+                let file = OpenOptions::new().write(true).open(&segment.path)?;
+                file.set_len(last_len)?;
+                drop(file);
+                OpenOptions::new().append(true).open(&segment.path)?
+            }
+            None => {
+                let segment = new_segment(&dir, next_seq)?;
+                segments.push_back(segment.0);
+                segment.1
+            }
+        };
+        let mut queue = Self {
+            dir,
+            options,
+            segments,
+            file,
+            next_seq,
+            pending,
+            expired: 0,
+        };
+        queue.prune()?;
+        messages.retain(|message| queue.pending.contains(&message.seq));
+        Ok((queue, messages))
+    }
+
+    /// Append a message, written to the segment before this returns, and
+    /// return its sequence number
+    pub fn push(&mut self, topic: &str, payload: &[u8], qos: Qos, retain: bool) -> io::Result<u64> {
+        let active = self.segments.back().unwrap();
+        if active.bytes >= self.options.segment_size && active.messages > 0 {
+            let (segment, file) = new_segment(&self.dir, self.next_seq)?;
+            self.file.sync_data()?;
+            self.segments.push_back(segment);
+            self.file = file;
+        }
+        let message = QueuedMessage {
+            seq: self.next_seq,
+            timestamp: SystemTime::now(),
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos,
+            retain,
+        };
+        self.append(&Record::Message(message))?;
+        let active = self.segments.back_mut().unwrap();
+        active.messages += 1;
+        active.newest = Some(SystemTime::now());
+        self.pending.insert(self.next_seq);
+        self.next_seq += 1;
+        self.prune()?;
+        Ok(self.next_seq - 1)
+    }
+
+    /// Mark a message delivered, it won't be replayed anymore
+    pub fn ack(&mut self, seq: u64) -> io::Result<()> {
+        if self.pending.remove(&seq) {
+            self.append(&Record::Ack(seq))?;
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// Number of messages not acknowledged yet
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Number of messages dropped by the retention since the queue was
+    /// opened, never delivered
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut bytes = vec![];
+        record.write(&mut bytes)?;
+        self.file.write_all(&bytes)?;
+        self.segments.back_mut().unwrap().bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Delete the oldest segments that are done with or past the retention,
+    /// never the one appended to. Acknowledgements are always in the same
+    /// segment as their message or a newer one, deleting from the oldest
+    /// never brings a message back
+    fn prune(&mut self) -> io::Result<()> {
+        let now = SystemTime::now();
+        while self.segments.len() > 1 {
+            let (oldest, next) = (&self.segments[0], &self.segments[1]);
+            let seqs = oldest.first_seq..next.first_seq;
+            let done = self.pending.range(seqs.clone()).next().is_none();
+            let expired = self.options.max_age.is_some_and(|max_age| {
+                oldest
+                    .newest
+                    .is_some_and(|newest| now.duration_since(newest).unwrap_or_default() > max_age)
+            });
+            let oversized = self.options.max_size.is_some_and(|max_size| {
+                self.segments.iter().map(|s| s.bytes).sum::<u64>() > max_size
+            });
+            if !(done || expired || oversized) {
+                break;
+            }
+            let dropped = self.pending.range(seqs).copied().collect::<Vec<_>>();
+            if !dropped.is_empty() {
+                eprintln!(
+                    "{}: dropping {} undelivered messages past the retention",
+                    oldest.path.display(),
+                    dropped.len()
+                );
+                self.expired += dropped.len() as u64;
+                for seq in dropped {
+                    self.pending.remove(&seq);
+                }
+            }
+            fs::remove_file(&oldest.path)?;
+            self.segments.pop_front();
+        }
+        Ok(())
+    }
+}
+
+fn new_segment(dir: &Path, first_seq: u64) -> io::Result<(Segment, File)> {
+    let path = segment_path(dir, first_seq);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&path)?;
+    file.write_all(MAGIC)?;
+    let file = OpenOptions::new().append(true).open(&path)?;
+    let segment = Segment {
+        first_seq,
+        path,
+        bytes: MAGIC.len() as u64,
+        messages: 0,
+        newest: None,
+    };
+    Ok((segment, file))
+}
+
+/// What a segment of a queue directory holds, see `inspect`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentInfo {
+    pub path: PathBuf,
+    pub first_seq: u64,
+    pub bytes: u64,
+    pub messages: u64,
+    /// Its messages acknowledged, in this segment or a later one
+    pub acked: u64,
+    pub corrupt: u64,
+    pub truncated: bool,
+    pub oldest: Option<SystemTime>,
+    pub newest: Option<SystemTime>,
+}
+
+/// Read the segments of a queue directory without changing them, along
+/// with the messages still to deliver
+pub fn inspect(dir: impl AsRef<Path>) -> io::Result<(Vec<SegmentInfo>, Vec<QueuedMessage>)> {
+    let mut infos = vec![];
+    let mut messages = vec![];
+    let mut acked = BTreeSet::new();
+    for (first_seq, path) in segments(dir.as_ref())? {
+        let scan = scan(&path)?;
+        let mut info = SegmentInfo {
+            bytes: fs::metadata(&path)?.len(),
+            path,
+            first_seq,
+            messages: 0,
+            acked: 0,
+            corrupt: scan.corrupt,
+            truncated: scan.truncated,
+            oldest: None,
+            newest: None,
+        };
+        for record in scan.records {
+            match record {
+                Record::Message(message) => {
+                    info.messages += 1;
+                    info.oldest = Some(
+                        info.oldest
+                            .map_or(message.timestamp, |oldest| oldest.min(message.timestamp)),
+                    );
+                    info.newest = info.newest.max(Some(message.timestamp));
+                    messages.push(message);
+                }
+                Record::Ack(seq) => {
+                    acked.insert(seq);
+                }
+            }
+        }
+        infos.push(info);
+    }
+    for message in &messages {
+        if acked.contains(&message.seq) {
+            // Messages are in the last segment starting at or before them
+            if let Some(info) = infos
+                .iter_mut()
+                .rev()
+                .find(|info| info.first_seq <= message.seq)
+            {
+                info.acked += 1;
+            }
+        }
+    }
+    messages.retain(|message| !acked.contains(&message.seq));
+    Ok((infos, messages))
+}
+
+#[cfg(test)]
+mod queue_tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sake-queue-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn topics(messages: &[QueuedMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.topic.as_str()).collect()
+    }
+
+    #[test]
+    fn test_queue_replay() -> io::Result<()> {
+        let dir = temp_dir("replay");
+        let (mut queue, replayed) = Queue::open(&dir, QueueOptions::default())?;
+        assert!(replayed.is_empty());
+        for topic in ["a", "b", "c", "d"] {
+            queue.push(topic, topic.as_bytes(), Qos::AtLeastOnce, topic == "b")?;
+        }
+        queue.ack(0)?;
+        queue.ack(2)?;
+        assert_eq!(queue.len(), 2);
+        drop(queue);
+
+        let (mut queue, replayed) = Queue::open(&dir, QueueOptions::default())?;
+        assert_eq!(topics(&replayed), ["b", "d"]);
+        assert_eq!((replayed[0].seq, replayed[0].retain), (1, true));
+        assert_eq!(replayed[1].payload, b"d");
+        // Numbering goes on where it stopped
+        assert_eq!(queue.push("e", b"e", Qos::AtMostOnce, false)?, 4);
+        drop(queue);
+
+        // Cut the last record short, then flip a byte of the first one
+        let (_, path) = segments(&dir)?.pop().unwrap();
+        let mut bytes = fs::read(&path)?;
+        bytes.pop();
+        bytes[MAGIC.len() + 9] ^= 0xFF;
+        fs::write(&path, &bytes)?;
+        let (mut queue, replayed) = Queue::open(&dir, QueueOptions::default())?;
+        assert_eq!(topics(&replayed), ["b", "d"]);
+        // The truncated record doesn't hide the ones appended after it
+        queue.push("f", b"f", Qos::AtMostOnce, false)?;
+        drop(queue);
+        let (_, replayed) = Queue::open(&dir, QueueOptions::default())?;
+        assert_eq!(topics(&replayed), ["b", "d", "f"]);
+
+        let (infos, pending) = inspect(&dir)?;
+        assert_eq!(pending, replayed);
+        assert_eq!(infos.len(), 1);
+        assert_eq!(
+            (infos[0].messages, infos[0].acked, infos[0].corrupt),
+            (4, 1, 1)
+        );
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn test_queue_segments() -> io::Result<()> {
+        let dir = temp_dir("segments");
+        let options = QueueOptions {
+            segment_size: 64,
+            ..QueueOptions::default()
+        };
+        let (mut queue, _) = Queue::open(&dir, options.clone())?;
+        for n in 0..6 {
+            queue.push("t", &[n; 40], Qos::AtLeastOnce, false)?;
+        }
+        // A segment per message past the first record
+        assert_eq!(segments(&dir)?.len(), 6);
+        queue.ack(1)?;
+        assert_eq!(segments(&dir)?.len(), 6);
+        queue.ack(0)?;
+        // Only the oldest segments go, the one appended to stays
+        assert_eq!(segments(&dir)?.len(), 4);
+        for n in 2..6 {
+            queue.ack(n)?;
+        }
+        assert_eq!(segments(&dir)?.len(), 1);
+        assert!(queue.is_empty());
+        drop(queue);
+
+        let (mut queue, replayed) = Queue::open(
+            &dir,
+            QueueOptions {
+                max_size: Some(240),
+                ..options
+            },
+        )?;
+        assert!(replayed.is_empty());
+        for n in 0..6 {
+            queue.push("t", &[n; 40], Qos::AtLeastOnce, false)?;
+        }
+        // Three segments of 78 bytes fit in 240, the messages of the others are lost
+        assert_eq!(queue.expired(), 3);
+        drop(queue);
+        let (infos, pending) = inspect(&dir)?;
+        assert_eq!(infos.len(), 3);
+        assert_eq!(
+            pending.iter().map(|m| m.payload[0]).collect::<Vec<_>>(),
+            [3, 4, 5]
+        );
+        fs::remove_dir_all(&dir)
+    }
+}
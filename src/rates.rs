@@ -0,0 +1,202 @@
+use crate::topic::TopicTree;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct Counters {
+    messages: u64,
+    bytes: u64,
+    total_messages: u64,
+    total_bytes: u64,
+}
+
+/// Rates of a topic, or of a group of topics, over the last interval
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicRate {
+    pub topic: String,
+    pub messages_per_sec: f64,
+    pub bytes_per_sec: f64,
+    /// Average payload size over the interval, or since the start for
+    /// topics silent during the interval
+    pub average_size: f64,
+    pub total_messages: u64,
+}
+
+/// Counts messages and payload bytes per topic, between two calls to
+/// `rates` and since the start. Topics matching a group filter are counted
+/// together under the filter, as `sensors/+/temp`
+#[derive(Debug)]
+pub struct TopicRates {
+    groups: TopicTree<String>,
+    topics: HashMap<String, Counters>,
+    since: Instant,
+}
+
+impl TopicRates {
+    pub fn new(groups: &[String], now: Instant) -> Self {
+        let mut tree = TopicTree::new();
+        for group in groups {
+            tree.insert(group, group.clone());
+        }
+        Self {
+            groups: tree,
+            topics: HashMap::new(),
+            since: now,
+        }
+    }
+
+    pub fn record(&mut self, topic: &str, payload_len: usize) {
+        // The longest filter is taken as the most specific when several match
+        let key = self
+            .groups
+            .matches(topic)
+            .into_iter()
+            .max_by_key(|group| (group.len(), group.as_str()))
+            .map_or(topic, |group| group.as_str());
+        let counters = match self.topics.get_mut(key) {
+            Some(counters) => counters,
+            None => self.topics.entry(key.to_string()).or_default(),
+        };
+        counters.messages += 1;
+        counters.bytes += payload_len as u64;
+        counters.total_messages += 1;
+        counters.total_bytes += payload_len as u64;
+    }
+
+    /// Rates since the previous call of the `top` busiest topics, by
+    /// messages then bytes per second, and start a new interval
+    pub fn rates(&mut self, now: Instant, top: usize) -> Vec<TopicRate> {
+        let elapsed = now
+            .saturating_duration_since(self.since)
+            .max(Duration::from_millis(1))
+            .as_secs_f64();
+        self.since = now;
+        let mut rates: Vec<TopicRate> = self
+            .topics
+            .iter_mut()
+            .map(|(topic, counters)| {
+                let (messages, bytes) = match counters.messages {
+                    0 => (counters.total_messages, counters.total_bytes),
+                    _ => (counters.messages, counters.bytes),
+                };
+                let rate = TopicRate {
+                    topic: topic.clone(),
+                    messages_per_sec: counters.messages as f64 / elapsed,
+                    bytes_per_sec: counters.bytes as f64 / elapsed,
+                    average_size: bytes as f64 / messages.max(1) as f64,
+                    total_messages: counters.total_messages,
+                };
+                counters.messages = 0;
+                counters.bytes = 0;
+                rate
+            })
+            .collect();
+        rates.sort_by(|a, b| {
+            b.messages_per_sec
+                .total_cmp(&a.messages_per_sec)
+                .then(b.bytes_per_sec.total_cmp(&a.bytes_per_sec))
+                .then_with(|| a.topic.cmp(&b.topic))
+        });
+        rates.truncate(top);
+        rates
+    }
+
+    /// Topics, or groups, seen so far
+    pub fn len(&self) -> usize {
+        self.topics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+}
+
+/// Aligned table of rates, one topic per row
+pub struct RateTable<'a>(pub &'a [TopicRate]);
+
+impl fmt::Display for RateTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .0
+            .iter()
+            .map(|rate| rate.topic.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("TOPIC".len());
+        write!(
+            f,
+            "{:<width$}  {:>10}  {:>12}  {:>9}  {:>10}",
+            "TOPIC", "MSG/S", "BYTES/S", "AVG SIZE", "TOTAL"
+        )?;
+        for rate in self.0 {
+            write!(
+                f,
+                "\n{:<width$}  {:>10.1}  {:>12.1}  {:>9.1}  {:>10}",
+                rate.topic,
+                rate.messages_per_sec,
+                rate.bytes_per_sec,
+                rate.average_size,
+                rate.total_messages
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rates_tests {
+    use super::*;
+
+    #[test]
+    fn test_rates() {
+        let start = Instant::now();
+        let mut rates = TopicRates::new(&["sensors/+/temp".to_string()], start);
+        for _ in 0..4 {
+            rates.record("a", 10);
+        }
+        rates.record("b", 100);
+        rates.record("sensors/1/temp", 2);
+        rates.record("sensors/2/temp", 4);
+        assert_eq!(rates.len(), 3);
+
+        let interval = rates.rates(start + Duration::from_secs(2), 2);
+        assert_eq!(
+            interval,
+            [
+                TopicRate {
+                    topic: "a".to_string(),
+                    messages_per_sec: 2.0,
+                    bytes_per_sec: 20.0,
+                    average_size: 10.0,
+                    total_messages: 4,
+                },
+                TopicRate {
+                    topic: "sensors/+/temp".to_string(),
+                    messages_per_sec: 1.0,
+                    bytes_per_sec: 3.0,
+                    average_size: 3.0,
+                    total_messages: 2,
+                },
+            ]
+        );
+
+        // Silent topics keep their average size and total
+        rates.record("b", 50);
+        let interval = rates.rates(start + Duration::from_secs(3), 3);
+        assert_eq!(interval[0].topic, "b");
+        assert_eq!(interval[0].messages_per_sec, 1.0);
+        assert_eq!(interval[1].topic, "a");
+        assert_eq!(
+            (interval[1].messages_per_sec, interval[1].average_size),
+            (0.0, 10.0)
+        );
+
+        let table = RateTable(&interval[..1]).to_string();
+        assert_eq!(
+            table,
+            "TOPIC       MSG/S       BYTES/S   AVG SIZE       TOTAL\n\
+             b             1.0          50.0       50.0           2"
+        );
+    }
+}
@@ -0,0 +1,962 @@
+use crate::compress::crc32;
+use crate::mqtt::{AckType, Dedup, Protocol, PublishOptions, Qos, Response, SUBACK_FAILURE};
+use crate::topic::{self, RewriteRule, TopicTree};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Start of every recording, the last byte being the format version
+const MAGIC: &[u8; 4] = b"SKR\x01";
+/// End of a recording closed cleanly, after the offset of its index
+const FOOTER_MAGIC: &[u8; 4] = b"SKRX";
+const FOOTER_LEN: u64 = 12;
+/// Magic and flags
+const HEADER_LEN: u64 = 5;
+/// Tag, first timestamp, message count, stored length and checksum
+const BLOCK_HEADER_LEN: u64 = 21;
+
+const TAG_BLOCK: u8 = 1;
+const TAG_INDEX: u8 = 2;
+
+/// The blocks of the recording are zstd frames
+const FLAG_ZSTD: u8 = 0x01;
+
+/// Messages are gathered into blocks of about this many bytes, each one
+/// compressed on its own and listed in the index
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Longest a message waits in memory before its block is written, what a
+/// crash of the recorder may lose
+pub const BLOCK_AGE: Duration = Duration::from_secs(1);
+
+/// How often `record` checks whether it was asked to stop
+const POLL: Duration = Duration::from_millis(200);
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn micros(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+/// A message as it was received by the recorder
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recorded {
+    pub timestamp: SystemTime,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: Qos,
+    pub retain: bool,
+}
+
+impl Recorded {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_u64::<BigEndian>(micros(self.timestamp))?;
+        writer.write_u8(u8::from(&self.qos))?;
+        writer.write_u8(self.retain as u8)?;
+        writer.write_u16::<BigEndian>(self.topic.len() as u16)?;
+        writer.write_all(self.topic.as_bytes())?;
+        writer.write_u32::<BigEndian>(self.payload.len() as u32)?;
+        writer.write_all(&self.payload)
+    }
+
+    fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let timestamp = UNIX_EPOCH + Duration::from_micros(reader.read_u64::<BigEndian>()?);
+        let qos = match reader.read_u8()? {
+            qos @ 0..=2 => Qos::from(qos),
+            _ => return Err(invalid("Invalid QoS")),
+        };
+        let retain = reader.read_u8()? != 0;
+        let mut topic = vec![0; reader.read_u16::<BigEndian>()? as usize];
+        reader.read_exact(&mut topic)?;
+        let topic =
+            String::from_utf8(topic).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut payload = vec![0; reader.read_u32::<BigEndian>()? as usize];
+        reader.read_exact(&mut payload)?;
+        Ok(Self {
+            timestamp,
+            topic,
+            payload,
+            qos,
+            retain,
+        })
+    }
+}
+
+/// Writes one recording: a header, blocks of messages and, once
+/// `finish`ed, an index of the blocks by timestamp at the end
+#[derive(Debug)]
+pub struct RecordWriter<W: Write> {
+    inner: W,
+    compress: bool,
+    /// Messages not written yet, encoded
+    block: Vec<u8>,
+    block_count: u32,
+    /// Timestamp of the first message of `block`, and when it was added
+    block_start: Option<(u64, Instant)>,
+    /// Bytes written to `inner`
+    offset: u64,
+    /// First timestamp and offset of every block written
+    index: Vec<(u64, u64)>,
+    messages: u64,
+}
+
+impl<W: Write> RecordWriter<W> {
+    /// Start a recording, with zstd compressed blocks if `compress`
+    pub fn new(mut inner: W, compress: bool) -> io::Result<Self> {
+        if compress && !cfg!(feature = "zstd") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Compressed recordings need sake built with the zstd feature",
+            ));
+        }
+        inner.write_all(MAGIC)?;
+        inner.write_u8(if compress { FLAG_ZSTD } else { 0 })?;
+        Ok(Self {
+            inner,
+            compress,
+            block: Vec::new(),
+            block_count: 0,
+            block_start: None,
+            offset: HEADER_LEN,
+            index: Vec::new(),
+            messages: 0,
+        })
+    }
+
+    pub fn append(&mut self, message: &Recorded) -> io::Result<()> {
+        if message.topic.len() > u16::MAX as usize || message.payload.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Message on {} too large to record", message.topic),
+            ));
+        }
+        self.block_start
+            .get_or_insert_with(|| (micros(message.timestamp), Instant::now()));
+        message.write(&mut self.block)?;
+        self.block_count += 1;
+        self.messages += 1;
+        if self.block.len() >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Write the messages held in memory once the oldest waited
+    /// `BLOCK_AGE`, to be called while no message arrives
+    pub fn tick(&mut self) -> io::Result<()> {
+        if matches!(self.block_start, Some((_, since)) if since.elapsed() >= BLOCK_AGE) {
+            self.write_block()?;
+            self.inner.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes of the recording so far, the messages held in memory included
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn messages(&self) -> u64 {
+        self.messages
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let Some((first, _)) = self.block_start.take() else {
+            return Ok(());
+        };
+        let stored = match self.compress {
+            #[cfg(feature = "zstd")]
+            true => zstd::bulk::compress(&self.block, 0)?,
+            _ => std::mem::take(&mut self.block),
+        };
+        self.inner.write_u8(TAG_BLOCK)?;
+        self.inner.write_u64::<BigEndian>(first)?;
+        self.inner.write_u32::<BigEndian>(self.block_count)?;
+        self.inner.write_u32::<BigEndian>(stored.len() as u32)?;
+        self.inner.write_u32::<BigEndian>(crc32(&stored))?;
+        self.inner.write_all(&stored)?;
+        self.index.push((first, self.offset));
+        self.offset += BLOCK_HEADER_LEN + stored.len() as u64;
+        self.block.clear();
+        self.block_count = 0;
+        Ok(())
+    }
+
+    /// Write the last block and the index, returning the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        let index_offset = self.offset;
+        self.inner.write_u8(TAG_INDEX)?;
+        self.inner.write_u32::<BigEndian>(self.index.len() as u32)?;
+        for (timestamp, offset) in &self.index {
+            self.inner.write_u64::<BigEndian>(*timestamp)?;
+            self.inner.write_u64::<BigEndian>(*offset)?;
+        }
+        self.inner.write_u64::<BigEndian>(index_offset)?;
+        self.inner.write_all(FOOTER_MAGIC)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads a recording back, from its start or from the block holding a point
+/// in time. The index locates the block without reading the file, and a
+/// recording cut short by a crash, without one, has its block headers
+/// scanned instead
+#[derive(Debug)]
+pub struct RecordReader<R> {
+    inner: R,
+    compressed: bool,
+    /// First timestamp and offset of every block
+    blocks: Vec<(u64, u64)>,
+    indexed: bool,
+    next_block: usize,
+    pending: VecDeque<Recorded>,
+    /// Messages older than this are skipped, in microseconds
+    from: u64,
+}
+
+impl<R: Read + Seek> RecordReader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a sake recording"));
+        }
+        let compressed = match inner.read_u8()? {
+            0 => false,
+            FLAG_ZSTD if cfg!(feature = "zstd") => true,
+            FLAG_ZSTD => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "The recording is compressed, it needs sake built with the zstd feature",
+                ))
+            }
+            flags => return Err(invalid(&format!("Unknown recording flags 0x{:02X}", flags))),
+        };
+        let len = inner.seek(SeekFrom::End(0))?;
+        let mut reader = Self {
+            inner,
+            compressed,
+            blocks: Vec::new(),
+            indexed: false,
+            next_block: 0,
+            pending: VecDeque::new(),
+            from: 0,
+        };
+        match reader.read_index(len)? {
+            Some(blocks) => {
+                reader.blocks = blocks;
+                reader.indexed = true;
+            }
+            None => reader.blocks = reader.scan_blocks(len)?,
+        }
+        Ok(reader)
+    }
+
+    /// The blocks listed by the index the footer points to, `None` if the
+    /// recording has no footer
+    fn read_index(&mut self, len: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+        if len < HEADER_LEN + FOOTER_LEN {
+            return Ok(None);
+        }
+        self.inner.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+        let index_offset = self.inner.read_u64::<BigEndian>()?;
+        let mut magic = [0; 4];
+        self.inner.read_exact(&mut magic)?;
+        if &magic != FOOTER_MAGIC || index_offset < HEADER_LEN || index_offset >= len {
+            return Ok(None);
+        }
+        self.inner.seek(SeekFrom::Start(index_offset))?;
+        if self.inner.read_u8()? != TAG_INDEX {
+            return Err(invalid("Recording footer not pointing to its index"));
+        }
+        let count = self.inner.read_u32::<BigEndian>()? as u64;
+        if index_offset + 5 + count * 16 + FOOTER_LEN != len {
+            return Err(invalid("Recording index of the wrong size"));
+        }
+        let mut blocks = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let timestamp = self.inner.read_u64::<BigEndian>()?;
+            let offset = self.inner.read_u64::<BigEndian>()?;
+            blocks.push((timestamp, offset));
+        }
+        Ok(Some(blocks))
+    }
+
+    /// The blocks found walking the block headers, up to the first one cut
+    /// short
+    fn scan_blocks(&mut self, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        let mut blocks = vec![];
+        let mut offset = HEADER_LEN;
+        while offset + BLOCK_HEADER_LEN <= len {
+            self.inner.seek(SeekFrom::Start(offset))?;
+            if self.inner.read_u8()? != TAG_BLOCK {
+                break;
+            }
+            let timestamp = self.inner.read_u64::<BigEndian>()?;
+            let _count = self.inner.read_u32::<BigEndian>()?;
+            let stored_len = self.inner.read_u32::<BigEndian>()? as u64;
+            let end = offset + BLOCK_HEADER_LEN + stored_len;
+            if end > len {
+                break;
+            }
+            blocks.push((timestamp, offset));
+            offset = end;
+        }
+        Ok(blocks)
+    }
+
+    /// Whether the recording was closed cleanly, with its index
+    pub fn is_indexed(&self) -> bool {
+        self.indexed
+    }
+
+    /// Timestamp of the first message, `None` if there's none
+    pub fn start(&self) -> Option<SystemTime> {
+        self.blocks
+            .first()
+            .map(|&(timestamp, _)| UNIX_EPOCH + Duration::from_micros(timestamp))
+    }
+
+    /// Continue from the first message received at or after `t`, reading
+    /// only the block holding it
+    pub fn seek(&mut self, t: SystemTime) {
+        self.from = micros(t);
+        let after = self
+            .blocks
+            .partition_point(|&(timestamp, _)| timestamp <= self.from);
+        self.next_block = after.saturating_sub(1);
+        self.pending.clear();
+    }
+
+    pub fn next_message(&mut self) -> io::Result<Option<Recorded>> {
+        loop {
+            while let Some(message) = self.pending.pop_front() {
+                if micros(message.timestamp) >= self.from {
+                    return Ok(Some(message));
+                }
+            }
+            let Some(&(_, offset)) = self.blocks.get(self.next_block) else {
+                return Ok(None);
+            };
+            self.next_block += 1;
+            self.read_block(offset)?;
+        }
+    }
+
+    fn read_block(&mut self, offset: u64) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        if self.inner.read_u8()? != TAG_BLOCK {
+            return Err(invalid("Recording index not pointing to a block"));
+        }
+        let _first = self.inner.read_u64::<BigEndian>()?;
+        let count = self.inner.read_u32::<BigEndian>()?;
+        let mut stored = vec![0; self.inner.read_u32::<BigEndian>()? as usize];
+        let crc = self.inner.read_u32::<BigEndian>()?;
+        self.inner.read_exact(&mut stored)?;
+        if crc32(&stored) != crc {
+            return Err(invalid("Recording block failing its checksum"));
+        }
+        let raw = match self.compressed {
+            #[cfg(feature = "zstd")]
+            true => zstd::decode_all(stored.as_slice())?,
+            _ => stored,
+        };
+        let mut raw = raw.as_slice();
+        for _ in 0..count {
+            self.pending.push_back(Recorded::read(&mut raw)?);
+        }
+        if !raw.is_empty() {
+            return Err(invalid("Trailing bytes in recording block"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Iterator for RecordReader<R> {
+    type Item = io::Result<Recorded>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_message().transpose()
+    }
+}
+
+/// When a recorder moves on to a new file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotate {
+    /// Once the file grows past this many bytes
+    Size(u64),
+    /// Once a message arrives this long after the first one of the file
+    Every(Duration),
+}
+
+impl std::str::FromStr for Rotate {
+    type Err = io::Error;
+
+    /// A size as `100MB` or a duration as `1h`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid rotation {}, expected a size as 100MB or a duration as 1h",
+                    s
+                ),
+            )
+        };
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (value, unit) = s.split_at(split);
+        let value: u64 = value.parse().map_err(|_| invalid())?;
+        let rotate = match unit.to_ascii_uppercase().as_str() {
+            "B" => Rotate::Size(value),
+            "KB" | "KIB" => Rotate::Size(value << 10),
+            "MB" | "MIB" => Rotate::Size(value << 20),
+            "GB" | "GIB" => Rotate::Size(value << 30),
+            "S" => Rotate::Every(Duration::from_secs(value)),
+            "M" => Rotate::Every(Duration::from_secs(value * 60)),
+            "H" => Rotate::Every(Duration::from_secs(value * 3600)),
+            "D" => Rotate::Every(Duration::from_secs(value * 86_400)),
+            _ => return Err(invalid()),
+        };
+        match rotate {
+            Rotate::Size(0) => Err(invalid()),
+            Rotate::Every(Duration::ZERO) => Err(invalid()),
+            rotate => Ok(rotate),
+        }
+    }
+}
+
+/// Compression and rotation of the files of a `Recorder`
+#[derive(Debug, Clone, Default)]
+pub struct RecordOptions {
+    /// zstd compress the blocks of messages, needs the zstd feature
+    pub compress: bool,
+    pub rotate: Option<Rotate>,
+}
+
+/// Writes the messages received to a recording, or with rotation to
+/// several numbered after it, `session.skr` becoming `session.0001.skr`,
+/// `session.0002.skr` and so on, which sort in order
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    options: RecordOptions,
+    writer: Option<RecordWriter<BufWriter<File>>>,
+    /// Timestamp of the first message of the current file
+    started: Option<SystemTime>,
+    paths: Vec<PathBuf>,
+}
+
+impl Recorder {
+    /// Create the first file right away, so that a path that can't be
+    /// written fails before anything is received
+    pub fn create(path: &Path, options: RecordOptions) -> io::Result<Self> {
+        let mut recorder = Self {
+            path: path.to_path_buf(),
+            options,
+            writer: None,
+            started: None,
+            paths: Vec::new(),
+        };
+        recorder.open()?;
+        Ok(recorder)
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let path = match self.options.rotate {
+            None => self.path.clone(),
+            Some(_) => numbered(&self.path, self.paths.len() + 1),
+        };
+        let file = BufWriter::new(File::create(&path)?);
+        self.writer = Some(RecordWriter::new(file, self.options.compress)?);
+        self.started = None;
+        self.paths.push(path);
+        Ok(())
+    }
+
+    pub fn append(&mut self, message: &Recorded) -> io::Result<()> {
+        let writer = self.writer.as_ref().expect("open until finished");
+        let due = match (self.options.rotate, self.started) {
+            (Some(Rotate::Size(max)), Some(_)) => writer.size() >= max,
+            (Some(Rotate::Every(every)), Some(started)) => message
+                .timestamp
+                .duration_since(started)
+                .is_ok_and(|elapsed| elapsed >= every),
+            _ => false,
+        };
+        if due {
+            self.writer.take().unwrap().finish()?;
+            self.open()?;
+        }
+        self.started.get_or_insert(message.timestamp);
+        self.writer.as_mut().unwrap().append(message)
+    }
+
+    /// See `RecordWriter::tick`
+    pub fn tick(&mut self) -> io::Result<()> {
+        self.writer.as_mut().expect("open until finished").tick()
+    }
+
+    /// Files written so far, the current one last
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Close the current file with its index, returning every file written
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        self.writer.take().expect("open until finished").finish()?;
+        Ok(self.paths)
+    }
+}
+
+/// `path` with `n` inserted before its extension
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{:04}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}.{:04}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+/// Subscribe to `filters` and record every message received until `stop` is
+/// raised, returning how many were. The broker disconnecting is an error,
+/// the recorder still holds what came before it
+pub fn record(
+    client: &mut Protocol,
+    filters: &[String],
+    recorder: &mut Recorder,
+    stop: &AtomicBool,
+) -> io::Result<u64> {
+    for filter in filters {
+        client.subscribe(filter, Qos::ExactlyOnce)?;
+    }
+    client.set_read_timeout(Some(POLL))?;
+    let mut qos2 = Dedup::default();
+    let mut recorded = 0;
+    while !stop.load(Ordering::Acquire) {
+        let response = match client.read_message::<Response>() {
+            Ok(response) => response,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                recorder.tick()?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        match response {
+            Response::Suback { return_codes, .. } if return_codes.contains(&SUBACK_FAILURE) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Subscription refused",
+                ));
+            }
+            Response::Publish {
+                packet_id,
+                qos,
+                retain,
+                topic,
+                payload,
+                ..
+            } => {
+                match qos {
+                    1 => client.ack(AckType::Puback(packet_id))?,
+                    2 => {
+                        client.ack(AckType::Pubrec(packet_id))?;
+                        if !qos2.arrived(packet_id) {
+                            continue;
+                        }
+                    }
+                    _ => {}
+                }
+                recorder.append(&Recorded {
+                    timestamp: SystemTime::now(),
+                    topic,
+                    payload,
+                    qos: Qos::from(qos),
+                    retain,
+                })?;
+                recorded += 1;
+            }
+            Response::Pubrel { packet_id } => {
+                client.ack(AckType::Pubcomp(packet_id))?;
+                qos2.released(&packet_id);
+            }
+            _ => {}
+        }
+        recorder.tick()?;
+    }
+    Ok(recorded)
+}
+
+/// What part of recordings is replayed, where and how fast
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Start from the first message received at or after this time, the
+    /// beginning of the recordings if `None`
+    pub from: Option<SystemTime>,
+    /// Start this long after the first message of the recordings, the later
+    /// of the two if `from` is given too
+    pub offset: Option<Duration>,
+    /// Only replay the messages on topics matching one of these filters,
+    /// all of them if empty
+    pub filters: Vec<String>,
+    /// Applied to the topics of the messages replayed, see `topic::rewrite`
+    pub rewrites: Vec<RewriteRule>,
+    /// 2.0 replays twice as fast as recorded, `None` publishes without
+    /// waiting between messages
+    pub speed: Option<f64>,
+    pub ack_timeout: Duration,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            from: None,
+            offset: None,
+            filters: vec![],
+            rewrites: vec![],
+            speed: Some(1.0),
+            ack_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Publish the messages of the recordings at `paths` one after the other,
+/// each at its own QoS and retain flag and spaced as they were received.
+/// Returns how many were published
+pub fn replay(
+    client: &mut Protocol,
+    paths: &[PathBuf],
+    options: &ReplayOptions,
+) -> io::Result<u64> {
+    let readers = paths
+        .iter()
+        .map(|path| RecordReader::new(BufReader::new(File::open(path)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+    let start = readers.iter().find_map(RecordReader::start);
+    let offset = options
+        .offset
+        .zip(start)
+        .map(|(offset, start)| start + offset);
+    let from = options.from.into_iter().chain(offset).max();
+    let mut filters = TopicTree::new();
+    for filter in &options.filters {
+        filters.insert(filter, ());
+    }
+    let mut clock: Option<(SystemTime, Instant)> = None;
+    let mut replayed = 0;
+    for mut reader in readers {
+        if let Some(from) = from {
+            reader.seek(from);
+        }
+        for message in reader {
+            let message = message?;
+            if !filters.is_empty() && filters.matches(&message.topic).is_empty() {
+                continue;
+            }
+            if let Some(speed) = options.speed {
+                let (first, started) =
+                    *clock.get_or_insert_with(|| (message.timestamp, Instant::now()));
+                let offset = message.timestamp.duration_since(first).unwrap_or_default();
+                let due = started + offset.div_f64(speed);
+                if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                    std::thread::sleep(wait);
+                }
+            }
+            let publish = PublishOptions {
+                qos: message.qos,
+                ack_timeout: Some(options.ack_timeout),
+                retries: 2,
+                retain: message.retain,
+            };
+            let topic = topic::rewrite(&options.rewrites, &message.topic);
+            client.publish_with(&topic, &message.payload, &publish)?;
+            replayed += 1;
+        }
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod record_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+    use std::io::Cursor;
+
+    fn message(secs: u64, topic: &str, payload: &[u8]) -> Recorded {
+        Recorded {
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos: Qos::AtLeastOnce,
+            retain: secs.is_multiple_of(2),
+        }
+    }
+
+    /// A recording of a message per second from `1_000_000`, each `size`
+    /// bytes, so that several blocks are written
+    fn recording(count: u64, size: usize, compress: bool) -> io::Result<Vec<u8>> {
+        let mut writer = RecordWriter::new(Vec::new(), compress)?;
+        for i in 0..count {
+            let payload = vec![(i % 251) as u8; size];
+            writer.append(&message(1_000_000 + i, &format!("sensors/{}", i), &payload))?;
+        }
+        writer.finish()
+    }
+
+    fn topics(reader: RecordReader<Cursor<Vec<u8>>>) -> io::Result<Vec<String>> {
+        reader.map(|message| Ok(message?.topic)).collect()
+    }
+
+    #[test]
+    fn test_round_trip() -> io::Result<()> {
+        let bytes = recording(100, 4000, false)?;
+        let mut reader = RecordReader::new(Cursor::new(bytes))?;
+        assert!(reader.is_indexed());
+        assert!(reader.blocks.len() > 1);
+        assert_eq!(
+            reader.start(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_000_000))
+        );
+        let first = reader.next_message()?.unwrap();
+        assert_eq!(first, message(1_000_000, "sensors/0", &[0; 4000]));
+        assert_eq!(reader.count(), 99);
+        Ok(())
+    }
+
+    #[test]
+    fn test_seek() -> io::Result<()> {
+        let bytes = recording(100, 4000, false)?;
+        let mut reader = RecordReader::new(Cursor::new(bytes.clone()))?;
+        reader.seek(UNIX_EPOCH + Duration::from_secs(1_000_042));
+        let seeked = topics(reader)?;
+        assert_eq!(seeked.len(), 58);
+        assert_eq!(seeked[0], "sensors/42");
+
+        // Past the end, then before the start
+        let mut reader = RecordReader::new(Cursor::new(bytes.clone()))?;
+        reader.seek(UNIX_EPOCH + Duration::from_secs(2_000_000));
+        assert!(reader.next_message()?.is_none());
+        reader.seek(UNIX_EPOCH);
+        assert_eq!(reader.count(), 100);
+
+        // Cut short by a crash, before the index and within a block: the
+        // complete blocks are found by their headers
+        let mut cut = bytes;
+        let index = u64::from_be_bytes(cut[cut.len() - 12..cut.len() - 4].try_into().unwrap());
+        cut.truncate(index as usize - 10);
+        let mut reader = RecordReader::new(Cursor::new(cut))?;
+        assert!(!reader.is_indexed());
+        reader.seek(UNIX_EPOCH + Duration::from_secs(1_000_042));
+        let scanned = topics(reader)?;
+        assert_eq!(scanned[0], "sensors/42");
+        assert!(scanned.len() < 58);
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_block() -> io::Result<()> {
+        let mut bytes = recording(3, 10, false)?;
+        bytes[HEADER_LEN as usize + BLOCK_HEADER_LEN as usize + 2] ^= 0xFF;
+        let mut reader = RecordReader::new(Cursor::new(bytes))?;
+        let err = reader.next_message().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(RecordReader::new(Cursor::new(b"SKQ\x01\x00".to_vec())).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compressed() -> io::Result<()> {
+        let plain = recording(100, 4000, false)?;
+        let compressed = recording(100, 4000, true)?;
+        assert!(compressed.len() * 10 < plain.len());
+        let mut reader = RecordReader::new(Cursor::new(compressed))?;
+        reader.seek(UNIX_EPOCH + Duration::from_secs(1_000_090));
+        assert_eq!(topics(reader)?.len(), 10);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn test_compressed_unsupported() {
+        let err = RecordWriter::new(Vec::new(), true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let err = RecordReader::new(Cursor::new(b"SKR\x01\x01".to_vec())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_rotate() -> io::Result<()> {
+        assert_eq!("100MB".parse::<Rotate>()?, Rotate::Size(100 << 20));
+        assert_eq!("512kb".parse::<Rotate>()?, Rotate::Size(512 << 10));
+        assert_eq!(
+            "1h".parse::<Rotate>()?,
+            Rotate::Every(Duration::from_secs(3600))
+        );
+        for invalid in ["", "100", "0MB", "1w", "MB", "-1h"] {
+            assert!(invalid.parse::<Rotate>().is_err(), "{}", invalid);
+        }
+
+        let dir = std::env::temp_dir().join(format!("sake-record-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("session.skr");
+        let hourly = RecordOptions {
+            rotate: Some(Rotate::Every(Duration::from_secs(3600))),
+            ..RecordOptions::default()
+        };
+        let mut recorder = Recorder::create(&path, hourly)?;
+        for minutes in [0, 30, 59, 60, 61, 200] {
+            recorder.append(&message(1_000_000 + minutes * 60, "t", b""))?;
+        }
+        let paths = recorder.finish()?;
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[0], dir.join("session.0001.skr"));
+        let counts = paths
+            .iter()
+            .map(|path| Ok(RecordReader::new(File::open(path)?)?.count()))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(counts, vec![3, 2, 1]);
+
+        let by_size = RecordOptions {
+            rotate: Some(Rotate::Size(10_000)),
+            ..RecordOptions::default()
+        };
+        let mut recorder = Recorder::create(&dir.join("sized"), by_size)?;
+        for i in 0..10 {
+            recorder.append(&message(1_000_000 + i, "t", &[0; 3000]))?;
+        }
+        let paths = recorder.finish()?;
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[2], dir.join("sized.0003"));
+        std::fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn test_record_and_replay() -> io::Result<()> {
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let addr = broker.local_addr()?;
+        std::thread::spawn(move || broker.run());
+        let connect = |client_id: &str| {
+            Protocol::builder()
+                .addrs(&[addr])
+                .client_id(client_id)
+                .connect()
+        };
+        let path = std::env::temp_dir().join(format!("sake-replay-{}.skr", std::process::id()));
+        let mut recorder = Recorder::create(&path, RecordOptions::default())?;
+        let stop = std::sync::Arc::new(AtomicBool::new(false));
+        let recording = std::thread::spawn({
+            let stop = std::sync::Arc::clone(&stop);
+            let mut client = connect("recorder")?;
+            move || {
+                let recorded = record(&mut client, &["in/#".to_string()], &mut recorder, &stop);
+                (recorded, recorder)
+            }
+        });
+        std::thread::sleep(Duration::from_millis(300));
+        let mut publisher = connect("publisher")?;
+        for i in 0..3u8 {
+            publisher.publish_with(
+                "in/data",
+                &[i],
+                &PublishOptions {
+                    qos: Qos::AtLeastOnce,
+                    ack_timeout: Some(Duration::from_secs(5)),
+                    retries: 0,
+                    retain: false,
+                },
+            )?;
+        }
+        std::thread::sleep(Duration::from_millis(300));
+        stop.store(true, Ordering::Release);
+        let (recorded, recorder) = recording.join().unwrap();
+        assert_eq!(recorded?, 3);
+        let paths = recorder.finish()?;
+
+        let mut subscriber = connect("subscriber")?;
+        subscriber.subscribe("in/#", Qos::AtMostOnce)?;
+        subscriber.set_read_timeout(Some(Duration::from_secs(5)))?;
+        assert!(matches!(
+            subscriber.read_message::<Response>()?,
+            Response::Suback { .. }
+        ));
+        let mut replayer = connect("replayer")?;
+        let options = ReplayOptions {
+            speed: None,
+            ..ReplayOptions::default()
+        };
+        assert_eq!(replay(&mut replayer, &paths, &options)?, 3);
+        for i in 0..3u8 {
+            match subscriber.read_message::<Response>()? {
+                Response::Publish { topic, payload, .. } => {
+                    assert_eq!((topic.as_str(), payload), ("in/data", vec![i]))
+                }
+                response => panic!("Unexpected response {:?}", response),
+            }
+        }
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_partial_replay() -> io::Result<()> {
+        let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+        let addr = broker.local_addr()?;
+        std::thread::spawn(move || broker.run());
+        let connect = |client_id: &str| {
+            Protocol::builder()
+                .addrs(&[addr])
+                .client_id(client_id)
+                .connect()
+        };
+        let path = std::env::temp_dir().join(format!("sake-partial-{}.skr", std::process::id()));
+        let mut recorder = Recorder::create(&path, RecordOptions::default())?;
+        for i in 0..10 {
+            let topic = match i % 2 {
+                0 => format!("sensors/{}", i),
+                _ => format!("actuators/{}", i),
+            };
+            recorder.append(&message(1_000_000 + i, &topic, b"x"))?;
+        }
+        let paths = recorder.finish()?;
+
+        let mut subscriber = connect("subscriber")?;
+        subscriber.subscribe("#", Qos::AtMostOnce)?;
+        subscriber.set_read_timeout(Some(Duration::from_secs(5)))?;
+        assert!(matches!(
+            subscriber.read_message::<Response>()?,
+            Response::Suback { .. }
+        ));
+        let options = ReplayOptions {
+            offset: Some(Duration::from_secs(5)),
+            filters: vec!["sensors/#".to_string()],
+            rewrites: vec!["sensors/# -> replayed/#".parse()?],
+            speed: None,
+            ..ReplayOptions::default()
+        };
+        assert_eq!(replay(&mut connect("replayer")?, &paths, &options)?, 2);
+        for expected in ["replayed/6", "replayed/8"] {
+            match subscriber.read_message::<Response>()? {
+                Response::Publish { topic, .. } => assert_eq!(topic, expected),
+                response => panic!("Unexpected response {:?}", response),
+            }
+        }
+
+        // A later --from wins over the offset
+        let options = ReplayOptions {
+            from: Some(UNIX_EPOCH + Duration::from_secs(1_000_007)),
+            ..options
+        };
+        assert_eq!(replay(&mut connect("replayer")?, &paths, &options)?, 1);
+        std::fs::remove_file(&path)
+    }
+}
@@ -0,0 +1,368 @@
+use std::fmt;
+use std::io;
+
+/// Repetitions a `{n}` or `{n,m}` quantifier may ask for
+const MAX_REPEAT: usize = 1000;
+
+/// A small backtracking regular expression, enough to match payloads from
+/// the command line: literals, `.`, classes like `[a-z0-9_]` and `[^,]`,
+/// `\d \w \s` and their negations, anchors `^ $`, groups with alternatives
+/// `(a|b)` and the quantifiers `* + ? {n} {n,} {n,m}`, lazy with a trailing
+/// `?`. Backtracking is exponential on pathological patterns, like any such
+/// engine
+#[derive(Clone, PartialEq)]
+pub struct Regex {
+    source: String,
+    alternatives: Vec<Vec<Node>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Regex({:?})", self.source)
+    }
+}
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl std::str::FromStr for Regex {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            source: s,
+            chars: s.chars().collect(),
+            pos: 0,
+        };
+        let alternatives = parser.alternatives()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unmatched )"));
+        }
+        Ok(Self {
+            source: s.to_string(),
+            alternatives,
+        })
+    }
+}
+
+impl Regex {
+    /// Whether the pattern matches anywhere in `text`
+    pub fn is_match(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        let group = Node::Group(self.alternatives.clone());
+        (0..=chars.len())
+            .any(|start| match_seq(std::slice::from_ref(&group), &chars, start, &mut |_| true))
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, what: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid regex {} at {}: {}", self.source, self.pos, what),
+        )
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    /// Alternatives up to the end or a closing parenthesis, left unconsumed
+    fn alternatives(&mut self) -> io::Result<Vec<Vec<Node>>> {
+        let mut alternatives = vec![vec![]];
+        while let Some(c) = self.peek() {
+            match c {
+                ')' => break,
+                '|' => {
+                    self.pos += 1;
+                    alternatives.push(vec![]);
+                }
+                _ => {
+                    let atom = self.atom()?;
+                    let node = self.quantified(atom)?;
+                    alternatives.last_mut().unwrap().push(node);
+                }
+            }
+        }
+        Ok(alternatives)
+    }
+
+    fn atom(&mut self) -> io::Result<Node> {
+        match self.next().unwrap() {
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '(' => {
+                let alternatives = self.alternatives()?;
+                match self.next() {
+                    Some(')') => Ok(Node::Group(alternatives)),
+                    _ => Err(self.error("unclosed (")),
+                }
+            }
+            '[' => self.class(),
+            '\\' => self.escape(),
+            '*' | '+' | '?' | '{' => Err(self.error("nothing to repeat")),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    fn escape(&mut self) -> io::Result<Node> {
+        let class = |ranges: &[(char, char)], negated| Node::Class {
+            ranges: ranges.to_vec(),
+            negated,
+        };
+        const DIGIT: &[(char, char)] = &[('0', '9')];
+        const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+        const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+        match self.next() {
+            Some('d') => Ok(class(DIGIT, false)),
+            Some('D') => Ok(class(DIGIT, true)),
+            Some('w') => Ok(class(WORD, false)),
+            Some('W') => Ok(class(WORD, true)),
+            Some('s') => Ok(class(SPACE, false)),
+            Some('S') => Ok(class(SPACE, true)),
+            Some('n') => Ok(Node::Char('\n')),
+            Some('t') => Ok(Node::Char('\t')),
+            Some(c) => Ok(Node::Char(c)),
+            None => Err(self.error("trailing \\")),
+        }
+    }
+
+    fn class(&mut self) -> io::Result<Node> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = vec![];
+        // A leading ] is a literal
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                None => return Err(self.error("unclosed [")),
+                Some(']') if !first => break,
+                Some('\\') => match self.escape()? {
+                    Node::Char(c) => c,
+                    Node::Class { ranges: r, .. } => {
+                        ranges.extend(r);
+                        first = false;
+                        continue;
+                    }
+                    _ => unreachable!(),
+                },
+                Some(c) => c,
+            };
+            first = false;
+            match (self.peek(), self.chars.get(self.pos + 1)) {
+                (Some('-'), Some(&end)) if end != ']' => {
+                    self.pos += 2;
+                    if end < c {
+                        return Err(self.error("range out of order"));
+                    }
+                    ranges.push((c, end));
+                }
+                _ => ranges.push((c, c)),
+            }
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+
+    fn quantified(&mut self, node: Node) -> io::Result<Node> {
+        let (min, max) = match self.peek() {
+            Some('*') => {
+                self.pos += 1;
+                (0, None)
+            }
+            Some('+') => {
+                self.pos += 1;
+                (1, None)
+            }
+            Some('?') => {
+                self.pos += 1;
+                (0, Some(1))
+            }
+            Some('{') => {
+                self.pos += 1;
+                let min = self.number().ok_or_else(|| self.error("expected {n}"))?;
+                let max = match self.next() {
+                    Some('}') => Some(min),
+                    Some(',') if self.peek() == Some('}') => {
+                        self.pos += 1;
+                        None
+                    }
+                    Some(',') => {
+                        let max = self.number().ok_or_else(|| self.error("expected {n,m}"))?;
+                        if self.next() != Some('}') {
+                            return Err(self.error("expected }"));
+                        }
+                        Some(max)
+                    }
+                    _ => return Err(self.error("expected } or ,")),
+                };
+                if max.is_some_and(|max| max < min) || min.max(max.unwrap_or(0)) > MAX_REPEAT {
+                    return Err(self.error("invalid repetition"));
+                }
+                (min, max)
+            }
+            _ => return Ok(node),
+        };
+        if matches!(node, Node::Start | Node::End) {
+            return Err(self.error("nothing to repeat"));
+        }
+        let greedy = self.peek() != Some('?');
+        if !greedy {
+            self.pos += 1;
+        }
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+            greedy,
+        })
+    }
+}
+
+/// Match `nodes` at `i`, calling `k` with where the match ended until it
+/// accepts one
+fn match_seq(nodes: &[Node], text: &[char], i: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    let Some((node, rest)) = nodes.split_first() else {
+        return k(i);
+    };
+    match node {
+        Node::Start => i == 0 && match_seq(rest, text, i, k),
+        Node::End => i == text.len() && match_seq(rest, text, i, k),
+        Node::Group(alternatives) => alternatives.iter().any(|alternative| {
+            match_seq(alternative, text, i, &mut |j| match_seq(rest, text, j, k))
+        }),
+        Node::Repeat {
+            node,
+            min,
+            max,
+            greedy,
+        } => repeat(node, *min, *max, *greedy, 0, rest, text, i, k),
+        node => {
+            text.get(i).is_some_and(|&c| matches_char(node, c)) && match_seq(rest, text, i + 1, k)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    greedy: bool,
+    count: usize,
+    rest: &[Node],
+    text: &[char],
+    i: usize,
+    k: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    let more = |k: &mut dyn FnMut(usize) -> bool| {
+        max.is_none_or(|max| count < max)
+            && match_seq(std::slice::from_ref(node), text, i, &mut |j| {
+                // An empty iteration past the minimum would loop forever
+                (j > i || count < min)
+                    && repeat(node, min, max, greedy, count + 1, rest, text, j, k)
+            })
+    };
+    if count < min {
+        return more(k);
+    }
+    match greedy {
+        true => more(k) || match_seq(rest, text, i, k),
+        false => match_seq(rest, text, i, k) || more(k),
+    }
+}
+
+fn matches_char(node: &Node, c: char) -> bool {
+    match node {
+        Node::Char(expected) => c == *expected,
+        Node::Any => c != '\n',
+        Node::Class { ranges, negated } => {
+            ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod regex_tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        pattern.parse::<Regex>().unwrap().is_match(text)
+    }
+
+    #[test]
+    fn test_match() {
+        assert!(matches("done", "job done!"));
+        assert!(!matches("^done", "job done"));
+        assert!(matches("^a.c$", "abc"));
+        assert!(!matches("^a.c$", "abcd"));
+        assert!(matches("\"temp\":\\s*(3\\d|[4-9]\\d)", "{\"temp\": 42}"));
+        assert!(!matches("\"temp\":\\s*(3\\d|[4-9]\\d)", "{\"temp\": 21}"));
+        assert!(matches("^(ok|error)+$", "okerrorok"));
+        assert!(matches("^[^,]+,[^,]+$", "a,b"));
+        assert!(!matches("^[^,]+,[^,]+$", "a,b,c"));
+        assert!(matches("^\\w{3}-\\d{2,}$", "abc-123"));
+        assert!(!matches("^\\w{3}-\\d{2,}$", "abc-1"));
+        assert!(matches("^a.*?b$", "axxb"));
+        assert!(matches("^(a*)*$", "aaaa"));
+        assert!(matches("[]a]", "]"));
+        assert!(matches("x?$", ""));
+        assert!(matches("é+", "café"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for pattern in ["(a", "a)", "[a", "*a", "a{2,1}", "\\", "[z-a]", "^*"] {
+            assert!(pattern.parse::<Regex>().is_err(), "{}", pattern);
+        }
+    }
+}
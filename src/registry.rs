@@ -0,0 +1,294 @@
+use crate::avro::{self, AvroSchema};
+use crate::json::{self, Value};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Bound on connecting to the registry and on each read and write
+pub const DEFAULT_REGISTRY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Client of a Confluent compatible schema registry, resolving the Avro
+/// schemas of payloads framed with `avro::MAGIC_BYTE`. Schemas are fetched
+/// once, by id, over plain HTTP
+#[derive(Debug, Clone)]
+pub struct SchemaRegistry {
+    host: String,
+    port: u16,
+    /// Path the registry is served under, without the trailing slash
+    prefix: String,
+    timeout: Duration,
+    schemas: HashMap<u32, AvroSchema>,
+}
+
+impl std::str::FromStr for SchemaRegistry {
+    type Err = io::Error;
+
+    /// Parse `http://host[:port][/path]`, the port defaulting to 80
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid schema registry URL {}: {}", s, what),
+            )
+        };
+        if s.starts_with("https://") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "HTTPS schema registries are not supported, use http://",
+            ));
+        }
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("expected http://"))?;
+        let (authority, prefix) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                (host, port.parse().map_err(|_| invalid("invalid port"))?)
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        Ok(Self {
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            timeout: DEFAULT_REGISTRY_TIMEOUT,
+            schemas: HashMap::new(),
+        })
+    }
+}
+
+/// Percent encode a path segment, subjects are free form
+fn path_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl SchemaRegistry {
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// GET `path` and parse the JSON body of a 200 response. Errors of the
+    /// registry are reported with their message, 404 as `NotFound`
+    fn get(&self, path: &str) -> io::Result<Value> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no registry address found"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let request = format!(
+            "GET {}{} HTTP/1.1\r\nHost: {}\r\nAccept: application/vnd.schemaregistry.v1+json\r\nConnection: close\r\n\r\n",
+            self.prefix, path, self.host
+        );
+        stream.write_all(request.as_bytes())?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        let (status, body) = parse_response(&response)?;
+        let body = std::str::from_utf8(&body).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Registry body is not UTF-8")
+        })?;
+        if status == 200 {
+            return json::parse(body);
+        }
+        let message = json::parse(body)
+            .ok()
+            .and_then(|error| {
+                error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| body.trim().to_string());
+        let kind = match status {
+            404 => io::ErrorKind::NotFound,
+            _ => io::ErrorKind::Other,
+        };
+        Err(io::Error::new(
+            kind,
+            format!("Schema registry answered {}: {}", status, message),
+        ))
+    }
+
+    fn schema_field(response: &Value) -> io::Result<AvroSchema> {
+        response
+            .get("schema")
+            .and_then(Value::as_str)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Registry sent no schema"))?
+            .parse()
+    }
+
+    /// The schema registered with `id`
+    pub fn schema(&mut self, id: u32) -> io::Result<&AvroSchema> {
+        if !self.schemas.contains_key(&id) {
+            let schema = Self::schema_field(&self.get(&format!("/schemas/ids/{}", id))?)?;
+            self.schemas.insert(id, schema);
+        }
+        Ok(&self.schemas[&id])
+    }
+
+    /// Id and schema of the latest version of `subject`, fetched again on
+    /// every call to follow new versions
+    pub fn latest(&mut self, subject: &str) -> io::Result<(u32, &AvroSchema)> {
+        let response = self.get(&format!(
+            "/subjects/{}/versions/latest",
+            path_segment(subject)
+        ))?;
+        let id = response
+            .get("id")
+            .and_then(Value::as_f64)
+            .filter(|id| id.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(id))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Registry sent no schema id")
+            })? as u32;
+        let schema = Self::schema_field(&response)?;
+        Ok((id, self.schemas.entry(id).or_insert(schema)))
+    }
+
+    /// Decode a framed payload to JSON, `None` if it isn't framed
+    pub fn decode(&mut self, payload: &[u8]) -> io::Result<Option<Value>> {
+        match avro::unframe(payload) {
+            Some((id, data)) => self.schema(id)?.decode(data).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Status and body of an HTTP/1.1 response read to its end, chunked or not
+fn parse_response(response: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP response");
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|_| invalid())?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    let body = &response[end + 4..];
+    if !chunked {
+        return Ok((status, body.to_vec()));
+    }
+    let mut rest = body;
+    let mut out = vec![];
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(invalid)?;
+        let size = std::str::from_utf8(&rest[..line_end])
+            .ok()
+            .map(|size| size.split(';').next().unwrap_or("").trim())
+            .and_then(|size| usize::from_str_radix(size, 16).ok())
+            .ok_or_else(invalid)?;
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            return Ok((status, out));
+        }
+        out.extend_from_slice(rest.get(..size).ok_or_else(invalid)?);
+        rest = rest.get(size + 2..).ok_or_else(invalid)?;
+    }
+}
+
+#[cfg(test)]
+mod registry_tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answer each connection with the next of `responses`, returning the
+    /// request lines
+    fn serve(responses: Vec<String>) -> io::Result<(String, thread::JoinHandle<Vec<String>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/registry/", listener.local_addr()?);
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                requests.push(request.lines().next().unwrap().to_string());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        Ok((url, server))
+    }
+
+    #[test]
+    fn test_registry() -> io::Result<()> {
+        let schema = json::string(
+            r#"{"type": "record", "name": "R", "fields": [{"name": "n", "type": "int"}]}"#,
+        );
+        let body = format!(
+            r#"{{"subject": "t value", "version": 3, "id": 7, "schema": {}}}"#,
+            schema
+        );
+        let (url, server) = serve(vec![
+            format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{}", body),
+            format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{{\"schema\": {}}}\r\n0\r\n\r\n",
+                schema.len() + 12,
+                schema
+            ),
+            "HTTP/1.1 404 Not Found\r\n\r\n{\"error_code\": 40403, \"message\": \"Schema not found\"}".to_string(),
+        ])?;
+        let mut registry: SchemaRegistry = url.parse()?;
+        let (id, schema) = registry.latest("t value")?;
+        assert_eq!(id, 7);
+        let payload = avro::frame(id, &schema.encode(&json::parse(r#"{"n": -2}"#)?)?);
+        assert_eq!(payload, [0, 0, 0, 0, 7, 3]);
+        // Cached by the lookup of the subject
+        assert_eq!(
+            registry.decode(&payload)?.unwrap().to_string(),
+            r#"{"n":-2}"#
+        );
+        assert_eq!(
+            registry.decode(&avro::frame(8, &[4]))?.unwrap().to_string(),
+            r#"{"n":2}"#
+        );
+        let missing = registry.decode(&avro::frame(9, &[])).unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+        assert!(missing.to_string().contains("Schema not found"));
+        assert!(registry.decode(b"plain")?.is_none());
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                "GET /registry/subjects/t%20value/versions/latest HTTP/1.1",
+                "GET /registry/schemas/ids/8 HTTP/1.1",
+                "GET /registry/schemas/ids/9 HTTP/1.1",
+            ]
+        );
+        assert!("https://registry".parse::<SchemaRegistry>().is_err());
+        assert!("registry:8081".parse::<SchemaRegistry>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,422 @@
+use std::fmt;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Years searched for a matching minute before giving up, enough for
+/// `0 0 29 2 *` to find the next leap year
+const MAX_SEARCH_YEARS: i64 = 8;
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year, month and day of a number of days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn unix_secs(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs_f64().ceil() as i64),
+    }
+}
+
+fn from_unix_secs(secs: i64) -> SystemTime {
+    match secs {
+        0.. => UNIX_EPOCH + Duration::from_secs(secs as u64),
+        _ => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()),
+    }
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ`, always UTC
+pub fn format_timestamp(t: SystemTime) -> String {
+    let secs = unix_secs(t);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let time = secs.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Seconds since the Unix epoch, or a UTC date and time written
+/// `YYYY-MM-DDTHH:MM[:SS][Z]`, with a space allowed instead of the `T`
+pub fn parse_timestamp(s: &str) -> io::Result<SystemTime> {
+    let invalid = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid timestamp {}: {}", s, what),
+        )
+    };
+    if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+        let secs = s.parse().map_err(|_| invalid("out of range"))?;
+        return Ok(from_unix_secs(secs));
+    }
+    let s = s.strip_suffix('Z').unwrap_or(s);
+    let (date, time) = s
+        .split_once(['T', ' '])
+        .ok_or_else(|| invalid("expected YYYY-MM-DDTHH:MM[:SS]Z"))?;
+    if time.contains(['+', '-']) {
+        return Err(invalid("only UTC times are supported"));
+    }
+    let number = |field: Option<&str>, what: &str| -> io::Result<u32> {
+        field
+            .filter(|f| !f.is_empty() && f.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(|| invalid(&format!("bad {}", what)))
+    };
+    let mut date = date.splitn(3, '-');
+    let year = number(date.next(), "year")?;
+    let month = number(date.next(), "month")?;
+    let day = number(date.next(), "day")?;
+    let mut time = time.splitn(3, ':');
+    let hour = number(time.next(), "hour")?;
+    let minute = number(time.next(), "minute")?;
+    let second = match time.next() {
+        Some(second) => number(Some(second), "second")?,
+        None => 0,
+    };
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year as i64, month) {
+        return Err(invalid("no such date"));
+    }
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err(invalid("no such time"));
+    }
+    let days = days_from_civil(year as i64, month, day);
+    Ok(from_unix_secs(
+        days * 86_400 + (hour * 3600 + minute * 60 + second) as i64,
+    ))
+}
+
+/// Parse one field of a cron expression into a bit set of the values it
+/// allows, `names` mapping 3 letter names to values from `min` on
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |s: &str| -> Result<u32, String> {
+        let lower = s.to_ascii_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            Some(i) => i as u32 + min,
+            None => s.parse().map_err(|_| format!("{} is not a number", s))?,
+        };
+        match (min..=max).contains(&value) {
+            true => Ok(value),
+            false => Err(format!("{} is out of {}-{}", s, min, max)),
+        }
+    };
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .ok()
+                    .filter(|&step| step > 0)
+                    .ok_or_else(|| format!("bad step {}", step))?;
+                (range, step)
+            }
+            None => (item, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                // `N/step` runs from N to the maximum
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if start > end {
+            return Err(format!("empty range {}", range));
+        }
+        for v in (start..=end).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+/// A cron expression of five fields, minute, hour, day of the month, month
+/// and day of the week, evaluated in UTC. Fields take `*`, values, ranges
+/// `a-b`, steps `*/n` or `a-b/n` and lists of those separated by commas,
+/// months and days of the week also their 3 letter English names, Sunday
+/// being 0 or 7. As in cron, when both days are restricted a day matching
+/// either is enough. `@hourly`, `@daily`, `@weekly`, `@monthly` and
+/// `@yearly` are shorthands
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl std::str::FromStr for Cron {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |what: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid cron expression {}: {}", s, what),
+            )
+        };
+        let expanded = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(invalid(format!("expected 5 fields, got {}", fields.len())));
+        };
+        let mut weekdays = parse_field(weekday, 0, 7, &WEEKDAYS).map_err(invalid)?;
+        // 7 is Sunday too
+        if weekdays & 1 << 7 != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            expr: s.trim().to_string(),
+            minutes: parse_field(minute, 0, 59, &[]).map_err(invalid)?,
+            hours: parse_field(hour, 0, 23, &[]).map_err(invalid)?,
+            days: parse_field(day, 1, 31, &[]).map_err(invalid)?,
+            months: parse_field(month, 1, 12, &MONTHS).map_err(invalid)?,
+            weekdays,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expr)
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        let weekday = (days + 4).rem_euclid(7) as u32;
+        let day_ok = self.days & 1 << day != 0;
+        let weekday_ok = self.weekdays & 1 << weekday != 0;
+        let days_ok = match (self.any_day, self.any_weekday) {
+            (false, false) => day_ok || weekday_ok,
+            _ => day_ok && weekday_ok,
+        };
+        self.months & 1 << month != 0 && days_ok
+    }
+
+    /// First minute strictly after `t` matching the expression, `None` if
+    /// there's none in the next years, as for `0 0 31 2 *`
+    pub fn next_after(&self, t: SystemTime) -> Option<SystemTime> {
+        let start = unix_secs(t).div_euclid(60) + 1;
+        let first_day = start.div_euclid(1440);
+        for days in first_day..first_day + MAX_SEARCH_YEARS * 366 {
+            if !self.matches_day(days) {
+                continue;
+            }
+            let from = match days == first_day {
+                true => start.rem_euclid(1440),
+                false => 0,
+            };
+            let minute = (from..1440).find(|minute| {
+                self.hours & 1 << (minute / 60) != 0 && self.minutes & 1 << (minute % 60) != 0
+            });
+            if let Some(minute) = minute {
+                return Some(from_unix_secs((days * 1440 + minute) * 60));
+            }
+        }
+        None
+    }
+}
+
+/// When `publish` fires, parsed from `--cron` or `--at`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Cron(Cron),
+    At(SystemTime),
+}
+
+impl Schedule {
+    /// First time strictly after `t` the schedule fires
+    pub fn next_after(&self, t: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Cron(cron) => cron.next_after(t),
+            Schedule::At(at) => (*at > t).then_some(*at),
+        }
+    }
+
+    /// The next `count` times the schedule fires after `t`
+    pub fn upcoming(&self, t: SystemTime, count: usize) -> Vec<SystemTime> {
+        let mut times = vec![];
+        let mut last = t;
+        while times.len() < count {
+            let Some(next) = self.next_after(last) else {
+                break;
+            };
+            times.push(next);
+            last = next;
+        }
+        times
+    }
+}
+
+#[cfg(test)]
+mod schedule_tests {
+    use super::*;
+
+    fn at(s: &str) -> SystemTime {
+        parse_timestamp(s).unwrap()
+    }
+
+    #[test]
+    fn test_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        for days in [-800_000, -1, 0, 59, 10_956, 20_000, 800_000] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_timestamps() -> io::Result<()> {
+        let t = parse_timestamp("2026-10-16T08:30:05Z")?;
+        assert_eq!(unix_secs(t), 1_792_139_405);
+        assert_eq!(format_timestamp(t), "2026-10-16T08:30:05Z");
+        assert_eq!(
+            parse_timestamp("2026-10-16 08:30")?,
+            at("2026-10-16T08:30:00Z")
+        );
+        assert_eq!(parse_timestamp("1792139405")?, t);
+        for invalid in [
+            "",
+            "2026-10-16",
+            "2026-02-29T00:00Z",
+            "2026-10-16T24:00Z",
+            "2026-10-16T08:30+02:00",
+            "yesterday",
+        ] {
+            assert!(parse_timestamp(invalid).is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cron_parse() {
+        let cron: Cron = "*/15 9-17 * * mon-fri".parse().unwrap();
+        assert_eq!(cron.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cron.hours, 0b11_1111_1110_0000_0000);
+        assert_eq!(cron.weekdays, 0b011_1110);
+        assert!(!cron.any_weekday && cron.any_day);
+        let sunday: Cron = "0 0 * * 7".parse().unwrap();
+        assert_eq!(sunday.weekdays, 1);
+        assert_eq!(
+            "@daily".parse::<Cron>().unwrap().minutes,
+            "0 0 * * *".parse::<Cron>().unwrap().minutes
+        );
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * * foo *",
+        ] {
+            assert!(invalid.parse::<Cron>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_cron_next() {
+        let next = |expr: &str, t: &str| {
+            let cron: Cron = expr.parse().unwrap();
+            cron.next_after(at(t)).map(format_timestamp)
+        };
+        assert_eq!(
+            next("*/15 * * * *", "2026-10-16T12:07:30Z").as_deref(),
+            Some("2026-10-16T12:15:00Z")
+        );
+        // Strictly after, even on a matching minute
+        assert_eq!(
+            next("*/15 * * * *", "2026-10-16T12:15:00Z").as_deref(),
+            Some("2026-10-16T12:30:00Z")
+        );
+        // Saturday to Monday morning
+        assert_eq!(
+            next("0 9 * * mon-fri", "2026-10-17T10:00:00Z").as_deref(),
+            Some("2026-10-19T09:00:00Z")
+        );
+        assert_eq!(
+            next("30 23 31 dec *", "2026-10-16T00:00:00Z").as_deref(),
+            Some("2026-12-31T23:30:00Z")
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2026-10-16T00:00:00Z").as_deref(),
+            Some("2028-02-29T00:00:00Z")
+        );
+        // Either the 1st or a Friday when both are restricted
+        assert_eq!(
+            next("0 0 1 * fri", "2026-10-16T12:00:00Z").as_deref(),
+            Some("2026-10-23T00:00:00Z")
+        );
+        assert_eq!(next("0 0 31 2 *", "2026-10-16T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let once = Schedule::At(at("2026-10-16T12:00:00Z"));
+        assert_eq!(once.upcoming(at("2026-10-16T11:00:00Z"), 3).len(), 1);
+        assert!(once.next_after(at("2026-10-16T12:00:00Z")).is_none());
+        let hourly = Schedule::Cron("@hourly".parse().unwrap());
+        let times: Vec<String> = hourly
+            .upcoming(at("2026-10-16T11:00:00Z"), 2)
+            .into_iter()
+            .map(format_timestamp)
+            .collect();
+        assert_eq!(times, ["2026-10-16T12:00:00Z", "2026-10-16T13:00:00Z"]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// Raised by the signal handlers, see `on_terminate`
+static TERMINATED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+fn terminate() {
+    if let Some(flag) = TERMINATED.get() {
+        flag.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::os::raw::c_int;
+
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    const SIG_DFL: usize = 0;
+    const SIG_ERR: usize = !0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn handle(signum: c_int) {
+        super::terminate();
+        // A second signal kills the process as usual, in case stopping
+        // gracefully hangs
+        // SAFETY: signal is async-signal-safe
+        unsafe { signal(signum, SIG_DFL) };
+    }
+
+    pub fn install() -> io::Result<()> {
+        for signum in [SIGTERM, SIGINT] {
+            // SAFETY: `handle` only stores to an atomic and calls signal,
+            // both async-signal-safe
+            if unsafe { signal(signum, handle as *const () as usize) } == SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+
+    pub fn install() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Catching termination signals is only supported on Unix",
+        ))
+    }
+}
+
+/// A flag raised by the first SIGTERM or SIGINT, which no longer kill the
+/// process, for long running commands to stop gracefully. The next one
+/// does. Every call returns the same flag
+pub fn on_terminate() -> io::Result<Arc<AtomicBool>> {
+    let mut first = false;
+    let flag = TERMINATED.get_or_init(|| {
+        first = true;
+        Arc::new(AtomicBool::new(false))
+    });
+    // Only once the flag exists, for the handlers to find it
+    if first {
+        sys::install()?;
+    }
+    Ok(Arc::clone(flag))
+}
+
+#[cfg(all(test, unix))]
+mod shutdown_tests {
+    use super::*;
+
+    extern "C" {
+        fn raise(signum: std::os::raw::c_int) -> std::os::raw::c_int;
+    }
+
+    #[test]
+    fn test_on_terminate() -> io::Result<()> {
+        let flag = on_terminate()?;
+        assert!(!flag.load(Ordering::Acquire));
+        assert!(Arc::ptr_eq(&flag, &on_terminate()?));
+        // SAFETY: the handler is installed, SIGTERM only raises the flag
+        assert_eq!(unsafe { raise(15) }, 0);
+        assert!(flag.load(Ordering::Acquire));
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use crate::encoding::base64_encode;
+use crate::json;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Per-topic files kept open by `--output-mode jsonl`, all are closed once
+/// more topics than this were written to
+const MAX_OPEN_FILES: usize = 64;
+
+/// How `--output-dir` lays out the messages received
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    /// A `<timestamp>.bin` file per message holding its raw payload, in the
+    /// directory of its topic
+    Bin,
+    /// A line per message appended to the `.jsonl` file of its topic
+    Jsonl,
+}
+
+impl std::str::FromStr for OutputMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bin" => Ok(OutputMode::Bin),
+            "jsonl" => Ok(OutputMode::Jsonl),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown output mode {}, expected bin or jsonl", s),
+            )),
+        }
+    }
+}
+
+/// Relative path of a topic, a directory per level. Levels are percent
+/// encoded where they hold characters file systems reject or that would
+/// escape the output directory, an empty level becomes `+`, which can't
+/// appear in a topic name
+pub fn topic_path(topic: &str) -> PathBuf {
+    topic
+        .split('/')
+        .map(|level| match level {
+            "" => "+".to_string(),
+            "." => "%2E".to_string(),
+            ".." => "%2E%2E".to_string(),
+            level => {
+                let mut out = String::with_capacity(level.len());
+                for c in level.chars() {
+                    match c {
+                        '%' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => {
+                            out.push_str(&format!("%{:02X}", c as u32))
+                        }
+                        c if c.is_control() => {
+                            let mut buf = [0; 4];
+                            for b in c.encode_utf8(&mut buf).bytes() {
+                                out.push_str(&format!("%{:02X}", b));
+                            }
+                        }
+                        c => out.push(c),
+                    }
+                }
+                out
+            }
+        })
+        .collect()
+}
+
+/// A message as a single line JSON object, the payload as a string if it
+/// is UTF-8 and base64 encoded otherwise
+pub fn json_line(topic: &str, payload: &[u8], received: SystemTime) -> String {
+    let timestamp = received.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let payload = match std::str::from_utf8(payload) {
+        Ok(text) => format!("\"payload\":{}", json::string(text)),
+        Err(_) => format!("\"payload_base64\":\"{}\"", base64_encode(payload)),
+    };
+    format!(
+        "{{\"timestamp\":{:.6},\"topic\":{},{}}}",
+        timestamp.as_secs_f64(),
+        json::string(topic),
+        payload
+    )
+}
+
+/// Writes every message received under a directory, by topic
+pub struct TopicDir {
+    root: PathBuf,
+    mode: OutputMode,
+    files: HashMap<PathBuf, File>,
+}
+
+impl TopicDir {
+    pub fn new(root: impl AsRef<Path>, mode: OutputMode) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+        Ok(Self {
+            root: root.as_ref().to_path_buf(),
+            mode,
+            files: HashMap::new(),
+        })
+    }
+
+    /// Write a message, returns the file it went to
+    pub fn write(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        received: SystemTime,
+    ) -> io::Result<PathBuf> {
+        let dir = self.root.join(topic_path(topic));
+        match self.mode {
+            OutputMode::Bin => {
+                fs::create_dir_all(&dir)?;
+                let t = received.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let stem = format!("{}.{:09}", t.as_secs(), t.subsec_nanos());
+                // Messages received within the same nanosecond
+                for n in 0.. {
+                    let path = match n {
+                        0 => dir.join(format!("{}.bin", stem)),
+                        n => dir.join(format!("{}-{}.bin", stem, n)),
+                    };
+                    match OpenOptions::new().write(true).create_new(true).open(&path) {
+                        Ok(mut file) => {
+                            file.write_all(payload)?;
+                            return Ok(path);
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                unreachable!()
+            }
+            OutputMode::Jsonl => {
+                let path = dir.with_extension("jsonl");
+                if !self.files.contains_key(&path) {
+                    if self.files.len() >= MAX_OPEN_FILES {
+                        self.files.clear();
+                    }
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                    self.files.insert(path.clone(), file);
+                }
+                let line = format!("{}\n", json_line(topic, payload, received));
+                self.files
+                    .get_mut(&path)
+                    .unwrap()
+                    .write_all(line.as_bytes())?;
+                Ok(path)
+            }
+        }
+    }
+}
+
+/// A single JSON Lines file every message is appended to. Each line is
+/// handed to the OS as soon as it is written, so tailing readers see it
+/// immediately, while fsync only happens every `sync_every` lines or
+/// `sync_interval`, whichever comes first
+pub struct JsonlSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    unsynced: usize,
+    last_sync: Instant,
+    /// Lines written between two fsync
+    pub sync_every: usize,
+    /// Longest time a line stays written but not synced
+    pub sync_interval: Duration,
+    /// Rotate once the file grows past this many bytes
+    pub max_size: Option<u64>,
+    /// Rotated files kept, `out.jsonl.1` being the most recent
+    pub keep: usize,
+}
+
+impl JsonlSink {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            sync_every: 100,
+            sync_interval: Duration::from_secs(1),
+            max_size: None,
+            keep: 5,
+        })
+    }
+
+    pub fn write(&mut self, topic: &str, payload: &[u8], received: SystemTime) -> io::Result<()> {
+        let line = format!("{}\n", json_line(topic, payload, received));
+        // A line never gets split across two files
+        if self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max)
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        self.unsynced += 1;
+        if self.unsynced >= self.sync_every || self.last_sync.elapsed() >= self.sync_interval {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Make every line written so far durable
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+
+    /// Shift `out.jsonl.N` to `out.jsonl.N+1`, dropping the oldest, and
+    /// start over with an empty `out.jsonl`
+    fn rotate(&mut self) -> io::Result<()> {
+        self.sync()?;
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+        match fs::remove_file(self.rotated(self.keep)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for n in (1..self.keep).rev() {
+            match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Drop for JsonlSink {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+#[cfg(test)]
+mod sink_tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sake-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_topic_path() {
+        assert_eq!(topic_path("a/b/c"), Path::new("a/b/c"));
+        assert_eq!(topic_path("/a//b/"), Path::new("+/a/+/b/+"));
+        assert_eq!(topic_path("../x/."), Path::new("%2E%2E/x/%2E"));
+        assert_eq!(topic_path("a:b%c\n"), Path::new("a%3Ab%25c%0A"));
+        assert_eq!(topic_path("$SYS/..x"), Path::new("$SYS/..x"));
+    }
+
+    #[test]
+    fn test_json_line() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        assert_eq!(
+            json_line("a/b", b"hi \"x\"", t),
+            "{\"timestamp\":1700000000.250000,\"topic\":\"a/b\",\"payload\":\"hi \\\"x\\\"\"}"
+        );
+        assert_eq!(
+            json_line("a", &[0xFF, 0], t),
+            "{\"timestamp\":1700000000.250000,\"topic\":\"a\",\"payload_base64\":\"/wA=\"}"
+        );
+    }
+
+    #[test]
+    fn test_bin_output() -> io::Result<()> {
+        let root = temp_dir("bin-output");
+        let mut sink = TopicDir::new(&root, OutputMode::Bin)?;
+        let t = UNIX_EPOCH + Duration::new(1_700_000_000, 5);
+        let first = sink.write("a/../b", b"one", t)?;
+        let second = sink.write("a/../b", b"two", t)?;
+        assert_eq!(first, root.join("a/%2E%2E/b/1700000000.000000005.bin"));
+        assert_eq!(second, root.join("a/%2E%2E/b/1700000000.000000005-1.bin"));
+        assert_eq!(fs::read(&second)?, b"two");
+        fs::remove_dir_all(&root)
+    }
+
+    #[test]
+    fn test_jsonl_output() -> io::Result<()> {
+        let root = temp_dir("jsonl-output");
+        let mut sink = TopicDir::new(&root, OutputMode::Jsonl)?;
+        let t = UNIX_EPOCH;
+        sink.write("a/b", b"1", t)?;
+        sink.write("a", b"2", t)?;
+        let path = sink.write("a/b", b"3", t)?;
+        assert_eq!(path, root.join("a/b.jsonl"));
+        let lines = fs::read_to_string(&path)?;
+        assert_eq!(lines.lines().count(), 2);
+        assert!(lines.ends_with("\"payload\":\"3\"}\n"));
+        assert!(root.join("a.jsonl").exists());
+        fs::remove_dir_all(&root)
+    }
+
+    #[test]
+    fn test_jsonl_rotation() -> io::Result<()> {
+        let root = temp_dir("jsonl-rotation");
+        fs::create_dir_all(&root)?;
+        let path = root.join("out.jsonl");
+        let mut sink = JsonlSink::open(&path)?;
+        let line = json_line("t", b"0", UNIX_EPOCH).len() as u64 + 1;
+        sink.max_size = Some(line * 2);
+        sink.keep = 2;
+        for i in 0..7 {
+            sink.write("t", i.to_string().as_bytes(), UNIX_EPOCH)?;
+        }
+        drop(sink);
+        let payloads = |name: &str| -> io::Result<Vec<String>> {
+            Ok(fs::read_to_string(root.join(name))?
+                .lines()
+                .map(|l| l[l.len() - 3..l.len() - 2].to_string())
+                .collect())
+        };
+        assert_eq!(payloads("out.jsonl")?, ["6"]);
+        assert_eq!(payloads("out.jsonl.1")?, ["4", "5"]);
+        assert_eq!(payloads("out.jsonl.2")?, ["2", "3"]);
+        assert!(!root.join("out.jsonl.3").exists());
+
+        // Appends to what is already there
+        let mut sink = JsonlSink::open(&path)?;
+        sink.write("t", b"7", UNIX_EPOCH)?;
+        drop(sink);
+        assert_eq!(payloads("out.jsonl")?, ["6", "7"]);
+        fs::remove_dir_all(&root)
+    }
+}
@@ -0,0 +1,283 @@
+use crate::encoding::{base64_decode, base64_encode};
+use crate::json::{self, Value};
+use crate::mqtt::{AckType, Dedup, Protocol, PublishOptions, Qos, Response, SUBACK_FAILURE};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version of the snapshot document, bumped on incompatible changes
+pub const SCHEMA: u64 = 1;
+
+/// A message retained by a broker
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retained {
+    pub topic: String,
+    pub qos: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Retained messages of a broker matching a filter, as `sake retained
+/// export` saves them and `sake retained import` republishes them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub filter: String,
+    pub broker: Option<SocketAddr>,
+    pub exported_at: Option<SystemTime>,
+    pub messages: Vec<Retained>,
+}
+
+impl Snapshot {
+    /// One message per line, payloads as strings if they are UTF-8 and
+    /// base64 encoded otherwise
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            "{{\"schema\":{},\"filter\":{},\"broker\":{},\"exported_at\":{},\"messages\":[",
+            SCHEMA,
+            json::string(&self.filter),
+            self.broker
+                .map_or("null".to_string(), |b| json::string(&b.to_string())),
+            self.exported_at
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or("null".to_string(), |t| t.as_secs().to_string()),
+        );
+        for (i, message) in self.messages.iter().enumerate() {
+            let payload = match std::str::from_utf8(&message.payload) {
+                Ok(text) => format!("\"payload\":{}", json::string(text)),
+                Err(_) => format!("\"payload_base64\":\"{}\"", base64_encode(&message.payload)),
+            };
+            out.push_str(&format!(
+                "{}\n{{\"topic\":{},\"qos\":{},{}}}",
+                if i > 0 { "," } else { "" },
+                json::string(&message.topic),
+                message.qos,
+                payload
+            ));
+        }
+        out.push_str("\n]}\n");
+        out
+    }
+
+    pub fn from_json(s: &str) -> io::Result<Self> {
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid snapshot: {}", what),
+            )
+        };
+        let doc = json::parse(s)?;
+        match doc.get("schema").and_then(Value::as_f64) {
+            Some(schema) if schema == SCHEMA as f64 => {}
+            Some(schema) => return Err(invalid(&format!("unsupported schema {}", schema))),
+            None => return Err(invalid("missing schema")),
+        }
+        let Some(Value::Array(values)) = doc.get("messages") else {
+            return Err(invalid("missing messages"));
+        };
+        let mut messages = Vec::with_capacity(values.len());
+        for value in values {
+            let topic = value
+                .get("topic")
+                .and_then(Value::as_str)
+                .ok_or_else(|| invalid("message without a topic"))?;
+            let qos = match value.get("qos").and_then(Value::as_f64) {
+                None => 0,
+                Some(qos) if qos == 0.0 || qos == 1.0 || qos == 2.0 => qos as u8,
+                Some(qos) => return Err(invalid(&format!("QoS {} of {}", qos, topic))),
+            };
+            let payload = match (value.get("payload"), value.get("payload_base64")) {
+                (Some(Value::String(text)), None) => text.as_bytes().to_vec(),
+                (None, Some(Value::String(encoded))) => base64_decode(encoded)?,
+                _ => return Err(invalid(&format!("payload of {}", topic))),
+            };
+            messages.push(Retained {
+                topic: topic.to_string(),
+                qos,
+                payload,
+            });
+        }
+        Ok(Self {
+            filter: doc
+                .get("filter")
+                .and_then(Value::as_str)
+                .unwrap_or("#")
+                .to_string(),
+            broker: doc
+                .get("broker")
+                .and_then(Value::as_str)
+                .and_then(|b| b.parse().ok()),
+            exported_at: doc
+                .get("exported_at")
+                .and_then(Value::as_f64)
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs as u64)),
+            messages,
+        })
+    }
+}
+
+/// Subscribe to `filter` and collect the retained messages the broker
+/// replays. There's no marker of the last one, so collecting stops once
+/// `quiet` passed without any; live messages arriving meanwhile are skipped
+pub fn export(client: &mut Protocol, filter: &str, quiet: Duration) -> io::Result<Snapshot> {
+    client.subscribe(filter, Qos::ExactlyOnce)?;
+    client.set_read_timeout(Some(quiet))?;
+    let mut messages: Vec<Retained> = vec![];
+    let mut qos2 = Dedup::default();
+    loop {
+        let response = match client.read_message::<Response>() {
+            Ok(response) => response,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+        match response {
+            Response::Suback { return_codes, .. } if return_codes.contains(&SUBACK_FAILURE) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Subscription to {} refused", filter),
+                ));
+            }
+            Response::Publish {
+                packet_id,
+                qos,
+                retain,
+                topic,
+                payload,
+                ..
+            } => {
+                match qos {
+                    1 => client.ack(AckType::Puback(packet_id))?,
+                    2 => {
+                        client.ack(AckType::Pubrec(packet_id))?;
+                        if !qos2.arrived(packet_id) {
+                            continue;
+                        }
+                    }
+                    _ => {}
+                }
+                if retain {
+                    messages.push(Retained {
+                        topic,
+                        qos,
+                        payload,
+                    });
+                }
+            }
+            Response::Pubrel { packet_id } => {
+                client.ack(AckType::Pubcomp(packet_id))?;
+                qos2.released(&packet_id);
+            }
+            _ => {}
+        }
+    }
+    client.set_read_timeout(None)?;
+    Ok(Snapshot {
+        filter: filter.to_string(),
+        broker: client.peer_addr().ok(),
+        exported_at: Some(SystemTime::now()),
+        messages,
+    })
+}
+
+/// Republish every message of a snapshot with the retain flag set, each at
+/// its own QoS, waiting up to `ack_timeout` for the broker to acknowledge it
+pub fn import(client: &mut Protocol, snapshot: &Snapshot, ack_timeout: Duration) -> io::Result<()> {
+    for message in &snapshot.messages {
+        let options = PublishOptions {
+            qos: Qos::from(message.qos),
+            ack_timeout: Some(ack_timeout),
+            retries: 2,
+            retain: true,
+        };
+        client.publish_with(&message.topic, &message.payload, &options)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+    use crate::broker::{Broker, BrokerOptions};
+    use std::thread;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            filter: "a/#".to_string(),
+            broker: Some("127.0.0.1:1883".parse().unwrap()),
+            exported_at: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            messages: vec![
+                Retained {
+                    topic: "a/1".to_string(),
+                    qos: 1,
+                    payload: b"{\"on\":true}".to_vec(),
+                },
+                Retained {
+                    topic: "a/2".to_string(),
+                    qos: 0,
+                    payload: vec![0xFF, 0x00],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_json_roundtrip() -> io::Result<()> {
+        let json = snapshot().to_json();
+        assert!(json.starts_with(
+            "{\"schema\":1,\"filter\":\"a/#\",\"broker\":\"127.0.0.1:1883\",\"exported_at\":1700000000"
+        ));
+        assert!(json.contains("{\"topic\":\"a/2\",\"qos\":0,\"payload_base64\":\"/wA=\"}"));
+        assert_eq!(Snapshot::from_json(&json)?, snapshot());
+        assert!(Snapshot::from_json("{\"schema\":2,\"messages\":[]}").is_err());
+        assert!(Snapshot::from_json("{\"schema\":1,\"messages\":[{\"topic\":\"a\"}]}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_import() -> io::Result<()> {
+        let start = || -> io::Result<SocketAddr> {
+            let broker = Broker::bind("127.0.0.1:0", BrokerOptions::default())?;
+            let addr = broker.local_addr()?;
+            thread::spawn(move || broker.run());
+            Ok(addr)
+        };
+        let connect = |addr, client_id: &str| {
+            Protocol::builder()
+                .addrs(&[addr])
+                .client_id(client_id)
+                .connect()
+        };
+        let source = start()?;
+        let target = start()?;
+
+        let mut client = connect(source, "seed")?;
+        import(&mut client, &snapshot(), Duration::from_secs(5))?;
+        client.publish_with(
+            "b/1",
+            b"not retained",
+            &PublishOptions {
+                ack_timeout: Some(Duration::from_secs(5)),
+                ..PublishOptions::default()
+            },
+        )?;
+        client.disconnect()?;
+
+        let mut client = connect(source, "export")?;
+        let exported = export(&mut client, "#", Duration::from_millis(300))?;
+        client.disconnect()?;
+        assert_eq!(exported.broker, Some(source));
+        let mut messages = exported.messages.clone();
+        messages.sort_by(|a, b| a.topic.cmp(&b.topic));
+        assert_eq!(messages, snapshot().messages);
+
+        let mut client = connect(target, "import")?;
+        import(&mut client, &exported, Duration::from_secs(5))?;
+        client.disconnect()?;
+        let mut client = connect(target, "check")?;
+        let migrated = export(&mut client, "a/+", Duration::from_millis(300))?;
+        assert_eq!(migrated.messages.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use crate::json::{self, Value};
+use std::fmt;
+use std::io;
+
+/// Placeholders starting with it take their value from the payload, see
+/// `Template`
+const JSON_PREFIX: &str = "json:";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Var(String),
+    /// `{{json:$.path}}`, the path as written and parsed
+    Json(String, Vec<String>),
+}
+
+/// A string with `{{name}}` placeholders, as `sensors/{{n}}`, expanded
+/// again for every message. Double braces keep JSON payloads, full of
+/// single ones, usable as templates.
+///
+/// `{{json:$.path}}` placeholders are the value at the path of a JSON
+/// payload, as in `devices/{{json:$.device_id}}/telemetry`, see
+/// `json::parse_path`. Strings are inserted unquoted, other values as JSON
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl std::str::FromStr for Template {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid template {}: unclosed {{{{", s),
+                )
+            })?;
+            let name = after[..end].trim();
+            if name.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid template {}: empty placeholder", s),
+                ));
+            }
+            match name.strip_prefix(JSON_PREFIX) {
+                Some(path) => {
+                    let path = json::parse_path(path.trim()).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Invalid template {}: {}", s, e),
+                        )
+                    })?;
+                    parts.push(Part::Json(name.to_string(), path));
+                }
+                None => parts.push(Part::Var(name.to_string())),
+            }
+            rest = &after[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => f.write_str(text)?,
+                Part::Var(name) | Part::Json(name, _) => write!(f, "{{{{{}}}}}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Template {
+    /// Names of the placeholders, in order, the JSON ones aside
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Var(name) => Some(name.as_str()),
+            Part::Text(_) | Part::Json(..) => None,
+        })
+    }
+
+    /// Whether some placeholder takes its value from the payload
+    pub fn reads_payload(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Json(..)))
+    }
+
+    /// Fail on the first placeholder `known` doesn't accept, to report
+    /// mistakes before any message is sent
+    pub fn check(&self, known: impl Fn(&str) -> bool) -> io::Result<()> {
+        match self.names().find(|name| !known(name)) {
+            Some(name) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown template placeholder {{{{{}}}}} in {}", name, self),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace every placeholder with the value `lookup` gives for its name,
+    /// failing on names it has no value for and on JSON placeholders
+    pub fn expand(&self, lookup: impl Fn(&str) -> Option<String>) -> io::Result<String> {
+        self.expand_from(None, lookup)
+    }
+
+    /// Like `expand`, taking the value of JSON placeholders from `payload`.
+    /// Fails with `InvalidData` if it isn't JSON or lacks one of the paths
+    pub fn expand_payload(
+        &self,
+        payload: &[u8],
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> io::Result<String> {
+        self.expand_from(Some(payload), lookup)
+    }
+
+    fn expand_from(
+        &self,
+        payload: Option<&[u8]>,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> io::Result<String> {
+        let mut out = String::new();
+        // Parsed on the first JSON placeholder only
+        let mut doc = None;
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Json(name, path) => {
+                    let doc = match &mut doc {
+                        Some(doc) => doc,
+                        None => doc.insert(parse_payload(payload, self)?),
+                    };
+                    match doc.at(path) {
+                        Some(Value::String(s)) => out.push_str(s),
+                        Some(value) => out.push_str(&value.to_string()),
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("No value for {{{{{}}}}} in the payload", name),
+                            ))
+                        }
+                    }
+                }
+                Part::Var(name) => match lookup(name) {
+                    Some(value) => out.push_str(&value),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("No value for {{{{{}}}}} in {}", name, self),
+                        ))
+                    }
+                },
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn parse_payload(payload: Option<&[u8]>, template: &Template) -> io::Result<Value> {
+    let payload = payload.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No payload to expand {} from", template),
+        )
+    })?;
+    std::str::from_utf8(payload)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Payload is not UTF-8"))
+        .and_then(json::parse)
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Payload is not JSON, needed by {}: {}", template, e),
+            )
+        })
+}
+
+#[cfg(test)]
+mod template_tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() -> io::Result<()> {
+        let template: Template = r#"{"n": {{ n }}, "at": "{{iso}}"}"#.parse()?;
+        assert_eq!(template.names().collect::<Vec<_>>(), ["n", "iso"]);
+        assert_eq!(template.to_string(), r#"{"n": {{n}}, "at": "{{iso}}"}"#);
+        let lookup = |name: &str| match name {
+            "n" => Some("3".to_string()),
+            "iso" => Some("2026-10-16T12:00:00Z".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            template.expand(lookup)?,
+            r#"{"n": 3, "at": "2026-10-16T12:00:00Z"}"#
+        );
+        assert!(template.check(|name| name == "n").is_err());
+        assert!(template.check(|name| lookup(name).is_some()).is_ok());
+        assert!("plain {json}".parse::<Template>()?.expand(|_| None).is_ok());
+        assert!("{{n".parse::<Template>().is_err());
+        assert!("{{ }}".parse::<Template>().is_err());
+        Ok(())
+    }
+    #[test]
+    fn test_expand_payload() -> io::Result<()> {
+        let template: Template =
+            "devices/{{ json:$.device_id }}/{{json:$.readings.1}}/{{n}}".parse()?;
+        assert!(template.reads_payload());
+        assert_eq!(template.names().collect::<Vec<_>>(), ["n"]);
+        assert_eq!(
+            template.to_string(),
+            "devices/{{json:$.device_id}}/{{json:$.readings.1}}/{{n}}"
+        );
+        let n = |name: &str| (name == "n").then(|| "3".to_string());
+        let payload = br#"{"device_id": "pump-7", "readings": [0.5, 12]}"#;
+        assert_eq!(template.expand_payload(payload, n)?, "devices/pump-7/12/3");
+        let kind = |payload: &[u8]| template.expand_payload(payload, n).unwrap_err().kind();
+        assert_eq!(
+            kind(br#"{"device_id": "pump-7"}"#),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(kind(b"pump-7"), io::ErrorKind::InvalidData);
+        assert!(template.expand(n).is_err());
+
+        let whole: Template = "{{json:$}}".parse()?;
+        assert_eq!(
+            whole.expand_payload(br#" {"a": [1]} "#, |_| None)?,
+            r#"{"a":[1]}"#
+        );
+        assert!("{{json:device_id}}".parse::<Template>().is_err());
+        assert!("{{json:$.a..b}}".parse::<Template>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use crate::mqtt::{AsyncTransport, Transport};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Bytes written to one end and not yet read from the other
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    closed: bool,
+    /// Tasks polling for bytes to read
+    wakers: Vec<Waker>,
+}
+
+impl Pipe {
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.notify(&mut state);
+    }
+
+    /// Wake the readers, blocked or polling
+    fn notify(&self, state: &mut PipeState) {
+        self.readable.notify_all();
+        state.wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+/// One end of the pair, shared by its clones and closed with the last one
+#[derive(Debug)]
+struct End {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+    nonblocking: AtomicBool,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+/// An end of an in-memory connection, see `duplex`
+#[derive(Debug, Clone)]
+pub struct Duplex {
+    end: Arc<End>,
+}
+
+/// A pair of connected in-memory transports, what is written to one is read
+/// from the other. They behave as a loopback TCP connection would: reads
+/// time out with `WouldBlock`, hit the end of the stream once the other end
+/// shuts down its writes or is dropped, and writes to a dropped end fail
+/// with `BrokenPipe`. Writes never block, nothing bounds the bytes in flight.
+/// Either end may also be driven as an `AsyncTransport`
+pub fn duplex() -> (Duplex, Duplex) {
+    let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+    let end = |incoming, outgoing| Duplex {
+        end: Arc::new(End {
+            incoming,
+            outgoing,
+            read_timeout: Mutex::new(None),
+            nonblocking: AtomicBool::new(false),
+        }),
+    };
+    (end(Arc::clone(&a), Arc::clone(&b)), end(b, a))
+}
+
+impl Duplex {
+    /// Bytes written by the other end and not read yet
+    pub fn pending(&self) -> usize {
+        self.end.incoming.state.lock().unwrap().buf.len()
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self
+            .end
+            .read_timeout
+            .lock()
+            .unwrap()
+            .map(|timeout| Instant::now() + timeout);
+        let pipe = &self.end.incoming;
+        let mut state = pipe.state.lock().unwrap();
+        while state.buf.is_empty() && !state.closed && !buf.is_empty() {
+            if self.end.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            state = match deadline {
+                None => pipe.readable.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    pipe.readable.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+        let n = buf.len().min(state.buf.len());
+        for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pipe = &self.end.outgoing;
+        let mut state = pipe.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.buf.extend(buf);
+        pipe.notify(&mut state);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Duplex {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Zero read timeout",
+            ));
+        }
+        *self.end.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.end.read_timeout.lock().unwrap())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.end.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.end.incoming.close();
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.end.outgoing.close();
+        }
+        Ok(())
+    }
+}
+
+impl AsyncTransport for Duplex {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.end.incoming.state.lock().unwrap();
+        if state.buf.is_empty() && !state.closed && !buf.is_empty() {
+            state.wakers.push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(state.buf.len());
+        for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+            *dst = src;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod duplex_tests {
+    use super::*;
+
+    #[test]
+    fn test_duplex() -> io::Result<()> {
+        let (mut a, mut b) = duplex();
+        a.write_all(b"ping")?;
+        assert_eq!(b.pending(), 4);
+        let mut buf = [0; 8];
+        assert_eq!(b.read(&mut buf)?, 4);
+        assert_eq!(&buf[..4], b"ping");
+
+        b.set_read_timeout(Some(Duration::from_millis(10)))?;
+        assert_eq!(
+            b.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        b.set_read_timeout(None)?;
+        b.set_nonblocking(true)?;
+        assert_eq!(
+            b.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        b.set_nonblocking(false)?;
+
+        // Bytes still in flight are read before the end of the stream
+        let writer = Transport::try_clone(&a)?;
+        a.write_all(b"bye")?;
+        a.shutdown(Shutdown::Write)?;
+        let mut rest = vec![];
+        b.read_to_end(&mut rest)?;
+        assert_eq!(rest, b"bye");
+
+        // Only the last clone dropped closes the end
+        drop(a);
+        b.write_all(b"kept")?;
+        drop(writer);
+        assert_eq!(
+            b.write(b"lost").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocking_read() -> io::Result<()> {
+        let (mut a, mut b) = duplex();
+        let reader = std::thread::spawn(move || {
+            let mut buf = [0; 4];
+            b.read_exact(&mut buf).map(|_| buf)
+        });
+        a.write_all(b"ab")?;
+        a.write_all(b"cd")?;
+        assert_eq!(&reader.join().unwrap()?, b"abcd");
+        Ok(())
+    }
+}
@@ -0,0 +1,545 @@
+use crate::mqtt::{Deserialize, Serialize};
+use std::io;
+
+/// A packet of the golden corpus, the hex dump in `corpus/<name>.hex`
+/// written from the spec rather than from the encoders it checks. Its
+/// first line is a `#` comment describing the packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fixture {
+    pub name: &'static str,
+    text: &'static str,
+}
+
+impl Fixture {
+    const fn new(name: &'static str, text: &'static str) -> Self {
+        Self { name, text }
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.text
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix('#'))
+            .map_or("", str::trim)
+    }
+
+    /// The packet, fixed header included
+    pub fn bytes(&self) -> Vec<u8> {
+        parse_hex(self.text).unwrap_or_else(|e| panic!("Fixture {} is corrupt: {}", self.name, e))
+    }
+}
+
+/// Every packet type a client sends or receives, MQTT 3.1.1 and 5, and the
+/// encodings most likely to regress: optional CONNECT fields, DUP and
+/// retain flags, multi-byte remaining lengths and UTF-8 topics
+pub const FIXTURES: &[Fixture] = &[
+    Fixture::new("auth", include_str!("corpus/auth.hex")),
+    Fixture::new(
+        "connack_accepted",
+        include_str!("corpus/connack_accepted.hex"),
+    ),
+    Fixture::new(
+        "connack_not_authorized",
+        include_str!("corpus/connack_not_authorized.hex"),
+    ),
+    Fixture::new(
+        "connack_session_present",
+        include_str!("corpus/connack_session_present.hex"),
+    ),
+    Fixture::new(
+        "connack_v5_properties",
+        include_str!("corpus/connack_v5_properties.hex"),
+    ),
+    Fixture::new(
+        "connect_credentials",
+        include_str!("corpus/connect_credentials.hex"),
+    ),
+    Fixture::new(
+        "connect_empty_client_id",
+        include_str!("corpus/connect_empty_client_id.hex"),
+    ),
+    Fixture::new(
+        "connect_minimal",
+        include_str!("corpus/connect_minimal.hex"),
+    ),
+    Fixture::new(
+        "connect_username_only",
+        include_str!("corpus/connect_username_only.hex"),
+    ),
+    Fixture::new("connect_v5", include_str!("corpus/connect_v5.hex")),
+    Fixture::new(
+        "connect_v5_will_properties",
+        include_str!("corpus/connect_v5_will_properties.hex"),
+    ),
+    Fixture::new("connect_will", include_str!("corpus/connect_will.hex")),
+    Fixture::new(
+        "connect_will_credentials",
+        include_str!("corpus/connect_will_credentials.hex"),
+    ),
+    Fixture::new("disconnect", include_str!("corpus/disconnect.hex")),
+    Fixture::new(
+        "disconnect_v5_server_moved",
+        include_str!("corpus/disconnect_v5_server_moved.hex"),
+    ),
+    Fixture::new(
+        "disconnect_v5_with_will",
+        include_str!("corpus/disconnect_v5_with_will.hex"),
+    ),
+    Fixture::new("pingreq", include_str!("corpus/pingreq.hex")),
+    Fixture::new("pingresp", include_str!("corpus/pingresp.hex")),
+    Fixture::new("puback", include_str!("corpus/puback.hex")),
+    Fixture::new("pubcomp", include_str!("corpus/pubcomp.hex")),
+    Fixture::new(
+        "publish_empty_payload",
+        include_str!("corpus/publish_empty_payload.hex"),
+    ),
+    Fixture::new("publish_qos0", include_str!("corpus/publish_qos0.hex")),
+    Fixture::new(
+        "publish_qos1_retain",
+        include_str!("corpus/publish_qos1_retain.hex"),
+    ),
+    Fixture::new(
+        "publish_qos2_dup",
+        include_str!("corpus/publish_qos2_dup.hex"),
+    ),
+    Fixture::new(
+        "publish_two_byte_length",
+        include_str!("corpus/publish_two_byte_length.hex"),
+    ),
+    Fixture::new(
+        "publish_utf8_topic",
+        include_str!("corpus/publish_utf8_topic.hex"),
+    ),
+    Fixture::new(
+        "publish_v5_properties",
+        include_str!("corpus/publish_v5_properties.hex"),
+    ),
+    Fixture::new("pubrec", include_str!("corpus/pubrec.hex")),
+    Fixture::new("pubrel", include_str!("corpus/pubrel.hex")),
+    Fixture::new("suback", include_str!("corpus/suback.hex")),
+    Fixture::new("suback_v5", include_str!("corpus/suback_v5.hex")),
+    Fixture::new("subscribe", include_str!("corpus/subscribe.hex")),
+    Fixture::new("subscribe_v5", include_str!("corpus/subscribe_v5.hex")),
+    Fixture::new("unsuback", include_str!("corpus/unsuback.hex")),
+    Fixture::new("unsubscribe", include_str!("corpus/unsubscribe.hex")),
+];
+
+/// The fixture called `name`, panics if there is none
+pub fn fixture(name: &str) -> &'static Fixture {
+    FIXTURES
+        .iter()
+        .find(|fixture| fixture.name == name)
+        .unwrap_or_else(|| panic!("No fixture named {}", name))
+}
+
+/// Bytes of whitespace separated hex pairs, skipping `#` comments
+pub fn parse_hex(text: &str) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        for pair in line.split_whitespace() {
+            let byte = match pair.len() {
+                2 => u8::from_str_radix(pair, 16).ok(),
+                _ => None,
+            };
+            bytes.push(byte.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid hex byte {}", pair),
+                )
+            })?);
+        }
+    }
+    Ok(bytes)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let pairs: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    pairs.join(" ")
+}
+
+/// Panic unless `packet` encodes to the bytes of fixture `name`
+pub fn assert_encodes(name: &str, packet: &impl Serialize) {
+    let encoded = packet
+        .to_bytes()
+        .unwrap_or_else(|e| panic!("Fixture {}: encoding failed: {}", name, e));
+    let expected = fixture(name).bytes();
+    assert!(
+        encoded == expected,
+        "Fixture {}: encoded\n  {}\nexpected\n  {}",
+        name,
+        to_hex(&encoded),
+        to_hex(&expected)
+    );
+}
+
+/// Decode fixture `name` with `T`, panicking unless every byte is consumed
+pub fn decode<T: Deserialize>(name: &str) -> T::Output {
+    T::from_slice(&fixture(name).bytes())
+        .unwrap_or_else(|e| panic!("Fixture {}: decoding failed: {}", name, e))
+}
+
+#[cfg(test)]
+mod fixtures_tests {
+    use super::*;
+    use crate::broker::{ClientPacket, Message, ServerPacket};
+    use crate::mqtt::{
+        protocol, ConnackV5, ConnectV5, DisconnectReason, Property, PublishV5, Qos, Request,
+        Response, ResponseV5, SubscribeV5, SubscriptionTopic, Will, DISCONNECT_WITH_WILL,
+    };
+    use std::collections::HashSet;
+
+    fn connect(
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+        will: Option<Will>,
+    ) -> Request {
+        Request::Connect {
+            client_id: client_id.to_string(),
+            clean_session: true,
+            username: username.map(str::to_string),
+            password: password.map(str::to_string),
+            keepalive: 60,
+            will,
+        }
+    }
+
+    fn publish(
+        packet_id: u16,
+        qos: u8,
+        dup: bool,
+        retain: bool,
+        topic: &str,
+        payload: &[u8],
+    ) -> Request {
+        Request::Publish {
+            packet_id,
+            qos,
+            dup,
+            retain,
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn message(topic: &str, payload: &[u8], qos: u8, retain: bool) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos,
+            retain,
+        }
+    }
+
+    #[test]
+    fn test_corpus() -> io::Result<()> {
+        let mut names = HashSet::new();
+        for fixture in FIXTURES {
+            assert!(names.insert(fixture.name), "{} twice", fixture.name);
+            assert!(!fixture.description().is_empty(), "{}", fixture.name);
+            let bytes = fixture.bytes();
+            let mut body = &bytes[1..];
+            let len = protocol::read_remaining_length(&mut body)?;
+            assert_eq!(len as usize, body.len(), "{}", fixture.name);
+        }
+        assert!(parse_hex("10 0").is_err());
+        assert_eq!(parse_hex("# x\nE0 00 # DISCONNECT")?, [0xE0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_packets() {
+        assert_encodes("connect_minimal", &connect("id", None, None, None));
+        assert_encodes("connect_empty_client_id", &connect("", None, None, None));
+        assert_encodes(
+            "connect_credentials",
+            &connect("id", Some("user"), Some("pw"), None),
+        );
+        assert_encodes(
+            "connect_username_only",
+            &connect("id", Some("user"), None, None),
+        );
+        let will = Will::new("w", b"bye", Qos::AtLeastOnce, true);
+        assert_encodes(
+            "connect_will",
+            &Request::Connect {
+                client_id: "id".to_string(),
+                clean_session: false,
+                username: None,
+                password: None,
+                keepalive: 30,
+                will: Some(will),
+            },
+        );
+        assert_encodes(
+            "connect_will_credentials",
+            &connect(
+                "id",
+                Some("user"),
+                Some("pw"),
+                Some(Will::new("w", b"x", Qos::AtMostOnce, false)),
+            ),
+        );
+        let connect_v5 = ConnectV5 {
+            client_id: "id".to_string(),
+            clean_start: true,
+            keepalive: 60,
+            username: None,
+            password: None,
+            will: None,
+            properties: vec![Property::SessionExpiryInterval(60)],
+        };
+        assert_encodes("connect_v5", &connect_v5);
+        let mut delayed = Will::new("w", b"x", Qos::AtMostOnce, false);
+        delayed.delay_interval = Some(10);
+        let connect_v5 = ConnectV5 {
+            will: Some(delayed),
+            properties: vec![],
+            ..connect_v5
+        };
+        assert_encodes("connect_v5_will_properties", &connect_v5);
+        assert_eq!(
+            decode::<ConnectV5>("connect_v5_will_properties"),
+            connect_v5
+        );
+
+        assert_encodes("publish_qos0", &publish(0, 0, false, false, "a/b", b"hi"));
+        assert_encodes(
+            "publish_qos1_retain",
+            &publish(10, 1, false, true, "a/b", b"hi"),
+        );
+        assert_encodes(
+            "publish_qos2_dup",
+            &publish(1, 2, true, false, "a/b", b"hi"),
+        );
+        assert_encodes(
+            "publish_empty_payload",
+            &publish(0, 0, false, false, "t", b""),
+        );
+        assert_encodes(
+            "publish_utf8_topic",
+            &publish(0, 0, false, false, "é", b"!"),
+        );
+        assert_encodes(
+            "publish_two_byte_length",
+            &publish(0, 0, false, false, "t", &[b'x'; 125]),
+        );
+        assert_encodes(
+            "publish_v5_properties",
+            &PublishV5 {
+                packet_id: 1,
+                qos: 1,
+                dup: false,
+                retain: false,
+                topic: "a".to_string(),
+                payload: b"{}".to_vec(),
+                properties: vec![Property::ContentType("json".to_string())],
+            },
+        );
+        assert_encodes("puback", &Request::Puback { packet_id: 10 });
+        assert_encodes("pubrec", &Request::Pubrec { packet_id: 10 });
+        assert_encodes("pubrel", &Request::Pubrel { packet_id: 10 });
+        assert_encodes("pubcomp", &Request::Pubcomp { packet_id: 10 });
+        let topic = |topic: &str, qos| SubscriptionTopic {
+            topic: topic.to_string(),
+            qos,
+        };
+        assert_encodes(
+            "subscribe",
+            &Request::Subscribe {
+                packet_id: 1,
+                subscription_topics: vec![
+                    topic("a/+", Qos::AtLeastOnce),
+                    topic("#", Qos::AtMostOnce),
+                ],
+            },
+        );
+        assert_encodes(
+            "subscribe_v5",
+            &SubscribeV5 {
+                packet_id: 1,
+                subscription_topics: vec![topic("a", Qos::AtLeastOnce)],
+                properties: vec![],
+            },
+        );
+        assert_encodes("pingreq", &Request::Pingreq);
+        assert_encodes("disconnect", &Request::Disconnect { reason: None });
+        assert_encodes(
+            "disconnect_v5_with_will",
+            &Request::Disconnect {
+                reason: Some(DisconnectReason::new(DISCONNECT_WITH_WILL)),
+            },
+        );
+    }
+
+    #[test]
+    fn test_broker_decodes_client_packets() {
+        assert_eq!(
+            decode::<ClientPacket>("connect_credentials"),
+            ClientPacket::Connect {
+                level: 4,
+                client_id: "id".to_string(),
+                clean_session: true,
+                keepalive: 60,
+                username: Some("user".to_string()),
+                password: Some(b"pw".to_vec()),
+                will: None,
+            }
+        );
+        match decode::<ClientPacket>("connect_will_credentials") {
+            ClientPacket::Connect {
+                username,
+                password,
+                will,
+                ..
+            } => {
+                assert_eq!(username.as_deref(), Some("user"));
+                assert_eq!(password.as_deref(), Some(&b"pw"[..]));
+                assert_eq!(will, Some(message("w", b"x", 0, false)));
+            }
+            packet => panic!("Unexpected packet {:?}", packet),
+        }
+        assert_eq!(
+            decode::<ClientPacket>("publish_qos2_dup"),
+            ClientPacket::Publish {
+                packet_id: 1,
+                dup: true,
+                message: message("a/b", b"hi", 2, false),
+            }
+        );
+        assert_eq!(
+            decode::<ClientPacket>("subscribe"),
+            ClientPacket::Subscribe {
+                packet_id: 1,
+                filters: vec![("a/+".to_string(), 1), ("#".to_string(), 0)],
+            }
+        );
+        assert_eq!(
+            decode::<ClientPacket>("unsubscribe"),
+            ClientPacket::Unsubscribe {
+                packet_id: 2,
+                filters: vec!["a/b".to_string()],
+            }
+        );
+        assert_eq!(decode::<ClientPacket>("pubrel"), ClientPacket::Pubrel(10));
+        assert_eq!(decode::<ClientPacket>("pingreq"), ClientPacket::Pingreq);
+        assert_eq!(
+            decode::<ClientPacket>("disconnect"),
+            ClientPacket::Disconnect
+        );
+    }
+
+    #[test]
+    fn test_server_packets() {
+        for (name, session_present, return_code) in [
+            ("connack_accepted", false, 0),
+            ("connack_session_present", true, 0),
+            ("connack_not_authorized", false, 5),
+        ] {
+            let connack = ServerPacket::Connack {
+                session_present,
+                return_code,
+            };
+            assert_encodes(name, &connack);
+            match decode::<Response>(name) {
+                Response::Connack {
+                    session_present: s,
+                    return_code: r,
+                } => assert_eq!((s, r), (session_present, return_code), "{}", name),
+                resp => panic!("Unexpected response {}", resp),
+            }
+        }
+        assert_eq!(
+            decode::<ConnackV5>("connack_v5_properties").properties,
+            [Property::MaximumQos(1), Property::ReceiveMaximum(10)]
+        );
+
+        let publish = ServerPacket::Publish {
+            packet_id: 10,
+            dup: false,
+            message: message("a/b", b"hi", 1, true),
+        };
+        assert_encodes("publish_qos1_retain", &publish);
+        match decode::<Response>("publish_qos1_retain") {
+            Response::Publish {
+                packet_id,
+                qos,
+                retain,
+                topic,
+                payload,
+                ..
+            } => {
+                assert_eq!((packet_id, qos, retain), (10, 1, true));
+                assert_eq!((topic.as_str(), payload.as_slice()), ("a/b", &b"hi"[..]));
+            }
+            resp => panic!("Unexpected response {}", resp),
+        }
+        match decode::<Response>("publish_two_byte_length") {
+            Response::Publish { payload, .. } => assert_eq!(payload, [b'x'; 125]),
+            resp => panic!("Unexpected response {}", resp),
+        }
+        match decode::<ResponseV5>("publish_v5_properties") {
+            Response::Publish {
+                payload,
+                properties,
+                ..
+            } => {
+                assert_eq!(payload, b"{}");
+                assert_eq!(properties, [Property::ContentType("json".to_string())]);
+            }
+            resp => panic!("Unexpected response {}", resp),
+        }
+
+        assert_encodes("puback", &ServerPacket::Puback(10));
+        assert_encodes("pubrec", &ServerPacket::Pubrec(10));
+        assert_encodes("pubrel", &ServerPacket::Pubrel(10));
+        assert_encodes("pubcomp", &ServerPacket::Pubcomp(10));
+        assert!(matches!(
+            decode::<Response>("pubrel"),
+            Response::Pubrel { packet_id: 10 }
+        ));
+        let suback = ServerPacket::Suback {
+            packet_id: 1,
+            return_codes: vec![1, 0, 0x80],
+        };
+        assert_encodes("suback", &suback);
+        match decode::<Response>("suback") {
+            Response::Suback { return_codes, .. } => assert_eq!(return_codes, [1, 0, 0x80]),
+            resp => panic!("Unexpected response {}", resp),
+        }
+        match decode::<ResponseV5>("suback_v5") {
+            Response::Suback { return_codes, .. } => assert_eq!(return_codes, [1]),
+            resp => panic!("Unexpected response {}", resp),
+        }
+        assert_encodes("unsuback", &ServerPacket::Unsuback(2));
+        assert!(matches!(
+            decode::<Response>("unsuback"),
+            Response::Unknown {
+                packet_type: 0x0B,
+                ..
+            }
+        ));
+        assert_encodes("pingresp", &ServerPacket::Pingresp);
+        assert!(matches!(decode::<Response>("pingresp"), Response::Pingresp));
+        match decode::<ResponseV5>("disconnect_v5_server_moved") {
+            Response::Disconnect {
+                reason_code,
+                properties,
+            } => {
+                assert_eq!(reason_code, 0x9D);
+                assert_eq!(
+                    properties,
+                    [Property::ServerReference("broker2".to_string())]
+                );
+            }
+            resp => panic!("Unexpected response {}", resp),
+        }
+        match decode::<ResponseV5>("auth") {
+            Response::Unknown { packet_type, body } => {
+                assert_eq!((packet_type, body.as_slice()), (0x0F, &[0x18, 0][..]))
+            }
+            resp => panic!("Unexpected response {}", resp),
+        }
+    }
+}
@@ -0,0 +1,3 @@
+mod duplex;
+pub mod fixtures;
+pub use duplex::{duplex, Duplex};
@@ -0,0 +1,392 @@
+use crate::json::Value;
+use std::io;
+
+/// Parse a TOML document into the JSON values holding the same data, tables
+/// as objects in the order they're defined. Covers what configuration files
+/// use: tables, arrays of tables, dotted keys, basic and literal strings,
+/// integers, floats, booleans, arrays and inline tables. Multi-line strings
+/// and dates are rejected
+pub fn parse(s: &str) -> io::Result<Value> {
+    let mut parser = Parser {
+        s: s.as_bytes(),
+        pos: 0,
+        line: 1,
+    };
+    let mut root = Value::Object(vec![]);
+    // Path of the table the next keys go in
+    let mut current: Vec<String> = vec![];
+    loop {
+        parser.blank();
+        match parser.peek() {
+            None => return Ok(root),
+            Some(b'[') => {
+                parser.pos += 1;
+                let array = parser.eat(b'[');
+                let path = parser.key()?;
+                parser.expect(b']')?;
+                if array {
+                    parser.expect(b']')?;
+                    let (last, parent) = path.split_last().unwrap();
+                    let table = table(&mut root, parent).map_err(|e| parser.error(&e))?;
+                    match field(table, last) {
+                        Some(Value::Array(tables)) => tables.push(Value::Object(vec![])),
+                        Some(_) => return Err(parser.error(&format!("{} is not an array", last))),
+                        None => {
+                            table.push((last.clone(), Value::Array(vec![Value::Object(vec![])])))
+                        }
+                    }
+                } else {
+                    table(&mut root, &path).map_err(|e| parser.error(&e))?;
+                }
+                current = path;
+            }
+            Some(_) => {
+                let (key, value) = parser.key_value()?;
+                let table = table(&mut root, &current).map_err(|e| parser.error(&e))?;
+                insert(table, &key, value).map_err(|e| parser.error(&e))?;
+            }
+        }
+        parser.end_of_line()?;
+    }
+}
+
+fn field<'a>(table: &'a mut [(String, Value)], key: &str) -> Option<&'a mut Value> {
+    table.iter_mut().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// The table at `path`, created as needed. Arrays of tables lead to their
+/// last table
+fn table<'a>(root: &'a mut Value, path: &[String]) -> Result<&'a mut Vec<(String, Value)>, String> {
+    let mut value = root;
+    for key in path {
+        let Value::Object(fields) = value else {
+            unreachable!("only tables are walked into");
+        };
+        if field(fields, key).is_none() {
+            fields.push((key.clone(), Value::Object(vec![])));
+        }
+        value = match field(fields, key).unwrap() {
+            Value::Array(tables) => match tables.last_mut() {
+                Some(table @ Value::Object(_)) => table,
+                _ => return Err(format!("{} is not a table", key)),
+            },
+            table @ Value::Object(_) => table,
+            _ => return Err(format!("{} is not a table", key)),
+        };
+    }
+    match value {
+        Value::Object(fields) => Ok(fields),
+        _ => unreachable!("only tables are returned"),
+    }
+}
+
+/// Set a dotted key of `table`, each key defined once
+fn insert(table: &mut Vec<(String, Value)>, key: &[String], value: Value) -> Result<(), String> {
+    let (last, parents) = key.split_last().unwrap();
+    let mut wrapped = Value::Object(std::mem::take(table));
+    let result = self::table(&mut wrapped, parents).and_then(|fields| {
+        match field(fields, last) {
+            Some(_) => return Err(format!("{} defined twice", last)),
+            None => fields.push((last.clone(), value)),
+        }
+        Ok(())
+    });
+    if let Value::Object(fields) = wrapped {
+        *table = fields;
+    }
+    result
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, what: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid TOML at line {}: {}", self.line, what),
+        )
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    /// Spaces and tabs
+    fn ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn comment(&mut self) {
+        if self.peek() == Some(b'#') {
+            while !matches!(self.peek(), Some(b'\n') | None) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Whitespace, comments and newlines
+    fn blank(&mut self) {
+        loop {
+            self.ws();
+            self.comment();
+            match self.peek() {
+                Some(b'\n') => self.line += 1,
+                Some(b'\r') => {}
+                _ => return,
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.ws();
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: u8) -> io::Result<()> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(self.error(&format!("expected '{}'", c as char))),
+        }
+    }
+
+    fn end_of_line(&mut self) -> io::Result<()> {
+        self.ws();
+        self.comment();
+        self.eat(b'\r');
+        match self.peek() {
+            None | Some(b'\n') => Ok(()),
+            Some(_) => Err(self.error("expected the end of the line")),
+        }
+    }
+
+    /// A dotted key, its parts bare or quoted
+    fn key(&mut self) -> io::Result<Vec<String>> {
+        let mut parts = vec![];
+        loop {
+            self.ws();
+            let part = match self.peek() {
+                Some(b'"') => self.basic_string()?,
+                Some(b'\'') => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while self
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err(self.error("expected a key"));
+                    }
+                    String::from_utf8_lossy(&self.s[start..self.pos]).into_owned()
+                }
+            };
+            parts.push(part);
+            if !self.eat(b'.') {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn key_value(&mut self) -> io::Result<(Vec<String>, Value)> {
+        let key = self.key()?;
+        self.expect(b'=')?;
+        self.ws();
+        Ok((key, self.value()?))
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        match self.peek() {
+            Some(b'"') => self.basic_string().map(Value::String),
+            Some(b'\'') => self.literal_string().map(Value::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = vec![];
+                loop {
+                    self.blank();
+                    if self.eat(b']') {
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.blank();
+                    if !self.eat(b',') {
+                        self.blank();
+                        self.expect(b']')?;
+                        return Ok(Value::Array(values));
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = vec![];
+                if self.eat(b'}') {
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    let (key, value) = self.key_value()?;
+                    insert(&mut fields, &key, value).map_err(|e| self.error(&e))?;
+                    if !self.eat(b',') {
+                        self.expect(b'}')?;
+                        return Ok(Value::Object(fields));
+                    }
+                }
+            }
+            _ => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| !b",]} \t\r\n#".contains(&c)) {
+                    self.pos += 1;
+                }
+                let literal = String::from_utf8_lossy(&self.s[start..self.pos]);
+                match literal.as_ref() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    literal => number(literal)
+                        .map(Value::Number)
+                        .ok_or_else(|| self.error("expected a value")),
+                }
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> io::Result<String> {
+        self.pos += 1;
+        if self.s[self.pos..].starts_with(b"\"\"") {
+            return Err(self.error("multi-line strings are not supported"));
+        }
+        let mut out = vec![];
+        loop {
+            match self.peek() {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(b'n') => out.push(b'\n'),
+                        Some(b'r') => out.push(b'\r'),
+                        Some(b't') => out.push(b'\t'),
+                        Some(b'b') => out.push(8),
+                        Some(b'f') => out.push(12),
+                        Some(b'"') => out.push(b'"'),
+                        Some(b'\\') => out.push(b'\\'),
+                        Some(b'u') => {
+                            let c = self
+                                .s
+                                .get(self.pos + 1..self.pos + 5)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid escape"))?;
+                            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                            self.pos += 4;
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                Some(b'\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => out.push(c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        String::from_utf8(out).map_err(|_| self.error("invalid UTF-8"))
+    }
+
+    fn literal_string(&mut self) -> io::Result<String> {
+        self.pos += 1;
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                Some(b'\'') => break,
+                Some(b'\n') | None => return Err(self.error("unterminated string")),
+                Some(_) => self.pos += 1,
+            }
+        }
+        self.pos += 1;
+        String::from_utf8(self.s[start..self.pos - 1].to_vec())
+            .map_err(|_| self.error("invalid UTF-8"))
+    }
+}
+
+/// Integers and floats, with `_` between digits
+fn number(literal: &str) -> Option<f64> {
+    let digits = literal.strip_prefix(['+', '-']).unwrap_or(literal);
+    let valid = digits.starts_with(|c: char| c.is_ascii_digit())
+        && !digits.contains("__")
+        && !digits.ends_with('_');
+    if !valid {
+        return None;
+    }
+    literal.replace('_', "").parse().ok()
+}
+
+#[cfg(test)]
+mod toml_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> io::Result<()> {
+        let doc = parse(
+            r#"
+# Comments anywhere
+name = "load \"test\""   # after values too
+path = 'C:\dir'
+rate = 1_000
+ratio = -0.5
+on = true
+point.x = 1
+
+[broker]
+"quoted key" = [1, 2.5,
+  'three',  # in arrays
+]
+limits = { size = 64, qos.max = 2 }
+
+[[phase]]
+name = "ramp"
+
+[[phase]]
+name = "steady"
+
+[phase.extra]
+n = 1
+"#,
+        )?;
+        assert_eq!(
+            doc.to_string(),
+            r#"{"name":"load \"test\"","path":"C:\\dir","rate":1000,"ratio":-0.5,"on":true,"point":{"x":1},"broker":{"quoted key":[1,2.5,"three"],"limits":{"size":64,"qos":{"max":2}}},"phase":[{"name":"ramp"},{"name":"steady","extra":{"n":1}}]}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        for doc in [
+            "a = 1\na = 2",
+            "a = ",
+            "a = 1 2",
+            "a = \"open",
+            "a = \"\"\"multi\"\"\"",
+            "[a\nb = 1",
+            "a = 1\n[[a]]",
+            "a = 1_",
+            "a = [1, 2",
+        ] {
+            let err = parse(doc).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", doc);
+        }
+        assert!(parse("a = 1\n\nb = ?")
+            .unwrap_err()
+            .to_string()
+            .contains("line 3"));
+    }
+}
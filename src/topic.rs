@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+/// Whether `topic` matches the subscription `filter`, `+` matching exactly
+/// one level and a trailing `#` any number of levels, its parent included
+pub fn matches(filter: &str, topic: &str) -> bool {
+    // Wildcards never match topics starting with `$`, as `$SYS/...`
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Values indexed by subscription filter, one node per level, so that the
+/// values matching a topic are found walking its levels rather than testing
+/// every filter. Matching follows `matches`
+#[derive(Debug)]
+pub struct TopicTree<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    children: HashMap<String, Node<T>>,
+    values: Vec<T>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            children: HashMap::new(),
+            values: vec![],
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.children.is_empty()
+    }
+
+    fn collect<'a>(&'a self, levels: &[&str], dollar: bool, out: &mut Vec<&'a T>) {
+        if !dollar {
+            if let Some(multi) = self.children.get("#") {
+                out.extend(&multi.values);
+            }
+        }
+        match levels.split_first() {
+            None => out.extend(&self.values),
+            Some((level, rest)) => {
+                if let Some(child) = self.children.get(*level) {
+                    child.collect(rest, false, out);
+                }
+                if !dollar {
+                    if let Some(single) = self.children.get("+") {
+                        single.collect(rest, false, out);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drop the values of `filter` failing `keep` and the nodes left empty,
+    /// returns how many were dropped
+    fn retain(&mut self, levels: &[&str], keep: &mut impl FnMut(&T) -> bool) -> usize {
+        match levels.split_first() {
+            None => {
+                let before = self.values.len();
+                self.values.retain(|value| keep(value));
+                before - self.values.len()
+            }
+            Some((level, rest)) => {
+                let Some(child) = self.children.get_mut(*level) else {
+                    return 0;
+                };
+                let removed = child.retain(rest, keep);
+                if child.is_empty() {
+                    self.children.remove(*level);
+                }
+                removed
+            }
+        }
+    }
+}
+
+impl<T> Default for TopicTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> TopicTree<T> {
+    pub fn new() -> Self {
+        Self {
+            root: Node::default(),
+            len: 0,
+        }
+    }
+
+    /// Number of values, across all filters
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add a value under `filter`, next to the ones already there
+    pub fn insert(&mut self, filter: &str, value: T) {
+        let node = filter.split('/').fold(&mut self.root, |node, level| {
+            node.children.entry(level.to_string()).or_default()
+        });
+        node.values.push(value);
+        self.len += 1;
+    }
+
+    /// Keep only the values of `filter` for which `keep` returns true,
+    /// returns how many were removed
+    pub fn retain(&mut self, filter: &str, mut keep: impl FnMut(&T) -> bool) -> usize {
+        let levels: Vec<&str> = filter.split('/').collect();
+        let removed = self.root.retain(&levels, &mut keep);
+        self.len -= removed;
+        removed
+    }
+
+    /// Values of every filter matching `topic`, in no particular order
+    pub fn matches(&self, topic: &str) -> Vec<&T> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut out = vec![];
+        self.root.collect(&levels, topic.starts_with('$'), &mut out);
+        out
+    }
+}
+
+/// A level of a rewrite pattern, wildcards refer to their capture by index
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `+`, a single level
+    Single(usize),
+    /// `#`, the remaining levels, possibly none
+    Multi(usize),
+}
+
+/// One side of a rewrite rule
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    /// Captures of `topic`, indexed as the wildcards of the pattern, `None`
+    /// if it doesn't match. Only valid when `#` is the last segment
+    fn captures(&self, topic: &str, count: usize) -> Option<Vec<String>> {
+        let mut captures = vec![String::new(); count];
+        let mut levels = topic.split('/');
+        for segment in &self.0 {
+            match segment {
+                Segment::Multi(i) => {
+                    captures[*i] = levels.collect::<Vec<_>>().join("/");
+                    return Some(captures);
+                }
+                Segment::Single(i) => captures[*i] = levels.next()?.to_string(),
+                Segment::Literal(s) => {
+                    if levels.next()? != s {
+                        return None;
+                    }
+                }
+            }
+        }
+        levels.next().is_none().then_some(captures)
+    }
+
+    /// Fill the wildcards with `captures`, an empty `#` capture drops its
+    /// level so that `remote/#` renders `remote` rather than `remote/`
+    fn render(&self, captures: &[String]) -> String {
+        self.0
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Literal(s) => Some(s.as_str()),
+                Segment::Single(i) => Some(captures[*i].as_str()),
+                Segment::Multi(i) if captures[*i].is_empty() => None,
+                Segment::Multi(i) => Some(captures[*i].as_str()),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn is_filter(&self) -> bool {
+        self.0
+            .iter()
+            .rev()
+            .skip(1)
+            .all(|s| !matches!(s, Segment::Multi(_)))
+    }
+
+    fn indexes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().filter_map(|segment| match segment {
+            Segment::Single(i) | Segment::Multi(i) => Some(*i),
+            Segment::Literal(_) => None,
+        })
+    }
+}
+
+/// Topic rewrite rule, parsed from `FROM -> TO`:
+///
+/// - `FROM` is a topic filter, each `+` and `#` captures the levels it
+///   matches, numbered from 1 left to right
+/// - `TO` is the rewritten topic, `$n` is replaced by the n-th capture while
+///   a bare `+` takes the next `+` capture and `#` the `#` one, so
+///   `local/# -> remote/#` swaps the prefix and `+/status -> devices/$1` moves a level around
+///
+/// A rule is applied forward to outgoing messages and, when `TO` is itself a
+/// valid filter using every capture, reversed on incoming ones
+#[derive(Debug, Clone, PartialEq)]
+pub struct RewriteRule {
+    source: String,
+    from: Pattern,
+    to: Pattern,
+    captures: usize,
+    reversible: bool,
+}
+
+impl std::str::FromStr for RewriteRule {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid rewrite rule {}: {}", s, reason),
+            )
+        };
+        let (from, to) = s
+            .split_once("->")
+            .ok_or_else(|| invalid("expected FROM -> TO"))?;
+        let (from, to) = (from.trim(), to.trim());
+        if from.is_empty() || to.is_empty() {
+            return Err(invalid("expected FROM -> TO"));
+        }
+        let source = format!("{} -> {}", from, to);
+
+        let mut kinds = vec![];
+        let mut levels = from.split('/').peekable();
+        let mut segments = vec![];
+        while let Some(level) = levels.next() {
+            segments.push(match level {
+                "+" => {
+                    kinds.push(false);
+                    Segment::Single(kinds.len() - 1)
+                }
+                "#" if levels.peek().is_none() => {
+                    kinds.push(true);
+                    Segment::Multi(kinds.len() - 1)
+                }
+                "#" => return Err(invalid("# must be the last level")),
+                level if level.contains(['+', '#']) => {
+                    return Err(invalid("wildcards must take a whole level"))
+                }
+                level => Segment::Literal(level.to_string()),
+            });
+        }
+        let from = Pattern(segments);
+
+        let mut singles = (0..kinds.len()).filter(|&i| !kinds[i]);
+        let multi = kinds.iter().position(|&multi| multi);
+        let mut segments = vec![];
+        for level in to.split('/') {
+            let index = match level {
+                "+" => singles
+                    .next()
+                    .ok_or_else(|| invalid("more + in TO than in FROM"))?,
+                "#" => multi.ok_or_else(|| invalid("# in TO but not in FROM"))?,
+                level => match level.strip_prefix('$') {
+                    Some(n) => n
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|n| n.checked_sub(1))
+                        .ok_or_else(|| invalid("expected $n with n from 1"))?,
+                    None if level.contains(['+', '#']) => {
+                        return Err(invalid("wildcards must take a whole level"))
+                    }
+                    None => {
+                        segments.push(Segment::Literal(level.to_string()));
+                        continue;
+                    }
+                },
+            };
+            segments.push(match kinds.get(index) {
+                Some(true) => Segment::Multi(index),
+                Some(false) => Segment::Single(index),
+                None => return Err(invalid("more captures than wildcards in FROM")),
+            });
+        }
+        let to = Pattern(segments);
+
+        let mut used: Vec<usize> = to.indexes().collect();
+        used.sort_unstable();
+        used.dedup();
+        let reversible = to.is_filter() && used.len() == kinds.len();
+        Ok(Self {
+            source,
+            from,
+            to,
+            captures: kinds.len(),
+            reversible,
+        })
+    }
+}
+
+impl fmt::Display for RewriteRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl RewriteRule {
+    /// Rewrite an outgoing topic, `None` if the rule doesn't match it
+    pub fn apply(&self, topic: &str) -> Option<String> {
+        let captures = self.from.captures(topic, self.captures)?;
+        Some(self.to.render(&captures))
+    }
+
+    /// Rewrite an incoming topic back, `None` if the rule doesn't match it
+    /// or can't be reversed
+    pub fn reverse(&self, topic: &str) -> Option<String> {
+        if !self.reversible {
+            return None;
+        }
+        let captures = self.to.captures(topic, self.captures)?;
+        Some(self.from.render(&captures))
+    }
+}
+
+/// Apply the first matching rule to an outgoing topic, topics matching no
+/// rule are left untouched
+pub fn rewrite(rules: &[RewriteRule], topic: &str) -> String {
+    rules
+        .iter()
+        .find_map(|rule| rule.apply(topic))
+        .unwrap_or_else(|| topic.to_string())
+}
+
+/// Apply the first matching rule backwards to an incoming topic
+pub fn rewrite_back(rules: &[RewriteRule], topic: &str) -> String {
+    rules
+        .iter()
+        .find_map(|rule| rule.reverse(topic))
+        .unwrap_or_else(|| topic.to_string())
+}
+
+#[cfg(test)]
+mod topic_tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let cases = [
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/+", "a/b", true),
+            ("a/+", "a/b/c", false),
+            ("a/+/c", "a/b/c", true),
+            ("a/#", "a", true),
+            ("a/#", "a/b/c", true),
+            ("#", "a/b", true),
+            ("+/+", "/a", true),
+            ("#", "$SYS/load", false),
+            ("$SYS/#", "$SYS/load", true),
+        ];
+        for (filter, topic, expected) in cases {
+            assert_eq!(matches(filter, topic), expected, "{} {}", filter, topic);
+        }
+    }
+
+    #[test]
+    fn test_tree_matches_like_filters() {
+        let filters = [
+            "a/b", "a/c", "a/+", "a/+/c", "a/#", "#", "+/+", "+", "$SYS/#", "$SYS/+", "/a",
+        ];
+        let mut tree = TopicTree::new();
+        for filter in filters {
+            tree.insert(filter, filter);
+        }
+        for topic in ["a", "a/b", "a/b/c", "/a", "b", "$SYS/load", "$SYS", ""] {
+            let mut found: Vec<&str> = tree.matches(topic).into_iter().copied().collect();
+            found.sort_unstable();
+            let mut expected: Vec<&str> = filters
+                .into_iter()
+                .filter(|filter| matches(filter, topic))
+                .collect();
+            expected.sort_unstable();
+            assert_eq!(found, expected, "{}", topic);
+        }
+    }
+
+    #[test]
+    fn test_tree_retain() {
+        let mut tree = TopicTree::new();
+        tree.insert("a/+", 1);
+        tree.insert("a/+", 2);
+        tree.insert("a/b/#", 3);
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.retain("a/+", |v| *v != 1), 1);
+        assert_eq!(tree.matches("a/b"), [&3, &2]);
+        assert_eq!(tree.retain("a/x", |_| false), 0);
+        tree.retain("a/+", |_| false);
+        tree.retain("a/b/#", |_| false);
+        assert!(tree.is_empty());
+        assert!(tree.root.is_empty());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let cases = [
+            (
+                "local/# -> remote/site1/#",
+                "local/a/b",
+                Some("remote/site1/a/b"),
+            ),
+            ("local/# -> remote/site1/#", "local", Some("remote/site1")),
+            ("local/# -> remote/site1/#", "other/a", None),
+            ("site1/# -> #", "site1/a/b", Some("a/b")),
+            ("# -> site1/#", "a/b", Some("site1/a/b")),
+            (
+                "+/+/status -> status/$2/$1",
+                "home/lamp/status",
+                Some("status/lamp/home"),
+            ),
+            ("+/+/status -> status/$2/$1", "home/status", None),
+            ("a/+ -> b/$1/$1", "a/x", Some("b/x/x")),
+            ("a/+/# -> b/# ", "a/x/y", Some("b/y")),
+        ];
+        for (rule, topic, expected) in cases {
+            let parsed: RewriteRule = rule.parse().unwrap();
+            assert_eq!(
+                parsed.apply(topic).as_deref(),
+                expected,
+                "{} {}",
+                rule,
+                topic
+            );
+        }
+    }
+
+    #[test]
+    fn test_reverse() {
+        let cases = [
+            (
+                "local/# -> remote/site1/#",
+                "remote/site1/a/b",
+                Some("local/a/b"),
+            ),
+            ("local/# -> remote/site1/#", "remote/site2/a", None),
+            (
+                "+/+/status -> status/$2/$1",
+                "status/lamp/home",
+                Some("home/lamp/status"),
+            ),
+            // The dropped capture can't be recovered
+            ("a/+/# -> b/#", "b/y", None),
+            // `#` is not the last level of TO
+            ("a/# -> $1/b", "x/b", None),
+        ];
+        for (rule, topic, expected) in cases {
+            let parsed: RewriteRule = rule.parse().unwrap();
+            assert_eq!(
+                parsed.reverse(topic).as_deref(),
+                expected,
+                "{} {}",
+                rule,
+                topic
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        for rule in [
+            "a/b",
+            " -> a",
+            "a/#/b -> c",
+            "a/b+ -> c",
+            "a/+ -> $2",
+            "a/+ -> $0",
+            "a/+ -> +/+",
+            "a -> b/c#",
+        ] {
+            let err = rule.parse::<RewriteRule>().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", rule);
+        }
+    }
+
+    #[test]
+    fn test_first_rule_wins() {
+        let rules: Vec<RewriteRule> = ["a/b -> x", "a/# -> y/#"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        assert_eq!(rewrite(&rules, "a/b"), "x");
+        assert_eq!(rewrite(&rules, "a/c"), "y/c");
+        assert_eq!(rewrite(&rules, "b"), "b");
+        assert_eq!(rewrite_back(&rules, "y/c"), "a/c");
+        assert_eq!(rewrite_back(&rules, "x"), "a/b");
+    }
+}
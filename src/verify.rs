@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+/// Gaps wider than this are counted but their numbers not remembered, so a
+/// publisher restarting from a much higher number can't exhaust memory
+const MAX_TRACKED_GAP: u64 = 10_000;
+
+/// Where the sequence number lives in a payload, parsed from `--verify-seq`:
+///
+/// - `text`: the whole payload is the number
+/// - `json:$.seq`: the number is at the given path of a JSON object, nested
+///   keys are separated by dots as in `json:$.meta.seq`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SeqSpec {
+    Text,
+    Json(Vec<String>),
+}
+
+impl std::str::FromStr for SeqSpec {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid sequence spec {}, expected text or json:$.path", s),
+            )
+        };
+        if s == "text" {
+            return Ok(SeqSpec::Text);
+        }
+        let path = s
+            .strip_prefix("json:$.")
+            .filter(|p| !p.is_empty())
+            .ok_or_else(invalid)?;
+        let keys: Vec<String> = path.split('.').map(|k| k.to_string()).collect();
+        if keys.iter().any(|k| k.is_empty()) {
+            return Err(invalid());
+        }
+        Ok(SeqSpec::Json(keys))
+    }
+}
+
+impl SeqSpec {
+    /// Extract the sequence number from a payload, `None` if it's missing
+    /// or not an unsigned integer
+    pub fn extract(&self, payload: &[u8]) -> Option<u64> {
+        let text = std::str::from_utf8(payload).ok()?;
+        match self {
+            SeqSpec::Text => text.trim().parse().ok(),
+            SeqSpec::Json(keys) => {
+                let mut json = Json {
+                    s: text.as_bytes(),
+                    pos: 0,
+                };
+                json.find(keys)
+            }
+        }
+    }
+}
+
+/// Just enough of a JSON scanner to walk objects down a key path, values
+/// that are not on the path are skipped without being decoded
+struct Json<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl Json<'_> {
+    fn ws(&mut self) {
+        while self
+            .s
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> Option<()> {
+        self.ws();
+        (self.s.get(self.pos) == Some(&c)).then(|| self.pos += 1)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.eat(b'"')?;
+        let mut out = vec![];
+        loop {
+            match *self.s.get(self.pos)? {
+                b'"' => break,
+                b'\\' => {
+                    self.pos += 1;
+                    out.push(*self.s.get(self.pos)?);
+                }
+                c => out.push(c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        String::from_utf8(out).ok()
+    }
+
+    fn skip(&mut self) -> Option<()> {
+        self.ws();
+        match *self.s.get(self.pos)? {
+            b'"' => self.string().map(|_| ()),
+            open @ (b'{' | b'[') => {
+                let close = if open == b'{' { b'}' } else { b']' };
+                self.pos += 1;
+                if self.eat(close).is_some() {
+                    return Some(());
+                }
+                loop {
+                    if open == b'{' {
+                        self.string()?;
+                        self.eat(b':')?;
+                    }
+                    self.skip()?;
+                    if self.eat(b',').is_none() {
+                        return self.eat(close);
+                    }
+                }
+            }
+            _ => {
+                while self
+                    .s
+                    .get(self.pos)
+                    .is_some_and(|c| !b",}] \t\r\n".contains(c))
+                {
+                    self.pos += 1;
+                }
+                Some(())
+            }
+        }
+    }
+
+    fn find(&mut self, keys: &[String]) -> Option<u64> {
+        let Some((key, rest)) = keys.split_first() else {
+            self.ws();
+            let start = self.pos;
+            self.skip()?;
+            return std::str::from_utf8(&self.s[start..self.pos])
+                .ok()?
+                .parse()
+                .ok();
+        };
+        self.eat(b'{')?;
+        loop {
+            let name = self.string()?;
+            self.eat(b':')?;
+            if &name == key {
+                return self.find(rest);
+            }
+            self.skip()?;
+            self.eat(b',')?;
+        }
+    }
+}
+
+/// What a sequence number says about a delivery
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeqEvent {
+    /// First message seen on the topic, or the expected next one
+    InOrder,
+    /// Some numbers were skipped, they may still arrive out of order
+    Gap { expected: u64, got: u64 },
+    /// A number older than the last one, not seen before
+    OutOfOrder { last: u64, got: u64 },
+    /// A number already seen
+    Duplicate(u64),
+    /// The payload carries no sequence number
+    Missing,
+}
+
+impl fmt::Display for SeqEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeqEvent::InOrder => write!(f, "in order"),
+            SeqEvent::Gap { expected, got } => write!(
+                f,
+                "gap: expected {} got {} ({} missing)",
+                expected,
+                got,
+                got - expected
+            ),
+            SeqEvent::OutOfOrder { last, got } => {
+                write!(f, "out of order: {} after {}", got, last)
+            }
+            SeqEvent::Duplicate(seq) => write!(f, "duplicate: {}", seq),
+            SeqEvent::Missing => write!(f, "no sequence number"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TopicSeq {
+    last: u64,
+    /// Numbers skipped by a gap and not received since
+    missing: Vec<u64>,
+}
+
+/// Counters over every topic observed by a `SeqVerifier`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SeqReport {
+    pub received: u64,
+    pub gaps: u64,
+    pub out_of_order: u64,
+    pub duplicates: u64,
+    pub missing_seq: u64,
+    /// Numbers skipped by gaps that never arrived
+    pub lost: u64,
+}
+
+impl fmt::Display for SeqReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received {}, gaps {}, lost {}, out of order {}, duplicates {}, without sequence {}",
+            self.received,
+            self.gaps,
+            self.lost,
+            self.out_of_order,
+            self.duplicates,
+            self.missing_seq
+        )
+    }
+}
+
+/// Tracks sequence numbers per topic and classifies each delivery
+#[derive(Debug)]
+pub struct SeqVerifier {
+    spec: SeqSpec,
+    topics: HashMap<String, TopicSeq>,
+    report: SeqReport,
+}
+
+impl SeqVerifier {
+    pub fn new(spec: SeqSpec) -> Self {
+        Self {
+            spec,
+            topics: HashMap::new(),
+            report: SeqReport::default(),
+        }
+    }
+
+    pub fn observe(&mut self, topic: &str, payload: &[u8]) -> SeqEvent {
+        self.report.received += 1;
+        let Some(seq) = self.spec.extract(payload) else {
+            self.report.missing_seq += 1;
+            return SeqEvent::Missing;
+        };
+        let Some(state) = self.topics.get_mut(topic) else {
+            self.topics.insert(
+                topic.to_string(),
+                TopicSeq {
+                    last: seq,
+                    missing: vec![],
+                },
+            );
+            return SeqEvent::InOrder;
+        };
+        if seq == state.last + 1 {
+            state.last = seq;
+            SeqEvent::InOrder
+        } else if seq > state.last {
+            let expected = state.last + 1;
+            if seq - expected <= MAX_TRACKED_GAP {
+                state.missing.extend(expected..seq);
+            }
+            state.last = seq;
+            self.report.gaps += 1;
+            SeqEvent::Gap { expected, got: seq }
+        } else if let Some(i) = state.missing.iter().position(|&m| m == seq) {
+            state.missing.swap_remove(i);
+            self.report.out_of_order += 1;
+            SeqEvent::OutOfOrder {
+                last: state.last,
+                got: seq,
+            }
+        } else {
+            self.report.duplicates += 1;
+            SeqEvent::Duplicate(seq)
+        }
+    }
+
+    pub fn report(&self) -> SeqReport {
+        SeqReport {
+            lost: self.topics.values().map(|t| t.missing.len() as u64).sum(),
+            ..self.report
+        }
+    }
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use super::*;
+
+    #[test]
+    fn test_spec() -> io::Result<()> {
+        let spec: SeqSpec = "json:$.meta.seq".parse()?;
+        assert_eq!(
+            spec.extract(br#"{"id": "x", "list": [1, {"seq": 9}], "meta": {"seq": 42}}"#),
+            Some(42)
+        );
+        assert_eq!(spec.extract(br#"{"meta": {"seq": "42"}}"#), None);
+        assert_eq!("text".parse::<SeqSpec>()?.extract(b" 7\n"), Some(7));
+        assert!("json:seq".parse::<SeqSpec>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_events() {
+        let mut verifier = SeqVerifier::new(SeqSpec::Text);
+        assert_eq!(verifier.observe("a", b"1"), SeqEvent::InOrder);
+        assert_eq!(verifier.observe("a", b"2"), SeqEvent::InOrder);
+        assert_eq!(
+            verifier.observe("a", b"5"),
+            SeqEvent::Gap {
+                expected: 3,
+                got: 5
+            }
+        );
+        assert_eq!(
+            verifier.observe("a", b"3"),
+            SeqEvent::OutOfOrder { last: 5, got: 3 }
+        );
+        assert_eq!(verifier.observe("a", b"3"), SeqEvent::Duplicate(3));
+        // Topics are tracked independently
+        assert_eq!(verifier.observe("b", b"100"), SeqEvent::InOrder);
+        assert_eq!(verifier.observe("b", b"x"), SeqEvent::Missing);
+        let report = verifier.report();
+        assert_eq!(report.received, 7);
+        assert_eq!(report.gaps, 1);
+        assert_eq!(report.lost, 1);
+        assert_eq!(report.out_of_order, 1);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.missing_seq, 1);
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    modified: Option<SystemTime>,
+    len: u64,
+    /// Created or modified since last reported
+    pending: bool,
+}
+
+/// Polls a directory for files created or modified, for integrations
+/// dropping files rather than speaking MQTT. Only regular files directly
+/// in the directory are watched, names starting with a dot are skipped as
+/// they are usually written to and then renamed
+#[derive(Debug)]
+pub struct DirWatcher {
+    dir: PathBuf,
+    files: HashMap<PathBuf, Entry>,
+}
+
+impl DirWatcher {
+    /// Start watching `dir`, the files already there are only reported once
+    /// modified
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut watcher = Self {
+            dir: dir.as_ref().to_path_buf(),
+            files: HashMap::new(),
+        };
+        for (path, entry) in watcher.scan()? {
+            watcher.files.insert(
+                path,
+                Entry {
+                    pending: false,
+                    ..entry
+                },
+            );
+        }
+        Ok(watcher)
+    }
+
+    fn scan(&self) -> io::Result<Vec<(PathBuf, Entry)>> {
+        let mut found = vec![];
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            if dir_entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            // Files removed since listed are skipped, not errors
+            let Ok(metadata) = dir_entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let entry = Entry {
+                modified: metadata.modified().ok(),
+                len: metadata.len(),
+                pending: true,
+            };
+            found.push((dir_entry.path(), entry));
+        }
+        Ok(found)
+    }
+
+    /// Files created or modified since the previous poll that stayed the
+    /// same since, sorted by path. A file still being written to is left
+    /// for a later poll, so files are reported a poll after they settle
+    pub fn poll(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut files = HashMap::new();
+        let mut settled = vec![];
+        for (path, mut entry) in self.scan()? {
+            match self.files.get(&path) {
+                Some(seen) if (seen.modified, seen.len) == (entry.modified, entry.len) => {
+                    if seen.pending {
+                        settled.push(path.clone());
+                    }
+                    entry.pending = false;
+                }
+                _ => {}
+            }
+            files.insert(path, entry);
+        }
+        self.files = files;
+        settled.sort();
+        Ok(settled)
+    }
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+
+    #[test]
+    fn test_poll() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("sake-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub"))?;
+        fs::write(dir.join("old.txt"), "old")?;
+        let mut watcher = DirWatcher::new(&dir)?;
+        assert!(watcher.poll()?.is_empty());
+
+        fs::write(dir.join("b.json"), "{}")?;
+        fs::write(dir.join("a.csv"), "1,2")?;
+        fs::write(dir.join(".partial"), "x")?;
+        fs::write(dir.join("sub").join("nested"), "x")?;
+        // Seen but not settled yet
+        assert!(watcher.poll()?.is_empty());
+        assert_eq!(watcher.poll()?, [dir.join("a.csv"), dir.join("b.json")]);
+        assert!(watcher.poll()?.is_empty());
+
+        // Growing files are reported once they stop growing
+        fs::write(dir.join("old.txt"), "older")?;
+        assert!(watcher.poll()?.is_empty());
+        fs::write(dir.join("old.txt"), "oldest")?;
+        assert!(watcher.poll()?.is_empty());
+        assert_eq!(watcher.poll()?, [dir.join("old.txt")]);
+
+        fs::remove_file(dir.join("a.csv"))?;
+        fs::write(dir.join("a.csv"), "3,4,5")?;
+        watcher.poll()?;
+        assert_eq!(watcher.poll()?, [dir.join("a.csv")]);
+        fs::remove_dir_all(&dir)
+    }
+}